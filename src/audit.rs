@@ -0,0 +1,500 @@
+use anyhow::{Context, Result};
+use bitcoin::{
+    hashes::{hmac, sha256, Hash, HashEngine},
+    Txid,
+};
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+use serde_json::{json, Value};
+
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{thread::spawn, types::ScriptHash};
+
+/// Maximum number of records pending to be written (newer ones are dropped)
+const MAX_PENDING_RECORDS: usize = 10_000;
+
+/// Delay before retrying a failed write (meanwhile, the new records are queued)
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct AuditLogOptions {
+    pub path: PathBuf,
+    pub max_size: u64, // rotate the log file before it exceeds this size (in bytes)
+    pub max_files: usize, // number of rotated files to keep
+    pub fsync: bool,
+    pub scripthash_secret: Option<Vec<u8>>, // if set, scripthash queries are logged (HMAC'd)
+}
+
+impl fmt::Debug for AuditLogOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuditLogOptions")
+            .field("path", &self.path)
+            .field("max_size", &self.max_size)
+            .field("max_files", &self.max_files)
+            .field("fsync", &self.fsync)
+            .field(
+                "scripthash_secret",
+                &self.scripthash_secret.as_ref().map(|_| "<sensitive>"),
+            )
+            .finish()
+    }
+}
+
+struct Inner {
+    tx: Sender<Value>,
+    secret: Option<Vec<u8>>,
+    dropped: AtomicUsize,
+}
+
+/// Audit log sink, writing JSON lines from a separate thread (so recording never blocks on I/O).
+#[derive(Clone, Default)]
+pub(crate) struct AuditLog {
+    inner: Option<Arc<Inner>>, // `None` if disabled
+    network: Option<String>,
+}
+
+impl AuditLog {
+    pub(crate) fn open(options: Option<&AuditLogOptions>) -> Result<Self> {
+        let options = match options {
+            Some(options) => options,
+            None => return Ok(Self::default()),
+        };
+        let writer = Writer::open(options)?;
+        let (tx, rx) = bounded(MAX_PENDING_RECORDS);
+        spawn("audit_log", move || write_loop(writer, rx, RETRY_DELAY));
+        info!("writing audit log to {}", options.path.display());
+        Ok(Self {
+            inner: Some(Arc::new(Inner {
+                tx,
+                secret: options.scripthash_secret.clone(),
+                dropped: AtomicUsize::new(0),
+            })),
+            network: None,
+        })
+    }
+
+    /// Label the records with the given network (when serving multiple networks).
+    pub(crate) fn with_network(&self, network: &str) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            network: Some(network.to_owned()),
+        }
+    }
+
+    /// `skipped` is set if the transaction wasn't sent to bitcoind (e.g. it was already broadcast).
+    pub(crate) fn broadcast(
+        &self,
+        peer: Option<SocketAddr>,
+        txid: Txid,
+        skipped: Option<&str>,
+        error: Option<String>,
+    ) {
+        self.record(json!({
+            "event": "broadcast",
+            "peer": peer.map(|addr| addr.to_string()),
+            "txid": txid,
+            "skipped": skipped,
+            "error": error,
+        }));
+    }
+
+    /// Admin method calls (including the refused ones), with their params.
+    pub(crate) fn admin(
+        &self,
+        peer: Option<SocketAddr>,
+        method: &str,
+        params: &Value,
+        error: Option<String>,
+    ) {
+        self.record(json!({
+            "event": "admin",
+            "peer": peer.map(|addr| addr.to_string()),
+            "method": method,
+            "params": params,
+            "error": error,
+        }));
+    }
+
+    /// Recorded only if a scripthash secret is configured.
+    pub(crate) fn query(&self, peer: Option<SocketAddr>, method: &str, scripthash: &ScriptHash) {
+        let secret = match self.inner.as_ref().and_then(|inner| inner.secret.as_ref()) {
+            Some(secret) => secret,
+            None => return,
+        };
+        self.record(json!({
+            "event": "query",
+            "peer": peer.map(|addr| addr.to_string()),
+            "method": method,
+            "scripthash_hmac": hash_scripthash(secret, scripthash),
+        }));
+    }
+
+    fn record(&self, mut record: Value) {
+        let inner = match &self.inner {
+            Some(inner) => inner,
+            None => return,
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        record["time"] = json!(now.as_secs());
+        if let Some(network) = &self.network {
+            record["network"] = json!(network);
+        }
+        let (lagging, err) = match inner.tx.try_send(record) {
+            Ok(()) => return,
+            Err(TrySendError::Full(_)) => (true, "audit log is lagging"),
+            Err(TrySendError::Disconnected(_)) => (false, "audit log writer stopped"),
+        };
+        let dropped = inner.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        if dropped % 1000 == 1 {
+            if lagging {
+                warn!("{}: {} records dropped", err, dropped);
+            } else {
+                error!("{}: {} records dropped", err, dropped);
+            }
+        }
+    }
+}
+
+/// HMAC-SHA256 of the scripthash, so the log can't be used to learn the queried addresses.
+fn hash_scripthash(secret: &[u8], scripthash: &ScriptHash) -> String {
+    let mut engine = hmac::HmacEngine::<sha256::Hash>::new(secret);
+    engine.input(scripthash.as_byte_array());
+    hmac::Hmac::<sha256::Hash>::from_engine(engine).to_string()
+}
+
+/// Write the records until the sink is dropped. Failed writes are logged and retried (after
+/// reopening the file), so an I/O error (e.g. a full disk) doesn't stop the auditing for good.
+fn write_loop(mut writer: Writer, rx: Receiver<Value>, retry_delay: Duration) -> Result<()> {
+    for record in rx.iter() {
+        let mut failures = 0;
+        while let Err(e) = writer.write(&record) {
+            failures += 1;
+            error!("audit log write failed ({} times): {:#}", failures, e);
+            thread::sleep(retry_delay);
+            if let Err(e) = writer.reopen() {
+                error!("audit log reopening failed: {:#}", e);
+            }
+        }
+    }
+    Ok(())
+}
+
+struct Writer {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    fsync: bool,
+    file: File,
+    size: u64,
+}
+
+impl Writer {
+    fn open(options: &AuditLogOptions) -> Result<Self> {
+        let (file, size) = open_file(&options.path)?;
+        Ok(Self {
+            path: options.path.clone(),
+            max_size: options.max_size,
+            max_files: options.max_files,
+            fsync: options.fsync,
+            file,
+            size,
+        })
+    }
+
+    fn write(&mut self, record: &Value) -> Result<()> {
+        let mut line = record.to_string();
+        line.push('\n');
+        let len = line.len() as u64;
+        if self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+        self.file
+            .write_all(line.as_bytes())
+            .with_context(|| format!("failed to write {}", self.path.display()))?;
+        if self.fsync {
+            self.file
+                .sync_data()
+                .with_context(|| format!("failed to sync {}", self.path.display()))?;
+        }
+        self.size += len;
+        Ok(())
+    }
+
+    /// Reopen `path` (e.g. if it was rotated away, but the new file failed to open).
+    fn reopen(&mut self) -> Result<()> {
+        let (file, size) = open_file(&self.path)?;
+        self.file = file;
+        self.size = size;
+        Ok(())
+    }
+
+    /// Rename `path` to `path.1` (and `path.N` to `path.N+1`), dropping the oldest file.
+    fn rotate(&mut self) -> Result<()> {
+        for i in (1..self.max_files).rev() {
+            let src = rotated_path(&self.path, i);
+            if src.exists() {
+                fs::rename(&src, rotated_path(&self.path, i + 1))
+                    .with_context(|| format!("failed to rotate {}", src.display()))?;
+            }
+        }
+        if self.max_files > 0 {
+            fs::rename(&self.path, rotated_path(&self.path, 1))
+        } else {
+            fs::remove_file(&self.path)
+        }
+        .with_context(|| format!("failed to rotate {}", self.path.display()))?;
+        self.reopen()
+    }
+}
+
+fn open_file(path: &Path) -> Result<(File, u64)> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open audit log {}", path.display()))?;
+    let size = file.metadata()?.len();
+    Ok((file, size))
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash_scripthash, rotated_path, write_loop, AuditLog, AuditLogOptions, Writer};
+    use crate::types::ScriptHash;
+    use bitcoin::{hashes::Hash, ScriptBuf, Txid};
+    use crossbeam_channel::unbounded;
+    use serde_json::{json, Value};
+    use std::fs;
+    use std::path::Path;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn options(path: &Path, max_size: u64, max_files: usize) -> AuditLogOptions {
+        AuditLogOptions {
+            path: path.to_owned(),
+            max_size,
+            max_files,
+            fsync: true,
+            scripthash_secret: None,
+        }
+    }
+
+    fn read_records(path: &Path, count: usize) -> Vec<Value> {
+        let start = Instant::now();
+        loop {
+            let lines = fs::read_to_string(path).unwrap_or_default();
+            let records: Vec<Value> = lines
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect();
+            if records.len() >= count {
+                return records;
+            }
+            assert!(start.elapsed() < Duration::from_secs(10), "timed out");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let options = options(&path, 30, 2);
+        let mut writer = Writer::open(&options).unwrap();
+        for i in 0..4 {
+            writer.write(&json!({ "record": i })).unwrap(); // 13 bytes each
+        }
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "{\"record\":2}\n{\"record\":3}\n"
+        );
+        assert_eq!(
+            fs::read_to_string(rotated_path(&path, 1)).unwrap(),
+            "{\"record\":0}\n{\"record\":1}\n"
+        );
+        assert!(!rotated_path(&path, 2).exists());
+
+        for i in 4..8 {
+            writer.write(&json!({ "record": i })).unwrap();
+        }
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "{\"record\":6}\n{\"record\":7}\n"
+        );
+        assert_eq!(
+            fs::read_to_string(rotated_path(&path, 2)).unwrap(),
+            "{\"record\":2}\n{\"record\":3}\n"
+        );
+        assert!(!rotated_path(&path, 3).exists());
+
+        // appends to the existing file after restart
+        let mut writer = Writer::open(&options).unwrap();
+        writer.write(&json!({ "record": 8 })).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{\"record\":8}\n");
+        assert_eq!(
+            fs::read_to_string(rotated_path(&path, 1)).unwrap(),
+            "{\"record\":6}\n{\"record\":7}\n"
+        );
+    }
+
+    #[test]
+    fn test_write_retry() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let writer = Writer::open(&options(&path, 30, 1)).unwrap();
+        let (tx, rx) = unbounded();
+        let writer_thread = thread::spawn(move || write_loop(writer, rx, Duration::from_millis(1)));
+        for i in 0..2 {
+            tx.send(json!({ "record": i })).unwrap();
+        }
+        read_records(&path, 2);
+
+        // rotation fails, since the rotated file's path is taken by a directory
+        let blocker = rotated_path(&path, 1);
+        fs::create_dir(&blocker).unwrap();
+        for i in 2..4 {
+            tx.send(json!({ "record": i })).unwrap();
+        }
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(
+            read_records(&path, 2),
+            vec![json!({"record": 0}), json!({"record": 1})]
+        );
+
+        // the failed write is retried, and no record is lost
+        fs::remove_dir(&blocker).unwrap();
+        assert_eq!(
+            read_records(&path, 2),
+            vec![json!({"record": 2}), json!({"record": 3})]
+        );
+        assert_eq!(
+            read_records(&rotated_path(&path, 1), 2),
+            vec![json!({"record": 0}), json!({"record": 1})]
+        );
+        drop(tx);
+        writer_thread.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_broadcast_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let log = AuditLog::open(Some(&options(&path, 1_000_000, 1))).unwrap();
+        let addr = "127.0.0.1:50001";
+        let peer = Some(addr.parse().unwrap());
+        let txid = Txid::all_zeros();
+        log.broadcast(peer, txid, None, None);
+        log.broadcast(peer, txid, Some("already_broadcast"), None); // answered early
+        log.broadcast(None, txid, None, Some("rejected".to_owned()));
+        let records: Vec<Value> = read_records(&path, 3)
+            .into_iter()
+            .map(|mut record| {
+                assert!(record["time"].is_u64());
+                record["time"].take();
+                record
+            })
+            .collect();
+        let record = |peer: Value, skipped: Value, error: Value| {
+            json!({
+                "event": "broadcast",
+                "peer": peer,
+                "txid": txid,
+                "skipped": skipped,
+                "error": error,
+                "time": null,
+            })
+        };
+        assert_eq!(
+            records,
+            vec![
+                record(json!(addr), json!(null), json!(null)),
+                record(json!(addr), json!("already_broadcast"), json!(null)),
+                record(json!(null), json!(null), json!("rejected")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_admin_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let log = AuditLog::open(Some(&options(&path, 1_000_000, 1)))
+            .unwrap()
+            .with_network("regtest");
+        let addr = "127.0.0.1:50001";
+        let peer = Some(addr.parse().unwrap());
+        log.admin(peer, "server.bans.unban", &json!(["192.0.2.1"]), None);
+        log.admin(
+            None,
+            "server.cache.flush",
+            &json!({"kind": "all"}),
+            Some("denied".to_owned()),
+        );
+        let mut records = read_records(&path, 2);
+        for record in &mut records {
+            assert!(record["time"].is_u64());
+            record["time"].take();
+        }
+        assert_eq!(
+            records,
+            vec![
+                json!({
+                    "event": "admin",
+                    "peer": addr,
+                    "method": "server.bans.unban",
+                    "params": ["192.0.2.1"],
+                    "error": null,
+                    "network": "regtest",
+                    "time": null,
+                }),
+                json!({
+                    "event": "admin",
+                    "peer": null,
+                    "method": "server.cache.flush",
+                    "params": {"kind": "all"},
+                    "error": "denied",
+                    "network": "regtest",
+                    "time": null,
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scripthash_hmac() {
+        let scripthash = ScriptHash::new(&ScriptBuf::new());
+        let hashed = hash_scripthash(b"secret", &scripthash);
+        assert_eq!(hashed.len(), 64);
+        assert_eq!(hashed, hash_scripthash(b"secret", &scripthash));
+        assert_ne!(hashed, hash_scripthash(b"other", &scripthash));
+        assert_ne!(hashed, scripthash.to_string());
+    }
+
+    #[test]
+    fn test_disabled() {
+        let log = AuditLog::open(None).unwrap().with_network("regtest");
+        let scripthash = ScriptHash::new(&ScriptBuf::new());
+        log.query(None, "blockchain.scripthash.get_balance", &scripthash);
+        assert!(log.inner.is_none());
+    }
+}
@@ -3,5 +3,14 @@
 use anyhow::Result;
 
 fn main() -> Result<()> {
+    #[cfg(feature = "sqlite_export")]
+    {
+        if std::env::args_os()
+            .nth(1)
+            .map_or(false, |arg| arg == "export")
+        {
+            return electrs::run_export();
+        }
+    }
     electrs::run()
 }
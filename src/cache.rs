@@ -1,16 +1,90 @@
-use bitcoin::{Transaction, Txid};
-use parking_lot::RwLock;
+use bitcoin::{BlockHash, Transaction, Txid};
+use parking_lot::{Mutex, RwLock};
+use serde_json::{json, Value};
 
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::{hash_map::Entry, HashMap, VecDeque};
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc,
+};
 
-use crate::metrics::{self, Histogram, Metrics};
+use crate::{
+    chain::Chain,
+    lock_order::{self, Level},
+    metrics::{self, Counter, Gauge, Histogram, Metrics},
+    protocol::CacheStats,
+};
+
+/// The least recently used blocks' txids are dropped when exceeded
+const MAX_CACHED_BLOCKS: usize = 100;
+
+/// The least recently used verbose transactions are dropped when exceeded
+const MAX_CACHED_TX_INFOS: usize = 1_000;
+
+/// Blocks' txids (e.g. for merkle proofs), which never need invalidation (since a blockhash
+/// commits to its transactions)
+#[derive(Default)]
+struct BlockTxids {
+    txids: HashMap<BlockHash, Arc<Vec<Txid>>>,
+    order: VecDeque<BlockHash>, // least recently used first
+}
+
+impl BlockTxids {
+    fn touch(&mut self, blockhash: BlockHash) {
+        self.order.retain(|hash| *hash != blockhash);
+        self.order.push_back(blockhash);
+    }
+}
+
+/// A verbose transaction, with its confirming block (if any)
+struct TxInfo {
+    info: Value,
+    block: Option<(BlockHash, usize)>, // with its height
+}
+
+impl TxInfo {
+    /// Confirmed transactions' info is valid while their block is in the chain (and their
+    /// `confirmations` are set by the current tip).
+    fn current(&self, chain: &Chain) -> Option<Value> {
+        let mut info = self.info.clone();
+        if let Some((blockhash, height)) = self.block {
+            if chain.get_block_height(&blockhash) != Some(height) {
+                return None; // reorged out
+            }
+            info["confirmations"] = json!(chain.height() - height + 1);
+        }
+        Some(info)
+    }
+}
+
+#[derive(Default)]
+struct TxInfos {
+    infos: HashMap<Txid, TxInfo>,
+    order: VecDeque<Txid>, // least recently used first
+}
+
+impl TxInfos {
+    fn touch(&mut self, txid: Txid) {
+        self.order.retain(|id| *id != txid);
+        self.order.push_back(txid);
+    }
+}
 
 pub(crate) struct Cache {
     txs: Arc<RwLock<HashMap<Txid, Transaction>>>,
+    tx_infos: Mutex<TxInfos>,
+    block_txids: Mutex<BlockTxids>,
 
     // stats
     txs_size: Histogram,
+    txs_current: Gauge,
+    txs_bytes: AtomicUsize, // updated while holding the write lock
+    txs_hits: AtomicU64,
+    txs_misses: AtomicU64,
+    tx_infos_current: Gauge,
+    tx_info_lookups: Counter,
+    block_txids_current: Gauge,
+    block_txids_lookups: Counter,
 }
 
 impl Cache {
@@ -23,21 +97,437 @@ impl Cache {
                 "type",
                 metrics::default_size_buckets(),
             ),
+            txs_current: metrics.gauge(
+                "cache_txs_current",
+                "Currently cached transactions (count and total size in bytes)",
+                "type",
+            ),
+            txs_bytes: AtomicUsize::new(0),
+            txs_hits: AtomicU64::new(0),
+            txs_misses: AtomicU64::new(0),
+            tx_infos: Default::default(),
+            tx_infos_current: metrics.gauge(
+                "cache_tx_infos_current",
+                "Currently cached verbose transactions",
+                "type",
+            ),
+            tx_info_lookups: metrics.counter(
+                "cache_tx_info_lookups",
+                "Verbose transactions looked up in the cache",
+                "result",
+            ),
+            block_txids: Default::default(),
+            block_txids_current: metrics.gauge(
+                "cache_block_txids_current",
+                "Currently cached blocks' txids (count of blocks and of txids)",
+                "type",
+            ),
+            block_txids_lookups: metrics.counter(
+                "cache_block_txids_lookups",
+                "Blocks' txids looked up in the cache",
+                "result",
+            ),
         }
     }
 
+    /// `f` is called without holding the cache lock, and only for non-cached transactions.
     pub fn add_tx(&self, txid: Txid, f: impl FnOnce() -> Transaction) {
-        self.txs.write().entry(txid).or_insert_with(|| {
-            let tx = f();
-            self.txs_size.observe("serialized", tx.size() as f64);
-            tx
-        });
+        if self.contains_tx(&txid) {
+            return;
+        }
+        let tx = f();
+        let size = tx.size();
+        let count = {
+            let _order = lock_order::acquire(Level::Cache);
+            let mut txs = self.txs.write();
+            match txs.entry(txid) {
+                Entry::Occupied(_) => None, // added concurrently
+                Entry::Vacant(e) => {
+                    e.insert(tx);
+                    self.txs_bytes.fetch_add(size, Ordering::Relaxed);
+                    Some(txs.len())
+                }
+            }
+        };
+        if let Some(count) = count {
+            self.txs_size.observe("serialized", size as f64);
+            self.update_current(count);
+        }
+    }
+
+    fn update_current(&self, count: usize) {
+        self.txs_current.set("count", count as f64);
+        self.txs_current
+            .set("bytes", self.txs_bytes.load(Ordering::Relaxed) as f64);
+    }
+
+    pub(crate) fn contains_tx(&self, txid: &Txid) -> bool {
+        let _order = lock_order::acquire(Level::Cache);
+        self.txs.read().contains_key(txid)
     }
 
+    /// `f` is called while holding the cache lock, so it should be short (and not acquire other locks).
     pub fn get_tx<F, T>(&self, txid: &Txid, f: F) -> Option<T>
     where
         F: FnOnce(&Transaction) -> T,
     {
-        self.txs.read().get(txid).map(f)
+        let _order = lock_order::acquire(Level::Cache);
+        let result = self.txs.read().get(txid).map(f);
+        let counter = match result {
+            Some(_) => &self.txs_hits,
+            None => &self.txs_misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        result
+    }
+
+    pub(crate) fn tx_stats(&self) -> CacheStats {
+        let entries = {
+            let _order = lock_order::acquire(Level::Cache);
+            self.txs.read().len()
+        };
+        let hits = self.txs_hits.load(Ordering::Relaxed);
+        let misses = self.txs_misses.load(Ordering::Relaxed);
+        let lookups = hits + misses;
+        CacheStats {
+            entries,
+            bytes: self.txs_bytes.load(Ordering::Relaxed),
+            hits,
+            misses,
+            hit_ratio: if lookups > 0 {
+                Some(hits as f64 / lookups as f64)
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Cache a verbose transaction (with its confirming block and its height, if any), dropping
+    /// the least recently used ones if exceeded. Unconfirmed transactions' info should be dropped
+    /// once they leave the mempool (see `prune_tx_infos()`).
+    pub(crate) fn add_tx_info(&self, txid: Txid, info: Value, block: Option<(BlockHash, usize)>) {
+        let count = {
+            let _order = lock_order::acquire(Level::Cache);
+            let mut tx_infos = self.tx_infos.lock();
+            tx_infos.infos.insert(txid, TxInfo { info, block });
+            tx_infos.touch(txid);
+            while tx_infos.order.len() > MAX_CACHED_TX_INFOS {
+                if let Some(evicted) = tx_infos.order.pop_front() {
+                    tx_infos.infos.remove(&evicted);
+                }
+            }
+            tx_infos.infos.len()
+        };
+        self.tx_infos_current.set("count", count as f64);
+    }
+
+    /// A cached verbose transaction, whose `confirmations` reflect the current tip (unless its
+    /// block was reorged out).
+    pub(crate) fn get_tx_info(&self, txid: &Txid, chain: &Chain) -> Option<Value> {
+        let result = {
+            let _order = lock_order::acquire(Level::Cache);
+            let mut tx_infos = self.tx_infos.lock();
+            let result = tx_infos
+                .infos
+                .get(txid)
+                .and_then(|tx_info| tx_info.current(chain));
+            if result.is_some() {
+                tx_infos.touch(*txid);
+            }
+            result
+        };
+        self.tx_info_lookups
+            .inc(if result.is_some() { "hits" } else { "misses" });
+        result
+    }
+
+    /// Drop the verbose transactions which are no longer valid: the unconfirmed ones which left
+    /// the mempool (since they were confirmed or evicted), and the confirmed ones whose block was
+    /// reorged out. Returns the number of dropped entries.
+    pub(crate) fn prune_tx_infos(
+        &self,
+        chain: &Chain,
+        in_mempool: impl Fn(&Txid) -> bool,
+    ) -> usize {
+        let (pruned, count) = {
+            let _order = lock_order::acquire(Level::Cache);
+            let mut tx_infos = self.tx_infos.lock();
+            let before = tx_infos.infos.len();
+            tx_infos.infos.retain(|txid, tx_info| match tx_info.block {
+                Some((blockhash, height)) => chain.get_block_height(&blockhash) == Some(height),
+                None => in_mempool(txid),
+            });
+            let TxInfos { infos, order } = &mut *tx_infos;
+            order.retain(|txid| infos.contains_key(txid));
+            (before - infos.len(), infos.len())
+        };
+        self.tx_infos_current.set("count", count as f64);
+        pruned
+    }
+
+    /// Drop all cached verbose transactions, returning their number.
+    pub(crate) fn flush_tx_infos(&self) -> usize {
+        let flushed = {
+            let _order = lock_order::acquire(Level::Cache);
+            std::mem::take(&mut *self.tx_infos.lock())
+        }; // deallocated without holding the lock
+        self.tx_infos_current.set("count", 0.0);
+        flushed.infos.len()
+    }
+
+    /// Cache a block's txids, dropping the least recently used blocks' ones if exceeded.
+    pub(crate) fn add_block_txids(&self, blockhash: BlockHash, txids: Arc<Vec<Txid>>) {
+        let _order = lock_order::acquire(Level::Cache);
+        let mut block_txids = self.block_txids.lock();
+        block_txids.txids.insert(blockhash, txids);
+        block_txids.touch(blockhash);
+        while block_txids.order.len() > MAX_CACHED_BLOCKS {
+            if let Some(evicted) = block_txids.order.pop_front() {
+                block_txids.txids.remove(&evicted);
+            }
+        }
+        self.update_block_txids_current(&block_txids);
+    }
+
+    pub(crate) fn get_block_txids(&self, blockhash: &BlockHash) -> Option<Arc<Vec<Txid>>> {
+        let result = {
+            let _order = lock_order::acquire(Level::Cache);
+            let mut block_txids = self.block_txids.lock();
+            let result = block_txids.txids.get(blockhash).map(Arc::clone);
+            if result.is_some() {
+                block_txids.touch(*blockhash);
+            }
+            result
+        };
+        self.block_txids_lookups
+            .inc(if result.is_some() { "hits" } else { "misses" });
+        result
+    }
+
+    /// Drop all cached blocks' txids, returning the number of blocks.
+    pub(crate) fn flush_block_txids(&self) -> usize {
+        let _order = lock_order::acquire(Level::Cache);
+        let mut block_txids = self.block_txids.lock();
+        let flushed = block_txids.txids.len();
+        *block_txids = BlockTxids::default();
+        self.update_block_txids_current(&block_txids);
+        flushed
+    }
+
+    fn update_block_txids_current(&self, block_txids: &BlockTxids) {
+        let txids: usize = block_txids.txids.values().map(|txids| txids.len()).sum();
+        self.block_txids_current
+            .set("blocks", block_txids.txids.len() as f64);
+        self.block_txids_current.set("txids", txids as f64);
+    }
+
+    /// Drop all cached transactions, returning their number. Concurrent `get_tx()` calls finish
+    /// before the cache is flushed (since they hold the read lock), and later ones miss.
+    pub(crate) fn flush_txs(&self) -> usize {
+        let flushed = {
+            let _order = lock_order::acquire(Level::Cache);
+            let mut txs = self.txs.write();
+            self.txs_bytes.store(0, Ordering::Relaxed);
+            std::mem::take(&mut *txs)
+        }; // deallocated without holding the lock
+        self.update_current(0); // cumulative stats (e.g. `cache_txs_size`) are not reset
+        flushed.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cache, CacheStats, MAX_CACHED_BLOCKS, MAX_CACHED_TX_INFOS};
+    use crate::{
+        chain::{Chain, NewHeader},
+        metrics::Metrics,
+    };
+    use bitcoin::{absolute::LockTime, hashes::Hash, BlockHash, Network, Transaction, Txid};
+    use serde_json::json;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    fn make_tx(n: u32) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: LockTime::from_consensus(n),
+            input: vec![],
+            output: vec![],
+        }
+    }
+
+    #[test]
+    fn test_add_tx_once() {
+        let cache = Cache::new(&Metrics::dummy());
+        let txid = Txid::all_zeros();
+        cache.add_tx(txid, || make_tx(1));
+        cache.add_tx(txid, || panic!("transaction is already cached"));
+        assert_eq!(
+            cache.get_tx(&txid, |tx| tx.lock_time),
+            Some(make_tx(1).lock_time)
+        );
+    }
+
+    #[test]
+    fn test_flush() {
+        let cache = Cache::new(&Metrics::dummy());
+        let tx = make_tx(1);
+        let txid = tx.txid();
+        assert_eq!(cache.get_tx(&txid, |_| ()), None);
+        cache.add_tx(txid, || tx);
+        assert_eq!(
+            cache.get_tx(&txid, |tx| tx.lock_time),
+            Some(make_tx(1).lock_time)
+        );
+        assert_eq!(
+            cache.tx_stats(),
+            CacheStats {
+                entries: 1,
+                bytes: 10,
+                hits: 1,
+                misses: 1,
+                hit_ratio: Some(0.5),
+            }
+        );
+
+        assert_eq!(cache.flush_txs(), 1);
+        assert_eq!(cache.get_tx(&txid, |_| ()), None);
+        // lookups' stats are cumulative
+        assert_eq!(
+            cache.tx_stats(),
+            CacheStats {
+                entries: 0,
+                bytes: 0,
+                hits: 1,
+                misses: 2,
+                hit_ratio: Some(1.0 / 3.0),
+            }
+        );
+        cache.add_tx(txid, || make_tx(1)); // re-fetched after flushing
+        assert_eq!(cache.tx_stats().entries, 1);
+    }
+
+    #[test]
+    fn test_tx_info() {
+        let mut chain = Chain::new(Network::Regtest);
+        let genesis = *chain.get_block_header(0).unwrap();
+        let mut header = genesis;
+        for height in 1..=2 {
+            header.prev_blockhash = chain.tip();
+            chain.update(vec![NewHeader::from((header, height))]);
+        }
+        let cache = Cache::new(&Metrics::dummy());
+        let (confirmed, unconfirmed) = (make_tx(1).txid(), make_tx(2).txid());
+        let block = (chain.get_block_hash(1).unwrap(), 1);
+        cache.add_tx_info(confirmed, json!({"confirmations": 1}), Some(block));
+        cache.add_tx_info(unconfirmed, json!({"vsize": 10}), None);
+
+        // confirmations are set by the current tip
+        let info = cache.get_tx_info(&confirmed, &chain).unwrap();
+        assert_eq!(info, json!({"confirmations": 2}));
+        assert_eq!(
+            cache.get_tx_info(&unconfirmed, &chain),
+            Some(json!({"vsize": 10}))
+        );
+
+        // the unconfirmed transaction left the mempool
+        assert_eq!(cache.prune_tx_infos(&chain, |_| false), 1);
+        assert_eq!(cache.get_tx_info(&unconfirmed, &chain), None);
+
+        // the confirming block is reorged out (at the same height)
+        let mut fork = genesis;
+        fork.prev_blockhash = chain.get_block_hash(0).unwrap();
+        fork.time += 1;
+        chain.update(vec![NewHeader::from((fork, 1))]);
+        assert_eq!(cache.get_tx_info(&confirmed, &chain), None);
+        assert_eq!(cache.prune_tx_infos(&chain, |_| true), 1);
+        assert_eq!(cache.flush_tx_infos(), 0);
+    }
+
+    #[test]
+    fn test_tx_infos_bounded() {
+        let chain = Chain::new(Network::Regtest);
+        let cache = Cache::new(&Metrics::dummy());
+        let txid = |n: usize| make_tx(n as u32).txid();
+        for n in 0..MAX_CACHED_TX_INFOS {
+            cache.add_tx_info(txid(n), json!(n), None);
+        }
+        assert_eq!(cache.get_tx_info(&txid(0), &chain), Some(json!(0)));
+
+        // the least recently used transaction is dropped
+        let last = MAX_CACHED_TX_INFOS;
+        cache.add_tx_info(txid(last), json!(last), None);
+        assert!(cache.get_tx_info(&txid(0), &chain).is_some());
+        assert!(cache.get_tx_info(&txid(1), &chain).is_none());
+        assert!(cache.get_tx_info(&txid(last), &chain).is_some());
+
+        // pruned transactions are dropped from the order too
+        let first = txid(0);
+        assert_eq!(cache.prune_tx_infos(&chain, |txid| *txid != first), 1);
+        assert_eq!(cache.tx_infos.lock().order.len(), MAX_CACHED_TX_INFOS - 1);
+        assert_eq!(cache.flush_tx_infos(), MAX_CACHED_TX_INFOS - 1);
+    }
+
+    #[test]
+    fn test_block_txids() {
+        let cache = Cache::new(&Metrics::dummy());
+        let blockhash = |n: u8| BlockHash::from_byte_array([n; 32]);
+        let txids = Arc::new(vec![make_tx(1).txid()]);
+        for n in 0..MAX_CACHED_BLOCKS as u8 {
+            cache.add_block_txids(blockhash(n), Arc::clone(&txids));
+        }
+        assert_eq!(
+            cache.get_block_txids(&blockhash(0)),
+            Some(Arc::clone(&txids))
+        );
+
+        // the least recently used block is dropped
+        cache.add_block_txids(blockhash(200), Arc::clone(&txids));
+        assert!(cache.get_block_txids(&blockhash(0)).is_some());
+        assert!(cache.get_block_txids(&blockhash(1)).is_none());
+        assert!(cache.get_block_txids(&blockhash(200)).is_some());
+
+        assert_eq!(cache.flush_block_txids(), MAX_CACHED_BLOCKS);
+        assert!(cache.get_block_txids(&blockhash(0)).is_none());
+    }
+
+    #[test]
+    fn test_concurrent_access_latency() {
+        const MAX_LATENCY: Duration = Duration::from_secs(1);
+        let cache = Arc::new(Cache::new(&Metrics::dummy()));
+
+        // one thread keeps adding transactions (e.g. a subscription storm),
+        // while another keeps querying them (e.g. get_history)
+        let writer = {
+            let cache = Arc::clone(&cache);
+            std::thread::spawn(move || {
+                let mut max_latency = Duration::default();
+                for n in 0..10_000 {
+                    let tx = make_tx(n);
+                    let start = Instant::now();
+                    cache.add_tx(tx.txid(), || {
+                        std::thread::sleep(Duration::from_micros(10)); // e.g. cloning a large tx
+                        tx
+                    });
+                    max_latency = std::cmp::max(max_latency, start.elapsed());
+                }
+                max_latency
+            })
+        };
+        let reader = {
+            let cache = Arc::clone(&cache);
+            std::thread::spawn(move || {
+                let mut max_latency = Duration::default();
+                for n in 0..10_000 {
+                    let txid = make_tx(n).txid();
+                    let start = Instant::now();
+                    cache.get_tx(&txid, |tx| tx.version);
+                    max_latency = std::cmp::max(max_latency, start.elapsed());
+                }
+                max_latency
+            })
+        };
+        assert!(writer.join().unwrap() < MAX_LATENCY);
+        assert!(reader.join().unwrap() < MAX_LATENCY);
     }
 }
@@ -0,0 +1,72 @@
+use anyhow::Result;
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::{error, fmt};
+
+/// Returned by abandoned work, whose client has disconnected (so it won't read the response)
+#[derive(Debug)]
+pub(crate) struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cancelled due to client disconnection")
+    }
+}
+
+impl error::Error for Cancelled {}
+
+/// Set when the client's peer is removed or its responses can't be sent (but not when it just
+/// stops sending requests), and checked (at reasonable intervals) by the long-running RPC handlers.
+#[derive(Clone, Default)]
+pub(crate) struct CancelToken {
+    flag: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub(crate) fn cancel(&self) {
+        self.flag.store(true, Ordering::Relaxed)
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::Relaxed)
+    }
+
+    /// Whether both tokens are clones of the same one (i.e. belong to the same client)
+    pub(crate) fn same_as(&self, other: &CancelToken) -> bool {
+        Arc::ptr_eq(&self.flag, &other.flag)
+    }
+
+    pub(crate) fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            Err(Cancelled.into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+pub(crate) fn is_cancelled(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| cause.is::<Cancelled>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_cancelled, CancelToken};
+    use anyhow::Context;
+
+    #[test]
+    fn test_cancel() {
+        let token = CancelToken::default();
+        let handler_token = token.clone();
+        assert!(handler_token.check().is_ok());
+
+        token.cancel();
+        assert!(handler_token.is_cancelled());
+        let err = handler_token.check().context("history").unwrap_err();
+        assert!(is_cancelled(&err));
+        assert!(!is_cancelled(&anyhow!("failed")));
+    }
+}
@@ -1,4 +1,5 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::ops::Range;
 
 use bitcoin::blockdata::block::Header as BlockHeader;
 use bitcoin::network::constants;
@@ -29,10 +30,17 @@ impl NewHeader {
     }
 }
 
+/// Number of recently orphaned headers to keep, so they can still be resolved during a reorg
+const MAX_STALE_HEADERS: usize = 100;
+
+/// Number of blocks used for computing the median time past (BIP113)
+const MEDIAN_TIME_SPAN: usize = 11;
+
 /// Current blockchain headers' list
 pub struct Chain {
     headers: Vec<(BlockHash, BlockHeader)>,
     heights: HashMap<BlockHash, usize>,
+    stale: VecDeque<(BlockHash, BlockHeader, usize)>, // orphaned headers (and heights), oldest first
 }
 
 impl Chain {
@@ -43,6 +51,7 @@ impl Chain {
         Self {
             headers: vec![(genesis_hash, genesis.header)],
             heights: std::iter::once((genesis_hash, 0)).collect(), // genesis header @ zero height
+            stale: VecDeque::new(),
         }
     }
 
@@ -88,6 +97,70 @@ impl Chain {
         self.headers.get(height).map(|(_hash, header)| header)
     }
 
+    /// Get the header (and height) of a block in the best chain.
+    /// Since block heights are already mapped by hash, this requires no additional memory.
+    pub(crate) fn get_block_header_by_hash(
+        &self,
+        blockhash: &BlockHash,
+    ) -> Option<(usize, &BlockHeader)> {
+        let height = self.get_block_height(blockhash)?;
+        Some((height, &self.headers[height].1))
+    }
+
+    /// Median time of the last 11 blocks, up to the specified height (the same as bitcoind's)
+    pub(crate) fn get_median_time(&self, height: usize) -> Option<u32> {
+        let first = height.saturating_sub(MEDIAN_TIME_SPAN - 1);
+        let mut times: Vec<u32> = self
+            .headers
+            .get(first..=height)?
+            .iter()
+            .map(|(_, header)| header.time)
+            .collect();
+        times.sort_unstable();
+        Some(times[times.len() / 2])
+    }
+
+    /// Get the header (and former height) of a recently orphaned block.
+    pub(crate) fn get_stale_header(&self, blockhash: &BlockHash) -> Option<(usize, &BlockHeader)> {
+        self.stale
+            .iter()
+            .rev()
+            .find(|(hash, _, _)| hash == blockhash)
+            .map(|(_, header, height)| (*height, header))
+    }
+
+    /// Height of the latest block shared by the best chain and the given block's ancestry, i.e. the
+    /// block's own height if it is in the best chain, or the fork point of a recently orphaned one
+    /// (`None` if the block is unknown, or orphaned too long ago).
+    pub(crate) fn fork_height(&self, blockhash: &BlockHash) -> Option<usize> {
+        let mut blockhash = *blockhash;
+        loop {
+            if let Some(height) = self.get_block_height(&blockhash) {
+                return Some(height);
+            }
+            let (_height, header) = self.get_stale_header(&blockhash)?;
+            blockhash = header.prev_blockhash;
+        }
+    }
+
+    /// Get the best chain's headers (and heights) within the specified height range
+    pub(crate) fn get_block_headers(
+        &self,
+        heights: Range<usize>,
+    ) -> impl Iterator<Item = (usize, &BlockHeader)> + '_ {
+        let start = heights.start.min(self.headers.len());
+        let end = heights.end.max(start).min(self.headers.len());
+        self.headers[start..end]
+            .iter()
+            .zip(start..)
+            .map(|((_hash, header), height)| (height, header))
+    }
+
+    /// Recently orphaned blocks' hashes and former heights (oldest first)
+    pub(crate) fn stale_blocks(&self) -> impl Iterator<Item = (BlockHash, usize)> + '_ {
+        self.stale.iter().map(|(hash, _, height)| (*hash, *height))
+    }
+
     /// Get the block height given the specified hash (if exists)
     pub(crate) fn get_block_height(&self, blockhash: &BlockHash) -> Option<usize> {
         self.heights.get(blockhash).copied()
@@ -96,8 +169,9 @@ impl Chain {
     /// Update the chain with a list of new headers (possibly a reorg)
     pub(crate) fn update(&mut self, headers: Vec<NewHeader>) {
         if let Some(first_height) = headers.first().map(|h| h.height) {
-            for (hash, _header) in self.headers.drain(first_height..) {
+            for ((hash, header), height) in self.headers.drain(first_height..).zip(first_height..) {
                 assert!(self.heights.remove(&hash).is_some());
+                self.stale.push_back((hash, header, height));
             }
             for (h, height) in headers.into_iter().zip(first_height..) {
                 assert_eq!(h.height, height);
@@ -105,6 +179,12 @@ impl Chain {
                 assert!(self.heights.insert(h.hash, h.height).is_none());
                 self.headers.push((h.hash, h.header));
             }
+            let heights = &self.heights;
+            self.stale
+                .retain(|(hash, _, _)| !heights.contains_key(hash));
+            while self.stale.len() > MAX_STALE_HEADERS {
+                self.stale.pop_front();
+            }
             info!(
                 "chain updated: tip={}, height={}",
                 self.headers.last().unwrap().0,
@@ -123,6 +203,13 @@ impl Chain {
         self.headers.len() - 1
     }
 
+    /// Best block height, hash and header - read together, so they always match each other
+    /// (unlike separate `height()` and `get_block_header()` calls around a chain update).
+    pub(crate) fn tip_header(&self) -> (usize, BlockHash, BlockHeader) {
+        let (hash, header) = self.headers.last().expect("empty chain");
+        (self.headers.len() - 1, *hash, *header)
+    }
+
     /// List of block hashes for efficient fork detection and block/header sync
     /// see https://en.bitcoin.it/wiki/Protocol_documentation#getblocks
     pub(crate) fn locator(&self) -> Vec<BlockHash> {
@@ -145,10 +232,11 @@ impl Chain {
 
 #[cfg(test)]
 mod tests {
-    use super::{Chain, NewHeader};
+    use super::{Chain, NewHeader, MAX_STALE_HEADERS};
     use bitcoin::blockdata::block::Header as BlockHeader;
     use bitcoin::consensus::deserialize;
     use bitcoin::network::constants::Network::Regtest;
+    use bitcoin::{hashes::Hash, BlockHash};
     use hex_lit::hex;
 
     #[test]
@@ -252,5 +340,141 @@ hex!("000000200030d7f9c11ef35b89a0eefb9a5e449909339b5e7854d99804ea8d6a49bf900a03
                 .parse()
                 .unwrap()
         );
+
+        // test lookups by hash
+        let old_header = headers.last().unwrap();
+        let old_hash = old_header.block_hash();
+        assert_eq!(
+            regtest.get_block_header_by_hash(&new_header.block_hash()),
+            Some((height, &new_header))
+        );
+        assert_eq!(regtest.get_block_header_by_hash(&old_hash), None);
+        assert_eq!(
+            regtest.get_stale_header(&old_hash),
+            Some((height, old_header))
+        );
+        assert_eq!(regtest.get_stale_header(&new_header.block_hash()), None);
+
+        // re-connected headers are not stale
+        regtest.update(vec![NewHeader::from((*old_header, height))]);
+        assert_eq!(regtest.get_stale_header(&old_hash), None);
+        assert_eq!(
+            regtest.get_stale_header(&new_header.block_hash()),
+            Some((height, &new_header))
+        );
+        assert_eq!(
+            regtest.get_block_header_by_hash(&old_hash),
+            Some((height, old_header))
+        );
+    }
+
+    #[test]
+    fn test_median_time() {
+        let mut regtest = Chain::new(Regtest);
+        let genesis = *regtest.get_block_header(0).unwrap();
+        assert_eq!(regtest.get_median_time(0), Some(genesis.time));
+        assert_eq!(regtest.get_median_time(1), None);
+
+        // timestamps don't have to be monotonic
+        let offsets = [10, 5, 20, 15, 30, 25, 40, 35, 50, 45, 60, 55];
+        let mut header = genesis;
+        let new_headers = offsets
+            .iter()
+            .zip(1..)
+            .map(|(offset, height)| {
+                header.prev_blockhash = header.block_hash();
+                header.time = genesis.time + offset;
+                NewHeader::from((header, height))
+            })
+            .collect();
+        regtest.update(new_headers);
+        assert_eq!(regtest.get_median_time(2), Some(genesis.time + 5));
+        // the median of the 11 blocks at heights 2..=12
+        assert_eq!(regtest.get_median_time(12), Some(genesis.time + 35));
+    }
+
+    #[test]
+    fn test_stale_headers_limit() {
+        let mut regtest = Chain::new(Regtest);
+        let mut header = *regtest.get_block_header(0).unwrap();
+        header.prev_blockhash = regtest.tip();
+        let mut hashes = vec![];
+        for nonce in 0..(MAX_STALE_HEADERS as u32 + 10) {
+            header.nonce = nonce;
+            hashes.push(header.block_hash());
+            regtest.update(vec![NewHeader::from((header, 1))]); // replace the previous tip
+        }
+        let tip = hashes.pop().unwrap();
+        assert_eq!(regtest.tip(), tip);
+        assert_eq!(regtest.get_stale_header(&tip), None);
+        let (evicted, kept) = hashes.split_at(hashes.len() - MAX_STALE_HEADERS);
+        assert!(evicted
+            .iter()
+            .all(|hash| regtest.get_stale_header(hash).is_none()));
+        assert!(kept
+            .iter()
+            .all(|hash| regtest.get_stale_header(hash).map(|(height, _)| height) == Some(1)));
+    }
+
+    #[test]
+    fn test_tip_header_after_shrink() {
+        let mut regtest = Chain::new(Regtest);
+        let mut header = *regtest.get_block_header(0).unwrap();
+        let new_headers = (1..=5)
+            .map(|height| {
+                header.prev_blockhash = header.block_hash();
+                NewHeader::from((header, height))
+            })
+            .collect();
+        regtest.update(new_headers);
+        let (height, tip, tip_header) = regtest.tip_header();
+        assert_eq!((height, tip), (5, tip_header.block_hash()));
+
+        // the chain shrinks between reading its height and fetching the header
+        regtest.drop_last_headers(2);
+        assert_eq!(regtest.get_block_header(height), None);
+        let (height, tip, tip_header) = regtest.tip_header();
+        assert_eq!(height, 3);
+        assert_eq!(tip, regtest.tip());
+        assert_eq!(Some(&tip_header), regtest.get_block_header(height));
+    }
+
+    #[test]
+    fn test_fork_height() {
+        let mut regtest = Chain::new(Regtest);
+        let genesis = *regtest.get_block_header(0).unwrap();
+        let new_headers = |prev_blockhash, first_height, nonce| {
+            let mut header = genesis;
+            header.nonce = nonce;
+            header.prev_blockhash = prev_blockhash;
+            (first_height..=4)
+                .map(|height| {
+                    let new_header = NewHeader::from((header, height));
+                    header.prev_blockhash = new_header.hash();
+                    new_header
+                })
+                .collect::<Vec<_>>()
+        };
+        regtest.update(new_headers(regtest.tip(), 1, 0));
+        let old_tip = regtest.tip();
+        assert_eq!(regtest.fork_height(&old_tip), Some(4));
+
+        // replace the last 2 blocks
+        let fork = regtest.get_block_hash(2).unwrap();
+        regtest.update(new_headers(fork, 3, 1));
+        assert_eq!(regtest.height(), 4);
+        assert_eq!(regtest.fork_height(&old_tip), Some(2));
+        assert_eq!(regtest.fork_height(&BlockHash::all_zeros()), None);
+
+        let heights: Vec<usize> = regtest.get_block_headers(3..5).map(|(h, _)| h).collect();
+        assert_eq!(heights, vec![3, 4]);
+        assert_eq!(
+            regtest
+                .get_block_headers(3..10)
+                .last()
+                .map(|(_, h)| h.block_hash()),
+            Some(regtest.tip())
+        );
+        assert_eq!(regtest.get_block_headers(10..20).count(), 0);
     }
 }
@@ -0,0 +1,87 @@
+use std::time::Instant;
+
+#[cfg(test)]
+use std::{
+    convert::TryFrom,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Monotonic time source for TTLs and expiries, so wall-clock jumps (e.g. NTP corrections) don't
+/// expire (or extend) them. Wall-clock time should only be used for timestamps sent to peers or
+/// persisted to disk.
+#[derive(Clone, Default)]
+pub(crate) struct Clock {
+    #[cfg(test)]
+    mock: Option<Arc<MockTime>>,
+}
+
+#[cfg(test)]
+struct MockTime {
+    start: Instant,
+    elapsed_nanos: AtomicU64,
+}
+
+impl Clock {
+    #[cfg(not(test))]
+    pub(crate) fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn now(&self) -> Instant {
+        match &self.mock {
+            Some(mock) => {
+                mock.start + Duration::from_nanos(mock.elapsed_nanos.load(Ordering::SeqCst))
+            }
+            None => Instant::now(),
+        }
+    }
+
+    /// A clock which only moves when advanced (shared by its clones)
+    #[cfg(test)]
+    pub(crate) fn mock() -> Self {
+        let mock = MockTime {
+            start: Instant::now(),
+            elapsed_nanos: AtomicU64::new(0),
+        };
+        Self {
+            mock: Some(Arc::new(mock)),
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn advance(&self, duration: Duration) {
+        let mock = self.mock.as_ref().expect("not a mock clock");
+        let nanos = u64::try_from(duration.as_nanos()).expect("duration too long");
+        mock.elapsed_nanos.fetch_add(nanos, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Clock;
+    use std::time::Duration;
+
+    #[test]
+    fn test_mock() {
+        let clock = Clock::mock();
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+
+        let other = clock.clone();
+        other.advance(Duration::from_secs(7200));
+        assert_eq!(clock.now() - start, Duration::from_secs(7200));
+        assert_eq!(other.now(), clock.now());
+    }
+
+    #[test]
+    fn test_system() {
+        let clock = Clock::default();
+        let start = clock.now();
+        assert!(clock.now() >= start);
+    }
+}
@@ -0,0 +1,882 @@
+use anyhow::{Context, Result};
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde_json::Value;
+
+use std::fmt;
+use std::io::{self, BufRead};
+
+use crate::sanitize::Sanitized;
+
+/// Larger CBOR frames are rejected (before being read)
+const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// The wire encoding of a connection's messages: newline-delimited JSON by default, or CBOR
+/// frames (each one prefixed by its length, as a big-endian `u32`) after switching via
+/// `server.codec`. The messages' logical structure is the same in both cases.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Codec {
+    Json,
+    Cbor,
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Codec::Json
+    }
+}
+
+impl Codec {
+    /// Read the next incoming frame (or `None` if the connection is closed). At most `limit` bytes
+    /// (and the line delimiter) are buffered: longer frames are skipped, as `Frame::Oversized`.
+    pub(crate) fn read(self, reader: &mut impl BufRead, limit: usize) -> io::Result<Option<Frame>> {
+        match self {
+            Codec::Json => {
+                let mut line = Vec::new();
+                let max_len = limit.saturating_add(2) as u64; // including the "\r\n"
+                if reader.by_ref().take(max_len).read_until(b'\n', &mut line)? == 0 {
+                    return Ok(None);
+                }
+                if line.last() != Some(&b'\n') && line.len() as u64 == max_len {
+                    let skipped = skip_line(reader)?;
+                    return Ok(Some(Frame::Oversized(line.len() + skipped)));
+                }
+                if line.last() == Some(&b'\n') {
+                    line.pop();
+                    if line.last() == Some(&b'\r') {
+                        line.pop();
+                    }
+                }
+                let line = String::from_utf8(line).map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "stream did not contain valid UTF-8",
+                    )
+                })?;
+                Ok(Some(Frame::Json(line)))
+            }
+            Codec::Cbor => {
+                let mut len = [0u8; 4];
+                match reader.read_exact(&mut len) {
+                    Ok(()) => (),
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                    Err(e) => return Err(e),
+                }
+                let len = u32::from_be_bytes(len) as usize;
+                if len > MAX_FRAME_LEN {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("CBOR frame is too large: {} bytes", len),
+                    ));
+                }
+                if len > limit {
+                    let skipped = io::copy(&mut reader.by_ref().take(len as u64), &mut io::sink())?;
+                    if skipped < len as u64 {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    return Ok(Some(Frame::Oversized(len)));
+                }
+                let mut payload = vec![0u8; len];
+                reader.read_exact(&mut payload)?;
+                Ok(Some(Frame::Cbor(payload)))
+            }
+        }
+    }
+
+    /// Encode an outgoing message (including its delimiter or length prefix)
+    pub(crate) fn encode(self, value: &Value) -> Vec<u8> {
+        match self {
+            Codec::Json => {
+                let mut bytes = serde_json::to_vec(value).expect("failed to encode JSON");
+                bytes.push(b'\n');
+                bytes
+            }
+            Codec::Cbor => {
+                let mut payload = Vec::new();
+                ciborium::ser::into_writer(value, &mut payload).expect("failed to encode CBOR");
+                let mut bytes = Vec::with_capacity(4 + payload.len());
+                bytes.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+                bytes.extend(payload);
+                bytes
+            }
+        }
+    }
+}
+
+/// Skip the rest of the current line (including its newline), returning the skipped length.
+fn skip_line(reader: &mut impl BufRead) -> io::Result<usize> {
+    let mut skipped = 0;
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(skipped); // the connection is closed
+        }
+        match buf.iter().position(|b| *b == b'\n') {
+            Some(pos) => {
+                reader.consume(pos + 1);
+                return Ok(skipped + pos);
+            }
+            None => {
+                let len = buf.len();
+                reader.consume(len);
+                skipped += len;
+            }
+        }
+    }
+}
+
+/// Deserialize a CBOR payload, also returning whether an error is due to an unexpected structure
+/// (rather than malformed CBOR).
+fn from_cbor<T: DeserializeOwned>(payload: &[u8]) -> std::result::Result<T, (bool, anyhow::Error)> {
+    ciborium::de::from_reader(payload).map_err(|err| {
+        let is_data = matches!(err, ciborium::de::Error::Semantic(..));
+        (is_data, anyhow!("{:?}", err))
+    })
+}
+
+/// An incoming message (without its delimiter or length prefix)
+pub(crate) enum Frame {
+    Json(String),
+    Cbor(Vec<u8>),
+    Oversized(usize), // skipped, having at least this size
+}
+
+/// A frame which couldn't be deserialized
+pub(crate) enum DecodeError {
+    Malformed(anyhow::Error),  // invalid JSON (or CBOR)
+    Unexpected(anyhow::Error), // well-formed, but of an unexpected structure
+}
+
+impl Frame {
+    pub(crate) fn decode(&self) -> Result<Value> {
+        match self {
+            Frame::Json(line) => serde_json::from_str(line).context("invalid JSON"),
+            Frame::Cbor(payload) => {
+                from_cbor(payload).map_err(|(_, err)| err.context("invalid CBOR"))
+            }
+            Frame::Oversized(size) => bail!("request is too large: {} bytes", size),
+        }
+    }
+
+    /// Deserialize the frame directly (without building an intermediate `Value`), while
+    /// distinguishing malformed frames from unexpected structures (as `decode()` would).
+    pub(crate) fn parse<T: DeserializeOwned>(&self) -> std::result::Result<T, DecodeError> {
+        let (is_data, err, format) = match self {
+            Frame::Json(line) => match serde_json::from_str(line) {
+                Ok(value) => return Ok(value),
+                Err(err) => (err.is_data(), anyhow::Error::new(err), "JSON"),
+            },
+            Frame::Cbor(payload) => match from_cbor(payload) {
+                Ok(value) => return Ok(value),
+                Err((is_data, err)) => (is_data, err, "CBOR"),
+            },
+            Frame::Oversized(_) => return Err(DecodeError::Malformed(self.decode().unwrap_err())),
+        };
+        // an unexpected structure may be detected before a syntax error later in the frame
+        if is_data && self.is_well_formed() {
+            Err(DecodeError::Unexpected(err))
+        } else {
+            Err(DecodeError::Malformed(
+                err.context(format!("invalid {}", format)),
+            ))
+        }
+    }
+
+    fn is_well_formed(&self) -> bool {
+        match self {
+            Frame::Json(line) => serde_json::from_str::<IgnoredAny>(line).is_ok(),
+            // CBOR may contain values which have no JSON counterpart (e.g. byte strings)
+            Frame::Cbor(_) => self.decode().is_ok(),
+            Frame::Oversized(_) => false,
+        }
+    }
+
+    /// The frame's size in bytes (without its delimiter or length prefix)
+    pub(crate) fn size(&self) -> usize {
+        match self {
+            Frame::Json(line) => line.len(),
+            Frame::Cbor(payload) => payload.len(),
+            Frame::Oversized(size) => *size,
+        }
+    }
+
+    pub(crate) fn as_json(&self) -> Option<&str> {
+        match self {
+            Frame::Json(line) => Some(line),
+            Frame::Cbor(_) | Frame::Oversized(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Frame::Json(line) => write!(f, "{}", Sanitized(line)),
+            Frame::Cbor(payload) => write!(f, "<{} CBOR bytes>", payload.len()),
+            Frame::Oversized(size) => write!(f, "<{} bytes (skipped)>", size),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Codec, DecodeError, Frame, MAX_FRAME_LEN};
+    use crate::protocol::{
+        BalanceTotals, BanInfo, BlockTxidsSubscribeResult, BuildRbfResult, CacheFlushResult,
+        CacheStats, CacheStatsResult, CborCodec, ChainedProof, DecodedHeader, DescriptorInfoResult,
+        ExportedProof, Extensions, FeaturesResult, FeeEstimate, FeeHistogramResult,
+        GetBalanceResponse, HeaderByHashResult, HeadersResult, HeadersSubscribeResult, HealthFlags,
+        HealthReport, HistoryDeltas, HistoryEntryWire, IdFromPosResult, IdleUnsubscribe,
+        MempoolFees, MempoolInfoResult, MerkleResult, MirrorStats, NotificationsDebounce,
+        OrphanedEntry, OutPointStatusResult, PrefixMatch, ProofsExportResult, PsbtAnalysis,
+        PsbtInputAnalysis, PsbtSpender, RequestLimits, ScriptHash, SearchPrefixResult,
+        SelectStrategy, SelectUnspentResult, ServerInfoResult, SpenderResult, StaleUnspentResult,
+        TransactionsGetEntry, UnspentEntryWire, VerboseHistoryResult, WebhookStats,
+    };
+    use bitcoin::{hash_types::TxMerkleNode, hashes::Hash, Amount, BlockHash, ScriptBuf, Txid};
+    use serde_json::{json, Value};
+    use std::io::{BufReader, Cursor};
+    use std::time::Instant;
+
+    const TXID: &str = "5b75086dafeede555fc8f9a810d8b10df57c46f9f176ccc3dd8d2fa20edd685b";
+    const HEADER: &str = "0000002000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4adae5494dffff7f2002000000";
+    const TX: &str = "0200000000000000";
+
+    fn response(result: Value) -> Value {
+        json!({"jsonrpc": "2.0", "id": 1, "result": result})
+    }
+
+    fn txid() -> Txid {
+        TXID.parse().unwrap()
+    }
+
+    fn block_hash() -> BlockHash {
+        TXID.parse().unwrap()
+    }
+
+    fn node() -> TxMerkleNode {
+        TXID.parse().unwrap()
+    }
+
+    fn history(len: usize) -> Value {
+        let entries: Vec<HistoryEntryWire> = (0..len)
+            .map(|i| HistoryEntryWire {
+                tx_hash: txid(),
+                height: 700_000 + i as i64,
+                fee: None,
+                tx_pos: None,
+                reorged_from: None,
+            })
+            .collect();
+        json!(entries)
+    }
+
+    /// Each method's result, built from the types serialized by its handler (responses' shapes
+    /// don't depend on the codec)
+    fn responses() -> Vec<(&'static str, Value)> {
+        let utxo = UnspentEntryWire {
+            height: 1,
+            tx_hash: txid(),
+            tx_pos: 0,
+            value: 5_000_000_000,
+            spent_in_mempool: false,
+            script_pubkey: Some(ScriptBuf::from(vec![0x51])),
+            ancestor_count: None,
+            ancestor_size: None,
+            spendable_now: None,
+            unconfirmed: Some(false),
+        };
+        let unconfirmed = HistoryEntryWire {
+            tx_hash: txid(),
+            height: -1,
+            fee: Some(1000),
+            tx_pos: Some(None),
+            reorged_from: None,
+        };
+        let proof = ChainedProof {
+            txid: txid(),
+            height: 1,
+            header: HEADER.to_owned(),
+            tx_pos: 0,
+            tx_branch: vec![node()],
+            cp_height: 2,
+            header_branch: vec![node()],
+            cp_root: node(),
+        };
+        let extensions = Extensions {
+            error_codes: crate::errors::registry(),
+            idle_unsubscribe: Some(IdleUnsubscribe {
+                idle_secs: 600,
+                notification: "blockchain.scripthash.unsubscribed".to_owned(),
+            }),
+            cbor_codec: Some(CborCodec {
+                method: "server.codec".to_owned(),
+                length_prefix: "u32be".to_owned(),
+            }),
+            history_deltas: HistoryDeltas {
+                method: "server.history_deltas".to_owned(),
+                max_entries: 100,
+            },
+            notifications_debounce: NotificationsDebounce {
+                method: "server.notifications.debounce".to_owned(),
+                max_ms: 10_000,
+            },
+            request_limits: RequestLimits {
+                max_batch_calls: 1000,
+                max_request_bytes: 1_000_000,
+            },
+        };
+        vec![
+            ("blockchain.block.header", json!(HEADER)),
+            (
+                "blockchain.block.header_by_hash",
+                json!(HeaderByHashResult {
+                    height: 1,
+                    hex: HEADER.to_owned(),
+                    stale: false,
+                }),
+            ),
+            (
+                "blockchain.block.headers",
+                json!(HeadersResult {
+                    count: 2,
+                    hex: HEADER.repeat(2),
+                    max: 2016,
+                    branch: Some(vec![node(), node()]),
+                    root: Some(node()),
+                }),
+            ),
+            (
+                "blockchain.block.txids.subscribe",
+                json!(BlockTxidsSubscribeResult {
+                    height: 1,
+                    hash: block_hash(),
+                }),
+            ),
+            ("blockchain.estimatefee", json!(FeeEstimate(Some(0.25)))),
+            (
+                "blockchain.headers.subscribe",
+                json!(HeadersSubscribeResult {
+                    hex: HEADER.to_owned(),
+                    height: 1,
+                    decoded: Some(DecodedHeader {
+                        version: 0x2000_0000,
+                        previousblockhash: block_hash(),
+                        merkleroot: node(),
+                        time: 1_296_688_602,
+                        mediantime: Some(1_296_688_602),
+                        bits: "207fffff".to_owned(),
+                        nonce: 2,
+                    }),
+                }),
+            ),
+            (
+                "blockchain.outpoint.get_spender",
+                json!(SpenderResult {
+                    spender_txid: txid(),
+                    height: 0,
+                }),
+            ),
+            (
+                "blockchain.outpoint.subscribe",
+                json!(OutPointStatusResult {
+                    height: Some(1),
+                    spender_txhash: Some(txid()),
+                    spender_height: Some(-1),
+                }),
+            ),
+            ("blockchain.outpoint.unsubscribe", json!(true)),
+            (
+                "blockchain.psbt.analyze",
+                json!(PsbtAnalysis {
+                    txid: txid(),
+                    inputs: vec![PsbtInputAnalysis {
+                        outpoint: format!("{}:0", TXID),
+                        value: Some(Amount::from_sat(5_000_000_000)),
+                        has_witness_utxo: true,
+                        has_non_witness_utxo: false,
+                        finalized: false,
+                        status: "spent".to_owned(),
+                        confirmations: Some(6),
+                        spent_by: Some(PsbtSpender {
+                            txid: txid(),
+                            height: 0,
+                        }),
+                        warnings: vec!["spent".to_owned()],
+                    }],
+                    input_value: Some(Amount::from_sat(5_000_000_000)),
+                    output_value: Amount::from_sat(4_999_990_000),
+                    fee: Some(Amount::from_sat(10_000)),
+                    vsize: 110,
+                    vsize_final: false,
+                    fee_rate: Some(90.9),
+                    warnings: vec!["spent_inputs".to_owned()],
+                }),
+            ),
+            ("blockchain.relayfee", json!(0.00001)),
+            (
+                "blockchain.scripthash.get_balance",
+                json!(GetBalanceResponse {
+                    confirmed: 5_000_000_000,
+                    unconfirmed: -1000,
+                    stale: false,
+                    height: None,
+                    totals: Some(BalanceTotals {
+                        received: 10_000_000_000,
+                        sent: 5_000_000_000,
+                    }),
+                    as_of_height: Some(1),
+                    diagnostic: None,
+                }),
+            ),
+            ("blockchain.scripthash.get_history", history(3)),
+            (
+                "blockchain.scripthash.get_history_filter",
+                json!(VerboseHistoryResult {
+                    history: vec![unconfirmed.clone()],
+                    stale: false,
+                    height: None,
+                    total: Some(4),
+                    from: Some(1),
+                    to: None,
+                    as_of_height: Some(1),
+                    diagnostic: None,
+                }),
+            ),
+            ("blockchain.scripthash.get_mempool", json!([unconfirmed])),
+            (
+                "blockchain.scripthash.get_orphaned",
+                json!([OrphanedEntry {
+                    tx_hash: txid(),
+                    block_hash: block_hash(),
+                    height: 1,
+                    confirmed_height: None,
+                    in_mempool: true,
+                }]),
+            ),
+            (
+                "blockchain.scripthash.listunspent",
+                json!(StaleUnspentResult {
+                    unspent: vec![utxo.clone()],
+                    stale: true,
+                    height: 1,
+                }),
+            ),
+            ("blockchain.scripthash.release_unspent", json!(2)),
+            (
+                "blockchain.scripthash.select_unspent",
+                json!(SelectUnspentResult {
+                    utxos: vec![utxo.clone(), utxo.clone()],
+                    inputs: 2,
+                    total_value: 10_000_000_000,
+                    input_vsize: Some(136),
+                    input_fee: Some(340),
+                    strategies: Some(vec![SelectStrategy::Bnb]),
+                }),
+            ),
+            ("blockchain.scripthash.unspent_exist", json!(utxo)),
+            ("blockchain.scripthash.subscribe", json!(TXID)),
+            ("blockchain.scripthash.unsubscribe", json!(false)),
+            ("blockchain.transaction.broadcast", json!(TXID)),
+            (
+                "blockchain.transaction.build_rbf",
+                json!(BuildRbfResult {
+                    hex: TX.to_owned(),
+                    change_index: 1,
+                    change_value: Amount::from_sat(18_000),
+                    vsize: 141,
+                    fee_rate: 14.2,
+                    original_fee: Amount::from_sat(1000),
+                    replaced_fee: Amount::from_sat(1000),
+                    incremental_relay_fee: Amount::from_sat(1000),
+                    fee: Amount::from_sat(2000),
+                    additional_fee: Amount::from_sat(1000),
+                }),
+            ),
+            ("blockchain.transaction.get", json!(TX)),
+            (
+                "blockchain.transaction.get_merkle",
+                json!(MerkleResult {
+                    block_height: 1,
+                    pos: 2,
+                    merkle: vec![TXID.to_owned(), TXID.to_owned()],
+                    header_hex: Some(HEADER.to_owned()),
+                }),
+            ),
+            ("blockchain.transaction.subscribe", json!(700_000)),
+            ("blockchain.transaction.unsubscribe", json!(true)),
+            (
+                "blockchain.transaction.mempool_info",
+                json!(MempoolInfoResult {
+                    txid: txid(),
+                    vsize: 141,
+                    fees: MempoolFees {
+                        base: 352,
+                        ancestor: 704,
+                        descendant: 352,
+                    },
+                    ancestor_count: 2,
+                    descendant_count: 1,
+                    fee_rate: 2.5,
+                    vsize_ahead: 250_000,
+                    mempool_min_fee_rate: Some(1.0),
+                    eviction_risk: false,
+                    partial: false,
+                }),
+            ),
+            (
+                "blockchain.transaction.search_prefix",
+                json!(SearchPrefixResult {
+                    matches: vec![PrefixMatch {
+                        tx_hash: txid(),
+                        height: 1,
+                    }],
+                    truncated: false,
+                    collision: false,
+                }),
+            ),
+            (
+                "blockchain.transaction.id_from_pos",
+                json!(IdFromPosResult {
+                    tx_hash: txid(),
+                    merkle: vec![TXID.to_owned()],
+                }),
+            ),
+            (
+                "blockchain.transactions.get",
+                json!(vec![
+                    TransactionsGetEntry {
+                        txid: txid(),
+                        result: Some(json!(TX)),
+                        error: None,
+                    },
+                    TransactionsGetEntry {
+                        txid: txid(),
+                        result: None,
+                        error: Some("transaction not found".to_owned()),
+                    },
+                ]),
+            ),
+            (
+                "mempool.get_fee_histogram",
+                json!(FeeHistogramResult {
+                    histogram: vec![(12, 100_000), (1, 200_000)],
+                    partial: false,
+                }),
+            ),
+            (
+                "proofs.export",
+                json!(ProofsExportResult {
+                    cp_height: 2,
+                    cp_block_hash: Some(block_hash()),
+                    cp_root: node(),
+                    proofs: vec![ExportedProof {
+                        txid: txid(),
+                        height: 1,
+                        proof: Some(proof),
+                        error: None,
+                    }],
+                }),
+            ),
+            ("server.add_peer", json!(true)),
+            ("server.banner", json!("Welcome to electrs")),
+            (
+                "server.bans.list",
+                json!([BanInfo {
+                    ip: "::1".parse().unwrap(),
+                    remaining_secs: 60,
+                    bans: 1,
+                    reason: "parse_error".to_owned(),
+                }]),
+            ),
+            ("server.bans.unban", json!(true)),
+            (
+                "server.cache.flush",
+                json!(CacheFlushResult { tx: 10, txids: 1 }),
+            ),
+            (
+                "server.cache.stats",
+                json!(CacheStatsResult {
+                    tx: CacheStats {
+                        entries: 1,
+                        bytes: 1000,
+                        hits: 3,
+                        misses: 1,
+                        hit_ratio: Some(0.75),
+                    },
+                }),
+            ),
+            ("server.codec", json!("cbor")),
+            ("server.donation_address", Value::Null),
+            (
+                "server.features",
+                json!(FeaturesResult {
+                    genesis_hash: Some(block_hash()),
+                    hosts: json!({"tcp_port": 50001}),
+                    protocol_max: "1.4".to_owned(),
+                    protocol_min: "1.4".to_owned(),
+                    pruning: None,
+                    server_version: "electrs/0.9.13".to_owned(),
+                    hash_function: "sha256".to_owned(),
+                    extensions,
+                }),
+            ),
+            ("server.history_deltas", json!(true)),
+            ("server.notifications.debounce", json!(100)),
+            (
+                "server.info",
+                json!(ServerInfoResult {
+                    server_version: "electrs/0.9.13".to_owned(),
+                    txindex: false,
+                }),
+            ),
+            ("server.orphaned", json!(Vec::<OrphanedEntry>::new())),
+            (
+                "server.peers.subscribe",
+                json!(vec![(
+                    "203.0.113.5".to_owned(),
+                    "a.example.org".to_owned(),
+                    vec!["v1.4".to_owned(), "t".to_owned()],
+                )]),
+            ),
+            ("server.ping", Value::Null),
+            ("server.strict_params", json!(true)),
+            (
+                "server.sync_status",
+                json!(HealthReport {
+                    height: 1,
+                    tip: block_hash(),
+                    flags: HealthFlags {
+                        synced: true,
+                        daemon_reachable: true,
+                        index_writable: true,
+                        mempool_partial: false,
+                        stale: false,
+                        degraded: false,
+                    },
+                    daemon_height: Some(1),
+                    last_sync_secs: Some(0.5),
+                    mempool_txs: 10,
+                    mempool_age_secs: None,
+                    tx_cache_entries: 1,
+                    tx_cache_bytes: 1000,
+                }),
+            ),
+            ("server.version", json!(["electrs/0.9.13", "1.4"])),
+            (
+                "server.webhooks.stats",
+                json!([WebhookStats {
+                    url: "http://127.0.0.1/".to_owned(),
+                    queued: 0,
+                    delivered: 1,
+                    retries: 1,
+                    dead_lettered: 1,
+                    dropped: 0,
+                    dead_letters: vec![json!({"event": "tx", "txid": TXID})],
+                }]),
+            ),
+            (
+                "server.broadcast_mirrors.stats",
+                json!([MirrorStats {
+                    url: "http://127.0.0.1/".to_owned(),
+                    kind: "http".to_owned(),
+                    network: None,
+                    queued: 0,
+                    submitted: 1,
+                    retries: 0,
+                    failed: 0,
+                    dropped: 0,
+                    healthy: true,
+                    last_error: None,
+                }]),
+            ),
+            (
+                "wallet.descriptor.info",
+                json!(DescriptorInfoResult {
+                    descriptor: "wpkh(...)".to_owned(),
+                    checksum: "abcdefgh".to_owned(),
+                    script_type: "wpkh".to_owned(),
+                    has_wildcard: true,
+                    scripthashes: vec![ScriptHash::from_byte_array([1; 32])],
+                }),
+            ),
+        ]
+    }
+
+    fn round_trip(codec: Codec, value: &Value) -> Value {
+        let bytes = codec.encode(value);
+        let mut reader = BufReader::new(Cursor::new(bytes));
+        let frame = codec.read(&mut reader, MAX_FRAME_LEN).unwrap().unwrap();
+        assert!(codec.read(&mut reader, MAX_FRAME_LEN).unwrap().is_none());
+        frame.decode().unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let error = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "error": {"code": 15, "message": "not found", "data": {"txid": TXID}},
+        });
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": "blockchain.scripthash.subscribe",
+            "params": [TXID, null],
+        });
+        let mut values: Vec<Value> = responses()
+            .into_iter()
+            .map(|(_method, result)| response(result))
+            .collect();
+        values.push(error);
+        values.push(notification);
+        for value in values {
+            for codec in [Codec::Json, Codec::Cbor].iter() {
+                assert_eq!(round_trip(*codec, &value), value, "{:?}", codec);
+            }
+        }
+    }
+
+    #[test]
+    fn test_frames() {
+        let mut bytes = Codec::Json.encode(&json!({"id": 1}));
+        bytes.extend(b"{\"id\": 2}\r\n");
+        let cbor = Codec::Cbor.encode(&json!({"id": 3}));
+        assert_eq!(&cbor[..4], &(cbor.len() as u32 - 4).to_be_bytes());
+        bytes.extend(&cbor);
+        bytes.extend(&cbor[..cbor.len() - 1]); // truncated
+
+        let mut reader = BufReader::new(Cursor::new(bytes));
+        let frame = Codec::Json
+            .read(&mut reader, MAX_FRAME_LEN)
+            .unwrap()
+            .unwrap();
+        assert_eq!(frame.as_json(), Some(r#"{"id":1}"#));
+        let frame = Codec::Json
+            .read(&mut reader, MAX_FRAME_LEN)
+            .unwrap()
+            .unwrap();
+        assert_eq!(frame.as_json(), Some(r#"{"id": 2}"#));
+        // the rest of the connection is read as CBOR frames
+        let frame = Codec::Cbor
+            .read(&mut reader, MAX_FRAME_LEN)
+            .unwrap()
+            .unwrap();
+        assert_eq!(frame.as_json(), None);
+        assert_eq!(
+            frame.to_string(),
+            format!("<{} CBOR bytes>", cbor.len() - 4)
+        );
+        assert_eq!(frame.decode().unwrap(), json!({"id": 3}));
+        assert!(Codec::Cbor.read(&mut reader, MAX_FRAME_LEN).is_err());
+
+        let len = (MAX_FRAME_LEN as u32 + 1).to_be_bytes();
+        let mut reader = BufReader::new(Cursor::new(len.to_vec()));
+        assert!(Codec::Cbor.read(&mut reader, MAX_FRAME_LEN).is_err());
+
+        assert!(Frame::Cbor(vec![0xff]).decode().is_err());
+        assert!(Frame::Json("{".to_owned()).decode().is_err());
+    }
+
+    #[test]
+    fn test_oversized() {
+        let limit = 10;
+        let mut bytes = b"[1, 2, 3]\n".to_vec();
+        bytes.extend(b"[1, 2, 3, 4]\r\n");
+        bytes.extend(b"[1]\n");
+        bytes.extend(b"[1, 2, 3, 4, 5, 6]"); // no newline
+        let mut reader = BufReader::new(Cursor::new(bytes));
+        let read = |reader: &mut BufReader<_>| Codec::Json.read(reader, limit).unwrap().unwrap();
+        assert_eq!(read(&mut reader).as_json(), Some("[1, 2, 3]"));
+        let frame = read(&mut reader);
+        assert!(matches!(frame, Frame::Oversized(13))); // including the '\r'
+        assert!(frame.decode().is_err());
+        assert!(matches!(
+            frame.parse::<Value>(),
+            Err(DecodeError::Malformed(_))
+        ));
+        assert_eq!(read(&mut reader).as_json(), Some("[1]")); // the rest of the line is skipped
+        assert!(matches!(read(&mut reader), Frame::Oversized(18)));
+        assert!(Codec::Json.read(&mut reader, limit).unwrap().is_none());
+
+        let small = Codec::Cbor.encode(&json!([1]));
+        let large = Codec::Cbor.encode(&json!([1, 2, 3, 4, 5, 6, 7, 8, 9, 10]));
+        let mut bytes = large.clone();
+        bytes.extend(&small);
+        bytes.extend(&large[..large.len() - 1]); // truncated
+        let mut reader = BufReader::new(Cursor::new(bytes));
+        let frame = Codec::Cbor.read(&mut reader, limit).unwrap().unwrap();
+        assert_eq!(frame.size(), large.len() - 4);
+        assert!(matches!(frame, Frame::Oversized(_)));
+        let frame = Codec::Cbor.read(&mut reader, limit).unwrap().unwrap();
+        assert_eq!(frame.decode().unwrap(), json!([1]));
+        assert!(Codec::Cbor.read(&mut reader, limit).is_err());
+    }
+
+    #[test]
+    fn test_parse() {
+        let json = |line: &str| Frame::Json(line.to_owned());
+        let cbor = |value: Value| Frame::Cbor(Codec::Cbor.encode(&value)[4..].to_vec());
+        let parse = |frame: Frame| frame.parse::<Vec<u32>>();
+
+        assert_eq!(parse(json("[1, 2]")).ok(), Some(vec![1, 2]));
+        assert_eq!(parse(cbor(json!([1, 2]))).ok(), Some(vec![1, 2]));
+
+        let malformed = [
+            json("[1, 2"),
+            json("[1, 2] 3"),
+            json("[\"a\", {"), // the unexpected element precedes the syntax error
+            Frame::Cbor(vec![0xff]),
+            Frame::Cbor(vec![0x41, 0x01]), // a byte string, which has no JSON counterpart
+        ];
+        for frame in malformed.iter() {
+            assert!(
+                matches!(frame.parse::<Vec<u32>>(), Err(DecodeError::Malformed(_))),
+                "{}",
+                frame
+            );
+        }
+
+        let unexpected = [json("{}"), json("[1, \"a\"]"), cbor(json!([1, "a"]))];
+        for frame in unexpected.iter() {
+            assert!(
+                matches!(frame.parse::<Vec<u32>>(), Err(DecodeError::Unexpected(_))),
+                "{}",
+                frame
+            );
+        }
+    }
+
+    /// Compares the CPU time spent encoding and decoding a large response (and a batch of each
+    /// method's response) using each codec:
+    /// `cargo test --release bench_codecs -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_codecs() {
+        let batch: Vec<Value> = responses()
+            .into_iter()
+            .map(|(_method, result)| response(result))
+            .collect();
+        let values = [
+            ("history", response(history(10_000))),
+            ("batch", json!(batch)),
+        ];
+        let iterations = 100u32;
+        for (name, value) in values.iter() {
+            for codec in [Codec::Json, Codec::Cbor].iter() {
+                let start = Instant::now();
+                let mut size = 0;
+                for _ in 0..iterations {
+                    let bytes = codec.encode(value);
+                    size = bytes.len();
+                    let mut reader = BufReader::new(Cursor::new(bytes));
+                    let frame = codec.read(&mut reader, MAX_FRAME_LEN).unwrap().unwrap();
+                    assert_eq!(&frame.decode().unwrap(), value);
+                }
+                let elapsed = start.elapsed() / iterations;
+                println!(
+                    "{} ({:?}): {} bytes, {:?} per round-trip",
+                    name, codec, size, elapsed
+                );
+            }
+        }
+    }
+}
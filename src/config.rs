@@ -2,16 +2,25 @@ use bitcoin::network::constants::{Magic, Network};
 use bitcoincore_rpc::Auth;
 use dirs_next::home_dir;
 
+use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
 use std::fmt;
 use std::net::SocketAddr;
 use std::net::ToSocketAddrs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 use std::env::consts::{ARCH, OS};
 use std::time::Duration;
 
+use crate::audit::AuditLogOptions;
+use crate::config_compat::{self, TranslatedFiles};
+use crate::listener::{Keepalive, ListenerOptions};
+use crate::metrics::{MetricsAuth, MetricsListener, MetricsOptions};
+use crate::mirrors::MirrorOptions;
+use crate::reputation::ReputationOptions;
+use crate::webhooks::WebhookOptions;
+
 pub const ELECTRS_VERSION: &str = env!("CARGO_PKG_VERSION");
 const DEFAULT_SERVER_ADDRESS: [u8; 4] = [127, 0, 0, 1]; // by default, serve on IPv4 localhost
 
@@ -120,32 +129,197 @@ impl From<BitcoinNetwork> for Network {
     }
 }
 
+/// How to serve clients until the initial sync is done
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncServing {
+    /// Disconnect new clients (so they'll use another server)
+    RefuseConnectionsUntilSynced,
+    /// Allow only the RPCs which don't require the index
+    ServeHeadersOnly,
+    /// Also answer scripthash queries from the partially built index (marked as stale)
+    ServeConfirmedDataWithStalenessFlag,
+}
+
+impl Default for SyncServing {
+    fn default() -> Self {
+        SyncServing::ServeHeadersOnly
+    }
+}
+
+impl FromStr for SyncServing {
+    type Err = String;
+
+    fn from_str(string: &str) -> std::result::Result<Self, Self::Err> {
+        match string {
+            "refuse_connections_until_synced" => Ok(SyncServing::RefuseConnectionsUntilSynced),
+            "serve_headers_only" => Ok(SyncServing::ServeHeadersOnly),
+            "serve_confirmed_data_with_staleness_flag" => {
+                Ok(SyncServing::ServeConfirmedDataWithStalenessFlag)
+            }
+            _ => Err(format!("unknown sync serving mode: {}", string)),
+        }
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for SyncServing {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> fmt::Result {
+        write!(
+            writer,
+            "either 'refuse_connections_until_synced', 'serve_headers_only' or 'serve_confirmed_data_with_staleness_flag'"
+        )
+    }
+}
+
+/// Whether config files are translated from the upstream (romanz/electrs) dialect
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigCompat {
+    /// Translate only the files containing upstream-specific options
+    Auto,
+    /// Translate all the files
+    Upstream,
+    /// Don't translate (so upstream-specific options are rejected)
+    #[serde(rename = "none")]
+    Disabled,
+}
+
+impl Default for ConfigCompat {
+    fn default() -> Self {
+        ConfigCompat::Auto
+    }
+}
+
+impl FromStr for ConfigCompat {
+    type Err = String;
+
+    fn from_str(string: &str) -> std::result::Result<Self, Self::Err> {
+        match string {
+            "auto" => Ok(ConfigCompat::Auto),
+            "upstream" => Ok(ConfigCompat::Upstream),
+            "none" => Ok(ConfigCompat::Disabled),
+            _ => Err(format!("unknown config compatibility mode: {}", string)),
+        }
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for ConfigCompat {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> fmt::Result {
+        write!(writer, "either 'auto', 'upstream' or 'none'")
+    }
+}
+
+/// RocksDB tuning preset (see `db::Tuning` for the actual values)
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DbProfile {
+    /// Small caches and write buffers, for machines with little RAM
+    LowMemory,
+    /// Suitable for most servers
+    Balanced,
+    /// Large caches and write buffers, and more compaction threads
+    Throughput,
+}
+
+impl Default for DbProfile {
+    fn default() -> Self {
+        DbProfile::Balanced
+    }
+}
+
+impl FromStr for DbProfile {
+    type Err = String;
+
+    fn from_str(string: &str) -> std::result::Result<Self, Self::Err> {
+        match string {
+            "low_memory" => Ok(DbProfile::LowMemory),
+            "balanced" => Ok(DbProfile::Balanced),
+            "throughput" => Ok(DbProfile::Throughput),
+            _ => Err(format!("unknown DB profile: {}", string)),
+        }
+    }
+}
+
+impl ::configure_me::parse_arg::ParseArgFromStr for DbProfile {
+    fn describe_type<W: fmt::Write>(mut writer: W) -> fmt::Result {
+        write!(writer, "either 'low_memory', 'balanced' or 'throughput'")
+    }
+}
+
 /// Parsed and post-processed configuration
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Config {
     // See below for the documentation of each field:
     pub network: Network,
     pub db_path: PathBuf,
+    pub db_profile: DbProfile,
+    pub db_memory_limit: Option<usize>,
     pub daemon_dir: PathBuf,
     pub daemon_auth: SensitiveAuth,
     pub daemon_rpc_addr: SocketAddr,
     pub daemon_p2p_addr: SocketAddr,
     pub electrum_rpc_addr: SocketAddr,
-    pub monitoring_addr: SocketAddr,
+    pub electrum_admin_addr: Option<SocketAddr>,
+    pub monitoring: MetricsOptions,
     pub wait_duration: Duration,
     pub jsonrpc_timeout: Duration,
+    pub broadcast_idempotency_ttl: Duration,
+    pub select_bnb_tolerance: u64, // in sat
+    pub select_reservation_ttl: Option<Duration>,
+    pub select_max_inputs: usize,
+    pub select_input_padding: bool,
+    pub transactions_batch_limit: usize,
+    pub request_batch_limit: usize,
+    pub request_size_limit: usize,
+    pub status_max_lag_blocks: usize,
+    pub status_cache_ttl: Option<Duration>,
+    pub status_cache_capacity: usize,
+    pub daemon_startup_timeout: Option<Duration>,
     pub index_batch_size: usize,
     pub index_lookup_limit: Option<usize>,
+    pub max_history_len: Option<usize>,
     pub reindex_last_blocks: usize,
     pub auto_reindex: bool,
+    pub force_reindex_on_mismatch: bool,
     pub ignore_mempool: bool,
+    pub serve_stale_without_daemon: bool,
+    pub block_txids_subscription: bool,
+    pub txid_prefix_index: bool,
+    pub block_txids_index: bool,
+    pub spender_index: bool,
+    pub cache_management_rpc: bool,
+    pub proofs_export_rpc: bool,
+    pub cbor_codec: bool,
+    pub strict_params: bool,
+    pub legacy_id_from_pos: bool,
+    pub client_memory_budget: Option<usize>,
+    pub scripthash_subscriptions_limit: Option<usize>,
+    pub subscription_idle_timeout: Option<Duration>,
+    pub outpoint_subscriptions_limit: usize,
+    pub transaction_subscriptions_limit: usize,
+    pub transaction_subscription_confirmations: Option<usize>,
+    pub sync_serving: SyncServing,
     pub sync_once: bool,
     pub disable_electrum_rpc: bool,
+    pub electrum_listener: ListenerOptions,
     pub server_banner: String,
+    pub hostnames_file: Option<PathBuf>,
+    pub peers: Option<String>,
+    pub verify_announced_peers: bool,
+    pub audit_log: Option<AuditLogOptions>,
+    pub peer_reputation: Option<ReputationOptions>,
+    pub peer_bans_rpc: bool,
+    pub orphans_rpc: bool,
+    pub webhooks: Option<WebhookOptions>,
+    pub webhooks_rpc: bool,
+    pub broadcast_mirrors: Option<MirrorOptions>,
+    pub broadcast_mirrors_rpc: bool,
     pub signet_magic: Magic,
+    pub extra_networks: Vec<Config>,
     pub args: Vec<String>,
 }
 
+#[derive(Clone)]
 pub struct SensitiveAuth(pub Auth);
 
 impl SensitiveAuth {
@@ -167,6 +341,20 @@ impl fmt::Debug for SensitiveAuth {
     }
 }
 
+/// Parse 'user:password' credentials
+fn parse_basic_auth(value: &str) -> Result<MetricsAuth, String> {
+    let mut parts = value.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(user), Some(password)) if !user.is_empty() && !password.is_empty() => {
+            Ok(MetricsAuth::Basic {
+                user: user.to_owned(),
+                password: password.to_owned(),
+            })
+        }
+        _ => Err("expected 'user:password'".to_owned()),
+    }
+}
+
 /// Returns default daemon directory
 fn default_daemon_dir() -> PathBuf {
     let mut home = home_dir().unwrap_or_else(|| {
@@ -177,6 +365,153 @@ fn default_daemon_dir() -> PathBuf {
     home
 }
 
+fn unsupported_network(network: Network) -> ! {
+    eprintln!("Error: unsupported network: {}", network);
+    std::process::exit(1);
+}
+
+/// Per-network defaults (DB sub-directory, daemon sub-directory and ports)
+struct NetworkDefaults {
+    db_subdir: &'static str,
+    daemon_subdir: Option<&'static str>,
+    daemon_rpc_port: u16,
+    daemon_p2p_port: u16,
+    electrum_port: u16,
+    monitoring_port: u16,
+}
+
+impl NetworkDefaults {
+    fn new(network: Network) -> Self {
+        match network {
+            Network::Bitcoin => NetworkDefaults {
+                db_subdir: "bitcoin",
+                daemon_subdir: None,
+                daemon_rpc_port: 8332,
+                daemon_p2p_port: 8333,
+                electrum_port: 50001,
+                monitoring_port: 4224,
+            },
+            Network::Testnet => NetworkDefaults {
+                db_subdir: "testnet",
+                daemon_subdir: Some("testnet3"),
+                daemon_rpc_port: 18332,
+                daemon_p2p_port: 18333,
+                electrum_port: 60001,
+                monitoring_port: 14224,
+            },
+            Network::Regtest => NetworkDefaults {
+                db_subdir: "regtest",
+                daemon_subdir: Some("regtest"),
+                daemon_rpc_port: 18443,
+                daemon_p2p_port: 18444,
+                electrum_port: 60401,
+                monitoring_port: 24224,
+            },
+            Network::Signet => NetworkDefaults {
+                db_subdir: "signet",
+                daemon_subdir: Some("signet"),
+                daemon_rpc_port: 38332,
+                daemon_p2p_port: 38333,
+                electrum_port: 60601,
+                monitoring_port: 34224,
+            },
+            unsupported => unsupported_network(unsupported),
+        }
+    }
+
+    fn daemon_dir(&self, base: &Path) -> PathBuf {
+        match self.daemon_subdir {
+            Some(subdir) => base.join(subdir),
+            None => base.to_path_buf(),
+        }
+    }
+}
+
+/// Parse the given option's entries ('network' or 'network=addr:port', comma-separated)
+fn parse_network_entries(option: &str, value: &str) -> Vec<(Network, Option<ResolvAddr>)> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let mut parts = entry.splitn(2, '=');
+            let name = parts.next().unwrap().trim();
+            let network = Network::from_str(name).unwrap_or_else(|_| {
+                eprintln!("Error: invalid network '{}' in {}", name, option);
+                std::process::exit(1);
+            });
+            let addr = parts.next().map(|addr| ResolvAddr(addr.trim().to_owned()));
+            (network, addr)
+        })
+        .collect()
+}
+
+/// Parse `extra_networks` entries ('network' or 'network=addr:port', comma-separated)
+fn parse_extra_networks(value: &str) -> Vec<(Network, Option<ResolvAddr>)> {
+    parse_network_entries("extra_networks", value)
+}
+
+/// Parse the given option's 'network=addr:port' entries, which must refer to extra networks
+fn parse_network_addrs(
+    option: &str,
+    value: Option<&str>,
+    extra_networks: &[(Network, Option<ResolvAddr>)],
+) -> HashMap<Network, ResolvAddr> {
+    let entries = value.map_or_else(Vec::new, |value| parse_network_entries(option, value));
+    entries
+        .into_iter()
+        .map(|(network, addr)| {
+            if !extra_networks.iter().any(|(extra, _)| *extra == network) {
+                eprintln!(
+                    "Error: {} is not in extra_networks (set by {})",
+                    network, option
+                );
+                std::process::exit(1);
+            }
+            let addr = addr.unwrap_or_else(|| {
+                eprintln!("Error: missing address for {} in {}", network, option);
+                std::process::exit(1);
+            });
+            (network, addr)
+        })
+        .collect()
+}
+
+/// Make sure that each network is served once, on a separate address
+fn check_networks(listeners: &[(Network, SocketAddr)]) -> std::result::Result<(), String> {
+    for (i, (network1, addr1)) in listeners.iter().enumerate() {
+        for (network2, addr2) in &listeners[i + 1..] {
+            if network1 == network2 {
+                return Err(format!("network {} is configured twice", network1));
+            }
+            if addr1 == addr2 {
+                return Err(format!(
+                    "networks {} and {} can't both listen on {}",
+                    network1, network2, addr1
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Make sure that the admin addresses aren't shared (with any other listener)
+fn check_admin_addrs(
+    listeners: &[(Network, SocketAddr)],
+    admin_listeners: &[(Network, SocketAddr)],
+) -> std::result::Result<(), String> {
+    for (i, (network, addr)) in admin_listeners.iter().enumerate() {
+        let mut others = listeners.iter().chain(&admin_listeners[i + 1..]);
+        if others.any(|(_, other)| other == addr) {
+            return Err(format!(
+                "the admin address of network {} ({}) is already used",
+                network, addr
+            ));
+        }
+    }
+    Ok(())
+}
+
 fn default_config_files() -> Vec<OsString> {
     let mut files = vec![OsString::from("electrs.toml")]; // cwd
     if let Some(mut path) = home_dir() {
@@ -190,55 +525,65 @@ fn default_config_files() -> Vec<OsString> {
 impl Config {
     /// Parses args, env vars, config files and post-processes them
     pub fn from_args() -> Config {
-        use internal::ResultExt;
-
-        let (mut config, args) =
-            internal::Config::including_optional_config_files(default_config_files())
-                .unwrap_or_exit();
+        Self::from_custom_args(std::env::args_os())
+    }
 
-        fn unsupported_network(network: Network) -> ! {
-            eprintln!("Error: unsupported network: {}", network);
-            std::process::exit(1);
+    /// Same as `from_args()`, using the given command-line args (starting with the program name)
+    pub fn from_custom_args<A>(args: A) -> Config
+    where
+        A: IntoIterator<Item = OsString>,
+    {
+        let (config, log_filters, ignored) = Self::parse(args);
+        eprintln!(
+            "Starting electrs {} on {} {} with {:?}",
+            ELECTRS_VERSION, ARCH, OS, config
+        );
+        let mut builder = env_logger::Builder::from_default_env();
+        builder.default_format().format_timestamp_millis();
+        if let Some(log_filters) = &log_filters {
+            builder.parse_filters(log_filters);
+        }
+        builder.init();
+        for option in ignored {
+            warn!("ignoring upstream option {} (no equivalent)", option);
         }
 
-        let db_subdir = match config.network {
-            Network::Bitcoin => "bitcoin",
-            Network::Testnet => "testnet",
-            Network::Regtest => "regtest",
-            Network::Signet => "signet",
-            unsupported => unsupported_network(unsupported),
-        };
+        config
+    }
 
-        config.db_dir.push(db_subdir);
+    /// Returns the configuration, the logging filters and the ignored upstream options (without
+    /// initializing the logger)
+    fn parse<A>(args: A) -> (Config, Option<String>, Vec<String>)
+    where
+        A: IntoIterator<Item = OsString>,
+    {
+        use internal::ResultExt;
 
-        let default_daemon_rpc_port = match config.network {
-            Network::Bitcoin => 8332,
-            Network::Testnet => 18332,
-            Network::Regtest => 18443,
-            Network::Signet => 38332,
-            unsupported => unsupported_network(unsupported),
-        };
-        let default_daemon_p2p_port = match config.network {
-            Network::Bitcoin => 8333,
-            Network::Testnet => 18333,
-            Network::Regtest => 18444,
-            Network::Signet => 38333,
-            unsupported => unsupported_network(unsupported),
-        };
-        let default_electrum_port = match config.network {
-            Network::Bitcoin => 50001,
-            Network::Testnet => 60001,
-            Network::Regtest => 60401,
-            Network::Signet => 60601,
-            unsupported => unsupported_network(unsupported),
-        };
-        let default_monitoring_port = match config.network {
-            Network::Bitcoin => 4224,
-            Network::Testnet => 14224,
-            Network::Regtest => 24224,
-            Network::Signet => 34224,
-            unsupported => unsupported_network(unsupported),
-        };
+        let args: Vec<OsString> = args.into_iter().collect();
+        // the translated files must be removed before exiting (e.g. on errors or `--help`)
+        let mut translated = TranslatedFiles::default();
+        let parsed = config_compat::mode(&args)
+            .and_then(|mode| translated.translate_all(mode, args, default_config_files()))
+            .map(|(args, files)| internal::Config::custom_args_and_optional_files(args, files));
+        let ignored = translated.take_ignored();
+        drop(translated);
+        let (mut config, args) = parsed
+            .unwrap_or_else(|msg| {
+                eprintln!("Error: {}", msg);
+                std::process::exit(1);
+            })
+            .unwrap_or_exit();
+
+        let defaults = NetworkDefaults::new(config.network);
+        let base_db_dir = config.db_dir.clone();
+        let base_daemon_dir = config.daemon_dir.clone();
+
+        config.db_dir.push(defaults.db_subdir);
+
+        let default_daemon_rpc_port = defaults.daemon_rpc_port;
+        let default_daemon_p2p_port = defaults.daemon_p2p_port;
+        let default_electrum_port = defaults.electrum_port;
+        let default_monitoring_port = defaults.monitoring_port;
 
         let magic = match (config.network, config.signet_magic) {
             (Network::Signet, Some(magic)) => magic.parse().unwrap_or_else(|error| {
@@ -267,25 +612,46 @@ impl Config {
             (DEFAULT_SERVER_ADDRESS, default_electrum_port).into(),
             ResolvAddr::resolve_or_exit,
         );
+        let electrum_admin_addr = config.electrum_admin_addr.map(ResolvAddr::resolve_or_exit);
         #[cfg(not(feature = "metrics"))]
         {
-            if config.monitoring_addr.is_some() {
-                eprintln!("Error: enable \"metrics\" feature to specify monitoring_addr");
+            if config.monitoring_addr.is_some()
+                || config.monitoring_unix_socket.is_some()
+                || config.monitoring_auth_token.is_some()
+                || config.monitoring_basic_auth.is_some()
+            {
+                eprintln!("Error: enable \"metrics\" feature to specify monitoring options");
                 std::process::exit(1);
             }
         }
-        let monitoring_addr: SocketAddr = config.monitoring_addr.map_or(
-            (DEFAULT_SERVER_ADDRESS, default_monitoring_port).into(),
-            ResolvAddr::resolve_or_exit,
-        );
+        let monitoring_listener = if config.disable_monitoring {
+            MetricsListener::Disabled
+        } else if let Some(path) = config.monitoring_unix_socket {
+            MetricsListener::Unix(path)
+        } else {
+            MetricsListener::Tcp(config.monitoring_addr.map_or(
+                (DEFAULT_SERVER_ADDRESS, default_monitoring_port).into(),
+                ResolvAddr::resolve_or_exit,
+            ))
+        };
+        let monitoring_auth = match (config.monitoring_auth_token, config.monitoring_basic_auth) {
+            (None, None) => None,
+            (Some(token), None) => Some(MetricsAuth::Bearer(token)),
+            (None, Some(value)) => Some(parse_basic_auth(&value).unwrap_or_else(|msg| {
+                eprintln!("Error: invalid monitoring_basic_auth: {}", msg);
+                std::process::exit(1);
+            })),
+            (Some(_), Some(_)) => {
+                eprintln!("Error: monitoring_auth_token and monitoring_basic_auth can't be both specified");
+                std::process::exit(1);
+            }
+        };
+        let monitoring = MetricsOptions {
+            listener: monitoring_listener,
+            auth: monitoring_auth,
+        };
 
-        match config.network {
-            Network::Bitcoin => (),
-            Network::Testnet => config.daemon_dir.push("testnet3"),
-            Network::Regtest => config.daemon_dir.push("regtest"),
-            Network::Signet => config.daemon_dir.push("signet"),
-            unsupported => unsupported_network(unsupported),
-        }
+        config.daemon_dir = defaults.daemon_dir(&config.daemon_dir);
 
         let daemon_dir = &config.daemon_dir;
         let daemon_auth = SensitiveAuth(match (config.auth, config.cookie_file) {
@@ -324,53 +690,563 @@ impl Config {
             std::process::exit(1);
         }
 
+        if config.select_max_inputs == 0 {
+            eprintln!("Error: select_max_inputs must be positive");
+            std::process::exit(1);
+        }
+
         if config.version {
             println!("v{}", ELECTRS_VERSION);
             std::process::exit(0);
         }
 
-        let config = Config {
+        let keepalive = match (
+            config.electrum_keepalive_idle_secs,
+            config.electrum_keepalive_interval_secs,
+            config.electrum_keepalive_retries,
+        ) {
+            (0, None, None) => None,
+            (0, _, _) => {
+                eprintln!(
+                    "Error: TCP keepalive interval and retries require electrum_keepalive_idle_secs"
+                );
+                std::process::exit(1);
+            }
+            (idle_secs, interval_secs, retries) => Some(Keepalive {
+                idle: Duration::from_secs(idle_secs),
+                interval: interval_secs.map(Duration::from_secs),
+                retries,
+            }),
+        };
+        let electrum_listener = ListenerOptions {
+            dual_stack: config.electrum_dual_stack,
+            reuse_address: config.electrum_reuse_address,
+            reuse_port: config.electrum_reuse_port,
+            keepalive,
+            proxy_protocol: config.electrum_proxy_protocol,
+        };
+
+        let audit_log_secret = match (
+            config.audit_log_scripthash_queries,
+            config.audit_log_secret_file,
+        ) {
+            (false, _) => None,
+            (true, Some(secret_file)) => {
+                let secret = std::fs::read_to_string(&secret_file).unwrap_or_else(|e| {
+                    eprintln!(
+                        "Error: failed to read audit_log_secret_file {}: {}",
+                        secret_file.display(),
+                        e
+                    );
+                    std::process::exit(1);
+                });
+                let secret = secret.trim();
+                if secret.is_empty() {
+                    eprintln!("Error: audit_log_secret_file is empty");
+                    std::process::exit(1);
+                }
+                Some(secret.as_bytes().to_vec())
+            }
+            (true, None) => {
+                eprintln!("Error: audit_log_scripthash_queries requires audit_log_secret_file");
+                std::process::exit(1);
+            }
+        };
+        let audit_log_max_size = config.audit_log_max_size_mb * 1024 * 1024;
+        let audit_log_max_files = config.audit_log_max_files;
+        let audit_log_fsync = config.audit_log_fsync;
+        let audit_log = config.audit_log_path.map(|path| AuditLogOptions {
+            path,
+            max_size: audit_log_max_size,
+            max_files: audit_log_max_files,
+            fsync: audit_log_fsync,
+            scripthash_secret: audit_log_secret,
+        });
+
+        let peer_reputation = match config.peer_ban_threshold {
+            0 => None,
+            ban_threshold => Some(ReputationOptions {
+                ban_threshold,
+                ban_duration: Duration::from_secs(config.peer_ban_secs),
+                max_ban_duration: Duration::from_secs(config.peer_ban_max_secs),
+                path: config.peer_bans_file,
+            }),
+        };
+
+        if config.webhook_max_attempts == 0 {
+            eprintln!("Error: webhook_max_attempts must be positive");
+            std::process::exit(1);
+        }
+        let webhook_max_attempts = config.webhook_max_attempts;
+        let webhook_retry_delay = Duration::from_secs(config.webhook_retry_secs);
+        let webhooks = config.webhooks_file.map(|path| WebhookOptions {
+            path,
+            max_attempts: webhook_max_attempts,
+            retry_delay: webhook_retry_delay,
+        });
+
+        if config.broadcast_mirror_max_attempts == 0 {
+            eprintln!("Error: broadcast_mirror_max_attempts must be positive");
+            std::process::exit(1);
+        }
+        let mirror_max_attempts = config.broadcast_mirror_max_attempts;
+        let mirror_retry_delay = Duration::from_secs(config.broadcast_mirror_retry_secs);
+        let broadcast_mirrors = config.broadcast_mirrors_file.map(|path| MirrorOptions {
+            path,
+            max_attempts: mirror_max_attempts,
+            retry_delay: mirror_retry_delay,
+        });
+
+        let extra_networks = config
+            .extra_networks
+            .as_deref()
+            .map(parse_extra_networks)
+            .unwrap_or_default();
+        let mut extra_daemon_rpc_addrs = parse_network_addrs(
+            "extra_daemon_rpc_addrs",
+            config.extra_daemon_rpc_addrs.as_deref(),
+            &extra_networks,
+        );
+        let mut extra_daemon_p2p_addrs = parse_network_addrs(
+            "extra_daemon_p2p_addrs",
+            config.extra_daemon_p2p_addrs.as_deref(),
+            &extra_networks,
+        );
+        let mut extra_electrum_admin_addrs = parse_network_addrs(
+            "extra_electrum_admin_addrs",
+            config.extra_electrum_admin_addrs.as_deref(),
+            &extra_networks,
+        );
+
+        let mut config = Config {
             network: config.network,
             db_path: config.db_dir,
+            db_profile: config.db_profile,
+            db_memory_limit: match config.db_memory_limit_mb {
+                0 => None,
+                mb => Some(mb * 1024 * 1024),
+            },
             daemon_dir: config.daemon_dir,
             daemon_auth,
             daemon_rpc_addr,
             daemon_p2p_addr,
             electrum_rpc_addr,
-            monitoring_addr,
+            electrum_admin_addr,
+            monitoring,
             wait_duration: Duration::from_secs(config.wait_duration_secs),
             jsonrpc_timeout: Duration::from_secs(config.jsonrpc_timeout_secs),
+            broadcast_idempotency_ttl: Duration::from_secs(config.broadcast_idempotency_ttl_secs),
+            select_bnb_tolerance: config.select_bnb_tolerance_sat,
+            select_reservation_ttl: match config.select_reservation_secs {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            select_max_inputs: config.select_max_inputs,
+            select_input_padding: !config.disable_select_input_padding,
+            transactions_batch_limit: config.transactions_batch_limit,
+            request_batch_limit: config.request_batch_limit,
+            request_size_limit: config.request_size_limit,
+            status_max_lag_blocks: config.status_max_lag_blocks,
+            status_cache_ttl: match config.status_cache_ttl_secs {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            status_cache_capacity: config.status_cache_capacity,
+            daemon_startup_timeout: match config.daemon_startup_timeout_secs {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
             index_batch_size: config.index_batch_size,
             index_lookup_limit,
+            max_history_len: match config.max_history_len {
+                0 => None,
+                limit => Some(limit),
+            },
             reindex_last_blocks: config.reindex_last_blocks,
             auto_reindex: config.auto_reindex,
+            force_reindex_on_mismatch: config.force_reindex_on_mismatch,
             ignore_mempool: config.ignore_mempool,
+            serve_stale_without_daemon: config.serve_stale_without_daemon,
+            block_txids_subscription: config.block_txids_subscription,
+            txid_prefix_index: config.txid_prefix_index,
+            block_txids_index: config.block_txids_index,
+            spender_index: config.spender_index,
+            cache_management_rpc: config.cache_management_rpc,
+            proofs_export_rpc: config.proofs_export_rpc,
+            cbor_codec: config.cbor_codec,
+            strict_params: config.strict_params,
+            legacy_id_from_pos: config.legacy_id_from_pos,
+            client_memory_budget: match config.client_memory_budget_mb {
+                0 => None,
+                mb => Some(mb * 1024 * 1024),
+            },
+            scripthash_subscriptions_limit: match config.scripthash_subscriptions_limit {
+                0 => None,
+                limit => Some(limit),
+            },
+            subscription_idle_timeout: match config.subscription_idle_hours {
+                0 => None,
+                hours => Some(Duration::from_secs(hours * 3600)),
+            },
+            outpoint_subscriptions_limit: config.outpoint_subscriptions_limit,
+            transaction_subscriptions_limit: config.transaction_subscriptions_limit,
+            transaction_subscription_confirmations: match config
+                .transaction_subscription_confirmations
+            {
+                0 => None,
+                confirmations => Some(confirmations),
+            },
+            sync_serving: config.sync_serving,
             sync_once: config.sync_once,
             disable_electrum_rpc: config.disable_electrum_rpc,
+            electrum_listener,
             server_banner: config.server_banner,
+            hostnames_file: config.hostnames_file,
+            peers: config.peers,
+            verify_announced_peers: config.verify_announced_peers,
+            audit_log,
+            peer_reputation,
+            peer_bans_rpc: config.peer_bans_rpc,
+            orphans_rpc: config.orphans_rpc,
+            webhooks,
+            webhooks_rpc: config.webhooks_rpc,
+            broadcast_mirrors,
+            broadcast_mirrors_rpc: config.broadcast_mirrors_rpc,
             signet_magic: magic,
+            extra_networks: vec![],
             args: args.map(|a| a.into_string().unwrap()).collect(),
         };
-        eprintln!(
-            "Starting electrs {} on {} {} with {:?}",
-            ELECTRS_VERSION, ARCH, OS, config
-        );
-        let mut builder = env_logger::Builder::from_default_env();
-        builder.default_format().format_timestamp_millis();
-        if let Some(log_filters) = &log_filters {
-            builder.parse_filters(log_filters);
+        config.extra_networks = extra_networks
+            .into_iter()
+            .map(|(network, addr)| {
+                let defaults = NetworkDefaults::new(network);
+                let daemon_dir = defaults.daemon_dir(&base_daemon_dir);
+                // user/password are shared, cookie files are specific to each bitcoind
+                let daemon_auth = SensitiveAuth(match config.daemon_auth.get_auth() {
+                    Auth::UserPass(user, pass) => Auth::UserPass(user, pass),
+                    _ => Auth::CookieFile(daemon_dir.join(".cookie")),
+                });
+                let default_addr =
+                    |port: u16| -> SocketAddr { (DEFAULT_SERVER_ADDRESS, port).into() };
+                Config {
+                    network,
+                    db_path: base_db_dir.join(defaults.db_subdir),
+                    db_profile: config.db_profile,
+                    db_memory_limit: config.db_memory_limit,
+                    daemon_dir,
+                    daemon_auth,
+                    daemon_rpc_addr: extra_daemon_rpc_addrs.remove(&network).map_or(
+                        default_addr(defaults.daemon_rpc_port),
+                        ResolvAddr::resolve_or_exit,
+                    ),
+                    daemon_p2p_addr: extra_daemon_p2p_addrs.remove(&network).map_or(
+                        default_addr(defaults.daemon_p2p_port),
+                        ResolvAddr::resolve_or_exit,
+                    ),
+                    electrum_rpc_addr: addr.map_or(
+                        default_addr(defaults.electrum_port),
+                        ResolvAddr::resolve_or_exit,
+                    ),
+                    electrum_admin_addr: extra_electrum_admin_addrs
+                        .remove(&network)
+                        .map(ResolvAddr::resolve_or_exit),
+                    // the other settings are shared by all networks
+                    jsonrpc_timeout: config.jsonrpc_timeout,
+                    broadcast_idempotency_ttl: config.broadcast_idempotency_ttl,
+                    select_bnb_tolerance: config.select_bnb_tolerance,
+                    select_reservation_ttl: config.select_reservation_ttl,
+                    select_max_inputs: config.select_max_inputs,
+                    select_input_padding: config.select_input_padding,
+                    transactions_batch_limit: config.transactions_batch_limit,
+                    request_batch_limit: config.request_batch_limit,
+                    request_size_limit: config.request_size_limit,
+                    status_max_lag_blocks: config.status_max_lag_blocks,
+                    status_cache_ttl: config.status_cache_ttl,
+                    status_cache_capacity: config.status_cache_capacity,
+                    daemon_startup_timeout: config.daemon_startup_timeout,
+                    index_batch_size: config.index_batch_size,
+                    index_lookup_limit: config.index_lookup_limit,
+                    max_history_len: config.max_history_len,
+                    reindex_last_blocks: config.reindex_last_blocks,
+                    auto_reindex: config.auto_reindex,
+                    force_reindex_on_mismatch: config.force_reindex_on_mismatch,
+                    ignore_mempool: config.ignore_mempool,
+                    serve_stale_without_daemon: config.serve_stale_without_daemon,
+                    block_txids_subscription: config.block_txids_subscription,
+                    txid_prefix_index: config.txid_prefix_index,
+                    block_txids_index: config.block_txids_index,
+                    spender_index: config.spender_index,
+                    cache_management_rpc: config.cache_management_rpc,
+                    proofs_export_rpc: config.proofs_export_rpc,
+                    cbor_codec: config.cbor_codec,
+                    strict_params: config.strict_params,
+                    legacy_id_from_pos: config.legacy_id_from_pos,
+                    client_memory_budget: config.client_memory_budget,
+                    scripthash_subscriptions_limit: config.scripthash_subscriptions_limit,
+                    subscription_idle_timeout: config.subscription_idle_timeout,
+                    outpoint_subscriptions_limit: config.outpoint_subscriptions_limit,
+                    transaction_subscriptions_limit: config.transaction_subscriptions_limit,
+                    transaction_subscription_confirmations: config
+                        .transaction_subscription_confirmations,
+                    sync_serving: config.sync_serving,
+                    server_banner: config.server_banner.clone(),
+                    // the advertised hosts and peers are specific to the main network
+                    hostnames_file: None,
+                    peers: None,
+                    verify_announced_peers: config.verify_announced_peers,
+                    // process-wide, so only the main network's settings are used (see `serve()`)
+                    monitoring: config.monitoring.clone(),
+                    wait_duration: config.wait_duration,
+                    sync_once: config.sync_once,
+                    disable_electrum_rpc: config.disable_electrum_rpc,
+                    electrum_listener: config.electrum_listener.clone(),
+                    args: config.args.clone(),
+                    audit_log: None,
+                    peer_reputation: None,
+                    peer_bans_rpc: config.peer_bans_rpc,
+                    orphans_rpc: config.orphans_rpc,
+                    webhooks: None,
+                    webhooks_rpc: config.webhooks_rpc,
+                    broadcast_mirrors: None,
+                    broadcast_mirrors_rpc: config.broadcast_mirrors_rpc,
+                    signet_magic: network.magic(),
+                    extra_networks: vec![],
+                }
+            })
+            .collect();
+        let configs = || std::iter::once(&config).chain(config.extra_networks.iter());
+        let listeners: Vec<(Network, SocketAddr)> = configs()
+            .map(|c| (c.network, c.electrum_rpc_addr))
+            .collect();
+        let admin_listeners: Vec<(Network, SocketAddr)> = configs()
+            .filter_map(|c| c.electrum_admin_addr.map(|addr| (c.network, addr)))
+            .collect();
+        let checked = check_networks(&listeners)
+            .and_then(|()| check_admin_addrs(&listeners, &admin_listeners));
+        if let Err(msg) = checked {
+            eprintln!("Error: {}", msg);
+            std::process::exit(1);
         }
-        builder.init();
-
-        config
+        (config, log_filters, ignored)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Auth, SensitiveAuth};
+    use super::{
+        check_admin_addrs, check_networks, parse_basic_auth, Auth, Config, ConfigCompat, DbProfile,
+        MetricsAuth, Network, SensitiveAuth, SyncServing,
+    };
+    use std::ffi::OsString;
+    use std::net::SocketAddr;
     use std::path::Path;
 
+    #[test]
+    fn test_check_networks() {
+        let addr1: SocketAddr = "127.0.0.1:50001".parse().unwrap();
+        let addr2: SocketAddr = "127.0.0.1:60001".parse().unwrap();
+        assert_eq!(check_networks(&[(Network::Bitcoin, addr1)]), Ok(()));
+        assert_eq!(
+            check_networks(&[(Network::Bitcoin, addr1), (Network::Testnet, addr2)]),
+            Ok(())
+        );
+        assert_eq!(
+            check_networks(&[(Network::Bitcoin, addr1), (Network::Testnet, addr1)]),
+            Err("networks bitcoin and testnet can't both listen on 127.0.0.1:50001".to_owned())
+        );
+        assert_eq!(
+            check_networks(&[(Network::Testnet, addr1), (Network::Testnet, addr2)]),
+            Err("network testnet is configured twice".to_owned())
+        );
+
+        let admin: SocketAddr = "127.0.0.1:50002".parse().unwrap();
+        let listeners = [(Network::Bitcoin, addr1), (Network::Testnet, addr2)];
+        assert_eq!(
+            check_admin_addrs(&listeners, &[(Network::Bitcoin, admin)]),
+            Ok(())
+        );
+        assert_eq!(
+            check_admin_addrs(&listeners, &[(Network::Bitcoin, addr2)]),
+            Err(
+                "the admin address of network bitcoin (127.0.0.1:60001) is already used".to_owned()
+            )
+        );
+        assert_eq!(
+            check_admin_addrs(
+                &listeners,
+                &[(Network::Bitcoin, admin), (Network::Testnet, admin)]
+            ),
+            Err(
+                "the admin address of network bitcoin (127.0.0.1:50002) is already used".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_sync_serving() {
+        assert_eq!(
+            "refuse_connections_until_synced".parse(),
+            Ok(SyncServing::RefuseConnectionsUntilSynced)
+        );
+        assert_eq!(
+            "serve_headers_only".parse(),
+            Ok(SyncServing::ServeHeadersOnly)
+        );
+        assert_eq!(
+            "serve_confirmed_data_with_staleness_flag".parse(),
+            Ok(SyncServing::ServeConfirmedDataWithStalenessFlag)
+        );
+        assert_eq!(
+            "serve_everything".parse::<SyncServing>(),
+            Err("unknown sync serving mode: serve_everything".to_owned())
+        );
+        assert_eq!(SyncServing::default(), SyncServing::ServeHeadersOnly);
+    }
+
+    #[test]
+    fn test_parse_db_profile() {
+        assert_eq!("low_memory".parse(), Ok(DbProfile::LowMemory));
+        assert_eq!("balanced".parse(), Ok(DbProfile::Balanced));
+        assert_eq!("throughput".parse(), Ok(DbProfile::Throughput));
+        assert_eq!(
+            "huge".parse::<DbProfile>(),
+            Err("unknown DB profile: huge".to_owned())
+        );
+        assert_eq!(DbProfile::default(), DbProfile::Balanced);
+    }
+
+    #[test]
+    fn test_parse_config_compat() {
+        assert_eq!("auto".parse(), Ok(ConfigCompat::Auto));
+        assert_eq!("upstream".parse(), Ok(ConfigCompat::Upstream));
+        assert_eq!("none".parse(), Ok(ConfigCompat::Disabled));
+        assert_eq!(ConfigCompat::default(), ConfigCompat::Auto);
+    }
+
+    #[test]
+    fn test_upstream_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let upstream = dir.path().join("upstream.toml");
+        std::fs::write(
+            &upstream,
+            r#"
+            network = "testnet"
+            db_dir = "/var/lib/electrs"
+            daemon_dir = "/var/lib/bitcoind"
+            cookie = "user:pass"
+            electrum_rpc_addr = "127.0.0.1:60001"
+            txid_limit = 200
+            db_log_dir = "/var/log/electrs"
+            db_parallelism = 4
+            server_banner = "Welcome"
+            "#,
+        )
+        .unwrap();
+        let translated = dir.path().join("translated.toml");
+        std::fs::write(
+            &translated,
+            r#"
+            network = "testnet"
+            db_dir = "/var/lib/electrs"
+            daemon_dir = "/var/lib/bitcoind"
+            auth = "user:pass"
+            electrum_rpc_addr = "127.0.0.1:60001"
+            index_lookup_limit = 200
+            server_banner = "Welcome"
+            "#,
+        )
+        .unwrap();
+
+        let parse = |path: &Path, compat: &str| {
+            let args = [
+                "--skip-default-conf-files",
+                "--config-compat",
+                compat,
+                "--conf",
+            ];
+            let args = std::iter::once("electrs")
+                .chain(args.iter().copied())
+                .map(OsString::from)
+                .chain(std::iter::once(path.as_os_str().to_owned()));
+            Config::parse(args).0
+        };
+        // detected automatically
+        let config = parse(&upstream, "auto");
+        let expected = parse(&translated, "none");
+        assert_eq!(format!("{:?}", config), format!("{:?}", expected));
+        assert_eq!(
+            config.daemon_auth.get_auth(),
+            expected.daemon_auth.get_auth()
+        );
+        assert_eq!(config.index_lookup_limit, Some(200));
+
+        // forced (without upstream-specific options)
+        let config = parse(&translated, "upstream");
+        assert_eq!(format!("{:?}", config), format!("{:?}", expected));
+    }
+
+    #[test]
+    fn test_extra_networks() {
+        let args = [
+            "electrs",
+            "--skip-default-conf-files",
+            "--network",
+            "regtest",
+            "--extra-networks",
+            "testnet=127.0.0.1:60011,signet",
+            "--extra-daemon-rpc-addrs",
+            "testnet=10.0.0.2:18332",
+            "--extra-daemon-p2p-addrs",
+            "signet=10.0.0.3:38333",
+            "--electrum-admin-addr",
+            "127.0.0.1:40401",
+            "--extra-electrum-admin-addrs",
+            "testnet=127.0.0.1:40001",
+            "--audit-log-path",
+            "/var/log/electrs/audit.log",
+            "--hostnames-file",
+            "/etc/electrs/hostnames.json",
+        ];
+        let config = Config::parse(args.iter().map(OsString::from)).0;
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        assert!(config.audit_log.is_some());
+        assert!(config.hostnames_file.is_some());
+        assert_eq!(config.electrum_admin_addr, Some(addr("127.0.0.1:40401")));
+
+        let testnet = &config.extra_networks[0];
+        assert_eq!(testnet.network, Network::Testnet);
+        assert_eq!(testnet.electrum_rpc_addr, addr("127.0.0.1:60011"));
+        assert_eq!(testnet.daemon_rpc_addr, addr("10.0.0.2:18332"));
+        assert_eq!(testnet.daemon_p2p_addr, addr("127.0.0.1:18333"));
+        assert_eq!(testnet.electrum_admin_addr, Some(addr("127.0.0.1:40001")));
+        let signet = &config.extra_networks[1];
+        assert_eq!(signet.network, Network::Signet);
+        assert_eq!(signet.electrum_rpc_addr, addr("127.0.0.1:60601"));
+        assert_eq!(signet.daemon_rpc_addr, addr("127.0.0.1:38332"));
+        assert_eq!(signet.daemon_p2p_addr, addr("10.0.0.3:38333"));
+        assert_eq!(signet.electrum_admin_addr, None);
+        for extra in &config.extra_networks {
+            // only the main network's process-wide settings are used
+            assert!(extra.audit_log.is_none());
+            assert!(extra.hostnames_file.is_none());
+            assert!(extra.extra_networks.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_parse_basic_auth() {
+        assert_eq!(
+            parse_basic_auth("user:pa:ss"),
+            Ok(MetricsAuth::Basic {
+                user: "user".to_owned(),
+                password: "pa:ss".to_owned(),
+            })
+        );
+        assert!(parse_basic_auth("user").is_err());
+        assert!(parse_basic_auth(":pass").is_err());
+        assert!(parse_basic_auth("user:").is_err());
+    }
+
     #[test]
     fn test_auth_debug() {
         let auth = Auth::None;
@@ -0,0 +1,297 @@
+//! Translation of upstream (romanz/electrs) config files into this fork's options, so the same
+//! files can be used by both. Upstream uses the same file layout and most of the same option
+//! names, so only its specific options need to be translated.
+
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use crate::config::ConfigCompat;
+
+type Table = toml::value::Table;
+
+/// Upstream options which were renamed (having the same semantics)
+const RENAMED: &[(&str, &str)] = &[
+    ("cookie", "auth"),                   // "USER:PASSWORD" (deprecated by upstream)
+    ("txid_limit", "index_lookup_limit"), // 0 disables the limit
+];
+
+/// Upstream options without an equivalent (ignored, unless conflicting with this fork's options)
+const UNSUPPORTED: &[(&str, Option<&str>)] = &[
+    ("db_log_dir", None),
+    ("db_parallelism", Some("db_profile")), // both tune RocksDB's concurrency
+    ("jsonrpc_import", None),
+    ("bulk_index_threads", None),
+    ("tx_cache_size_mb", None),
+    ("blocktxids_cache_size_mb", None),
+];
+
+const ARG: &str = "--config-compat";
+const ENV_VAR: &str = "ELECTRS_CONFIG_COMPAT";
+
+fn is_upstream_specific(key: &str) -> bool {
+    RENAMED.iter().any(|(name, _)| *name == key) || UNSUPPORTED.iter().any(|(name, _)| *name == key)
+}
+
+/// The compatibility mode is needed before parsing the config files, so it is taken only from
+/// the arguments or the environment (the last argument wins, as for other options).
+pub(crate) fn mode(args: &[OsString]) -> Result<ConfigCompat, String> {
+    let mut value: Option<String> = std::env::var(ENV_VAR).ok();
+    let mut args = args.iter().map(|arg| arg.to_string_lossy());
+    while let Some(arg) = args.next() {
+        if arg == ARG {
+            value = args.next().map(|value| value.into_owned());
+        } else if let Some(rest) = arg.strip_prefix(ARG) {
+            if let Some(rest) = rest.strip_prefix('=') {
+                value = Some(rest.to_owned());
+            }
+        }
+    }
+    value.map_or(Ok(ConfigCompat::default()), |value| value.parse())
+}
+
+/// Translate an upstream config into this fork's options, dropping the unsupported ones.
+pub(crate) fn translate(table: Table) -> Result<Table, String> {
+    let conflicts = RENAMED
+        .iter()
+        .map(|(name, new_name)| (*name, Some(*new_name)));
+    for (name, conflict) in conflicts.chain(UNSUPPORTED.iter().copied()) {
+        if let Some(conflict) = conflict {
+            if table.contains_key(name) && table.contains_key(conflict) {
+                return Err(format!("{} conflicts with {}", name, conflict));
+            }
+        }
+    }
+    let mut translated = Table::new();
+    for (key, value) in table {
+        if let Some((_, new_key)) = RENAMED.iter().find(|(name, _)| *name == key) {
+            translated.insert((*new_key).to_owned(), value);
+        } else if !UNSUPPORTED.iter().any(|(name, _)| *name == key) {
+            translated.insert(key, value);
+        }
+    }
+    Ok(translated)
+}
+
+/// Translated copies of config files, kept in a private (0700) directory which is removed when
+/// dropped (since they may contain credentials)
+#[derive(Default)]
+pub(crate) struct TranslatedFiles {
+    dir: Option<tempfile::TempDir>,
+    count: usize,
+    ignored: Vec<String>,
+}
+
+impl TranslatedFiles {
+    /// Returns the path of the translated copy, or the original path if no translation is needed
+    /// (including unreadable or malformed files, which are reported when they are parsed).
+    fn translate_file(&mut self, mode: ConfigCompat, path: &OsStr) -> Result<OsString, String> {
+        let table: Table = match fs::read_to_string(path).map(|content| toml::from_str(&content)) {
+            Ok(Ok(table)) => table,
+            _ => return Ok(path.to_owned()),
+        };
+        let upstream = match mode {
+            ConfigCompat::Disabled => false,
+            ConfigCompat::Auto => table.keys().any(|key| is_upstream_specific(key)),
+            ConfigCompat::Upstream => true,
+        };
+        if !upstream {
+            return Ok(path.to_owned());
+        }
+        let display = Path::new(path).display();
+        for key in table.keys() {
+            if UNSUPPORTED.iter().any(|(name, _)| name == key) {
+                self.ignored.push(format!("{} in {}", key, display));
+            }
+        }
+        let table = translate(table).map_err(|e| format!("{} in {}", e, display))?;
+        let content = toml::to_string(&table).map_err(|e| e.to_string())?;
+        self.write(&content)
+            .map_err(|e| format!("failed to translate {}: {}", display, e))
+    }
+
+    fn write(&mut self, content: &str) -> std::io::Result<OsString> {
+        let dir = match self.dir.take() {
+            Some(dir) => dir,
+            None => tempfile::Builder::new()
+                .prefix("electrs-config-")
+                .tempdir()?, // only accessible by the current user (0700)
+        };
+        let path = dir.path().join(format!("{}.toml", self.count));
+        self.dir = Some(dir);
+        self.count += 1;
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?;
+        file.write_all(content.as_bytes())?;
+        Ok(path.into_os_string())
+    }
+
+    /// The ignored upstream options (to be logged once the logger is initialized)
+    pub(crate) fn take_ignored(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.ignored)
+    }
+
+    /// Translate the default config files and the ones passed via `--conf` (but not the ones
+    /// in `--conf-dir`), returning the arguments and files to be parsed instead.
+    pub(crate) fn translate_all(
+        &mut self,
+        mode: ConfigCompat,
+        args: Vec<OsString>,
+        files: Vec<OsString>,
+    ) -> Result<(Vec<OsString>, Vec<OsString>), String> {
+        let files = files
+            .iter()
+            .map(|path| self.translate_file(mode, path))
+            .collect::<Result<_, _>>()?;
+        let mut translated_args = Vec::with_capacity(args.len());
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "--conf" {
+                translated_args.push(arg);
+                if let Some(path) = args.next() {
+                    translated_args.push(self.translate_file(mode, &path)?);
+                }
+                continue;
+            }
+            let path = arg.to_str().and_then(|arg| arg.strip_prefix("--conf="));
+            match path {
+                Some(path) => {
+                    let mut translated = OsString::from("--conf=");
+                    translated.push(self.translate_file(mode, OsStr::new(path))?);
+                    translated_args.push(translated);
+                }
+                None => translated_args.push(arg),
+            }
+        }
+        Ok((translated_args, files))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{mode, translate, Table, TranslatedFiles};
+    use crate::config::ConfigCompat;
+    use std::ffi::OsString;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn table(content: &str) -> Table {
+        toml::from_str(content).unwrap()
+    }
+
+    #[test]
+    fn test_mode() {
+        let args = |args: &[&str]| -> Vec<OsString> { args.iter().map(OsString::from).collect() };
+        assert_eq!(mode(&args(&["electrs"])), Ok(ConfigCompat::Auto));
+        assert_eq!(
+            mode(&args(&["electrs", "--config-compat", "upstream"])),
+            Ok(ConfigCompat::Upstream)
+        );
+        assert_eq!(
+            mode(&args(&[
+                "electrs",
+                "--config-compat=upstream",
+                "--config-compat=none"
+            ])),
+            Ok(ConfigCompat::Disabled)
+        );
+        assert_eq!(
+            mode(&args(&["electrs", "--config-compat", "v0.8"])),
+            Err("unknown config compatibility mode: v0.8".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_translate() {
+        let upstream = table(
+            r#"
+            network = "testnet"
+            cookie = "user:pass"
+            txid_limit = 500
+            db_log_dir = "/var/log/electrs"
+            jsonrpc_import = true
+            "#,
+        );
+        let expected = table(
+            r#"
+            network = "testnet"
+            auth = "user:pass"
+            index_lookup_limit = 500
+            "#,
+        );
+        assert_eq!(translate(upstream), Ok(expected));
+
+        let conflicts = [
+            ("cookie = 'a:b'\nauth = 'c:d'", "cookie conflicts with auth"),
+            ("auth = 'c:d'\ncookie = 'a:b'", "cookie conflicts with auth"),
+            (
+                "txid_limit = 1\nindex_lookup_limit = 2",
+                "txid_limit conflicts with index_lookup_limit",
+            ),
+            (
+                "db_parallelism = 8\ndb_profile = 'throughput'",
+                "db_parallelism conflicts with db_profile",
+            ),
+            (
+                "db_profile = 'throughput'\ndb_parallelism = 8",
+                "db_parallelism conflicts with db_profile",
+            ),
+        ];
+        for (content, msg) in &conflicts {
+            assert_eq!(
+                translate(table(content)),
+                Err((*msg).to_owned()),
+                "{}",
+                content
+            );
+        }
+    }
+
+    #[test]
+    fn test_translate_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let upstream = dir.path().join("upstream.toml");
+        std::fs::write(&upstream, "txid_limit = 10\njsonrpc_import = true\n").unwrap();
+        let fork = dir.path().join("fork.toml");
+        std::fs::write(&fork, "index_lookup_limit = 10\n").unwrap();
+        let missing = dir.path().join("missing.toml");
+
+        let mut translated = TranslatedFiles::default();
+        let args = vec![
+            OsString::from("electrs"),
+            OsString::from("--conf"),
+            upstream.clone().into_os_string(),
+            OsString::from(format!("--conf={}", fork.display())),
+        ];
+        let files = vec![missing.clone().into_os_string()];
+        let (args, files) = translated
+            .translate_all(ConfigCompat::Auto, args, files)
+            .unwrap();
+        assert_eq!(files, vec![missing.into_os_string()]); // unchanged
+        assert_eq!(
+            args[3],
+            OsString::from(format!("--conf={}", fork.display()))
+        );
+        let path = std::path::PathBuf::from(&args[2]);
+        assert_ne!(path, upstream);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "index_lookup_limit = 10\n"
+        );
+        assert_eq!(
+            translated.take_ignored(),
+            vec![format!("jsonrpc_import in {}", upstream.display())]
+        );
+        assert!(translated.take_ignored().is_empty());
+        let dir = path.parent().unwrap().to_owned();
+        let mode = std::fs::metadata(&dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        drop(translated);
+        assert!(!path.exists());
+        assert!(!dir.exists());
+    }
+}
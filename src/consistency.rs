@@ -0,0 +1,260 @@
+use anyhow::Result;
+use bitcoin::{blockdata::constants::genesis_block, consensus::deserialize, BlockHash, Network};
+
+use crate::{
+    daemon::Daemon,
+    db::{DBStore, NetworkTag},
+};
+
+/// Daemon queries used for verifying the index against the daemon's chain
+pub(crate) trait DaemonChain {
+    fn genesis_hash(&self) -> Result<BlockHash>;
+    fn has_block(&self, blockhash: &BlockHash) -> Result<bool>;
+}
+
+impl DaemonChain for Daemon {
+    fn genesis_hash(&self) -> Result<BlockHash> {
+        self.get_genesis_hash()
+    }
+
+    fn has_block(&self, blockhash: &BlockHash) -> Result<bool> {
+        Daemon::has_block(self, blockhash)
+    }
+}
+
+fn network_tag(network: Network) -> NetworkTag {
+    NetworkTag {
+        name: network.to_string(),
+        genesis: genesis_block(network).block_hash(),
+    }
+}
+
+fn describe(genesis: &BlockHash) -> String {
+    let network = [
+        Network::Bitcoin,
+        Network::Testnet,
+        Network::Signet,
+        Network::Regtest,
+    ]
+    .iter()
+    .find(|network| genesis_block(**network).block_hash() == *genesis)
+    .map_or_else(|| "unknown".to_owned(), |network| network.to_string());
+    format!("{} network, genesis {}", network, genesis)
+}
+
+/// Verify that the index DB belongs to the configured network and the daemon's chain, before
+/// loading it. On mismatch, the DB is wiped if `force_reindex` is set (otherwise, fail).
+pub(crate) fn check(
+    store: DBStore,
+    network: Network,
+    daemon: &dyn DaemonChain,
+    force_reindex: bool,
+) -> Result<DBStore> {
+    let expected = check_daemon(network, daemon)?;
+    let mismatch = match mismatch(&store, &expected, daemon)? {
+        Some(cause) => cause,
+        None => {
+            store.set_network(expected);
+            return Ok(store);
+        }
+    };
+    if !force_reindex {
+        bail!(
+            "{} (use --force-reindex-on-mismatch to delete the index and re-index)",
+            mismatch
+        );
+    }
+    warn!("{}, going to delete the index and re-index", mismatch);
+    let store = store.reset()?;
+    store.set_network(expected);
+    Ok(store)
+}
+
+/// Same as `check()`, but without modifying the DB (e.g. when it's opened for reading only).
+#[cfg_attr(not(feature = "sqlite_export"), allow(dead_code))]
+pub(crate) fn verify(store: &DBStore, network: Network, daemon: &dyn DaemonChain) -> Result<()> {
+    let expected = check_daemon(network, daemon)?;
+    if let Some(mismatch) = mismatch(store, &expected, daemon)? {
+        bail!("{}", mismatch);
+    }
+    Ok(())
+}
+
+fn check_daemon(network: Network, daemon: &dyn DaemonChain) -> Result<NetworkTag> {
+    let expected = network_tag(network);
+    let daemon_genesis = daemon.genesis_hash()?;
+    if daemon_genesis != expected.genesis {
+        bail!(
+            "bitcoind chain ({}) doesn't match the configured {} network (genesis {})",
+            describe(&daemon_genesis),
+            expected.name,
+            expected.genesis
+        );
+    }
+    Ok(expected)
+}
+
+fn mismatch(
+    store: &DBStore,
+    expected: &NetworkTag,
+    daemon: &dyn DaemonChain,
+) -> Result<Option<String>> {
+    let daemon_chain = describe(&expected.genesis); // same as the daemon's (verified above)
+    if let Some(recorded) = store.get_network() {
+        if recorded.name != expected.name || !daemon.has_block(&recorded.genesis)? {
+            return Ok(Some(format!(
+                "index was built for the {} network (genesis {}), but bitcoind chain has {}",
+                recorded.name, recorded.genesis, daemon_chain
+            )));
+        }
+    }
+    if let Some(row) = store.get_tip() {
+        let tip: BlockHash = deserialize(&row).expect("invalid tip");
+        if !daemon.has_block(&tip)? {
+            return Ok(Some(format!(
+                "index tip {} is not found on bitcoind chain ({})",
+                tip, daemon_chain
+            )));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, network_tag, verify, DaemonChain};
+    use crate::chain::{Chain, NewHeader};
+    use crate::config::DbProfile;
+    use crate::db::{DBStore, WriteBatch};
+    use anyhow::Result;
+    use bitcoin::{consensus::serialize, BlockHash, Network};
+
+    struct MockDaemon {
+        chain: Chain,
+    }
+
+    impl MockDaemon {
+        fn new(network: Network) -> Self {
+            Self {
+                chain: Chain::new(network),
+            }
+        }
+    }
+
+    impl DaemonChain for MockDaemon {
+        fn genesis_hash(&self) -> Result<BlockHash> {
+            Ok(self.chain.get_block_hash(0).unwrap())
+        }
+
+        fn has_block(&self, blockhash: &BlockHash) -> Result<bool> {
+            Ok(self.chain.get_block_height(blockhash).is_some())
+        }
+    }
+
+    fn extend(chain: &mut Chain) -> BlockHash {
+        let mut header = *chain.get_block_header(chain.height()).unwrap();
+        header.prev_blockhash = chain.tip();
+        chain.update(vec![NewHeader::from((header, chain.height() + 1))]);
+        chain.tip()
+    }
+
+    fn set_tip(store: &DBStore, tip: BlockHash) {
+        let batch = WriteBatch {
+            tip_row: serialize(&tip).into_boxed_slice(),
+            ..Default::default()
+        };
+        store.write(&batch).unwrap();
+        store.flush().unwrap();
+    }
+
+    #[test]
+    fn test_consistent() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut daemon = MockDaemon::new(Network::Regtest);
+        let tip = extend(&mut daemon.chain);
+
+        let store = DBStore::open(dir.path(), DbProfile::default(), None, false).unwrap();
+        assert_eq!(store.get_network(), None); // e.g. created by an older version
+        set_tip(&store, tip);
+        let store = check(store, Network::Regtest, &daemon, false).unwrap();
+        assert_eq!(store.get_network(), Some(network_tag(Network::Regtest)));
+
+        let store = check(store, Network::Regtest, &daemon, false).unwrap();
+        assert!(store.get_tip().is_some());
+    }
+
+    #[test]
+    fn test_daemon_network_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let daemon = MockDaemon::new(Network::Testnet);
+        let store = DBStore::open(dir.path(), DbProfile::default(), None, false).unwrap();
+        let err = check(store, Network::Regtest, &daemon, true).err().unwrap();
+        assert!(
+            err.to_string().starts_with(
+                "bitcoind chain (testnet network, genesis 000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943) \
+                 doesn't match the configured regtest network"
+            ),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_index_network_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let daemon = MockDaemon::new(Network::Regtest);
+        {
+            let store = DBStore::open(dir.path(), DbProfile::default(), None, false).unwrap();
+            store.set_network(network_tag(Network::Bitcoin));
+        }
+        let store = DBStore::open(dir.path(), DbProfile::default(), None, false).unwrap();
+        let err = check(store, Network::Regtest, &daemon, false)
+            .err()
+            .unwrap();
+        assert!(
+            err.to_string()
+                .starts_with("index was built for the bitcoin network (genesis 000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f), but bitcoind chain has regtest network"),
+            "{}",
+            err
+        );
+        assert!(err.to_string().contains("--force-reindex-on-mismatch"));
+    }
+
+    #[test]
+    fn test_tip_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let daemon = MockDaemon::new(Network::Regtest);
+        let mut header = *daemon.chain.get_block_header(0).unwrap();
+        header.nonce += 1; // not known to the daemon
+        let unknown_tip = header.block_hash();
+
+        {
+            let store = DBStore::open(dir.path(), DbProfile::default(), None, false).unwrap();
+            set_tip(&store, unknown_tip);
+        }
+        let store = DBStore::open(dir.path(), DbProfile::default(), None, false).unwrap();
+        let err = check(store, Network::Regtest, &daemon, false)
+            .err()
+            .unwrap();
+        assert!(
+            err.to_string().starts_with(&format!(
+                "index tip {} is not found on bitcoind chain (regtest network, genesis ",
+                unknown_tip
+            )),
+            "{}",
+            err
+        );
+
+        // the refusal keeps the DB intact
+        let store = DBStore::open(dir.path(), DbProfile::default(), None, false).unwrap();
+        assert!(store.get_tip().is_some());
+
+        let err = verify(&store, Network::Regtest, &daemon).err().unwrap();
+        assert!(err.to_string().starts_with("index tip "), "{}", err);
+        assert!(store.get_tip().is_some());
+
+        let store = check(store, Network::Regtest, &daemon, true).unwrap();
+        assert!(store.get_tip().is_none());
+        assert_eq!(store.get_network(), Some(network_tag(Network::Regtest)));
+    }
+}
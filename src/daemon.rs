@@ -9,48 +9,118 @@ use serde_json::{json, Value};
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
+use std::time::{Duration, Instant};
 
 use crate::{
     chain::{Chain, NewHeader},
     config::Config,
+    errors::{CodedError, ErrorCode},
+    lock_order::{self, Level},
     metrics::Metrics,
     p2p::Connection,
     signals::ExitFlag,
 };
 
-enum PollResult {
-    Done(Result<()>),
-    Retry,
+/// Retries bitcoind RPC calls while it's starting up (e.g. loading its block index),
+/// or not accepting connections yet.
+struct Warmup<'a> {
+    exit_flag: &'a ExitFlag,
+    deadline: Option<Instant>,
 }
 
-fn rpc_poll(client: &mut Client) -> PollResult {
-    match client.get_blockchain_info() {
-        Ok(info) => {
-            let left_blocks = info.headers - info.blocks;
-            if info.initial_block_download || left_blocks > 0 {
-                info!(
-                    "waiting for {} blocks to download{}",
-                    left_blocks,
-                    if info.initial_block_download {
-                        " (IBD)"
-                    } else {
-                        ""
-                    }
-                );
-                return PollResult::Retry;
+const WARMUP_MIN_DELAY: Duration = Duration::from_millis(100);
+const WARMUP_MAX_DELAY: Duration = Duration::from_secs(5);
+
+impl<'a> Warmup<'a> {
+    fn new(exit_flag: &'a ExitFlag, timeout: Option<Duration>) -> Self {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        Self {
+            exit_flag,
+            deadline,
+        }
+    }
+
+    fn retry<T, F>(&self, desc: &str, mut func: F) -> Result<T>
+    where
+        F: FnMut() -> Result<T>,
+    {
+        let mut delay = WARMUP_MIN_DELAY;
+        loop {
+            self.exit_flag
+                .poll()
+                .with_context(|| format!("bitcoind {} interrupted", desc))?;
+            let err = match func() {
+                Ok(value) => return Ok(value),
+                Err(err) => err,
+            };
+            let reason = match warmup_reason(&err) {
+                Some(reason) => reason,
+                None => return Err(err),
+            };
+            if let Some(deadline) = self.deadline {
+                if Instant::now() + delay > deadline {
+                    return Err(err).context(format!("bitcoind {} timed out", desc));
+                }
             }
-            PollResult::Done(Ok(()))
+            info!("waiting for bitcoind {}: {}", desc, reason);
+            self.sleep(delay)?;
+            delay = std::cmp::min(delay * 2, WARMUP_MAX_DELAY);
         }
-        Err(err) => {
-            if let Some(e) = extract_bitcoind_error(&err) {
+    }
+
+    fn sleep(&self, duration: Duration) -> Result<()> {
+        let until = Instant::now() + duration;
+        loop {
+            self.exit_flag
+                .poll()
+                .context("bitcoind polling interrupted")?;
+            let now = Instant::now();
+            if now >= until {
+                return Ok(());
+            }
+            std::thread::sleep(std::cmp::min(until - now, WARMUP_MIN_DELAY));
+        }
+    }
+}
+
+/// Returns a description of errors which are expected while bitcoind is starting up.
+fn warmup_reason(err: &anyhow::Error) -> Option<String> {
+    use bitcoincore_rpc::jsonrpc::{error::Error::Transport, simple_http};
+    use std::io::ErrorKind;
+
+    let is_startup_io_error = |e: &std::io::Error| {
+        matches!(
+            e.kind(),
+            ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset
+        )
+    };
+    for cause in err.chain() {
+        if let Some(e) = cause.downcast_ref::<bitcoincore_rpc::Error>() {
+            if let Some(e) = extract_bitcoind_error(e) {
                 if e.code == -28 {
-                    info!("waiting for RPC warmup: {}", e.message);
-                    return PollResult::Retry;
+                    return Some(format!("RPC warmup ({})", e.message));
+                }
+            }
+            if let bitcoincore_rpc::Error::JsonRpc(Transport(e)) = e {
+                if let Some(simple_http::Error::SocketError(e)) =
+                    e.downcast_ref::<simple_http::Error>()
+                {
+                    if is_startup_io_error(e) {
+                        return Some(e.to_string());
+                    }
                 }
             }
-            PollResult::Done(Err(err).context("daemon not available"))
+        }
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            if is_startup_io_error(e) {
+                return Some(e.to_string());
+            }
+            if e.kind() == ErrorKind::NotFound {
+                return Some("cookie file not found".to_owned()); // created by bitcoind on startup
+            }
         }
     }
+    None
 }
 
 fn read_cookie(path: &Path) -> Result<(String, String)> {
@@ -94,6 +164,7 @@ fn rpc_connect(config: &Config) -> Result<Client> {
 pub struct Daemon {
     p2p: Mutex<Connection>,
     rpc: Client,
+    txindex: bool,
 }
 
 impl Daemon {
@@ -102,34 +173,45 @@ impl Daemon {
         exit_flag: &ExitFlag,
         metrics: &Metrics,
     ) -> Result<Self> {
-        let mut rpc = rpc_connect(config)?;
+        let warmup = Warmup::new(exit_flag, config.daemon_startup_timeout);
+        let rpc = warmup.retry("connection", || rpc_connect(config))?;
 
-        loop {
-            exit_flag
-                .poll()
-                .context("bitcoin RPC polling interrupted")?;
-            match rpc_poll(&mut rpc) {
-                PollResult::Done(result) => {
-                    result.context("bitcoind RPC polling failed")?;
-                    break; // on success, finish polling
-                }
-                PollResult::Retry => {
-                    std::thread::sleep(std::time::Duration::from_secs(1)); // wait a bit before polling
-                }
+        let info = loop {
+            let info = warmup.retry("RPC polling", || {
+                rpc.get_blockchain_info().context("daemon not available")
+            })?;
+            let left_blocks = info.headers - info.blocks;
+            if !info.initial_block_download && left_blocks == 0 {
+                break info; // on success, finish polling
             }
-        }
+            info!(
+                "waiting for {} blocks to download{}",
+                left_blocks,
+                if info.initial_block_download {
+                    " (IBD)"
+                } else {
+                    ""
+                }
+            );
+            warmup.sleep(Duration::from_secs(1))?; // wait a bit before polling
+        };
 
-        let network_info = rpc.get_network_info()?;
+        let network_info = warmup.retry("network info", || {
+            rpc.get_network_info().context("failed to get network info")
+        })?;
         if network_info.version < 21_00_00 {
             bail!("electrs requires bitcoind 0.21+");
         }
         if !network_info.network_active {
             bail!("electrs requires active bitcoind p2p network");
         }
-        let info = rpc.get_blockchain_info()?;
         if info.pruned {
             bail!("electrs requires non-pruned bitcoind node");
         }
+        let txindex = warmup.retry("index info", || has_txindex(&rpc))?;
+        if !txindex {
+            info!("bitcoind txindex is disabled: only indexed and mempool transactions can be fetched");
+        }
 
         let p2p = Mutex::new(Connection::connect(
             config.network,
@@ -137,7 +219,12 @@ impl Daemon {
             metrics,
             config.signet_magic,
         )?);
-        Ok(Self { p2p, rpc })
+        Ok(Self { p2p, rpc, txindex })
+    }
+
+    /// Whether bitcoind can look up confirmed transactions without a blockhash
+    pub(crate) fn txindex(&self) -> bool {
+        self.txindex
     }
 
     pub(crate) fn estimate_fee(&self, nblocks: u16) -> Result<Option<Amount>> {
@@ -156,6 +243,42 @@ impl Daemon {
             .relay_fee)
     }
 
+    /// The minimal fee rate (per kvB) for entering the mempool, raised by bitcoind when it's full
+    pub(crate) fn get_mempool_min_fee(&self) -> Result<Amount> {
+        let info: Value = self
+            .rpc
+            .call("getmempoolinfo", &[])
+            .context("failed to get mempool info")?;
+        let fee = info["mempoolminfee"]
+            .as_f64()
+            .context("missing mempoolminfee")?;
+        Amount::from_btc(fee).context("invalid mempoolminfee")
+    }
+
+    /// The minimal fee rate increase for replacing a transaction (BIP-125)
+    pub(crate) fn get_incremental_relay_fee(&self) -> Result<Amount> {
+        Ok(self
+            .rpc
+            .get_network_info()
+            .context("failed to get incremental relay fee")?
+            .incremental_fee)
+    }
+
+    /// bitcoind version and user agent
+    pub(crate) fn get_version(&self) -> Result<(usize, String)> {
+        let info = self
+            .rpc
+            .get_network_info()
+            .context("failed to get network info")?;
+        Ok((info.version, info.subversion))
+    }
+
+    pub(crate) fn get_block_count(&self) -> Result<u64> {
+        self.rpc
+            .get_block_count()
+            .context("failed to get block count")
+    }
+
     pub(crate) fn broadcast(&self, tx: &Transaction) -> Result<Txid> {
         self.rpc
             .send_raw_transaction(tx)
@@ -173,7 +296,15 @@ impl Daemon {
                 "getrawtransaction",
                 &[json!(txid), json!(true), json!(blockhash)],
             )
-            .context("failed to get transaction info")
+            .map_err(|e| {
+                transaction_error(
+                    e,
+                    txid,
+                    blockhash,
+                    self.txindex,
+                    "failed to get transaction info",
+                )
+            })
     }
 
     pub(crate) fn get_transaction_hex(
@@ -197,17 +328,48 @@ impl Daemon {
     ) -> Result<Transaction> {
         self.rpc
             .get_raw_transaction(txid, blockhash.as_ref())
-            .context("failed to get transaction")
+            .map_err(|e| {
+                transaction_error(
+                    e,
+                    txid,
+                    blockhash,
+                    self.txindex,
+                    "failed to get transaction",
+                )
+            })
+    }
+
+    pub(crate) fn get_genesis_hash(&self) -> Result<BlockHash> {
+        self.rpc
+            .get_block_hash(0)
+            .context("failed to get genesis block hash")
+    }
+
+    /// Returns `false` if the block is unknown to bitcoind (stale blocks are known).
+    pub(crate) fn has_block(&self, blockhash: &BlockHash) -> Result<bool> {
+        match self.rpc.get_block_header(blockhash) {
+            Ok(_header) => Ok(true),
+            Err(e) if extract_bitcoind_error(&e).map_or(false, |e| e.code == -5) => Ok(false),
+            Err(e) => Err(e).context("failed to get block header"),
+        }
     }
 
     pub(crate) fn get_block_txids(&self, blockhash: BlockHash) -> Result<Vec<Txid>> {
         Ok(self
             .rpc
             .get_block_info(&blockhash)
-            .context("failed to get block txids")?
+            .map_err(|e| block_error(e, &blockhash, "failed to get block txids"))?
             .tx)
     }
 
+    /// Fetched via RPC (unlike `for_blocks()`), so it fails if the block is unavailable (e.g. a
+    /// stale block which bitcoind didn't download).
+    pub(crate) fn get_block(&self, blockhash: BlockHash) -> Result<Block> {
+        self.rpc
+            .get_block(&blockhash)
+            .map_err(|e| block_error(e, &blockhash, "failed to get block"))
+    }
+
     pub(crate) fn get_mempool_txids(&self) -> Result<Vec<Txid>> {
         self.rpc
             .get_raw_mempool()
@@ -221,6 +383,7 @@ impl Daemon {
     }
 
     pub(crate) fn get_new_headers(&self, chain: &Chain) -> Result<Vec<NewHeader>> {
+        let _order = lock_order::acquire(Level::DaemonP2P);
         self.p2p.lock().get_new_headers(chain)
     }
 
@@ -229,16 +392,91 @@ impl Daemon {
         B: IntoIterator<Item = BlockHash>,
         F: FnMut(BlockHash, Block),
     {
+        let _order = lock_order::acquire(Level::DaemonP2P);
         self.p2p.lock().for_blocks(blockhashes, func)
     }
 
     pub(crate) fn new_block_notification(&self) -> Receiver<()> {
+        let _order = lock_order::acquire(Level::DaemonP2P);
         self.p2p.lock().new_block_notification()
     }
 }
 
 pub(crate) type RpcError = bitcoincore_rpc::jsonrpc::error::RpcError;
 
+fn has_txindex(rpc: &Client) -> Result<bool> {
+    let info: Value = rpc
+        .call("getindexinfo", &[])
+        .context("failed to get index info")?;
+    Ok(is_txindex_enabled(&info))
+}
+
+/// `getindexinfo` lists only the enabled indexes (even while they're being built).
+fn is_txindex_enabled(info: &Value) -> bool {
+    info.get("txindex").is_some()
+}
+
+/// Without txindex, bitcoind can't find a confirmed transaction unless its blockhash is specified:
+/// returns a descriptive error in that case, using `ErrorCode::TxNotFound` (otherwise, the original
+/// error with `context`, so it's still returned as a daemon error).
+fn transaction_error(
+    err: bitcoincore_rpc::Error,
+    txid: &Txid,
+    blockhash: Option<BlockHash>,
+    txindex: bool,
+    context: &'static str,
+) -> anyhow::Error {
+    let not_found = extract_bitcoind_error(&err).map_or(false, |e| e.code == -5);
+    if not_found && blockhash.is_none() && !txindex {
+        // don't wrap the daemon error, since it doesn't apply to the client
+        let message = format!(
+            "transaction {} not found: it's not in the mempool or the index, \
+             and bitcoind can't look it up without txindex",
+            txid
+        );
+        return CodedError::new(ErrorCode::TxNotFound, message)
+            .with_data(json!({ "txid": txid }))
+            .into();
+    }
+    anyhow::Error::new(err).context(context)
+}
+
+/// Pruned blocks are reported using `ErrorCode::BlockPruned` (otherwise, the original error with
+/// `context` is returned).
+fn block_error(
+    err: bitcoincore_rpc::Error,
+    blockhash: &BlockHash,
+    context: &'static str,
+) -> anyhow::Error {
+    // bitcoind returns RPC_MISC_ERROR: "Block not available (pruned data)"
+    let pruned = extract_bitcoind_error(&err)
+        .map_or(false, |e| e.code == -1 && e.message.contains("pruned data"));
+    if !pruned {
+        return anyhow::Error::new(err).context(context);
+    }
+    let message = format!("block {} is not available (pruned by bitcoind)", blockhash);
+    let pruned = CodedError::new(ErrorCode::BlockPruned, message)
+        .with_data(json!({ "blockhash": blockhash }));
+    anyhow::Error::new(err).context(pruned)
+}
+
+/// Returns `true` if bitcoind couldn't be reached (as opposed to a failed RPC).
+pub(crate) fn is_unreachable(err: &anyhow::Error) -> bool {
+    use bitcoincore_rpc::jsonrpc::{error::Error::Transport, simple_http};
+
+    for cause in err.chain() {
+        if let Some(bitcoincore_rpc::Error::JsonRpc(Transport(e))) =
+            cause.downcast_ref::<bitcoincore_rpc::Error>()
+        {
+            if let Some(simple_http::Error::SocketError(_)) = e.downcast_ref::<simple_http::Error>()
+            {
+                return true;
+            }
+        }
+    }
+    false
+}
+
 pub(crate) fn extract_bitcoind_error(err: &bitcoincore_rpc::Error) -> Option<&RpcError> {
     use bitcoincore_rpc::{
         jsonrpc::error::Error::Rpc as ServerError, Error::JsonRpc as JsonRpcError,
@@ -248,3 +486,199 @@ pub(crate) fn extract_bitcoind_error(err: &bitcoincore_rpc::Error) -> Option<&Rp
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        block_error, extract_bitcoind_error, is_txindex_enabled, is_unreachable, transaction_error,
+        warmup_reason, Warmup,
+    };
+    use crate::errors::{find_coded, ErrorCode};
+    use crate::signals::ExitFlag;
+    use bitcoin::{hashes::Hash, BlockHash, Txid};
+    use bitcoincore_rpc::jsonrpc::error::{Error as JsonRpcError, RpcError};
+    use serde_json::json;
+    use std::cell::Cell;
+    use std::time::Duration;
+
+    fn rpc_error(code: i32, message: &str) -> bitcoincore_rpc::Error {
+        let err = RpcError {
+            code,
+            message: message.to_owned(),
+            data: None,
+        };
+        bitcoincore_rpc::Error::JsonRpc(JsonRpcError::Rpc(err))
+    }
+
+    fn bitcoind_error(code: i32, message: &str) -> anyhow::Error {
+        anyhow::Error::new(rpc_error(code, message)).context("daemon not available")
+    }
+
+    fn is_bitcoind_error(err: &anyhow::Error) -> bool {
+        err.chain()
+            .filter_map(|cause| cause.downcast_ref::<bitcoincore_rpc::Error>())
+            .any(|e| extract_bitcoind_error(e).is_some())
+    }
+
+    #[test]
+    fn test_is_txindex_enabled() {
+        let synced = json!({"synced": true, "best_block_height": 800000});
+        let syncing = json!({"synced": false, "best_block_height": 1000});
+        assert!(is_txindex_enabled(&json!({ "txindex": synced })));
+        assert!(is_txindex_enabled(&json!({ "txindex": syncing })));
+        assert!(!is_txindex_enabled(&json!({})));
+        assert!(!is_txindex_enabled(
+            &json!({ "basic block filter index": synced })
+        ));
+    }
+
+    #[test]
+    fn test_transaction_error_without_txindex() {
+        let txid = Txid::all_zeros();
+        // returned by `getrawtransaction` for a confirmed transaction, without txindex
+        let not_found = || {
+            rpc_error(
+                -5,
+                "No such mempool transaction. Use -txindex or provide a block hash to enable \
+                 blockchain transaction queries. Use gettransaction for wallet transactions.",
+            )
+        };
+        let err = transaction_error(not_found(), &txid, None, false, "failed to get transaction");
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "transaction {} not found: it's not in the mempool or the index, \
+                 and bitcoind can't look it up without txindex",
+                txid
+            )
+        );
+        // bitcoind's advice (to use -txindex or a block hash) doesn't apply to the client
+        assert!(!is_bitcoind_error(&err));
+        let coded = find_coded(&err).unwrap();
+        assert_eq!(coded.code(), ErrorCode::TxNotFound);
+        assert_eq!(coded.to_value()["data"], json!({ "txid": txid }));
+
+        // with a blockhash hint, bitcoind's error is returned as-is
+        let blockhash = Some(BlockHash::all_zeros());
+        let err = transaction_error(not_found(), &txid, blockhash, false, "failed");
+        assert_eq!(err.to_string(), "failed");
+        assert!(is_bitcoind_error(&err));
+    }
+
+    #[test]
+    fn test_transaction_error_passthrough() {
+        let txid = Txid::all_zeros();
+        let not_found = || rpc_error(-5, "No such mempool or blockchain transaction");
+
+        // bitcoind with txindex (or a blockhash hint) doesn't know this transaction at all, so its
+        // error is returned as-is (with the `daemon-error` code)
+        let err = transaction_error(not_found(), &txid, None, true, "failed to get transaction");
+        assert_eq!(err.to_string(), "failed to get transaction");
+        assert!(is_bitcoind_error(&err));
+        assert!(find_coded(&err).is_none());
+
+        let blockhash = Some(BlockHash::all_zeros());
+        let err = transaction_error(not_found(), &txid, blockhash, false, "failed");
+        assert!(is_bitcoind_error(&err));
+        assert!(find_coded(&err).is_none());
+
+        let err = transaction_error(rpc_error(-8, "bad"), &txid, None, false, "failed");
+        assert_eq!(err.to_string(), "failed");
+        assert!(is_bitcoind_error(&err));
+        assert!(find_coded(&err).is_none());
+    }
+
+    #[test]
+    fn test_block_error() {
+        let blockhash = BlockHash::all_zeros();
+        let err = block_error(
+            rpc_error(-1, "Block not available (pruned data)"),
+            &blockhash,
+            "failed",
+        );
+        assert!(is_bitcoind_error(&err));
+        let coded = find_coded(&err).unwrap();
+        assert_eq!(coded.code(), ErrorCode::BlockPruned);
+        assert_eq!(coded.to_value()["data"], json!({ "blockhash": blockhash }));
+
+        let err = block_error(rpc_error(-5, "Block not found"), &blockhash, "failed");
+        assert_eq!(err.to_string(), "failed");
+        assert!(find_coded(&err).is_none());
+    }
+
+    #[test]
+    fn test_warmup_reason() {
+        let err = bitcoind_error(-28, "Loading block index...");
+        assert_eq!(
+            warmup_reason(&err),
+            Some("RPC warmup (Loading block index...)".to_owned())
+        );
+        assert_eq!(warmup_reason(&bitcoind_error(-8, "bad param")), None);
+
+        let refused = std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+        assert!(warmup_reason(&anyhow::Error::new(refused)).is_some());
+        let denied = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        assert!(warmup_reason(&anyhow::Error::new(denied)).is_none());
+    }
+
+    #[test]
+    fn test_is_unreachable() {
+        use bitcoincore_rpc::jsonrpc::simple_http;
+
+        let refused = std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+        let transport = JsonRpcError::Transport(Box::new(simple_http::Error::SocketError(refused)));
+        let err = anyhow::Error::new(bitcoincore_rpc::Error::JsonRpc(transport))
+            .context("failed to get mempool txids");
+        assert!(is_unreachable(&err));
+
+        // bitcoind is reachable, but the RPC has failed
+        let not_found = bitcoind_error(-5, "No such mempool transaction");
+        assert!(!is_unreachable(&not_found));
+        let status = JsonRpcError::Transport(Box::new(simple_http::Error::HttpErrorCode(500)));
+        let err = anyhow::Error::new(bitcoincore_rpc::Error::JsonRpc(status));
+        assert!(!is_unreachable(&err));
+    }
+
+    #[test]
+    fn test_warmup_retry() {
+        let exit_flag = ExitFlag::new();
+        let warmup = Warmup::new(&exit_flag, None);
+        let calls = Cell::new(0);
+        let result = warmup.retry("test", || {
+            calls.set(calls.get() + 1);
+            if calls.get() <= 3 {
+                return Err(bitcoind_error(-28, "Loading block index..."));
+            }
+            Ok(calls.get())
+        });
+        assert_eq!(result.unwrap(), 4);
+    }
+
+    #[test]
+    fn test_warmup_unexpected_error() {
+        let exit_flag = ExitFlag::new();
+        let warmup = Warmup::new(&exit_flag, None);
+        let calls = Cell::new(0);
+        let result = warmup.retry("test", || -> anyhow::Result<()> {
+            calls.set(calls.get() + 1);
+            Err(bitcoind_error(-1, "unexpected"))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn test_warmup_timeout() {
+        let exit_flag = ExitFlag::new();
+        let warmup = Warmup::new(&exit_flag, Some(Duration::from_millis(250)));
+        let calls = Cell::new(0);
+        let result = warmup.retry("test", || -> anyhow::Result<()> {
+            calls.set(calls.get() + 1);
+            Err(bitcoind_error(-28, "Loading block index..."))
+        });
+        let err = result.unwrap_err();
+        assert_eq!(err.to_string(), "bitcoind test timed out");
+        assert!(warmup_reason(&err).is_some());
+        assert_eq!(calls.get(), 2); // retried after 100ms, then 200ms would exceed the deadline
+    }
+}
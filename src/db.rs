@@ -1,8 +1,12 @@
 use anyhow::{Context, Result};
+use bitcoin::BlockHash;
 use electrs_rocksdb as rocksdb;
 
-use std::path::Path;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::config::DbProfile;
 
 pub(crate) type Row = Box<[u8]>;
 
@@ -13,6 +17,10 @@ pub(crate) struct WriteBatch {
     pub(crate) funding_rows: Vec<Row>,
     pub(crate) spending_rows: Vec<Row>,
     pub(crate) txid_rows: Vec<Row>,
+    pub(crate) txid_prefix_rows: Vec<Row>, // empty, unless the txid prefix index is enabled
+    pub(crate) block_txids_rows: Vec<(Row, Row)>, // empty, unless the block txids index is enabled
+    pub(crate) stale_block_txids_keys: Vec<Row>, // of the blocks replaced by a reorg
+    pub(crate) spender_rows: Vec<(Row, Row)>, // empty, unless the spender index is enabled
 }
 
 impl WriteBatch {
@@ -21,13 +29,64 @@ impl WriteBatch {
         self.funding_rows.sort_unstable();
         self.spending_rows.sort_unstable();
         self.txid_rows.sort_unstable();
+        self.txid_prefix_rows.sort_unstable();
+        self.block_txids_rows.sort_unstable();
+        self.spender_rows.sort_unstable();
     }
 }
 
 /// RocksDB wrapper for index storage
 pub struct DBStore {
     db: rocksdb::DB,
+    tuning: Tuning, // to re-create the DB after deletion
     bulk_import: AtomicBool,
+    writes_until_failure: AtomicUsize, // may be set by tests, to simulate a full disk
+}
+
+/// A failed (or stopped) DB write, e.g. due to a full disk.
+/// Since each `WriteBatch` is written atomically, none of its rows are indexed.
+#[derive(Debug)]
+pub(crate) struct WriteError {
+    path: PathBuf,
+    free_bytes: Option<u64>,
+    reason: String,
+}
+
+impl WriteError {
+    fn new(path: &Path, reason: impl fmt::Display) -> Self {
+        Self {
+            path: path.to_owned(),
+            free_bytes: free_bytes(path),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "failed to write to DB at {} (", self.path.display())?;
+        match self.free_bytes {
+            Some(free_bytes) => write!(f, "{:.3} GB free", free_bytes as f64 / 1e9)?,
+            None => write!(f, "free space is unknown")?,
+        }
+        write!(
+            f,
+            "): {} - free some disk space, and indexing will resume automatically",
+            self.reason
+        )
+    }
+}
+
+impl std::error::Error for WriteError {}
+
+/// Returns `true` if the index couldn't be updated (as opposed to a failed sync).
+pub(crate) fn is_write_error(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| cause.is::<WriteError>())
+}
+
+/// Available space in the file system containing `path` (if it can be queried).
+fn free_bytes(path: &Path) -> Option<u64> {
+    fs2::available_space(path).ok()
 }
 
 const CONFIG_CF: &str = "config";
@@ -35,8 +94,20 @@ const HEADERS_CF: &str = "headers";
 const TXID_CF: &str = "txid";
 const FUNDING_CF: &str = "funding";
 const SPENDING_CF: &str = "spending";
+const TXID_PREFIX_CF: &str = "txid_prefix";
+const BLOCK_TXIDS_CF: &str = "block_txids";
+const SPENDER_CF: &str = "spender";
 
-const COLUMN_FAMILIES: &[&str] = &[CONFIG_CF, HEADERS_CF, TXID_CF, FUNDING_CF, SPENDING_CF];
+const COLUMN_FAMILIES: &[&str] = &[
+    CONFIG_CF,
+    HEADERS_CF,
+    TXID_CF,
+    FUNDING_CF,
+    SPENDING_CF,
+    TXID_PREFIX_CF,
+    BLOCK_TXIDS_CF,
+    SPENDER_CF,
+];
 
 const CONFIG_KEY: &str = "C";
 const TIP_KEY: &[u8] = b"T";
@@ -82,6 +153,21 @@ const DB_PROPERIES: &[&str] = &[
 struct Config {
     compacted: bool,
     format: u64,
+    #[serde(default)]
+    network: Option<NetworkTag>, // missing in DBs created by older versions
+    #[serde(default)]
+    txid_prefix_index: bool, // whether all the indexed blocks have txid prefix rows
+    #[serde(default)]
+    block_txids_index: bool, // whether new blocks' txids are indexed (older ones are backfilled)
+    #[serde(default)]
+    spender_index: bool, // whether all the indexed blocks have spender rows
+}
+
+/// The network (and its genesis block) that the DB is indexing
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub(crate) struct NetworkTag {
+    pub(crate) name: String,
+    pub(crate) genesis: BlockHash,
 }
 
 const CURRENT_FORMAT: u64 = 0;
@@ -91,38 +177,173 @@ impl Default for Config {
         Config {
             compacted: false,
             format: CURRENT_FORMAT,
+            network: None,
+            txid_prefix_index: false,
+            block_txids_index: false,
+            spender_index: false,
         }
     }
 }
 
-fn default_opts() -> rocksdb::Options {
+/// RocksDB options which are derived from the configured `DbProfile`.
+///
+/// Only options which can be changed between runs are included: the compaction style, prefix
+/// extractor and table format are the same for all profiles (since existing SST files depend on
+/// them), and each SST file records its own compression type and filter.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Tuning {
+    block_cache_size: usize, // per column family
+    write_buffer_size: usize,
+    max_write_buffer_number: i32,
+    db_write_buffer_size: usize, // shared by all column families (0 means unlimited)
+    max_background_jobs: i32,
+    bloom_filter_bits: i32,
+    compression_per_level: &'static [rocksdb::DBCompressionType],
+}
+
+const ZSTD_PER_LEVEL: &[rocksdb::DBCompressionType] = &[rocksdb::DBCompressionType::Zstd; 7];
+const FAST_PER_LEVEL: &[rocksdb::DBCompressionType] = &[
+    rocksdb::DBCompressionType::None,
+    rocksdb::DBCompressionType::None,
+    rocksdb::DBCompressionType::Snappy,
+    rocksdb::DBCompressionType::Snappy,
+    rocksdb::DBCompressionType::Zstd,
+    rocksdb::DBCompressionType::Zstd,
+    rocksdb::DBCompressionType::Zstd,
+];
+
+impl Tuning {
+    pub(crate) fn new(profile: DbProfile) -> Self {
+        match profile {
+            DbProfile::LowMemory => Self {
+                block_cache_size: 8 << 20,
+                write_buffer_size: 16 << 20,
+                max_write_buffer_number: 2,
+                db_write_buffer_size: 64 << 20,
+                max_background_jobs: 2,
+                bloom_filter_bits: 10,
+                compression_per_level: ZSTD_PER_LEVEL,
+            },
+            DbProfile::Balanced => Self {
+                block_cache_size: 32 << 20,
+                write_buffer_size: 256 << 20,
+                max_write_buffer_number: 2,
+                db_write_buffer_size: 0,
+                max_background_jobs: 4,
+                bloom_filter_bits: 10,
+                compression_per_level: ZSTD_PER_LEVEL,
+            },
+            DbProfile::Throughput => Self {
+                block_cache_size: 256 << 20,
+                write_buffer_size: 512 << 20,
+                max_write_buffer_number: 4,
+                db_write_buffer_size: 0,
+                max_background_jobs: 8,
+                bloom_filter_bits: 16,
+                compression_per_level: FAST_PER_LEVEL,
+            },
+        }
+    }
+
+    /// Upper bound of block caches and memtables usage
+    fn memory_budget(&self) -> usize {
+        let memtables =
+            self.write_buffer_size * self.max_write_buffer_number as usize * COLUMN_FAMILIES.len();
+        let memtables = match self.db_write_buffer_size {
+            0 => memtables,
+            limit => std::cmp::min(memtables, limit),
+        };
+        self.block_cache_size * COLUMN_FAMILIES.len() + memtables
+    }
+
+    /// Fail if the profile may use more memory than the configured limit (if any).
+    fn check_memory_limit(&self, profile: DbProfile, limit: Option<usize>) -> Result<()> {
+        let budget = self.memory_budget();
+        match limit {
+            Some(limit) if budget > limit => bail!(
+                "{:?} DB profile may use up to {} MB, above db_memory_limit_mb = {} \
+                (use a smaller db_profile)",
+                profile,
+                budget >> 20,
+                limit >> 20
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Tuning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} MB block cache per column family, {} x {} MB write buffers",
+            self.block_cache_size >> 20,
+            self.max_write_buffer_number,
+            self.write_buffer_size >> 20,
+        )?;
+        if self.db_write_buffer_size > 0 {
+            write!(
+                f,
+                " (up to {} MB in total)",
+                self.db_write_buffer_size >> 20
+            )?;
+        }
+        write!(
+            f,
+            ", {} background jobs, {} bloom filter bits per key, compression per level {:?}",
+            self.max_background_jobs, self.bloom_filter_bits, self.compression_per_level
+        )
+    }
+}
+
+fn default_opts(tuning: &Tuning) -> rocksdb::Options {
+    let mut opts = base_opts(tuning);
+    opts.set_prefix_extractor(rocksdb::SliceTransform::create_fixed_prefix(8));
+    opts
+}
+
+/// Without a prefix extractor, for column families scanned by arbitrary-length prefixes.
+fn base_opts(tuning: &Tuning) -> rocksdb::Options {
     let mut opts = rocksdb::Options::default();
     opts.set_keep_log_file_num(10);
     opts.set_max_open_files(16);
     opts.set_compaction_style(rocksdb::DBCompactionStyle::Level);
-    opts.set_compression_type(rocksdb::DBCompressionType::Zstd);
+    opts.set_compression_per_level(tuning.compression_per_level);
     opts.set_target_file_size_base(256 << 20);
-    opts.set_write_buffer_size(256 << 20);
+    opts.set_write_buffer_size(tuning.write_buffer_size);
+    opts.set_max_write_buffer_number(tuning.max_write_buffer_number);
+    opts.set_db_write_buffer_size(tuning.db_write_buffer_size);
+    opts.set_max_background_jobs(tuning.max_background_jobs);
+    let mut table_opts = rocksdb::BlockBasedOptions::default();
+    table_opts.set_lru_cache(tuning.block_cache_size);
+    table_opts.set_bloom_filter(tuning.bloom_filter_bits, false);
+    opts.set_block_based_table_factory(&table_opts);
     opts.set_disable_auto_compactions(true); // for initial bulk load
     opts.set_advise_random_on_open(false); // bulk load uses sequential I/O
-    opts.set_prefix_extractor(rocksdb::SliceTransform::create_fixed_prefix(8));
     opts
 }
 
 impl DBStore {
-    fn create_cf_descriptors() -> Vec<rocksdb::ColumnFamilyDescriptor> {
+    fn create_cf_descriptors(tuning: &Tuning) -> Vec<rocksdb::ColumnFamilyDescriptor> {
         COLUMN_FAMILIES
             .iter()
-            .map(|&name| rocksdb::ColumnFamilyDescriptor::new(name, default_opts()))
+            .map(|&name| {
+                let opts = match name {
+                    TXID_PREFIX_CF => base_opts(tuning),
+                    _ => default_opts(tuning),
+                };
+                rocksdb::ColumnFamilyDescriptor::new(name, opts)
+            })
             .collect()
     }
 
-    fn open_internal(path: &Path) -> Result<Self> {
-        let mut db_opts = default_opts();
+    fn open_internal(path: &Path, tuning: Tuning) -> Result<Self> {
+        let mut db_opts = default_opts(&tuning);
         db_opts.create_if_missing(true);
         db_opts.create_missing_column_families(true);
 
-        let db = rocksdb::DB::open_cf_descriptors(&db_opts, path, Self::create_cf_descriptors())
+        let cf_descriptors = Self::create_cf_descriptors(&tuning);
+        let db = rocksdb::DB::open_cf_descriptors(&db_opts, path, cf_descriptors)
             .with_context(|| format!("failed to open DB: {}", path.display()))?;
         let live_files = db.live_files()?;
         info!(
@@ -134,7 +355,9 @@ impl DBStore {
         );
         let store = DBStore {
             db,
+            tuning,
             bulk_import: AtomicBool::new(true),
+            writes_until_failure: AtomicUsize::new(usize::MAX),
         };
         Ok(store)
     }
@@ -147,9 +370,24 @@ impl DBStore {
             .is_some()
     }
 
-    /// Opens a new RocksDB at the specified location.
-    pub fn open(path: &Path, auto_reindex: bool) -> Result<Self> {
-        let mut store = Self::open_internal(path)?;
+    /// Opens a new RocksDB at the specified location, failing if the profile's block caches and
+    /// write buffers may use more than `memory_limit` bytes.
+    pub fn open(
+        path: &Path,
+        profile: DbProfile,
+        memory_limit: Option<usize>,
+        auto_reindex: bool,
+    ) -> Result<Self> {
+        let tuning = Tuning::new(profile);
+        info!(
+            "{:?}: using {:?} DB profile: {} (up to {} MB)",
+            path,
+            profile,
+            tuning,
+            tuning.memory_budget() >> 20
+        );
+        tuning.check_memory_limit(profile, memory_limit)?;
+        let mut store = Self::open_internal(path, tuning)?;
         let config = store.get_config();
         debug!("DB {:?}", config);
         let mut config = config.unwrap_or_default(); // use default config when DB is empty
@@ -173,15 +411,7 @@ impl DBStore {
                 cause,
                 path.display()
             );
-            // close DB before deletion
-            drop(store);
-            rocksdb::DB::destroy(&default_opts(), path).with_context(|| {
-                format!(
-                    "re-index required but the old database ({}) can not be deleted",
-                    path.display()
-                )
-            })?;
-            store = Self::open_internal(path)?;
+            store = store.reset()?;
             config = Config::default(); // re-init config after dropping DB
         }
         if config.compacted {
@@ -191,6 +421,148 @@ impl DBStore {
         Ok(store)
     }
 
+    /// Open an existing DB for reading only (e.g. while it's being used by a running server).
+    /// The returned store reflects the DB's state as of opening it.
+    #[cfg_attr(not(feature = "sqlite_export"), allow(dead_code))]
+    pub(crate) fn open_read_only(path: &Path, profile: DbProfile) -> Result<Self> {
+        let tuning = Tuning::new(profile);
+        let opts = default_opts(&tuning);
+        // column families added by newer versions are created only when opened for writing
+        let existing = rocksdb::DB::list_cf(&opts, path).unwrap_or_default();
+        let names = COLUMN_FAMILIES
+            .iter()
+            .filter(|&&name| existing.is_empty() || existing.iter().any(|cf| cf == name));
+        let db = rocksdb::DB::open_cf_for_read_only(&opts, path, names, false)
+            .with_context(|| format!("failed to open DB for reading: {}", path.display()))?;
+        let store = DBStore {
+            db,
+            tuning,
+            bulk_import: AtomicBool::new(false),
+            writes_until_failure: AtomicUsize::new(usize::MAX),
+        };
+        match store.get_config() {
+            None => bail!("DB is not indexed: {}", path.display()),
+            Some(config) if config.format != CURRENT_FORMAT => bail!(
+                "re-index required due to unsupported format {} != {}",
+                config.format,
+                CURRENT_FORMAT
+            ),
+            Some(_) => Ok(store),
+        }
+    }
+
+    /// Delete the DB and re-create it empty.
+    pub(crate) fn reset(self) -> Result<Self> {
+        let path = self.db.path().to_owned();
+        let tuning = self.tuning;
+        // close DB before deletion
+        drop(self);
+        rocksdb::DB::destroy(&default_opts(&tuning), &path).with_context(|| {
+            format!(
+                "re-index required but the old database ({}) can not be deleted",
+                path.display()
+            )
+        })?;
+        Self::open_internal(&path, tuning)
+    }
+
+    pub(crate) fn set_txid_prefix_index(self, enabled: bool, auto_reindex: bool) -> Result<Self> {
+        self.set_full_index(
+            "txid_prefix_index",
+            |config| &mut config.txid_prefix_index,
+            enabled,
+            auto_reindex,
+        )
+    }
+
+    pub(crate) fn set_spender_index(self, enabled: bool, auto_reindex: bool) -> Result<Self> {
+        self.set_full_index(
+            "spender_index",
+            |config| &mut config.spender_index,
+            enabled,
+            auto_reindex,
+        )
+    }
+
+    /// Some optional indexes must be built from the first block, so enabling one on an existing
+    /// index requires re-indexing (and disabling it makes the existing rows outdated).
+    fn set_full_index(
+        self,
+        name: &str,
+        flag: fn(&mut Config) -> &mut bool,
+        enabled: bool,
+        auto_reindex: bool,
+    ) -> Result<Self> {
+        let mut config = self.get_config().unwrap_or_default();
+        if *flag(&mut config) == enabled {
+            return Ok(self);
+        }
+        let mut store = self;
+        if enabled && store.get_tip().is_some() {
+            let cause = format!("{} being enabled on an index built without it", name);
+            if !auto_reindex {
+                bail!(
+                    "re-index required due to {} (enable auto_reindex, or disable {})",
+                    cause,
+                    name
+                );
+            }
+            let path = store.db.path().to_owned();
+            warn!(
+                "Database needs to be re-indexed due to {}, going to delete {}",
+                cause,
+                path.display()
+            );
+            store = store.reset()?;
+            // the network was verified before, and the other indexes' flags hold for an empty DB
+            config.compacted = false;
+        }
+        if !enabled {
+            warn!(
+                "{} is disabled, so enabling it again will require re-indexing",
+                name.replace('_', " ")
+            );
+        }
+        *flag(&mut config) = enabled;
+        store.set_config(config);
+        Ok(store)
+    }
+
+    pub(crate) fn has_txid_prefix_index(&self) -> bool {
+        self.get_config()
+            .map_or(false, |config| config.txid_prefix_index)
+    }
+
+    /// Unlike the txid prefix index, the blocks indexed without their txids are backfilled on
+    /// demand (see `Tracker::get_block_txids()`), so it may be enabled without re-indexing.
+    pub(crate) fn set_block_txids_index(&self, enabled: bool) {
+        let mut config = self.get_config().unwrap_or_default();
+        if config.block_txids_index != enabled {
+            config.block_txids_index = enabled;
+            self.set_config(config);
+        }
+    }
+
+    pub(crate) fn has_spender_index(&self) -> bool {
+        self.get_config()
+            .map_or(false, |config| config.spender_index)
+    }
+
+    pub(crate) fn has_block_txids_index(&self) -> bool {
+        self.get_config()
+            .map_or(false, |config| config.block_txids_index)
+    }
+
+    pub(crate) fn get_network(&self) -> Option<NetworkTag> {
+        self.get_config().and_then(|config| config.network)
+    }
+
+    pub(crate) fn set_network(&self, network: NetworkTag) {
+        let mut config = self.get_config().unwrap_or_default();
+        config.network = Some(network);
+        self.set_config(config);
+    }
+
     fn config_cf(&self) -> &rocksdb::ColumnFamily {
         self.db.cf_handle(CONFIG_CF).expect("missing CONFIG_CF")
     }
@@ -211,6 +583,22 @@ impl DBStore {
         self.db.cf_handle(HEADERS_CF).expect("missing HEADERS_CF")
     }
 
+    fn txid_prefix_cf(&self) -> &rocksdb::ColumnFamily {
+        self.db
+            .cf_handle(TXID_PREFIX_CF)
+            .expect("missing TXID_PREFIX_CF")
+    }
+
+    fn spender_cf(&self) -> &rocksdb::ColumnFamily {
+        self.db.cf_handle(SPENDER_CF).expect("missing SPENDER_CF")
+    }
+
+    fn block_txids_cf(&self) -> &rocksdb::ColumnFamily {
+        self.db
+            .cf_handle(BLOCK_TXIDS_CF)
+            .expect("missing BLOCK_TXIDS_CF")
+    }
+
     pub(crate) fn iter_funding(&self, prefix: Row) -> impl Iterator<Item = Row> + '_ {
         self.iter_prefix_cf(self.funding_cf(), prefix)
     }
@@ -223,6 +611,17 @@ impl DBStore {
         self.iter_prefix_cf(self.txid_cf(), prefix)
     }
 
+    /// Unlike the other scans, the prefix may be shorter than 8 bytes.
+    pub(crate) fn iter_txid_prefix(&self, prefix: Row) -> impl Iterator<Item = Row> + '_ {
+        let mode = rocksdb::IteratorMode::From(&prefix, rocksdb::Direction::Forward);
+        let opts = rocksdb::ReadOptions::default();
+        let rows = self
+            .db
+            .iterator_cf_opt(self.txid_prefix_cf(), opts, mode)
+            .map(|(key, _value)| key);
+        rows.take_while(move |key| key.starts_with(&prefix))
+    }
+
     fn iter_prefix_cf(
         &self,
         cf: &rocksdb::ColumnFamily,
@@ -236,6 +635,30 @@ impl DBStore {
             .map(|(key, _value)| key) // values are empty in prefix-scanned CFs
     }
 
+    /// Unlike the other column families, the rows' values are used (see `BlockTxidsRow`).
+    pub(crate) fn get_block_txids(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.db
+            .get_cf(self.block_txids_cf(), key)
+            .expect("get_block_txids failed")
+    }
+
+    /// Store the txids of a block that was indexed without them (e.g. before the block txids
+    /// index was enabled).
+    pub(crate) fn put_block_txids(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.check_writable()?;
+        self.db
+            .put_cf(self.block_txids_cf(), key, value)
+            .map_err(|e| self.write_error(e))?;
+        Ok(())
+    }
+
+    /// Unlike the other column families, the rows' values are used (see `SpenderRow`).
+    pub(crate) fn get_spender(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.db
+            .get_cf(self.spender_cf(), key)
+            .expect("get_spender failed")
+    }
+
     pub(crate) fn read_headers(&self) -> Vec<Row> {
         let mut opts = rocksdb::ReadOptions::default();
         opts.fill_cache(false);
@@ -252,7 +675,9 @@ impl DBStore {
             .expect("get_tip failed")
     }
 
-    pub(crate) fn write(&self, batch: &WriteBatch) {
+    /// Rows and the new tip are written atomically: on failure, none of them are indexed.
+    pub(crate) fn write(&self, batch: &WriteBatch) -> Result<()> {
+        self.check_writable()?;
         let mut db_batch = rocksdb::WriteBatch::default();
         for key in &batch.funding_rows {
             db_batch.put_cf(self.funding_cf(), key, b"");
@@ -263,6 +688,18 @@ impl DBStore {
         for key in &batch.txid_rows {
             db_batch.put_cf(self.txid_cf(), key, b"");
         }
+        for key in &batch.txid_prefix_rows {
+            db_batch.put_cf(self.txid_prefix_cf(), key, b"");
+        }
+        for key in &batch.stale_block_txids_keys {
+            db_batch.delete_cf(self.block_txids_cf(), key);
+        }
+        for (key, value) in &batch.block_txids_rows {
+            db_batch.put_cf(self.block_txids_cf(), key, value);
+        }
+        for (key, value) in &batch.spender_rows {
+            db_batch.put_cf(self.spender_cf(), key, value);
+        }
         for key in &batch.header_rows {
             db_batch.put_cf(self.headers_cf(), key, b"");
         }
@@ -272,14 +709,18 @@ impl DBStore {
         let bulk_import = self.bulk_import.load(Ordering::Relaxed);
         opts.set_sync(!bulk_import);
         opts.disable_wal(bulk_import);
-        self.db.write_opt(db_batch, &opts).unwrap();
+        self.db
+            .write_opt(db_batch, &opts)
+            .map_err(|e| self.write_error(e))?;
+        Ok(())
     }
 
-    pub(crate) fn flush(&self) {
+    pub(crate) fn flush(&self) -> Result<()> {
+        self.check_writable()?;
         let mut config = self.get_config().unwrap_or_default();
         for name in COLUMN_FAMILIES {
             let cf = self.db.cf_handle(name).expect("missing CF");
-            self.db.flush_cf(cf).expect("CF flush failed");
+            self.db.flush_cf(cf).map_err(|e| self.write_error(e))?;
         }
         if !config.compacted {
             for name in COLUMN_FAMILIES {
@@ -288,7 +729,7 @@ impl DBStore {
                 self.db.compact_range_cf(cf, None::<&[u8]>, None::<&[u8]>);
             }
             config.compacted = true;
-            self.set_config(config);
+            self.put_config(&config).map_err(|e| self.write_error(e))?;
             info!("finished full compaction");
             self.start_compactions();
         }
@@ -300,21 +741,69 @@ impl DBStore {
                 .expect("missing property");
             trace!("RocksDB stats: {}", stats);
         }
+        Ok(())
+    }
+
+    /// Fail early if RocksDB stopped accepting writes (e.g. after a background flush or
+    /// compaction has failed), instead of blocking until it recovers.
+    fn check_writable(&self) -> Result<()> {
+        match self.writes_until_failure.load(Ordering::Relaxed) {
+            0 => return Err(self.write_error("injected failure").into()),
+            usize::MAX => (),
+            writes => self
+                .writes_until_failure
+                .store(writes - 1, Ordering::Relaxed),
+        }
+        let is_stopped = self
+            .db
+            .property_int_value("rocksdb.is-write-stopped")
+            .map_err(|e| self.write_error(e))?;
+        if is_stopped.unwrap_or(0) != 0 {
+            let errors = self
+                .db
+                .property_int_value("rocksdb.background-errors")
+                .map_err(|e| self.write_error(e))?;
+            let reason = format!(
+                "writes are stopped by RocksDB ({} background errors)",
+                errors.unwrap_or(0)
+            );
+            return Err(self.write_error(reason).into());
+        }
+        Ok(())
+    }
+
+    fn write_error(&self, reason: impl fmt::Display) -> WriteError {
+        WriteError::new(self.db.path(), reason)
+    }
+
+    /// Make the following writes (and flushes) fail, until reset.
+    #[cfg(test)]
+    pub(crate) fn inject_write_failures(&self, fail: bool) {
+        self.inject_write_failures_after(if fail { 0 } else { usize::MAX });
+    }
+
+    /// Make the writes (and flushes) fail after the given number of successful ones.
+    #[cfg(test)]
+    pub(crate) fn inject_write_failures_after(&self, writes: usize) {
+        self.writes_until_failure.store(writes, Ordering::Relaxed);
     }
 
     pub(crate) fn get_properties(
         &self,
     ) -> impl Iterator<Item = (&'static str, &'static str, u64)> + '_ {
-        COLUMN_FAMILIES.iter().flat_map(move |cf_name| {
-            let cf = self.db.cf_handle(cf_name).expect("missing CF");
-            DB_PROPERIES.iter().filter_map(move |property_name| {
-                let value = self
-                    .db
-                    .property_int_value_cf(cf, property_name)
-                    .expect("failed to get property");
-                Some((*cf_name, *property_name, value?))
+        COLUMN_FAMILIES
+            .iter()
+            // may be missing in a read-only DB, created by an older version
+            .filter_map(move |cf_name| Some((cf_name, self.db.cf_handle(cf_name)?)))
+            .flat_map(move |(cf_name, cf)| {
+                DB_PROPERIES.iter().filter_map(move |property_name| {
+                    let value = self
+                        .db
+                        .property_int_value_cf(cf, property_name)
+                        .expect("failed to get property");
+                    Some((*cf_name, *property_name, value?))
+                })
             })
-        })
     }
 
     fn start_compactions(&self) {
@@ -329,13 +818,16 @@ impl DBStore {
     }
 
     fn set_config(&self, config: Config) {
+        self.put_config(&config).expect("DB::put failed");
+    }
+
+    fn put_config(&self, config: &Config) -> Result<(), rocksdb::Error> {
         let mut opts = rocksdb::WriteOptions::default();
         opts.set_sync(true);
         opts.disable_wal(false);
-        let value = serde_json::to_vec(&config).expect("failed to serialize config");
+        let value = serde_json::to_vec(config).expect("failed to serialize config");
         self.db
             .put_cf_opt(self.config_cf(), CONFIG_KEY, value, &opts)
-            .expect("DB::put failed");
     }
 
     fn get_config(&self) -> Option<Config> {
@@ -354,19 +846,26 @@ impl Drop for DBStore {
 
 #[cfg(test)]
 mod tests {
-    use super::{rocksdb, DBStore, WriteBatch, CURRENT_FORMAT};
+    use super::{is_write_error, rocksdb, DBStore, DbProfile, Tuning, WriteBatch, CURRENT_FORMAT};
+
+    /// RocksDB memory usage upper bound (excluding index and filter blocks) of the `low_memory`
+    /// profile - documented in `doc/config.md`.
+    const LOW_MEMORY_BUDGET: usize = 128 << 20;
 
     #[test]
     fn test_reindex_new_format() {
         let dir = tempfile::tempdir().unwrap();
         {
-            let store = DBStore::open(dir.path(), false).unwrap();
+            let store = DBStore::open(dir.path(), DbProfile::default(), None, false).unwrap();
             let mut config = store.get_config().unwrap();
             config.format += 1;
             store.set_config(config);
         };
         assert_eq!(
-            DBStore::open(dir.path(), false).err().unwrap().to_string(),
+            DBStore::open(dir.path(), DbProfile::default(), None, false)
+                .err()
+                .unwrap()
+                .to_string(),
             format!(
                 "re-index required due to unsupported format {} != {}",
                 CURRENT_FORMAT + 1,
@@ -374,8 +873,8 @@ mod tests {
             )
         );
         {
-            let store = DBStore::open(dir.path(), true).unwrap();
-            store.flush();
+            let store = DBStore::open(dir.path(), DbProfile::default(), None, true).unwrap();
+            store.flush().unwrap();
             let config = store.get_config().unwrap();
             assert_eq!(config.format, CURRENT_FORMAT);
             assert_eq!(store.is_legacy_format(), false);
@@ -392,21 +891,139 @@ mod tests {
             db.put(b"F", b"").unwrap(); // insert legacy DB compaction marker (in 'default' column family)
         };
         assert_eq!(
-            DBStore::open(dir.path(), false).err().unwrap().to_string(),
+            DBStore::open(dir.path(), DbProfile::default(), None, false)
+                .err()
+                .unwrap()
+                .to_string(),
             format!("re-index required due to legacy format",)
         );
         {
-            let store = DBStore::open(dir.path(), true).unwrap();
-            store.flush();
+            let store = DBStore::open(dir.path(), DbProfile::default(), None, true).unwrap();
+            store.flush().unwrap();
             let config = store.get_config().unwrap();
             assert_eq!(config.format, CURRENT_FORMAT);
         }
     }
 
+    #[test]
+    fn test_profiles() {
+        let low = Tuning::new(DbProfile::LowMemory);
+        let balanced = Tuning::new(DbProfile::Balanced);
+        let throughput = Tuning::new(DbProfile::Throughput);
+        assert!(low.memory_budget() <= LOW_MEMORY_BUDGET);
+        assert!(low.memory_budget() < balanced.memory_budget());
+        assert!(balanced.memory_budget() < throughput.memory_budget());
+        assert!(low
+            .check_memory_limit(DbProfile::LowMemory, Some(LOW_MEMORY_BUDGET))
+            .is_ok());
+        assert!(throughput
+            .check_memory_limit(DbProfile::Throughput, Some(LOW_MEMORY_BUDGET))
+            .is_err());
+        assert!(throughput
+            .check_memory_limit(DbProfile::Throughput, None)
+            .is_ok());
+        assert_eq!(
+            low.to_string(),
+            "8 MB block cache per column family, 2 x 16 MB write buffers (up to 64 MB in total), \
+            2 background jobs, 10 bloom filter bits per key, compression per level \
+            [Zstd, Zstd, Zstd, Zstd, Zstd, Zstd, Zstd]"
+        );
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let limit = Some(LOW_MEMORY_BUDGET);
+        let err = DBStore::open(dir.path(), DbProfile::Throughput, limit, false)
+            .err()
+            .unwrap();
+        assert!(
+            err.to_string().contains("db_memory_limit_mb = 128"),
+            "{}",
+            err
+        );
+        assert!(DBStore::open(dir.path(), DbProfile::LowMemory, limit, false).is_ok());
+    }
+
+    #[test]
+    fn test_change_profile() {
+        let dir = tempfile::tempdir().unwrap();
+        let batch = WriteBatch {
+            tip_row: b"tip".to_vec().into_boxed_slice(),
+            funding_rows: vec![b"A1234567".to_vec().into_boxed_slice()],
+            ..Default::default()
+        };
+        let profiles = [
+            DbProfile::Throughput,
+            DbProfile::LowMemory,
+            DbProfile::Balanced,
+        ];
+        for profile in &profiles {
+            let store = DBStore::open(dir.path(), *profile, None, false).unwrap();
+            store.write(&batch).unwrap();
+            store.flush().unwrap();
+            assert_eq!(store.get_tip().as_deref(), Some(&b"tip"[..]));
+            let rows: Vec<_> = store.iter_funding(b"A1234567".to_vec().into()).collect();
+            assert_eq!(rows.len(), 1);
+        }
+    }
+
+    #[test]
+    fn test_open_read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(DBStore::open_read_only(dir.path(), DbProfile::default()).is_err()); // missing DB
+
+        let store = DBStore::open(dir.path(), DbProfile::default(), None, false).unwrap();
+        let batch = WriteBatch {
+            tip_row: b"tip".to_vec().into_boxed_slice(),
+            ..Default::default()
+        };
+        store.write(&batch).unwrap();
+        store.flush().unwrap();
+
+        // may be opened while the DB is in use
+        let reader = DBStore::open_read_only(dir.path(), DbProfile::default()).unwrap();
+        assert_eq!(reader.get_tip(), Some(b"tip".to_vec()));
+    }
+
+    #[test]
+    fn test_write_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DBStore::open(dir.path(), DbProfile::default(), None, false).unwrap();
+        let batch = |tip: &[u8]| WriteBatch {
+            tip_row: tip.to_vec().into_boxed_slice(),
+            txid_rows: vec![tip.to_vec().into_boxed_slice()],
+            ..Default::default()
+        };
+        store.write(&batch(b"tip00001")).unwrap();
+
+        // e.g. the disk is full
+        store.inject_write_failures(true);
+        let err = store.write(&batch(b"tip00002")).unwrap_err();
+        assert!(is_write_error(&err));
+        let msg = err.to_string();
+        assert!(msg.contains(&dir.path().display().to_string()), "{}", msg);
+        assert!(msg.contains("injected failure"), "{}", msg);
+        assert!(msg.contains("GB free"), "{}", msg); // of the DB's file system
+        assert!(is_write_error(&store.flush().unwrap_err()));
+        assert!(!is_write_error(&anyhow!("other error")));
+
+        // nothing is written
+        assert_eq!(store.get_tip(), Some(b"tip00001".to_vec()));
+        let rows = store.iter_txid(b"tip00002".to_vec().into_boxed_slice());
+        assert_eq!(rows.count(), 0);
+
+        // the disk has some free space again
+        store.inject_write_failures(false);
+        store.write(&batch(b"tip00002")).unwrap();
+        store.flush().unwrap();
+        assert_eq!(store.get_tip(), Some(b"tip00002".to_vec()));
+    }
+
     #[test]
     fn test_db_prefix_scan() {
         let dir = tempfile::tempdir().unwrap();
-        let store = DBStore::open(dir.path(), true).unwrap();
+        let store = DBStore::open(dir.path(), DbProfile::default(), None, true).unwrap();
 
         let items: &[&[u8]] = &[
             b"ab",
@@ -421,12 +1038,129 @@ mod tests {
 
         let mut batch = WriteBatch::default();
         batch.txid_rows = to_rows(&items);
-        store.write(&batch);
+        store.write(&batch).unwrap();
 
         let rows = store.iter_txid(b"abcdefgh".to_vec().into_boxed_slice());
         assert_eq!(rows.collect::<Vec<_>>(), to_rows(&items[1..5]));
     }
 
+    #[test]
+    fn test_txid_prefix_scan() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DBStore::open(dir.path(), DbProfile::default(), None, true).unwrap();
+
+        let items: &[&[u8]] = &[b"\x12", b"\x12\x34", b"\x12\x34\x56", b"\x12\x35", b"\x13"];
+
+        let mut batch = WriteBatch::default();
+        batch.txid_prefix_rows = to_rows(&items);
+        store.write(&batch).unwrap();
+
+        // prefixes may be shorter than the other column families' fixed prefix length
+        let rows = store.iter_txid_prefix(b"\x12\x34".to_vec().into_boxed_slice());
+        assert_eq!(rows.collect::<Vec<_>>(), to_rows(&items[1..3]));
+        let rows = store.iter_txid_prefix(b"\x12".to_vec().into_boxed_slice());
+        assert_eq!(rows.collect::<Vec<_>>(), to_rows(&items[..4]));
+    }
+
+    #[test]
+    fn test_enable_txid_prefix_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DBStore::open(dir.path(), DbProfile::default(), None, false).unwrap();
+        assert!(!store.has_txid_prefix_index());
+
+        // an empty index may be enabled without re-indexing
+        let store = store.set_txid_prefix_index(true, false).unwrap();
+        assert!(store.has_txid_prefix_index());
+        let store = store.set_txid_prefix_index(false, false).unwrap();
+        assert!(!store.has_txid_prefix_index());
+
+        let batch = WriteBatch {
+            tip_row: b"tip".to_vec().into_boxed_slice(),
+            ..Default::default()
+        };
+        store.write(&batch).unwrap();
+        assert_eq!(
+            store.set_txid_prefix_index(true, false).err().unwrap().to_string(),
+            "re-index required due to txid_prefix_index being enabled on an index built without it \
+             (enable auto_reindex, or disable txid_prefix_index)"
+        );
+
+        let store = DBStore::open(dir.path(), DbProfile::default(), None, false).unwrap();
+        let store = store.set_txid_prefix_index(true, true).unwrap();
+        assert!(store.has_txid_prefix_index());
+        assert_eq!(store.get_tip(), None);
+    }
+
+    #[test]
+    fn test_enable_spender_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DBStore::open(dir.path(), DbProfile::default(), None, false).unwrap();
+        let store = store.set_txid_prefix_index(true, false).unwrap();
+        let batch = WriteBatch {
+            tip_row: b"tip".to_vec().into_boxed_slice(),
+            spender_rows: vec![(to_row(b"outpoint"), to_row(b"spender"))],
+            ..Default::default()
+        };
+        store.write(&batch).unwrap();
+        assert_eq!(store.get_spender(b"outpoint"), Some(b"spender".to_vec()));
+        assert_eq!(
+            store
+                .set_spender_index(true, false)
+                .err()
+                .unwrap()
+                .to_string(),
+            "re-index required due to spender_index being enabled on an index built without it \
+             (enable auto_reindex, or disable spender_index)"
+        );
+
+        let store = DBStore::open(dir.path(), DbProfile::default(), None, false).unwrap();
+        let store = store.set_spender_index(true, true).unwrap();
+        assert!(store.has_spender_index());
+        assert!(store.has_txid_prefix_index()); // holds for the re-created (empty) index
+        assert_eq!(store.get_tip(), None);
+        assert_eq!(store.get_spender(b"outpoint"), None);
+    }
+
+    #[test]
+    fn test_block_txids() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DBStore::open(dir.path(), DbProfile::default(), None, false).unwrap();
+        assert!(!store.has_block_txids_index());
+        store.set_block_txids_index(true);
+        assert!(store.has_block_txids_index());
+        assert_eq!(store.get_block_txids(b"block1"), None);
+
+        let batch = WriteBatch {
+            block_txids_rows: vec![(to_row(b"block1"), to_row(b"txids1"))],
+            ..Default::default()
+        };
+        store.write(&batch).unwrap();
+        store.put_block_txids(b"block2", b"txids2").unwrap(); // e.g. backfilled
+        assert_eq!(store.get_block_txids(b"block1"), Some(b"txids1".to_vec()));
+        assert_eq!(store.get_block_txids(b"block2"), Some(b"txids2".to_vec()));
+
+        // replaced by a reorg
+        let batch = WriteBatch {
+            block_txids_rows: vec![(to_row(b"block3"), to_row(b"txids3"))],
+            stale_block_txids_keys: vec![to_row(b"block2")],
+            ..Default::default()
+        };
+        store.write(&batch).unwrap();
+        assert_eq!(store.get_block_txids(b"block1"), Some(b"txids1".to_vec()));
+        assert_eq!(store.get_block_txids(b"block2"), None);
+        assert_eq!(store.get_block_txids(b"block3"), Some(b"txids3".to_vec()));
+
+        store.inject_write_failures(true);
+        assert!(is_write_error(
+            &store.put_block_txids(b"block4", b"txids4").unwrap_err()
+        ));
+        assert_eq!(store.get_block_txids(b"block4"), None);
+    }
+
+    fn to_row(value: &[u8]) -> Box<[u8]> {
+        value.to_vec().into_boxed_slice()
+    }
+
     fn to_rows(values: &[&[u8]]) -> Vec<Box<[u8]>> {
         values
             .iter()
@@ -0,0 +1,225 @@
+use anyhow::Result;
+use bitcoin::secp256k1::{Secp256k1, Verification};
+use miniscript::descriptor::{ConversionError, Descriptor, DescriptorPublicKey, DescriptorType};
+
+use std::str::FromStr;
+
+use crate::types::ScriptHash;
+
+const INPUT_CHARSET: &str = "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+const CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+fn poly_mod(mut c: u64, value: u64) -> u64 {
+    let c0 = c >> 35;
+    c = ((c & 0x7ffffffff) << 5) ^ value;
+    for (i, generator) in [
+        0xf5dee51989,
+        0xa9fdca3312,
+        0x1bab10e32d,
+        0x3706b1677a,
+        0x644d626ffd,
+    ]
+    .iter()
+    .enumerate()
+    {
+        if (c0 >> i) & 1 != 0 {
+            c ^= generator;
+        }
+    }
+    c
+}
+
+/// Append the checksum (see BIP-380) to a descriptor, e.g. one which can't be parsed by
+/// `PublicDescriptor` (such as `addr(...)` or `raw(...)`).
+pub(crate) fn with_checksum(desc: &str) -> String {
+    let mut c = 1;
+    let mut class = 0;
+    let mut class_count = 0;
+    for ch in desc.chars() {
+        let pos = INPUT_CHARSET
+            .find(ch)
+            .expect("invalid descriptor character") as u64;
+        c = poly_mod(c, pos & 31);
+        class = class * 3 + (pos >> 5);
+        class_count += 1;
+        if class_count == 3 {
+            c = poly_mod(c, class);
+            class = 0;
+            class_count = 0;
+        }
+    }
+    if class_count > 0 {
+        c = poly_mod(c, class);
+    }
+    for _ in 0..8 {
+        c = poly_mod(c, 0);
+    }
+    c ^= 1;
+    let checksum: String = (0..8)
+        .map(|i| CHECKSUM_CHARSET[((c >> (5 * (7 - i))) & 31) as usize] as char)
+        .collect();
+    format!("{}#{}", desc, checksum)
+}
+
+/// A parsed output descriptor (containing only public keys)
+pub(crate) struct PublicDescriptor {
+    desc: Descriptor<DescriptorPublicKey>,
+}
+
+impl PublicDescriptor {
+    /// Parse a descriptor, verifying its checksum (if specified).
+    pub(crate) fn parse(value: &str) -> Result<Self> {
+        let desc = Descriptor::<DescriptorPublicKey>::from_str(value.trim())
+            .map_err(|e| anyhow!("invalid descriptor: {}", e))?;
+        if desc.is_multipath() {
+            bail!("unsupported descriptor: multipath derivation is not supported");
+        }
+        let result = Self { desc };
+        if let Err(script_type) = result.script_type() {
+            bail!("unsupported script type: {}", script_type);
+        }
+        Ok(result)
+    }
+
+    /// Canonical form, including the checksum
+    pub(crate) fn canonical(&self) -> String {
+        self.desc.to_string()
+    }
+
+    pub(crate) fn checksum(&self) -> String {
+        let canonical = self.canonical();
+        let mut parts = canonical.rsplitn(2, '#');
+        parts.next().unwrap().to_owned()
+    }
+
+    /// Returns `Err` with the script type name, if it's not supported.
+    pub(crate) fn script_type(&self) -> std::result::Result<&'static str, &'static str> {
+        match self.desc.desc_type() {
+            DescriptorType::Bare => Err("bare"),
+            DescriptorType::Sh => Ok("sh"),
+            DescriptorType::Pkh => Ok("pkh"),
+            DescriptorType::Wpkh => Ok("wpkh"),
+            DescriptorType::Wsh => Ok("wsh"),
+            DescriptorType::ShWsh => Ok("sh-wsh"),
+            DescriptorType::ShWpkh => Ok("sh-wpkh"),
+            DescriptorType::ShSortedMulti => Ok("sh-sortedmulti"),
+            DescriptorType::WshSortedMulti => Ok("wsh-sortedmulti"),
+            DescriptorType::ShWshSortedMulti => Ok("sh-wsh-sortedmulti"),
+            DescriptorType::Tr => Ok("tr"),
+        }
+    }
+
+    pub(crate) fn has_wildcard(&self) -> bool {
+        self.desc.has_wildcard()
+    }
+
+    /// Derive the scripthash at `index` (ignored if the descriptor has no wildcard).
+    pub(crate) fn scripthash<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        index: u32,
+    ) -> Result<ScriptHash> {
+        let derived = self
+            .desc
+            .derived_descriptor(secp, index)
+            .map_err(|e| match e {
+                ConversionError::HardenedChild | ConversionError::HardenedWildcard => anyhow!(
+                    "hardened derivation requires a private key (index {}): {}",
+                    index,
+                    e
+                ),
+                e => anyhow!("failed to derive index {}: {}", index, e),
+            })?;
+        Ok(ScriptHash::new(&derived.script_pubkey()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{with_checksum, PublicDescriptor};
+    use bitcoin::secp256k1::Secp256k1;
+
+    const XPUB: &str = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+
+    fn scripthashes(desc: &PublicDescriptor, count: u32) -> Vec<String> {
+        let secp = Secp256k1::verification_only();
+        (0..count)
+            .map(|index| desc.scripthash(&secp, index).unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_wpkh() {
+        let desc = PublicDescriptor::parse(&format!("wpkh({}/0/*)", XPUB)).unwrap();
+        assert_eq!(desc.checksum(), "wvk84d79");
+        assert_eq!(desc.canonical(), format!("wpkh({}/0/*)#wvk84d79", XPUB));
+        assert_eq!(desc.script_type(), Ok("wpkh"));
+        assert!(desc.has_wildcard());
+        assert_eq!(
+            scripthashes(&desc, 3),
+            vec![
+                "0caf908c5780a1e7e480c7ea2bfe4b163decac1aca3d8c47c15bb75f2c168ebb",
+                "c7e48413eccaa0e0c718251a4e080223ab4c54a0d2167c247178dcc1e9c2db83",
+                "1b61f50e0c88c203f441424a309d9a1892c71ad9329e0adf7960f00ac009455d",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sh_wpkh_and_pkh() {
+        let desc = PublicDescriptor::parse(&format!("sh(wpkh({}/0/*))#knyhj9av", XPUB)).unwrap();
+        assert_eq!(desc.script_type(), Ok("sh-wpkh"));
+        assert_eq!(
+            scripthashes(&desc, 2),
+            vec![
+                "d19b7d955e916deaa60505a8d385f8fdd49f6b05f6e6686af0548466e477dc54",
+                "a75a29eeaec7dbf11c4daab33231ddf4bae5b547c9cea7931fd5fec292513628",
+            ]
+        );
+
+        let desc = PublicDescriptor::parse(&format!("pkh({}/0/*)#xgqkr0nt", XPUB)).unwrap();
+        assert_eq!(desc.script_type(), Ok("pkh"));
+        assert_eq!(
+            scripthashes(&desc, 1),
+            vec!["168700aecbcb3d7cb1a5de1ded16677c2c546c125244fc1bcc859d8f9f769e62"]
+        );
+    }
+
+    #[test]
+    fn test_with_checksum() {
+        assert_eq!(with_checksum("raw(deadbeef)"), "raw(deadbeef)#89f8spxm");
+        assert_eq!(
+            with_checksum("addr(mkmZxiEcEd8ZqjQWVZuC6so5dFMKEFpN2j)"),
+            "addr(mkmZxiEcEd8ZqjQWVZuC6so5dFMKEFpN2j)#02wpgw69"
+        );
+        for desc in &[
+            format!("wpkh({}/0/*)", XPUB),
+            format!("sh(wpkh({}/0/*))", XPUB),
+        ] {
+            let parsed = PublicDescriptor::parse(desc).unwrap();
+            assert_eq!(with_checksum(desc), parsed.canonical());
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let bad_checksum = format!("wpkh({}/0/*)#wvk84d78", XPUB);
+        let err = PublicDescriptor::parse(&bad_checksum).err().unwrap();
+        assert!(
+            err.to_string().starts_with("invalid descriptor: "),
+            "{}",
+            err
+        );
+
+        let bare = "pk(0339a36013301597daef41fbe593a02cc513d0b55527ec2df1050e2e8ff49c85c2)";
+        let err = PublicDescriptor::parse(bare).err().unwrap();
+        assert_eq!(err.to_string(), "unsupported script type: bare");
+
+        let secp = Secp256k1::verification_only();
+        let hardened = PublicDescriptor::parse(&format!("wpkh({}/0/*h)", XPUB)).unwrap();
+        let err = hardened.scripthash(&secp, 0).err().unwrap();
+        assert!(err
+            .to_string()
+            .starts_with("hardened derivation requires a private key"));
+    }
+}
@@ -1,27 +1,105 @@
 use anyhow::{bail, Context, Result};
-use bitcoin::{consensus::{deserialize, encode::serialize_hex}, hashes::hex::FromHex, BlockHash, Txid, Amount};
+use bitcoin::{
+    block::Header,
+    consensus::{deserialize, encode::serialize_hex},
+    Amount, BlockHash, Network, OutPoint, Script, ScriptBuf, Transaction, Txid,
+};
 use crossbeam_channel::Receiver;
+use parking_lot::Mutex;
 use rayon::prelude::*;
-use serde_derive::Deserialize;
+use serde::de::{self, value::MapAccessDeserializer};
+use serde_derive::{Deserialize, Serialize};
 use serde_json::{self, json, Value};
-use std::collections::{hash_map::Entry, HashMap};
+use std::collections::{hash_map::Entry, HashMap, HashSet, VecDeque};
+use std::fmt;
 use std::iter::FromIterator;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::{
+    audit::AuditLog,
     cache::Cache,
-    config::{Config, ELECTRS_VERSION},
+    cancel::{self, CancelToken},
+    chain::Chain,
+    clock::Clock,
+    codec::{Codec, DecodeError, Frame},
+    config::{Config, SyncServing, ELECTRS_VERSION},
     daemon::{self, extract_bitcoind_error, Daemon},
-    merkle::Proof,
-    metrics::{self, Histogram, Metrics},
-    signals::Signal,
-    status::ScriptHashStatus,
+    db,
+    descriptor::PublicDescriptor,
+    errors::{self, ensure_enabled, CodedError, ErrorCode},
+    hex::{self, BytesParam, HashParam},
+    hostnames::Hostnames,
+    index::BlockEvent,
+    lock_order::{self, Level},
+    merkle::{ChainedProof, Proof, Tree},
+    metrics::{self, Counter, Gauge, Histogram, Info, Metrics},
+    mirrors::Mirrors,
+    peers::{Announcement, Peers},
+    protocol::{
+        BlockTxidsSubscribeResult, CacheFlushResult, CacheStatsResult, CborCodec, DecodedHeader,
+        DescriptorInfoResult, EmptyResultInfo, ExportedProof, Extensions, FeaturesResult,
+        FeeEstimate, FeeHistogramResult, HeaderByHashResult, HeaderProofResult, HeadersResult,
+        HeadersSubscribeResult, HistoryDeltas, HistoryEntryWire, HistoryOrder, IdFromPosResult,
+        IdleUnsubscribe, LegacyIdFromPosResult, MempoolFees, MempoolInfoResult, MerkleResult,
+        NotificationsDebounce, PrefixMatch, ProofsExportResult, PsbtSpender, RequestLimits,
+        SearchPrefixResult, SelectMode, SelectStrategy, SelectUnspentOptions, SelectUnspentResult,
+        ServerInfoResult, SpenderResult, StaleUnspentResult, TransactionsGetEntry,
+        UnspentEntryWire, VerboseHistoryResult,
+    },
+    psbt,
+    rbf::{self, Change},
+    reputation::{Reputation, Violation},
+    reservations::{self, Reservations},
+    sanitize::Sanitized,
+    scheduler::{Priority, Scheduler},
+    signals::ExitFlag,
+    status::{ScriptHashStatus, MAX_DELTA_ENTRIES},
+    status_cache::StatusCache,
     tracker::Tracker,
+    txinfo,
     types::ScriptHash,
+    webhooks::Webhooks,
+};
+use crate::status::{
+    Balance, HistoryEntry, OutPointStatus, UnspentEntry, UnspentSnapshot, WatchedTx,
 };
-use crate::status::UnspentEntry;
 
 const PROTOCOL_VERSION: &str = "1.4";
-const UNKNOWN_FEE: isize = -1; // (allowed by Electrum protocol)
+const MAX_DESCRIPTOR_DERIVATIONS: usize = 1000;
+const MAX_BROADCAST_KEYS: usize = 100; // per client
+const MAX_BROADCAST_KEY_LEN: usize = 128;
+const MAX_NOTIFIED_TXIDS: usize = 10_000; // larger blocks are notified in chunks
+const MAX_EXPORTED_PROOFS: usize = 100; // each one requires fetching its block's txids
+const MAX_ANALYZED_PSBT_INPUTS: usize = 100; // each one may require fetching a few blocks
+const CODEC_METHOD: &str = "server.codec";
+/// Methods allowed only on admin connections (see `--electrum-admin-addr`)
+const ADMIN_METHODS: &[&str] = &[
+    "server.cache.flush",
+    "server.cache.stats",
+    "server.bans.list",
+    "server.bans.unban",
+    "blockchain.block.txids.subscribe",
+    "proofs.export",
+    "server.webhooks.stats",
+    "server.broadcast_mirrors.stats",
+    "server.orphaned",
+];
+const MIN_TXID_PREFIX_LEN: usize = 8; // hex digits (shorter prefixes may match too many txids)
+const MAX_TXID_PREFIX_MATCHES: usize = 100;
+const MAX_REORG_NOTIFIED_HEADERS: usize = 100; // deeper reorgs notify only the new tip
+const MIN_BULK_SUBSCRIPTIONS: usize = 100; // larger batches' statuses are built at a lower priority
+const MAX_DEBOUNCE_MS: u64 = 60_000;
+const MAX_DEBOUNCED_NOTIFICATIONS: usize = 10_000; // per client (flushed early when exceeded)
+const INPUT_VSIZE: u64 = 68; // of a P2WPKH input, for estimating the selected UTXOs' fees
+const MAX_BNB_ITERATIONS: usize = 100_000; // per amount (the best selection so far is used)
+const MAX_SELECT_FEE_RATE: f64 = 10_000.0; // sat/vB (bitcoind's default -maxfeerate)
+const PADDED_INPUTS: usize = 3; // of the default selections (unless disabled)
+const CANCEL_CHECK_INTERVAL: usize = 1_000; // history entries handled between cancellation checks
+const DAEMON_INFO_REFRESH_INTERVAL: Duration = Duration::from_secs(600);
 
 const UNSUBSCRIBED_QUERY_MESSAGE: &str = "your wallet uses less efficient method of querying electrs, consider contacting the developer of your wallet. Reason:";
 
@@ -29,848 +107,7736 @@ const UNSUBSCRIBED_QUERY_MESSAGE: &str = "your wallet uses less efficient method
 #[derive(Default)]
 pub struct Client {
     tip: Option<BlockHash>,
-    scripthashes: HashMap<ScriptHash, ScriptHashStatus>,
-}
-
-#[derive(Deserialize)]
-struct Request {
-    id: Value,
-    method: String,
-
-    #[serde(default)]
-    params: Value,
+    decoded_headers: bool, // include the decoded fields in headers' notifications
+    scripthashes: HashMap<ScriptHash, Subscription>,
+    prefetched: HashMap<ScriptHash, ScriptHashStatus>, // for the current batch's queries
+    outpoints: HashMap<OutPoint, OutPointStatus>,      // see `blockchain.outpoint.subscribe`
+    transactions: HashMap<Txid, Option<WatchedTx>>,    // see `blockchain.transaction.subscribe`
+    watched_synced: Option<(BlockHash, u64)>,          // tip and mempool version of the above
+    broadcasts: BroadcastKeys,
+    block_txids: bool,             // subscribed to new blocks' txids
+    memory_usage: usize,           // of the subscriptions' statuses (approximate)
+    peer_addr: Option<SocketAddr>, // for the audit log and peers' reputation
+    codec: Codec,                  // of the following messages (see `server.codec`)
+    strict_params: bool,           // opted in using `server.strict_params`
+    history_deltas: bool,          // notified with the subscriptions (see `server.history_deltas`)
+    hostname: Option<String>,      // requested by the client (e.g. using TLS SNI)
+    debounced: Debounced,          // scripthash notifications (see `server.notifications.debounce`)
+    cancel: CancelToken,           // set when the client disconnects
+    admin: bool,                   // connected via the admin RPC address
+    /// Set by the last successful `server.version` call (required before `server.codec`)
+    negotiated_version: Option<ProtocolVersion>,
 }
 
-#[derive(Deserialize)]
-#[serde(untagged)]
-enum Requests {
-    Single(Request),
-    Batch(Vec<Request>),
-}
+impl Client {
+    pub(crate) fn new(peer_addr: Option<SocketAddr>, hostname: Option<String>) -> Self {
+        Self {
+            peer_addr,
+            hostname,
+            ..Default::default()
+        }
+    }
 
-#[derive(Deserialize, Debug, PartialEq, Eq)]
-#[serde(untagged)]
-enum Version {
-    Single(String),
-    Range(String, String),
-}
+    /// Abandon the client's long-running requests when `cancel` is set (i.e. on disconnection).
+    pub(crate) fn with_cancel(self, cancel: CancelToken) -> Self {
+        Self { cancel, ..self }
+    }
 
-#[derive(Deserialize)]
-#[serde(untagged)]
-enum TxGetArgs {
-    Txid((Txid,)),
-    TxidVerbose(Txid, bool),
-}
+    /// Allow the client to call the admin methods (see `ADMIN_METHODS`).
+    pub(crate) fn with_admin(self, admin: bool) -> Self {
+        Self { admin, ..self }
+    }
 
-impl From<&TxGetArgs> for (Txid, bool) {
-    fn from(args: &TxGetArgs) -> Self {
-        match args {
-            TxGetArgs::Txid((txid,)) => (*txid, false),
-            TxGetArgs::TxidVerbose(txid, verbose) => (*txid, *verbose),
+    /// Identifies the client's UTXO reservations (see `blockchain.scripthash.select_unspent`)
+    fn reservation_owner(&self, scripthash: ScriptHash) -> reservations::Owner {
+        reservations::Owner {
+            scripthash,
+            client: self.cancel.clone(),
         }
     }
-}
 
-enum StandardError {
-    ParseError,
-    InvalidRequest,
-    MethodNotFound,
-    InvalidParams,
-}
+    /// Last tip notified to this client (if subscribed to headers)
+    pub(crate) fn tip(&self) -> Option<BlockHash> {
+        self.tip
+    }
 
-enum RpcError {
-    // JSON-RPC spec errors
-    Standard(StandardError),
-    // Electrum-specific errors
-    BadRequest(anyhow::Error),
-    DaemonError(daemon::RpcError),
-    UnavailableIndex,
-}
+    /// Approximate memory used by the subscriptions' statuses (in bytes)
+    pub(crate) fn memory_usage(&self) -> usize {
+        self.memory_usage
+    }
 
-impl RpcError {
-    fn to_value(&self) -> Value {
-        match self {
-            RpcError::Standard(err) => match err {
-                StandardError::ParseError => json!({"code": -32700, "message": "parse error"}),
-                StandardError::InvalidRequest => {
-                    json!({"code": -32600, "message": "invalid request"})
-                }
-                StandardError::MethodNotFound => {
-                    json!({"code": -32601, "message": "method not found"})
-                }
-                StandardError::InvalidParams => {
-                    json!({"code": -32602, "message": "invalid params"})
-                }
-            },
-            RpcError::BadRequest(err) => json!({"code": 1, "message": err.to_string()}),
-            RpcError::DaemonError(err) => json!({"code": 2, "message": err.message}),
-            RpcError::UnavailableIndex => {
-                // Internal JSON-RPC error (https://www.jsonrpc.org/specification#error_object)
-                json!({"code": -32603, "message": "unavailable index"})
-            }
-        }
+    /// The number of subscribed scripthashes
+    pub(crate) fn subscriptions(&self) -> usize {
+        self.scripthashes.len()
     }
-}
 
-/// Electrum RPC handler
-pub struct Rpc {
-    tracker: Tracker,
-    cache: Cache,
-    rpc_duration: Histogram,
-    daemon: Daemon,
-    signal: Signal,
-    banner: String,
-    port: u16,
-}
+    pub(crate) fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
 
-impl Rpc {
-    /// Perform initial index sync (may take a while on first run).
-    pub fn new(config: &Config, metrics: Metrics) -> Result<Self> {
-        let rpc_duration = metrics.histogram_vec(
-            "rpc_duration",
-            "RPC duration (in seconds)",
-            "method",
-            metrics::default_duration_buckets(),
-        );
+    pub(crate) fn codec(&self) -> Codec {
+        self.codec
+    }
 
-        let tracker = Tracker::new(config, metrics)?;
-        let signal = Signal::new();
-        let daemon = Daemon::connect(config, signal.exit_flag(), tracker.metrics())?;
-        let cache = Cache::new(tracker.metrics());
-        Ok(Self {
-            tracker,
-            cache,
-            rpc_duration,
-            daemon,
-            signal,
-            banner: config.server_banner.clone(),
-            port: config.electrum_rpc_addr.port(),
-        })
+    /// When the debounced notifications should be flushed (if any are pending)
+    pub(crate) fn debounce_deadline(&self) -> Option<Instant> {
+        self.debounced.deadline
     }
 
-    pub(crate) fn signal(&self) -> &Signal {
-        &self.signal
+    /// Take all the debounced notifications (e.g. before disconnecting).
+    pub(crate) fn flush_notifications(&mut self) -> Vec<Value> {
+        let pending = self.debounced.take();
+        self.subscribed_notifications(pending)
     }
 
-    pub fn new_block_notification(&self) -> Receiver<()> {
-        self.daemon.new_block_notification()
+    /// Take the debounced notifications, if their window has ended.
+    fn flush_due_notifications(&mut self, now: Instant) -> Vec<Value> {
+        let pending = self.debounced.take_due(now);
+        self.subscribed_notifications(pending)
     }
 
-    pub fn sync(&mut self) -> Result<bool> {
-        self.tracker.sync(&self.daemon, self.signal.exit_flag())
+    /// Subscribe to the transaction, after updating its status (using `update`), returning its
+    /// height (or null if it's unknown). It's not kept if already confirmed by `confirmations`.
+    fn subscribe_transaction(
+        &mut self,
+        txid: Txid,
+        limit: usize,
+        confirmations: Option<usize>,
+        chain: &Chain,
+        update: impl FnOnce(&mut Option<WatchedTx>) -> Result<bool>,
+    ) -> Result<Value> {
+        check_subscriptions_limit(
+            self.transactions.contains_key(&txid),
+            self.transactions.len(),
+            limit,
+            "transaction",
+        )?;
+        let mut status = self.transactions.get(&txid).cloned().flatten();
+        update(&mut status)?;
+        let result = json!(status.as_ref().map(WatchedTx::height));
+        if is_deeply_confirmed(status.as_ref(), chain, confirmations) {
+            self.transactions.remove(&txid);
+        } else {
+            self.transactions.insert(txid, status);
+        }
+        Ok(result)
     }
 
-    pub fn update_client(&self, client: &mut Client) -> Result<Vec<String>> {
-        let chain = self.tracker.chain();
-        let mut notifications = client
-            .scripthashes
+    /// Update the subscribed transactions' statuses (using `update`), returning the changed ones'
+    /// notifications, and dropping the ones confirmed by `confirmations` blocks.
+    fn update_transactions(
+        &mut self,
+        confirmations: Option<usize>,
+        chain: &Chain,
+        update: impl Fn(Txid, &mut Option<WatchedTx>) -> Result<bool> + Sync + Send,
+    ) -> Result<Vec<Value>> {
+        let notifications = self
+            .transactions
             .par_iter_mut()
-            .filter_map(|(scripthash, status)| -> Option<Result<Value>> {
-                match self
-                    .tracker
-                    .update_scripthash_status(status, &self.daemon, &self.cache)
-                {
-                    Ok(true) => Some(Ok(notification(
-                        "blockchain.scripthash.subscribe",
-                        &[json!(scripthash), json!(status.statushash())],
-                    ))),
-                    Ok(false) => None, // statushash is the same
-                    Err(e) => Some(Err(e)),
-                }
+            .filter_map(|(txid, status)| match update(*txid, status) {
+                Ok(true) => Some(Ok(transaction_notification(txid, status.as_ref()))),
+                Ok(false) => None, // status is the same
+                Err(e) => Some(Err(e)),
             })
-            .collect::<Result<Vec<Value>>>()
-            .context("failed to update status")?;
-
-        if let Some(old_tip) = client.tip {
-            let new_tip = self.tracker.chain().tip();
-            if old_tip != new_tip {
-                client.tip = Some(new_tip);
-                let height = chain.height();
-                let header = chain.get_block_header(height).unwrap();
-                notifications.push(notification(
-                    "blockchain.headers.subscribe",
-                    &[json!({"hex": serialize_hex(&header), "height": height})],
-                ));
-            }
-        }
-        Ok(notifications.into_iter().map(|v| v.to_string()).collect())
+            .collect::<Result<Vec<Value>>>()?;
+        // the deeply confirmed transactions are not expected to change
+        self.transactions
+            .retain(|_txid, status| !is_deeply_confirmed(status.as_ref(), chain, confirmations));
+        Ok(notifications)
     }
 
-    fn headers_subscribe(&self, client: &mut Client) -> Result<Value> {
-        let chain = self.tracker.chain();
-        client.tip = Some(chain.tip());
-        let height = chain.height();
-        let header = chain.get_block_header(height).unwrap();
-        Ok(json!({"hex": serialize_hex(header), "height": height}))
+    /// Drop the notifications of scripthashes unsubscribed since they were debounced.
+    fn subscribed_notifications(&self, mut pending: Vec<(ScriptHash, Value)>) -> Vec<Value> {
+        pending.retain(|(scripthash, _)| self.scripthashes.contains_key(scripthash));
+        pending.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        pending
+            .into_iter()
+            .map(|(_, notification)| notification)
+            .collect()
     }
 
-    fn block_header(&self, (height,): (usize,)) -> Result<Value> {
-        let chain = self.tracker.chain();
-        let header = match chain.get_block_header(height) {
-            None => bail!("no header at {}", height),
-            Some(header) => header,
-        };
-        Ok(json!(serialize_hex(header)))
+    fn status(&self, scripthash: &ScriptHash) -> Option<&ScriptHashStatus> {
+        self.scripthashes
+            .get(scripthash)
+            .map(|subscription| &subscription.status)
     }
 
-    fn block_headers(&self, (start_height, count): (usize, usize)) -> Result<Value> {
-        let chain = self.tracker.chain();
-        let max_count = 2016usize;
-        // return only the available block headers
-        let end_height = std::cmp::min(
-            chain.height() + 1,
-            start_height + std::cmp::min(count, max_count),
-        );
-        let heights = start_height..end_height;
-        let count = heights.len();
-        let hex_headers =
-            heights.filter_map(|height| chain.get_block_header(height).map(serialize_hex));
-
-        Ok(json!({"count": count, "hex": String::from_iter(hex_headers), "max": max_count}))
+    /// Mark a subscription as used by the client (if subscribed).
+    fn touch(&mut self, scripthash: &ScriptHash, now: Instant) {
+        if let Some(subscription) = self.scripthashes.get_mut(scripthash) {
+            subscription.touched = now;
+        }
     }
 
-    fn estimate_fee(&self, (nblocks,): (u16,)) -> Result<Value> {
-        Ok(self
-            .daemon
-            .estimate_fee(nblocks)?
-            .map(|fee_rate| json!(fee_rate.to_btc()))
-            .unwrap_or_else(|| json!(UNKNOWN_FEE)))
+    /// Drop the subscriptions which were neither notified nor queried during the last `timeout`,
+    /// returning their scripthashes (sorted).
+    fn unsubscribe_idle(&mut self, now: Instant, timeout: Duration) -> Vec<ScriptHash> {
+        let mut idle: Vec<ScriptHash> = self
+            .scripthashes
+            .iter()
+            .filter(|(_, subscription)| {
+                now.saturating_duration_since(subscription.touched) >= timeout
+            })
+            .map(|(scripthash, _)| *scripthash)
+            .collect();
+        idle.sort_unstable();
+        for scripthash in &idle {
+            let subscription = self.scripthashes.remove(scripthash).unwrap();
+            self.memory_usage = self
+                .memory_usage
+                .saturating_sub(subscription.status.memory_usage());
+        }
+        idle
     }
+}
 
-    fn relayfee(&self) -> Result<Value> {
-        Ok(json!(self.daemon.get_relay_fee()?.to_btc())) // [BTC/kB]
-    }
+/// A subscribed scripthash status, and when it was last notified or queried
+struct Subscription {
+    status: ScriptHashStatus,
+    touched: Instant,
+}
 
-    fn scripthash_get_balance(
-        &self,
-        client: &Client,
-        (scripthash,): &(ScriptHash,),
-    ) -> Result<Value> {
-        let balance = match client.scripthashes.get(scripthash) {
-            Some(status) => self.tracker.get_balance(status),
-            None => {
-                info!(
-                    "{} blockchain.scripthash.get_balance called for unsubscribed scripthash",
-                    UNSUBSCRIBED_QUERY_MESSAGE
-                );
-                self.tracker.get_balance(&self.new_status(*scripthash)?)
-            }
-        };
-        Ok(json!(balance))
-    }
+/// The scripthash status answering a single query: the client's subscribed one (if up to date),
+/// or a freshly synced one (possibly prefetched with the rest of its batch)
+enum QueryStatus<'a> {
+    Subscribed(&'a ScriptHashStatus),
+    Prefetched(&'a ScriptHashStatus),
+    Cached(Arc<ScriptHashStatus>), // recently built by another query
+    Fresh(Arc<ScriptHashStatus>),
+}
 
-    fn scripthash_get_history(
-        &self,
-        client: &Client,
-        (scripthash, ): &(ScriptHash, ),
-    ) -> Result<Value> {
-        let history_entries = match client.scripthashes.get(scripthash) {
-            Some(status) => json!(status.get_history(&None, &None)),
-            None => {
-                info!(
-                    "{} blockchain.scripthash.get_history called for unsubscribed scripthash",
-                    UNSUBSCRIBED_QUERY_MESSAGE
-                );
-                json!(self.new_status(*scripthash)?.get_history(&None, &None))
-            }
-        };
-        Ok(history_entries)
-    }
+impl Deref for QueryStatus<'_> {
+    type Target = ScriptHashStatus;
 
-    fn scripthash_get_history_filter(
-        &self,
-        client: &Client,
-        (scripthash, from, to): &(ScriptHash, Option<usize>, Option<usize>),
-    ) -> Result<Value> {
-        let history_entries = match client.scripthashes.get(scripthash) {
-            Some(status) => json!(status.get_history(from, to)),
-            None => {
-                info!(
-                    "{} blockchain.scripthash.get_history called for unsubscribed scripthash: {}",
-                    UNSUBSCRIBED_QUERY_MESSAGE, scripthash
-                );
-                json!(self.new_status(*scripthash)?.get_history(from, to))
-            }
-        };
-        Ok(history_entries)
+    fn deref(&self) -> &ScriptHashStatus {
+        match self {
+            QueryStatus::Subscribed(status) | QueryStatus::Prefetched(status) => status,
+            QueryStatus::Cached(status) | QueryStatus::Fresh(status) => status,
+        }
     }
+}
 
-    fn scripthash_list_unspent(
-        &self,
-        client: &Client,
-        (scripthash,): &(ScriptHash,),
-    ) -> Result<Value> {
-        let unspent_entries = match client.scripthashes.get(scripthash) {
-            Some(status) => self.tracker.get_unspent(status),
-            None => {
-                info!(
-                    "{} blockchain.scripthash.listunspent called for unsubscribed scripthash",
-                    UNSUBSCRIBED_QUERY_MESSAGE
-                );
-                self.tracker.get_unspent(&self.new_status(*scripthash)?)
-            }
-        };
-        Ok(json!(unspent_entries))
-    }
+/// Scripthash notifications coalesced during the client's debounce window, so at most one
+/// notification per scripthash is sent per window (reflecting its latest status)
+#[derive(Default)]
+struct Debounced {
+    window: Option<Duration>,
+    pending: HashMap<ScriptHash, Value>, // the latest notification of each scripthash
+    deadline: Option<Instant>,           // set when the first notification is debounced
+}
 
-    fn scripthash_select_unspent(
-        &self,
-        client: &Client,
-        (scripthash, amounts, min_amount, confirmed): &(ScriptHash, Vec<u64>, u64, bool),
-    ) -> Result<Value> {
-        let mut unspent_entries = match client.scripthashes.get(scripthash) {
-            Some(status) => self.tracker.get_unspent(status),
-            None => {
-                info!(
-                "{} blockchain.scripthash.listunspent called for unsubscribed scripthash",
-                UNSUBSCRIBED_QUERY_MESSAGE
-            );
-                self.tracker.get_unspent(&self.new_status(*scripthash)?)
-            }
+impl Debounced {
+    /// Returns the notification if it should be sent right away (i.e. debouncing is disabled).
+    fn push(
+        &mut self,
+        scripthash: ScriptHash,
+        mut notification: Value,
+        now: Instant,
+    ) -> Option<Value> {
+        let window = match self.window {
+            Some(window) => window,
+            None => return Some(notification),
         };
-        let filter_confirmed = |utxo: &UnspentEntry, confirmed| {
-            if confirmed {
-                utxo.height > 0
-            } else {
-                true
-            }
-        };
-        unspent_entries.retain(|utxo| utxo.value >= Amount::from_sat(*min_amount) && filter_confirmed(utxo, *confirmed));
-        unspent_entries.sort_by(|a, b| a.value.partial_cmp(&b.value).unwrap());
-
-        let mut choose_list = Vec::new();
-        for target_amount in amounts {
-            let (mut part_choose_list, part_index) = select_utxos(&unspent_entries, Amount::from_sat(*target_amount));
-            for (iter_index, selcet_index) in part_index.iter().enumerate() {
-                unspent_entries.remove(selcet_index - iter_index);
+        let deadline = *self.deadline.get_or_insert(now + window);
+        if let Some(prev) = self.pending.get(&scripthash) {
+            // the previous delta would be lost, so the client should fetch the whole history
+            if prev["params"].get(2).is_some() {
+                notification["params"][2] = json!({ "delta": null });
             }
-            choose_list.append(&mut part_choose_list);
         }
-        info!(
-            "choose_list len for req: {:?}", choose_list.len(),
-        );
-        Ok(json!(choose_list))
+        self.pending.insert(scripthash, notification);
+        if self.pending.len() >= MAX_DEBOUNCED_NOTIFICATIONS && deadline > now {
+            self.deadline = Some(now);
+        }
+        None
     }
 
-    fn scripthash_unspent_is_exist(
-        &self,
-        client: &Client,
-        (scripthash, tx_id): &(ScriptHash, Txid),
-    ) -> Result<Value> {
-        let unspent_entries = match client.scripthashes.get(scripthash) {
-            Some(status) => self.tracker.get_unspent(status),
-            None => {
-                info!(
-                    "{} blockchain.scripthash.listunspent called for unsubscribed scripthash: {}",
-                    UNSUBSCRIBED_QUERY_MESSAGE, scripthash
-                );
-                self.tracker.get_unspent(&self.new_status(*scripthash)?)
-            }
-        };
-        let is_exist = unspent_entries.iter().find(|unspent| &unspent.tx_hash == tx_id).is_some();
-        Ok(json!(is_exist))
+    fn take_due(&mut self, now: Instant) -> Vec<(ScriptHash, Value)> {
+        match self.deadline {
+            Some(deadline) if deadline > now && self.window.is_some() => vec![],
+            _ => self.take(),
+        }
     }
 
-    fn scripthash_subscribe(
-        &self,
-        client: &mut Client,
-        (scripthash,): &(ScriptHash,),
-    ) -> Result<Value> {
-        self.scripthashes_subscribe(client, &[*scripthash])
-            .next()
-            .unwrap()
+    fn take(&mut self) -> Vec<(ScriptHash, Value)> {
+        self.deadline = None;
+        self.pending.drain().collect()
     }
+}
 
-    fn scripthash_unsubscribe(
-        &self,
-        client: &mut Client,
-        (scripthash,): &(ScriptHash,),
-    ) -> Result<Value> {
-        let removed = client.scripthashes.remove(scripthash).is_some();
-        Ok(json!(removed))
-    }
+/// Recently broadcast transactions, by their client-supplied idempotency keys
+#[derive(Default)]
+struct BroadcastKeys {
+    entries: VecDeque<(String, Txid, Instant)>, // oldest first
+}
 
-    fn scripthashes_subscribe<'a>(
-        &self,
-        client: &'a mut Client,
-        scripthashes: &'a [ScriptHash],
-    ) -> impl Iterator<Item = Result<Value>> + 'a {
-        let new_scripthashes: Vec<ScriptHash> = scripthashes
+impl BroadcastKeys {
+    fn get(&mut self, key: &str, now: Instant, ttl: Duration) -> Option<Txid> {
+        while let Some((_, _, time)) = self.entries.front() {
+            if now.saturating_duration_since(*time) < ttl {
+                break;
+            }
+            self.entries.pop_front(); // expired
+        }
+        self.entries
             .iter()
-            .copied()
-            .filter(|scripthash| !client.scripthashes.contains_key(scripthash))
-            .collect();
-
-        let mut results: HashMap<ScriptHash, Result<ScriptHashStatus>> = new_scripthashes
-            .into_par_iter()
-            .map(|scripthash| (scripthash, self.new_status(scripthash)))
-            .collect();
-
-        scripthashes.iter().map(move |scripthash| {
-            let statushash = match client.scripthashes.entry(*scripthash) {
-                Entry::Occupied(e) => e.get().statushash(),
-                Entry::Vacant(e) => {
-                    let status = results
-                        .remove(scripthash)
-                        .expect("missing scripthash status")?; // return an error for failed subscriptions
-                    e.insert(status).statushash()
-                }
-            };
-            Ok(json!(statushash))
-        })
+            .find(|(k, _, _)| k == key)
+            .map(|(_, txid, _)| *txid)
     }
 
-    fn new_status(&self, scripthash: ScriptHash) -> Result<ScriptHashStatus> {
-        let mut status = ScriptHashStatus::new(scripthash);
-        self.tracker
-            .update_scripthash_status(&mut status, &self.daemon, &self.cache)?;
-        Ok(status)
+    fn insert(&mut self, key: String, txid: Txid, now: Instant) {
+        self.entries.retain(|(k, _, _)| *k != key);
+        self.entries.push_back((key, txid, now));
+        while self.entries.len() > MAX_BROADCAST_KEYS {
+            self.entries.pop_front();
+        }
     }
+}
 
-    fn transaction_broadcast(&self, (tx_hex,): &(String,)) -> Result<Value> {
-        let tx_bytes = Vec::from_hex(tx_hex).context("non-hex transaction")?;
-        let tx = deserialize(&tx_bytes).context("invalid transaction")?;
-        let txid = self.daemon.broadcast(&tx)?;
-        Ok(json!(txid))
-    }
+#[derive(Deserialize)]
+struct Request {
+    // notifications (i.e. requests without an `id`, or with a `null` one) are not answered
+    #[serde(default)]
+    id: Option<Value>,
+    method: String,
 
-    fn transaction_get(&self, args: &TxGetArgs) -> Result<Value> {
-        let (txid, verbose) = args.into();
-        if verbose {
-            let blockhash = self
-                .tracker
-                .lookup_transaction(&self.daemon, txid)?
-                .map(|(blockhash, _tx)| blockhash);
-            return self.daemon.get_transaction_info(&txid, blockhash);
-        }
-        if let Some(tx) = self.cache.get_tx(&txid, |tx| serialize_hex(tx)) {
-            return Ok(json!(tx));
-        }
-        debug!("tx cache miss: txid={}", txid);
-        // use internal index to load confirmed transaction without an RPC
-        if let Some(tx) = self
-            .tracker
-            .lookup_transaction(&self.daemon, txid)?
-            .map(|(_blockhash, tx)| tx)
-        {
-            return Ok(json!(serialize_hex(&tx)));
-        }
-        // load unconfirmed transaction via RPC
-        Ok(json!(self.daemon.get_transaction_hex(&txid, None)?))
-    }
+    #[serde(default)]
+    params: Value,
+}
 
-    fn transaction_get_merkle(&self, (txid, height): &(Txid, usize)) -> Result<Value> {
-        let chain = self.tracker.chain();
-        let blockhash = match chain.get_block_hash(*height) {
-            None => bail!("missing block at {}", height),
-            Some(blockhash) => blockhash,
-        };
-        let txids = self.daemon.get_block_txids(blockhash)?;
-        match txids.iter().position(|current_txid| *current_txid == *txid) {
-            None => bail!("missing txid {} in block {}", txid, blockhash),
-            Some(position) => {
-                let proof = Proof::create(&txids, position);
-                Ok(json!({
-                "block_height": height,
-                "pos": proof.position(),
-                "merkle": proof.to_hex(),
-                }))
+enum Requests {
+    Single(Request),
+    Batch(Vec<Request>),
+}
+
+/// Deserialized in a single pass (unlike an untagged enum, which would buffer the whole batch),
+/// so a large batch doesn't materialize an intermediate tree of its requests.
+impl<'de> de::Deserialize<'de> for Requests {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct RequestsVisitor;
+
+        impl<'de> de::Visitor<'de> for RequestsVisitor {
+            type Value = Requests;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a request or a batch of requests")
             }
-        }
-    }
 
-    fn transaction_from_pos(
-        &self,
-        (height, tx_pos, merkle): (usize, usize, bool),
-    ) -> Result<Value> {
-        let chain = self.tracker.chain();
-        let blockhash = match chain.get_block_hash(height) {
-            None => bail!("missing block at {}", height),
-            Some(blockhash) => blockhash,
-        };
-        let txids = self.daemon.get_block_txids(blockhash)?;
-        if tx_pos >= txids.len() {
-            bail!("invalid tx_pos {} in block at height {}", tx_pos, height);
-        }
-        let txid: Txid = txids[tx_pos];
-        if merkle {
-            let proof = Proof::create(&txids, tx_pos);
-            Ok(json!({"tx_id": txid, "merkle": proof.to_hex()}))
-        } else {
-            Ok(json!({ "tx_id": txid }))
+            fn visit_map<A: de::MapAccess<'de>>(self, map: A) -> Result<Requests, A::Error> {
+                let request = de::Deserialize::deserialize(MapAccessDeserializer::new(map))?;
+                Ok(Requests::Single(request))
+            }
+
+            fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Requests, A::Error> {
+                let mut requests = Vec::new();
+                while let Some(request) = seq.next_element()? {
+                    requests.push(request);
+                }
+                Ok(Requests::Batch(requests))
+            }
         }
-    }
 
-    fn get_fee_histogram(&self) -> Result<Value> {
-        Ok(json!(self.tracker.fees_histogram()))
+        deserializer.deserialize_any(RequestsVisitor)
     }
+}
 
-    fn server_id(&self) -> String {
-        format!("electrs/{}", ELECTRS_VERSION)
-    }
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[serde(untagged)]
+enum Version {
+    Single(String),
+    Range(String, String),
+}
 
-    fn version(&self, (client_id, client_version): &(String, Version)) -> Result<Value> {
-        match client_version {
-            Version::Single(v) if v == PROTOCOL_VERSION => {
-                Ok(json!([self.server_id(), PROTOCOL_VERSION]))
+impl Version {
+    /// The (inclusive) range of protocol versions supported by the client
+    fn range(&self) -> Result<(ProtocolVersion, ProtocolVersion)> {
+        match self {
+            Version::Single(v) => {
+                let v = ProtocolVersion::parse(v)?;
+                Ok((v.clone(), v))
             }
-            _ => {
-                bail!(
-                    "{} requested {:?}, server supports {}",
-                    client_id,
-                    client_version,
-                    PROTOCOL_VERSION
-                );
+            Version::Range(min, max) => {
+                let min = ProtocolVersion::parse(min)?;
+                let max = ProtocolVersion::parse(max)?;
+                ensure!(min <= max, "inverted version range: {} > {}", min, max);
+                Ok((min, max))
             }
         }
     }
+}
 
-    fn features(&self) -> Result<Value> {
-        Ok(json!({
-            "genesis_hash": self.tracker.chain().get_block_hash(0),
-            "hosts": { "tcp_port": self.port },
-            "protocol_max": PROTOCOL_VERSION,
-            "protocol_min": PROTOCOL_VERSION,
-            "pruning": null,
-            "server_version": self.server_id(),
-            "hash_function": "sha256"
-        }))
+/// Electrum protocol version (e.g. "1.4.2"), ordered by its numeric components
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct ProtocolVersion(Vec<u32>);
+
+impl ProtocolVersion {
+    fn parse(version: &str) -> Result<Self> {
+        let mut parts = version
+            .split('.')
+            .map(|part| part.parse::<u32>())
+            .collect::<std::result::Result<Vec<u32>, _>>()
+            .map_err(|_| anyhow!("invalid protocol version '{}'", Sanitized(version)))?;
+        ensure!(
+            parts.len() <= 3,
+            "invalid protocol version '{}'",
+            Sanitized(version)
+        );
+        // "1.4" and "1.4.0" are the same version
+        while parts.len() > 1 && parts.last() == Some(&0) {
+            parts.pop();
+        }
+        Ok(Self(parts))
     }
 
-    pub fn handle_requests(&self, client: &mut Client, lines: &[String]) -> Vec<String> {
-        lines
-            .iter()
-            .map(|line| {
-                parse_requests(line)
-                    .map(Calls::parse)
-                    .map_err(error_msg_no_id)
-            })
-            .map(|calls| self.handle_calls(client, calls).to_string())
-            .collect()
+    /// Returns the highest version supported by both the server and the client.
+    fn negotiate(client_version: &Version) -> Result<Self> {
+        let (min, max) = client_version.range()?;
+        let server = Self::parse(PROTOCOL_VERSION).expect("invalid PROTOCOL_VERSION");
+        ensure!(
+            min <= server && server <= max,
+            "no mutually supported version"
+        );
+        Ok(server)
     }
+}
 
-    fn handle_calls(&self, client: &mut Client, calls: Result<Calls, Value>) -> Value {
-        let calls: Calls = match calls {
-            Ok(calls) => calls,
-            Err(response) => return response, // JSON parsing failed - the response does not contain request id
-        };
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let parts: Vec<String> = self.0.iter().map(|part| part.to_string()).collect();
+        write!(f, "{}", parts.join("."))
+    }
+}
 
-        match calls {
-            Calls::Batch(batch) => {
-                if let Some(result) = self.try_multi_call(client, &batch) {
-                    return json!(result);
-                }
-                json!(batch
-                    .into_iter()
-                    .map(|result| self.single_call(client, result))
-                    .collect::<Vec<Value>>())
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BlockHeadersArgs {
+    Range((usize, usize)),
+    // optionally prove the last header's inclusion under the checkpoint (if `cp_height > 0`)
+    WithCheckpoint((usize, usize, usize)),
+}
+
+impl From<&BlockHeadersArgs> for (usize, usize, usize) {
+    fn from(args: &BlockHeadersArgs) -> Self {
+        match args {
+            BlockHeadersArgs::Range((start_height, count)) => (*start_height, *count, 0),
+            BlockHeadersArgs::WithCheckpoint((start_height, count, cp_height)) => {
+                (*start_height, *count, *cp_height)
             }
-            Calls::Single(result) => self.single_call(client, result),
         }
     }
+}
 
-    fn try_multi_call(
-        &self,
-        client: &mut Client,
-        calls: &[Result<Call, Value>],
-    ) -> Option<Vec<Value>> {
-        // exit if any call failed to parse
-        let valid_calls = calls
-            .iter()
-            .map(|result| result.as_ref().ok())
-            .collect::<Option<Vec<&Call>>>()?;
-
-        // only "blockchain.scripthashes.subscribe" are supported
-        let scripthashes: Vec<ScriptHash> = valid_calls
-            .iter()
-            .map(|call| match &call.params {
-                Params::ScriptHashSubscribe((scripthash,)) => Some(*scripthash),
-                _ => None, // exit if any of the calls is not supported
-            })
-            .collect::<Option<Vec<ScriptHash>>>()?;
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BlockHeaderArgs {
+    Height((usize,)),
+    // optionally prove the header's inclusion under the checkpoint (if `cp_height > 0`)
+    WithCheckpoint((usize, usize)),
+}
 
-        Some(
-            self.rpc_duration
-                .observe_duration("blockchain.scripthash.subscribe:multi", || {
-                    self.scripthashes_subscribe(client, &scripthashes)
-                        .zip(valid_calls)
-                        .map(|(result, call)| call.response(result))
-                        .collect::<Vec<Value>>()
-                }),
-        )
+impl From<&BlockHeaderArgs> for (usize, usize) {
+    fn from(args: &BlockHeaderArgs) -> Self {
+        match args {
+            BlockHeaderArgs::Height((height,)) => (*height, 0),
+            BlockHeaderArgs::WithCheckpoint((height, cp_height)) => (*height, *cp_height),
+        }
     }
+}
 
-    fn single_call(&self, client: &mut Client, call: Result<Call, Value>) -> Value {
-        let call = match call {
-            Ok(call) => call,
-            Err(response) => return response, // params parsing may fail - the response contains request id
-        };
-        self.rpc_duration.observe_duration(&call.method, || {
-            if self.tracker.status().is_err() {
-                // Allow only a few RPC (for sync status notification) not requiring index DB being compacted.
-                match &call.params {
-                    Params::BlockHeader(_)
-                    | Params::BlockHeaders(_)
-                    | Params::HeadersSubscribe
-                    | Params::Version(_) => (),
-                    _ => return error_msg(&call.id, RpcError::UnavailableIndex),
-                };
-            }
-            let result = match &call.params {
-                Params::Banner => Ok(json!(self.banner)),
-                Params::BlockHeader(args) => self.block_header(*args),
-                Params::BlockHeaders(args) => self.block_headers(*args),
-                Params::Donation => Ok(Value::Null),
-                Params::EstimateFee(args) => self.estimate_fee(*args),
-                Params::Features => self.features(),
-                Params::HeadersSubscribe => self.headers_subscribe(client),
-                Params::MempoolFeeHistogram => self.get_fee_histogram(),
-                Params::PeersSubscribe => Ok(json!([])),
-                Params::Ping => Ok(Value::Null),
-                Params::RelayFee => self.relayfee(),
-                Params::ScriptHashGetBalance(args) => self.scripthash_get_balance(client, args),
-                Params::ScriptHashGetHistory(args) => self.scripthash_get_history(client, args),
-                Params::ScriptHashGetHistoryFilter(args) => self.scripthash_get_history_filter(client, args),
-                Params::ScriptHashListUnspent(args) => self.scripthash_list_unspent(client, args),
-                Params::ScriptHashSelectUnspent(args) => self.scripthash_select_unspent(client, args),
-                Params::ScriptHashUnspentExist(args) => self.scripthash_unspent_is_exist(client, args),
-                Params::ScriptHashSubscribe(args) => self.scripthash_subscribe(client, args),
-                Params::ScriptHashUnsubscribe(args) => self.scripthash_unsubscribe(client, args),
-                Params::TransactionBroadcast(args) => self.transaction_broadcast(args),
-                Params::TransactionGet(args) => self.transaction_get(args),
-                Params::TransactionGetMerkle(args) => self.transaction_get_merkle(args),
-                Params::TransactionFromPosition(args) => self.transaction_from_pos(*args),
-                Params::Version(args) => self.version(args),
-            };
-            call.response(result)
-        })
-    }
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TxGetArgs {
+    Txid((HashParam<Txid>,)),
+    TxidVerbose(HashParam<Txid>, bool),
 }
 
 #[derive(Deserialize)]
-enum Params {
-    Banner,
-    BlockHeader((usize,)),
-    BlockHeaders((usize, usize)),
-    TransactionBroadcast((String,)),
-    Donation,
-    EstimateFee((u16,)),
-    Features,
-    HeadersSubscribe,
-    MempoolFeeHistogram,
-    PeersSubscribe,
-    Ping,
-    RelayFee,
-    ScriptHashGetBalance((ScriptHash,)),
-    ScriptHashGetHistory((ScriptHash, )),
-    ScriptHashGetHistoryFilter((ScriptHash, Option<usize>, Option<usize>, )),
-    ScriptHashListUnspent((ScriptHash,)),
-    ScriptHashSelectUnspent((ScriptHash, Vec<u64>, u64, bool, )),
-    ScriptHashUnspentExist((ScriptHash, Txid, )),
-    ScriptHashSubscribe((ScriptHash,)),
-    ScriptHashUnsubscribe((ScriptHash,)),
-    TransactionGet(TxGetArgs),
-    TransactionGetMerkle((Txid, usize)),
-    TransactionFromPosition((usize, usize, bool)),
-    Version((String, Version)),
+#[serde(untagged)]
+enum TxsGetArgs {
+    Txids((Vec<HashParam<Txid>>,)),
+    TxidsVerbose(Vec<HashParam<Txid>>, bool),
 }
 
-impl Params {
-    fn parse(method: &str, params: Value) -> std::result::Result<Params, StandardError> {
-        Ok(match method {
-            "blockchain.block.header" => Params::BlockHeader(convert(params)?),
-            "blockchain.block.headers" => Params::BlockHeaders(convert(params)?),
-            "blockchain.estimatefee" => Params::EstimateFee(convert(params)?),
-            "blockchain.headers.subscribe" => Params::HeadersSubscribe,
-            "blockchain.relayfee" => Params::RelayFee,
-            "blockchain.scripthash.get_balance" => Params::ScriptHashGetBalance(convert(params)?),
-            "blockchain.scripthash.get_history" => Params::ScriptHashGetHistory(convert(params)?),
-            "blockchain.scripthash.get_history_filter" => Params::ScriptHashGetHistoryFilter(convert(params)?),
-            "blockchain.scripthash.listunspent" => Params::ScriptHashListUnspent(convert(params)?),
-            "blockchain.scripthash.unspent_exist" => Params::ScriptHashUnspentExist(convert(params)?),
-            "blockchain.scripthash.select_unspent" => Params::ScriptHashSelectUnspent(convert(params)?),
-            "blockchain.scripthash.subscribe" => Params::ScriptHashSubscribe(convert(params)?),
-            "blockchain.scripthash.unsubscribe" => Params::ScriptHashUnsubscribe(convert(params)?),
-            "blockchain.transaction.broadcast" => Params::TransactionBroadcast(convert(params)?),
-            "blockchain.transaction.get" => Params::TransactionGet(convert(params)?),
-            "blockchain.transaction.get_merkle" => Params::TransactionGetMerkle(convert(params)?),
-            "blockchain.transaction.id_from_pos" => {
-                Params::TransactionFromPosition(convert(params)?)
-            }
-            "mempool.get_fee_histogram" => Params::MempoolFeeHistogram,
-            "server.banner" => Params::Banner,
-            "server.donation_address" => Params::Donation,
-            "server.features" => Params::Features,
-            "server.peers.subscribe" => Params::PeersSubscribe,
-            "server.ping" => Params::Ping,
-            "server.version" => Params::Version(convert(params)?),
-            _ => {
-                warn!("unknown method {}", method);
-                return Err(StandardError::MethodNotFound);
-            }
-        })
-    }
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum GetMerkleArgs {
+    TxidHeight((HashParam<Txid>, usize)),
+    // optionally include the block header (so the proof can be verified without another call)
+    TxidHeightWithHeader(HashParam<Txid>, usize, bool),
 }
 
-struct Call {
-    id: Value,
-    method: String,
-    params: Params,
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum IdFromPosArgs {
+    Position((usize, usize)),
+    // optionally include the transaction's merkle proof (under the block's merkle root)
+    WithMerkle((usize, usize, bool)),
 }
 
-impl Call {
-    fn parse(request: Request) -> Result<Call, Value> {
-        match Params::parse(&request.method, request.params) {
-            Ok(params) => Ok(Call {
-                id: request.id,
-                method: request.method,
-                params,
-            }),
-            Err(e) => Err(error_msg(&request.id, RpcError::Standard(e))),
+impl From<&IdFromPosArgs> for (usize, usize, bool) {
+    fn from(args: &IdFromPosArgs) -> Self {
+        match args {
+            IdFromPosArgs::Position((height, tx_pos)) => (*height, *tx_pos, false),
+            IdFromPosArgs::WithMerkle((height, tx_pos, merkle)) => (*height, *tx_pos, *merkle),
         }
     }
+}
 
-    fn response(&self, result: Result<Value>) -> Value {
-        match result {
-            Ok(value) => result_msg(&self.id, value),
-            Err(err) => {
-                warn!("RPC {} failed: {:#}", self.method, err);
-                match err
-                    .downcast_ref::<bitcoincore_rpc::Error>()
-                    .and_then(extract_bitcoind_error)
-                {
-                    Some(e) => error_msg(&self.id, RpcError::DaemonError(e.clone())),
-                    None => error_msg(&self.id, RpcError::BadRequest(err)),
-                }
-            }
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OutPointArgs {
+    OutPoint((HashParam<Txid>, u32)),
+    // the funding output's script hint is not needed (the outpoint is looked up by its txid)
+    WithHint((HashParam<Txid>, u32, String)),
+}
+
+impl From<&OutPointArgs> for OutPoint {
+    fn from(args: &OutPointArgs) -> Self {
+        match args {
+            OutPointArgs::OutPoint((HashParam(txid), vout))
+            | OutPointArgs::WithHint((HashParam(txid), vout, _)) => OutPoint::new(*txid, *vout),
         }
     }
 }
 
-enum Calls {
-    Batch(Vec<Result<Call, Value>>),
-    Single(Result<Call, Value>),
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BroadcastArgs {
+    Tx((BytesParam,)),
+    // repeated broadcasts with the same key return the recorded txid
+    WithKey((BytesParam, String)),
 }
 
-impl Calls {
-    fn parse(requests: Requests) -> Calls {
-        match requests {
-            Requests::Single(request) => Calls::Single(Call::parse(request)),
-            Requests::Batch(batch) => {
-                Calls::Batch(batch.into_iter().map(Call::parse).collect::<Vec<_>>())
+impl<'a> From<&'a BroadcastArgs> for (&'a [u8], Option<&'a str>) {
+    fn from(args: &'a BroadcastArgs) -> Self {
+        match args {
+            BroadcastArgs::Tx((BytesParam(tx_bytes),)) => (tx_bytes.as_slice(), None),
+            BroadcastArgs::WithKey((BytesParam(tx_bytes), key)) => {
+                (tx_bytes.as_slice(), Some(key.as_str()))
             }
         }
     }
 }
 
-fn convert<T>(params: Value) -> std::result::Result<T, StandardError>
-where
-    T: serde::de::DeserializeOwned,
-{
-    let params_str = params.to_string();
-    serde_json::from_value(params).map_err(|err| {
-        warn!("invalid params {}: {}", params_str, err);
-        StandardError::InvalidParams
-    })
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ScriptHashArgs {
+    ScriptHash((HashParam<ScriptHash>,)),
+    // optionally explain an empty result (see `Rpc::empty_result_info()`)
+    Verbose(HashParam<ScriptHash>, bool),
 }
 
-fn notification(method: &str, params: &[Value]) -> Value {
-    json!({"jsonrpc": "2.0", "method": method, "params": params})
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BuildRbfArgs {
+    FeeRate((HashParam<Txid>, f64)),
+    // optionally specify the change output (instead of finding the one paying back to an input)
+    WithChange((HashParam<Txid>, f64, usize)),
 }
 
-fn result_msg(id: &Value, result: Value) -> Value {
-    json!({"jsonrpc": "2.0", "id": id, "result": result})
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum HistoryArgs {
+    ScriptHash((HashParam<ScriptHash>,)),
+    // optionally explain an empty result (see `Rpc::empty_result_info()`)
+    Verbose(HashParam<ScriptHash>, bool),
+    // optionally include the confirmed transactions' position in their block
+    WithTxPos(HashParam<ScriptHash>, bool, bool),
 }
 
-fn error_msg(id: &Value, error: RpcError) -> Value {
-    json!({"jsonrpc": "2.0", "id": id, "error": error.to_value()})
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum HistoryFilterArgs {
+    Range((HashParam<ScriptHash>, Option<usize>, Option<usize>)),
+    // optionally iterate newest-first (the height range still applies)
+    RangeReverse(
+        (
+            HashParam<ScriptHash>,
+            Option<usize>,
+            Option<usize>,
+            OrderArg,
+        ),
+    ),
+    // optionally explain an empty result (see `Rpc::empty_result_info()`), and report the total
+    // history length (for pagination)
+    RangeReverseVerbose(
+        (
+            HashParam<ScriptHash>,
+            Option<usize>,
+            Option<usize>,
+            OrderArg,
+            bool,
+        ),
+    ),
 }
 
-fn error_msg_no_id(err: StandardError) -> Value {
-    error_msg(&Value::Null, RpcError::Standard(err))
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OrderArg {
+    Reverse(bool), // `true` is the same as `HistoryOrder::Desc`
+    Order(HistoryOrder),
 }
 
-fn parse_requests(line: &str) -> Result<Requests, StandardError> {
-    match serde_json::from_str(line) {
-        // parse JSON from str
-        Ok(value) => match serde_json::from_value(value) {
-            // parse RPC from JSON
-            Ok(requests) => Ok(requests),
-            Err(err) => {
-                warn!("invalid RPC request ({:?}): {}", line, err);
-                Err(StandardError::InvalidRequest)
-            }
-        },
-        Err(err) => {
-            warn!("invalid JSON ({:?}): {}", line, err);
-            Err(StandardError::ParseError)
+impl HistoryFilterArgs {
+    fn scripthash(&self) -> &ScriptHash {
+        match self {
+            HistoryFilterArgs::Range((HashParam(scripthash), ..))
+            | HistoryFilterArgs::RangeReverse((HashParam(scripthash), ..))
+            | HistoryFilterArgs::RangeReverseVerbose((HashParam(scripthash), ..)) => scripthash,
         }
     }
-}
 
-fn select_utxos(
-    utxos: &[UnspentEntry],
-    target_value: Amount,
-) -> (Vec<UnspentEntry>, Vec<usize>) {
-    let mut choose_list = Vec::new();
-    let mut choose_index = Vec::new();
-    if utxos.len() <= 3 {
-        for (index, utxo) in utxos.iter().enumerate() {
-            choose_list.push(utxo.clone());
-            choose_index.push(index);
+    fn range(&self) -> (Option<usize>, Option<usize>) {
+        match self {
+            HistoryFilterArgs::Range((_, from, to))
+            | HistoryFilterArgs::RangeReverse((_, from, to, _))
+            | HistoryFilterArgs::RangeReverseVerbose((_, from, to, ..)) => (*from, *to),
         }
-        return (choose_list, choose_index);
-    } else {
-        let utxo_len = utxos.len();
-        if let Some((index, _middle_utxo)) = utxos
-            .iter()
+    }
+
+    fn reverse(&self) -> bool {
+        match self {
+            HistoryFilterArgs::Range(_) => false,
+            HistoryFilterArgs::RangeReverse((.., order))
+            | HistoryFilterArgs::RangeReverseVerbose((.., order, _)) => matches!(
+                order,
+                OrderArg::Reverse(true) | OrderArg::Order(HistoryOrder::Desc)
+            ),
+        }
+    }
+
+    fn verbose(&self) -> bool {
+        match self {
+            HistoryFilterArgs::Range(_) | HistoryFilterArgs::RangeReverse(_) => false,
+            HistoryFilterArgs::RangeReverseVerbose((.., verbose)) => *verbose,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum UnspentExistArgs {
+    Txid((HashParam<ScriptHash>, HashParam<Txid>)),
+    // optionally match an exact outpoint (or list the transaction's unspent outputs, if `null`)
+    Outpoint(HashParam<ScriptHash>, HashParam<Txid>, Option<u32>),
+}
+
+impl UnspentExistArgs {
+    fn scripthash(&self) -> &ScriptHash {
+        match self {
+            UnspentExistArgs::Txid((HashParam(scripthash), _))
+            | UnspentExistArgs::Outpoint(HashParam(scripthash), ..) => scripthash,
+        }
+    }
+
+    fn txid(&self) -> &Txid {
+        match self {
+            UnspentExistArgs::Txid((_, HashParam(txid)))
+            | UnspentExistArgs::Outpoint(_, HashParam(txid), _) => txid,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ListUnspentArgs {
+    ScriptHash((HashParam<ScriptHash>,)),
+    // optionally include the outputs spent by mempool transactions (flagged as `spent_in_mempool`)
+    WithMempoolSpent(HashParam<ScriptHash>, bool),
+    // optionally include the outputs' `script_pubkey` (e.g. for PSBT construction)
+    WithScriptPubKey(HashParam<ScriptHash>, bool, bool),
+    // optionally include only the "safe" outputs (not spent by the current mempool)
+    SafeOnly(HashParam<ScriptHash>, bool, bool, bool),
+}
+
+/// UTXO selection options of `blockchain.scripthash.select_unspent`
+#[derive(Clone, Copy)]
+struct SelectOptions {
+    fee_rate: f64, // in sat/vB (zero if the inputs' fees are not accounted for)
+    strategy: SelectStrategy,
+    bnb_tolerance: Amount, // maximum excess of a changeless selection
+    max_inputs: usize,     // for each amount
+    pad_inputs: bool,      // to `PADDED_INPUTS` (by the default strategy)
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ConfirmedArg {
+    Flag(bool), // `true` is the same as `SelectMode::Confirmed`
+    Mode(SelectMode),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SelectUnspentArgs {
+    // the optional parameters following `confirmed`, as an object (e.g. `{"max_inputs": 5}`)
+    WithOptions(
+        (
+            HashParam<ScriptHash>,
+            Vec<u64>,
+            u64,
+            bool,
+            SelectUnspentOptions,
+        ),
+    ),
+    // optionally override the maximal number of inputs for each amount
+    WithMaxInputs(
+        (
+            HashParam<ScriptHash>,
+            Vec<u64>,
+            u64,
+            bool,
+            Option<SelectMode>,
+            bool,
+            bool,
+            Option<f64>,
+            Option<SelectStrategy>,
+            usize,
+        ),
+    ),
+    // optionally use another selection strategy
+    WithStrategy(
+        (
+            HashParam<ScriptHash>,
+            Vec<u64>,
+            u64,
+            bool,
+            Option<SelectMode>,
+            bool,
+            bool,
+            Option<f64>,
+            Option<SelectStrategy>,
+        ),
+    ),
+    // optionally account for the selected inputs' fees, at the given fee rate (in sat/vB)
+    // - a null fee rate (or strategy) is the same as an omitted one
+    WithFeeRate(
+        (
+            HashParam<ScriptHash>,
+            Vec<u64>,
+            u64,
+            bool,
+            Option<SelectMode>,
+            bool,
+            bool,
+            Option<f64>,
+        ),
+    ),
+    // optionally include the selected outputs' `script_pubkey` (e.g. for PSBT construction)
+    WithScriptPubKey(
+        (
+            HashParam<ScriptHash>,
+            Vec<u64>,
+            u64,
+            bool,
+            Option<SelectMode>,
+            bool,
+            bool,
+        ),
+    ),
+    // optionally, select unconfirmed UTXOs exceeding the mempool package limits
+    WithPackageLimits(
+        (
+            HashParam<ScriptHash>,
+            Vec<u64>,
+            u64,
+            bool,
+            Option<SelectMode>,
+            bool,
+        ),
+    ),
+    // the mode (if specified and not null) overrides the `confirmed` flag
+    WithMode(
+        (
+            HashParam<ScriptHash>,
+            Vec<u64>,
+            u64,
+            bool,
+            Option<SelectMode>,
+        ),
+    ),
+    Confirmed((HashParam<ScriptHash>, Vec<u64>, u64, ConfirmedArg)),
+}
+
+impl SelectUnspentArgs {
+    fn scripthash(&self) -> &ScriptHash {
+        match self {
+            SelectUnspentArgs::WithOptions((HashParam(scripthash), ..))
+            | SelectUnspentArgs::WithMaxInputs((HashParam(scripthash), ..))
+            | SelectUnspentArgs::WithStrategy((HashParam(scripthash), ..))
+            | SelectUnspentArgs::WithFeeRate((HashParam(scripthash), ..))
+            | SelectUnspentArgs::WithScriptPubKey((HashParam(scripthash), ..))
+            | SelectUnspentArgs::WithPackageLimits((HashParam(scripthash), ..))
+            | SelectUnspentArgs::WithMode((HashParam(scripthash), ..))
+            | SelectUnspentArgs::Confirmed((HashParam(scripthash), ..)) => scripthash,
+        }
+    }
+
+    fn amounts(&self) -> &[u64] {
+        match self {
+            SelectUnspentArgs::WithOptions((_, amounts, ..))
+            | SelectUnspentArgs::WithMaxInputs((_, amounts, ..))
+            | SelectUnspentArgs::WithStrategy((_, amounts, ..))
+            | SelectUnspentArgs::WithFeeRate((_, amounts, ..))
+            | SelectUnspentArgs::WithScriptPubKey((_, amounts, ..))
+            | SelectUnspentArgs::WithPackageLimits((_, amounts, ..))
+            | SelectUnspentArgs::WithMode((_, amounts, ..))
+            | SelectUnspentArgs::Confirmed((_, amounts, ..)) => amounts,
+        }
+    }
+
+    fn min_amount(&self) -> Amount {
+        match self {
+            SelectUnspentArgs::WithOptions((_, _, min_amount, ..))
+            | SelectUnspentArgs::WithMaxInputs((_, _, min_amount, ..))
+            | SelectUnspentArgs::WithStrategy((_, _, min_amount, ..))
+            | SelectUnspentArgs::WithFeeRate((_, _, min_amount, ..))
+            | SelectUnspentArgs::WithScriptPubKey((_, _, min_amount, ..))
+            | SelectUnspentArgs::WithPackageLimits((_, _, min_amount, ..))
+            | SelectUnspentArgs::WithMode((_, _, min_amount, ..))
+            | SelectUnspentArgs::Confirmed((_, _, min_amount, _)) => Amount::from_sat(*min_amount),
+        }
+    }
+
+    fn mode(&self) -> SelectMode {
+        match self {
+            SelectUnspentArgs::WithOptions((_, _, _, confirmed, options)) => match options.mode {
+                Some(mode) => mode,
+                None if *confirmed => SelectMode::Confirmed,
+                None => SelectMode::Any,
+            },
+            SelectUnspentArgs::WithMaxInputs((_, _, _, confirmed, mode, ..))
+            | SelectUnspentArgs::WithStrategy((_, _, _, confirmed, mode, ..))
+            | SelectUnspentArgs::WithFeeRate((_, _, _, confirmed, mode, ..))
+            | SelectUnspentArgs::WithScriptPubKey((_, _, _, confirmed, mode, ..))
+            | SelectUnspentArgs::WithPackageLimits((_, _, _, confirmed, mode, ..))
+            | SelectUnspentArgs::WithMode((_, _, _, confirmed, mode)) => match mode {
+                Some(mode) => *mode,
+                None if *confirmed => SelectMode::Confirmed,
+                None => SelectMode::Any,
+            },
+            SelectUnspentArgs::Confirmed((.., ConfirmedArg::Flag(true))) => SelectMode::Confirmed,
+            SelectUnspentArgs::Confirmed((.., ConfirmedArg::Flag(false))) => SelectMode::Any,
+            SelectUnspentArgs::Confirmed((.., ConfirmedArg::Mode(mode))) => *mode,
+        }
+    }
+
+    fn ignore_package_limits(&self) -> bool {
+        match self {
+            SelectUnspentArgs::WithOptions((.., options)) => options.ignore_package_limits,
+            SelectUnspentArgs::WithMaxInputs((.., ignore, _, _, _, _)) => *ignore,
+            SelectUnspentArgs::WithStrategy((.., ignore, _, _, _)) => *ignore,
+            SelectUnspentArgs::WithFeeRate((.., ignore, _, _)) => *ignore,
+            SelectUnspentArgs::WithScriptPubKey((.., ignore, _)) => *ignore,
+            SelectUnspentArgs::WithPackageLimits((.., ignore)) => *ignore,
+            SelectUnspentArgs::WithMode(_) | SelectUnspentArgs::Confirmed(_) => false,
+        }
+    }
+
+    fn include_script_pubkey(&self) -> bool {
+        match self {
+            SelectUnspentArgs::WithOptions((.., options)) => options.include_script_pubkey,
+            SelectUnspentArgs::WithMaxInputs((.., include, _, _, _)) => *include,
+            SelectUnspentArgs::WithStrategy((.., include, _, _)) => *include,
+            SelectUnspentArgs::WithFeeRate((.., include, _)) => *include,
+            SelectUnspentArgs::WithScriptPubKey((.., include)) => *include,
+            SelectUnspentArgs::WithPackageLimits(_)
+            | SelectUnspentArgs::WithMode(_)
+            | SelectUnspentArgs::Confirmed(_) => false,
+        }
+    }
+
+    fn fee_rate(&self) -> Option<f64> {
+        match self {
+            SelectUnspentArgs::WithOptions((.., options)) => options.fee_rate,
+            SelectUnspentArgs::WithMaxInputs((.., fee_rate, _, _)) => *fee_rate,
+            SelectUnspentArgs::WithStrategy((.., fee_rate, _)) => *fee_rate,
+            SelectUnspentArgs::WithFeeRate((.., fee_rate)) => *fee_rate,
+            SelectUnspentArgs::WithScriptPubKey(_)
+            | SelectUnspentArgs::WithPackageLimits(_)
+            | SelectUnspentArgs::WithMode(_)
+            | SelectUnspentArgs::Confirmed(_) => None,
+        }
+    }
+
+    fn strategy(&self) -> Option<SelectStrategy> {
+        match self {
+            SelectUnspentArgs::WithOptions((.., options)) => options.strategy,
+            SelectUnspentArgs::WithMaxInputs((.., strategy, _)) => *strategy,
+            SelectUnspentArgs::WithStrategy((.., strategy)) => *strategy,
+            SelectUnspentArgs::WithFeeRate(_)
+            | SelectUnspentArgs::WithScriptPubKey(_)
+            | SelectUnspentArgs::WithPackageLimits(_)
+            | SelectUnspentArgs::WithMode(_)
+            | SelectUnspentArgs::Confirmed(_) => None,
+        }
+    }
+
+    fn max_inputs(&self) -> Option<usize> {
+        match self {
+            SelectUnspentArgs::WithOptions((.., options)) => options.max_inputs,
+            SelectUnspentArgs::WithMaxInputs((.., max_inputs)) => Some(*max_inputs),
+            SelectUnspentArgs::WithStrategy(_)
+            | SelectUnspentArgs::WithFeeRate(_)
+            | SelectUnspentArgs::WithScriptPubKey(_)
+            | SelectUnspentArgs::WithPackageLimits(_)
+            | SelectUnspentArgs::WithMode(_)
+            | SelectUnspentArgs::Confirmed(_) => None,
+        }
+    }
+}
+
+impl From<&ScriptHashArgs> for (ScriptHash, bool) {
+    fn from(args: &ScriptHashArgs) -> Self {
+        match args {
+            ScriptHashArgs::ScriptHash((HashParam(scripthash),)) => (*scripthash, false),
+            ScriptHashArgs::Verbose(HashParam(scripthash), verbose) => (*scripthash, *verbose),
+        }
+    }
+}
+
+/// The replaced txid, the new fee rate (in sat/vB), and the change output's index (if specified)
+impl From<&BuildRbfArgs> for (Txid, f64, Option<usize>) {
+    fn from(args: &BuildRbfArgs) -> Self {
+        match args {
+            BuildRbfArgs::FeeRate((HashParam(txid), fee_rate)) => (*txid, *fee_rate, None),
+            BuildRbfArgs::WithChange((HashParam(txid), fee_rate, change_index)) => {
+                (*txid, *fee_rate, Some(*change_index))
+            }
+        }
+    }
+}
+
+/// The scripthash, and whether to be verbose and to include the transactions' block position
+impl From<&HistoryArgs> for (ScriptHash, bool, bool) {
+    fn from(args: &HistoryArgs) -> Self {
+        match args {
+            HistoryArgs::ScriptHash((HashParam(scripthash),)) => (*scripthash, false, false),
+            HistoryArgs::Verbose(HashParam(scripthash), verbose) => (*scripthash, *verbose, false),
+            HistoryArgs::WithTxPos(HashParam(scripthash), verbose, include_tx_pos) => {
+                (*scripthash, *verbose, *include_tx_pos)
+            }
+        }
+    }
+}
+
+/// The scripthash, whether to include the mempool-spent outputs and their `script_pubkey`, and
+/// whether to include only the outputs not spent by the current mempool
+impl From<&ListUnspentArgs> for (ScriptHash, bool, bool, bool) {
+    fn from(args: &ListUnspentArgs) -> Self {
+        match args {
+            ListUnspentArgs::ScriptHash((HashParam(scripthash),)) => {
+                (*scripthash, false, false, false)
+            }
+            ListUnspentArgs::WithMempoolSpent(HashParam(scripthash), include) => {
+                (*scripthash, *include, false, false)
+            }
+            ListUnspentArgs::WithScriptPubKey(HashParam(scripthash), include, include_script) => {
+                (*scripthash, *include, *include_script, false)
+            }
+            ListUnspentArgs::SafeOnly(HashParam(scripthash), include, include_script, safe) => {
+                (*scripthash, *include, *include_script, *safe)
+            }
+        }
+    }
+}
+
+impl From<&GetMerkleArgs> for (Txid, usize, bool) {
+    fn from(args: &GetMerkleArgs) -> Self {
+        match args {
+            GetMerkleArgs::TxidHeight((HashParam(txid), height)) => (*txid, *height, false),
+            GetMerkleArgs::TxidHeightWithHeader(HashParam(txid), height, with_header) => {
+                (*txid, *height, *with_header)
+            }
+        }
+    }
+}
+
+impl From<&TxGetArgs> for (Txid, bool) {
+    fn from(args: &TxGetArgs) -> Self {
+        match args {
+            TxGetArgs::Txid((HashParam(txid),)) => (*txid, false),
+            TxGetArgs::TxidVerbose(HashParam(txid), verbose) => (*txid, *verbose),
+        }
+    }
+}
+
+impl From<&TxsGetArgs> for (Vec<Txid>, bool) {
+    fn from(args: &TxsGetArgs) -> Self {
+        let (txids, verbose) = match args {
+            TxsGetArgs::Txids((txids,)) => (txids, false),
+            TxsGetArgs::TxidsVerbose(txids, verbose) => (txids, *verbose),
+        };
+        let txids = txids.iter().map(|HashParam(txid)| *txid).collect();
+        (txids, verbose)
+    }
+}
+
+enum StandardError {
+    ParseError,
+    InvalidRequest,
+    MethodNotFound,
+    InvalidParams,
+    UnexpectedParams(&'static Signature), // in strict mode (see `Params::parse_strict()`)
+    InvalidHex(String),                   // naming the parameter (see `check_hex_params()`)
+}
+
+enum RpcError {
+    // JSON-RPC spec errors
+    Standard(StandardError),
+    // Electrum-specific errors (see `errors::ErrorCode`)
+    BadRequest(anyhow::Error),
+    DaemonError(daemon::RpcError),
+    UnavailableIndex,
+    UnavailableDaemon,
+    Coded(CodedError),
+}
+
+impl RpcError {
+    fn to_value(&self) -> Value {
+        match self {
+            RpcError::Standard(err) => match err {
+                StandardError::ParseError => json!({"code": -32700, "message": "parse error"}),
+                StandardError::InvalidRequest => {
+                    json!({"code": -32600, "message": "invalid request"})
+                }
+                StandardError::MethodNotFound => {
+                    json!({"code": -32601, "message": "method not found"})
+                }
+                StandardError::InvalidParams => {
+                    json!({"code": -32602, "message": "invalid params"})
+                }
+                StandardError::UnexpectedParams(signature) => json!({
+                    "code": -32602,
+                    "message": format!("invalid params (expected {})", signature),
+                }),
+                StandardError::InvalidHex(message) => json!({"code": -32602, "message": message}),
+            },
+            RpcError::BadRequest(err) => {
+                json!({"code": ErrorCode::BadRequest.code(), "message": err.to_string()})
+            }
+            RpcError::DaemonError(err) => json!({
+                "code": ErrorCode::DaemonError.code(),
+                "message": err.message,
+                "data": {"daemon_code": err.code},
+            }),
+            RpcError::UnavailableIndex => {
+                json!({"code": ErrorCode::StaleIndex.code(), "message": "unavailable index"})
+            }
+            RpcError::UnavailableDaemon => {
+                json!({"code": ErrorCode::DaemonUnreachable.code(), "message": "unavailable daemon"})
+            }
+            RpcError::Coded(err) => err.to_value(),
+        }
+    }
+}
+
+/// Tracks whether bitcoind can be reached, to serve in degraded mode while it can't
+struct DaemonHealth {
+    unreachable: AtomicBool,
+    gauge: Gauge,
+}
+
+impl DaemonHealth {
+    fn new(metrics: &Metrics) -> Self {
+        let gauge = metrics.gauge("daemon_status", "bitcoind status (1 = true)", "state");
+        gauge.set("unreachable", 0.0);
+        Self {
+            unreachable: AtomicBool::new(false),
+            gauge,
+        }
+    }
+
+    fn is_unreachable(&self) -> bool {
+        self.unreachable.load(Ordering::SeqCst)
+    }
+
+    fn set_unreachable(&self, unreachable: bool) {
+        if self.unreachable.swap(unreachable, Ordering::SeqCst) != unreachable {
+            if unreachable {
+                warn!("bitcoind is unreachable, serving in degraded mode");
+            } else {
+                info!("bitcoind is reachable again");
+            }
+        }
+        self.gauge
+            .set("unreachable", if unreachable { 1.0 } else { 0.0 });
+    }
+}
+
+/// Whether the index can be updated, since failing to write it (e.g. due to a full disk)
+/// stops syncing - while the existing index keeps being served (marked as stale).
+struct IndexHealth {
+    write_failure: AtomicBool,
+    gauge: Gauge,
+}
+
+impl IndexHealth {
+    fn new(metrics: &Metrics) -> Self {
+        let gauge = metrics.gauge("index_status", "index status (1 = true)", "state");
+        gauge.set("write_failure", 0.0);
+        Self {
+            write_failure: AtomicBool::new(false),
+            gauge,
+        }
+    }
+
+    fn has_write_failure(&self) -> bool {
+        self.write_failure.load(Ordering::SeqCst)
+    }
+
+    fn set_write_failure(&self, write_failure: bool) {
+        if self.write_failure.swap(write_failure, Ordering::SeqCst) != write_failure {
+            if write_failure {
+                error!("index can't be written, stopped syncing and serving in degraded mode");
+            } else {
+                info!("index can be written again, resumed syncing");
+            }
+        }
+        self.gauge
+            .set("write_failure", if write_failure { 1.0 } else { 0.0 });
+    }
+}
+
+/// bitcoind's version only changes when it's restarted, so it's refreshed periodically (instead of
+/// calling `getnetworkinfo` on every sync).
+struct DaemonInfo {
+    info: Info,
+    refreshed: Option<Instant>,
+}
+
+impl DaemonInfo {
+    fn new(metrics: &Metrics) -> Self {
+        let info = metrics.info(
+            "electrs_daemon_info",
+            "bitcoind version",
+            &["version", "subversion"],
+        );
+        Self {
+            info,
+            refreshed: None,
+        }
+    }
+
+    /// Failures are only logged (and retried on the next sync), since the metric isn't needed
+    /// for syncing.
+    fn refresh(&mut self, now: Instant, get_version: impl FnOnce() -> Result<(usize, String)>) {
+        if let Some(refreshed) = self.refreshed {
+            if now.saturating_duration_since(refreshed) < DAEMON_INFO_REFRESH_INTERVAL {
+                return;
+            }
+        }
+        match get_version() {
+            Ok((version, subversion)) => {
+                self.info.set(&[&version.to_string(), &subversion]);
+                self.refreshed = Some(now);
+            }
+            Err(e) => warn!("failed to refresh bitcoind version: {:#}", e),
+        }
+    }
+}
+
+/// A cheap summary of the server's health (derived from already-maintained state, without daemon
+/// calls), returned by `server.sync_status` and usable by embedders' readiness checks.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HealthReport {
+    pub height: usize,
+    pub tip: BlockHash,
+    #[serde(flatten)]
+    pub flags: HealthFlags,
+    pub daemon_height: Option<usize>, // as of the last sync
+    pub last_sync_secs: Option<f64>,
+    pub mempool_txs: usize,
+    pub mempool_age_secs: Option<f64>, // since the mempool transactions were last listed
+    pub tx_cache_entries: usize,
+    pub tx_cache_bytes: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthFlags {
+    pub synced: bool,
+    pub daemon_reachable: bool,
+    pub index_writable: bool,
+    pub mempool_partial: bool,
+    pub stale: bool,    // the answers may not reflect the latest chain
+    pub degraded: bool, // bitcoind is unreachable, or the index can't be written
+}
+
+impl HealthFlags {
+    fn new(
+        synced: bool,
+        daemon: &DaemonHealth,
+        index: &IndexHealth,
+        mempool_partial: bool,
+    ) -> Self {
+        let daemon_reachable = !daemon.is_unreachable();
+        let index_writable = !index.has_write_failure();
+        Self {
+            synced,
+            daemon_reachable,
+            index_writable,
+            mempool_partial,
+            stale: !(synced && daemon_reachable && index_writable),
+            degraded: !(daemon_reachable && index_writable),
+        }
+    }
+}
+
+/// Electrum RPC handler
+pub struct Rpc {
+    tracker: Tracker,
+    cache: Cache,
+    scheduler: Scheduler,
+    rpc_duration: Histogram,
+    rejected_requests: Counter, // exceeding the request limits
+    tip_info: Info,
+    daemon_info: DaemonInfo,
+    daemon_health: DaemonHealth,
+    index_health: IndexHealth,
+    last_sync_duration: Option<Duration>,
+    block_events: Vec<BlockEvent>, // collected by the last sync
+    headers_trees: HeadersTreeCache,
+    daemon: Daemon,
+    exit_flag: ExitFlag,
+    banner: String,
+    hostnames: Hostnames,
+    peers: Peers,
+    port: u16,
+    network: Network, // for the verbose transactions' addresses
+    clock: Clock,     // for the subscriptions' and broadcast keys' expiry
+    broadcast_ttl: Duration,
+    select_bnb_tolerance: Amount,
+    select_max_inputs: usize,
+    select_input_padding: bool,
+    reservations: Reservations, // of the selected UTXOs (shared by all clients)
+    status_cache: StatusCache,  // of the unsubscribed scripthashes (shared by all clients)
+    transactions_batch_limit: usize,
+    request_batch_limit: usize,
+    request_size_limit: usize,
+    status_max_lag_blocks: usize,
+    serve_stale_without_daemon: bool,
+    block_txids_subscription: bool,
+    cache_management_rpc: bool,
+    proofs_export_rpc: bool,
+    cbor_codec: bool,
+    strict_params: bool,
+    legacy_id_from_pos: bool,
+    peer_bans_rpc: bool,
+    orphans_rpc: bool,
+    txid_prefix_index: bool,
+    spender_index: bool,
+    client_memory_budget: Option<usize>,
+    scripthash_subscriptions_limit: Option<usize>,
+    rejected_subscriptions: Counter, // exceeding the per-client limits
+    subscription_idle_timeout: Option<Duration>,
+    outpoint_subscriptions_limit: usize,
+    transaction_subscriptions_limit: usize,
+    transaction_subscription_confirmations: Option<usize>,
+    sync_serving: SyncServing,
+    sync_once: bool,
+    audit_log: AuditLog,
+    reputation: Reputation,
+    webhooks: Webhooks,
+    webhooks_rpc: bool,
+    webhook_statuses: HashMap<ScriptHash, ScriptHashStatus>,
+    webhook_statuses_ready: bool, // initial statuses are not notified
+    mirrors: Mirrors,
+    broadcast_mirrors_rpc: bool,
+}
+
+impl Rpc {
+    /// Perform initial index sync (may take a while on first run).
+    pub(crate) fn new(
+        config: &Config,
+        metrics: Metrics,
+        exit_flag: ExitFlag,
+        audit_log: AuditLog,
+        reputation: Reputation,
+        webhooks: Webhooks,
+        mirrors: Mirrors,
+    ) -> Result<Self> {
+        let rpc_duration = metrics.histogram_vec(
+            "rpc_duration",
+            "RPC duration (in seconds)",
+            "method",
+            metrics::default_duration_buckets(),
+        );
+        let rejected_requests = metrics.counter(
+            "rpc_rejected_requests",
+            "# of requests rejected for exceeding the request limits",
+            "limit",
+        );
+        let rejected_subscriptions = metrics.counter(
+            "rpc_rejected_subscriptions",
+            "# of scripthash subscriptions rejected for exceeding the per-client limits",
+            "limit",
+        );
+        let tip_info = metrics.info(
+            "electrs_chain_tip_info",
+            "Indexed chain tip",
+            &["hash", "height"],
+        );
+        let daemon_info = DaemonInfo::new(&metrics);
+
+        let daemon_health = DaemonHealth::new(&metrics);
+        let index_health = IndexHealth::new(&metrics);
+        let scheduler = Scheduler::new(rayon::current_num_threads(), &metrics);
+        let reservations = Reservations::new(config.select_reservation_ttl, &metrics);
+        let status_cache = StatusCache::new(
+            config.status_cache_ttl,
+            config.status_cache_capacity,
+            &metrics,
+        );
+
+        let daemon = Daemon::connect(config, &exit_flag, &metrics)?;
+        let tracker = Tracker::new(config, metrics, &daemon)?;
+        let cache = Cache::new(tracker.metrics());
+        let webhook_statuses = webhooks
+            .scripthashes()
+            .into_iter()
+            .map(|scripthash| (scripthash, ScriptHashStatus::new(scripthash)))
+            .collect();
+        Ok(Self {
+            tracker,
+            cache,
+            scheduler,
+            rpc_duration,
+            rejected_requests,
+            tip_info,
+            daemon_info,
+            daemon_health,
+            index_health,
+            last_sync_duration: None,
+            block_events: vec![],
+            headers_trees: HeadersTreeCache::default(),
+            daemon,
+            exit_flag,
+            banner: config.server_banner.clone(),
+            hostnames: Hostnames::open(config.hostnames_file.as_deref())?,
+            peers: Peers::parse(config.peers.as_deref(), config.verify_announced_peers)
+                .context("invalid peers config")?,
+            port: config.electrum_rpc_addr.port(),
+            network: config.network,
+            clock: Clock::default(),
+            broadcast_ttl: config.broadcast_idempotency_ttl,
+            select_bnb_tolerance: Amount::from_sat(config.select_bnb_tolerance),
+            select_max_inputs: config.select_max_inputs,
+            select_input_padding: config.select_input_padding,
+            reservations,
+            status_cache,
+            transactions_batch_limit: config.transactions_batch_limit,
+            request_batch_limit: config.request_batch_limit,
+            request_size_limit: config.request_size_limit,
+            status_max_lag_blocks: config.status_max_lag_blocks,
+            serve_stale_without_daemon: config.serve_stale_without_daemon,
+            block_txids_subscription: config.block_txids_subscription,
+            cache_management_rpc: config.cache_management_rpc,
+            proofs_export_rpc: config.proofs_export_rpc,
+            cbor_codec: config.cbor_codec,
+            strict_params: config.strict_params,
+            legacy_id_from_pos: config.legacy_id_from_pos,
+            peer_bans_rpc: config.peer_bans_rpc,
+            orphans_rpc: config.orphans_rpc,
+            txid_prefix_index: config.txid_prefix_index,
+            spender_index: config.spender_index,
+            client_memory_budget: config.client_memory_budget,
+            scripthash_subscriptions_limit: config.scripthash_subscriptions_limit,
+            rejected_subscriptions,
+            subscription_idle_timeout: config.subscription_idle_timeout,
+            outpoint_subscriptions_limit: config.outpoint_subscriptions_limit,
+            transaction_subscriptions_limit: config.transaction_subscriptions_limit,
+            transaction_subscription_confirmations: config.transaction_subscription_confirmations,
+            sync_serving: config.sync_serving,
+            sync_once: config.sync_once,
+            audit_log,
+            reputation,
+            webhooks,
+            webhooks_rpc: config.webhooks_rpc,
+            webhook_statuses,
+            webhook_statuses_ready: false,
+            mirrors: mirrors.with_network(config.network),
+            broadcast_mirrors_rpc: config.broadcast_mirrors_rpc,
+        })
+    }
+
+    pub(crate) fn reputation(&self) -> &Reputation {
+        &self.reputation
+    }
+
+    pub fn new_block_notification(&self) -> Receiver<()> {
+        self.daemon.new_block_notification()
+    }
+
+    pub fn sync(&mut self) -> Result<bool> {
+        let start = Instant::now();
+        let result = self.sync_with_daemon();
+        self.last_sync_duration = Some(start.elapsed());
+        match result {
+            // keep serving the (synced) index, and retry later
+            Err(e) if daemon::is_unreachable(&e) && self.tracker.status().is_ok() => {
+                warn!("sync failed: {:#}", e);
+                self.daemon_health.set_unreachable(true);
+                Ok(true)
+            }
+            // keep serving the existing index, and retry writing it later
+            Err(e) if db::is_write_error(&e) && !self.sync_once => {
+                error!("sync failed: {:#}", e);
+                self.index_health.set_write_failure(true);
+                Ok(true)
+            }
+            result => {
+                if result.is_ok() {
+                    self.daemon_health.set_unreachable(false);
+                    self.index_health.set_write_failure(false);
+                }
+                result
+            }
+        }
+    }
+
+    fn sync_with_daemon(&mut self) -> Result<bool> {
+        let synced = (self.tracker.chain().tip(), self.tracker.mempool_version());
+        let done = self.tracker.sync(&self.daemon, &self.exit_flag)?;
+        self.block_events = self.tracker.take_block_events();
+        let chain = self.tracker.chain();
+        if chain.tip() != synced.0 {
+            self.prefetch_block_txids(chain.tip());
+        }
+        if (chain.tip(), self.tracker.mempool_version()) != synced {
+            self.status_cache.clear(); // the cached statuses may be outdated
+            let pruned = self
+                .cache
+                .prune_tx_infos(chain, |txid| self.tracker.mempool_contains(txid));
+            debug!("pruned {} cached verbose transactions", pruned);
+        }
+        self.tip_info
+            .set(&[&chain.tip().to_string(), &chain.height().to_string()]);
+        let daemon = &self.daemon;
+        self.daemon_info
+            .refresh(self.clock.now(), || daemon.get_version());
+        Ok(done)
+    }
+
+    /// Most merkle proofs are requested for the new tip's transactions (e.g. by wallets notified
+    /// of their confirmation), so its txids are cached in advance.
+    fn prefetch_block_txids(&self, blockhash: BlockHash) {
+        let connected = self.block_events.iter().find_map(|event| match event {
+            BlockEvent::Connected { hash, txids, .. } if *hash == blockhash => Some(txids.clone()),
+            _ => None,
+        });
+        let txids = match connected {
+            Some(txids) => txids,
+            None => match self.tracker.get_block_txids(&self.daemon, blockhash) {
+                Ok(txids) => txids,
+                Err(e) => {
+                    warn!("failed to prefetch the tip's txids: {:#}", e);
+                    return;
+                }
+            },
+        };
+        self.cache.add_block_txids(blockhash, Arc::new(txids));
+    }
+
+    pub(crate) fn tip(&self) -> BlockHash {
+        self.tracker.chain().tip()
+    }
+
+    /// New clients may be refused until the initial sync is done
+    pub(crate) fn accepts_connections(&self) -> bool {
+        self.sync_serving != SyncServing::RefuseConnectionsUntilSynced
+            || self.tracker.status().is_ok()
+    }
+
+    pub fn update_client(&self, client: &mut Client) -> Result<Vec<Value>> {
+        let now = self.clock.now();
+        if self.daemon_health.is_unreachable() {
+            // the index and mempool are not updated
+            return Ok(client.flush_due_notifications(now));
+        }
+        let chain = self.tracker.chain();
+        let with_delta = client.history_deltas;
+        let changed = client
+            .scripthashes
+            .par_iter_mut()
+            .filter_map(|(scripthash, subscription)| {
+                let status = &mut subscription.status;
+                match self
+                    .tracker
+                    .update_scripthash_status(status, &self.daemon, &self.cache)
+                {
+                    Ok(true) => {
+                        subscription.touched = now;
+                        let notification =
+                            scripthash_notification(scripthash, &subscription.status, with_delta);
+                        Some(Ok((*scripthash, notification)))
+                    }
+                    Ok(false) => None, // statushash is the same
+                    Err(e) => Some(Err(e)),
+                }
+            })
+            .collect::<Result<Vec<(ScriptHash, Value)>>>()
+            .context("failed to update status")?;
+        let mut notifications: Vec<Value> = changed
+            .into_iter()
+            .filter_map(|(scripthash, notification)| {
+                client.debounced.push(scripthash, notification, now)
+            })
+            .collect();
+        notifications.extend(client.flush_due_notifications(now));
+        client.memory_usage = client
+            .scripthashes
+            .values()
+            .map(|subscription| subscription.status.memory_usage())
+            .sum();
+        if let Some(timeout) = self.subscription_idle_timeout {
+            let idle = client.unsubscribe_idle(now, timeout);
+            if !idle.is_empty() {
+                info!("unsubscribed {} idle scripthashes", idle.len());
+                notifications.push(notification(
+                    "blockchain.scripthash.unsubscribed",
+                    &[json!(idle)],
+                ));
+            }
+        }
+
+        notifications.extend(self.update_watched(client)?);
+
+        if let Some(old_tip) = client.tip {
+            let new_tip = chain.tip();
+            if old_tip != new_tip {
+                client.tip = Some(new_tip);
+                notifications.extend(headers_notifications(
+                    chain,
+                    old_tip,
+                    client.decoded_headers,
+                ));
+            }
+        }
+        if client.block_txids {
+            notifications.extend(block_txids_notifications(&self.block_events));
+        }
+        Ok(notifications)
+    }
+
+    /// The subscribed outpoints' and transactions' status changes, looked up only if the tip or
+    /// the mempool have changed since the previous call.
+    fn update_watched(&self, client: &mut Client) -> Result<Vec<Value>> {
+        let chain = self.tracker.chain();
+        let synced = (chain.tip(), self.tracker.mempool_version());
+        let is_empty = client.outpoints.is_empty() && client.transactions.is_empty();
+        if is_empty || client.watched_synced == Some(synced) {
+            return Ok(vec![]);
+        }
+        let mut notifications = client
+            .outpoints
+            .par_iter_mut()
+            .filter_map(|(outpoint, status)| {
+                match self
+                    .tracker
+                    .update_outpoint_status(*outpoint, status, &self.daemon)
+                {
+                    Ok(true) => Some(Ok(outpoint_notification(outpoint, status))),
+                    Ok(false) => None, // status is the same
+                    Err(e) => Some(Err(e)),
+                }
+            })
+            .collect::<Result<Vec<Value>>>()
+            .context("failed to update outpoint status")?;
+        let changed = client
+            .update_transactions(
+                self.transaction_subscription_confirmations,
+                chain,
+                |txid, status| self.tracker.update_tx_status(txid, status, &self.daemon),
+            )
+            .context("failed to update transaction status")?;
+        notifications.extend(changed);
+        client.watched_synced = Some(synced);
+        Ok(notifications)
+    }
+
+    /// Notify the webhooks about their scripthashes' status changes (since the previous call).
+    pub fn update_webhooks(&mut self) -> Result<()> {
+        if self.webhook_statuses.is_empty()
+            || self.daemon_health.is_unreachable()
+            || self.tracker.status().is_err()
+        {
+            return Ok(()); // the index and mempool may not be up-to-date
+        }
+        let (tracker, daemon, cache) = (&self.tracker, &self.daemon, &self.cache);
+        let changed = self
+            .webhook_statuses
+            .par_iter_mut()
+            .filter_map(|(scripthash, status)| {
+                match tracker.update_scripthash_status(status, daemon, cache) {
+                    Ok(true) => Some(Ok(*scripthash)),
+                    Ok(false) => None, // statushash is the same
+                    Err(e) => Some(Err(e)),
+                }
+            })
+            .collect::<Result<Vec<ScriptHash>>>()
+            .context("failed to update webhooks' status")?;
+        if !self.webhook_statuses_ready {
+            self.webhook_statuses_ready = true;
+            return Ok(());
+        }
+        let height = tracker.chain().height();
+        for scripthash in changed {
+            let statushash = self.webhook_statuses[&scripthash].statushash();
+            self.webhooks
+                .scripthash_status(scripthash, statushash, height);
+        }
+        Ok(())
+    }
+
+    fn headers_subscribe(&self, client: &mut Client, decoded: bool) -> Result<Value> {
+        let chain = self.tracker.chain();
+        let (height, tip, header) = chain.tip_header();
+        client.tip = Some(tip);
+        client.decoded_headers = decoded;
+        Ok(json!(header_result(chain, height, &header, decoded)))
+    }
+
+    fn block_txids_subscribe(&self, client: &mut Client) -> Result<Value> {
+        ensure_enabled(
+            self.block_txids_subscription,
+            "blockchain.block.txids.subscribe",
+            "block-txids-subscription",
+        )?;
+        client.block_txids = true;
+        let chain = self.tracker.chain();
+        Ok(json!(BlockTxidsSubscribeResult {
+            height: chain.height(),
+            hash: chain.tip(),
+        }))
+    }
+
+    fn cache_stats(&self) -> Result<Value> {
+        ensure_enabled(
+            self.cache_management_rpc,
+            "server.cache.stats",
+            "cache-management-rpc",
+        )?;
+        Ok(json!(CacheStatsResult {
+            tx: self.cache.tx_stats()
+        }))
+    }
+
+    /// Transactions (and their verbose results) and blocks' txids are cached, while block headers
+    /// are kept in the in-memory chain (which is always consistent with the index).
+    fn cache_flush(&self, (kind,): &(String,)) -> Result<Value> {
+        ensure_enabled(
+            self.cache_management_rpc,
+            "server.cache.flush",
+            "cache-management-rpc",
+        )?;
+        match kind.as_str() {
+            "tx" | "txids" | "all" => {
+                let mut result = CacheFlushResult { tx: 0, txids: 0 };
+                if kind != "txids" {
+                    result.tx = self.cache.flush_txs();
+                    let flushed_infos = self.cache.flush_tx_infos();
+                    info!(
+                        "flushed {} cached transactions ({} verbose)",
+                        result.tx, flushed_infos
+                    );
+                }
+                if kind != "tx" {
+                    result.txids = self.cache.flush_block_txids();
+                    info!("flushed {} cached blocks' txids", result.txids);
+                }
+                Ok(json!(result))
+            }
+            "headers" => bail!("{} are not cached by this server", kind),
+            _ => bail!(
+                "unknown cache '{}' (expected tx, txids, headers or all)",
+                Sanitized(kind)
+            ),
+        }
+    }
+
+    fn bans_list(&self) -> Result<Value> {
+        ensure_enabled(self.peer_bans_rpc, "server.bans.list", "peer-bans-rpc")?;
+        Ok(json!(self.reputation.bans()))
+    }
+
+    fn bans_unban(&self, (ip,): &(String,)) -> Result<Value> {
+        ensure_enabled(self.peer_bans_rpc, "server.bans.unban", "peer-bans-rpc")?;
+        let ip: IpAddr = ip
+            .parse()
+            .map_err(|_| anyhow!("invalid IP address '{}'", Sanitized(ip)))?;
+        Ok(json!(self.reputation.unban(ip)))
+    }
+
+    fn orphaned(&self) -> Result<Value> {
+        ensure_enabled(self.orphans_rpc, "server.orphaned", "orphans-rpc")?;
+        Ok(json!(self.tracker.orphaned(None)))
+    }
+
+    fn webhooks_stats(&self) -> Result<Value> {
+        ensure_enabled(self.webhooks_rpc, "server.webhooks.stats", "webhooks-rpc")?;
+        Ok(json!(self.webhooks.stats()))
+    }
+
+    fn broadcast_mirrors_stats(&self) -> Result<Value> {
+        ensure_enabled(
+            self.broadcast_mirrors_rpc,
+            "server.broadcast_mirrors.stats",
+            "broadcast-mirrors-rpc",
+        )?;
+        Ok(json!(self.mirrors.stats()))
+    }
+
+    fn block_header(&self, args: &BlockHeaderArgs) -> Result<Value> {
+        let (height, cp_height) = args.into();
+        let chain = self.tracker.chain();
+        let header = match chain.get_block_header(height) {
+            None => bail!("no header at {}", height),
+            Some(header) => header,
+        };
+        if cp_height == 0 {
+            return Ok(json!(serialize_hex(header)));
+        }
+        ensure!(
+            height <= cp_height,
+            "height {} is above cp_height {}",
+            height,
+            cp_height
+        );
+        let headers_tree = self.headers_trees.get(chain, cp_height)?;
+        Ok(json!(HeaderProofResult {
+            header: serialize_hex(header),
+            branch: headers_tree.branch(height),
+            root: headers_tree.root(),
+        }))
+    }
+
+    fn block_header_by_hash(
+        &self,
+        (HashParam(blockhash),): &(HashParam<BlockHash>,),
+    ) -> Result<Value> {
+        let chain = self.tracker.chain();
+        let (height, header, stale) = match chain.get_block_header_by_hash(blockhash) {
+            Some((height, header)) => (height, header, false),
+            None => match chain.get_stale_header(blockhash) {
+                Some((height, header)) => (height, header, true), // recently orphaned
+                None => bail!("unknown block {}", blockhash),
+            },
+        };
+        Ok(json!(HeaderByHashResult {
+            height,
+            hex: serialize_hex(header),
+            stale,
+        }))
+    }
+
+    fn block_headers(&self, args: &BlockHeadersArgs) -> Result<Value> {
+        let (start_height, count, cp_height) = args.into();
+        let chain = self.tracker.chain();
+        let max_count = 2016usize;
+        // return only the available block headers
+        let end_height = std::cmp::min(
+            chain.height() + 1,
+            start_height + std::cmp::min(count, max_count),
+        );
+        let heights = start_height..end_height;
+        let count = heights.len();
+        let hex_headers =
+            heights.filter_map(|height| chain.get_block_header(height).map(serialize_hex));
+
+        let mut result = HeadersResult {
+            count,
+            hex: String::from_iter(hex_headers),
+            max: max_count,
+            branch: None,
+            root: None,
+        };
+        if cp_height > 0 && count > 0 {
+            let last_height = start_height + count - 1;
+            ensure!(
+                last_height <= cp_height,
+                "height {} is above cp_height {}",
+                last_height,
+                cp_height
+            );
+            let headers_tree = self.headers_trees.get(chain, cp_height)?;
+            result.branch = Some(headers_tree.branch(last_height));
+            result.root = Some(headers_tree.root());
+        }
+        Ok(json!(result))
+    }
+
+    fn estimate_fee(&self, (nblocks,): (u16,)) -> Result<Value> {
+        let fee_rate = self.daemon.estimate_fee(nblocks)?;
+        Ok(json!(FeeEstimate(fee_rate.map(|rate| rate.to_btc()))))
+    }
+
+    fn relayfee(&self) -> Result<Value> {
+        Ok(json!(self.daemon.get_relay_fee()?.to_btc())) // [BTC/kB]
+    }
+
+    fn scripthash_get_balance(&self, client: &Client, args: &ScriptHashArgs) -> Result<Value> {
+        let (scripthash, verbose) = args.into();
+        let stale = self.is_stale();
+        let snapshot = self.unspent_snapshot(client, scripthash, "get_balance", stale)?;
+        let balance = snapshot.balance();
+        let is_empty = balance == Balance::default();
+        let mut result = balance.to_wire();
+        if stale {
+            result.stale = true;
+            result.height = Some(self.tracker.chain().height());
+        }
+        if verbose {
+            result.totals = Some(snapshot.totals().to_wire());
+            result.as_of_height = snapshot.as_of_height();
+            if is_empty {
+                result.diagnostic = Some(self.empty_result_info(scripthash, "all_spent"));
+            }
+        }
+        Ok(json!(result))
+    }
+
+    fn scripthash_get_history(&self, client: &Client, args: &HistoryArgs) -> Result<Value> {
+        let (scripthash, verbose, include_tx_pos) = args.into();
+        let stale = self.is_stale();
+        let status = self.query_status(client, scripthash, "get_history")?;
+        let mut history_entries = status.get_history(&None, &None);
+        if stale {
+            // the mempool is not synced (or the index is partial), so it's ignored
+            history_entries.retain(|entry| entry.is_confirmed());
+        }
+        let check_orphans = self.tracker.has_orphans();
+        let history_entries: Vec<HistoryEntryWire> = history_entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| -> Result<HistoryEntryWire> {
+                if i % CANCEL_CHECK_INTERVAL == 0 {
+                    client.cancel.check()?;
+                }
+                let mut wire = entry.to_wire();
+                if include_tx_pos {
+                    wire.tx_pos = Some(entry.tx_pos()); // `null` for mempool entries
+                }
+                // confirmed at another height than the one notified before the reorg
+                if check_orphans {
+                    wire.reorged_from = entry
+                        .confirmed_height()
+                        .and_then(|height| self.tracker.reorged_from(&entry.txid(), height));
+                }
+                Ok(wire)
+            })
+            .collect::<Result<_>>()?;
+        // stale results are always verbose, so the flag can't be missed
+        if !verbose && !stale {
+            return Ok(json!(history_entries));
+        }
+        let diagnostic = if verbose && history_entries.is_empty() {
+            Some(self.empty_result_info(scripthash, "unconfirmed_excluded"))
+        } else {
+            None
+        };
+        Ok(json!(VerboseHistoryResult {
+            history: history_entries,
+            stale,
+            height: if stale {
+                Some(self.tracker.chain().height())
+            } else {
+                None
+            },
+            total: None,
+            from: None,
+            to: None,
+            as_of_height: status.as_of_height(),
+            diagnostic,
+        }))
+    }
+
+    fn scripthash_get_mempool(
+        &self,
+        client: &Client,
+        (HashParam(scripthash),): &(HashParam<ScriptHash>,),
+    ) -> Result<Value> {
+        let status = self.query_status(client, *scripthash, "get_mempool")?;
+        let mempool_entries: Vec<HistoryEntryWire> = status
+            .get_mempool()
+            .into_iter()
+            .map(HistoryEntry::to_wire)
+            .collect();
+        Ok(json!(mempool_entries))
+    }
+
+    fn scripthash_get_history_filter(
+        &self,
+        client: &Client,
+        args: &HistoryFilterArgs,
+    ) -> Result<Value> {
+        let scripthash = args.scripthash();
+        let (from, to) = args.range();
+        let status = self.query_status(client, *scripthash, "get_history")?;
+        let history_entries = status
+            .history_iter(&from, &to, args.reverse())
+            .enumerate()
+            .map(|(i, entry)| -> Result<HistoryEntryWire> {
+                if i % CANCEL_CHECK_INTERVAL == 0 {
+                    client.cancel.check()?;
+                }
+                Ok(entry.to_wire())
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if !args.verbose() {
+            return Ok(json!(history_entries));
+        }
+        let diagnostic = if history_entries.is_empty() {
+            Some(self.empty_result_info(*scripthash, "filtered_out"))
+        } else {
+            None
+        };
+        Ok(json!(VerboseHistoryResult {
+            history: history_entries,
+            stale: false,
+            height: None,
+            total: Some(status.history_len()),
+            from,
+            to,
+            as_of_height: status.as_of_height(),
+            diagnostic,
+        }))
+    }
+
+    /// Explain an empty result (for triaging "zero balance" reports): whether the scripthash was
+    /// ever funded, and how far the index and the mempool are synced. If it was funded, `reason`
+    /// tells why the result is still empty.
+    fn empty_result_info(&self, scripthash: ScriptHash, reason: &str) -> EmptyResultInfo {
+        let ever_seen = self.tracker.is_used(scripthash);
+        let daemon_reachable = !self.daemon_health.is_unreachable();
+        let daemon_height = if daemon_reachable {
+            self.daemon.get_block_count().ok()
+        } else {
+            None
+        };
+        EmptyResultInfo {
+            ever_seen,
+            indexed_height: self.tracker.chain().height(),
+            daemon_height,
+            mempool_checked: daemon_reachable && self.tracker.is_mempool_synced(),
+            reason: if ever_seen {
+                Some(reason.to_owned())
+            } else {
+                None
+            },
+        }
+    }
+
+    fn scripthash_list_unspent(&self, client: &Client, args: &ListUnspentArgs) -> Result<Value> {
+        let (scripthash, include_mempool_spent, include_script_pubkey, safe_only) = args.into();
+        ensure!(
+            !(include_mempool_spent && safe_only),
+            "include_mempool_spent and safe_only can't be combined"
+        );
+        let stale = self.is_stale();
+        let snapshot = self.unspent_snapshot(client, scripthash, "listunspent", stale)?;
+        let mut unspent_entries = if include_mempool_spent {
+            snapshot.entries_with_mempool_spent()
+        } else {
+            snapshot.entries()
+        };
+        if safe_only {
+            self.tracker.exclude_mempool_spent(&mut unspent_entries);
+        }
+        if include_script_pubkey {
+            self.set_script_pubkey(scripthash, &snapshot, &mut unspent_entries)?;
+        }
+        sort_unspent(&mut unspent_entries);
+        let unspent_entries = unspent_entries
+            .iter()
+            .map(|utxo| self.unspent_entry(utxo))
+            .collect::<Vec<UnspentEntryWire>>();
+        if stale {
+            return Ok(json!(StaleUnspentResult {
+                unspent: unspent_entries,
+                stale,
+                height: self.tracker.chain().height(),
+            }));
+        }
+        Ok(json!(unspent_entries))
+    }
+
+    /// The balance and the unspent outputs are derived from a single snapshot of the status, so
+    /// they are consistent even if the mempool changes meanwhile. If `stale`, the mempool is not
+    /// synced (or the index is partial), so its transactions are ignored.
+    fn unspent_snapshot(
+        &self,
+        client: &Client,
+        scripthash: ScriptHash,
+        method: &str,
+        stale: bool,
+    ) -> Result<UnspentSnapshot> {
+        let status = self.query_status(client, scripthash, method)?;
+        let snapshot = self.tracker.get_unspent_snapshot(&status);
+        Ok(if stale {
+            snapshot.confirmed_only()
+        } else {
+            snapshot
+        })
+    }
+
+    /// The outputs of a scripthash share a single scriptPubKey, so it's resolved at most once per
+    /// call: usually by the status sync, otherwise from one of the funding transactions.
+    fn set_script_pubkey(
+        &self,
+        scripthash: ScriptHash,
+        snapshot: &UnspentSnapshot,
+        entries: &mut [UnspentEntry],
+    ) -> Result<()> {
+        let fetch = |txid: &Txid| match self.tracker.lookup_transaction(&self.daemon, *txid)? {
+            Some((_blockhash, tx)) => Ok(tx),
+            None => self.daemon.get_transaction(txid, None),
+        };
+        resolve_script_pubkey(scripthash, snapshot.script_pubkey(), entries, fetch)
+    }
+
+    fn is_stale(&self) -> bool {
+        self.health_flags().stale
+    }
+
+    /// Include the unconfirmed ancestors' stats of mempool-funded UTXOs, since exceeding the
+    /// mempool package limits prevents spending them (until some ancestors are confirmed).
+    fn unspent_entry(&self, utxo: &UnspentEntry) -> UnspentEntryWire {
+        let mut entry = utxo.to_wire();
+        if utxo.height == 0 {
+            if let Some(stats) = self.tracker.package_stats(&utxo.tx_hash) {
+                entry.ancestor_count = Some(stats.ancestor_count);
+                entry.ancestor_size = Some(stats.ancestor_size);
+                entry.spendable_now = Some(stats.spendable_now);
+            }
+        }
+        entry
+    }
+
+    fn scripthash_select_unspent(
+        &self,
+        client: &Client,
+        args: &SelectUnspentArgs,
+    ) -> Result<Value> {
+        let scripthash = args.scripthash();
+        let amounts = args.amounts();
+        validate_select_args(args)?;
+        let options = SelectOptions {
+            fee_rate: args.fee_rate().unwrap_or(0.0),
+            strategy: args.strategy().unwrap_or(SelectStrategy::Default),
+            bnb_tolerance: self.select_bnb_tolerance,
+            max_inputs: args.max_inputs().unwrap_or(self.select_max_inputs),
+            pad_inputs: self.select_input_padding,
+        };
+        let snapshot = self.unspent_snapshot(client, *scripthash, "select_unspent", false)?;
+        let mut unspent_entries = snapshot.entries();
+        self.tracker.exclude_mempool_spent(&mut unspent_entries);
+        let outpoints = unspent_entries.iter().map(UnspentEntry::outpoint);
+        let reserved = self.reservations.reserved(outpoints, self.clock.now());
+        exclude_outpoints(&mut unspent_entries, &reserved);
+        // skip the UTXOs not worth spending (at the given fee rate)
+        unspent_entries.retain(|utxo| {
+            utxo.value >= args.min_amount() && utxo.value >= input_fee(options.fee_rate, 1)
+        });
+        if args.include_script_pubkey() {
+            self.set_script_pubkey(*scripthash, &snapshot, &mut unspent_entries)?;
+        }
+        client.cancel.check()?;
+        match args.mode() {
+            SelectMode::Any => (),
+            SelectMode::Confirmed => unspent_entries.retain(|utxo| utxo.height > 0),
+            SelectMode::PreferConfirmed => {
+                let (choose_list, strategies) = self.select_unspent_prefer_confirmed(
+                    unspent_entries,
+                    amounts,
+                    args.ignore_package_limits(),
+                    &options,
+                    &client.cancel,
+                )?;
+                self.reserve_selected(client, *scripthash, &choose_list);
+                return Ok(select_result(choose_list, args, &strategies));
+            }
+        }
+        sort_by_value(&mut unspent_entries);
+
+        let (choose_list, strategies) = select_for_amounts(unspent_entries, amounts, &options)?;
+        info!(
+            "choose_list len for req: {:?}", choose_list.len(),
+        );
+        let choose_list: Vec<UnspentEntryWire> =
+            choose_list.iter().map(UnspentEntry::to_wire).collect();
+        self.reserve_selected(client, *scripthash, &choose_list);
+        Ok(select_result(choose_list, args, &strategies))
+    }
+
+    /// Exclude the selected UTXOs from the following selections (if enabled), until they are
+    /// released, expire or the client disconnects.
+    fn reserve_selected(
+        &self,
+        client: &Client,
+        scripthash: ScriptHash,
+        choose_list: &[UnspentEntryWire],
+    ) {
+        let outpoints = choose_list
+            .iter()
+            .map(|utxo| OutPoint::new(utxo.tx_hash, utxo.tx_pos));
+        let owner = client.reservation_owner(scripthash);
+        self.reservations
+            .reserve(&owner, outpoints, self.clock.now());
+    }
+
+    /// Only the outpoints reserved by this client (for this scripthash) are released.
+    fn scripthash_release_unspent(
+        &self,
+        client: &Client,
+        (HashParam(scripthash), outpoints): &(HashParam<ScriptHash>, Vec<(HashParam<Txid>, u32)>),
+    ) -> Result<Value> {
+        let outpoints = outpoints
+            .iter()
+            .map(|(HashParam(txid), vout)| OutPoint::new(*txid, *vout));
+        let owner = client.reservation_owner(*scripthash);
+        let released = self
+            .reservations
+            .release(&owner, outpoints, self.clock.now());
+        Ok(json!(released))
+    }
+
+    fn select_unspent_prefer_confirmed(
+        &self,
+        unspent_entries: Vec<UnspentEntry>,
+        amounts: &[u64],
+        ignore_package_limits: bool,
+        options: &SelectOptions,
+        cancel: &CancelToken,
+    ) -> Result<(Vec<UnspentEntryWire>, Vec<SelectStrategy>)> {
+        let (mut confirmed, mut unconfirmed): (Vec<UnspentEntry>, Vec<UnspentEntry>) =
+            unspent_entries
+                .into_iter()
+                .partition(|utxo| utxo.height > 0);
+        sort_by_value(&mut confirmed);
+        if !ignore_package_limits {
+            unconfirmed.retain(|utxo| {
+                self.tracker
+                    .package_stats(&utxo.tx_hash)
+                    .map_or(true, |stats| stats.spendable_now)
+            });
+        }
+        // the most likely to confirm first
+        let fee_rates: HashMap<Txid, f64> = unconfirmed
+            .iter()
+            .map(|utxo| {
+                let fee_rate = self.tracker.ancestor_fee_rate(&utxo.tx_hash);
+                (utxo.tx_hash, fee_rate.unwrap_or(0.0))
+            })
+            .collect();
+        unconfirmed.sort_by(|a, b| {
+            fee_rates[&b.tx_hash]
+                .partial_cmp(&fee_rates[&a.tx_hash])
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then_with(|| a.outpoint().cmp(&b.outpoint()))
+        });
+
+        let mut choose_list = vec![];
+        let mut strategies = vec![];
+        for target_amount in amounts {
+            cancel.check()?;
+            let (selected, strategy) = select_prefer_confirmed(
+                &mut confirmed,
+                &mut unconfirmed,
+                Amount::from_sat(*target_amount),
+                options,
+            )?;
+            for utxo in selected {
+                let mut entry = self.unspent_entry(&utxo);
+                entry.unconfirmed = Some(utxo.height == 0);
+                choose_list.push(entry);
+            }
+            strategies.push(strategy);
+        }
+        Ok((choose_list, strategies))
+    }
+
+    fn scripthash_unspent_is_exist(
+        &self,
+        client: &Client,
+        args: &UnspentExistArgs,
+    ) -> Result<Value> {
+        let status = self.query_status(client, *args.scripthash(), "unspent_exist")?;
+        let unspent_entries = self.tracker.get_unspent(&status);
+        Ok(unspent_exist_result(args, &unspent_entries))
+    }
+
+    fn scripthash_subscribe(
+        &self,
+        client: &mut Client,
+        (HashParam(scripthash),): &(HashParam<ScriptHash>,),
+    ) -> Result<Value> {
+        self.scripthashes_subscribe(client, &[*scripthash])
+            .next()
+            .unwrap()
+    }
+
+    fn scripthash_unsubscribe(
+        &self,
+        client: &mut Client,
+        (HashParam(scripthash),): &(HashParam<ScriptHash>,),
+    ) -> Result<Value> {
+        let removed = client.scripthashes.remove(scripthash);
+        if let Some(subscription) = &removed {
+            let memory_usage = subscription.status.memory_usage();
+            client.memory_usage = client.memory_usage.saturating_sub(memory_usage);
+        }
+        Ok(json!(removed.is_some()))
+    }
+
+    fn scripthashes_subscribe<'a>(
+        &'a self,
+        client: &'a mut Client,
+        scripthashes: &'a [ScriptHash],
+    ) -> impl Iterator<Item = Result<Value>> + 'a {
+        let limits = SubscriptionLimits {
+            count: self.scripthash_subscriptions_limit,
+            memory_budget: self.client_memory_budget,
+        };
+        let now = self.clock.now();
+        let build = move |cancel: &CancelToken, new: Vec<ScriptHash>| {
+            let priority = if new.len() >= MIN_BULK_SUBSCRIPTIONS {
+                Priority::Bulk
+            } else {
+                Priority::Interactive
+            };
+            // empty if cancelled (so the statuses are not built)
+            self.scheduler
+                .run_all(priority, new, cancel, |scripthash| {
+                    (scripthash, self.build_status(scripthash))
+                })
+                .map(|results| results.into_iter().collect())
+                .unwrap_or_default()
+        };
+        let rejected = &self.rejected_subscriptions;
+        subscribe_scripthashes(client, scripthashes, limits, now, rejected, build)
+    }
+
+    /// Use the client's subscribed status, unless it lags the tip by more than
+    /// `status_max_lag_blocks` (e.g. if its last update has failed): it's refreshed at the next
+    /// notification, but the query is answered by a freshly synced status.
+    fn query_status<'a>(
+        &self,
+        client: &'a Client,
+        scripthash: ScriptHash,
+        method: &str,
+    ) -> Result<QueryStatus<'a>> {
+        match client.status(&scripthash) {
+            // can't be synced without the daemon, and stale results are flagged as such
+            Some(status) if self.daemon_health.is_unreachable() || self.is_stale() => {
+                return Ok(QueryStatus::Subscribed(status))
+            }
+            Some(status) if status.is_lagging(self.tracker.chain(), self.status_max_lag_blocks) => {
+                info!(
+                    "refreshing {} status for blockchain.scripthash.{}: synced as of height {:?}, \
+                     while the tip is at height {}",
+                    scripthash,
+                    method,
+                    status.as_of_height(),
+                    self.tracker.chain().height()
+                );
+            }
+            Some(status) => return Ok(QueryStatus::Subscribed(status)),
+            None => info!(
+                "{} blockchain.scripthash.{} called for unsubscribed scripthash: {}",
+                UNSUBSCRIBED_QUERY_MESSAGE, method, scripthash
+            ),
+        }
+        if let Some(status) = client.prefetched.get(&scripthash) {
+            return Ok(QueryStatus::Prefetched(status));
+        }
+        if let Some(status) = self.status_cache.get(&scripthash, self.clock.now()) {
+            return Ok(QueryStatus::Cached(status));
+        }
+        let status = self.new_status(scripthash, &client.cancel)?;
+        let status = self
+            .status_cache
+            .insert(scripthash, status, self.clock.now());
+        Ok(QueryStatus::Fresh(status))
+    }
+
+    /// Build the statuses of a batch's unsubscribed (or lagging) scripthashes in parallel, so its
+    /// queries don't build them one by one (see `query_status()`).
+    fn prefetch_statuses(&self, client: &mut Client, scripthashes: &[ScriptHash]) {
+        // such statuses can't be refreshed (and unsubscribed ones would fail to build)
+        if self.daemon_health.is_unreachable() || self.is_stale() {
+            return;
+        }
+        let chain = self.tracker.chain();
+        let now = self.clock.now();
+        let missing: Vec<ScriptHash> = scripthashes
+            .iter()
+            .copied()
+            .collect::<HashSet<ScriptHash>>()
+            .into_iter()
+            .filter(|scripthash| match client.status(scripthash) {
+                Some(status) => status.is_lagging(chain, self.status_max_lag_blocks),
+                None => !self.status_cache.contains(scripthash, now),
+            })
+            .collect();
+        let priority = if missing.len() >= MIN_BULK_SUBSCRIPTIONS {
+            Priority::Bulk
+        } else {
+            Priority::Interactive
+        };
+        // empty if cancelled, and failed builds are retried (and reported) by their queries
+        client.prefetched = self
+            .scheduler
+            .run_all(priority, missing, &client.cancel, |scripthash| {
+                (scripthash, self.build_status(scripthash))
+            })
+            .map(|results| {
+                results
+                    .into_iter()
+                    .filter_map(|(scripthash, result)| Some((scripthash, result.ok()?)))
+                    .collect()
+            })
+            .unwrap_or_default();
+    }
+
+    /// Build the status for an interactive query (preempting bulk subscriptions' builds),
+    /// unless the client disconnects before its turn.
+    fn new_status(&self, scripthash: ScriptHash, cancel: &CancelToken) -> Result<ScriptHashStatus> {
+        self.scheduler.run(Priority::Interactive, cancel, || {
+            self.build_status(scripthash)
+        })?
+    }
+
+    fn build_status(&self, scripthash: ScriptHash) -> Result<ScriptHashStatus> {
+        self.tracker
+            .new_scripthash_status(scripthash, &self.daemon, &self.cache)
+    }
+
+    fn transaction_broadcast(&self, client: &mut Client, args: &BroadcastArgs) -> Result<Value> {
+        let (tx_bytes, key): (&[u8], Option<&str>) = args.into();
+        let tx: Transaction = deserialize(tx_bytes).context("invalid transaction")?;
+        let txid = tx.txid();
+        let result = self.broadcast_transaction(client, &tx, key);
+        // audited even if answered early (without sending the transaction to bitcoind)
+        let skipped = result.as_ref().ok().and_then(|skipped| *skipped);
+        let error = result.as_ref().err().map(|e| e.to_string());
+        self.audit_log
+            .broadcast(client.peer_addr, txid, skipped, error);
+        result?;
+        Ok(json!(txid))
+    }
+
+    /// Returns why the transaction wasn't sent to bitcoind (if it wasn't).
+    fn broadcast_transaction(
+        &self,
+        client: &mut Client,
+        tx: &Transaction,
+        key: Option<&str>,
+    ) -> Result<Option<&'static str>> {
+        let txid = tx.txid();
+        if let Some(key) = key {
+            ensure!(
+                key.len() <= MAX_BROADCAST_KEY_LEN,
+                "idempotency key is too long (max {} characters)",
+                MAX_BROADCAST_KEY_LEN
+            );
+            let recorded = client
+                .broadcasts
+                .get(key, self.clock.now(), self.broadcast_ttl);
+            if let Some(recorded) = recorded {
+                ensure!(
+                    recorded == txid,
+                    "idempotency key {:?} was used for another transaction {}",
+                    key,
+                    recorded
+                );
+                debug!("repeated broadcast (key={:?}), txid={}", key, txid);
+                return Ok(Some("repeated_key"));
+            }
+        }
+        let skipped = if self.tracker.mempool_contains(&txid)
+            || self
+                .tracker
+                .lookup_transaction(&self.daemon, txid)?
+                .is_some()
+        {
+            debug!("already broadcast, txid={}", txid);
+            Some("already_broadcast")
+        } else {
+            self.daemon.broadcast(tx)?;
+            self.webhooks.broadcast(txid);
+            self.mirrors.broadcast(&serialize_hex(tx));
+            None
+        };
+        if let Some(key) = key {
+            client
+                .broadcasts
+                .insert(key.to_owned(), txid, self.clock.now());
+        }
+        Ok(skipped)
+    }
+
+    fn transaction_get(&self, args: &TxGetArgs) -> Result<Value> {
+        let (txid, verbose) = args.into();
+        if verbose {
+            if let Some(info) = self.cache.get_tx_info(&txid, self.tracker.chain()) {
+                return Ok(info);
+            }
+            return self.verbose_transaction(txid);
+        }
+        if let Some(tx) = self.cache.get_tx(&txid, |tx| serialize_hex(tx)) {
+            return Ok(json!(tx));
+        }
+        debug!("tx cache miss: txid={}", txid);
+        // use internal index to load confirmed transaction without an RPC
+        if let Some(tx) = self
+            .tracker
+            .lookup_transaction(&self.daemon, txid)?
+            .map(|(_blockhash, tx)| tx)
+        {
+            return Ok(json!(serialize_hex(&tx)));
+        }
+        // load unconfirmed transaction via RPC
+        Ok(json!(self.daemon.get_transaction_hex(&txid, None)?))
+    }
+
+    /// A verbose transaction, which is cached (with its confirming block, if any) for the following
+    /// queries, since clients may fetch it repeatedly (e.g. for rendering their history).
+    fn verbose_transaction(&self, txid: Txid) -> Result<Value> {
+        let chain = self.tracker.chain();
+        let confirmed = self.tracker.lookup_transaction(&self.daemon, txid)?;
+        let blockhash = confirmed.as_ref().map(|(blockhash, _tx)| *blockhash);
+        let context = blockhash.and_then(|blockhash| BlockContext::new(chain, blockhash));
+        let local = match (&confirmed, &context) {
+            (Some((_blockhash, tx)), Some(context)) => self.confirmed_transaction_info(tx, context),
+            _ => None,
+        };
+        let info = match local {
+            Some(info) => info,
+            None => self.daemon.get_transaction_info(&txid, blockhash)?,
+        };
+        self.cache_tx_info(txid, &info, blockhash, context.as_ref());
+        Ok(info)
+    }
+
+    /// Cache a verbose transaction (with its confirming block, if any), unless its block was
+    /// reorged out meanwhile.
+    fn cache_tx_info(
+        &self,
+        txid: Txid,
+        info: &Value,
+        blockhash: Option<BlockHash>,
+        context: Option<&BlockContext>,
+    ) {
+        match (blockhash, context) {
+            (_, Some(context)) => {
+                let block = Some((context.blockhash, context.height));
+                self.cache.add_tx_info(txid, info.clone(), block);
+            }
+            (None, None) => self.cache.add_tx_info(txid, info.clone(), None),
+            (Some(_), None) => (), // its block was reorged out meanwhile
+        }
+    }
+
+    /// Add the missing transactions to the cache: the confirmed ones are loaded from their
+    /// blocks (each fetched once), and the others via parallel RPCs. Returns the failed RPCs'
+    /// errors (e.g. for unknown transactions).
+    fn warm_transactions(&self, txids: &[Txid]) -> Result<HashMap<Txid, anyhow::Error>> {
+        let missing: Vec<Txid> = txids
+            .iter()
+            .filter(|txid| !self.cache.contains_tx(txid))
+            .copied()
+            .collect::<HashSet<Txid>>()
+            .into_iter()
+            .collect();
+        let confirmed = self.tracker.lookup_transactions(&self.daemon, &missing)?;
+        let unconfirmed: Vec<Txid> = missing
+            .into_iter()
+            .filter(|txid| !confirmed.contains_key(txid))
+            .collect();
+        for (txid, (_blockhash, tx)) in confirmed {
+            self.cache.add_tx(txid, move || tx);
+        }
+        Ok(unconfirmed
+            .into_par_iter()
+            .filter_map(|txid| match self.daemon.get_transaction(&txid, None) {
+                Ok(tx) => {
+                    self.cache.add_tx(txid, move || tx);
+                    None
+                }
+                Err(err) => Some((txid, err)),
+            })
+            .collect())
+    }
+
+    /// Batched `blockchain.transaction.get` (e.g. for block explorers), reporting each
+    /// transaction's error inline (without failing the whole batch).
+    fn transactions_get(&self, args: &TxsGetArgs) -> Result<Value> {
+        let (txids, verbose): (Vec<Txid>, bool) = args.into();
+        ensure!(
+            txids.len() <= self.transactions_batch_limit,
+            "too many transactions: {} (max {})",
+            txids.len(),
+            self.transactions_batch_limit
+        );
+        let entries = transactions_get_entries(&txids, |unique| {
+            if verbose {
+                return self.verbose_transactions(unique);
+            }
+            let mut errors = self.warm_transactions(unique)?;
+            Ok(unique
+                .iter()
+                .map(|txid| {
+                    let result = match self.cache.get_tx(txid, |tx| serialize_hex(tx)) {
+                        Some(hex) => Ok(json!(hex)),
+                        None => match errors.remove(txid) {
+                            Some(err) => Err(err),
+                            // the cache may have been flushed meanwhile
+                            None => self.daemon.get_transaction_hex(txid, None),
+                        },
+                    };
+                    (*txid, result)
+                })
+                .collect())
+        })?;
+        Ok(json!(entries))
+    }
+
+    /// Verbose transactions (cached, or built from their blocks if confirmed, otherwise via
+    /// parallel RPCs), sharing the block-related fields of the ones confirmed by the same block
+    /// (which is resolved once, using the index). The built transactions are cached, as done by
+    /// `blockchain.transaction.get`.
+    fn verbose_transactions(&self, txids: &[Txid]) -> Result<HashMap<Txid, Result<Value>>> {
+        let chain = self.tracker.chain();
+        let mut results = HashMap::with_capacity(txids.len());
+        let missing: Vec<Txid> = txids
+            .iter()
+            .filter(|txid| match self.cache.get_tx_info(txid, chain) {
+                Some(info) => {
+                    results.insert(**txid, Ok(info));
+                    false
+                }
+                None => true,
+            })
+            .copied()
+            .collect();
+        let confirmed = self.tracker.lookup_transactions(&self.daemon, &missing)?;
+        let mut contexts = HashMap::<BlockHash, Option<BlockContext>>::new();
+        let mut blockhashes = HashMap::with_capacity(confirmed.len());
+        let mut infos = HashMap::with_capacity(confirmed.len());
+        for (txid, (blockhash, tx)) in confirmed {
+            let context = contexts
+                .entry(blockhash)
+                .or_insert_with(|| BlockContext::new(chain, blockhash));
+            match context
+                .as_ref()
+                .and_then(|context| self.confirmed_transaction_info(&tx, context))
+            {
+                Some(info) => {
+                    self.cache_tx_info(txid, &info, Some(blockhash), context.as_ref());
+                    infos.insert(txid, info);
+                }
+                None => {
+                    blockhashes.insert(txid, blockhash);
+                }
+            }
+            self.cache.add_tx(txid, move || tx);
+        }
+        let built: Vec<(Txid, Result<Value>)> = missing
+            .par_iter()
+            .map(|txid| {
+                if let Some(info) = infos.get(txid) {
+                    return (*txid, Ok(info.clone()));
+                }
+                let blockhash = blockhashes.get(txid).copied();
+                let context = blockhash.and_then(|blockhash| contexts[&blockhash].as_ref());
+                let result = self
+                    .daemon
+                    .get_transaction_info(txid, blockhash)
+                    .map(|mut info| {
+                        if let Some(context) = context {
+                            context.apply(&mut info);
+                        }
+                        self.cache_tx_info(*txid, &info, blockhash, context);
+                        info
+                    });
+                (*txid, result)
+            })
+            .collect();
+        results.extend(built);
+        Ok(results)
+    }
+
+    /// A confirmed transaction's verbose fields, derived from its block (already fetched to find
+    /// it) instead of an RPC, unless some of its outputs' fields can't be derived.
+    fn confirmed_transaction_info(
+        &self,
+        tx: &Transaction,
+        context: &BlockContext,
+    ) -> Option<Value> {
+        let mut info = txinfo::transaction_info(tx, self.network)?;
+        info["in_active_chain"] = json!(true); // as returned by bitcoind for a given blockhash
+        context.apply(&mut info);
+        Some(info)
+    }
+
+    /// The mempool's view of an unconfirmed transaction: its fees, its effective fee rate (with
+    /// its ancestors), the approximate vsize of the transactions paying more (from the fee
+    /// histogram) and whether it may be evicted (if it pays less than the mempool min fee).
+    fn transaction_mempool_info(&self, (HashParam(txid),): &(HashParam<Txid>,)) -> Result<Value> {
+        let entry = match self.tracker.mempool_entry(txid) {
+            Some(entry) => entry,
+            None => {
+                if let Some((blockhash, _tx)) =
+                    self.tracker.lookup_transaction(&self.daemon, *txid)?
+                {
+                    let height = self.tracker.chain().get_block_height(&blockhash);
+                    bail!(
+                        "transaction {} is already confirmed (at height {})",
+                        txid,
+                        height.map_or("unknown".to_owned(), |h| h.to_string())
+                    );
+                }
+                let message = format!("transaction {} is not in the mempool", txid);
+                let err = CodedError::new(ErrorCode::TxNotFound, message)
+                    .with_data(json!({ "txid": txid }));
+                return Err(err.into());
+            }
+        };
+        let fee_rate = entry.ancestor_fee_rate();
+        let min_fee_rate = self.tracker.mempool_min_fee_rate();
+        Ok(json!(MempoolInfoResult {
+            txid: *txid,
+            vsize: entry.vsize,
+            fees: MempoolFees {
+                base: entry.fee.to_sat(),
+                ancestor: entry.ancestor_fee.to_sat(),
+                descendant: entry.descendant_fee.to_sat(),
+            },
+            ancestor_count: entry.ancestor_count,
+            descendant_count: entry.descendant_count,
+            fee_rate,
+            vsize_ahead: self.tracker.fees_histogram().vsize_above(fee_rate as u64),
+            mempool_min_fee_rate: min_fee_rate,
+            eviction_risk: min_fee_rate.map_or(false, |min| entry.eviction_fee_rate() < min),
+            partial: self.tracker.is_mempool_partial(),
+        }))
+    }
+
+    /// An unsigned template replacing a mempool transaction (see `rbf::build()`)
+    fn transaction_build_rbf(&self, args: &BuildRbfArgs) -> Result<Value> {
+        let (txid, fee_rate, change_index) = args.into();
+        let entry = match self.tracker.mempool_entry(&txid) {
+            Some(entry) => entry,
+            None => {
+                let message = format!("transaction {} is not in the mempool", txid);
+                let err = CodedError::new(ErrorCode::TxNotFound, message)
+                    .with_data(json!({ "txid": txid }));
+                return Err(err.into());
+            }
+        };
+        let scripts;
+        let change = match change_index {
+            Some(index) => Change::Index(index),
+            None => {
+                scripts = self.prevout_scripts(&entry.tx)?;
+                Change::PayingTo(&scripts)
+            }
+        };
+        let original = rbf::Original {
+            tx: &entry.tx,
+            fee: entry.fee,
+            vsize: entry.vsize,
+            descendant_fee: entry.descendant_fee,
+        };
+        let incremental_relay_fee = self.daemon.get_incremental_relay_fee()?;
+        let replacement = rbf::build(&original, change, fee_rate, incremental_relay_fee)?;
+        Ok(json!(replacement))
+    }
+
+    /// The scripts of the outputs spent by the transaction's inputs
+    fn prevout_scripts(&self, tx: &Transaction) -> Result<Vec<ScriptBuf>> {
+        tx.input
+            .iter()
+            .map(|txin| {
+                let outpoint = txin.previous_output;
+                let prev_tx = match self.tracker.mempool_entry(&outpoint.txid) {
+                    Some(entry) => entry.tx.clone(),
+                    None => match self
+                        .tracker
+                        .lookup_transaction(&self.daemon, outpoint.txid)?
+                    {
+                        Some((_blockhash, tx)) => tx,
+                        None => bail!("missing funding transaction {}", outpoint.txid),
+                    },
+                };
+                let txout = prev_tx
+                    .output
+                    .get(outpoint.vout as usize)
+                    .with_context(|| format!("missing funding output {}", outpoint))?;
+                Ok(txout.script_pubkey.clone())
+            })
+            .collect()
+    }
+
+    /// Decode a PSBT, and check its inputs against the index and the mempool (for debugging
+    /// wallets' PSBTs). The PSBT is not modified, finalized or broadcast.
+    fn psbt_analyze(&self, (base64_psbt,): &(String,)) -> Result<Value> {
+        let psbt = psbt::parse(base64_psbt)?;
+        ensure!(
+            psbt.inputs.len() <= MAX_ANALYZED_PSBT_INPUTS,
+            "too many inputs: {} (max {})",
+            psbt.inputs.len(),
+            MAX_ANALYZED_PSBT_INPUTS
+        );
+        let analysis = psbt::analyze(&psbt, |outpoint| self.lookup_prevout(outpoint))?;
+        Ok(json!(analysis))
+    }
+
+    /// The previous output (if known), its confirmations and its spender (if spent)
+    fn lookup_prevout(&self, outpoint: OutPoint) -> Result<Option<psbt::Prevout>> {
+        let chain = self.tracker.chain();
+        let (tx, confirmations) = match self.tracker.mempool_entry(&outpoint.txid) {
+            Some(entry) => (entry.tx.clone(), 0),
+            None => match self
+                .tracker
+                .lookup_transaction(&self.daemon, outpoint.txid)?
+            {
+                Some((blockhash, tx)) => {
+                    let height = chain.get_block_height(&blockhash).expect("missing block");
+                    (tx, chain.height() + 1 - height)
+                }
+                None => return Ok(None),
+            },
+        };
+        let txout = match tx.output.get(outpoint.vout as usize) {
+            Some(txout) => txout.clone(),
+            None => return Ok(None),
+        };
+        let spent_by = self
+            .tracker
+            .lookup_spender(&self.daemon, outpoint)?
+            .map(|(txid, height)| PsbtSpender { txid, height });
+        Ok(Some(psbt::Prevout {
+            txout,
+            confirmations,
+            spent_by,
+        }))
+    }
+
+    /// Subscribing again to the same outpoint returns its current status (keeping a single
+    /// subscription).
+    fn outpoint_subscribe(&self, client: &mut Client, args: &OutPointArgs) -> Result<Value> {
+        let outpoint = OutPoint::from(args);
+        check_subscriptions_limit(
+            client.outpoints.contains_key(&outpoint),
+            client.outpoints.len(),
+            self.outpoint_subscriptions_limit,
+            "outpoint",
+        )?;
+        let mut status = client.outpoints.get(&outpoint).cloned().unwrap_or_default();
+        self.tracker
+            .update_outpoint_status(outpoint, &mut status, &self.daemon)?;
+        let result = json!(status.to_wire());
+        client.outpoints.insert(outpoint, status);
+        Ok(result)
+    }
+
+    fn outpoint_unsubscribe(
+        &self,
+        client: &mut Client,
+        (HashParam(txid), vout): &(HashParam<Txid>, u32),
+    ) -> Result<Value> {
+        let removed = client.outpoints.remove(&OutPoint::new(*txid, *vout));
+        Ok(json!(removed.is_some()))
+    }
+
+    /// Returns the transaction's height (or null if it's unknown), and notifies its client when it
+    /// changes (until it's confirmed by `transaction_subscription_confirmations` blocks).
+    fn transaction_subscribe(
+        &self,
+        client: &mut Client,
+        (HashParam(txid),): &(HashParam<Txid>,),
+    ) -> Result<Value> {
+        client.subscribe_transaction(
+            *txid,
+            self.transaction_subscriptions_limit,
+            self.transaction_subscription_confirmations,
+            self.tracker.chain(),
+            |status| self.tracker.update_tx_status(*txid, status, &self.daemon),
+        )
+    }
+
+    fn transaction_unsubscribe(
+        &self,
+        client: &mut Client,
+        (HashParam(txid),): &(HashParam<Txid>,),
+    ) -> Result<Value> {
+        Ok(json!(client.transactions.remove(txid).is_some()))
+    }
+
+    /// The transaction spending the outpoint (if any), for explorers - instead of scanning the
+    /// history of the output's address.
+    fn outpoint_get_spender(
+        &self,
+        (HashParam(txid), vout): &(HashParam<Txid>, u32),
+    ) -> Result<Value> {
+        ensure_enabled(
+            self.spender_index,
+            "blockchain.outpoint.get_spender",
+            "spender-index",
+        )?;
+        let outpoint = OutPoint::new(*txid, *vout);
+        let spender =
+            self.tracker
+                .lookup_spender(&self.daemon, outpoint)?
+                .map(|(spender_txid, height)| SpenderResult {
+                    spender_txid,
+                    height,
+                });
+        Ok(json!(spender))
+    }
+
+    /// Confirmed transactions whose txid starts with the given hex prefix (e.g. for explorer
+    /// search). More than one match means that the prefix is ambiguous.
+    fn transaction_search_prefix(&self, (prefix, limit): &(String, usize)) -> Result<Value> {
+        ensure_enabled(
+            self.txid_prefix_index,
+            "blockchain.transaction.search_prefix",
+            "txid-prefix-index",
+        )?;
+        let prefix = parse_txid_prefix(prefix)?;
+        ensure!(
+            *limit >= 1 && *limit <= MAX_TXID_PREFIX_MATCHES,
+            "invalid limit: {} (expected 1 to {})",
+            limit,
+            MAX_TXID_PREFIX_MATCHES
+        );
+        let chain = self.tracker.chain();
+        let candidates = self.tracker.search_txid_prefix(&prefix)?;
+        let result = select_prefix_matches(candidates, *limit, |txid, height| {
+            // the rows of blocks replaced by a reorg are not deleted from the index
+            match chain.get_block_hash(height) {
+                Some(blockhash) => Ok(self.block_txids(blockhash)?.contains(&txid)),
+                None => Ok(false),
+            }
+        })?;
+        Ok(json!(result))
+    }
+
+    fn transaction_get_merkle(&self, args: &GetMerkleArgs) -> Result<Value> {
+        let (txid, height, with_header) = args.into();
+        let chain = self.tracker.chain();
+        let blockhash = match chain.get_block_hash(height) {
+            None => bail!("missing block at {}", height),
+            Some(blockhash) => blockhash,
+        };
+        let txids = self.block_txids(blockhash)?;
+        match txids.iter().position(|current_txid| *current_txid == txid) {
+            None => Err(self.missing_txid(txid, height, blockhash)),
+            Some(position) => {
+                let proof = Proof::create(&txids, position);
+                let header = if with_header {
+                    chain.get_block_header(height)
+                } else {
+                    None
+                };
+                merkle_result(txid, height, blockhash, &proof, header).map(|result| json!(result))
+            }
+        }
+    }
+
+    /// Chained merkle proofs of confirmed transactions, bound to the header chain up to
+    /// `cp_height` (see `merkle::verify_chained_proof()`). Failures are reported per entry.
+    fn proofs_export(
+        &self,
+        (entries, cp_height): &(Vec<(HashParam<Txid>, usize)>, usize),
+    ) -> Result<Value> {
+        ensure_enabled(self.proofs_export_rpc, "proofs.export", "proofs-export-rpc")?;
+        ensure!(
+            entries.len() <= MAX_EXPORTED_PROOFS,
+            "too many transactions: {} (max {})",
+            entries.len(),
+            MAX_EXPORTED_PROOFS
+        );
+        let chain = self.tracker.chain();
+        let headers_tree = self.headers_trees.get(chain, *cp_height)?;
+        let cp_root = headers_tree.root();
+        let proofs = entries
+            .iter()
+            .map(|(HashParam(txid), height)| {
+                let result = self.chained_proof(*txid, *height, *cp_height, &headers_tree);
+                let (proof, error) = match result {
+                    Ok(proof) => (Some(proof), None),
+                    Err(e) => (None, Some(e.to_string())),
+                };
+                ExportedProof {
+                    txid: *txid,
+                    height: *height,
+                    proof,
+                    error,
+                }
+            })
+            .collect();
+        Ok(json!(ProofsExportResult {
+            cp_height: *cp_height,
+            cp_block_hash: chain.get_block_hash(*cp_height),
+            cp_root,
+            proofs,
+        }))
+    }
+
+    fn chained_proof(
+        &self,
+        txid: Txid,
+        height: usize,
+        cp_height: usize,
+        headers_tree: &Tree,
+    ) -> Result<ChainedProof> {
+        ensure!(
+            height <= cp_height,
+            "height {} is above cp_height {}",
+            height,
+            cp_height
+        );
+        let chain = self.tracker.chain();
+        let header = chain
+            .get_block_header(height)
+            .with_context(|| format!("missing header at height {}", height))?;
+        let blockhash = header.block_hash();
+        let txids = self.block_txids(blockhash)?;
+        let tx_pos = match txids.iter().position(|current_txid| *current_txid == txid) {
+            None => return Err(self.missing_txid(txid, height, blockhash)),
+            Some(position) => position,
+        };
+        Ok(ChainedProof {
+            txid,
+            height,
+            header: serialize_hex(header),
+            tx_pos,
+            tx_branch: Proof::create(&txids, tx_pos).into_branch(),
+            cp_height,
+            header_branch: headers_tree.branch(height),
+            cp_root: headers_tree.root(),
+        })
+    }
+
+    /// A block's txids, cached since clients verifying their wallets usually request proofs of a
+    /// few (mostly recent) blocks' transactions (and read from the block txids index if enabled).
+    fn block_txids(&self, blockhash: BlockHash) -> Result<Arc<Vec<Txid>>> {
+        if let Some(txids) = self.cache.get_block_txids(&blockhash) {
+            return Ok(txids);
+        }
+        let txids = Arc::new(self.tracker.get_block_txids(&self.daemon, blockhash)?);
+        self.cache.add_block_txids(blockhash, Arc::clone(&txids));
+        Ok(txids)
+    }
+
+    /// The transaction may have been confirmed at another height (after a reorg), so the client
+    /// should retry using the new one.
+    fn missing_txid(&self, txid: Txid, height: usize, blockhash: BlockHash) -> anyhow::Error {
+        let message = format!("missing txid {} in block {}", txid, blockhash);
+        let confirmed_height = match self.tracker.lookup_transaction(&self.daemon, txid) {
+            Ok(Some((blockhash, _tx))) => self.tracker.chain().get_block_height(&blockhash),
+            _ => None,
+        };
+        let err = match confirmed_height {
+            Some(confirmed_height) if confirmed_height != height => {
+                CodedError::new(ErrorCode::ReorgedRetry, message)
+                    .with_data(json!({"txid": txid, "height": confirmed_height}))
+            }
+            _ => CodedError::new(ErrorCode::TxNotFound, message).with_data(json!({ "txid": txid })),
+        };
+        err.into()
+    }
+
+    fn transaction_from_pos(
+        &self,
+        (height, tx_pos, merkle): (usize, usize, bool),
+    ) -> Result<Value> {
+        let chain = self.tracker.chain();
+        let blockhash = match chain.get_block_hash(height) {
+            None => bail!("missing block at {}", height),
+            Some(blockhash) => blockhash,
+        };
+        let txids = self.block_txids(blockhash)?;
+        if tx_pos >= txids.len() {
+            bail!("no tx at position {} in block at height {}", tx_pos, height);
+        }
+        Ok(id_from_pos_result(
+            &txids,
+            tx_pos,
+            merkle,
+            self.legacy_id_from_pos,
+        ))
+    }
+
+    fn get_fee_histogram(&self, verbose: bool) -> Result<Value> {
+        let histogram = self.tracker.fees_histogram().entries();
+        if !verbose {
+            return Ok(json!(histogram));
+        }
+        // the histogram is inaccurate until the initial mempool load is done
+        Ok(json!(FeeHistogramResult {
+            histogram,
+            partial: self.tracker.is_mempool_partial(),
+        }))
+    }
+
+    fn server_id(&self) -> String {
+        format!("electrs/{}", ELECTRS_VERSION)
+    }
+
+    fn version(
+        &self,
+        client: &mut Client,
+        (client_id, client_version): &(String, Version),
+    ) -> Result<Value> {
+        let version = ProtocolVersion::negotiate(client_version).map_err(|err| {
+            anyhow!(
+                "{} requested {}, server supports {}: {}",
+                Sanitized(client_id),
+                Sanitized(&format!("{:?}", client_version)),
+                PROTOCOL_VERSION,
+                err
+            )
+        })?;
+        let result = json!([self.server_id(), version.to_string()]);
+        client.negotiated_version = Some(version);
+        Ok(result)
+    }
+
+    /// Switch the rest of the connection to CBOR frames (after this call's response, which is
+    /// sent as JSON), e.g. for high-throughput clients.
+    fn codec(&self, client: &mut Client, (name,): &(String,)) -> Result<Value> {
+        ensure_enabled(self.cbor_codec, CODEC_METHOD, "cbor-codec")?;
+        ensure!(
+            client.negotiated_version.is_some(),
+            "server.version must be called before {}",
+            CODEC_METHOD
+        );
+        ensure!(
+            name == "cbor",
+            "unsupported codec '{}' (expected cbor)",
+            Sanitized(name)
+        );
+        client.codec = Codec::Cbor;
+        Ok(json!(name))
+    }
+
+    fn set_history_deltas(&self, client: &mut Client, (enabled,): &(bool,)) -> Result<Value> {
+        client.history_deltas = *enabled;
+        Ok(json!(enabled))
+    }
+
+    fn set_notifications_debounce(&self, client: &mut Client, (ms,): &(u64,)) -> Result<Value> {
+        ensure!(
+            *ms <= MAX_DEBOUNCE_MS,
+            "debounce window is too long ({} > {} ms)",
+            ms,
+            MAX_DEBOUNCE_MS
+        );
+        // pending notifications are flushed by the next update, if disabled
+        client.debounced.window = match ms {
+            0 => None,
+            ms => Some(Duration::from_millis(*ms)),
+        };
+        Ok(json!(ms))
+    }
+
+    fn set_strict_params(&self, client: &mut Client, (enabled,): &(bool,)) -> Result<Value> {
+        client.strict_params = *enabled;
+        Ok(json!(self.strict_params || client.strict_params))
+    }
+
+    fn descriptor_info(&self, (descriptor, count): &(String, usize)) -> Result<Value> {
+        if *count > MAX_DESCRIPTOR_DERIVATIONS {
+            bail!(
+                "too many derivations requested: {} (maximum is {})",
+                count,
+                MAX_DESCRIPTOR_DERIVATIONS
+            );
+        }
+        let desc = PublicDescriptor::parse(descriptor)?;
+        // a descriptor without a wildcard has a single script
+        let count = if desc.has_wildcard() { *count } else { 1 };
+        let secp = bitcoin::secp256k1::Secp256k1::verification_only();
+        let scripthashes = (0..count)
+            .map(|index| desc.scripthash(&secp, index as u32))
+            .collect::<Result<Vec<ScriptHash>>>()?;
+        Ok(json!(DescriptorInfoResult {
+            descriptor: desc.canonical(),
+            checksum: desc.checksum(),
+            script_type: desc
+                .script_type()
+                .expect("unsupported script type")
+                .to_owned(),
+            has_wildcard: desc.has_wildcard(),
+            scripthashes,
+        }))
+    }
+
+    fn banner(&self, client: &Client) -> Result<Value> {
+        let hostname = client.hostname.as_deref();
+        Ok(json!(self.hostnames.banner(hostname, &self.banner)))
+    }
+
+    fn donation_address(&self, client: &Client) -> Result<Value> {
+        let hostname = client.hostname.as_deref();
+        Ok(json!(self.hostnames.donation_address(hostname)))
+    }
+
+    fn features(&self, client: &Client) -> Result<Value> {
+        let hostname = client.hostname.as_deref();
+        // consistent with `server.peers.subscribe`, unless overridden
+        let default_hosts = self
+            .peers
+            .hosts(hostname)
+            .unwrap_or_else(|| json!({ "tcp_port": self.port }));
+        let hosts = self.hostnames.hosts(hostname, default_hosts);
+        Ok(json!(FeaturesResult {
+            genesis_hash: self.tracker.chain().get_block_hash(0),
+            hosts,
+            protocol_max: PROTOCOL_VERSION.to_owned(),
+            protocol_min: PROTOCOL_VERSION.to_owned(),
+            pruning: None,
+            server_version: self.server_id(),
+            hash_function: "sha256".to_owned(),
+            extensions: self.extensions(),
+        }))
+    }
+
+    fn add_peer(&self, (announcement,): &(Announcement,)) -> Result<Value> {
+        let genesis_hash = self
+            .tracker
+            .chain()
+            .get_block_hash(0)
+            .expect("missing genesis");
+        self.peers.add(announcement, genesis_hash)?;
+        Ok(json!(true))
+    }
+
+    /// Optional (non-standard) server behaviors, which clients may need to handle
+    fn extensions(&self) -> Extensions {
+        let idle_unsubscribe = self
+            .subscription_idle_timeout
+            .map(|timeout| IdleUnsubscribe {
+                idle_secs: timeout.as_secs(),
+                notification: "blockchain.scripthash.unsubscribed".to_owned(),
+            });
+        let cbor_codec = if self.cbor_codec {
+            Some(CborCodec {
+                method: CODEC_METHOD.to_owned(),
+                length_prefix: "u32be".to_owned(),
+            })
+        } else {
+            None
+        };
+        Extensions {
+            error_codes: errors::registry(),
+            idle_unsubscribe,
+            cbor_codec,
+            history_deltas: HistoryDeltas {
+                method: "server.history_deltas".to_owned(),
+                max_entries: MAX_DELTA_ENTRIES,
+            },
+            notifications_debounce: NotificationsDebounce {
+                method: "server.notifications.debounce".to_owned(),
+                max_ms: MAX_DEBOUNCE_MS,
+            },
+            request_limits: RequestLimits {
+                max_batch_calls: self.request_batch_limit,
+                max_request_bytes: self.request_size_limit,
+            },
+        }
+    }
+
+    /// electrs-specific server information
+    fn server_info(&self) -> Result<Value> {
+        Ok(json!(ServerInfoResult {
+            server_version: self.server_id(),
+            txindex: self.daemon.txindex(),
+        }))
+    }
+
+    fn health_flags(&self) -> HealthFlags {
+        HealthFlags::new(
+            self.tracker.status().is_ok(),
+            &self.daemon_health,
+            &self.index_health,
+            self.tracker.is_mempool_partial(),
+        )
+    }
+
+    pub fn health(&self) -> HealthReport {
+        let chain = self.tracker.chain();
+        let (mempool_txs, mempool_listed) = self.tracker.mempool_view();
+        let tx_cache = self.cache.tx_stats();
+        HealthReport {
+            height: chain.height(),
+            tip: chain.tip(),
+            flags: self.health_flags(),
+            daemon_height: self.tracker.daemon_height(),
+            last_sync_secs: self.last_sync_duration.map(|d| d.as_secs_f64()),
+            mempool_txs,
+            mempool_age_secs: mempool_listed.map(|listed| listed.elapsed().as_secs_f64()),
+            tx_cache_entries: tx_cache.entries,
+            tx_cache_bytes: tx_cache.bytes,
+        }
+    }
+
+    fn sync_status(&self) -> Result<Value> {
+        Ok(json!(self.health()))
+    }
+
+    /// Returns no responses for the requests handled after the client has disconnected.
+    pub fn handle_requests(&self, client: &mut Client, frames: &[Frame]) -> Vec<Value> {
+        let peer_addr = client.peer_addr;
+        let strict = self.strict_params || client.strict_params;
+        frames
+            .iter()
+            .map(|frame| self.parse_calls(frame, peer_addr, strict))
+            .filter_map(|calls| self.handle_calls(client, calls))
+            .collect()
+    }
+
+    /// Oversized frames are rejected before being parsed, and oversized batches before parsing
+    /// their calls' params (answering each of the batch's requests with the same error).
+    fn parse_calls(
+        &self,
+        frame: &Frame,
+        peer_addr: Option<SocketAddr>,
+        strict: bool,
+    ) -> Result<Calls, Value> {
+        let size = frame.size();
+        if size > self.request_size_limit {
+            self.rejected_requests.inc("size");
+            let message = format!(
+                "request is too large: {} bytes (max {})",
+                size, self.request_size_limit
+            );
+            let err = resource_limit_error(message, self.request_size_limit);
+            return Err(error_msg(&Value::Null, RpcError::Coded(err)));
+        }
+        let requests = parse_requests(frame).map_err(|err| {
+            self.reputation.record(peer_addr, Violation::ParseError);
+            error_msg_no_id(err)
+        })?;
+        if let Requests::Batch(batch) = &requests {
+            if batch.len() > self.request_batch_limit {
+                self.rejected_requests.inc("batch");
+            }
+        }
+        Ok(Calls::parse(requests, strict, self.request_batch_limit))
+    }
+
+    fn handle_calls(&self, client: &mut Client, calls: Result<Calls, Value>) -> Option<Value> {
+        if client.cancel.is_cancelled() {
+            return None; // nobody will read the response
+        }
+        let calls: Calls = match calls {
+            Ok(calls) => calls,
+            Err(response) => return Some(response), // JSON parsing failed - the response does not contain request id
+        };
+        let response = self.handle_parsed_calls(client, calls);
+        if client.cancel.is_cancelled() {
+            return None; // the handlers may have been abandoned midway
+        }
+        response
+    }
+
+    /// Returns no response for a notification, or for a batch of notifications.
+    fn handle_parsed_calls(&self, client: &mut Client, calls: Calls) -> Option<Value> {
+        match calls {
+            Calls::Batch(batch) => {
+                let responses = match self.try_multi_call(client, &batch) {
+                    Some(responses) => responses,
+                    None => batch
+                        .into_iter()
+                        .filter_map(|result| self.single_call(client, result))
+                        .collect::<Vec<Value>>(),
+                };
+                if responses.is_empty() {
+                    return None; // JSON-RPC 2.0 doesn't allow responding with an empty array
+                }
+                Some(json!(responses))
+            }
+            Calls::Single(result) => self.single_call(client, result),
+        }
+    }
+
+    fn try_multi_call(
+        &self,
+        client: &mut Client,
+        calls: &[Result<Call, Option<Value>>],
+    ) -> Option<Vec<Value>> {
+        // exit if any call failed to parse
+        let valid_calls = calls
+            .iter()
+            .map(|result| result.as_ref().ok())
+            .collect::<Option<Vec<&Call>>>()?;
+
+        if let Some(responses) = self.try_multi_transaction_get(&valid_calls) {
+            return Some(responses);
+        }
+        if let Some(responses) = self.try_multi_scripthash_query(client, &valid_calls) {
+            return Some(responses);
+        }
+
+        // only "blockchain.scripthashes.subscribe" are supported
+        let scripthashes: Vec<ScriptHash> = valid_calls
+            .iter()
+            .map(|call| match &call.params {
+                Params::ScriptHashSubscribe((HashParam(scripthash),)) => Some(*scripthash),
+                _ => None, // exit if any of the calls is not supported
+            })
+            .collect::<Option<Vec<ScriptHash>>>()?;
+
+        Some(
+            self.rpc_duration
+                .observe_duration("blockchain.scripthash.subscribe:multi", || {
+                    self.scripthashes_subscribe(client, &scripthashes)
+                        .zip(valid_calls)
+                        .filter_map(|(result, call)| call.response(result))
+                        .collect::<Vec<Value>>()
+                }),
+        )
+    }
+
+    /// Non-verbose `blockchain.transaction.get` batches are served after loading the missing
+    /// transactions together (as by `blockchain.transactions.get`).
+    fn try_multi_transaction_get(&self, calls: &[&Call]) -> Option<Vec<Value>> {
+        let txids = calls
+            .iter()
+            .map(|call| match &call.params {
+                Params::TransactionGet(args) => {
+                    let (txid, verbose): (Txid, bool) = args.into();
+                    if verbose {
+                        None
+                    } else {
+                        Some(txid)
+                    }
+                }
+                _ => None,
+            })
+            .collect::<Option<Vec<Txid>>>()?;
+        // otherwise, the calls are rejected by `single_call()`
+        if self.tracker.status().is_err() || self.daemon_health.is_unreachable() {
+            return None;
+        }
+        Some(
+            self.rpc_duration
+                .observe_duration("blockchain.transaction.get:multi", || {
+                    if let Err(e) = self.warm_transactions(&txids) {
+                        warn!("failed to load {} transactions: {:#}", txids.len(), e);
+                    }
+                    calls
+                        .iter()
+                        .filter_map(|call| match &call.params {
+                            Params::TransactionGet(args) => {
+                                call.response(self.transaction_get(args))
+                            }
+                            _ => unreachable!("only transaction.get calls are batched"),
+                        })
+                        .collect::<Vec<Value>>()
+                }),
+        )
+    }
+
+    /// Batches of `blockchain.scripthash.get_balance`, `get_history` and `listunspent` calls (e.g.
+    /// by accounting jobs, or wallets importing many addresses) are answered in order, after
+    /// building their scripthashes' missing statuses in parallel (once per scripthash).
+    fn try_multi_scripthash_query(
+        &self,
+        client: &mut Client,
+        calls: &[&Call],
+    ) -> Option<Vec<Value>> {
+        let scripthashes = multi_query_scripthashes(calls)?;
+        let first = calls.first()?;
+        let label = if calls.iter().all(|call| call.method == first.method) {
+            format!("{}:multi", first.method)
+        } else {
+            "blockchain.scripthash.query:multi".to_owned()
+        };
+        Some(self.rpc_duration.observe_duration(&label, || {
+            multi_query_responses(
+                client,
+                calls,
+                &scripthashes,
+                |client, scripthashes| self.prefetch_statuses(client, scripthashes),
+                |client, call| self.call_response(client, call),
+            )
+        }))
+    }
+
+    fn single_call(&self, client: &mut Client, call: Result<Call, Option<Value>>) -> Option<Value> {
+        let call = match call {
+            Ok(call) => call,
+            Err(response) => return response, // params parsing may fail - the response contains request id (if any)
+        };
+        self.rpc_duration
+            .observe_duration(&call.method, || self.call_response(client, &call))
+    }
+
+    fn call_response(&self, client: &mut Client, call: &Call) -> Option<Value> {
+        if self.tracker.status().is_err()
+            && !call.params.is_available_while_syncing(self.sync_serving)
+        {
+            return call.error(RpcError::UnavailableIndex);
+        }
+        if self.daemon_health.is_unreachable()
+            && !call
+                .params
+                .is_available_without_daemon(self.serve_stale_without_daemon)
+        {
+            return call.error(RpcError::UnavailableDaemon);
+        }
+        if let Some(params) = &call.admin_params {
+            if let Err(e) = errors::ensure_admin(client.admin, &call.method) {
+                let error = Some(e.to_string());
+                self.audit_log
+                    .admin(client.peer_addr, &call.method, params, error);
+                return call.response(Err(e));
+            }
+        }
+        if let Some(scripthash) = call.params.scripthash() {
+            self.audit_log
+                .query(client.peer_addr, &call.method, &scripthash);
+            client.touch(&scripthash, self.clock.now());
+        }
+        let result = match &call.params {
+            Params::AddPeer(args) => self.add_peer(args),
+            Params::Banner => self.banner(client),
+            Params::BansList => self.bans_list(),
+            Params::BansUnban(args) => self.bans_unban(args),
+            Params::BlockHeader(args) => self.block_header(args),
+            Params::BlockHeaderByHash(args) => self.block_header_by_hash(args),
+            Params::BlockHeaders(args) => self.block_headers(args),
+            Params::BlockTxidsSubscribe => self.block_txids_subscribe(client),
+            Params::CacheFlush(args) => self.cache_flush(args),
+            Params::CacheStats => self.cache_stats(),
+            Params::Codec(args) => self.codec(client, args),
+            Params::DescriptorInfo(args) => self.descriptor_info(args),
+            Params::Donation => self.donation_address(client),
+            Params::EstimateFee(args) => self.estimate_fee(*args),
+            Params::Features => self.features(client),
+            Params::HeadersSubscribe(decoded) => self.headers_subscribe(client, *decoded),
+            Params::HistoryDeltas(args) => self.set_history_deltas(client, args),
+            Params::MempoolFeeHistogram(verbose) => self.get_fee_histogram(*verbose),
+            Params::NotificationsDebounce(args) => self.set_notifications_debounce(client, args),
+            Params::Orphaned => self.orphaned(),
+            Params::OutPointGetSpender(args) => self.outpoint_get_spender(args),
+            Params::OutPointSubscribe(args) => self.outpoint_subscribe(client, args),
+            Params::OutPointUnsubscribe(args) => self.outpoint_unsubscribe(client, args),
+            Params::PeersSubscribe => Ok(json!(self.peers.subscribe_result())),
+            Params::ProofsExport(args) => self.proofs_export(args),
+            Params::PsbtAnalyze(args) => self.psbt_analyze(args),
+            Params::Ping => Ok(Value::Null),
+            Params::RelayFee => self.relayfee(),
+            Params::ServerInfo => self.server_info(),
+            Params::StrictParams(args) => self.set_strict_params(client, args),
+            Params::SyncStatus => self.sync_status(),
+            Params::ScriptHashGetBalance(args) => self.scripthash_get_balance(client, args),
+            Params::ScriptHashGetHistory(args) => self.scripthash_get_history(client, args),
+            Params::ScriptHashGetHistoryFilter(args) => {
+                self.scripthash_get_history_filter(client, args)
+            }
+            Params::ScriptHashGetMempool(args) => self.scripthash_get_mempool(client, args),
+            Params::ScriptHashGetOrphaned((HashParam(scripthash),)) => {
+                Ok(json!(self.tracker.orphaned(Some(*scripthash))))
+            }
+            Params::ScriptHashListUnspent(args) => self.scripthash_list_unspent(client, args),
+            Params::ScriptHashReleaseUnspent(args) => self.scripthash_release_unspent(client, args),
+            Params::ScriptHashSelectUnspent(args) => self.scripthash_select_unspent(client, args),
+            Params::ScriptHashUnspentExist(args) => self.scripthash_unspent_is_exist(client, args),
+            Params::ScriptHashSubscribe(args) => self.scripthash_subscribe(client, args),
+            Params::ScriptHashUnsubscribe(args) => self.scripthash_unsubscribe(client, args),
+            Params::TransactionBroadcast(args) => self.transaction_broadcast(client, args),
+            Params::TransactionBuildRbf(args) => self.transaction_build_rbf(args),
+            Params::TransactionMempoolInfo(args) => self.transaction_mempool_info(args),
+            Params::TransactionGet(args) => self.transaction_get(args),
+            Params::TransactionGetMerkle(args) => self.transaction_get_merkle(args),
+            Params::TransactionSearchPrefix(args) => self.transaction_search_prefix(args),
+            Params::TransactionSubscribe(args) => self.transaction_subscribe(client, args),
+            Params::TransactionUnsubscribe(args) => self.transaction_unsubscribe(client, args),
+            Params::TransactionFromPosition(args) => self.transaction_from_pos(args.into()),
+            Params::TransactionsGet(args) => self.transactions_get(args),
+            Params::Version(args) => self.version(client, args),
+            Params::WebhooksStats => self.webhooks_stats(),
+            Params::BroadcastMirrorsStats => self.broadcast_mirrors_stats(),
+        };
+        if let Some(params) = &call.admin_params {
+            let error = result.as_ref().err().map(|e| format!("{:#}", e));
+            self.audit_log
+                .admin(client.peer_addr, &call.method, params, error);
+        }
+        if let Err(e) = &result {
+            if daemon::is_unreachable(e) {
+                self.daemon_health.set_unreachable(true);
+            }
+        }
+        call.response(result)
+    }
+}
+
+#[derive(Deserialize)]
+enum Params {
+    AddPeer((Announcement,)),
+    Banner,
+    BansList,
+    BansUnban((String,)),
+    BlockHeader(BlockHeaderArgs),
+    BlockHeaderByHash((HashParam<BlockHash>,)),
+    BlockHeaders(BlockHeadersArgs),
+    BlockTxidsSubscribe,
+    BroadcastMirrorsStats,
+    CacheFlush((String,)),
+    CacheStats,
+    Codec((String,)),
+    TransactionBroadcast(BroadcastArgs),
+    TransactionBuildRbf(BuildRbfArgs),
+    TransactionMempoolInfo((HashParam<Txid>,)),
+    DescriptorInfo((String, usize)),
+    Donation,
+    EstimateFee((u16,)),
+    Features,
+    HeadersSubscribe(bool), // optionally, with the decoded header fields
+    HistoryDeltas((bool,)),
+    MempoolFeeHistogram(bool), // optionally, with the `partial` flag
+    NotificationsDebounce((u64,)),
+    Orphaned,
+    OutPointGetSpender((HashParam<Txid>, u32)),
+    OutPointSubscribe(OutPointArgs),
+    OutPointUnsubscribe((HashParam<Txid>, u32)),
+    PeersSubscribe,
+    Ping,
+    ProofsExport((Vec<(HashParam<Txid>, usize)>, usize)),
+    PsbtAnalyze((String,)),
+    RelayFee,
+    ServerInfo,
+    StrictParams((bool,)),
+    SyncStatus,
+    ScriptHashGetBalance(ScriptHashArgs),
+    ScriptHashGetHistory(HistoryArgs),
+    ScriptHashGetHistoryFilter(HistoryFilterArgs),
+    ScriptHashGetMempool((HashParam<ScriptHash>,)),
+    ScriptHashGetOrphaned((HashParam<ScriptHash>,)),
+    ScriptHashListUnspent(ListUnspentArgs),
+    ScriptHashReleaseUnspent((HashParam<ScriptHash>, Vec<(HashParam<Txid>, u32)>)),
+    ScriptHashSelectUnspent(SelectUnspentArgs),
+    ScriptHashUnspentExist(UnspentExistArgs),
+    ScriptHashSubscribe((HashParam<ScriptHash>,)),
+    ScriptHashUnsubscribe((HashParam<ScriptHash>,)),
+    TransactionGet(TxGetArgs),
+    TransactionGetMerkle(GetMerkleArgs),
+    TransactionSearchPrefix((String, usize)),
+    TransactionSubscribe((HashParam<Txid>,)),
+    TransactionUnsubscribe((HashParam<Txid>,)),
+    TransactionFromPosition(IdFromPosArgs),
+    TransactionsGet(TxsGetArgs),
+    Version((String, Version)),
+    WebhooksStats,
+}
+
+/// A method's positional parameters, reported by strict mode. Optional ones are suffixed by `?`,
+/// followed by their (JSON) default if they may be omitted before a specified one.
+struct Signature {
+    method: &'static str,
+    params: &'static [&'static str],
+}
+
+impl Signature {
+    /// The minimal and maximal number of parameters
+    fn arity(&self) -> (usize, usize) {
+        let optional = self.params.iter().filter(|p| p.contains('?')).count();
+        (self.params.len() - optional, self.params.len())
+    }
+
+    fn names(&self) -> impl Iterator<Item = &'static str> {
+        self.params
+            .iter()
+            .map(|param| param.split('?').next().unwrap())
+    }
+
+    fn default_value(param: &str) -> Option<Value> {
+        let default = &param[param.find("?=")? + 2..];
+        Some(serde_json::from_str(default).expect("invalid default"))
+    }
+
+    /// Map named parameters (e.g. `{"scripthash": ..., "verbose": true}`) to their positions,
+    /// rejecting unknown names. Omitted optional parameters followed by specified ones are set to
+    /// their defaults.
+    fn positional(&self, params: Value) -> std::result::Result<Value, StandardError> {
+        let mut named = match params {
+            Value::Object(named) => named,
+            params => return Ok(params),
+        };
+        let mut args: Vec<Option<Value>> = self.names().map(|name| named.remove(name)).collect();
+        if !named.is_empty() {
+            let unknown: Vec<&str> = named.keys().map(String::as_str).collect();
+            warn!(
+                "unknown {} params: {}",
+                self.method,
+                Sanitized(&unknown.join(", "))
+            );
+            return Err(StandardError::InvalidParams);
+        }
+        while let Some(None) = args.last() {
+            args.pop(); // omitted optional parameters (or missing ones, rejected by `convert()`)
+        }
+        let args = args
+            .into_iter()
+            .zip(self.params)
+            .map(|(arg, param)| arg.or_else(|| Self::default_value(param)));
+        match args.collect::<Option<Vec<Value>>>() {
+            Some(args) => Ok(Value::Array(args)),
+            None => {
+                warn!("missing {} params (before the specified ones)", self.method);
+                Err(StandardError::InvalidParams)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}({})", self.method, self.params.join(", "))
+    }
+}
+
+macro_rules! signatures {
+    ($($method:literal => [$($param:literal),*],)*) => {
+        &[$(Signature { method: $method, params: &[$($param),*] },)*]
+    };
+}
+
+/// Every supported method's signature (should be kept in sync with `Params::parse()`)
+const SIGNATURES: &[Signature] = signatures! {
+    "blockchain.block.header" => ["height", "cp_height?"],
+    "blockchain.block.header_by_hash" => ["block_hash"],
+    "blockchain.block.headers" => ["start_height", "count", "cp_height?"],
+    "blockchain.block.txids.subscribe" => [],
+    "blockchain.estimatefee" => ["number"],
+    "blockchain.headers.subscribe" => ["decoded?"],
+    "blockchain.outpoint.get_spender" => ["txid", "vout"],
+    "blockchain.outpoint.subscribe" => ["txid", "vout", "spk_hint?"],
+    "blockchain.outpoint.unsubscribe" => ["txid", "vout"],
+    "blockchain.psbt.analyze" => ["psbt"],
+    "blockchain.relayfee" => [],
+    "blockchain.scripthash.get_balance" => ["scripthash", "verbose?"],
+    "blockchain.scripthash.get_history" => ["scripthash", "verbose?=false", "include_tx_pos?"],
+    "blockchain.scripthash.get_history_filter" => [
+        "scripthash", "from_height", "to_height", "reverse?=false", "verbose?"
+    ],
+    "blockchain.scripthash.get_mempool" => ["scripthash"],
+    "blockchain.scripthash.get_orphaned" => ["scripthash"],
+    "blockchain.scripthash.listunspent" => [
+        "scripthash", "include_mempool_spent?=false", "include_script_pubkey?=false", "safe_only?"
+    ],
+    "blockchain.scripthash.release_unspent" => ["scripthash", "outpoints"],
+    "blockchain.scripthash.unspent_exist" => ["scripthash", "txid", "vout?"],
+    // the parameters following `confirmed` may be passed as an object (see `SelectUnspentOptions`)
+    "blockchain.scripthash.select_unspent" => [
+        "scripthash", "amounts", "min_amount", "confirmed",
+        "mode?=null", "ignore_package_limits?=false", "include_script_pubkey?=false",
+        "fee_rate?=null", "strategy?=null", "max_inputs?"
+    ],
+    "blockchain.scripthash.subscribe" => ["scripthash"],
+    "blockchain.scripthash.unsubscribe" => ["scripthash"],
+    "blockchain.transaction.broadcast" => ["raw_tx", "idempotency_key?"],
+    "blockchain.transaction.build_rbf" => ["txid", "fee_rate", "change_index?"],
+    "blockchain.transaction.get" => ["txid", "verbose?"],
+    "blockchain.transaction.get_merkle" => ["txid", "height", "include_header?"],
+    "blockchain.transaction.mempool_info" => ["txid"],
+    "blockchain.transaction.search_prefix" => ["prefix", "limit"],
+    "blockchain.transaction.subscribe" => ["txid"],
+    "blockchain.transaction.unsubscribe" => ["txid"],
+    "blockchain.transaction.id_from_pos" => ["height", "tx_pos", "merkle?"],
+    "blockchain.transactions.get" => ["txids", "verbose?"],
+    "mempool.get_fee_histogram" => ["verbose?"],
+    "server.add_peer" => ["features"],
+    "server.banner" => [],
+    "server.bans.list" => [],
+    "server.bans.unban" => ["ip"],
+    "server.broadcast_mirrors.stats" => [],
+    "server.cache.flush" => ["kind"],
+    "server.cache.stats" => [],
+    "server.codec" => ["name"],
+    "server.donation_address" => [],
+    "server.features" => [],
+    "server.history_deltas" => ["enabled"],
+    "server.info" => [],
+    "server.notifications.debounce" => ["ms"],
+    "server.orphaned" => [],
+    "server.strict_params" => ["enabled"],
+    "server.sync_status" => [],
+    "server.peers.subscribe" => [],
+    "server.ping" => [],
+    "proofs.export" => ["txid_heights", "cp_height"],
+    "server.version" => ["client_name", "protocol_version"],
+    "server.webhooks.stats" => [],
+    "wallet.descriptor.info" => ["descriptor", "count"],
+};
+
+fn signature(method: &str) -> Option<&'static Signature> {
+    SIGNATURES
+        .iter()
+        .find(|signature| signature.method == method)
+}
+
+/// Validate the hex-encoded parameters (by their names) before their conversion, since the
+/// untagged argument enums don't report why none of their variants matched.
+/// Mistyped (e.g. non-string) parameters are left for `convert()` to reject.
+fn check_hex_params(
+    signature: &Signature,
+    params: &Value,
+) -> std::result::Result<(), StandardError> {
+    fn check_hash<H: bitcoin::hashes::Hash>(name: &str, arg: &Value) -> Result<()> {
+        match arg.as_str() {
+            Some(value) => hex::parse_hash::<H>(name, value).map(drop),
+            None => Ok(()),
+        }
+    }
+    // applied to each item of an array parameter
+    fn check_each(arg: &Value, check: impl Fn(&Value) -> Result<()>) -> Result<()> {
+        match arg.as_array() {
+            Some(items) => items.iter().try_for_each(check),
+            None => Ok(()),
+        }
+    }
+
+    let args = match params {
+        Value::Array(args) => args,
+        _ => return Ok(()),
+    };
+    for (name, arg) in signature.names().zip(args) {
+        let result = match name {
+            "scripthash" => check_hash::<ScriptHash>(name, arg),
+            "txid" => check_hash::<Txid>(name, arg),
+            "block_hash" => check_hash::<BlockHash>(name, arg),
+            "raw_tx" => match arg.as_str() {
+                Some(value) => hex::parse_bytes(name, value).map(drop),
+                None => Ok(()),
+            },
+            "txids" => check_each(arg, |txid| check_hash::<Txid>("txid", txid)),
+            // pairs of a txid and a vout (or a height)
+            "outpoints" | "txid_heights" => {
+                check_each(arg, |pair| check_hash::<Txid>("txid", &pair[0]))
+            }
+            _ => Ok(()),
+        };
+        result.map_err(|err| StandardError::InvalidHex(err.to_string()))?;
+    }
+    Ok(())
+}
+
+impl Params {
+    /// Parse the parameters, rejecting extra (or mistyped) ones - instead of ignoring them.
+    fn parse_strict(method: &str, params: Value) -> std::result::Result<Params, StandardError> {
+        let signature = match signature(method) {
+            Some(signature) => signature,
+            None => return Self::parse(method, params), // unknown method
+        };
+        let params = signature
+            .positional(params)
+            .map_err(|_| StandardError::UnexpectedParams(signature))?;
+        let unexpected = StandardError::UnexpectedParams(signature);
+        let args: &[Value] = match &params {
+            Value::Null => &[],
+            Value::Array(args) => args,
+            _ => return Err(unexpected), // neither positional nor named parameters
+        };
+        let (min, max) = signature.arity();
+        if args.len() < min || args.len() > max {
+            return Err(unexpected);
+        }
+        // these flags are parsed leniently (see below)
+        if let "blockchain.headers.subscribe" | "mempool.get_fee_histogram" = method {
+            if !args.iter().all(Value::is_boolean) {
+                return Err(unexpected);
+            }
+        }
+        Self::parse(method, params).map_err(|err| match err {
+            StandardError::InvalidParams => unexpected,
+            err => err,
+        })
+    }
+
+    /// Parse the parameters, either positional or named (as in the method's signature).
+    fn parse(method: &str, params: Value) -> std::result::Result<Params, StandardError> {
+        let params = match signature(method) {
+            Some(signature) => {
+                let params = signature.positional(params)?;
+                check_hex_params(signature, &params)?;
+                params
+            }
+            None => params, // unknown method
+        };
+        Ok(match method {
+            "blockchain.block.header" => Params::BlockHeader(convert(params)?),
+            "blockchain.block.header_by_hash" => Params::BlockHeaderByHash(convert(params)?),
+            "blockchain.block.headers" => Params::BlockHeaders(convert(params)?),
+            "blockchain.block.txids.subscribe" => Params::BlockTxidsSubscribe,
+            "blockchain.estimatefee" => Params::EstimateFee(convert(params)?),
+            // other params are ignored (as by other servers)
+            "blockchain.headers.subscribe" => Params::HeadersSubscribe(params[0] == true),
+            "blockchain.outpoint.get_spender" => Params::OutPointGetSpender(convert(params)?),
+            "blockchain.outpoint.subscribe" => Params::OutPointSubscribe(convert(params)?),
+            "blockchain.outpoint.unsubscribe" => Params::OutPointUnsubscribe(convert(params)?),
+            "blockchain.psbt.analyze" => Params::PsbtAnalyze(convert(params)?),
+            "blockchain.relayfee" => Params::RelayFee,
+            "blockchain.scripthash.get_balance" => Params::ScriptHashGetBalance(convert(params)?),
+            "blockchain.scripthash.get_history" => Params::ScriptHashGetHistory(convert(params)?),
+            "blockchain.scripthash.get_history_filter" => Params::ScriptHashGetHistoryFilter(convert(params)?),
+            "blockchain.scripthash.get_mempool" => Params::ScriptHashGetMempool(convert(params)?),
+            "blockchain.scripthash.get_orphaned" => Params::ScriptHashGetOrphaned(convert(params)?),
+            "blockchain.scripthash.listunspent" => Params::ScriptHashListUnspent(convert(params)?),
+            "blockchain.scripthash.release_unspent" => Params::ScriptHashReleaseUnspent(convert(params)?),
+            "blockchain.scripthash.unspent_exist" => Params::ScriptHashUnspentExist(convert(params)?),
+            "blockchain.scripthash.select_unspent" => Params::ScriptHashSelectUnspent(convert(params)?),
+            "blockchain.scripthash.subscribe" => Params::ScriptHashSubscribe(convert(params)?),
+            "blockchain.scripthash.unsubscribe" => Params::ScriptHashUnsubscribe(convert(params)?),
+            "blockchain.transaction.broadcast" => Params::TransactionBroadcast(convert(params)?),
+            "blockchain.transaction.build_rbf" => Params::TransactionBuildRbf(convert(params)?),
+            "blockchain.transaction.get" => Params::TransactionGet(convert(params)?),
+            "blockchain.transaction.get_merkle" => Params::TransactionGetMerkle(convert(params)?),
+            "blockchain.transaction.mempool_info" => {
+                Params::TransactionMempoolInfo(convert(params)?)
+            }
+            "blockchain.transaction.search_prefix" => {
+                Params::TransactionSearchPrefix(convert(params)?)
+            }
+            "blockchain.transaction.subscribe" => Params::TransactionSubscribe(convert(params)?),
+            "blockchain.transaction.unsubscribe" => {
+                Params::TransactionUnsubscribe(convert(params)?)
+            }
+            "blockchain.transaction.id_from_pos" => {
+                Params::TransactionFromPosition(convert(params)?)
+            }
+            "blockchain.transactions.get" => Params::TransactionsGet(convert(params)?),
+            "mempool.get_fee_histogram" => Params::MempoolFeeHistogram(params[0] == true),
+            "server.add_peer" => Params::AddPeer(convert(params)?),
+            "server.banner" => Params::Banner,
+            "server.bans.list" => Params::BansList,
+            "server.bans.unban" => Params::BansUnban(convert(params)?),
+            "server.cache.flush" => Params::CacheFlush(convert(params)?),
+            "server.cache.stats" => Params::CacheStats,
+            "server.codec" => Params::Codec(convert(params)?),
+            "server.donation_address" => Params::Donation,
+            "server.features" => Params::Features,
+            "server.history_deltas" => Params::HistoryDeltas(convert(params)?),
+            "server.info" => Params::ServerInfo,
+            "server.notifications.debounce" => Params::NotificationsDebounce(convert(params)?),
+            "server.orphaned" => Params::Orphaned,
+            "server.strict_params" => Params::StrictParams(convert(params)?),
+            "server.sync_status" => Params::SyncStatus,
+            "server.peers.subscribe" => Params::PeersSubscribe,
+            "server.ping" => Params::Ping,
+            "proofs.export" => Params::ProofsExport(convert(params)?),
+            "server.version" => Params::Version(convert(params)?),
+            "server.webhooks.stats" => Params::WebhooksStats,
+            "server.broadcast_mirrors.stats" => Params::BroadcastMirrorsStats,
+            "wallet.descriptor.info" => Params::DescriptorInfo(convert(params)?),
+            _ => {
+                warn!("unknown method {}", Sanitized(method));
+                return Err(StandardError::MethodNotFound);
+            }
+        })
+    }
+
+    /// The queried scripthash (if any)
+    fn scripthash(&self) -> Option<ScriptHash> {
+        match self {
+            Params::ScriptHashReleaseUnspent((HashParam(scripthash), _))
+            | Params::ScriptHashSubscribe((HashParam(scripthash),))
+            | Params::ScriptHashUnsubscribe((HashParam(scripthash),))
+            | Params::ScriptHashGetMempool((HashParam(scripthash),))
+            | Params::ScriptHashGetOrphaned((HashParam(scripthash),)) => Some(*scripthash),
+            Params::ScriptHashGetHistoryFilter(args) => Some(*args.scripthash()),
+            Params::ScriptHashSelectUnspent(args) => Some(*args.scripthash()),
+            Params::ScriptHashUnspentExist(args) => Some(*args.scripthash()),
+            Params::ScriptHashGetBalance(args) => {
+                let (scripthash, _verbose): (ScriptHash, bool) = args.into();
+                Some(scripthash)
+            }
+            Params::ScriptHashGetHistory(args) => {
+                let (scripthash, _, _): (ScriptHash, bool, bool) = args.into();
+                Some(scripthash)
+            }
+            Params::ScriptHashListUnspent(args) => {
+                let (scripthash, _, _, _): (ScriptHash, bool, bool, bool) = args.into();
+                Some(scripthash)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the call can be answered before the index is synced and compacted
+    fn is_available_while_syncing(&self, mode: SyncServing) -> bool {
+        match self {
+            // Allow only a few RPC (for sync status notification) not requiring index DB being compacted.
+            Params::BansList
+            | Params::BansUnban(_)
+            | Params::BlockHeader(_)
+            | Params::BlockHeaderByHash(_)
+            | Params::BlockHeaders(_)
+            | Params::CacheFlush(_)
+            | Params::CacheStats
+            | Params::Codec(_)
+            | Params::HeadersSubscribe(_)
+            | Params::DescriptorInfo(_)
+            | Params::HistoryDeltas(_)
+            | Params::NotificationsDebounce(_)
+            | Params::StrictParams(_)
+            | Params::SyncStatus
+            | Params::Version(_)
+            | Params::WebhooksStats
+            | Params::BroadcastMirrorsStats => true,
+            Params::ScriptHashGetBalance(_)
+            | Params::ScriptHashGetHistory(_)
+            | Params::ScriptHashListUnspent(_) => {
+                mode == SyncServing::ServeConfirmedDataWithStalenessFlag
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the call can be answered while bitcoind is unreachable
+    /// (stale scripthash queries are answered from the subscriptions' status).
+    fn is_available_without_daemon(&self, serve_stale: bool) -> bool {
+        match self {
+            Params::AddPeer(_)
+            | Params::Banner
+            | Params::BansList
+            | Params::BansUnban(_)
+            | Params::BlockHeader(_)
+            | Params::BlockHeaderByHash(_)
+            | Params::BlockHeaders(_)
+            | Params::CacheFlush(_)
+            | Params::CacheStats
+            | Params::Codec(_)
+            | Params::DescriptorInfo(_)
+            | Params::Donation
+            | Params::Features
+            | Params::HeadersSubscribe(_)
+            | Params::HistoryDeltas(_)
+            | Params::NotificationsDebounce(_)
+            | Params::Orphaned
+            | Params::OutPointGetSpender(_)
+            | Params::PeersSubscribe
+            | Params::Ping
+            | Params::ScriptHashGetOrphaned(_)
+            | Params::ScriptHashReleaseUnspent(_)
+            | Params::ServerInfo
+            | Params::StrictParams(_)
+            | Params::SyncStatus
+            | Params::TransactionSearchPrefix(_)
+            | Params::Version(_)
+            | Params::WebhooksStats
+            | Params::BroadcastMirrorsStats => true,
+            Params::ScriptHashGetBalance(_) | Params::ScriptHashGetHistory(_) => serve_stale,
+            _ => false,
+        }
+    }
+}
+
+/// Whether a subscribed transaction is confirmed by `confirmations` blocks (if set).
+fn is_deeply_confirmed(
+    status: Option<&WatchedTx>,
+    chain: &Chain,
+    confirmations: Option<usize>,
+) -> bool {
+    match (status, confirmations) {
+        (Some(tx), Some(confirmations)) => tx.confirmations(chain) >= confirmations,
+        _ => false,
+    }
+}
+
+/// Per-client scripthash subscriptions' limits
+#[derive(Clone, Copy)]
+struct SubscriptionLimits {
+    count: Option<usize>,
+    memory_budget: Option<usize>, // of the subscribed statuses (in bytes)
+}
+
+/// Subscribe the client to the given scripthashes, answering each of them (in order). Only the
+/// new scripthashes' statuses are built (by `build`), up to the subscriptions' limit: the
+/// following ones fail without being built.
+fn subscribe_scripthashes<'a, F>(
+    client: &'a mut Client,
+    scripthashes: &'a [ScriptHash],
+    limits: SubscriptionLimits,
+    now: Instant,
+    rejected: &'a Counter,
+    build: F,
+) -> impl Iterator<Item = Result<Value>> + 'a
+where
+    F: FnOnce(&CancelToken, Vec<ScriptHash>) -> HashMap<ScriptHash, Result<ScriptHashStatus>>,
+{
+    let mut unique = HashSet::new();
+    let mut new_scripthashes: Vec<ScriptHash> = scripthashes
+        .iter()
+        .copied()
+        .filter(|scripthash| !client.scripthashes.contains_key(scripthash))
+        .filter(|scripthash| unique.insert(*scripthash))
+        .collect();
+    if let Some(limit) = limits.count {
+        new_scripthashes.truncate(limit.saturating_sub(client.scripthashes.len()));
+    }
+    let mut results = build(&client.cancel, new_scripthashes);
+
+    scripthashes.iter().map(move |scripthash| {
+        let statushash = match client.scripthashes.entry(*scripthash) {
+            Entry::Occupied(mut e) => {
+                e.get_mut().touched = now;
+                e.get().status.statushash()
+            }
+            Entry::Vacant(e) => {
+                client.cancel.check()?;
+                let status = match results.remove(scripthash) {
+                    Some(result) => result?, // return an error for failed subscriptions
+                    None => {
+                        let limit = limits.count.expect("missing scripthash status");
+                        rejected.inc("scripthashes");
+                        let message = format!(
+                            "subscription limit {} reached (unsubscribe some scripthashes)",
+                            limit
+                        );
+                        // not a memory budget, so the error has no `data`
+                        let err = CodedError::new(ErrorCode::SubscriptionLimit, message);
+                        return Err(err.into());
+                    }
+                };
+                let memory_usage = client.memory_usage + status.memory_usage();
+                if let Some(budget) = limits.memory_budget {
+                    if memory_usage > budget {
+                        rejected.inc("memory_budget");
+                        let message = format!(
+                            "subscriptions' memory budget exceeded: {} > {} bytes (unsubscribe some scripthashes)",
+                            memory_usage, budget
+                        );
+                        let err = CodedError::new(ErrorCode::SubscriptionLimit, message)
+                            .with_data(json!({"memory_usage": memory_usage, "budget": budget}));
+                        return Err(err.into());
+                    }
+                }
+                client.memory_usage = memory_usage;
+                let subscription = Subscription {
+                    status,
+                    touched: now,
+                };
+                e.insert(subscription).status.statushash()
+            }
+        };
+        Ok(json!(statushash))
+    })
+}
+
+/// `blockchain.transactions.get` entries, in request order: each distinct txid is fetched once
+/// (by `fetch`), and its failure is reported inline (without failing the other ones).
+fn transactions_get_entries<F>(txids: &[Txid], fetch: F) -> Result<Vec<TransactionsGetEntry>>
+where
+    F: FnOnce(&[Txid]) -> Result<HashMap<Txid, Result<Value>>>,
+{
+    let mut seen = HashSet::with_capacity(txids.len());
+    let unique: Vec<Txid> = txids
+        .iter()
+        .copied()
+        .filter(|txid| seen.insert(*txid))
+        .collect();
+    let mut results = fetch(&unique)?;
+    let entries: HashMap<Txid, TransactionsGetEntry> = unique
+        .into_iter()
+        .map(|txid| {
+            let (result, error) = match results.remove(&txid) {
+                Some(Ok(value)) => (Some(value), None),
+                Some(Err(e)) => (None, Some(e.to_string())),
+                None => (None, Some(format!("transaction {} not found", txid))),
+            };
+            (
+                txid,
+                TransactionsGetEntry {
+                    txid,
+                    result,
+                    error,
+                },
+            )
+        })
+        .collect();
+    Ok(txids.iter().map(|txid| entries[txid].clone()).collect())
+}
+
+/// The queried scripthashes, if all the calls are scripthash queries (which can be prefetched).
+fn multi_query_scripthashes(calls: &[&Call]) -> Option<Vec<ScriptHash>> {
+    calls
+        .iter()
+        .map(|call| match &call.params {
+            Params::ScriptHashGetBalance(args) => {
+                let (scripthash, _verbose): (ScriptHash, bool) = args.into();
+                Some(scripthash)
+            }
+            Params::ScriptHashGetHistory(args) => {
+                let (scripthash, ..): (ScriptHash, bool, bool) = args.into();
+                Some(scripthash)
+            }
+            Params::ScriptHashListUnspent(args) => {
+                let (scripthash, ..): (ScriptHash, bool, bool, bool) = args.into();
+                Some(scripthash)
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Answer the scripthash queries in request order (each with its own result or error), after
+/// prefetching their statuses, which are dropped afterwards.
+fn multi_query_responses<P, R>(
+    client: &mut Client,
+    calls: &[&Call],
+    scripthashes: &[ScriptHash],
+    prefetch: P,
+    mut respond: R,
+) -> Vec<Value>
+where
+    P: FnOnce(&mut Client, &[ScriptHash]),
+    R: FnMut(&mut Client, &Call) -> Option<Value>,
+{
+    prefetch(client, scripthashes);
+    let responses = calls
+        .iter()
+        .filter_map(|call| respond(client, call))
+        .collect();
+    client.prefetched.clear();
+    responses
+}
+
+/// Set the entries' scriptPubKey: the `synced` one (if resolved by the status sync), otherwise
+/// the one of the first entry's output, from its funding transaction (returned by `fetch`).
+fn resolve_script_pubkey<F>(
+    scripthash: ScriptHash,
+    synced: Option<&Script>,
+    entries: &mut [UnspentEntry],
+    fetch: F,
+) -> Result<()>
+where
+    F: FnOnce(&Txid) -> Result<Transaction>,
+{
+    let utxo = match entries.first() {
+        Some(utxo) => utxo,
+        None => return Ok(()),
+    };
+    let script_pubkey = match synced {
+        Some(script_pubkey) => script_pubkey.to_owned(),
+        None => {
+            let tx = fetch(&utxo.tx_hash)?;
+            let outpoint = utxo.outpoint();
+            let txo = tx
+                .output
+                .get(outpoint.vout as usize)
+                .with_context(|| format!("missing output {}", outpoint))?;
+            ensure!(
+                ScriptHash::new(&txo.script_pubkey) == scripthash,
+                "output {} doesn't match scripthash {}",
+                outpoint,
+                scripthash
+            );
+            txo.script_pubkey.clone()
+        }
+    };
+    for utxo in entries {
+        utxo.script_pubkey = Some(script_pubkey.clone());
+    }
+    Ok(())
+}
+
+/// Resubscribing is allowed, but a new subscription fails if the client already has `limit` ones.
+fn check_subscriptions_limit(
+    subscribed: bool,
+    count: usize,
+    limit: usize,
+    kind: &str,
+) -> Result<()> {
+    if !subscribed && count >= limit {
+        let message = format!(
+            "too many {} subscriptions (max {}), unsubscribe some {}s",
+            kind, limit, kind
+        );
+        // not a memory budget, so the error has no `data`
+        return Err(CodedError::new(ErrorCode::SubscriptionLimit, message).into());
+    }
+    Ok(())
+}
+
+/// Validate a txid hex prefix (case-insensitive), returning it lowercased.
+fn parse_txid_prefix(prefix: &str) -> Result<String> {
+    ensure!(
+        prefix.len() >= MIN_TXID_PREFIX_LEN && prefix.len() <= 64,
+        "invalid txid prefix length: {} (expected {} to 64 hex digits)",
+        prefix.len(),
+        MIN_TXID_PREFIX_LEN
+    );
+    ensure!(
+        prefix.chars().all(|c| c.is_ascii_hexdigit()),
+        "invalid txid prefix: {}",
+        Sanitized(prefix)
+    );
+    Ok(prefix.to_ascii_lowercase())
+}
+
+/// Collect up to `limit` distinct candidates which are still confirmed at their height.
+/// The same txid may be confirmed at more than one height (see BIP-30), which is not ambiguous.
+fn select_prefix_matches(
+    candidates: impl Iterator<Item = (Txid, usize)>,
+    limit: usize,
+    mut is_confirmed: impl FnMut(Txid, usize) -> Result<bool>,
+) -> Result<SearchPrefixResult> {
+    let mut matches: Vec<PrefixMatch> = vec![];
+    for (tx_hash, height) in candidates {
+        let m = PrefixMatch { tx_hash, height };
+        if matches.contains(&m) || !is_confirmed(tx_hash, height)? {
+            continue;
+        }
+        matches.push(m);
+        if matches.len() > limit {
+            break;
+        }
+    }
+    let truncated = matches.len() > limit;
+    matches.truncate(limit);
+    let collision = truncated || matches.iter().any(|m| m.tx_hash != matches[0].tx_hash);
+    Ok(SearchPrefixResult {
+        matches,
+        truncated,
+        collision,
+    })
+}
+
+struct Call {
+    id: Option<Value>, // `None` for notifications
+    method: String,
+    params: Params,
+    admin_params: Option<Value>, // the raw params of admin methods (for the audit log)
+}
+
+impl Call {
+    /// Returns the error response if the params are invalid (or `None`, for notifications).
+    fn parse(request: Request, strict: bool) -> Result<Call, Option<Value>> {
+        let admin_params = if ADMIN_METHODS.contains(&request.method.as_str()) {
+            Some(request.params.clone())
+        } else {
+            None
+        };
+        let params = if strict {
+            Params::parse_strict(&request.method, request.params)
+        } else {
+            Params::parse(&request.method, request.params)
+        };
+        match params {
+            Ok(params) => Ok(Call {
+                id: request.id,
+                method: request.method,
+                params,
+                admin_params,
+            }),
+            Err(e) => match request.id {
+                Some(id) => Err(Some(error_msg(&id, RpcError::Standard(e)))),
+                None => {
+                    warn!("invalid {} notification: {}", request.method, e);
+                    Err(None)
+                }
+            },
+        }
+    }
+
+    /// Returns no response for notifications (but their errors are still logged).
+    fn response(&self, result: Result<Value>) -> Option<Value> {
+        match result {
+            Ok(value) => self.id.as_ref().map(|id| result_msg(id, value)),
+            Err(err) => self.error(self.rpc_error(err)),
+        }
+    }
+
+    fn error(&self, error: RpcError) -> Option<Value> {
+        self.id.as_ref().map(|id| error_msg(id, error))
+    }
+
+    fn rpc_error(&self, err: anyhow::Error) -> RpcError {
+        if cancel::is_cancelled(&err) {
+            debug!("RPC {} {:#}", self.method, err); // the response is not sent
+            return RpcError::BadRequest(err);
+        }
+        warn!("RPC {} failed: {:#}", self.method, err);
+        if daemon::is_unreachable(&err) {
+            return RpcError::UnavailableDaemon;
+        }
+        if let Some(coded) = errors::find_coded(&err) {
+            return RpcError::Coded(coded.clone());
+        }
+        match err
+            .downcast_ref::<bitcoincore_rpc::Error>()
+            .and_then(extract_bitcoind_error)
+        {
+            Some(e) => RpcError::DaemonError(e.clone()),
+            None => RpcError::BadRequest(err),
+        }
+    }
+}
+
+enum Calls {
+    Batch(Vec<Result<Call, Option<Value>>>),
+    Single(Result<Call, Option<Value>>),
+}
+
+impl Calls {
+    /// Batches of more than `batch_limit` calls are rejected (without parsing their params).
+    fn parse(requests: Requests, strict: bool, batch_limit: usize) -> Calls {
+        match requests {
+            Requests::Single(request) => Calls::Single(Call::parse(request, strict)),
+            Requests::Batch(batch) if batch.len() > batch_limit => {
+                let message = format!(
+                    "too many calls in batch: {} (max {})",
+                    batch.len(),
+                    batch_limit
+                );
+                let err = resource_limit_error(message, batch_limit);
+                Calls::Batch(
+                    batch
+                        .into_iter()
+                        .map(|request| {
+                            let response = |id: Value| error_msg(&id, RpcError::Coded(err.clone()));
+                            Err(request.id.map(response))
+                        })
+                        .collect::<Vec<_>>(),
+                )
+            }
+            Requests::Batch(batch) => Calls::Batch(
+                batch
+                    .into_iter()
+                    .map(|request| Call::parse(request, strict))
+                    .collect::<Vec<_>>(),
+            ),
+        }
+    }
+}
+
+fn resource_limit_error(message: String, limit: usize) -> CodedError {
+    CodedError::new(ErrorCode::ResourceLimit, message).with_data(json!({ "limit": limit }))
+}
+
+fn convert<T>(params: Value) -> std::result::Result<T, StandardError>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let params_str = params.to_string();
+    serde_json::from_value(params).map_err(|err| {
+        warn!("invalid params {}: {}", Sanitized(&params_str), err);
+        StandardError::InvalidParams
+    })
+}
+
+/// The merkle tree of the block hashes up to `cp_height` (whose root commits to the header chain)
+fn headers_tree(chain: &Chain, cp_height: usize) -> Result<Tree> {
+    ensure!(
+        cp_height <= chain.height(),
+        "cp_height {} is above the tip height {}",
+        cp_height,
+        chain.height()
+    );
+    let block_hashes: Vec<BlockHash> = (0..=cp_height)
+        .map(|height| chain.get_block_hash(height).expect("missing block hash"))
+        .collect();
+    Ok(Tree::from_block_hashes(&block_hashes))
+}
+
+/// The latest checkpoint's headers tree (clients usually query a fixed `cp_height`), keyed by the
+/// checkpoint's block hash (which commits to the previous ones, so a reorg invalidates the tree)
+#[derive(Default)]
+struct HeadersTreeCache {
+    latest: Mutex<Option<(BlockHash, Arc<Tree>)>>,
+}
+
+impl HeadersTreeCache {
+    fn get(&self, chain: &Chain, cp_height: usize) -> Result<Arc<Tree>> {
+        let cp_block_hash = chain.get_block_hash(cp_height);
+        {
+            let _order = lock_order::acquire(Level::HeadersTree);
+            if let Some((blockhash, tree)) = &*self.latest.lock() {
+                if Some(*blockhash) == cp_block_hash {
+                    return Ok(Arc::clone(tree));
+                }
+            }
+        }
+        // built without holding the lock (concurrent misses may build the same tree)
+        let tree = Arc::new(headers_tree(chain, cp_height)?);
+        if let Some(blockhash) = cp_block_hash {
+            let _order = lock_order::acquire(Level::HeadersTree);
+            *self.latest.lock() = Some((blockhash, Arc::clone(&tree)));
+        }
+        Ok(tree)
+    }
+}
+
+/// The block-related fields of verbose transactions, shared by the ones confirmed by the same block
+struct BlockContext {
+    blockhash: BlockHash,
+    height: usize,
+    confirmations: usize,
+    time: u32,
+}
+
+impl BlockContext {
+    fn new(chain: &Chain, blockhash: BlockHash) -> Option<Self> {
+        let height = chain.get_block_height(&blockhash)?;
+        let header = chain.get_block_header(height)?;
+        Some(Self {
+            blockhash,
+            height,
+            confirmations: chain.height() - height + 1,
+            time: header.time,
+        })
+    }
+
+    /// Override bitcoind's fields (which may reflect a newer tip than the index)
+    fn apply(&self, info: &mut Value) {
+        if let Value::Object(fields) = info {
+            fields.insert("blockhash".to_owned(), json!(self.blockhash));
+            fields.insert("confirmations".to_owned(), json!(self.confirmations));
+            fields.insert("time".to_owned(), json!(self.time));
+            fields.insert("blocktime".to_owned(), json!(self.time));
+        }
+    }
+}
+
+/// `blockchain.headers.subscribe` result (and notification) for the given header, optionally
+/// including the decoded header fields (named as by bitcoind's `getblockheader`). The header must
+/// be taken from the same chain snapshot as its height (e.g. via `Chain::tip_header()`).
+fn header_result(
+    chain: &Chain,
+    height: usize,
+    header: &Header,
+    decoded: bool,
+) -> HeadersSubscribeResult {
+    let decoded = if decoded {
+        Some(DecodedHeader {
+            version: header.version.to_consensus(),
+            previousblockhash: header.prev_blockhash,
+            merkleroot: header.merkle_root,
+            time: header.time,
+            mediantime: chain.get_median_time(height),
+            bits: format!("{:08x}", header.bits.to_consensus()),
+            nonce: header.nonce,
+        })
+    } else {
+        None
+    };
+    HeadersSubscribeResult {
+        hex: serialize_hex(header),
+        height,
+        decoded,
+    }
+}
+
+/// `blockchain.headers.subscribe` notifications after the tip has changed from `old_tip`: only the
+/// new tip's header, unless `old_tip` was reorged out - then all the headers above the fork point
+/// (if not too many), so clients can roll back to it before applying the new headers.
+fn headers_notifications(chain: &Chain, old_tip: BlockHash, decoded: bool) -> Vec<Value> {
+    let height = chain.height();
+    let first_height = match chain.fork_height(&old_tip) {
+        Some(fork_height)
+            if chain.get_block_hash(fork_height) != Some(old_tip)
+                && height - fork_height <= MAX_REORG_NOTIFIED_HEADERS =>
+        {
+            // the new tip may be the fork point itself (after a reorg to a shorter chain)
+            (fork_height + 1).min(height)
+        }
+        _ => height,
+    };
+    chain
+        .get_block_headers(first_height..height + 1)
+        .map(|(height, header)| {
+            notification(
+                "blockchain.headers.subscribe",
+                &[json!(header_result(chain, height, header, decoded))],
+            )
+        })
+        .collect()
+}
+
+/// `blockchain.transaction.get_merkle` result, optionally including the block header - which must
+/// match the proof (i.e. be taken from the same chain snapshot as the block's txids).
+fn merkle_result(
+    txid: Txid,
+    height: usize,
+    blockhash: BlockHash,
+    proof: &Proof,
+    header: Option<&Header>,
+) -> Result<MerkleResult> {
+    let mut result = MerkleResult {
+        block_height: height,
+        pos: proof.position(),
+        merkle: proof.to_hex(),
+        header_hex: None,
+    };
+    if let Some(header) = header {
+        ensure!(
+            header.block_hash() == blockhash && header.merkle_root == proof.root(txid),
+            "block {} at height {} was replaced during the proof's creation",
+            blockhash,
+            height
+        );
+        result.header_hex = Some(serialize_hex(header));
+    }
+    Ok(result)
+}
+
+/// The txid at `tx_pos` (which must be in the block), with its merkle proof if requested - using
+/// the `legacy` result shape if configured (see `legacy_id_from_pos`).
+fn id_from_pos_result(txids: &[Txid], tx_pos: usize, merkle: bool, legacy: bool) -> Value {
+    let tx_hash = txids[tx_pos];
+    let merkle = if merkle {
+        Some(Proof::create(txids, tx_pos).to_hex())
+    } else {
+        None
+    };
+    if legacy {
+        return json!(LegacyIdFromPosResult {
+            tx_id: tx_hash,
+            merkle,
+        });
+    }
+    match merkle {
+        Some(merkle) => json!(IdFromPosResult { tx_hash, merkle }),
+        None => json!(tx_hash),
+    }
+}
+
+fn notification(method: &str, params: &[Value]) -> Value {
+    json!({"jsonrpc": "2.0", "method": method, "params": params})
+}
+
+fn transaction_notification(txid: &Txid, status: Option<&WatchedTx>) -> Value {
+    notification(
+        "blockchain.transaction.subscribe",
+        &[json!(txid), json!(status.map(WatchedTx::height))],
+    )
+}
+
+/// https://electrum-protocol.readthedocs.io/en/latest/protocol-methods.html#blockchain-outpoint-subscribe
+fn outpoint_notification(outpoint: &OutPoint, status: &OutPointStatus) -> Value {
+    notification(
+        "blockchain.outpoint.subscribe",
+        &[
+            json!([outpoint.txid, outpoint.vout]),
+            json!(status.to_wire()),
+        ],
+    )
+}
+
+/// Scripthash status change notification, optionally with the history's delta since the previous
+/// one (`null` if it's too large to be notified).
+fn scripthash_notification(
+    scripthash: &ScriptHash,
+    status: &ScriptHashStatus,
+    with_delta: bool,
+) -> Value {
+    let mut params = vec![json!(scripthash), json!(status.statushash())];
+    if with_delta {
+        params.push(json!({ "delta": status.delta() }));
+    }
+    notification("blockchain.scripthash.subscribe", &params)
+}
+
+/// New blocks' txids (possibly in chunks), and the blocks orphaned by a reorg
+fn block_txids_notifications(events: &[BlockEvent]) -> Vec<Value> {
+    let mut result = vec![];
+    for event in events {
+        match event {
+            BlockEvent::Connected {
+                height,
+                hash,
+                txids,
+            } => {
+                let chunks = (txids.len() + MAX_NOTIFIED_TXIDS - 1) / MAX_NOTIFIED_TXIDS;
+                for (chunk, txids) in txids.chunks(MAX_NOTIFIED_TXIDS).enumerate() {
+                    result.push(notification(
+                        "blockchain.block.txids.subscribe",
+                        &[json!({
+                            "height": height,
+                            "hash": hash,
+                            "txids": txids,
+                            "chunk": chunk,
+                            "chunks": chunks,
+                        })],
+                    ));
+                }
+            }
+            BlockEvent::Disconnected { height, hash } => result.push(notification(
+                "blockchain.block.txids.subscribe",
+                &[json!({"height": height, "hash": hash, "orphaned": true})],
+            )),
+        }
+    }
+    result
+}
+
+// Responses are built as `Value`s, so their fields are sorted by name (regardless of being
+// constructed by `json!` or serialized from a struct), as long as serde_json's `preserve_order`
+// feature is disabled.
+fn result_msg(id: &Value, result: Value) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "result": result})
+}
+
+fn error_msg(id: &Value, error: RpcError) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "error": error.to_value()})
+}
+
+fn error_msg_no_id(err: StandardError) -> Value {
+    error_msg(&Value::Null, RpcError::Standard(err))
+}
+
+fn parse_requests(frame: &Frame) -> Result<Requests, StandardError> {
+    // parse RPC directly from JSON (or CBOR) frame
+    match frame.parse() {
+        Ok(requests) => Ok(requests),
+        Err(DecodeError::Unexpected(err)) => {
+            warn!("invalid RPC request ({}): {}", frame, err);
+            Err(StandardError::InvalidRequest)
+        }
+        Err(DecodeError::Malformed(err)) => {
+            warn!("{:#} ({})", err, frame);
+            Err(StandardError::ParseError)
+        }
+    }
+}
+
+/// Whether the line requests switching the connection's codec, so the following messages must be
+/// read only after handling it.
+pub(crate) fn is_codec_request(line: &str) -> bool {
+    if !line.contains(CODEC_METHOD) {
+        return false; // don't parse most requests twice
+    }
+    match serde_json::from_str(line) {
+        Ok(Requests::Single(request)) => request.method == CODEC_METHOD,
+        Ok(Requests::Batch(requests)) => requests.iter().any(|r| r.method == CODEC_METHOD),
+        Err(_) => false,
+    }
+}
+
+/// Select UTXOs for `target_value` from `utxos` (which should be sorted by value), returning
+/// their outpoints (so the selected UTXOs can be removed regardless of their positions).
+/// At most `options.max_inputs` UTXOs are selected, padded to `PADDED_INPUTS` if enabled.
+fn select_utxos(
+    utxos: &[UnspentEntry],
+    target_value: Amount,
+    options: &SelectOptions,
+) -> Vec<OutPoint> {
+    if !options.pad_inputs || options.max_inputs < PADDED_INPUTS {
+        return select_unpadded(utxos, target_value, options.max_inputs);
+    }
+    let mut choose_list = Vec::new();
+    let mut choose = |utxo: &UnspentEntry| {
+        let outpoint = utxo.outpoint();
+        if !choose_list.contains(&outpoint) {
+            choose_list.push(outpoint);
+        }
+    };
+    if utxos.len() <= PADDED_INPUTS {
+        utxos.iter().for_each(choose);
+    } else {
+        let utxo_len = utxos.len();
+        if let Some((index, _middle_utxo)) = utxos
+            .iter()
+            .enumerate()
+            .find(|utxo| utxo.1.value >= target_value)
+        {
+            let select_index = if index == 0 {
+                // first utxo is enough
+                vec![0, 1, 2]
+            } else if index == utxo_len - 1 {
+                // last utxo is enough
+                vec![0, utxo_len - 2, utxo_len - 1]
+            } else {
+                // find one middle utxo enough
+                if let Some((new_index, _new_utxo)) = utxos[index + 1..]
+                    .iter()
+                    .enumerate()
+                    .find(|utxo| utxo.1.height > 0)
+                {
+                    // find another confirmed big utxo
+                    vec![0, index, new_index + index + 1]
+                } else {
+                    // no confirmed bit utxo, select last one
+                    vec![0, index, utxo_len - 1]
+                }
+            };
+            for i in select_index {
+                choose(&utxos[i]);
+            }
+        } else {
+            let mut total_amount = Amount::from_sat(0);
+            let max_len = std::cmp::min(utxo_len, options.max_inputs);
+            let mut chosen = 0;
+            for utxo in utxos.iter().rev().take(max_len) {
+                total_amount += utxo.value;
+                choose(utxo);
+                chosen += 1;
+                if total_amount > target_value {
+                    break;
+                }
+            }
+            // push small utxo to make inputs length to 3
+            if chosen < PADDED_INPUTS {
+                choose(&utxos[0]);
+            }
+        };
+    }
+    choose_list
+}
+
+/// Select the smallest UTXO covering `target_value` (from `utxos`, sorted by value), or else the
+/// largest ones (up to `max_inputs`) until they cover it.
+fn select_unpadded(
+    utxos: &[UnspentEntry],
+    target_value: Amount,
+    max_inputs: usize,
+) -> Vec<OutPoint> {
+    if let Some(utxo) = utxos.iter().find(|utxo| utxo.value >= target_value) {
+        return vec![utxo.outpoint()];
+    }
+    let mut selected = vec![];
+    let mut total = Amount::from_sat(0);
+    for utxo in utxos.iter().rev().take(max_inputs) {
+        if total >= target_value {
+            break;
+        }
+        total += utxo.value;
+        selected.push(utxo.outpoint());
+    }
+    selected
+}
+
+/// Select UTXOs for `target_value` plus the fee of the selected inputs (at `options.fee_rate`, in
+/// sat/vB), by increasing the target until it covers the fee. A zero fee rate is the same as
+/// `select_utxos`.
+fn select_utxos_with_fee(
+    utxos: &[UnspentEntry],
+    target_value: Amount,
+    options: &SelectOptions,
+) -> Vec<OutPoint> {
+    let mut fee = Amount::from_sat(0);
+    let mut selected = select_utxos(utxos, target_value, options);
+    loop {
+        let required = input_fee(options.fee_rate, selected.len());
+        // the fee is non-decreasing and bounded by the fee for all the UTXOs
+        if required <= fee || selected.len() == utxos.len() {
+            return selected;
+        }
+        fee = required;
+        match target_value.checked_add(fee) {
+            Some(target) => selected = select_utxos(utxos, target, options),
+            None => return selected, // can't be funded anyway (see `ensure_funded()`)
+        }
+    }
+}
+
+/// Select the largest UTXOs (up to `max_inputs`) until they cover `target_value` plus their fee
+/// (at `fee_rate`).
+fn select_largest_first(
+    utxos: &[UnspentEntry],
+    target_value: Amount,
+    fee_rate: f64,
+    max_inputs: usize,
+) -> Vec<OutPoint> {
+    let mut selected = vec![];
+    let mut total = Amount::from_sat(0);
+    for utxo in utxos.iter().rev().take(max_inputs) {
+        // an overflowing target can't be covered (see `ensure_funded()`)
+        let required = target_value.checked_add(input_fee(fee_rate, selected.len()));
+        if required.map_or(false, |required| total >= required) {
+            break;
+        }
+        total += utxo.value;
+        selected.push(utxo.outpoint());
+    }
+    selected
+}
+
+/// Search for confirmed UTXOs whose total effective value (i.e. excluding their inputs' fee) is
+/// within `bnb_tolerance` above `target_value`, so no change output is needed. Returns the
+/// selection with the smallest excess found within `MAX_BNB_ITERATIONS` (if any).
+fn select_bnb(
+    utxos: &[UnspentEntry],
+    target_value: Amount,
+    options: &SelectOptions,
+) -> Option<Vec<OutPoint>> {
+    let fee = input_fee(options.fee_rate, 1).to_sat();
+    let mut candidates: Vec<(u64, OutPoint)> = utxos
+        .iter()
+        .filter(|utxo| utxo.height > 0 && utxo.value.to_sat() > fee)
+        .map(|utxo| (utxo.value.to_sat() - fee, utxo.outpoint()))
+        .collect();
+    // the largest first, to exceed the target (and prune the search) early
+    candidates.sort_by(|a, b| b.0.cmp(&a.0));
+    let mut remaining = vec![0; candidates.len() + 1]; // the total value of `candidates[i..]`
+    for i in (0..candidates.len()).rev() {
+        remaining[i] = remaining[i + 1] + candidates[i].0;
+    }
+    let target = target_value.to_sat();
+    let max_value = target.saturating_add(options.bnb_tolerance.to_sat());
+
+    let mut best: Option<(u64, Vec<usize>)> = None; // the smallest excess, and its selection
+    let mut included: Vec<usize> = vec![];
+    let (mut value, mut next) = (0, 0); // the value of `included`, and the next candidate
+    for _ in 0..MAX_BNB_ITERATIONS {
+        let can_include = included.len() < options.max_inputs;
+        if can_include && value < target && value + remaining[next] >= target {
+            // include the next candidate (the target can't be reached without more candidates)
+            included.push(next);
+            value += candidates[next].0;
+            next += 1;
+            continue;
+        }
+        if value >= target && value <= max_value {
+            let excess = value - target;
+            if best
+                .as_ref()
+                .map_or(true, |(best_excess, _)| excess < *best_excess)
+            {
+                best = Some((excess, included.clone()));
+            }
+            if excess == 0 {
+                break;
+            }
+        }
+        // backtrack: exclude the last included candidate, and continue with the next ones
+        match included.pop() {
+            Some(i) => {
+                value -= candidates[i].0;
+                next = i + 1;
+            }
+            None => break, // the search is exhausted
+        }
+    }
+    best.map(|(_, indices)| indices.into_iter().map(|i| candidates[i].1).collect())
+}
+
+/// Select UTXOs for `target_value` using the given strategy, returning the one actually used
+/// (since the branch-and-bound search falls back to the default strategy).
+fn select_amount(
+    utxos: &[UnspentEntry],
+    target_value: Amount,
+    options: &SelectOptions,
+) -> (Vec<OutPoint>, SelectStrategy) {
+    match options.strategy {
+        SelectStrategy::Default => (),
+        SelectStrategy::LargestFirst => {
+            let selected =
+                select_largest_first(utxos, target_value, options.fee_rate, options.max_inputs);
+            return (selected, SelectStrategy::LargestFirst);
+        }
+        SelectStrategy::Bnb => {
+            if let Some(selected) = select_bnb(utxos, target_value, options) {
+                return (selected, SelectStrategy::Bnb);
+            }
+        }
+    }
+    let selected = select_utxos_with_fee(utxos, target_value, options);
+    (selected, SelectStrategy::Default)
+}
+
+/// The order of `blockchain.scripthash.listunspent` results: confirmed before unconfirmed, then
+/// by ascending value, then by outpoint (so identical requests get identically ordered results).
+fn sort_unspent(utxos: &mut [UnspentEntry]) {
+    utxos.sort_unstable_by_key(|utxo| (utxo.height == 0, utxo.value, utxo.outpoint()));
+}
+
+/// The order of UTXO selection candidates: by ascending value, breaking ties by height and then by
+/// outpoint (so identical requests select the same UTXOs, in the same order).
+fn sort_by_value(utxos: &mut [UnspentEntry]) {
+    utxos.sort_unstable_by_key(|utxo| (utxo.value, utxo.height, utxo.outpoint()));
+}
+
+/// Drop the given outpoints' UTXOs (e.g. reserved by other selections).
+fn exclude_outpoints(utxos: &mut Vec<UnspentEntry>, excluded: &HashSet<OutPoint>) {
+    utxos.retain(|utxo| !excluded.contains(&utxo.outpoint()));
+}
+
+/// Whether the transaction has an unspent output (for the legacy form), the exact outpoint's
+/// unspent entry (or `null`), or the transaction's unspent output indices (sorted).
+fn unspent_exist_result(args: &UnspentExistArgs, unspent_entries: &[UnspentEntry]) -> Value {
+    let txid = args.txid();
+    let mut outputs = unspent_entries
+        .iter()
+        .filter(|unspent| &unspent.tx_hash == txid);
+    match args {
+        UnspentExistArgs::Txid(_) => json!(outputs.next().is_some()),
+        UnspentExistArgs::Outpoint(_, _, Some(vout)) => json!(outputs
+            .find(|unspent| unspent.outpoint().vout == *vout)
+            .map(UnspentEntry::to_wire)),
+        UnspentExistArgs::Outpoint(_, _, None) => {
+            let mut vouts: Vec<u32> = outputs.map(|unspent| unspent.outpoint().vout).collect();
+            vouts.sort_unstable();
+            json!(vouts)
+        }
+    }
+}
+
+/// Estimated fee for spending the given number of (P2WPKH) inputs at `fee_rate` (in sat/vB)
+fn input_fee(fee_rate: f64, inputs: usize) -> Amount {
+    let vsize = INPUT_VSIZE * inputs as u64;
+    Amount::from_sat((fee_rate * vsize as f64).ceil() as u64)
+}
+
+/// Validate the client-supplied `select_unspent` arguments, so the selection's arithmetic can't
+/// overflow.
+fn validate_select_args(args: &SelectUnspentArgs) -> Result<()> {
+    if let Some(fee_rate) = args.fee_rate() {
+        ensure!(
+            fee_rate.is_finite() && fee_rate >= 0.0 && fee_rate <= MAX_SELECT_FEE_RATE,
+            "invalid fee rate: {} (must be non-negative, up to {} sat/vB)",
+            fee_rate,
+            MAX_SELECT_FEE_RATE
+        );
+    }
+    if let Some(max_inputs) = args.max_inputs() {
+        ensure!(max_inputs > 0, "invalid max_inputs: {}", max_inputs);
+    }
+    // the other modes don't check the unconfirmed UTXOs' package limits
+    ensure!(
+        !args.ignore_package_limits() || args.mode() == SelectMode::PreferConfirmed,
+        "ignore_package_limits is only allowed in the prefer_confirmed mode"
+    );
+    let max_money = Amount::MAX_MONEY.to_sat();
+    if let Some(amount) = args.amounts().iter().find(|amount| **amount > max_money) {
+        bail!("invalid amount: {} sat (above {} sat)", amount, max_money);
+    }
+    Ok(())
+}
+
+/// `target_value` plus the inputs' `fee`, failing (instead of panicking) on overflow.
+fn with_fee(target_value: Amount, fee: Amount) -> Result<Amount> {
+    target_value.checked_add(fee).with_context(|| {
+        format!(
+            "amount overflow: {} sat (and {} sat of input fees)",
+            target_value.to_sat(),
+            fee.to_sat()
+        )
+    })
+}
+
+/// Fail if `target_value` (and the inputs' fee) can't be funded by the largest
+/// `options.max_inputs` UTXOs (or by all of them, reported as insufficient funds).
+fn ensure_within_max_inputs(
+    utxos: &[UnspentEntry],
+    target_value: Amount,
+    options: &SelectOptions,
+) -> Result<()> {
+    if utxos.len() <= options.max_inputs {
+        return Ok(());
+    }
+    ensure_funded(utxos, target_value, options.fee_rate)?;
+    let mut values: Vec<Amount> = utxos.iter().map(|utxo| utxo.value).collect();
+    values.sort_unstable_by(|a, b| b.cmp(a));
+    let largest: Amount = values.iter().take(options.max_inputs).copied().sum();
+    let fee = input_fee(options.fee_rate, options.max_inputs);
+    ensure!(
+        largest >= with_fee(target_value, fee)?,
+        "can't fund {} sat within {} inputs: the largest UTXOs total {} sat (with {} sat of input fees)",
+        target_value.to_sat(),
+        options.max_inputs,
+        largest.to_sat(),
+        fee.to_sat()
+    );
+    Ok(())
+}
+
+fn ensure_funded(selected: &[UnspentEntry], target_value: Amount, fee_rate: f64) -> Result<()> {
+    let total: Amount = selected.iter().map(|utxo| utxo.value).sum();
+    let fee = input_fee(fee_rate, selected.len());
+    if total < with_fee(target_value, fee)? {
+        if fee.to_sat() > 0 {
+            bail!(
+                "insufficient funds for {} sat (and {} sat of input fees): missing {} sat",
+                target_value.to_sat(),
+                fee.to_sat(),
+                (target_value + fee - total).to_sat()
+            );
+        }
+        bail!(
+            "insufficient funds for {} sat: missing {} sat",
+            target_value.to_sat(),
+            (target_value - total).to_sat()
+        );
+    }
+    Ok(())
+}
+
+/// Add the selected inputs' count and total value, their estimated vsize and fee (if a fee rate
+/// was given), and the strategy used for each amount (if a strategy was given) to the response.
+fn select_result(
+    choose_list: Vec<UnspentEntryWire>,
+    args: &SelectUnspentArgs,
+    strategies: &[SelectStrategy],
+) -> Value {
+    if args.fee_rate().is_none() && args.strategy().is_none() {
+        return json!(choose_list); // compatible with older clients
+    }
+    let inputs = choose_list.len();
+    let fee_rate = args.fee_rate();
+    json!(SelectUnspentResult {
+        inputs,
+        total_value: choose_list.iter().map(|utxo| utxo.value).sum(),
+        utxos: choose_list,
+        input_vsize: fee_rate.map(|_| INPUT_VSIZE * inputs as u64),
+        input_fee: fee_rate.map(|fee_rate| input_fee(fee_rate, inputs).to_sat()),
+        strategies: args.strategy().map(|_| strategies.to_vec()),
+    })
+}
+
+/// Remove the selected UTXOs from `utxos`, returning them (in their selection order).
+fn take_selected(utxos: &mut Vec<UnspentEntry>, selected: &[OutPoint]) -> Vec<UnspentEntry> {
+    let taken = selected
+        .iter()
+        .filter_map(|outpoint| utxos.iter().find(|utxo| utxo.outpoint() == *outpoint))
+        .cloned()
+        .collect();
+    utxos.retain(|utxo| !selected.contains(&utxo.outpoint()));
+    taken
+}
+
+/// Select UTXOs for each of the `amounts` in turn, so no UTXO is selected twice.
+/// `utxos` should be sorted by value. Fails if an amount (and its inputs' fee) can't be funded
+/// by the remaining UTXOs. Returns the strategy used for each amount.
+fn select_for_amounts(
+    mut utxos: Vec<UnspentEntry>,
+    amounts: &[u64],
+    options: &SelectOptions,
+) -> Result<(Vec<UnspentEntry>, Vec<SelectStrategy>)> {
+    let mut choose_list = Vec::new();
+    let mut strategies = Vec::new();
+    for target_amount in amounts {
+        let target_value = Amount::from_sat(*target_amount);
+        ensure_within_max_inputs(&utxos, target_value, options)?;
+        let (selected, strategy) = select_amount(&utxos, target_value, options);
+        let selected = take_selected(&mut utxos, &selected);
+        ensure_funded(&selected, target_value, options.fee_rate)?;
+        choose_list.extend(selected);
+        strategies.push(strategy);
+    }
+    Ok((choose_list, strategies))
+}
+
+/// Select UTXOs for `target_value`, using unconfirmed UTXOs only if the confirmed ones are
+/// insufficient. `confirmed` should be sorted by value, and `unconfirmed` by preference.
+/// The inputs' fee (at `options.fee_rate`, in sat/vB) is added to the target.
+/// Selected UTXOs are removed from the given lists. Returns the strategy used for the confirmed
+/// UTXOs (all of them are used if insufficient, as by the default strategy).
+fn select_prefer_confirmed(
+    confirmed: &mut Vec<UnspentEntry>,
+    unconfirmed: &mut Vec<UnspentEntry>,
+    target_value: Amount,
+    options: &SelectOptions,
+) -> Result<(Vec<UnspentEntry>, SelectStrategy)> {
+    let fee_rate = options.fee_rate;
+    if confirmed.len() + unconfirmed.len() > options.max_inputs {
+        let utxos: Vec<UnspentEntry> = confirmed
+            .iter()
+            .chain(unconfirmed.iter())
+            .cloned()
+            .collect();
+        ensure_within_max_inputs(&utxos, target_value, options)?;
+    }
+    let confirmed_total: Amount = confirmed.iter().map(|utxo| utxo.value).sum();
+    let (mut selected, strategy) =
+        if confirmed_total > with_fee(target_value, input_fee(fee_rate, confirmed.len()))? {
+            let (selected, strategy) = select_amount(confirmed, target_value, options);
+            (take_selected(confirmed, &selected), strategy)
+        } else {
+            // the largest ones (all of them, unless limited by `max_inputs`)
+            let skipped = confirmed.len().saturating_sub(options.max_inputs);
+            (confirmed.split_off(skipped), SelectStrategy::Default)
+        };
+    let mut total: Amount = selected.iter().map(|utxo| utxo.value).sum();
+    while total < with_fee(target_value, input_fee(fee_rate, selected.len()))? {
+        if unconfirmed.is_empty() || selected.len() >= options.max_inputs {
+            break;
+        }
+        let utxo = unconfirmed.remove(0);
+        total += utxo.value;
+        selected.push(utxo);
+    }
+    ensure_funded(&selected, target_value, fee_rate)?;
+    Ok((selected, strategy))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        block_txids_notifications, check_subscriptions_limit, error_msg, exclude_outpoints,
+        header_result, headers_notifications, headers_tree, id_from_pos_result, input_fee,
+        is_codec_request, merkle_result, multi_query_responses, multi_query_scripthashes,
+        notification, parse_requests, parse_txid_prefix, resolve_script_pubkey, result_msg,
+        scripthash_notification, select_bnb, select_for_amounts, select_largest_first,
+        select_prefer_confirmed, select_prefix_matches, select_result, signature, sort_by_value,
+        sort_unspent, subscribe_scripthashes, transactions_get_entries, unspent_exist_result,
+        validate_select_args, BlockContext, BroadcastKeys, Call, Calls, Client, DaemonHealth,
+        DaemonInfo, HeadersTreeCache, HealthFlags, HealthReport, IndexHealth, Params, PrefixMatch,
+        ProtocolVersion, Request, Requests, RpcError, SelectMode, SelectOptions, SelectStrategy,
+        SelectUnspentArgs, StandardError, Subscription, SubscriptionLimits, TransactionsGetEntry,
+        Version, ADMIN_METHODS, DAEMON_INFO_REFRESH_INTERVAL, MAX_BROADCAST_KEYS,
+        MAX_NOTIFIED_TXIDS, SIGNATURES,
+    };
+    use crate::cancel::CancelToken;
+    use crate::chain::{Chain, NewHeader};
+    use crate::clock::Clock;
+    use crate::codec::{Codec, Frame};
+    use crate::config::SyncServing;
+    use crate::errors::{ensure_enabled, find_coded, CodedError, ErrorCode};
+    use crate::index::BlockEvent;
+    use crate::merkle::Proof;
+    use crate::metrics::Metrics;
+    use crate::reservations::Reservations;
+    use crate::status::{Balance, ScriptHashStatus, UnspentEntry, WatchedTx};
+    use crate::types::ScriptHash;
+    use anyhow::Context;
+    use bitcoin::{
+        block::Header, consensus::deserialize, hash_types::TxMerkleNode, hashes::Hash, Amount,
+        BlockHash, Network, OutPoint, Txid,
+    };
+    use serde_json::{json, Value};
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    const TXID: &str = "5b75086dafeede555fc8f9a810d8b10df57c46f9f176ccc3dd8d2fa20edd685b";
+    const SCRIPTHASH: &str = "4b3d912c1523ece4615e91bf0d27381ca72169dbf6b1c2ffcc9f92381d4984a3";
+    const RAW_TX: &str = "0200000000000000";
+
+    fn substitute(template: &Value, hex: &str) -> Value {
+        match template {
+            Value::String(s) if s == "HEX" => json!(hex),
+            Value::Array(values) => {
+                Value::Array(values.iter().map(|v| substitute(v, hex)).collect())
+            }
+            other => other.clone(),
+        }
+    }
+
+    fn mixed_case(hex: &str) -> String {
+        hex.chars()
             .enumerate()
-            .find(|utxo| utxo.1.value >= target_value)
-        {
-            let select_index = if index == 0 {
-                // first utxo is enough
-                vec![0, 1, 2]
-            } else if index == utxo_len - 1 {
-                // last utxo is enough
-                vec![0, utxo_len - 2, utxo_len - 1]
-            } else {
-                // find one middle utxo enough
-                if let Some((new_index, _new_utxo)) = utxos[index + 1..]
-                    .iter()
-                    .enumerate()
-                    .find(|utxo| utxo.1.height > 0)
-                {
-                    // find another confirmed big utxo
-                    vec![0, index, new_index + index + 1]
+            .map(|(i, c)| {
+                if i % 2 == 0 {
+                    c.to_ascii_uppercase()
                 } else {
-                    // no confirmed bit utxo, select last one
-                    vec![0, index, utxo_len - 1]
+                    c
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_hex_params() {
+        let cases = vec![
+            (
+                "scripthash",
+                SCRIPTHASH,
+                vec![
+                    ("blockchain.scripthash.get_balance", json!(["HEX"])),
+                    ("blockchain.scripthash.get_balance", json!(["HEX", true])),
+                    ("blockchain.scripthash.get_history", json!(["HEX"])),
+                    ("blockchain.scripthash.get_history", json!(["HEX", true])),
+                    (
+                        "blockchain.scripthash.get_history",
+                        json!(["HEX", false, true]),
+                    ),
+                    (
+                        "blockchain.scripthash.get_history_filter",
+                        json!(["HEX", null, 10]),
+                    ),
+                    (
+                        "blockchain.scripthash.get_history_filter",
+                        json!(["HEX", 1, null, true]),
+                    ),
+                    (
+                        "blockchain.scripthash.get_history_filter",
+                        json!(["HEX", 1, 2, false, true]),
+                    ),
+                    ("blockchain.scripthash.get_mempool", json!(["HEX"])),
+                    ("blockchain.scripthash.get_orphaned", json!(["HEX"])),
+                    ("blockchain.scripthash.listunspent", json!(["HEX"])),
+                    (
+                        "blockchain.scripthash.select_unspent",
+                        json!(["HEX", [1000], 0, false]),
+                    ),
+                    (
+                        "blockchain.scripthash.release_unspent",
+                        json!(["HEX", [[TXID, 0]]]),
+                    ),
+                    ("blockchain.scripthash.unspent_exist", json!(["HEX", TXID])),
+                    (
+                        "blockchain.scripthash.unspent_exist",
+                        json!(["HEX", TXID, 1]),
+                    ),
+                    ("blockchain.scripthash.subscribe", json!(["HEX"])),
+                    ("blockchain.scripthash.unsubscribe", json!(["HEX"])),
+                ],
+            ),
+            (
+                "txid",
+                TXID,
+                vec![
+                    (
+                        "blockchain.scripthash.release_unspent",
+                        json!([SCRIPTHASH, [["HEX", 0]]]),
+                    ),
+                    (
+                        "blockchain.scripthash.unspent_exist",
+                        json!([SCRIPTHASH, "HEX"]),
+                    ),
+                    (
+                        "blockchain.scripthash.unspent_exist",
+                        json!([SCRIPTHASH, "HEX", null]),
+                    ),
+                    ("blockchain.transaction.build_rbf", json!(["HEX", 2.5])),
+                    ("blockchain.transaction.build_rbf", json!(["HEX", 2.5, 1])),
+                    ("blockchain.transaction.get", json!(["HEX"])),
+                    ("blockchain.transaction.get", json!(["HEX", true])),
+                    ("blockchain.transaction.get_merkle", json!(["HEX", 123])),
+                    (
+                        "blockchain.transaction.get_merkle",
+                        json!(["HEX", 123, true]),
+                    ),
+                    ("blockchain.transaction.mempool_info", json!(["HEX"])),
+                    ("proofs.export", json!([[["HEX", 1], [TXID, 2]], 10])),
+                    ("blockchain.transactions.get", json!([[TXID, "HEX"]])),
+                    ("blockchain.transactions.get", json!([["HEX"], true])),
+                ],
+            ),
+            (
+                "raw_tx",
+                RAW_TX,
+                vec![
+                    ("blockchain.transaction.broadcast", json!(["HEX"])),
+                    ("blockchain.transaction.broadcast", json!(["HEX", "key"])),
+                ],
+            ),
+            (
+                "block_hash",
+                TXID,
+                vec![("blockchain.block.header_by_hash", json!(["HEX"]))],
+            ),
+        ];
+        for (name, hex, templates) in cases {
+            let valid = vec![hex.to_owned(), hex.to_uppercase(), mixed_case(hex)];
+            let mut invalid = vec![
+                (
+                    hex[1..].to_owned(),
+                    format!("odd-length hex string ({} characters)", hex.len() - 1),
+                ),
+                (
+                    format!("g{}", &hex[1..]),
+                    "non-hex character at position 0".to_owned(),
+                ),
+                (
+                    format!(" {}", &hex[1..]),
+                    "non-hex character at position 0".to_owned(),
+                ),
+            ];
+            if hex.len() == 64 {
+                let reason = format!("expected 64 hex characters, got {}", hex.len() + 2);
+                invalid.push((format!("{}00", hex), reason)); // wrong hash length
+            }
+            for (method, template) in templates {
+                for value in &valid {
+                    let params = substitute(&template, value);
+                    assert!(
+                        Params::parse(method, params).is_ok(),
+                        "{} {}",
+                        method,
+                        value
+                    );
+                }
+                for (value, reason) in &invalid {
+                    let params = substitute(&template, value);
+                    match Params::parse(method, params) {
+                        Err(StandardError::InvalidHex(message)) => {
+                            assert_eq!(
+                                message,
+                                format!("invalid {}: {}", name, reason),
+                                "{}",
+                                method
+                            )
+                        }
+                        _ => panic!("{} {} should be rejected", method, value),
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_unspent_order() {
+        let txid = |n: u8| Txid::from_byte_array([n; 32]);
+        let utxo = |height, n, vout, value| {
+            UnspentEntry::new(height, txid(n), vout, Amount::from_sat(value))
+        };
+        let utxos = vec![
+            utxo(0, 1, 0, 500),
+            utxo(100, 2, 1, 1000),
+            utxo(0, 3, 0, 200),
+            utxo(100, 2, 0, 1000),
+            utxo(90, 4, 0, 1000),
+            utxo(100, 1, 0, 1000),
+        ];
+        let summary = |utxos: &[UnspentEntry]| {
+            utxos
+                .iter()
+                .map(|utxo| (utxo.height, utxo.outpoint(), utxo.value.to_sat()))
+                .collect::<Vec<_>>()
+        };
+        let outpoint = |n, vout| OutPoint::new(txid(n), vout);
+
+        // identical regardless of the snapshot's order
+        let mut reversed = utxos.clone();
+        reversed.reverse();
+        for mut entries in vec![utxos.clone(), reversed] {
+            sort_unspent(&mut entries);
+            assert_eq!(
+                summary(&entries),
+                vec![
+                    (100, outpoint(1, 0), 1000),
+                    (100, outpoint(2, 0), 1000),
+                    (100, outpoint(2, 1), 1000),
+                    (90, outpoint(4, 0), 1000),
+                    (0, outpoint(3, 0), 200),
+                    (0, outpoint(1, 0), 500),
+                ]
+            );
+        }
+        let mut entries = utxos;
+        sort_by_value(&mut entries);
+        assert_eq!(
+            summary(&entries),
+            vec![
+                (0, outpoint(3, 0), 200),
+                (0, outpoint(1, 0), 500),
+                (90, outpoint(4, 0), 1000),
+                (100, outpoint(1, 0), 1000),
+                (100, outpoint(2, 0), 1000),
+                (100, outpoint(2, 1), 1000),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unspent_exist() {
+        let txid: Txid = TXID.parse().unwrap();
+        let other = Txid::from_byte_array([1; 32]);
+        // `txid` pays the scripthash twice, and its first output is already spent
+        let unspent = vec![
+            UnspentEntry::new(100, other, 0, Amount::from_sat(1000)),
+            UnspentEntry::new(100, txid, 1, Amount::from_sat(2000)),
+        ];
+        let result =
+            |params: Value| match Params::parse("blockchain.scripthash.unspent_exist", params) {
+                Ok(Params::ScriptHashUnspentExist(args)) => unspent_exist_result(&args, &unspent),
+                _ => panic!("failed to parse"),
+            };
+        assert_eq!(result(json!([SCRIPTHASH, TXID])), json!(true)); // backward compatible
+        assert_eq!(result(json!([SCRIPTHASH, TXID, 0])), Value::Null);
+        assert_eq!(
+            result(json!([SCRIPTHASH, TXID, 1])),
+            json!({"height": 100, "tx_hash": TXID, "tx_pos": 1, "value": 2000})
+        );
+        assert_eq!(result(json!([SCRIPTHASH, TXID, 2])), Value::Null);
+        assert_eq!(result(json!([SCRIPTHASH, TXID, null])), json!([1]));
+
+        let spent = Txid::from_byte_array([2; 32]).to_string();
+        assert_eq!(result(json!([SCRIPTHASH, spent])), json!(false));
+        assert_eq!(result(json!([SCRIPTHASH, spent, null])), json!([]));
+    }
+
+    #[test]
+    fn test_verbose_params() {
+        for (params, verbose) in vec![
+            (json!([SCRIPTHASH]), false),
+            (json!([SCRIPTHASH, false]), false),
+            (json!([SCRIPTHASH, true]), true),
+        ] {
+            match Params::parse("blockchain.scripthash.get_balance", params) {
+                Ok(Params::ScriptHashGetBalance(args)) => {
+                    let (_scripthash, value): (ScriptHash, bool) = (&args).into();
+                    assert_eq!(value, verbose);
+                }
+                _ => panic!("failed to parse"),
+            }
+        }
+        for (params, reverse, verbose) in vec![
+            (json!([SCRIPTHASH, 1, null]), false, false),
+            (json!([SCRIPTHASH, 1, null, true]), true, false),
+            (json!([SCRIPTHASH, 1, null, false, true]), false, true),
+            (json!([SCRIPTHASH, 1, null, "desc"]), true, false),
+            (json!([SCRIPTHASH, 1, null, "asc", true]), false, true),
+        ] {
+            match Params::parse("blockchain.scripthash.get_history_filter", params) {
+                Ok(Params::ScriptHashGetHistoryFilter(args)) => {
+                    assert_eq!(args.range(), (Some(1), None));
+                    assert_eq!(args.reverse(), reverse);
+                    assert_eq!(args.verbose(), verbose);
+                }
+                _ => panic!("failed to parse"),
+            }
+        }
+        let params = json!([SCRIPTHASH, 1, null, "newest"]);
+        assert!(Params::parse("blockchain.scripthash.get_history_filter", params).is_err());
+    }
+
+    fn utxo(height: usize, n: u8, value: u64) -> UnspentEntry {
+        let txid = Txid::from_byte_array([n; 32]);
+        UnspentEntry::new(height, txid, 0, Amount::from_sat(value))
+    }
+
+    fn values(utxos: &[UnspentEntry]) -> Vec<(usize, u64)> {
+        utxos
+            .iter()
+            .map(|utxo| (utxo.height, utxo.value.to_sat()))
+            .collect()
+    }
+
+    #[test]
+    fn test_decoded_header() {
+        for (params, decoded) in vec![
+            (json!([]), false),
+            (Value::Null, false),
+            (json!([false]), false),
+            (json!([true]), true),
+        ] {
+            match Params::parse("blockchain.headers.subscribe", params) {
+                Ok(Params::HeadersSubscribe(value)) => assert_eq!(value, decoded),
+                _ => panic!("failed to parse"),
+            }
+        }
+
+        let chain = Chain::new(Network::Regtest);
+        let (height, _, header) = chain.tip_header();
+        let result = json!(header_result(&chain, height, &header, false));
+        assert_eq!(result.as_object().unwrap().len(), 2); // the same as without the flag
+        assert_eq!(result["height"], json!(0));
+
+        let result = json!(header_result(&chain, height, &header, true));
+        assert_eq!(
+            result["hex"],
+            json!(header_result(&chain, height, &header, false).hex)
+        );
+        assert_eq!(
+            result["decoded"],
+            json!({
+                "version": 1,
+                "previousblockhash": BlockHash::all_zeros(),
+                "merkleroot": "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+                "time": 1296688602,
+                "mediantime": 1296688602,
+                "bits": "207fffff",
+                "nonce": 2,
+            })
+        );
+    }
+
+    #[test]
+    fn test_header_checkpoint() {
+        for (params, expected) in vec![(json!([5]), (5, 0)), (json!([5, 10]), (5, 10))] {
+            match Params::parse("blockchain.block.header", params) {
+                Ok(Params::BlockHeader(args)) => {
+                    assert_eq!(<(usize, usize)>::from(&args), expected)
+                }
+                _ => panic!("failed to parse"),
+            }
+        }
+        for (params, expected) in vec![
+            (json!([1, 10]), (1, 10, 0)),
+            (json!([1, 10, 20]), (1, 10, 20)),
+        ] {
+            match Params::parse("blockchain.block.headers", params) {
+                Ok(Params::BlockHeaders(args)) => {
+                    assert_eq!(<(usize, usize, usize)>::from(&args), expected)
+                }
+                _ => panic!("failed to parse"),
+            }
+        }
+
+        let new_chain = |nonce| {
+            let mut chain = Chain::new(Network::Regtest);
+            let mut header = *chain.get_block_header(0).unwrap();
+            let new_headers: Vec<NewHeader> = (1..=5)
+                .map(|height| {
+                    header.prev_blockhash = header.block_hash();
+                    header.nonce = nonce;
+                    NewHeader::from((header, height))
+                })
+                .collect();
+            chain.update(new_headers);
+            chain
+        };
+        let chain = new_chain(0);
+
+        let cp_height = 4;
+        let tree = headers_tree(&chain, cp_height).unwrap();
+        for height in 0..=cp_height {
+            // fold the branch into the root (as done by Electrum clients)
+            let mut node =
+                TxMerkleNode::from_raw_hash(chain.get_block_hash(height).unwrap().to_raw_hash());
+            let mut position = height;
+            for sibling in tree.branch(height) {
+                let pair = if position % 2 == 0 {
+                    [node, sibling]
+                } else {
+                    [sibling, node]
+                };
+                node = TxMerkleNode::hash(&[&pair[0][..], &pair[1][..]].concat());
+                position /= 2;
+            }
+            assert_eq!(node, tree.root());
+        }
+        assert!(headers_tree(&chain, 5).is_ok());
+        assert!(headers_tree(&chain, 6).is_err()); // above the tip
+
+        let cache = HeadersTreeCache::default();
+        let cached = cache.get(&chain, cp_height).unwrap();
+        assert_eq!(cached.root(), tree.root());
+        assert!(Arc::ptr_eq(&cached, &cache.get(&chain, cp_height).unwrap()));
+        assert!(!Arc::ptr_eq(&cached, &cache.get(&chain, 3).unwrap()));
+        assert!(cache.get(&chain, 6).is_err());
+
+        // the checkpoint was reorged
+        let reorged = cache.get(&new_chain(1), cp_height).unwrap();
+        assert_ne!(reorged.root(), tree.root());
+    }
+
+    #[test]
+    fn test_reorg_headers_notifications() {
+        let mut chain = Chain::new(Network::Regtest);
+        let genesis = *chain.get_block_header(0).unwrap();
+        let new_headers = |prev_blockhash, heights: std::ops::RangeInclusive<usize>, nonce| {
+            let mut header = genesis;
+            header.nonce = nonce;
+            header.prev_blockhash = prev_blockhash;
+            heights
+                .map(|height| {
+                    let new_header = NewHeader::from((header, height));
+                    header.prev_blockhash = new_header.hash();
+                    new_header
+                })
+                .collect::<Vec<_>>()
+        };
+        let notified_heights = |chain: &Chain, old_tip| -> Vec<Value> {
+            headers_notifications(chain, old_tip, false)
+                .iter()
+                .map(|notification| notification["params"][0]["height"].clone())
+                .collect()
+        };
+        chain.update(new_headers(chain.tip(), 1..=4, 0));
+        let old_tip = chain.get_block_hash(3).unwrap();
+        assert_eq!(notified_heights(&chain, old_tip), vec![json!(4)]); // a new block
+
+        // a 2-block reorg: the headers are notified from the fork point
+        let old_tip = chain.tip();
+        let fork = chain.get_block_hash(2).unwrap();
+        chain.update(new_headers(fork, 3..=4, 1));
+        assert_eq!(notified_heights(&chain, old_tip), vec![json!(3), json!(4)]);
+
+        // a reorg to a shorter chain: its new tip is notified
+        let old_tip = chain.tip();
+        chain.update(new_headers(fork, 3..=3, 2));
+        assert_eq!(notified_heights(&chain, old_tip), vec![json!(3)]);
+
+        // an unknown old tip: only the new tip is notified
+        let old_tip = BlockHash::all_zeros();
+        assert_eq!(notified_heights(&chain, old_tip), vec![json!(3)]);
+    }
+
+    #[test]
+    fn test_merkle_header() {
+        match Params::parse("blockchain.transaction.get_merkle", json!([TXID, 1, true])) {
+            Ok(Params::TransactionGetMerkle(args)) => {
+                let (_txid, height, with_header): (Txid, usize, bool) = (&args).into();
+                assert_eq!((height, with_header), (1, true));
+            }
+            _ => panic!("failed to parse"),
+        }
+
+        let txids: Vec<Txid> = (1..=5).map(|i| Txid::from_byte_array([i; 32])).collect();
+        let chain = Chain::new(Network::Regtest);
+        let mut header = *chain.get_block_header(0).unwrap();
+        header.merkle_root = Proof::create(&txids, 0).root(txids[0]);
+        let blockhash = header.block_hash();
+        let proof = Proof::create(&txids, 3);
+
+        let result = json!(merkle_result(txids[3], 1, blockhash, &proof, None).unwrap());
+        assert_eq!(
+            result.as_object().unwrap().len(),
+            3 // the same as without the flag
+        );
+        let result = json!(merkle_result(txids[3], 1, blockhash, &proof, Some(&header)).unwrap());
+        assert_eq!(result["pos"], json!(3));
+        assert_eq!(result["merkle"], json!(proof.to_hex()));
+        let header_bytes = crate::hex::decode(result["header_hex"].as_str().unwrap()).unwrap();
+        let bundled: Header = deserialize(&header_bytes).unwrap();
+        assert_eq!(bundled.merkle_root, proof.root(txids[3])); // the proof can be verified
+
+        // the header must match the proof's block
+        let stale = chain.get_block_header(0).unwrap();
+        assert!(merkle_result(txids[3], 1, blockhash, &proof, Some(stale)).is_err());
+        assert!(merkle_result(txids[3], 1, chain.tip(), &proof, Some(&header)).is_err());
+    }
+
+    #[test]
+    fn test_id_from_pos_result() {
+        let txids: Vec<Txid> = (1..=5).map(|i| Txid::from_byte_array([i; 32])).collect();
+        let proof = Proof::create(&txids, 3);
+
+        // the bare txid, unless a merkle proof is requested
+        let result = id_from_pos_result(&txids, 3, false, false);
+        assert_eq!(result, json!(txids[3]));
+
+        // decoded as by Electrum's SPV verifier
+        let result = id_from_pos_result(&txids, 3, true, false);
+        assert_eq!(result.as_object().unwrap().len(), 2);
+        let tx_hash: Txid = serde_json::from_value(result["tx_hash"].clone()).unwrap();
+        let merkle: Vec<String> = serde_json::from_value(result["merkle"].clone()).unwrap();
+        assert_eq!(tx_hash, txids[3]);
+        assert_eq!(merkle, proof.to_hex());
+
+        // the previous shape, if configured
+        let result = id_from_pos_result(&txids, 3, false, true);
+        assert_eq!(result, json!({ "tx_id": txids[3] }));
+        let result = id_from_pos_result(&txids, 3, true, true);
+        assert_eq!(result, json!({"tx_id": txids[3], "merkle": proof.to_hex()}));
+    }
+
+    #[test]
+    fn test_select_unspent_mode() {
+        let cases = vec![
+            (json!([SCRIPTHASH, [1000], 0, false]), SelectMode::Any),
+            (json!([SCRIPTHASH, [1000], 0, true]), SelectMode::Confirmed),
+            (json!([SCRIPTHASH, [1000], 0, "any"]), SelectMode::Any),
+            (
+                json!([SCRIPTHASH, [1000], 0, "prefer_confirmed"]),
+                SelectMode::PreferConfirmed,
+            ),
+            (
+                json!([SCRIPTHASH, [1000], 0, true, "prefer_confirmed"]),
+                SelectMode::PreferConfirmed,
+            ),
+            (
+                json!([SCRIPTHASH, [1000], 0, true, "prefer_confirmed", true]),
+                SelectMode::PreferConfirmed,
+            ),
+            (
+                json!([SCRIPTHASH, [1000], 0, true, "confirmed", true, true]),
+                SelectMode::Confirmed,
+            ),
+            (
+                json!([SCRIPTHASH, [1000], 0, true, "any", true, true, 2.5]),
+                SelectMode::Any,
+            ),
+            (
+                json!([SCRIPTHASH, [1000], 0, true, "any", true, true, 2.5, "bnb"]),
+                SelectMode::Any,
+            ),
+            (
+                json!([
+                    SCRIPTHASH,
+                    [1000],
+                    0,
+                    true,
+                    "any",
+                    true,
+                    true,
+                    2.5,
+                    "bnb",
+                    5
+                ]),
+                SelectMode::Any,
+            ),
+        ];
+        for (params, mode) in cases {
+            match Params::parse("blockchain.scripthash.select_unspent", params.clone()) {
+                Ok(Params::ScriptHashSelectUnspent(args)) => {
+                    assert_eq!(args.mode(), mode, "{}", params);
+                    let len = params.as_array().unwrap().len();
+                    assert_eq!(args.ignore_package_limits(), len >= 6);
+                    assert_eq!(args.include_script_pubkey(), len >= 7);
+                    assert_eq!(args.fee_rate(), if len >= 8 { Some(2.5) } else { None });
+                    let strategy = if len >= 9 {
+                        Some(SelectStrategy::Bnb)
+                    } else {
+                        None
+                    };
+                    assert_eq!(args.strategy(), strategy);
+                    assert_eq!(args.max_inputs(), if len == 10 { Some(5) } else { None });
+                }
+                _ => panic!("failed to parse {}", params),
+            }
+        }
+        let invalid = json!([SCRIPTHASH, [1000], 0, "unconfirmed"]);
+        assert!(Params::parse("blockchain.scripthash.select_unspent", invalid).is_err());
+        let invalid = json!([SCRIPTHASH, [1000], 0, true, "any", true, true, 2.5, "exact"]);
+        assert!(Params::parse("blockchain.scripthash.select_unspent", invalid).is_err());
+    }
+
+    #[test]
+    fn test_select_options() {
+        let parse =
+            |params: Value| match Params::parse("blockchain.scripthash.select_unspent", params) {
+                Ok(Params::ScriptHashSelectUnspent(args)) => args,
+                _ => panic!("invalid select_unspent params"),
+            };
+        let options =
+            json!({"mode": "prefer_confirmed", "max_inputs": 5, "ignore_package_limits": true});
+        let args = parse(json!([SCRIPTHASH, [1000], 0, false, options]));
+        assert_eq!(args.mode(), SelectMode::PreferConfirmed);
+        assert_eq!(args.max_inputs(), Some(5));
+        assert!(args.ignore_package_limits() && !args.include_script_pubkey());
+        assert_eq!((args.fee_rate(), args.strategy()), (None, None));
+        assert!(validate_select_args(&args).is_ok());
+
+        let options = json!({"fee_rate": 2.5, "strategy": "bnb", "include_script_pubkey": true});
+        let args = parse(json!([SCRIPTHASH, [1000], 0, true, options]));
+        assert_eq!(args.mode(), SelectMode::Confirmed);
+        assert_eq!(args.fee_rate(), Some(2.5));
+        assert_eq!(args.strategy(), Some(SelectStrategy::Bnb));
+        assert!(args.include_script_pubkey() && !args.ignore_package_limits());
+        assert_eq!(args.max_inputs(), None);
+
+        // omitted options are the same as omitted positional parameters
+        let args = parse(json!([SCRIPTHASH, [1000], 0, false, {}]));
+        assert_eq!(args.mode(), SelectMode::Any);
+        assert_eq!(
+            (args.fee_rate(), args.strategy(), args.max_inputs()),
+            (None, None, None)
+        );
+
+        let unknown = json!([SCRIPTHASH, [1000], 0, true, {"confirmed": true}]);
+        assert!(Params::parse("blockchain.scripthash.select_unspent", unknown).is_err());
+
+        // package limits are only checked (so may only be ignored) when preferring confirmed UTXOs
+        for params in vec![
+            json!([SCRIPTHASH, [1000], 0, true, {"ignore_package_limits": true}]),
+            json!([SCRIPTHASH, [1000], 0, false, {"mode": "any", "ignore_package_limits": true}]),
+            json!([SCRIPTHASH, [1000], 0, true, null, true]),
+        ] {
+            let err = validate_select_args(&parse(params)).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "ignore_package_limits is only allowed in the prefer_confirmed mode"
+            );
+        }
+    }
+
+    #[test]
+    fn test_list_unspent_args() {
+        let cases = vec![
+            (json!([SCRIPTHASH]), (false, false, false)),
+            (json!([SCRIPTHASH, false]), (false, false, false)),
+            (json!([SCRIPTHASH, true]), (true, false, false)),
+            (json!([SCRIPTHASH, false, true]), (false, true, false)),
+            (json!([SCRIPTHASH, true, true]), (true, true, false)),
+            (
+                json!([SCRIPTHASH, false, false, true]),
+                (false, false, true),
+            ),
+        ];
+        for (params, expected) in cases {
+            match Params::parse("blockchain.scripthash.listunspent", params.clone()) {
+                Ok(Params::ScriptHashListUnspent(args)) => {
+                    let (_scripthash, include_mempool_spent, include_script_pubkey, safe_only): (
+                        ScriptHash,
+                        bool,
+                        bool,
+                        bool,
+                    ) = (&args).into();
+                    let include = (include_mempool_spent, include_script_pubkey, safe_only);
+                    assert_eq!(include, expected, "{}", params)
                 }
+                _ => panic!("failed to parse {}", params),
+            }
+        }
+        let invalid = json!([SCRIPTHASH, "true"]);
+        assert!(Params::parse("blockchain.scripthash.listunspent", invalid).is_err());
+    }
+
+    #[test]
+    fn test_script_pubkey_psbt() {
+        use bitcoin::{
+            absolute::LockTime, psbt::Psbt, OutPoint, ScriptBuf, Sequence, Transaction, TxIn,
+            TxOut, WPubkeyHash, Witness,
+        };
+
+        let script_pubkey = ScriptBuf::new_v0_p2wpkh(&WPubkeyHash::from_byte_array([7; 20]));
+        let funding = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output: vec![
+                TxOut {
+                    value: 1000,
+                    script_pubkey: ScriptBuf::new(),
+                },
+                TxOut {
+                    value: 5000,
+                    script_pubkey: script_pubkey.clone(),
+                },
+            ],
+        };
+        let scripthash = ScriptHash::new(&script_pubkey);
+        let utxo = UnspentEntry::new(100, funding.txid(), 1, Amount::from_sat(5000));
+        // not included by default
+        assert!(json!(utxo.to_wire()).get("script_pubkey").is_none());
+
+        // resolved from the funding transaction (if the status sync couldn't)
+        let mut entries = vec![utxo];
+        let mut fetched = vec![];
+        resolve_script_pubkey(scripthash, None, &mut entries, |txid| {
+            fetched.push(*txid);
+            Ok(funding.clone())
+        })
+        .unwrap();
+        assert_eq!(fetched, vec![funding.txid()]);
+        let response = json!(entries[0].to_wire());
+
+        // build a PSBT input from the response (without fetching the funding transaction)
+        let outpoint = OutPoint::new(
+            response["tx_hash"].as_str().unwrap().parse().unwrap(),
+            response["tx_pos"].as_u64().unwrap() as u32,
+        );
+        let spending = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: outpoint,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: 4000,
+                script_pubkey: ScriptBuf::new(),
+            }],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(spending).unwrap();
+        let script_hex = response["script_pubkey"].as_str().unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: response["value"].as_u64().unwrap(),
+            script_pubkey: ScriptBuf::from_hex(script_hex).unwrap(),
+        });
+        let psbt = Psbt::deserialize(&psbt.serialize()).unwrap();
+
+        // verify it against the funding transaction
+        let prevout = psbt.unsigned_tx.input[0].previous_output;
+        assert_eq!(prevout.txid, funding.txid());
+        assert_eq!(
+            psbt.inputs[0].witness_utxo.as_ref(),
+            Some(&funding.output[prevout.vout as usize])
+        );
+    }
+
+    #[test]
+    fn test_resolve_script_pubkey() {
+        use bitcoin::{absolute::LockTime, ScriptBuf, Transaction, TxOut};
+
+        let script_pubkey = ScriptBuf::from(vec![0x51]);
+        let scripthash = ScriptHash::new(&script_pubkey);
+        let funding = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: ScriptBuf::new(), // of another scripthash
+            }],
+        };
+        let utxo = |vout| UnspentEntry::new(0, funding.txid(), vout, Amount::from_sat(1000));
+        let not_fetched = |_: &Txid| -> anyhow::Result<Transaction> { panic!("unexpected fetch") };
+
+        // resolved by the status sync
+        let mut entries = vec![utxo(0), utxo(1)];
+        resolve_script_pubkey(scripthash, Some(&script_pubkey), &mut entries, not_fetched).unwrap();
+        for entry in &entries {
+            assert_eq!(entry.script_pubkey.as_ref(), Some(&script_pubkey));
+        }
+        // nothing to resolve
+        resolve_script_pubkey(scripthash, None, &mut [], not_fetched).unwrap();
+
+        // the funding transaction doesn't match the scripthash
+        let fetch = |_: &Txid| Ok(funding.clone());
+        let err = resolve_script_pubkey(scripthash, None, &mut [utxo(0)], fetch).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "output {}:0 doesn't match scripthash {}",
+                funding.txid(),
+                scripthash
+            )
+        );
+        let err = resolve_script_pubkey(scripthash, None, &mut [utxo(1)], fetch).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("missing output {}:1", funding.txid())
+        );
+        let err = resolve_script_pubkey(scripthash, None, &mut [utxo(0)], |_| {
+            bail!("no such transaction")
+        })
+        .unwrap_err();
+        assert_eq!(err.to_string(), "no such transaction");
+    }
+
+    #[test]
+    fn test_admin_params() {
+        let request = |method: &str, params: Value| Request {
+            id: Some(json!(1)),
+            method: method.to_owned(),
+            params,
+        };
+        // kept for the audit log
+        let call = Call::parse(request("server.bans.unban", json!(["192.0.2.1"])), false).unwrap();
+        assert_eq!(call.admin_params, Some(json!(["192.0.2.1"])));
+        let call = Call::parse(request("server.cache.flush", json!({"kind": "tx"})), true).unwrap();
+        assert_eq!(call.admin_params, Some(json!({"kind": "tx"})));
+        let call = Call::parse(request("server.ping", json!([])), false).unwrap();
+        assert_eq!(call.admin_params, None);
+    }
+
+    fn unreachable_error() -> anyhow::Error {
+        use bitcoincore_rpc::jsonrpc::{error::Error, simple_http};
+
+        let refused = std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+        let transport = Error::Transport(Box::new(simple_http::Error::SocketError(refused)));
+        anyhow::Error::new(bitcoincore_rpc::Error::JsonRpc(transport)).context("failed")
+    }
+
+    #[test]
+    fn test_unavailable_daemon() {
+        let health = DaemonHealth::new(&Metrics::dummy());
+        assert!(!health.is_unreachable());
+
+        let call = Call {
+            id: Some(json!(1)),
+            method: "blockchain.scripthash.get_balance".to_owned(),
+            params: Params::parse("blockchain.scripthash.get_balance", json!([SCRIPTHASH]))
+                .unwrap(),
+            admin_params: None,
+        };
+        // bitcoind drops during the session
+        let response = call.response(Err(unreachable_error())).unwrap();
+        assert_eq!(response["error"]["code"], json!(3));
+        health.set_unreachable(true);
+        assert!(health.is_unreachable());
+
+        // the index can still answer some of the calls
+        assert!(!call.params.is_available_without_daemon(false));
+        assert!(call.params.is_available_without_daemon(true));
+        let cases = vec![
+            ("blockchain.block.header", json!([1]), true),
+            ("server.sync_status", json!([]), true),
+            ("server.cache.stats", json!([]), true),
+            ("server.cache.flush", json!(["all"]), true),
+            ("server.bans.list", json!([]), true),
+            ("server.webhooks.stats", json!([]), true),
+            ("server.broadcast_mirrors.stats", json!([]), true),
+            (
+                "blockchain.scripthash.release_unspent",
+                json!([SCRIPTHASH, [[TXID, 1]]]),
+                true,
+            ),
+            (
+                "server.add_peer",
+                json!([{"genesis_hash": TXID, "hosts": {"example.org": {"ssl_port": 50002}}}]),
+                true,
+            ),
+            (
+                "blockchain.transaction.search_prefix",
+                json!(["5b75086d", 10]),
+                true,
+            ),
+            ("blockchain.outpoint.get_spender", json!([TXID, 1]), true),
+            (
+                "blockchain.scripthash.get_history",
+                json!([SCRIPTHASH]),
+                false,
+            ),
+            (
+                "blockchain.scripthash.listunspent",
+                json!([SCRIPTHASH]),
+                false,
+            ),
+            ("blockchain.transaction.get", json!([TXID]), false),
+            (
+                "blockchain.transaction.build_rbf",
+                json!([TXID, 2.5]),
+                false,
+            ),
+            ("blockchain.transaction.mempool_info", json!([TXID]), false),
+        ];
+        for (method, params, available) in cases {
+            let params = Params::parse(method, params).unwrap();
+            assert_eq!(
+                params.is_available_without_daemon(false),
+                available,
+                "{}",
+                method
+            );
+        }
+
+        // semantic RPC errors are returned as-is
+        let err = bitcoincore_rpc::jsonrpc::error::RpcError {
+            code: -5,
+            message: "No such mempool transaction".to_owned(),
+            data: None,
+        };
+        let err = bitcoincore_rpc::Error::JsonRpc(bitcoincore_rpc::jsonrpc::Error::Rpc(err));
+        let response = call.response(Err(anyhow::Error::new(err))).unwrap();
+        assert_eq!(
+            response["error"],
+            json!({
+                "code": 2,
+                "message": "No such mempool transaction",
+                "data": {"daemon_code": -5},
+            })
+        );
+
+        health.set_unreachable(false);
+        assert!(!health.is_unreachable());
+    }
+
+    #[test]
+    fn test_coded_errors() {
+        let call = Call {
+            id: Some(json!(1)),
+            method: "server.cache.stats".to_owned(),
+            params: Params::parse("server.cache.stats", json!([])).unwrap(),
+            admin_params: Some(json!([])),
+        };
+        // coded errors are found even if wrapped with context
+        let err = ensure_enabled(false, "server.cache.stats", "cache-management-rpc")
+            .context("failed")
+            .unwrap_err();
+        assert_eq!(
+            call.response(Err(err)).unwrap().to_string(),
+            concat!(
+                r#"{"error":{"code":17,"data":{"method":"server.cache.stats","option":"cache-management-rpc"},"#,
+                r#""message":"server.cache.stats is disabled (see --cache-management-rpc)"},"#,
+                r#""id":1,"jsonrpc":"2.0"}"#
+            )
+        );
+        let err = CodedError::new(ErrorCode::TxNotFound, "not found".to_owned())
+            .with_data(json!({ "txid": TXID }));
+        assert_eq!(
+            call.response(Err(err.into())).unwrap()["error"],
+            json!({"code": 15, "message": "not found", "data": {"txid": TXID}})
+        );
+        // other errors are reported as bad requests
+        assert_eq!(
+            call.response(Err(anyhow!("bad"))).unwrap().to_string(),
+            r#"{"error":{"code":1,"message":"bad"},"id":1,"jsonrpc":"2.0"}"#
+        );
+        assert_eq!(
+            error_msg(&json!(1), RpcError::UnavailableDaemon).to_string(),
+            r#"{"error":{"code":3,"message":"unavailable daemon"},"id":1,"jsonrpc":"2.0"}"#
+        );
+    }
+
+    #[test]
+    fn test_notifications() {
+        let parse = |value: Value| match parse_requests(&Frame::Json(value.to_string())) {
+            Ok(Requests::Single(request)) => Call::parse(request, true),
+            _ => panic!("expected a single request"),
+        };
+
+        let call = parse(json!({"method": "server.ping"})).ok().unwrap();
+        assert_eq!(call.id, None);
+        assert_eq!(call.response(Ok(Value::Null)), None);
+        assert_eq!(call.response(Err(anyhow!("bad"))), None); // only logged
+        assert_eq!(call.error(RpcError::UnavailableDaemon), None);
+
+        // invalid params are not answered either
+        assert_eq!(
+            parse(json!({"method": "server.ping", "params": [1]})).err(),
+            Some(None)
+        );
+        let response = parse(json!({"id": 1, "method": "server.ping", "params": [1]})).err();
+        assert_eq!(response.flatten().unwrap()["id"], json!(1));
+
+        match parse_requests(&Frame::Json(
+            json!([{"method": "server.ping"}, {"id": 2, "method": "server.ping"}]).to_string(),
+        )) {
+            Ok(Requests::Batch(requests)) => {
+                let ids: Vec<Option<Value>> = requests.into_iter().map(|r| r.id).collect();
+                assert_eq!(ids, vec![None, Some(json!(2))]);
+            }
+            _ => panic!("expected a batch"),
+        }
+    }
+
+    #[test]
+    fn test_batch_limit() {
+        let parse = |value: Value, batch_limit| {
+            let requests = parse_requests(&Frame::Json(value.to_string()))
+                .ok()
+                .unwrap();
+            match Calls::parse(requests, false, batch_limit) {
+                Calls::Batch(batch) => batch,
+                Calls::Single(_) => panic!("expected a batch"),
+            }
+        };
+        let batch = json!([
+            {"id": 1, "method": "server.ping"},
+            {"method": "server.ping"},
+            {"id": "x", "method": "blockchain.scripthash.get_balance", "params": [SCRIPTHASH]},
+        ]);
+        assert!(parse(batch.clone(), 3).iter().all(Result::is_ok));
+
+        // each request is rejected (but notifications are not answered)
+        let responses: Vec<Option<Value>> = parse(batch, 2)
+            .into_iter()
+            .map(|call| call.err().unwrap())
+            .collect();
+        assert_eq!(responses[1], None);
+        let error = json!({
+            "code": 18,
+            "message": "too many calls in batch: 3 (max 2)",
+            "data": {"limit": 2},
+        });
+        assert_eq!(
+            responses[0],
+            Some(json!({"jsonrpc": "2.0", "id": 1, "error": error}))
+        );
+        assert_eq!(
+            responses[2],
+            Some(json!({"jsonrpc": "2.0", "id": "x", "error": error}))
+        );
+    }
+
+    #[test]
+    fn test_codec_request() {
+        let request = json!({"id": 1, "method": "server.codec", "params": ["cbor"]});
+        assert!(is_codec_request(&request.to_string()));
+        let batch = json!([{"id": 0, "method": "server.ping"}, request]);
+        assert!(is_codec_request(&batch.to_string()));
+        let request = json!({"id": 1, "method": "server.ping", "params": ["server.codec"]});
+        assert!(!is_codec_request(&request.to_string()));
+        assert!(!is_codec_request(r#"{"method": "server.codec""#));
+
+        let params = Params::parse("server.codec", json!(["cbor"])).unwrap();
+        assert!(params.is_available_while_syncing(SyncServing::RefuseConnectionsUntilSynced));
+        assert!(params.is_available_without_daemon(false));
+    }
+
+    #[test]
+    fn test_negotiate_version() {
+        let negotiate = |version: Version| {
+            ProtocolVersion::negotiate(&version)
+                .map(|v| v.to_string())
+                .map_err(|e| e.to_string())
+        };
+        let single = |v: &str| Version::Single(v.to_owned());
+        let range = |min: &str, max: &str| Version::Range(min.to_owned(), max.to_owned());
+
+        assert_eq!(negotiate(single("1.4")), Ok("1.4".to_owned()));
+        assert_eq!(negotiate(single("1.4.0")), Ok("1.4".to_owned()));
+        assert_eq!(negotiate(range("1.4", "1.4.2")), Ok("1.4".to_owned()));
+        assert_eq!(negotiate(range("1.1", "1.4")), Ok("1.4".to_owned()));
+        assert_eq!(negotiate(range("1.2", "2.0")), Ok("1.4".to_owned()));
+
+        let no_common = Err("no mutually supported version".to_owned());
+        assert_eq!(negotiate(single("1.2")), no_common);
+        assert_eq!(negotiate(range("1.1", "1.3")), no_common);
+        assert_eq!(negotiate(range("1.4.1", "1.5")), no_common);
+        assert_eq!(
+            negotiate(range("1.4", "1.1")),
+            Err("inverted version range: 1.4 > 1.1".to_owned())
+        );
+        for invalid in &["", "1.x", "1..4", "-1.4", "1.4.2.1"] {
+            assert_eq!(
+                negotiate(range("1.1", invalid)),
+                Err(format!("invalid protocol version '{}'", invalid))
+            );
+        }
+
+        match Params::parse("server.version", json!(["electrum", ["1.1", "1.4"]])).unwrap() {
+            Params::Version((_, version)) => assert_eq!(version, range("1.1", "1.4")),
+            _ => panic!("unexpected params"),
+        }
+    }
+
+    #[test]
+    fn test_parse_requests() {
+        let parse = |value: &str| parse_requests(&Frame::Json(value.to_owned()));
+
+        let request = json!({"id": 1, "method": "server.ping"});
+        match parse(&request.to_string()) {
+            Ok(Requests::Single(request)) => assert_eq!(request.method, "server.ping"),
+            _ => panic!("expected a single request"),
+        }
+        let batch: Vec<Value> = (0..10_000)
+            .map(|id| json!({"id": id, "method": "server.ping", "params": []}))
+            .collect();
+        match parse(&json!(batch).to_string()) {
+            Ok(Requests::Batch(requests)) => {
+                assert_eq!(requests.len(), 10_000);
+                assert_eq!(requests[9_999].id, Some(json!(9_999)));
+            }
+            _ => panic!("expected a batch"),
+        }
+        let cbor = Codec::Cbor.encode(&json!([request]));
+        assert!(matches!(
+            parse_requests(&Frame::Cbor(cbor[4..].to_vec())),
+            Ok(Requests::Batch(_))
+        ));
+
+        let malformed = [
+            r#"{"id": 1, "method": "server.ping""#,
+            r#"{"id": 1, "method": "server.ping"} {}"#,
+            r#"[{"id": 1, "method": "server.ping"}, 5, {"id": 2"#,
+            "",
+        ];
+        for line in malformed.iter() {
+            assert!(
+                matches!(parse(line), Err(StandardError::ParseError)),
+                "{}",
+                line
+            );
+        }
+
+        let invalid = [
+            "5",
+            r#""server.ping""#,
+            r#"{"id": 1}"#,
+            r#"{"id": 1, "method": 5}"#,
+            r#"[{"id": 1, "method": "server.ping"}, 5]"#,
+            r#"[{"id": 1, "method": "server.ping"}, {"params": []}]"#,
+        ];
+        for line in invalid.iter() {
+            assert!(
+                matches!(parse(line), Err(StandardError::InvalidRequest)),
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn test_txid_prefix() {
+        assert_eq!(parse_txid_prefix("5B75086dA").unwrap(), "5b75086da");
+        assert_eq!(parse_txid_prefix(TXID).unwrap(), TXID);
+        let err = parse_txid_prefix("5b75086").unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "invalid txid prefix length: 7 (expected 8 to 64 hex digits)"
+        );
+        assert!(parse_txid_prefix(&format!("{}0", TXID)).is_err());
+        assert!(parse_txid_prefix("5b75086x").is_err());
+
+        let method = "blockchain.transaction.search_prefix";
+        match Params::parse(method, json!(["5b75086d", 5])).unwrap() {
+            Params::TransactionSearchPrefix((prefix, limit)) => {
+                assert_eq!(prefix, "5b75086d");
+                assert_eq!(limit, 5);
+            }
+            _ => panic!("unexpected params"),
+        }
+    }
+
+    #[test]
+    fn test_select_prefix_matches() {
+        let txid1: Txid = TXID.parse().unwrap();
+        let txid2: Txid = format!("{}1", &TXID[..63]).parse().unwrap();
+        let m = |tx_hash, height| PrefixMatch { tx_hash, height };
+        // the row at height 5 was reorged out, and the one at height 7 is duplicated
+        let candidates = vec![(txid1, 5), (txid1, 7), (txid1, 7), (txid2, 9)];
+        let is_confirmed = |_txid: Txid, height: usize| Ok(height != 5);
+
+        let result =
+            select_prefix_matches(candidates.clone().into_iter(), 3, is_confirmed).unwrap();
+        assert_eq!(result.matches, vec![m(txid1, 7), m(txid2, 9)]);
+        assert!(!result.truncated);
+        assert!(result.collision);
+
+        let result = select_prefix_matches(candidates.into_iter(), 1, is_confirmed).unwrap();
+        assert_eq!(result.matches, vec![m(txid1, 7)]);
+        assert!(result.truncated);
+        assert!(result.collision);
+
+        // a txid confirmed at more than one height (see BIP-30) is not ambiguous
+        let candidates = vec![(txid1, 5), (txid1, 7)];
+        let result = select_prefix_matches(candidates.into_iter(), 3, |_, _| Ok(true)).unwrap();
+        assert_eq!(result.matches, vec![m(txid1, 5), m(txid1, 7)]);
+        assert!(!result.truncated);
+        assert!(!result.collision);
+
+        let candidates = vec![(txid1, 5)];
+        let result = select_prefix_matches(candidates.into_iter(), 3, |_, _| bail!("no block"));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_outpoint_args() {
+        let method = "blockchain.outpoint.subscribe";
+        let expected = OutPoint::new(TXID.parse().unwrap(), 1);
+        for params in vec![json!([TXID, 1]), json!([TXID, 1, "0014"])] {
+            match Params::parse(method, params).unwrap() {
+                Params::OutPointSubscribe(args) => assert_eq!(OutPoint::from(&args), expected),
+                _ => panic!("unexpected params"),
+            }
+        }
+        assert!(Params::parse(method, json!([TXID])).is_err());
+    }
+
+    /// A tracker's transaction status update, given the mempool and the confirmed transactions
+    fn update_tx(
+        chain: &Chain,
+        mempool: &[Txid],
+        confirmed: &[(Txid, usize)],
+        txid: Txid,
+        status: &mut Option<WatchedTx>,
+    ) -> anyhow::Result<bool> {
+        let unconfirmed = Some(txid)
+            .filter(|txid| mempool.contains(txid))
+            .map(|txid| WatchedTx::unconfirmed(txid, false));
+        let new_status = WatchedTx::update(unconfirmed, status.as_ref(), chain, || {
+            let height = confirmed.iter().find(|(t, _)| *t == txid).map(|(_, h)| *h);
+            Ok(height.map(|h| WatchedTx::confirmed(txid, h, chain.get_block_hash(h).unwrap())))
+        })?;
+        let changed = *status != new_status;
+        *status = new_status;
+        Ok(changed)
+    }
+
+    #[test]
+    fn test_transaction_subscriptions() {
+        let txid = |n: u8| -> Txid { format!("{:064x}", n).parse().unwrap() };
+        let mut chain = Chain::new(Network::Regtest);
+        let genesis = *chain.get_block_header(0).unwrap();
+        let mut client = Client::new(None, None);
+        let (limit, confirmations) = (2, Some(2));
+
+        // the first transaction is in the mempool, and the second one is unknown
+        let mempool = [txid(1)];
+        for (n, expected) in vec![(1, json!(0)), (2, json!(null))] {
+            let result = client.subscribe_transaction(txid(n), limit, confirmations, &chain, |s| {
+                update_tx(&chain, &mempool, &[], txid(n), s)
+            });
+            assert_eq!(result.unwrap(), expected);
+        }
+        let err = client
+            .subscribe_transaction(txid(3), limit, confirmations, &chain, |_| Ok(false))
+            .unwrap_err();
+        assert_eq!(
+            find_coded(&err).unwrap().code(),
+            ErrorCode::SubscriptionLimit
+        );
+        // resubscribing isn't limited
+        let result =
+            client.subscribe_transaction(txid(2), limit, confirmations, &chain, |_| Ok(false));
+        assert_eq!(result.unwrap(), json!(null));
+
+        // confirmed by a new block
+        let mut header = genesis;
+        header.prev_blockhash = chain.tip();
+        chain.update(vec![NewHeader::from((header, 1))]);
+        let confirmed = [(txid(1), 1)];
+        let notifications = client
+            .update_transactions(confirmations, &chain, |t, s| {
+                update_tx(&chain, &[], &confirmed, t, s)
+            })
+            .unwrap();
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0]["params"], json!([txid(1), 1]));
+        assert_eq!(client.transactions.len(), 2); // not deeply confirmed yet
+
+        // the block is replaced by a reorg, and the transaction returns to the mempool
+        header.time += 1;
+        chain.update(vec![NewHeader::from((header, 1))]);
+        let notifications = client
+            .update_transactions(confirmations, &chain, |t, s| {
+                update_tx(&chain, &mempool, &[], t, s)
+            })
+            .unwrap();
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0]["params"], json!([txid(1), 0]));
+
+        // confirmed again, and then by another block - so it's unsubscribed
+        let mut next = genesis;
+        next.prev_blockhash = chain.tip();
+        chain.update(vec![NewHeader::from((next, 2))]);
+        let notifications = client
+            .update_transactions(confirmations, &chain, |t, s| {
+                update_tx(&chain, &[], &confirmed, t, s)
+            })
+            .unwrap();
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0]["params"], json!([txid(1), 1]));
+        assert_eq!(client.transactions.len(), 1);
+        assert!(client.transactions.contains_key(&txid(2)));
+
+        // a deeply confirmed transaction's height is returned, without subscribing to it
+        let confirmed = [(txid(3), 1)];
+        let result = client.subscribe_transaction(txid(3), limit, confirmations, &chain, |s| {
+            update_tx(&chain, &[], &confirmed, txid(3), s)
+        });
+        assert_eq!(result.unwrap(), json!(1));
+        assert_eq!(client.transactions.len(), 1);
+    }
+
+    #[test]
+    fn test_subscriptions_limit() {
+        assert!(check_subscriptions_limit(false, 999, 1000, "outpoint").is_ok());
+        let err = check_subscriptions_limit(false, 1000, 1000, "outpoint").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "too many outpoint subscriptions (max 1000), unsubscribe some outpoints"
+        );
+        let coded = find_coded(&err).unwrap();
+        assert_eq!(coded.code(), ErrorCode::SubscriptionLimit);
+        // resubscribing returns the current status
+        assert!(check_subscriptions_limit(true, 1000, 1000, "outpoint").is_ok());
+    }
+
+    #[test]
+    fn test_scripthash_subscriptions_limit() {
+        let rejected = Metrics::dummy().counter("rejected", "rejected subscriptions", "limit");
+        let limits = SubscriptionLimits {
+            count: Some(3),
+            memory_budget: None,
+        };
+        let scripthash = |n| ScriptHash::from_byte_array([n; 32]);
+        let (a, b, c, d, e) = (
+            scripthash(1),
+            scripthash(2),
+            scripthash(3),
+            scripthash(4),
+            scripthash(5),
+        );
+        let mut client = Client::new(None, None);
+        let mut built = vec![];
+        let mut subscribe = |client: &mut Client, scripthashes: &[ScriptHash]| {
+            let build = |_: &CancelToken, new: Vec<ScriptHash>| {
+                built.push(new.clone());
+                new.into_iter()
+                    .map(|scripthash| (scripthash, Ok(ScriptHashStatus::new(scripthash))))
+                    .collect()
             };
-            for i in select_index {
-                choose_list.push(utxos[i].clone());
-                choose_index.push(i);
+            let now = Instant::now();
+            subscribe_scripthashes(client, scripthashes, limits, now, &rejected, build)
+                .map(|result| {
+                    result.map_err(|err| {
+                        assert_eq!(
+                            find_coded(&err).unwrap().code(),
+                            ErrorCode::SubscriptionLimit
+                        );
+                        err.to_string()
+                    })
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(subscribe(&mut client, &[a]), vec![Ok(json!(null))]);
+
+        // a batch crossing the limit (with a resubscription and a repeated scripthash)
+        let results = subscribe(&mut client, &[a, b, c, b, d, e]);
+        let message = "subscription limit 3 reached (unsubscribe some scripthashes)";
+        let rejected: Result<Value, String> = Err(message.to_owned());
+        assert_eq!(
+            results,
+            vec![
+                Ok(json!(null)),
+                Ok(json!(null)),
+                Ok(json!(null)),
+                Ok(json!(null)),
+                rejected.clone(),
+                rejected,
+            ]
+        );
+        // the rejected scripthashes' statuses are not built
+        assert_eq!(built, vec![vec![a], vec![b, c]]);
+        let mut subscribed: Vec<ScriptHash> = client.scripthashes.keys().copied().collect();
+        subscribed.sort();
+        assert_eq!(subscribed, vec![a, b, c]);
+    }
+
+    #[test]
+    fn test_scripthash_subscriptions_memory_budget() {
+        let rejected = Metrics::dummy().counter("rejected", "rejected subscriptions", "limit");
+        let scripthash = |n| ScriptHash::from_byte_array([n; 32]);
+        let (a, b) = (scripthash(1), scripthash(2));
+        let status_size = ScriptHashStatus::new(a).memory_usage();
+        let limits = SubscriptionLimits {
+            count: None,
+            memory_budget: Some(status_size * 3 / 2),
+        };
+        let build = |_: &CancelToken, new: Vec<ScriptHash>| {
+            new.into_iter()
+                .map(|scripthash| (scripthash, Ok(ScriptHashStatus::new(scripthash))))
+                .collect()
+        };
+        let mut client = Client::new(None, None);
+        let now = Instant::now();
+        let results: Vec<Result<Value>> =
+            subscribe_scripthashes(&mut client, &[a, b], limits, now, &rejected, build).collect();
+        assert_eq!(results[0].as_ref().unwrap(), &json!(null));
+        let coded = find_coded(results[1].as_ref().unwrap_err()).unwrap();
+        assert_eq!(coded.code(), ErrorCode::SubscriptionLimit);
+        assert_eq!(
+            coded.to_value()["data"],
+            json!({"memory_usage": 2 * status_size, "budget": status_size * 3 / 2})
+        );
+        // the rejected status is not accounted
+        assert_eq!(client.subscriptions(), 1);
+        assert_eq!(client.memory_usage(), status_size);
+
+        // unsubscribing releases the status' memory
+        let idle = client.unsubscribe_idle(now, Duration::from_secs(0));
+        assert_eq!(idle, vec![a]);
+        assert_eq!(client.memory_usage(), 0);
+        let results: Vec<Result<Value>> =
+            subscribe_scripthashes(&mut client, &[b], limits, now, &rejected, build).collect();
+        assert_eq!(results[0].as_ref().unwrap(), &json!(null));
+        assert_eq!(client.memory_usage(), status_size);
+    }
+
+    #[test]
+    fn test_multi_scripthash_query() {
+        let scripthash = |n| ScriptHash::from_byte_array([n; 32]);
+        let (a, b, c) = (scripthash(1), scripthash(2), scripthash(3));
+        let call = |id: u64, method: &str, scripthash: ScriptHash| Call {
+            id: Some(json!(id)),
+            method: method.to_owned(),
+            params: Params::parse(method, json!([scripthash.to_string()])).unwrap(),
+            admin_params: None,
+        };
+        let calls = vec![
+            call(1, "blockchain.scripthash.get_balance", a),
+            call(2, "blockchain.scripthash.get_history", b),
+            call(3, "blockchain.scripthash.listunspent", a),
+            call(4, "blockchain.scripthash.get_balance", c),
+        ];
+        let calls: Vec<&Call> = calls.iter().collect();
+        let scripthashes = multi_query_scripthashes(&calls).unwrap();
+        assert_eq!(scripthashes, vec![a, b, a, c]);
+
+        // other calls are answered one by one
+        let ping = Call {
+            id: Some(json!(5)),
+            method: "server.ping".to_owned(),
+            params: Params::parse("server.ping", json!([])).unwrap(),
+            admin_params: None,
+        };
+        assert_eq!(multi_query_scripthashes(&[calls[0], &ping]), None);
+
+        let mut client = Client::new(None, None);
+        let prefetch = |client: &mut Client, scripthashes: &[ScriptHash]| {
+            assert_eq!(scripthashes, [a, b, a, c]);
+            for scripthash in &[a, c] {
+                let status = ScriptHashStatus::new(*scripthash);
+                client.prefetched.insert(*scripthash, status);
             }
-        } else {
-            let mut total_amount = Amount::from_sat(0);
-            let max_len = std::cmp::min(utxo_len, 20);
-            // max inputs length is '20'
-            for i in 0..=max_len {
-                total_amount += utxos[utxo_len - 1 - i].value;
-                choose_index.push(utxo_len - 1 - i);
-                choose_list.push(utxos[utxo_len - 1 - i].clone());
-                if total_amount > target_value {
-                    break;
+        };
+        // `b`'s status failed to build, so its query fails
+        let respond = |client: &mut Client, call: &Call| {
+            let scripthash = multi_query_scripthashes(&[call]).unwrap()[0];
+            let result = match client.prefetched.get(&scripthash) {
+                Some(_) => Ok(json!(call.method)),
+                None => Err(anyhow!("failed to build {} status", scripthash)),
+            };
+            call.response(result)
+        };
+        let responses =
+            multi_query_responses(&mut client, &calls, &scripthashes, prefetch, respond);
+        let ids: Vec<&Value> = responses.iter().map(|response| &response["id"]).collect();
+        assert_eq!(ids, vec![&json!(1), &json!(2), &json!(3), &json!(4)]);
+        assert_eq!(
+            responses[0]["result"],
+            json!("blockchain.scripthash.get_balance")
+        );
+        assert_eq!(
+            responses[1]["error"]["message"],
+            json!(format!("failed to build {} status", b))
+        );
+        assert_eq!(responses[1].get("result"), None);
+        assert_eq!(
+            responses[2]["result"],
+            json!("blockchain.scripthash.listunspent")
+        );
+        assert_eq!(
+            responses[3]["result"],
+            json!("blockchain.scripthash.get_balance")
+        );
+        // the prefetched statuses are only used by this batch
+        assert!(client.prefetched.is_empty());
+    }
+
+    #[test]
+    fn test_transactions_get_entries() {
+        let txid = |n: u8| Txid::from_byte_array([n; 32]);
+        let (a, b, c) = (txid(1), txid(2), txid(3));
+        let mut fetched = vec![];
+        let entries = transactions_get_entries(&[c, a, b, a, c], |unique| {
+            fetched.extend_from_slice(unique);
+            Ok(unique
+                .iter()
+                .map(|txid| {
+                    let result = if *txid == b {
+                        Err(anyhow!("No such mempool or blockchain transaction"))
+                    } else {
+                        Ok(json!(txid.to_string()))
+                    };
+                    (*txid, result)
+                })
+                .collect())
+        })
+        .unwrap();
+        // each distinct txid is fetched once
+        assert_eq!(fetched, vec![c, a, b]);
+        let ok = |txid: Txid| TransactionsGetEntry {
+            txid,
+            result: Some(json!(txid.to_string())),
+            error: None,
+        };
+        let failed = TransactionsGetEntry {
+            txid: b,
+            result: None,
+            error: Some("No such mempool or blockchain transaction".to_owned()),
+        };
+        // answered in request order (repeating duplicates), with the failure kept inline
+        assert_eq!(entries, vec![ok(c), ok(a), failed, ok(a), ok(c)]);
+
+        // a missing result fails only its own entry
+        let entries = transactions_get_entries(&[a, b], |_| {
+            Ok(vec![(a, Ok(json!("a")))].into_iter().collect())
+        })
+        .unwrap();
+        assert_eq!(entries[0].result, Some(json!("a")));
+        assert_eq!(
+            entries[1].error,
+            Some(format!("transaction {} not found", b))
+        );
+
+        // a failed fetch fails the whole request
+        let err = transactions_get_entries(&[a], |_| bail!("daemon unavailable")).unwrap_err();
+        assert_eq!(err.to_string(), "daemon unavailable");
+    }
+
+    #[test]
+    fn test_id_from_pos_args() {
+        let method = "blockchain.transaction.id_from_pos";
+        for (params, expected) in vec![
+            (json!([1, 2]), (1, 2, false)),
+            (json!([1, 2, false]), (1, 2, false)),
+            (json!([1, 2, true]), (1, 2, true)),
+            (json!({"height": 1, "tx_pos": 2}), (1, 2, false)),
+        ] {
+            let params = signature(method).unwrap().positional(params).unwrap();
+            match Params::parse(method, params).unwrap() {
+                Params::TransactionFromPosition(args) => {
+                    assert_eq!(<(usize, usize, bool)>::from(&args), expected)
                 }
+                _ => panic!("unexpected params"),
             }
-            // push small utxo to make inputs length to 3
-            if choose_list.len() < 3 {
-                choose_list.push(utxos[0].clone());
-                choose_index.push(0);
+        }
+        assert!(Params::parse(method, json!([1])).is_err());
+    }
+
+    #[test]
+    fn test_transactions_get() {
+        let method = "blockchain.transactions.get";
+        for (params, verbose) in vec![(json!([[TXID]]), false), (json!([[TXID], true]), true)] {
+            match Params::parse(method, params).unwrap() {
+                Params::TransactionsGet(args) => {
+                    let txid: Txid = TXID.parse().unwrap();
+                    assert_eq!(<(Vec<Txid>, bool)>::from(&args), (vec![txid], verbose));
+                }
+                _ => panic!("unexpected params"),
             }
+        }
+        assert!(Params::parse(method, json!([TXID])).is_err()); // not a list
+
+        let chain = Chain::new(Network::Regtest);
+        let genesis = chain.get_block_hash(0).unwrap();
+        assert!(BlockContext::new(&chain, BlockHash::all_zeros()).is_none());
+        let context = BlockContext::new(&chain, genesis).unwrap();
+        let mut info = json!({"txid": TXID, "confirmations": 7, "blocktime": 1});
+        context.apply(&mut info);
+        assert_eq!(
+            info,
+            json!({
+                "txid": TXID,
+                "blockhash": genesis,
+                "confirmations": 1,
+                "time": 1296688602,
+                "blocktime": 1296688602,
+            })
+        );
+    }
+
+    #[test]
+    fn test_unsubscribe_idle() {
+        let mut client = Client::new(None, None);
+        let clock = Clock::mock();
+        let hour = Duration::from_secs(3600);
+        let scripthashes: Vec<ScriptHash> = (1..=3u8)
+            .map(|n| ScriptHash::from_byte_array([n; 32]))
+            .collect();
+        for scripthash in &scripthashes {
+            let subscription = Subscription {
+                status: ScriptHashStatus::new(*scripthash),
+                touched: clock.now(),
+            };
+            client.scripthashes.insert(*scripthash, subscription);
+        }
+        clock.advance(hour);
+        client.touch(&scripthashes[1], clock.now());
+        client.touch(&ScriptHash::from_byte_array([4; 32]), clock.now()); // not subscribed
+
+        assert!(client.unsubscribe_idle(clock.now(), 2 * hour).is_empty());
+        clock.advance(hour);
+        let idle = client.unsubscribe_idle(clock.now(), 2 * hour);
+        assert_eq!(idle, vec![scripthashes[0], scripthashes[2]]);
+        assert_eq!(client.scripthashes.len(), 1);
+        assert!(client.status(&scripthashes[1]).is_some());
+
+        clock.advance(hour);
+        let idle = client.unsubscribe_idle(clock.now(), 2 * hour);
+        assert_eq!(idle, vec![scripthashes[1]]);
+        assert!(client.scripthashes.is_empty());
+    }
+
+    #[test]
+    fn test_debounced_notifications() {
+        let mut client = Client::new(None, None);
+        let clock = Clock::mock();
+        let ms = Duration::from_millis;
+        let subscribed = ScriptHash::from_byte_array([1; 32]);
+        let subscription = Subscription {
+            status: ScriptHashStatus::new(subscribed),
+            touched: clock.now(),
+        };
+        client.scripthashes.insert(subscribed, subscription);
+        let notify = |scripthash: ScriptHash, params: &[Value]| {
+            let mut params = params.to_vec();
+            params.insert(0, json!(scripthash));
+            notification("blockchain.scripthash.subscribe", &params)
         };
+        let push = |client: &mut Client, scripthash: ScriptHash, notification: Value| {
+            client.debounced.push(scripthash, notification, clock.now())
+        };
+
+        // not debounced by default
+        let first = notify(subscribed, &[json!("a")]);
+        assert_eq!(push(&mut client, subscribed, first.clone()), Some(first));
+
+        client.debounced.window = Some(ms(100));
+        let start = clock.now();
+        for status in &["b", "c", "d"] {
+            let pending = notify(subscribed, &[json!(status)]);
+            assert_eq!(push(&mut client, subscribed, pending), None);
+            clock.advance(ms(30));
+        }
+        assert_eq!(client.debounce_deadline(), Some(start + ms(100)));
+        assert!(client.flush_due_notifications(clock.now()).is_empty());
+        clock.advance(ms(10));
+        assert_eq!(
+            client.flush_due_notifications(clock.now()),
+            vec![notify(subscribed, &[json!("d")])]
+        );
+        assert_eq!(client.debounce_deadline(), None);
+
+        // coalesced deltas are dropped, and so are the unsubscribed scripthashes' notifications
+        let delta = json!({"delta": {"removed": [], "added": []}});
+        let unsubscribed = ScriptHash::from_byte_array([2; 32]);
+        for status in &["e", "f"] {
+            let pending = notify(subscribed, &[json!(status), delta.clone()]);
+            assert_eq!(push(&mut client, subscribed, pending), None);
+        }
+        let pending = notify(unsubscribed, &[json!("g")]);
+        assert_eq!(push(&mut client, unsubscribed, pending), None);
+        assert_eq!(
+            client.flush_notifications(),
+            vec![notify(subscribed, &[json!("f"), json!({"delta": null})])]
+        );
+
+        // pending notifications are flushed after disabling debouncing
+        let pending = notify(subscribed, &[json!("h")]);
+        assert_eq!(push(&mut client, subscribed, pending.clone()), None);
+        client.debounced.window = None;
+        assert_eq!(client.flush_due_notifications(clock.now()), vec![pending]);
     }
-    (choose_list, choose_index)
-}
 
+    #[test]
+    fn test_index_write_failure() {
+        let health = IndexHealth::new(&Metrics::dummy());
+        assert!(!health.has_write_failure());
+        health.set_write_failure(true);
+        assert!(health.has_write_failure());
+        health.set_write_failure(true); // logged only once
+        health.set_write_failure(false);
+        assert!(!health.has_write_failure());
+    }
+
+    #[test]
+    fn test_health_flags() {
+        let (daemon, index) = (
+            DaemonHealth::new(&Metrics::dummy()),
+            IndexHealth::new(&Metrics::dummy()),
+        );
+        let flags = HealthFlags::new(false, &daemon, &index, true);
+        assert!(flags.stale && !flags.degraded && flags.mempool_partial); // initial sync
+        let flags = HealthFlags::new(true, &daemon, &index, false);
+        assert!(!flags.stale && !flags.degraded);
+
+        daemon.set_unreachable(true);
+        let flags = HealthFlags::new(true, &daemon, &index, false);
+        assert!(!flags.daemon_reachable && flags.stale && flags.degraded);
+        daemon.set_unreachable(false);
+        index.set_write_failure(true);
+        let flags = HealthFlags::new(true, &daemon, &index, false);
+        assert!(flags.daemon_reachable && !flags.index_writable);
+        assert!(flags.stale && flags.degraded);
+        index.set_write_failure(false);
+        assert_eq!(flags, HealthFlags::new(true, &daemon, &index, false));
+    }
+
+    #[test]
+    fn test_daemon_info_refresh() {
+        let clock = Clock::mock();
+        let mut info = DaemonInfo::new(&Metrics::dummy());
+        let calls = std::cell::Cell::new(0);
+        let version = || {
+            calls.set(calls.get() + 1);
+            Ok((22_00_00, "/Satoshi:22.0.0/".to_owned()))
+        };
+        info.refresh(clock.now(), version);
+        assert_eq!(calls.get(), 1);
+        // not called again on every sync
+        clock.advance(DAEMON_INFO_REFRESH_INTERVAL / 2);
+        info.refresh(clock.now(), version);
+        assert_eq!(calls.get(), 1);
+        clock.advance(DAEMON_INFO_REFRESH_INTERVAL / 2);
+        info.refresh(clock.now(), version);
+        assert_eq!(calls.get(), 2);
+
+        // a failure is retried on the next sync
+        clock.advance(DAEMON_INFO_REFRESH_INTERVAL);
+        info.refresh(clock.now(), || {
+            calls.set(calls.get() + 1);
+            bail!("connection refused")
+        });
+        assert_eq!(calls.get(), 3);
+        info.refresh(clock.now(), version);
+        assert_eq!(calls.get(), 4);
+        info.refresh(clock.now(), version);
+        assert_eq!(calls.get(), 4);
+    }
+
+    #[test]
+    fn test_health_report() {
+        let (daemon, index) = (
+            DaemonHealth::new(&Metrics::dummy()),
+            IndexHealth::new(&Metrics::dummy()),
+        );
+        let report = HealthReport {
+            height: 100,
+            tip: BlockHash::all_zeros(),
+            flags: HealthFlags::new(true, &daemon, &index, false),
+            daemon_height: Some(101),
+            last_sync_secs: Some(0.5),
+            mempool_txs: 10,
+            mempool_age_secs: None,
+            tx_cache_entries: 2,
+            tx_cache_bytes: 500,
+        };
+        // includes the fields previously returned by `server.sync_status`
+        assert_eq!(
+            json!(report),
+            json!({
+                "height": 100,
+                "tip": BlockHash::all_zeros(),
+                "synced": true,
+                "daemon_reachable": true,
+                "index_writable": true,
+                "mempool_partial": false,
+                "stale": false,
+                "degraded": false,
+                "daemon_height": 101,
+                "last_sync_secs": 0.5,
+                "mempool_txs": 10,
+                "mempool_age_secs": null,
+                "tx_cache_entries": 2,
+                "tx_cache_bytes": 500,
+            })
+        );
+    }
+
+    #[test]
+    fn test_available_while_syncing() {
+        let modes = [
+            SyncServing::RefuseConnectionsUntilSynced,
+            SyncServing::ServeHeadersOnly,
+            SyncServing::ServeConfirmedDataWithStalenessFlag,
+        ];
+        // availability per mode (the connections are refused before any RPC in the first one)
+        let cases = vec![
+            ("blockchain.block.header", json!([1]), [true, true, true]),
+            (
+                "blockchain.headers.subscribe",
+                json!([]),
+                [true, true, true],
+            ),
+            ("server.sync_status", json!([]), [true, true, true]),
+            ("server.cache.flush", json!(["tx"]), [true, true, true]),
+            ("server.bans.unban", json!(["::1"]), [true, true, true]),
+            (
+                "blockchain.scripthash.get_balance",
+                json!([SCRIPTHASH]),
+                [false, false, true],
+            ),
+            (
+                "blockchain.scripthash.get_history",
+                json!([SCRIPTHASH]),
+                [false, false, true],
+            ),
+            (
+                "blockchain.scripthash.listunspent",
+                json!([SCRIPTHASH]),
+                [false, false, true],
+            ),
+            (
+                "blockchain.scripthash.get_mempool",
+                json!([SCRIPTHASH]),
+                [false, false, false],
+            ),
+            (
+                "blockchain.scripthash.subscribe",
+                json!([SCRIPTHASH]),
+                [false, false, false],
+            ),
+            (
+                "blockchain.transaction.get",
+                json!([TXID]),
+                [false, false, false],
+            ),
+            (
+                "blockchain.transaction.build_rbf",
+                json!([TXID, 2.5]),
+                [false, false, false],
+            ),
+        ];
+        for (method, params, expected) in cases {
+            let params = Params::parse(method, params).unwrap();
+            for (mode, available) in modes.iter().zip(&expected) {
+                assert_eq!(
+                    params.is_available_while_syncing(*mode),
+                    *available,
+                    "{} {:?}",
+                    method,
+                    mode
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_block_txids_notifications() {
+        let orphaned = BlockHash::from_byte_array([1; 32]);
+        let hash = BlockHash::from_byte_array([2; 32]);
+        let txids: Vec<Txid> = (0..=MAX_NOTIFIED_TXIDS)
+            .map(|i| Txid::from_byte_array([(i % 256) as u8; 32]))
+            .collect();
+        let events = vec![
+            BlockEvent::Disconnected {
+                height: 100,
+                hash: orphaned,
+            },
+            BlockEvent::Connected {
+                height: 100,
+                hash,
+                txids: txids.clone(),
+            },
+        ];
+        let notifications = block_txids_notifications(&events);
+        assert_eq!(notifications.len(), 3);
+        for value in &notifications {
+            assert_eq!(value["method"], json!("blockchain.block.txids.subscribe"));
+        }
+        assert_eq!(
+            notifications[0]["params"],
+            json!([{"height": 100, "hash": orphaned, "orphaned": true}])
+        );
+
+        let first = &notifications[1]["params"][0];
+        assert_eq!(first["hash"], json!(hash));
+        assert_eq!(
+            (first["chunk"].clone(), first["chunks"].clone()),
+            (json!(0), json!(2))
+        );
+        assert_eq!(first["txids"], json!(txids[..MAX_NOTIFIED_TXIDS]));
+
+        let last = &notifications[2]["params"][0];
+        assert_eq!(last["chunk"], json!(1));
+        assert_eq!(last["txids"], json!([txids[MAX_NOTIFIED_TXIDS]]));
+    }
+
+    #[test]
+    fn test_broadcast_keys() {
+        let ttl = Duration::from_secs(600);
+        let start = Clock::mock().now();
+        let txid1 = Txid::from_byte_array([1; 32]);
+        let txid2 = Txid::from_byte_array([2; 32]);
+
+        let mut keys = BroadcastKeys::default();
+        assert_eq!(keys.get("a", start, ttl), None);
+        keys.insert("a".to_owned(), txid1, start);
+        keys.insert("b".to_owned(), txid2, start + Duration::from_secs(300));
+        assert_eq!(
+            keys.get("a", start + Duration::from_secs(599), ttl),
+            Some(txid1)
+        );
+        assert_eq!(
+            keys.get("b", start + Duration::from_secs(599), ttl),
+            Some(txid2)
+        );
+
+        // expired keys are forgotten
+        assert_eq!(keys.get("a", start + ttl, ttl), None);
+        assert_eq!(keys.get("b", start + ttl, ttl), Some(txid2));
+        assert_eq!(keys.entries.len(), 1);
+
+        // the oldest keys are evicted
+        for i in 0..MAX_BROADCAST_KEYS {
+            keys.insert(i.to_string(), txid1, start + ttl);
+        }
+        assert_eq!(keys.entries.len(), MAX_BROADCAST_KEYS);
+        assert_eq!(keys.get("b", start + ttl, ttl), None);
+        assert_eq!(keys.get("0", start + ttl, ttl), Some(txid1));
+    }
+
+    #[test]
+    fn test_stable_serialization() {
+        // `json!` fields are sorted
+        let merkle = json!({"pos": 1, "merkle": ["ab"], "block_height": 10});
+        assert_eq!(
+            result_msg(&json!(1), merkle).to_string(),
+            r#"{"id":1,"jsonrpc":"2.0","result":{"block_height":10,"merkle":["ab"],"pos":1}}"#
+        );
+        let features = json!({"hosts": {"tcp_port": 50001}, "genesis_hash": TXID, "pruning": null});
+        assert_eq!(
+            features.to_string(),
+            format!(
+                r#"{{"genesis_hash":"{}","hosts":{{"tcp_port":50001}},"pruning":null}}"#,
+                TXID
+            )
+        );
+
+        // serialized structs are sorted the same way
+        let utxo = UnspentEntry::new(100, TXID.parse().unwrap(), 1, Amount::from_sat(1000));
+        assert_eq!(
+            json!([utxo]).to_string(),
+            format!(
+                r#"[{{"height":100,"tx_hash":"{}","tx_pos":1,"value":1000}}]"#,
+                TXID
+            )
+        );
+        assert_eq!(
+            json!(Balance::default()).to_string(),
+            r#"{"confirmed":0,"unconfirmed":0}"#
+        );
+
+        assert_eq!(
+            error_msg(&json!(2), RpcError::UnavailableIndex).to_string(),
+            r#"{"error":{"code":-32603,"message":"unavailable index"},"id":2,"jsonrpc":"2.0"}"#
+        );
+        assert_eq!(
+            notification(
+                "blockchain.scripthash.subscribe",
+                &[json!(SCRIPTHASH), json!(null)]
+            )
+            .to_string(),
+            format!(
+                r#"{{"jsonrpc":"2.0","method":"blockchain.scripthash.subscribe","params":["{}",null]}}"#,
+                SCRIPTHASH
+            )
+        );
+    }
+
+    #[test]
+    fn test_scripthash_notification() {
+        let scripthash: ScriptHash = SCRIPTHASH.parse().unwrap();
+        let status = ScriptHashStatus::new(scripthash);
+        assert_eq!(
+            scripthash_notification(&scripthash, &status, false)["params"],
+            json!([SCRIPTHASH, null])
+        );
+        // the delta can't be computed before the status is synced
+        assert_eq!(
+            scripthash_notification(&scripthash, &status, true)["params"],
+            json!([SCRIPTHASH, null, {"delta": null}])
+        );
+    }
+
+    #[test]
+    fn test_strict_params() {
+        let valid = vec![
+            ("blockchain.block.header", json!([1, 10])),
+            ("blockchain.block.header_by_hash", json!([TXID])),
+            ("blockchain.block.headers", json!([1, 10])),
+            ("blockchain.block.txids.subscribe", json!([])),
+            ("blockchain.estimatefee", json!([6])),
+            ("blockchain.headers.subscribe", json!([true])),
+            ("blockchain.outpoint.get_spender", json!([TXID, 0])),
+            ("blockchain.outpoint.subscribe", json!([TXID, 0, "0014"])),
+            ("blockchain.outpoint.unsubscribe", json!([TXID, 0])),
+            ("blockchain.psbt.analyze", json!(["cHNidP8="])),
+            ("blockchain.relayfee", json!(null)),
+            ("blockchain.scripthash.get_balance", json!([SCRIPTHASH])),
+            (
+                "blockchain.scripthash.get_history",
+                json!([SCRIPTHASH, true, true]),
+            ),
+            (
+                "blockchain.scripthash.get_history_filter",
+                json!([SCRIPTHASH, 1, null]),
+            ),
+            ("blockchain.scripthash.get_mempool", json!([SCRIPTHASH])),
+            ("blockchain.scripthash.get_orphaned", json!([SCRIPTHASH])),
+            (
+                "blockchain.scripthash.listunspent",
+                json!([SCRIPTHASH, false, true, true]),
+            ),
+            (
+                "blockchain.scripthash.release_unspent",
+                json!([SCRIPTHASH, [[TXID, 0]]]),
+            ),
+            (
+                "blockchain.scripthash.unspent_exist",
+                json!([SCRIPTHASH, TXID, 1]),
+            ),
+            (
+                "blockchain.scripthash.select_unspent",
+                json!([SCRIPTHASH, [1000], 0, false]),
+            ),
+            ("blockchain.scripthash.subscribe", json!([SCRIPTHASH])),
+            ("blockchain.scripthash.unsubscribe", json!([SCRIPTHASH])),
+            ("blockchain.transaction.broadcast", json!(["00", "key"])),
+            ("blockchain.transaction.build_rbf", json!([TXID, 2.5, 1])),
+            ("blockchain.transaction.get", json!([TXID])),
+            ("blockchain.transaction.get_merkle", json!([TXID, 1, true])),
+            ("blockchain.transaction.mempool_info", json!([TXID])),
+            (
+                "blockchain.transaction.search_prefix",
+                json!(["5b75086d", 5]),
+            ),
+            ("blockchain.transaction.subscribe", json!([TXID])),
+            ("blockchain.transaction.unsubscribe", json!([TXID])),
+            ("blockchain.transaction.id_from_pos", json!([1, 0, false])),
+            ("blockchain.transactions.get", json!([[TXID], true])),
+            ("mempool.get_fee_histogram", json!([])),
+            (
+                "server.add_peer",
+                json!([{"genesis_hash": TXID, "hosts": {"example.org": {"tcp_port": 50001}}}]),
+            ),
+            ("server.banner", json!([])),
+            ("server.bans.list", json!([])),
+            ("server.bans.unban", json!(["127.0.0.1"])),
+            ("server.cache.flush", json!(["transactions"])),
+            ("server.cache.stats", json!([])),
+            ("server.codec", json!(["cbor"])),
+            ("server.donation_address", json!([])),
+            ("server.features", json!([])),
+            ("server.history_deltas", json!([true])),
+            ("server.notifications.debounce", json!([100])),
+            ("server.info", json!([])),
+            ("server.orphaned", json!([])),
+            ("server.strict_params", json!([true])),
+            ("server.sync_status", json!([])),
+            ("server.peers.subscribe", json!([])),
+            ("server.ping", json!([])),
+            ("proofs.export", json!([[[TXID, 1]], 10])),
+            ("server.version", json!(["electrum", "1.4"])),
+            ("server.webhooks.stats", json!([])),
+            ("server.broadcast_mirrors.stats", json!([])),
+            ("wallet.descriptor.info", json!(["wpkh(...)", 10])),
+        ];
+        // every method is covered
+        assert_eq!(valid.len(), SIGNATURES.len());
+        for method in ADMIN_METHODS {
+            assert!(signature(method).is_some(), "{}", method);
+        }
+        for signature in SIGNATURES {
+            assert!(valid.iter().any(|(method, _)| *method == signature.method));
+        }
+        fn is_unexpected(result: std::result::Result<Params, StandardError>) -> bool {
+            matches!(result, Err(StandardError::UnexpectedParams(_)))
+        }
+        for (method, params) in valid {
+            assert!(
+                Params::parse_strict(method, params.clone()).is_ok(),
+                "{}",
+                method
+            );
+
+            let args = match params.clone() {
+                Value::Null => vec![],
+                Value::Array(args) => args,
+                _ => unreachable!(),
+            };
+            let mut extra = args.clone();
+            extra.extend(vec![json!(null); 7]); // more than any method's maximal arity
+            assert!(
+                is_unexpected(Params::parse_strict(method, json!(extra))),
+                "{}",
+                method
+            );
+
+            let named = json!({"params": params});
+            assert!(
+                is_unexpected(Params::parse_strict(method, named)),
+                "{}",
+                method
+            );
+
+            // the same parameters, named as in the signature
+            let named: serde_json::Map<String, Value> = signature(method)
+                .unwrap()
+                .names()
+                .map(str::to_owned)
+                .zip(args)
+                .collect();
+            assert!(Params::parse(method, json!(named)).is_ok(), "{}", method);
+            assert!(
+                Params::parse_strict(method, json!(named)).is_ok(),
+                "{}",
+                method
+            );
+        }
+
+        // named parameters must be known, and the required ones can't be omitted
+        let invalid_named = vec![
+            (
+                "blockchain.scripthash.get_balance",
+                json!({"scripthash": SCRIPTHASH, "extra": true}),
+            ),
+            (
+                "blockchain.scripthash.get_balance",
+                json!({"verbose": true}),
+            ),
+            (
+                "blockchain.scripthash.get_history",
+                json!({"include_tx_pos": true}),
+            ),
+            ("server.ping", json!({"extra": true})),
+        ];
+        for (method, params) in invalid_named {
+            assert!(
+                matches!(
+                    Params::parse(method, params.clone()),
+                    Err(StandardError::InvalidParams)
+                ),
+                "{}",
+                method
+            );
+            assert!(
+                is_unexpected(Params::parse_strict(method, params)),
+                "{}",
+                method
+            );
+        }
+        let named = json!({"scripthash": SCRIPTHASH, "include_mempool_spent": true});
+        assert!(Params::parse("blockchain.scripthash.listunspent", named).is_ok());
+
+        // the optional parameters omitted before a specified one are set to their defaults
+        let positional = |method, named| signature(method).unwrap().positional(named).unwrap();
+        assert_eq!(
+            positional(
+                "blockchain.scripthash.listunspent",
+                json!({"scripthash": SCRIPTHASH, "safe_only": true})
+            ),
+            json!([SCRIPTHASH, false, false, true])
+        );
+        let named = json!({"scripthash": SCRIPTHASH, "safe_only": true});
+        match Params::parse_strict("blockchain.scripthash.listunspent", named) {
+            Ok(Params::ScriptHashListUnspent(args)) => {
+                let args: (ScriptHash, bool, bool, bool) = (&args).into();
+                assert_eq!(args, (SCRIPTHASH.parse().unwrap(), false, false, true));
+            }
+            _ => panic!("invalid listunspent params"),
+        }
+        assert_eq!(
+            positional(
+                "blockchain.scripthash.get_history",
+                json!({"scripthash": SCRIPTHASH, "include_tx_pos": true})
+            ),
+            json!([SCRIPTHASH, false, true])
+        );
+        let named = json!({
+            "scripthash": SCRIPTHASH,
+            "amounts": [1000],
+            "min_amount": 0,
+            "confirmed": true,
+            "max_inputs": 5,
+        });
+        match Params::parse_strict("blockchain.scripthash.select_unspent", named) {
+            Ok(Params::ScriptHashSelectUnspent(args)) => {
+                assert_eq!(args.max_inputs(), Some(5));
+                // the same as omitting them
+                assert_eq!(args.mode(), SelectMode::Confirmed);
+                assert!(!args.ignore_package_limits() && !args.include_script_pubkey());
+                assert_eq!((args.fee_rate(), args.strategy()), (None, None));
+            }
+            _ => panic!("invalid select_unspent params"),
+        }
+
+        // leniently parsed (and ignored) parameters are rejected in strict mode
+        let lenient = vec![
+            ("blockchain.headers.subscribe", json!(["true"])),
+            ("mempool.get_fee_histogram", json!([1])),
+            ("server.banner", json!(["extra"])),
+            ("server.ping", json!("extra")),
+        ];
+        for (method, params) in lenient {
+            assert!(Params::parse(method, params.clone()).is_ok(), "{}", method);
+            assert!(
+                is_unexpected(Params::parse_strict(method, params)),
+                "{}",
+                method
+            );
+        }
+
+        // missing and mistyped parameters
+        let method = "blockchain.scripthash.get_balance";
+        assert!(is_unexpected(Params::parse_strict(method, json!([]))));
+        assert!(is_unexpected(Params::parse_strict(
+            method,
+            json!([SCRIPTHASH, 1])
+        )));
+
+        let err = match Params::parse_strict(method, json!([SCRIPTHASH, true, true])) {
+            Err(err) => RpcError::Standard(err).to_value(),
+            Ok(_) => panic!("unexpected params"),
+        };
+        assert_eq!(
+            err,
+            json!({
+                "code": -32602,
+                "message": "invalid params (expected blockchain.scripthash.get_balance(scripthash, verbose?))",
+            })
+        );
+        match Params::parse_strict("unknown.method", json!([])) {
+            Err(StandardError::MethodNotFound) => (),
+            _ => panic!("unexpected result"),
+        }
+    }
+
+    #[test]
+    fn test_unknown_method_with_escapes() {
+        let method = "\u{1b}[2J\u{1b}[31m".repeat(10_000);
+        let request: Request =
+            serde_json::from_value(json!({"id": 1, "method": method, "params": []})).unwrap();
+        let response = match Call::parse(request, false) {
+            Err(response) => response.to_string(),
+            Ok(_) => panic!("unexpected method"),
+        };
+        // the response is valid JSON, and doesn't echo the method
+        let response: Value = serde_json::from_str(&response).unwrap();
+        assert_eq!(
+            response,
+            json!({"jsonrpc": "2.0", "id": 1, "error": {"code": -32601, "message": "method not found"}})
+        );
+    }
+
+    fn options(fee_rate: f64, strategy: SelectStrategy) -> SelectOptions {
+        SelectOptions {
+            fee_rate,
+            strategy,
+            bnb_tolerance: Amount::from_sat(546),
+            max_inputs: 20,
+            pad_inputs: true,
+        }
+    }
+
+    fn fee_rate(fee_rate: f64) -> SelectOptions {
+        options(fee_rate, SelectStrategy::Default)
+    }
+
+    #[test]
+    fn test_select_reservations() {
+        let reservations = Reservations::new(Some(Duration::from_secs(60)), &Metrics::dummy());
+        let now = Instant::now();
+        let scripthash = ScriptHash::from_byte_array([1; 32]);
+        let utxos = vec![utxo(100, 1, 1000), utxo(100, 2, 2000), utxo(100, 3, 3000)];
+        let select = |client: &Client| {
+            let mut utxos = utxos.clone();
+            let outpoints = utxos.iter().map(UnspentEntry::outpoint);
+            exclude_outpoints(&mut utxos, &reservations.reserved(outpoints, now));
+            let options = SelectOptions {
+                pad_inputs: false,
+                ..options(0.0, SelectStrategy::Default)
+            };
+            let (selected, _) = select_for_amounts(utxos, &[1500], &options).unwrap();
+            let outpoints: Vec<OutPoint> = selected.iter().map(UnspentEntry::outpoint).collect();
+            let owner = client.reservation_owner(scripthash);
+            reservations.reserve(&owner, outpoints.iter().copied(), now);
+            outpoints
+        };
+        let (first, second) = (Client::default(), Client::default());
+        let selected = select(&first);
+        assert_eq!(selected, vec![utxos[1].outpoint()]);
+        // concurrent clients select other UTXOs
+        assert_eq!(select(&second), vec![utxos[2].outpoint()]);
+
+        // only the selecting client may release its UTXOs (for the same scripthash)
+        let release = |client: &Client, scripthash: ScriptHash| {
+            let owner = client.reservation_owner(scripthash);
+            reservations.release(&owner, selected.iter().copied(), now)
+        };
+        assert_eq!(release(&second, scripthash), 0);
+        assert_eq!(release(&first, ScriptHash::from_byte_array([2; 32])), 0);
+        assert_eq!(release(&first, scripthash), 1);
+        assert_eq!(select(&first), selected);
+
+        // the reservations are dropped when their client disconnects
+        first.cancel.cancel();
+        second.cancel.cancel();
+        assert_eq!(select(&Client::default()), selected);
+    }
+
+    #[test]
+    fn test_select_for_amounts() {
+        let utxos: Vec<UnspentEntry> = (1..=10)
+            .map(|n| utxo(100, n, u64::from(n) * 1000))
+            .collect();
+        // the selections for the first amount aren't sorted by position
+        let selected = select_for_amounts(utxos.clone(), &[15000], &fee_rate(0.0))
+            .unwrap()
+            .0;
+        let amounts: Vec<u64> = selected.iter().map(|utxo| utxo.value.to_sat()).collect();
+        assert_eq!(amounts, vec![10000, 9000, 1000]);
+
+        // each UTXO is selected at most once (the last amount uses exactly the remaining ones)
+        let selected = select_for_amounts(utxos.clone(), &[15000, 15000, 14000], &fee_rate(0.0))
+            .unwrap()
+            .0;
+        let amounts: Vec<u64> = selected.iter().map(|utxo| utxo.value.to_sat()).collect();
+        assert_eq!(
+            amounts,
+            vec![10000, 9000, 1000, 8000, 7000, 6000, 5000, 4000, 3000, 2000]
+        );
+        let outpoints: HashSet<OutPoint> = selected.iter().map(UnspentEntry::outpoint).collect();
+        assert_eq!(outpoints.len(), selected.len());
+
+        // the UTXOs selected for the previous amounts are not available
+        let err =
+            select_for_amounts(utxos.clone(), &[15000, 15000, 14001], &fee_rate(0.0)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "insufficient funds for 14001 sat: missing 1 sat"
+        );
+        let err = select_for_amounts(utxos.clone(), &[100_000], &fee_rate(0.0)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "insufficient funds for 100000 sat: missing 45000 sat"
+        );
+
+        // a zero amount is always funded
+        let selected = select_for_amounts(utxos, &[0], &fee_rate(0.0)).unwrap().0;
+        let amounts: Vec<u64> = selected.iter().map(|utxo| utxo.value.to_sat()).collect();
+        assert_eq!(amounts, vec![1000, 2000, 3000]);
+        assert!(select_for_amounts(vec![], &[0], &fee_rate(0.0))
+            .unwrap()
+            .0
+            .is_empty());
+    }
+
+    #[test]
+    fn test_select_prefer_confirmed() {
+        let mut confirmed = vec![utxo(100, 1, 1000), utxo(101, 2, 2000)];
+        // sorted by fee rate
+        let mut unconfirmed = vec![utxo(0, 3, 5000), utxo(0, 4, 3000)];
+
+        // confirmed UTXOs are sufficient
+        let selected = select_prefer_confirmed(
+            &mut confirmed.clone(),
+            &mut unconfirmed.clone(),
+            Amount::from_sat(1500),
+            &fee_rate(0.0),
+        )
+        .unwrap()
+        .0;
+        assert_eq!(values(&selected), vec![(100, 1000), (101, 2000)]);
+
+        // partial fallback to unconfirmed UTXOs
+        let selected = select_prefer_confirmed(
+            &mut confirmed,
+            &mut unconfirmed,
+            Amount::from_sat(4000),
+            &fee_rate(0.0),
+        )
+        .unwrap()
+        .0;
+        assert_eq!(values(&selected), vec![(100, 1000), (101, 2000), (0, 5000)]);
+        assert!(confirmed.is_empty());
+        assert_eq!(values(&unconfirmed), vec![(0, 3000)]);
+
+        // insufficient even with unconfirmed UTXOs
+        let err = select_prefer_confirmed(
+            &mut confirmed,
+            &mut unconfirmed,
+            Amount::from_sat(4000),
+            &fee_rate(0.0),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "insufficient funds for 4000 sat: missing 1000 sat"
+        );
+    }
+
+    #[test]
+    fn test_select_with_fee_rate() {
+        let utxos: Vec<UnspentEntry> = (1..=10)
+            .map(|n| utxo(100, n, u64::from(n) * 1000))
+            .collect();
+        assert_eq!(input_fee(10.0, 3), Amount::from_sat(2040));
+        assert_eq!(input_fee(0.5, 1), Amount::from_sat(34));
+        assert_eq!(input_fee(0.0, 20), Amount::from_sat(0));
+
+        // the target is increased by the selected inputs' fee (3 inputs, 2040 sat)
+        let selected = select_for_amounts(utxos.clone(), &[8500], &fee_rate(0.0))
+            .unwrap()
+            .0;
+        let amounts: Vec<u64> = selected.iter().map(|utxo| utxo.value.to_sat()).collect();
+        assert_eq!(amounts, vec![1000, 9000, 10000]);
+        let selected = select_for_amounts(utxos.clone(), &[8500], &fee_rate(10.0))
+            .unwrap()
+            .0;
+        let amounts: Vec<u64> = selected.iter().map(|utxo| utxo.value.to_sat()).collect();
+        assert_eq!(amounts, vec![10000, 9000, 1000]);
+        let selected = select_for_amounts(utxos.clone(), &[7500], &fee_rate(0.0))
+            .unwrap()
+            .0;
+        let amounts: Vec<u64> = selected.iter().map(|utxo| utxo.value.to_sat()).collect();
+        assert_eq!(amounts, vec![1000, 8000, 9000]);
+        let selected = select_for_amounts(utxos.clone(), &[7500], &fee_rate(10.0))
+            .unwrap()
+            .0;
+        let amounts: Vec<u64> = selected.iter().map(|utxo| utxo.value.to_sat()).collect();
+        assert_eq!(amounts, vec![1000, 9000, 10000]);
+
+        // the inputs' fee may make the UTXOs insufficient
+        let err = select_for_amounts(utxos.clone(), &[50_000], &fee_rate(10.0)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "insufficient funds for 50000 sat (and 6800 sat of input fees): missing 1800 sat"
+        );
+
+        // confirmed UTXOs are sufficient only without the inputs' fee
+        let mut confirmed = vec![utxo(100, 1, 1000), utxo(101, 2, 2000)];
+        let mut unconfirmed = vec![utxo(0, 3, 5000)];
+        let selected = select_prefer_confirmed(
+            &mut confirmed.clone(),
+            &mut unconfirmed.clone(),
+            Amount::from_sat(2900),
+            &fee_rate(0.0),
+        )
+        .unwrap()
+        .0;
+        assert_eq!(values(&selected), vec![(100, 1000), (101, 2000)]);
+        let selected = select_prefer_confirmed(
+            &mut confirmed,
+            &mut unconfirmed,
+            Amount::from_sat(2900),
+            &fee_rate(1.0),
+        )
+        .unwrap()
+        .0;
+        assert_eq!(values(&selected), vec![(100, 1000), (101, 2000), (0, 5000)]);
+
+        let args = json!([SCRIPTHASH, [1000], 0, true, "any", false, false, 1.5]);
+        let args: SelectUnspentArgs = serde_json::from_value(args).unwrap();
+        let utxos = vec![utxo(0, 1, 1000).to_wire(), utxo(0, 2, 2000).to_wire()];
+        let result = select_result(utxos, &args, &[]);
+        assert_eq!(result["input_vsize"], 136);
+        assert_eq!(result["input_fee"], 204);
+        assert_eq!(result["utxos"].as_array().unwrap().len(), 2);
+        assert!(result.get("strategies").is_none());
+        let args: SelectUnspentArgs =
+            serde_json::from_value(json!([SCRIPTHASH, [1000], 0, true])).unwrap();
+        assert_eq!(select_result(vec![], &args, &[]), json!([]));
+    }
+
+    #[test]
+    fn test_select_amount_overflow() {
+        let parse = |args: Value| -> SelectUnspentArgs { serde_json::from_value(args).unwrap() };
+        let max_money = Amount::MAX_MONEY.to_sat();
+        assert!(validate_select_args(&parse(json!([SCRIPTHASH, [max_money], 0, true]))).is_ok());
+        for args in vec![
+            json!([SCRIPTHASH, [1000, max_money + 1], 0, true]),
+            json!([SCRIPTHASH, [u64::MAX], 0, true]),
+            json!([SCRIPTHASH, [1000], 0, true, "any", false, false, 1e18]),
+            json!([SCRIPTHASH, [1000], 0, true, "any", false, false, -1.0]),
+        ] {
+            assert!(validate_select_args(&parse(args)).is_err());
+        }
+
+        // huge amounts and fees are reported as errors (instead of panicking)
+        let utxos: Vec<UnspentEntry> = (1..=5).map(|n| utxo(100, n, 1000)).collect();
+        let err = select_for_amounts(utxos.clone(), &[u64::MAX], &fee_rate(1.0)).unwrap_err();
+        assert!(err.to_string().contains("amount overflow"), "{}", err);
+        let err = select_for_amounts(utxos.clone(), &[u64::MAX - 100], &fee_rate(0.0)).unwrap_err();
+        assert!(err.to_string().contains("insufficient funds"), "{}", err);
+        let err = select_prefer_confirmed(
+            &mut utxos.clone(),
+            &mut vec![utxo(0, 6, 1000)],
+            Amount::from_sat(u64::MAX),
+            &fee_rate(1.0),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("amount overflow"), "{}", err);
+
+        let target = Amount::from_sat(u64::MAX);
+        assert_eq!(select_largest_first(&utxos, target, 1.0, 3).len(), 3);
+        let largest_first = options(1.0, SelectStrategy::LargestFirst);
+        let err = select_for_amounts(utxos, &[u64::MAX], &largest_first).unwrap_err();
+        assert!(err.to_string().contains("amount overflow"), "{}", err);
+    }
+
+    #[test]
+    fn test_select_strategies() {
+        let mut utxos: Vec<UnspentEntry> = (1..=10)
+            .map(|n| utxo(100, n, u64::from(n) * 1000))
+            .chain(std::iter::once(utxo(0, 11, 2500)))
+            .collect();
+        utxos.sort_by_key(|utxo| utxo.value);
+        let select = |amounts: &[u64], options: &SelectOptions| {
+            let (selected, strategies) =
+                select_for_amounts(utxos.clone(), amounts, options).unwrap();
+            let amounts: Vec<u64> = selected.iter().map(|utxo| utxo.value.to_sat()).collect();
+            (amounts, strategies)
+        };
+
+        // an exact match, without change
+        assert_eq!(
+            select(&[12000], &options(0.0, SelectStrategy::Bnb)),
+            (vec![10000, 2000], vec![SelectStrategy::Bnb])
+        );
+        // within the tolerance (the unconfirmed UTXO is not used)
+        assert_eq!(
+            select(&[2500], &options(0.0, SelectStrategy::Bnb)),
+            (vec![3000], vec![SelectStrategy::Bnb])
+        );
+        // falls back to the default strategy if there's no match
+        let mut exact = options(0.0, SelectStrategy::Bnb);
+        exact.bnb_tolerance = Amount::from_sat(0);
+        assert_eq!(
+            select(&[2500, 12000], &exact),
+            (
+                vec![1000, 2500, 3000, 10000, 2000],
+                vec![SelectStrategy::Default, SelectStrategy::Bnb]
+            )
+        );
+        // the inputs' fee is accounted for (680 sat per input)
+        assert_eq!(
+            select(&[11320], &options(10.0, SelectStrategy::Bnb)),
+            (vec![10000, 3000], vec![SelectStrategy::Bnb])
+        );
+
+        assert_eq!(
+            select(&[15000], &options(0.0, SelectStrategy::LargestFirst)),
+            (vec![10000, 9000], vec![SelectStrategy::LargestFirst])
+        );
+        assert_eq!(
+            select(&[18100], &options(10.0, SelectStrategy::LargestFirst)),
+            (vec![10000, 9000, 8000], vec![SelectStrategy::LargestFirst])
+        );
+
+        // the search is bounded, even if there are too many combinations to try
+        let utxos: Vec<UnspentEntry> = (0..2000).map(|n| utxo(100, n as u8, 1000)).collect();
+        assert_eq!(select_bnb(&utxos, Amount::from_sat(2500), &exact), None);
+    }
+
+    #[test]
+    fn test_select_max_inputs() {
+        let utxos: Vec<UnspentEntry> = (1..=30).map(|n| utxo(100, n, 1000)).collect();
+        let select = |amounts: &[u64], options: &SelectOptions| {
+            select_for_amounts(utxos.clone(), amounts, options).map(|(selected, _)| selected.len())
+        };
+        // the default limit (20 inputs) is configurable
+        let err = select(&[20_500], &fee_rate(0.0)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "can't fund 20500 sat within 20 inputs: the largest UTXOs total 20000 sat (with 0 sat of input fees)"
+        );
+        let mut sweep = fee_rate(0.0);
+        sweep.max_inputs = 25;
+        assert_eq!(select(&[20_500], &sweep).unwrap(), 21);
+        sweep.strategy = SelectStrategy::LargestFirst;
+        assert_eq!(select(&[20_500], &sweep).unwrap(), 21);
+        let mut limited = options(0.0, SelectStrategy::Bnb);
+        limited.max_inputs = 2;
+        assert!(select(&[3000], &limited).is_err());
+        assert_eq!(select(&[2000], &limited).unwrap(), 2);
+        // insufficient funds are reported as such
+        let err = select(&[40_000], &sweep).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "insufficient funds for 40000 sat: missing 10000 sat"
+        );
 
+        let utxos: Vec<UnspentEntry> = (1..=10)
+            .map(|n| utxo(100, n, u64::from(n) * 1000))
+            .collect();
+        let select = |amount: u64, options: &SelectOptions| {
+            let (selected, _) = select_for_amounts(utxos.clone(), &[amount], options).unwrap();
+            let amounts: Vec<u64> = selected.iter().map(|utxo| utxo.value.to_sat()).collect();
+            amounts
+        };
+        // a single UTXO is enough, so the selection isn't padded
+        let mut unpadded = fee_rate(0.0);
+        unpadded.pad_inputs = false;
+        assert_eq!(select(500, &fee_rate(0.0)), vec![1000, 2000, 3000]);
+        assert_eq!(select(500, &unpadded), vec![1000]);
+        assert_eq!(select(7500, &unpadded), vec![8000]);
+        assert_eq!(select(15000, &unpadded), vec![10000, 9000]);
+        // padding is skipped if it would exceed the limit
+        let mut limited = fee_rate(0.0);
+        limited.max_inputs = 2;
+        assert_eq!(select(500, &limited), vec![1000]);
+
+        let (mut confirmed, mut unconfirmed) = (utxos[..5].to_vec(), vec![utxo(0, 11, 500)]);
+        let err = select_prefer_confirmed(
+            &mut confirmed,
+            &mut unconfirmed,
+            Amount::from_sat(10_000),
+            &limited,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "can't fund 10000 sat within 2 inputs: the largest UTXOs total 9000 sat (with 0 sat of input fees)"
+        );
+
+        let args = json!([
+            SCRIPTHASH,
+            [1000],
+            0,
+            true,
+            "any",
+            false,
+            false,
+            0.0,
+            "default",
+            2
+        ]);
+        let args: SelectUnspentArgs = serde_json::from_value(args).unwrap();
+        let utxos = vec![utxo(0, 1, 1000).to_wire(), utxo(0, 2, 2000).to_wire()];
+        let result = select_result(utxos, &args, &[SelectStrategy::Default]);
+        assert_eq!(result["inputs"], 2);
+        assert_eq!(result["total_value"], 3000);
+    }
+}
@@ -0,0 +1,277 @@
+use anyhow::Result;
+use serde_json::{json, Map, Value};
+
+use std::fmt;
+
+/// Application error codes, returned to clients in the JSON-RPC `error` object (see
+/// `CodedError::to_value()`) and advertised via `server.features` (see `registry()`).
+///
+/// The codes (and their names) are part of the protocol, so they must never be changed or reused:
+/// new categories get new codes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ErrorCode {
+    BadRequest = 1,
+    DaemonError = 2,
+    DaemonUnreachable = 3,
+    HistoryTooLarge = 10,
+    RateLimited = 11,
+    NotPermitted = 12,
+    SubscriptionLimit = 13,
+    ReorgedRetry = 14,
+    TxNotFound = 15,
+    BlockPruned = 16,
+    CapabilityDisabled = 17,
+    ResourceLimit = 18,
+    // Internal JSON-RPC error (https://www.jsonrpc.org/specification#error_object)
+    StaleIndex = -32603,
+}
+
+impl ErrorCode {
+    pub(crate) const ALL: [ErrorCode; 13] = [
+        ErrorCode::BadRequest,
+        ErrorCode::DaemonError,
+        ErrorCode::DaemonUnreachable,
+        ErrorCode::HistoryTooLarge,
+        ErrorCode::RateLimited,
+        ErrorCode::NotPermitted,
+        ErrorCode::SubscriptionLimit,
+        ErrorCode::ReorgedRetry,
+        ErrorCode::TxNotFound,
+        ErrorCode::BlockPruned,
+        ErrorCode::CapabilityDisabled,
+        ErrorCode::ResourceLimit,
+        ErrorCode::StaleIndex,
+    ];
+
+    pub(crate) fn code(self) -> i32 {
+        self as i32
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            ErrorCode::BadRequest => "bad-request",
+            ErrorCode::DaemonError => "daemon-error",
+            ErrorCode::DaemonUnreachable => "daemon-unreachable",
+            ErrorCode::HistoryTooLarge => "history-too-large",
+            ErrorCode::RateLimited => "rate-limited",
+            ErrorCode::NotPermitted => "not-permitted",
+            ErrorCode::SubscriptionLimit => "subscription-limit",
+            ErrorCode::ReorgedRetry => "reorged-retry",
+            ErrorCode::TxNotFound => "tx-not-found",
+            ErrorCode::BlockPruned => "block-pruned",
+            ErrorCode::CapabilityDisabled => "capability-disabled",
+            ErrorCode::ResourceLimit => "resource-limit",
+            ErrorCode::StaleIndex => "stale-index",
+        }
+    }
+
+    /// The fields of the error's `data` object (which is omitted if there are none)
+    pub(crate) fn data_fields(self) -> &'static [&'static str] {
+        match self {
+            ErrorCode::BadRequest | ErrorCode::DaemonUnreachable | ErrorCode::StaleIndex => &[],
+            ErrorCode::DaemonError => &["daemon_code"],
+            ErrorCode::HistoryTooLarge => &["limit"],
+            ErrorCode::RateLimited => &["retry_after_secs"],
+            ErrorCode::NotPermitted => &["method"],
+            ErrorCode::SubscriptionLimit => &["memory_usage", "budget"],
+            ErrorCode::ReorgedRetry => &["txid", "height"],
+            ErrorCode::TxNotFound => &["txid"],
+            ErrorCode::BlockPruned => &["blockhash"],
+            ErrorCode::CapabilityDisabled => &["method", "option"],
+            ErrorCode::ResourceLimit => &["limit"],
+        }
+    }
+}
+
+/// The error codes' registry, as advertised via `server.features`
+pub(crate) fn registry() -> Value {
+    let codes: Map<String, Value> = ErrorCode::ALL
+        .iter()
+        .map(|code| {
+            let entry = json!({"code": code.code(), "data": code.data_fields()});
+            (code.name().to_owned(), entry)
+        })
+        .collect();
+    Value::Object(codes)
+}
+
+/// An error with a stable code, and (optionally) machine-readable details: the message is meant
+/// for humans, so clients shouldn't need to parse it.
+#[derive(Clone, Debug)]
+pub(crate) struct CodedError {
+    code: ErrorCode,
+    message: String,
+    data: Option<Value>,
+}
+
+impl CodedError {
+    pub(crate) fn new(code: ErrorCode, message: String) -> Self {
+        Self {
+            code,
+            message,
+            data: None,
+        }
+    }
+
+    /// `data` should contain the fields listed by `ErrorCode::data_fields()`
+    pub(crate) fn with_data(mut self, data: Value) -> Self {
+        self.data = Some(data);
+        self
+    }
+
+    pub(crate) fn code(&self) -> ErrorCode {
+        self.code
+    }
+
+    pub(crate) fn to_value(&self) -> Value {
+        let mut value = json!({"code": self.code.code(), "message": self.message});
+        if let Some(data) = &self.data {
+            value["data"] = data.clone();
+        }
+        value
+    }
+}
+
+impl fmt::Display for CodedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for CodedError {}
+
+/// Returns the first coded error in `err`'s chain (if any), so it can be wrapped with context.
+pub(crate) fn find_coded(err: &anyhow::Error) -> Option<&CodedError> {
+    err.chain()
+        .find_map(|cause| cause.downcast_ref::<CodedError>())
+}
+
+/// Fails if an optional RPC method is disabled (using the given command-line option).
+pub(crate) fn ensure_enabled(enabled: bool, method: &str, option: &str) -> Result<()> {
+    if enabled {
+        return Ok(());
+    }
+    let message = format!("{} is disabled (see --{})", method, option);
+    let err = CodedError::new(ErrorCode::CapabilityDisabled, message)
+        .with_data(json!({"method": method, "option": option}));
+    Err(err.into())
+}
+
+/// Fails unless the client connected via the admin RPC address (see `--electrum-admin-addr`).
+pub(crate) fn ensure_admin(admin: bool, method: &str) -> Result<()> {
+    if admin {
+        return Ok(());
+    }
+    let message = format!(
+        "{} is only allowed on the admin RPC address (see --electrum-admin-addr)",
+        method
+    );
+    let err =
+        CodedError::new(ErrorCode::NotPermitted, message).with_data(json!({"method": method}));
+    Err(err.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ensure_admin, ensure_enabled, find_coded, registry, CodedError, ErrorCode};
+    use anyhow::Context;
+    use serde_json::json;
+
+    #[test]
+    fn test_codes() {
+        // these must never change, since clients may depend on them
+        let codes: Vec<(i32, &str)> = ErrorCode::ALL
+            .iter()
+            .map(|code| (code.code(), code.name()))
+            .collect();
+        assert_eq!(
+            codes,
+            vec![
+                (1, "bad-request"),
+                (2, "daemon-error"),
+                (3, "daemon-unreachable"),
+                (10, "history-too-large"),
+                (11, "rate-limited"),
+                (12, "not-permitted"),
+                (13, "subscription-limit"),
+                (14, "reorged-retry"),
+                (15, "tx-not-found"),
+                (16, "block-pruned"),
+                (17, "capability-disabled"),
+                (18, "resource-limit"),
+                (-32603, "stale-index"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_registry() {
+        assert_eq!(
+            registry().to_string(),
+            concat!(
+                r#"{"bad-request":{"code":1,"data":[]},"#,
+                r#""block-pruned":{"code":16,"data":["blockhash"]},"#,
+                r#""capability-disabled":{"code":17,"data":["method","option"]},"#,
+                r#""daemon-error":{"code":2,"data":["daemon_code"]},"#,
+                r#""daemon-unreachable":{"code":3,"data":[]},"#,
+                r#""history-too-large":{"code":10,"data":["limit"]},"#,
+                r#""not-permitted":{"code":12,"data":["method"]},"#,
+                r#""rate-limited":{"code":11,"data":["retry_after_secs"]},"#,
+                r#""reorged-retry":{"code":14,"data":["txid","height"]},"#,
+                r#""resource-limit":{"code":18,"data":["limit"]},"#,
+                r#""stale-index":{"code":-32603,"data":[]},"#,
+                r#""subscription-limit":{"code":13,"data":["memory_usage","budget"]},"#,
+                r#""tx-not-found":{"code":15,"data":["txid"]}}"#,
+            )
+        );
+    }
+
+    #[test]
+    fn test_serialization() {
+        let err = CodedError::new(ErrorCode::HistoryTooLarge, "too many entries".to_owned());
+        assert_eq!(
+            err.to_value().to_string(),
+            r#"{"code":10,"message":"too many entries"}"#
+        );
+        let err = err.with_data(json!({"limit": 100}));
+        assert_eq!(
+            err.to_value().to_string(),
+            r#"{"code":10,"data":{"limit":100},"message":"too many entries"}"#
+        );
+    }
+
+    #[test]
+    fn test_find_coded() {
+        let err = ensure_enabled(false, "server.cache.stats", "cache-management-rpc")
+            .context("failed")
+            .unwrap_err();
+        let coded = find_coded(&err).unwrap();
+        assert_eq!(coded.code(), ErrorCode::CapabilityDisabled);
+        assert_eq!(
+            coded.to_value(),
+            json!({
+                "code": 17,
+                "message": "server.cache.stats is disabled (see --cache-management-rpc)",
+                "data": {"method": "server.cache.stats", "option": "cache-management-rpc"},
+            })
+        );
+        assert!(ensure_enabled(true, "server.cache.stats", "cache-management-rpc").is_ok());
+        assert!(find_coded(&anyhow!("failed")).is_none());
+    }
+
+    #[test]
+    fn test_ensure_admin() {
+        let err = ensure_admin(false, "server.bans.unban").unwrap_err();
+        let coded = find_coded(&err).unwrap();
+        assert_eq!(coded.code(), ErrorCode::NotPermitted);
+        assert_eq!(
+            coded.to_value(),
+            json!({
+                "code": 12,
+                "message": "server.bans.unban is only allowed on the admin RPC address (see --electrum-admin-addr)",
+                "data": {"method": "server.bans.unban"},
+            })
+        );
+        assert!(ensure_admin(true, "server.bans.unban").is_ok());
+    }
+}
@@ -0,0 +1,491 @@
+//! Export the confirmed history of some scripthashes into an SQLite database, for offline analysis:
+//!
+//! ```text
+//! electrs export --scripthash <hex> [--scripthashes-file <path>] --out history.sqlite [options]
+//! ```
+//!
+//! The remaining options (e.g. `--network` or `--db-dir`) are handled like electrs' own.
+//! The index DB is opened for reading only, so it may be exported while the server is running.
+//! Since the index stores only block heights, the relevant blocks are fetched from bitcoind.
+//! An interrupted export is resumed by re-running it: already exported scripthashes are skipped.
+
+use anyhow::{Context, Result};
+use bitcoin::{Amount, BlockHash, OutPoint};
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    cache::Cache,
+    chain::Chain,
+    config::Config,
+    consistency,
+    daemon::Daemon,
+    db::DBStore,
+    index::Index,
+    mempool::Mempool,
+    metrics::{Metrics, MetricsListener, MetricsOptions},
+    signals::Signal,
+    status::{ConfirmedTx, ScriptHashStatus, UnspentEntry},
+    types::ScriptHash,
+};
+
+const SCHEMA_VERSION: u32 = 1;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS meta (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS scripthashes (
+    scripthash TEXT PRIMARY KEY,
+    tip TEXT NOT NULL,
+    height INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS txs (
+    scripthash TEXT NOT NULL,
+    txid TEXT NOT NULL,
+    height INTEGER NOT NULL,
+    PRIMARY KEY (scripthash, txid)
+);
+CREATE TABLE IF NOT EXISTS funding (
+    scripthash TEXT NOT NULL,
+    txid TEXT NOT NULL,
+    vout INTEGER NOT NULL,
+    value INTEGER NOT NULL,
+    PRIMARY KEY (scripthash, txid, vout)
+);
+CREATE TABLE IF NOT EXISTS spending (
+    scripthash TEXT NOT NULL,
+    txid TEXT NOT NULL,
+    prev_txid TEXT NOT NULL,
+    prev_vout INTEGER NOT NULL,
+    PRIMARY KEY (scripthash, prev_txid, prev_vout)
+);
+CREATE TABLE IF NOT EXISTS utxos (
+    scripthash TEXT NOT NULL,
+    txid TEXT NOT NULL,
+    vout INTEGER NOT NULL,
+    value INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    PRIMARY KEY (scripthash, txid, vout)
+);
+";
+
+struct ExportArgs {
+    scripthashes: Vec<ScriptHash>,
+    scripthash_files: Vec<PathBuf>,
+    out: PathBuf,
+    config_args: Vec<OsString>, // starting with the program name
+}
+
+fn next_value(args: &mut impl Iterator<Item = OsString>, flag: &str) -> Result<String> {
+    let value = args
+        .next()
+        .with_context(|| format!("missing value for {}", flag))?;
+    value
+        .into_string()
+        .map_err(|value| anyhow!("invalid value for {}: {:?}", flag, value))
+}
+
+fn parse_scripthash(value: &str) -> Result<ScriptHash> {
+    value
+        .trim()
+        .parse()
+        .with_context(|| format!("invalid scripthash: {}", value))
+}
+
+/// Split the export-specific args from the ones passed to `Config`.
+fn parse_args(mut args: impl Iterator<Item = OsString>) -> Result<ExportArgs> {
+    let mut config_args: Vec<OsString> = args.next().into_iter().collect();
+    ensure!(
+        args.next().map_or(false, |arg| arg == "export"),
+        "missing `export` subcommand"
+    );
+    let mut scripthashes = vec![];
+    let mut scripthash_files = vec![];
+    let mut out = None;
+    while let Some(arg) = args.next() {
+        if arg == "--scripthash" {
+            scripthashes.push(parse_scripthash(&next_value(&mut args, "--scripthash")?)?);
+        } else if arg == "--scripthashes-file" {
+            scripthash_files.push(next_value(&mut args, "--scripthashes-file")?.into());
+        } else if arg == "--out" {
+            out = Some(next_value(&mut args, "--out")?.into());
+        } else {
+            config_args.push(arg);
+        }
+    }
+    ensure!(
+        !scripthashes.is_empty() || !scripthash_files.is_empty(),
+        "no scripthashes to export (use --scripthash or --scripthashes-file)"
+    );
+    Ok(ExportArgs {
+        scripthashes,
+        scripthash_files,
+        out: out.context("missing output path (use --out)")?,
+        config_args,
+    })
+}
+
+/// Read scripthashes from a file (one per line, skipping empty lines and `#` comments).
+fn read_scripthashes(path: &Path) -> Result<Vec<ScriptHash>> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(parse_scripthash)
+        .collect()
+}
+
+/// Create the schema (if missing), verifying the version and network of an existing database.
+fn open_output(path: &Path, network: &str) -> Result<Connection> {
+    let conn =
+        Connection::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    conn.execute_batch(SCHEMA)?;
+    let get_meta = |key: &str| -> Result<Option<String>> {
+        Ok(conn
+            .query_row(
+                "SELECT value FROM meta WHERE key = ?",
+                params![key],
+                |row| row.get(0),
+            )
+            .optional()?)
+    };
+    match get_meta("schema_version")? {
+        Some(version) => ensure!(
+            version == SCHEMA_VERSION.to_string(),
+            "unsupported schema version {} != {} (use a new output path)",
+            version,
+            SCHEMA_VERSION
+        ),
+        None => {
+            conn.execute(
+                "INSERT INTO meta (key, value) VALUES ('schema_version', ?), ('network', ?)",
+                params![SCHEMA_VERSION.to_string(), network],
+            )?;
+        }
+    }
+    let exported_network = get_meta("network")?.unwrap_or_default();
+    ensure!(
+        exported_network == network,
+        "{} was exported from the {} network, not {}",
+        path.display(),
+        exported_network,
+        network
+    );
+    Ok(conn)
+}
+
+fn is_exported(conn: &Connection, scripthash: &ScriptHash) -> Result<bool> {
+    Ok(conn
+        .query_row(
+            "SELECT 1 FROM scripthashes WHERE scripthash = ?",
+            params![scripthash.to_string()],
+            |_row| Ok(()),
+        )
+        .optional()?
+        .is_some())
+}
+
+/// Write a single scripthash's history (marking it as exported).
+fn write_status(
+    db_tx: &Transaction,
+    scripthash: &ScriptHash,
+    (tip, height): (BlockHash, usize),
+    txs: &[ConfirmedTx],
+    utxos: &[UnspentEntry],
+) -> Result<()> {
+    let scripthash = scripthash.to_string();
+    let outpoint = |outpoint: &OutPoint| (outpoint.txid.to_string(), outpoint.vout);
+    let value = |amount: &Amount| amount.to_sat() as i64;
+    for table in &["txs", "funding", "spending", "utxos", "scripthashes"] {
+        db_tx.execute(
+            &format!("DELETE FROM {} WHERE scripthash = ?", table),
+            params![scripthash],
+        )?;
+    }
+    for tx in txs {
+        let txid = tx.txid.to_string();
+        db_tx.execute(
+            "INSERT INTO txs (scripthash, txid, height) VALUES (?, ?, ?)",
+            params![scripthash, txid, tx.height as i64],
+        )?;
+        for (funded, amount) in &tx.funding {
+            db_tx.execute(
+                "INSERT INTO funding (scripthash, txid, vout, value) VALUES (?, ?, ?, ?)",
+                params![scripthash, txid, funded.vout, value(amount)],
+            )?;
+        }
+        for spent in tx.spending {
+            let (prev_txid, prev_vout) = outpoint(spent);
+            db_tx.execute(
+                "INSERT INTO spending (scripthash, txid, prev_txid, prev_vout) VALUES (?, ?, ?, ?)",
+                params![scripthash, txid, prev_txid, prev_vout],
+            )?;
+        }
+    }
+    for utxo in utxos {
+        let (txid, vout) = outpoint(&utxo.outpoint());
+        db_tx.execute(
+            "INSERT INTO utxos (scripthash, txid, vout, value, height) VALUES (?, ?, ?, ?, ?)",
+            params![
+                scripthash,
+                txid,
+                vout,
+                value(&utxo.value),
+                utxo.height as i64
+            ],
+        )?;
+    }
+    db_tx.execute(
+        "INSERT INTO scripthashes (scripthash, tip, height) VALUES (?, ?, ?)",
+        params![scripthash, tip.to_string(), height as i64],
+    )?;
+    Ok(())
+}
+
+fn export(args: impl Iterator<Item = OsString>) -> Result<()> {
+    let args = parse_args(args)?;
+    let mut scripthashes = args.scripthashes;
+    for path in &args.scripthash_files {
+        scripthashes.extend(read_scripthashes(path)?);
+    }
+    let config = Config::from_custom_args(args.config_args);
+    let metrics = Metrics::new(&MetricsOptions {
+        listener: MetricsListener::Disabled,
+        auth: None,
+    })?;
+    let signal = Signal::new();
+    let daemon = Daemon::connect(&config, signal.exit_flag(), &metrics)?;
+
+    let store = DBStore::open_read_only(&config.db_path, config.db_profile)?;
+    consistency::verify(&store, config.network, &daemon)?;
+    let index = Index::load(
+        store,
+        Chain::new(config.network),
+        &metrics,
+        config.index_batch_size,
+        config.index_lookup_limit,
+        0,     // keep all indexed blocks
+        false, // no block notifications
+    )
+    .context("failed to open index")?;
+    let chain = index.chain();
+    let mempool = Mempool::new(&metrics); // empty, since only confirmed history is exported
+    let cache = Cache::new(&metrics);
+
+    let mut conn = open_output(&args.out, &config.network.to_string())?;
+    info!(
+        "exporting {} scripthashes into {} (indexed up to height {})",
+        scripthashes.len(),
+        args.out.display(),
+        chain.height()
+    );
+    for (i, scripthash) in scripthashes.iter().enumerate() {
+        signal.exit_flag().poll()?;
+        if is_exported(&conn, scripthash)? {
+            info!(
+                "{}/{}: {} already exported",
+                i + 1,
+                scripthashes.len(),
+                scripthash
+            );
+            continue;
+        }
+        let mut status = ScriptHashStatus::new(*scripthash);
+        status.sync(&index, &mempool, &daemon, &cache, None)?;
+        let txs = status.get_confirmed_txs(chain);
+        let utxos = status.get_unspent(chain);
+
+        let db_tx = conn.transaction()?;
+        write_status(
+            &db_tx,
+            scripthash,
+            (chain.tip(), chain.height()),
+            &txs,
+            &utxos,
+        )?;
+        db_tx.commit()?;
+        info!(
+            "{}/{}: {} exported ({} transactions, {} UTXOs)",
+            i + 1,
+            scripthashes.len(),
+            scripthash,
+            txs.len(),
+            utxos.len()
+        );
+    }
+    Ok(())
+}
+
+/// Run the `export` subcommand (using the process' command-line args).
+pub fn run() -> Result<()> {
+    export(std::env::args_os()).context("export failed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_exported, open_output, parse_args, read_scripthashes, write_status};
+    use crate::status::{ConfirmedTx, UnspentEntry};
+    use crate::types::ScriptHash;
+    use bitcoin::{hashes::Hash, Amount, BlockHash, OutPoint, Script, Txid};
+    use std::ffi::OsString;
+    use std::fs;
+    use std::path::PathBuf;
+
+    const SCRIPTHASH: &str = "4b3d912c1523ece4615e91bf0d27381ca72169dbf6b1c2ffcc9f92381d4984a3";
+
+    fn args(values: &[&str]) -> impl Iterator<Item = OsString> {
+        let values: Vec<OsString> = values.iter().map(OsString::from).collect();
+        values.into_iter()
+    }
+
+    fn txid(n: u8) -> Txid {
+        format!("{:064x}", n).parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let parsed = parse_args(args(&[
+            "electrs",
+            "export",
+            "--network",
+            "regtest",
+            "--scripthash",
+            SCRIPTHASH,
+            "--out",
+            "history.sqlite",
+            "--scripthashes-file",
+            "wallet.txt",
+        ]))
+        .unwrap();
+        assert_eq!(parsed.scripthashes.len(), 1);
+        assert_eq!(parsed.scripthashes[0].to_string(), SCRIPTHASH);
+        assert_eq!(parsed.scripthash_files, vec![PathBuf::from("wallet.txt")]);
+        assert_eq!(parsed.out.to_str(), Some("history.sqlite"));
+        assert_eq!(
+            parsed.config_args,
+            args(&["electrs", "--network", "regtest"]).collect::<Vec<_>>()
+        );
+
+        let err = parse_args(args(&["electrs", "export", "--out", "history.sqlite"]))
+            .err()
+            .unwrap();
+        assert!(err.to_string().starts_with("no scripthashes"), "{}", err);
+
+        let err = parse_args(args(&["electrs", "export", "--scripthash", "abc"]))
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "invalid scripthash: abc");
+
+        let err = parse_args(args(&["electrs", "export", "--scripthash", SCRIPTHASH]))
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "missing output path (use --out)");
+
+        let err = parse_args(args(&["electrs", "export", "--out"]))
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "missing value for --out");
+    }
+
+    #[test]
+    fn test_read_scripthashes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("scripthashes.txt");
+        fs::write(
+            &path,
+            format!("# wallet\n{}\n\n  {}  \n", SCRIPTHASH, SCRIPTHASH),
+        )
+        .unwrap();
+        assert_eq!(read_scripthashes(&path).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_output_schema() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.sqlite");
+        {
+            let conn = open_output(&path, "regtest").unwrap();
+            let version: String = conn
+                .query_row(
+                    "SELECT value FROM meta WHERE key = 'schema_version'",
+                    rusqlite::NO_PARAMS,
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert_eq!(version, "1");
+        }
+        open_output(&path, "regtest").unwrap(); // re-opening an export (e.g. to resume it)
+
+        let err = open_output(&path, "bitcoin").err().unwrap();
+        assert!(err
+            .to_string()
+            .ends_with("was exported from the regtest network, not bitcoin"));
+    }
+
+    #[test]
+    fn test_write_status() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut conn = open_output(&dir.path().join("history.sqlite"), "regtest").unwrap();
+        let scripthash = ScriptHash::new(Script::empty());
+        assert!(!is_exported(&conn, &scripthash).unwrap());
+
+        let spent = [OutPoint::new(txid(1), 0)];
+        let txs = vec![
+            ConfirmedTx {
+                height: 1,
+                txid: txid(1),
+                funding: vec![(OutPoint::new(txid(1), 0), Amount::from_sat(1000))],
+                spending: &[],
+            },
+            ConfirmedTx {
+                height: 2,
+                txid: txid(2),
+                funding: vec![(OutPoint::new(txid(2), 1), Amount::from_sat(500))],
+                spending: &spent,
+            },
+        ];
+        let utxos = vec![UnspentEntry::new(2, txid(2), 1, Amount::from_sat(500))];
+        for _ in 0..2 {
+            // re-exporting replaces the previous rows
+            let db_tx = conn.transaction().unwrap();
+            write_status(
+                &db_tx,
+                &scripthash,
+                (BlockHash::all_zeros(), 2),
+                &txs,
+                &utxos,
+            )
+            .unwrap();
+            db_tx.commit().unwrap();
+        }
+        assert!(is_exported(&conn, &scripthash).unwrap());
+
+        let count = |table: &str| -> i64 {
+            conn.query_row(
+                &format!("SELECT COUNT(*) FROM {}", table),
+                rusqlite::NO_PARAMS,
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+        assert_eq!(count("txs"), 2);
+        assert_eq!(count("funding"), 2);
+        assert_eq!(count("spending"), 1);
+        assert_eq!(count("utxos"), 1);
+        let (prev_txid, prev_vout): (String, u32) = conn
+            .query_row(
+                "SELECT prev_txid, prev_vout FROM spending WHERE txid = ?",
+                &[txid(2).to_string()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((prev_txid, prev_vout), (txid(1).to_string(), 0));
+    }
+}
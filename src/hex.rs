@@ -0,0 +1,170 @@
+use anyhow::Result;
+use bitcoin::{hashes::Hash, BlockHash, Txid};
+use serde::de::{Deserialize, Deserializer, Error as _};
+
+use std::fmt;
+
+use crate::types::ScriptHash;
+
+/// Hex decoding failure (the offending parameter is named by the caller)
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Error {
+    OddLength(usize),
+    InvalidChar(usize),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::OddLength(len) => write!(f, "odd-length hex string ({} characters)", len),
+            Error::InvalidChar(pos) => write!(f, "non-hex character at position {}", pos),
+        }
+    }
+}
+
+/// Decode a hex string (either lower or upper case is accepted).
+pub(crate) fn decode(value: &str) -> std::result::Result<Vec<u8>, Error> {
+    let bytes = value.as_bytes();
+    if bytes.len() % 2 != 0 {
+        return Err(Error::OddLength(bytes.len()));
+    }
+    let nibble = |pos: usize| {
+        (bytes[pos] as char)
+            .to_digit(16)
+            .map(|d| d as u8)
+            .ok_or(Error::InvalidChar(pos))
+    };
+    (0..bytes.len())
+        .step_by(2)
+        .map(|pos| -> std::result::Result<u8, Error> { Ok(nibble(pos)? << 4 | nibble(pos + 1)?) })
+        .collect()
+}
+
+/// Encode bytes as a lower-case hex string.
+pub(crate) fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parse a hex-encoded RPC parameter, naming it in case of an error.
+pub(crate) fn parse_bytes(name: &str, value: &str) -> Result<Vec<u8>> {
+    decode(value).map_err(|err| anyhow!("invalid {}: {}", name, err))
+}
+
+/// Parse a hex-encoded hash RPC parameter (using its display byte order).
+pub(crate) fn parse_hash<H: Hash>(name: &str, value: &str) -> Result<H> {
+    let mut bytes = parse_bytes(name, value)?;
+    if bytes.len() != H::LEN {
+        bail!(
+            "invalid {}: expected {} hex characters, got {}",
+            name,
+            H::LEN * 2,
+            value.len()
+        );
+    }
+    if H::DISPLAY_BACKWARD {
+        bytes.reverse();
+    }
+    Ok(H::from_slice(&bytes).expect("invalid hash length"))
+}
+
+/// Hash types which may be passed as RPC parameters
+pub(crate) trait ParamHash: Hash {
+    const NAME: &'static str;
+}
+
+impl ParamHash for Txid {
+    const NAME: &'static str = "txid";
+}
+
+impl ParamHash for BlockHash {
+    const NAME: &'static str = "blockhash";
+}
+
+impl ParamHash for ScriptHash {
+    const NAME: &'static str = "scripthash";
+}
+
+/// Hash RPC parameter, deserialized via `parse_hash()`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct HashParam<H>(pub H);
+
+impl<'de, H: ParamHash> Deserialize<'de> for HashParam<H> {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = String::deserialize(deserializer)?;
+        parse_hash(H::NAME, &value)
+            .map(HashParam)
+            .map_err(D::Error::custom)
+    }
+}
+
+/// Serialized transaction RPC parameter, deserialized via `parse_bytes()`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct BytesParam(pub Vec<u8>);
+
+impl<'de> Deserialize<'de> for BytesParam {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = String::deserialize(deserializer)?;
+        parse_bytes("raw_tx", &value)
+            .map(BytesParam)
+            .map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, parse_hash, Error};
+    use bitcoin::Txid;
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode(""), Ok(vec![]));
+        assert_eq!(decode("00ff7A"), Ok(vec![0x00, 0xff, 0x7a]));
+        assert_eq!(decode("ABCDEF"), Ok(vec![0xab, 0xcd, 0xef]));
+        assert_eq!(decode("abc"), Err(Error::OddLength(3)));
+        assert_eq!(decode("0g"), Err(Error::InvalidChar(1)));
+        assert_eq!(decode("+1"), Err(Error::InvalidChar(0)));
+        assert_eq!(decode("é"), Err(Error::InvalidChar(0)));
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode(&[]), "");
+        assert_eq!(encode(&[0x00, 0xff, 0x7a]), "00ff7a");
+        assert_eq!(decode(&encode(&[0xab, 0xcd, 0xef])), Ok(vec![0xab, 0xcd, 0xef]));
+    }
+
+    #[test]
+    fn test_parse_hash() {
+        let lower = "5b75086dafeede555fc8f9a810d8b10df57c46f9f176ccc3dd8d2fa20edd685b";
+        let expected: Txid = lower.parse().unwrap();
+        assert_eq!(parse_hash::<Txid>("txid", lower).unwrap(), expected);
+        assert_eq!(
+            parse_hash::<Txid>("txid", &lower.to_uppercase()).unwrap(),
+            expected
+        );
+        assert_eq!(
+            parse_hash::<Txid>("txid", &lower[1..])
+                .unwrap_err()
+                .to_string(),
+            "invalid txid: odd-length hex string (63 characters)"
+        );
+        assert_eq!(
+            parse_hash::<Txid>("txid", &lower[2..])
+                .unwrap_err()
+                .to_string(),
+            "invalid txid: expected 64 hex characters, got 62"
+        );
+        assert_eq!(
+            parse_hash::<Txid>("txid", &lower.replace("5b", "xx"))
+                .unwrap_err()
+                .to_string(),
+            "invalid txid: non-hex character at position 0"
+        );
+    }
+}
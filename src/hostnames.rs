@@ -0,0 +1,159 @@
+use anyhow::{Context, Result};
+use serde_json::Value;
+
+use std::{collections::HashMap, fs, path::Path};
+
+/// Responses for a specific hostname (unset fields use the defaults)
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct HostOverrides {
+    banner: Option<String>,
+    donation_address: Option<String>,
+    hosts: Option<Value>, // advertised by `server.features`
+}
+
+/// Per-hostname overrides, loaded from a JSON object keyed by hostname
+#[derive(Default)]
+pub(crate) struct Hostnames {
+    overrides: HashMap<String, HostOverrides>,
+}
+
+/// Hostnames are case-insensitive, and may be fully-qualified (with a trailing dot).
+pub(crate) fn normalize(hostname: &str) -> String {
+    hostname.trim_end_matches('.').to_ascii_lowercase()
+}
+
+impl Hostnames {
+    pub(crate) fn open(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(Self::default()),
+        };
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("failed to read hostnames from {}", path.display()))?;
+        let hostnames = Self::parse(&content)
+            .with_context(|| format!("invalid hostnames in {}", path.display()))?;
+        info!(
+            "loaded overrides for {} hostnames",
+            hostnames.overrides.len()
+        );
+        Ok(hostnames)
+    }
+
+    fn parse(content: &str) -> Result<Self> {
+        let entries: HashMap<String, HostOverrides> = serde_json::from_str(content)?;
+        let mut overrides = HashMap::with_capacity(entries.len());
+        for (hostname, entry) in entries {
+            if let Some(hosts) = &entry.hosts {
+                ensure!(hosts.is_object(), "{}: hosts must be an object", hostname);
+            }
+            let key = normalize(&hostname);
+            ensure!(
+                overrides.insert(key, entry).is_none(),
+                "{}: duplicate hostname",
+                hostname
+            );
+        }
+        Ok(Self { overrides })
+    }
+
+    /// The overrides for the client's hostname (`None` for unknown or missing hostnames)
+    fn get(&self, hostname: Option<&str>) -> Option<&HostOverrides> {
+        self.overrides.get(&normalize(hostname?))
+    }
+
+    pub(crate) fn banner<'a>(&'a self, hostname: Option<&str>, default: &'a str) -> &'a str {
+        self.get(hostname)
+            .and_then(|overrides| overrides.banner.as_deref())
+            .unwrap_or(default)
+    }
+
+    pub(crate) fn donation_address(&self, hostname: Option<&str>) -> Option<&str> {
+        self.get(hostname)
+            .and_then(|overrides| overrides.donation_address.as_deref())
+    }
+
+    pub(crate) fn hosts(&self, hostname: Option<&str>, default: Value) -> Value {
+        self.get(hostname)
+            .and_then(|overrides| overrides.hosts.clone())
+            .unwrap_or(default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HostOverrides, Hostnames};
+    use serde_json::json;
+
+    #[test]
+    fn test_get() {
+        let content = json!({
+            "Electrum.Example.org": {"banner": "welcome", "hosts": {"e.example.org": {"ssl_port": 50002}}},
+            "other.example.org": {"donation_address": "bc1qexample"},
+        });
+        let hostnames = Hostnames::parse(&content.to_string()).unwrap();
+        let expected = HostOverrides {
+            banner: Some("welcome".to_owned()),
+            donation_address: None,
+            hosts: Some(json!({"e.example.org": {"ssl_port": 50002}})),
+        };
+        assert_eq!(hostnames.get(Some("electrum.example.org")), Some(&expected));
+        assert_eq!(
+            hostnames.get(Some("ELECTRUM.example.org.")),
+            Some(&expected)
+        );
+        assert_eq!(hostnames.get(Some("unknown.example.org")), None);
+        assert_eq!(hostnames.get(None), None);
+        assert_eq!(Hostnames::default().get(Some("electrum.example.org")), None);
+    }
+
+    #[test]
+    fn test_responses() {
+        let content = json!({
+            "a.example.org": {"banner": "Welcome to A", "donation_address": "bc1qa"},
+            "b.example.org": {"banner": "Welcome to B", "hosts": {"b.example.org": {"ssl_port": 50002}}},
+        });
+        let hostnames = Hostnames::parse(&content.to_string()).unwrap();
+        let default_hosts = json!({"tcp_port": 50001});
+
+        let a = Some("a.example.org");
+        assert_eq!(hostnames.banner(a, "default"), "Welcome to A");
+        assert_eq!(hostnames.donation_address(a), Some("bc1qa"));
+        assert_eq!(hostnames.hosts(a, default_hosts.clone()), default_hosts);
+
+        let b = Some("b.example.org");
+        assert_eq!(hostnames.banner(b, "default"), "Welcome to B");
+        assert_eq!(hostnames.donation_address(b), None);
+        assert_eq!(
+            hostnames.hosts(b, default_hosts.clone()),
+            json!({"b.example.org": {"ssl_port": 50002}})
+        );
+
+        // unknown hostnames and plain TCP connections (without SNI) use the defaults
+        for hostname in &[Some("c.example.org"), None] {
+            assert_eq!(hostnames.banner(*hostname, "default"), "default");
+            assert_eq!(hostnames.donation_address(*hostname), None);
+            assert_eq!(
+                hostnames.hosts(*hostname, default_hosts.clone()),
+                default_hosts
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid() {
+        let invalid = [
+            json!(["electrum.example.org"]),
+            json!({"electrum.example.org": {"motd": "hi"}}),
+            json!({"electrum.example.org": {"hosts": 50002}}),
+            json!({"a.example.org": {}, "A.example.org": {}}),
+        ];
+        for content in &invalid {
+            assert!(
+                Hostnames::parse(&content.to_string()).is_err(),
+                "{}",
+                content
+            );
+        }
+    }
+}
@@ -0,0 +1,99 @@
+//! Minimal HTTP/1.1 client helpers, used for delivering events to external endpoints.
+
+use anyhow::{Context, Result};
+
+use std::{
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+pub(crate) const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An `http://` URL (TLS is not supported: use a local proxy for `https://` endpoints)
+pub(crate) struct Url {
+    pub(crate) authority: String, // `host:port`
+    pub(crate) path: String,
+}
+
+impl Url {
+    pub(crate) fn parse(url: &str) -> Result<Self> {
+        let rest = match url.strip_prefix("http://") {
+            Some(rest) => rest,
+            None if url.starts_with("https://") => bail!(
+                "unsupported URL {}: TLS is not supported (use a local TLS-terminating proxy)",
+                url
+            ),
+            None => bail!("unsupported URL {} (expected http://)", url),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        ensure!(!authority.is_empty(), "missing host in URL {}", url);
+        let authority = if authority.ends_with(']') || !authority.contains(':') {
+            format!("{}:80", authority)
+        } else {
+            authority.to_owned()
+        };
+        Ok(Self {
+            authority,
+            path: path.to_owned(),
+        })
+    }
+
+    /// Connect to the URL's host, with read and write timeouts.
+    pub(crate) fn connect(&self) -> Result<TcpStream> {
+        let addr = self
+            .authority
+            .to_socket_addrs()
+            .with_context(|| format!("failed to resolve {}", self.authority))?
+            .next()
+            .with_context(|| format!("no address for {}", self.authority))?;
+        let stream = TcpStream::connect_timeout(&addr, HTTP_TIMEOUT)
+            .with_context(|| format!("failed to connect to {}", addr))?;
+        stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+        stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+        Ok(stream)
+    }
+}
+
+/// Parse the status code of an HTTP response's status line.
+pub(crate) fn parse_status(status_line: &str) -> Result<u16> {
+    status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .with_context(|| format!("invalid response: {:?}", status_line.trim_end()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_status, Url};
+
+    #[test]
+    fn test_url() {
+        let url = Url::parse("http://localhost:8080/path/hook").unwrap();
+        assert_eq!(url.authority, "localhost:8080");
+        assert_eq!(url.path, "/path/hook");
+        let url = Url::parse("http://example.com").unwrap();
+        assert_eq!(url.authority, "example.com:80");
+        assert_eq!(url.path, "/");
+        let url = Url::parse("http://[::1]/").unwrap();
+        assert_eq!(url.authority, "[::1]:80");
+        let err = Url::parse("https://example.com/").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "unsupported URL https://example.com/: TLS is not supported (use a local TLS-terminating proxy)"
+        );
+        assert!(Url::parse("ftp://example.com/").is_err());
+        assert!(Url::parse("http:///hook").is_err());
+    }
+
+    #[test]
+    fn test_parse_status() {
+        assert_eq!(parse_status("HTTP/1.1 200 OK\r\n").unwrap(), 200);
+        assert_eq!(parse_status("HTTP/1.0 503\r\n").unwrap(), 503);
+        assert!(parse_status("").is_err());
+        assert!(parse_status("HTTP/1.1 OK\r\n").is_err());
+    }
+}
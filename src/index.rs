@@ -1,14 +1,20 @@
 use anyhow::{Context, Result};
 use bitcoin::consensus::{deserialize, serialize};
 use bitcoin::{Block, BlockHash, OutPoint, Txid};
+use serde_json::json;
 
 use crate::{
     chain::{Chain, NewHeader},
     daemon::Daemon,
     db::{DBStore, Row, WriteBatch},
+    errors::{CodedError, ErrorCode},
+    hex,
     metrics::{self, Gauge, Histogram, Metrics},
     signals::ExitFlag,
-    types::{HashPrefixRow, HeaderRow, ScriptHash, ScriptHashRow, SpendingPrefixRow, TxidRow},
+    types::{
+        BlockTxidsRow, HashPrefixRow, HeaderRow, ScriptHash, ScriptHashRow, SpenderRow,
+        SpendingPrefixRow, TxidPrefixRow, TxidRow,
+    },
 };
 
 #[derive(Clone)]
@@ -51,7 +57,22 @@ impl Stats {
         self.observe_size("write_funding_rows", &batch.funding_rows);
         self.observe_size("write_spending_rows", &batch.spending_rows);
         self.observe_size("write_txid_rows", &batch.txid_rows);
+        self.observe_size("write_txid_prefix_rows", &batch.txid_prefix_rows);
         self.observe_size("write_header_rows", &batch.header_rows);
+        let block_txids_size: usize = batch
+            .block_txids_rows
+            .iter()
+            .map(|(key, value)| key.len() + value.len())
+            .sum();
+        self.update_size
+            .observe("write_block_txids_rows", block_txids_size as f64);
+        let spender_size: usize = batch
+            .spender_rows
+            .iter()
+            .map(|(key, value)| key.len() + value.len())
+            .sum();
+        self.update_size
+            .observe("write_spender_rows", spender_size as f64);
         debug!(
             "writing {} funding and {} spending rows from {} transactions, {} blocks",
             batch.funding_rows.len(),
@@ -78,6 +99,9 @@ struct IndexResult {
     funding_rows: Vec<HashPrefixRow>,
     spending_rows: Vec<HashPrefixRow>,
     txid_rows: Vec<HashPrefixRow>,
+    txid_prefix_rows: Vec<TxidPrefixRow>,
+    spender_rows: Vec<(Row, Row)>,
+    txids: Vec<Txid>,
 }
 
 impl IndexResult {
@@ -91,11 +115,122 @@ impl IndexResult {
         let txid_rows = self.txid_rows.iter().map(HashPrefixRow::to_db_row);
         batch.txid_rows.extend(txid_rows);
 
+        let txid_prefix_rows = self.txid_prefix_rows.iter().map(TxidPrefixRow::to_db_row);
+        batch.txid_prefix_rows.extend(txid_prefix_rows);
+
+        batch.spender_rows.extend(self.spender_rows.iter().cloned());
+
         batch.header_rows.push(self.header_row.to_db_row());
         batch.tip_row = serialize(&self.header_row.header.block_hash()).into_boxed_slice();
     }
 }
 
+/// Daemon queries used for indexing new blocks
+pub(crate) trait BlockDaemon {
+    fn new_headers(&self, chain: &Chain) -> Result<Vec<NewHeader>>;
+    fn blocks(
+        &self,
+        blockhashes: Vec<BlockHash>,
+        func: &mut dyn FnMut(BlockHash, Block),
+    ) -> Result<()>;
+}
+
+impl BlockDaemon for Daemon {
+    fn new_headers(&self, chain: &Chain) -> Result<Vec<NewHeader>> {
+        self.get_new_headers(chain)
+    }
+
+    fn blocks(
+        &self,
+        blockhashes: Vec<BlockHash>,
+        func: &mut dyn FnMut(BlockHash, Block),
+    ) -> Result<()> {
+        self.for_blocks(blockhashes, func)
+    }
+}
+
+/// Serves a chain of blocks (for testing), counting the fetched ones
+#[cfg(test)]
+pub(crate) struct MockBlockDaemon {
+    genesis: bitcoin::block::Header,
+    blocks: Vec<Block>, // starting at height 1
+    fetched: std::cell::Cell<usize>,
+}
+
+#[cfg(test)]
+impl MockBlockDaemon {
+    pub(crate) fn new(genesis: bitcoin::block::Header) -> Self {
+        Self {
+            genesis,
+            blocks: vec![],
+            fetched: Default::default(),
+        }
+    }
+
+    /// Append a new block, containing the given transactions
+    pub(crate) fn push(&mut self, txdata: Vec<bitcoin::Transaction>) -> BlockHash {
+        let mut header = self.genesis;
+        header.prev_blockhash = self
+            .blocks
+            .last()
+            .map_or(self.genesis.block_hash(), Block::block_hash);
+        self.blocks.push(Block { header, txdata });
+        header.block_hash()
+    }
+
+    pub(crate) fn blockhash(&self, height: usize) -> BlockHash {
+        self.blocks[height - 1].block_hash()
+    }
+
+    /// The number of blocks fetched so far
+    pub(crate) fn fetched(&self) -> usize {
+        self.fetched.get()
+    }
+}
+
+#[cfg(test)]
+impl BlockDaemon for MockBlockDaemon {
+    fn new_headers(&self, chain: &Chain) -> Result<Vec<NewHeader>> {
+        Ok(self
+            .blocks
+            .iter()
+            .enumerate()
+            .map(|(i, block)| NewHeader::from((block.header, i + 1)))
+            .skip(chain.height())
+            .collect())
+    }
+
+    fn blocks(
+        &self,
+        blockhashes: Vec<BlockHash>,
+        func: &mut dyn FnMut(BlockHash, Block),
+    ) -> Result<()> {
+        for blockhash in blockhashes {
+            let block = self
+                .blocks
+                .iter()
+                .find(|block| block.block_hash() == blockhash)
+                .expect("unknown block");
+            self.fetched.set(self.fetched.get() + 1);
+            func(blockhash, block.clone());
+        }
+        Ok(())
+    }
+}
+
+/// A change of the indexed chain, collected during sync (for `blockchain.block.txids.subscribe`)
+pub(crate) enum BlockEvent {
+    Connected {
+        height: usize,
+        hash: BlockHash,
+        txids: Vec<Txid>,
+    },
+    Disconnected {
+        height: usize,
+        hash: BlockHash,
+    },
+}
+
 /// Confirmed transactions' address index
 pub struct Index {
     store: DBStore,
@@ -104,6 +239,11 @@ pub struct Index {
     chain: Chain,
     stats: Stats,
     is_ready: bool,
+    daemon_height: Option<usize>,          // as of the last sync
+    block_events: Option<Vec<BlockEvent>>, // collected only if enabled
+    txid_prefix_index: bool,
+    block_txids_index: bool,
+    spender_index: bool,
 }
 
 impl Index {
@@ -114,6 +254,7 @@ impl Index {
         batch_size: usize,
         lookup_limit: Option<usize>,
         reindex_last_blocks: usize,
+        collect_block_events: bool,
     ) -> Result<Self> {
         if let Some(row) = store.get_tip() {
             let tip = deserialize(&row).expect("invalid tip");
@@ -128,6 +269,9 @@ impl Index {
         let stats = Stats::new(metrics);
         stats.observe_chain(&chain);
         stats.observe_db(&store);
+        let txid_prefix_index = store.has_txid_prefix_index();
+        let block_txids_index = store.has_block_txids_index();
+        let spender_index = store.has_spender_index();
         Ok(Index {
             store,
             batch_size,
@@ -135,9 +279,25 @@ impl Index {
             chain,
             stats,
             is_ready: false,
+            daemon_height: None,
+            block_events: if collect_block_events {
+                Some(vec![])
+            } else {
+                None
+            },
+            txid_prefix_index,
+            block_txids_index,
+            spender_index,
         })
     }
 
+    /// Returns the chain changes since the last call (empty if not enabled).
+    pub(crate) fn take_block_events(&mut self) -> Vec<BlockEvent> {
+        self.block_events
+            .as_mut()
+            .map_or_else(Vec::new, std::mem::take)
+    }
+
     pub(crate) fn chain(&self) -> &Chain {
         &self.chain
     }
@@ -149,7 +309,10 @@ impl Index {
             None => entries.by_ref().collect(),
         };
         if entries.next().is_some() {
-            bail!(">{} index entries, query may take too long", result.len())
+            let message = format!(">{} index entries, query may take too long", result.len());
+            let err = CodedError::new(ErrorCode::HistoryTooLarge, message)
+                .with_data(json!({ "limit": result.len() }));
+            return Err(err.into());
         }
         Ok(result)
     }
@@ -181,11 +344,73 @@ impl Index {
             .filter_map(move |height| self.chain.get_block_hash(height))
     }
 
+    /// Returns the indexed transactions whose txid starts with the given (lowercase) hex prefix.
+    /// The rows of blocks replaced by a reorg are not deleted, so the caller should check that
+    /// each candidate is still confirmed at its height.
+    pub(crate) fn search_txid_prefix<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> Result<impl Iterator<Item = (Txid, usize)> + 'a> {
+        ensure!(self.txid_prefix_index, "txid prefix index is not enabled");
+        let scan_prefix = hex::parse_bytes("prefix", &prefix[..prefix.len() - prefix.len() % 2])?;
+        let tip = self.chain.height();
+        Ok(self
+            .store
+            .iter_txid_prefix(scan_prefix.into_boxed_slice())
+            .map(|row| TxidPrefixRow::from_db_row(&row))
+            .filter(move |row| row.height() <= tip) // skip rows of blocks that are not indexed yet
+            .map(|row| (row.txid(), row.height()))
+            .filter(move |(txid, _height)| txid.to_string().starts_with(prefix)))
+    }
+
+    pub(crate) fn has_spender_index(&self) -> bool {
+        self.spender_index
+    }
+
+    /// The confirmed transaction spending the outpoint (with its height), if any.
+    /// Spends confirmed by blocks which were replaced by a reorg are ignored.
+    pub(crate) fn lookup_spender(&self, outpoint: OutPoint) -> Result<Option<(Txid, usize)>> {
+        ensure!(self.spender_index, "spender index is not enabled");
+        let spender = self
+            .store
+            .get_spender(&SpenderRow::key(outpoint))
+            .map(|value| SpenderRow::spender(&value));
+        Ok(spender.and_then(|(txid, blockhash)| {
+            let height = self.chain.get_block_height(&blockhash)?;
+            Some((txid, height))
+        }))
+    }
+
+    /// The block's txids (in their block order), if the block txids index is enabled and has them
+    /// (blocks indexed before enabling it are backfilled using `add_block_txids()`).
+    pub(crate) fn get_block_txids(&self, blockhash: BlockHash) -> Option<Vec<Txid>> {
+        if !self.block_txids_index {
+            return None;
+        }
+        self.store
+            .get_block_txids(&BlockTxidsRow::key(blockhash))
+            .map(|value| BlockTxidsRow::txids(&value))
+    }
+
+    /// Store the txids of an indexed block (if the block txids index is enabled).
+    pub(crate) fn add_block_txids(&self, blockhash: BlockHash, txids: &[Txid]) -> Result<()> {
+        if !self.block_txids_index || self.chain.get_block_height(&blockhash).is_none() {
+            return Ok(());
+        }
+        self.store
+            .put_block_txids(&BlockTxidsRow::key(blockhash), &BlockTxidsRow::value(txids))
+    }
+
     // Return `Ok(true)` when the chain is fully synced and the index is compacted.
-    pub(crate) fn sync(&mut self, daemon: &Daemon, exit_flag: &ExitFlag) -> Result<bool> {
+    pub(crate) fn sync(&mut self, daemon: &dyn BlockDaemon, exit_flag: &ExitFlag) -> Result<bool> {
         let new_headers = self
             .stats
-            .observe_duration("headers", || daemon.get_new_headers(&self.chain))?;
+            .observe_duration("headers", || daemon.new_headers(&self.chain))?;
+        self.daemon_height = Some(
+            new_headers
+                .last()
+                .map_or(self.chain.height(), |h| h.height()),
+        );
         match (new_headers.first(), new_headers.last()) {
             (Some(first), Some(last)) => {
                 let count = new_headers.len();
@@ -197,37 +422,112 @@ impl Index {
                 );
             }
             _ => {
-                self.store.flush(); // full compaction is performed on the first flush call
+                self.store.flush()?; // full compaction is performed on the first flush call
                 self.is_ready = true;
                 return Ok(true); // no more blocks to index (done for now)
             }
         }
+        // the txids of blocks replaced by a reorg are deleted with the first chunk
+        let mut stale_blockhashes: Vec<BlockHash> = if self.block_txids_index {
+            let first_height = new_headers[0].height();
+            let chain = &self.chain;
+            (first_height..=chain.height())
+                .filter_map(|height| chain.get_block_hash(height))
+                .collect()
+        } else {
+            vec![]
+        };
+        // block events are collected only after the initial sync
+        let mut events = vec![];
+        if self.is_ready && self.block_events.is_some() {
+            // blocks to be replaced by a reorg
+            let first_height = new_headers[0].height();
+            let chain = &self.chain;
+            events.extend((first_height..=chain.height()).rev().map(|height| {
+                BlockEvent::Disconnected {
+                    height,
+                    hash: chain.get_block_hash(height).unwrap(),
+                }
+            }));
+        }
+        let mut indexed = 0; // headers whose blocks were written to the DB
+        let mut result = Ok(());
         for chunk in new_headers.chunks(self.batch_size) {
-            exit_flag.poll().with_context(|| {
-                format!(
-                    "indexing interrupted at height: {}",
-                    chunk.first().unwrap().height()
-                )
-            })?;
-            self.sync_blocks(daemon, chunk)?;
+            result = exit_flag
+                .poll()
+                .with_context(|| {
+                    format!(
+                        "indexing interrupted at height: {}",
+                        chunk.first().unwrap().height()
+                    )
+                })
+                .and_then(|()| {
+                    let stale = std::mem::take(&mut stale_blockhashes);
+                    self.sync_blocks(daemon, chunk, &stale, &mut events)
+                });
+            if result.is_err() {
+                break;
+            }
+            indexed += chunk.len();
+        }
+        // each chunk is written atomically (with its tip), so the chain is updated only with the
+        // written blocks - and the rest will be re-indexed by the next sync
+        let mut new_headers = new_headers;
+        new_headers.truncate(indexed);
+        if !new_headers.is_empty() {
+            if let Some(block_events) = self.block_events.as_mut() {
+                block_events.extend(events);
+            }
         }
         self.chain.update(new_headers);
         self.stats.observe_chain(&self.chain);
+        result?;
         Ok(false) // sync is not done
     }
 
-    fn sync_blocks(&mut self, daemon: &Daemon, chunk: &[NewHeader]) -> Result<()> {
+    /// Block events are collected into `events`, only if the blocks are indexed.
+    /// The txids of the `stale` blocks are deleted atomically with the chunk's rows.
+    fn sync_blocks(
+        &mut self,
+        daemon: &dyn BlockDaemon,
+        chunk: &[NewHeader],
+        stale: &[BlockHash],
+        events: &mut Vec<BlockEvent>,
+    ) -> Result<()> {
         let blockhashes: Vec<BlockHash> = chunk.iter().map(|h| h.hash()).collect();
         let mut heights = chunk.iter().map(|h| h.height());
 
-        let mut batch = WriteBatch::default();
-        daemon.for_blocks(blockhashes, |_blockhash, block| {
+        let mut batch = WriteBatch {
+            stale_block_txids_keys: stale.iter().copied().map(BlockTxidsRow::key).collect(),
+            ..Default::default()
+        };
+        let txid_prefix_index = self.txid_prefix_index;
+        let block_txids_index = self.block_txids_index;
+        let spender_index = self.spender_index;
+        let collect_events = self.is_ready && self.block_events.is_some();
+        let mut connected = vec![];
+        let result = daemon.blocks(blockhashes, &mut |blockhash, block| {
             let height = heights.next().expect("unexpected block");
-            self.stats.observe_duration("block", || {
-                index_single_block(block, height).extend(&mut batch)
+            let indexed = self.stats.observe_duration("block", || {
+                index_single_block(block, height, txid_prefix_index, spender_index)
             });
+            indexed.extend(&mut batch);
+            if block_txids_index {
+                batch.block_txids_rows.push((
+                    BlockTxidsRow::key(blockhash),
+                    BlockTxidsRow::value(&indexed.txids),
+                ));
+            }
+            if collect_events {
+                connected.push(BlockEvent::Connected {
+                    height,
+                    hash: blockhash,
+                    txids: indexed.txids,
+                });
+            }
             self.stats.height.set("tip", height as f64);
-        })?;
+        });
+        result?;
         let heights: Vec<_> = heights.collect();
         assert!(
             heights.is_empty(),
@@ -237,27 +537,47 @@ impl Index {
         batch.sort();
         self.stats.observe_batch(&batch);
         self.stats
-            .observe_duration("write", || self.store.write(&batch));
+            .observe_duration("write", || self.store.write(&batch))?;
         self.stats.observe_db(&self.store);
+        events.extend(connected);
         Ok(())
     }
 
     pub(crate) fn is_ready(&self) -> bool {
         self.is_ready
     }
+
+    /// The daemon's best chain height, as of the last sync (if any)
+    pub(crate) fn daemon_height(&self) -> Option<usize> {
+        self.daemon_height
+    }
 }
 
 fn db_rows_size(rows: &[Row]) -> usize {
     rows.iter().map(|key| key.len()).sum()
 }
 
-fn index_single_block(block: Block, height: usize) -> IndexResult {
+fn index_single_block(
+    block: Block,
+    height: usize,
+    txid_prefix_index: bool,
+    spender_index: bool,
+) -> IndexResult {
     let mut funding_rows = Vec::with_capacity(block.txdata.iter().map(|tx| tx.output.len()).sum());
     let mut spending_rows = Vec::with_capacity(block.txdata.iter().map(|tx| tx.input.len()).sum());
     let mut txid_rows = Vec::with_capacity(block.txdata.len());
+    let mut txid_prefix_rows = Vec::new();
+    let mut spender_rows = Vec::new();
+    let mut txids = Vec::with_capacity(block.txdata.len());
+    let blockhash = block.block_hash();
 
     for tx in &block.txdata {
-        txid_rows.push(TxidRow::row(tx.txid(), height));
+        let txid = tx.txid();
+        txid_rows.push(TxidRow::row(txid, height));
+        if txid_prefix_index {
+            txid_prefix_rows.push(TxidPrefixRow::new(txid, height));
+        }
+        txids.push(txid);
 
         funding_rows.extend(
             tx.output
@@ -270,18 +590,112 @@ fn index_single_block(block: Block, height: usize) -> IndexResult {
         );
 
         if tx.is_coin_base() {
-            continue; // coinbase doesn't have inputs
+            continue; // coinbase doesn't have inputs (so it has no spending and spender rows)
         }
         spending_rows.extend(
             tx.input
                 .iter()
                 .map(|txin| SpendingPrefixRow::row(txin.previous_output, height)),
         );
+        if spender_index {
+            let value = SpenderRow::value(txid, blockhash);
+            spender_rows.extend(
+                tx.input
+                    .iter()
+                    .map(|txin| (SpenderRow::key(txin.previous_output), value.clone())),
+            );
+        }
     }
     IndexResult {
         funding_rows,
         spending_rows,
         txid_rows,
+        txid_prefix_rows,
+        spender_rows,
+        txids,
         header_row: HeaderRow::new(block.header),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{index_single_block, Index, MockBlockDaemon};
+    use crate::{
+        chain::{Chain, NewHeader},
+        db::{is_write_error, DBStore, DbProfile, WriteBatch},
+        metrics::Metrics,
+        signals::ExitFlag,
+    };
+    use bitcoin::{
+        absolute::LockTime, block::Header as BlockHeader, consensus::serialize, Block, Network,
+        OutPoint, ScriptBuf, Transaction, TxIn, TxOut,
+    };
+
+    #[test]
+    fn test_lookup_spender() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DBStore::open(dir.path(), DbProfile::default(), None, false).unwrap();
+        let store = store.set_spender_index(true, false).unwrap();
+        let chain = Chain::new(Network::Regtest);
+        let metrics = Metrics::dummy();
+        let mut index = Index::load(store, chain, &metrics, 10, None, 0, false).unwrap();
+
+        let coinbase = tx(OutPoint::null());
+        let funding = OutPoint::new(coinbase.txid(), 0);
+        let spender = tx(funding);
+        let mut header = *index.chain.get_block_header(0).unwrap();
+        header.prev_blockhash = index.chain.tip();
+        write_block(&index, header, vec![coinbase.clone(), spender.clone()], 1);
+        index.chain.update(vec![NewHeader::from((header, 1))]);
+
+        assert_eq!(
+            index.lookup_spender(funding).unwrap(),
+            Some((spender.txid(), 1))
+        );
+        assert_eq!(index.lookup_spender(OutPoint::null()).unwrap(), None); // coinbase input
+        let unspent = OutPoint::new(spender.txid(), 0);
+        assert_eq!(index.lookup_spender(unspent).unwrap(), None);
+
+        // the spending block is orphaned by a reorg (replacing it by a block without the spend)
+        header.time += 1;
+        write_block(&index, header, vec![coinbase], 1);
+        index.chain.update(vec![NewHeader::from((header, 1))]);
+        assert_eq!(index.lookup_spender(funding).unwrap(), None);
+    }
+
+    #[test]
+    fn test_sync_write_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DBStore::open(dir.path(), DbProfile::default(), None, false).unwrap();
+        let chain = Chain::new(Network::Regtest);
+        let metrics = Metrics::dummy();
+        let mut index = Index::load(store, chain, &metrics, 2, None, 0, false).unwrap();
+        let mut daemon = MockBlockDaemon::new(*index.chain.get_block_header(0).unwrap());
+        for _ in 0..5 {
+            daemon.push(vec![tx(OutPoint::null())]);
+        }
+        let exit_flag = ExitFlag::new();
+
+        // the disk becomes full after writing the first chunk ([1, 2]), failing the second one
+        index.store.inject_write_failures_after(1);
+        let err = index.sync(&daemon, &exit_flag).unwrap_err();
+        assert!(is_write_error(&err));
+        // the chain contains only the written blocks
+        assert_eq!(index.chain.height(), 2);
+        assert_eq!(index.chain.tip(), daemon.blockhash(2));
+        assert_eq!(index.store.get_tip(), Some(serialize(&daemon.blockhash(2))));
+
+        // the next sync fails without indexing anything
+        let err = index.sync(&daemon, &exit_flag).unwrap_err();
+        assert!(is_write_error(&err));
+        assert_eq!(index.chain.height(), 2);
+
+        // some space is freed, so the rest of the blocks are indexed
+        index.store.inject_write_failures(false);
+        assert!(!index.sync(&daemon, &exit_flag).unwrap());
+        assert_eq!(index.chain.height(), 5);
+        assert_eq!(index.chain.tip(), daemon.blockhash(5));
+        assert_eq!(index.store.get_tip(), Some(serialize(&daemon.blockhash(5))));
+        assert!(index.sync(&daemon, &exit_flag).unwrap()); // done
+    }
+}
@@ -9,22 +9,54 @@ extern crate serde_derive;
 
 extern crate configure_me;
 
+mod audit;
 mod cache;
+mod cancel;
 mod chain;
+mod clock;
+mod codec;
 mod config;
+mod config_compat;
+mod consistency;
 mod daemon;
 mod db;
+mod descriptor;
 mod electrum;
+mod errors;
+#[cfg(feature = "sqlite_export")]
+mod export;
+mod hex;
+mod hostnames;
+mod http;
 mod index;
+mod listener;
+mod lock_order;
 mod mempool;
 mod merkle;
 mod metrics;
+mod mirrors;
+mod orphans;
 mod p2p;
+mod peers;
+pub mod protocol;
+mod psbt;
+mod rbf;
+mod reputation;
+mod reservations;
+mod sanitize;
+mod scheduler;
 mod server;
 mod signals;
 mod status;
+mod status_cache;
 mod thread;
 mod tracker;
+mod txinfo;
 mod types;
+mod webhooks;
 
-pub use server::run;
+#[cfg(feature = "sqlite_export")]
+pub use export::run as run_export;
+pub use electrum::{HealthFlags, HealthReport};
+pub use merkle::{verify_chained_proof, ChainedProof};
+pub use server::{run, run_monitored, HealthMonitor};
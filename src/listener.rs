@@ -0,0 +1,400 @@
+use anyhow::{Context, Result};
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+
+use std::io::Read;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// PROXY protocol v2 signature (see https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt)
+const PROXY_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// PROXY protocol v2 TLV type of the hostname requested by the client (e.g. its TLS SNI)
+const PP2_TYPE_AUTHORITY: u8 = 0x02;
+
+/// TCP keepalive parameters, applied to accepted sockets
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Keepalive {
+    pub idle: Duration,
+    pub interval: Option<Duration>,
+    pub retries: Option<u32>,
+}
+
+/// Socket options of the Electrum RPC listeners
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ListenerOptions {
+    pub dual_stack: bool,
+    pub reuse_address: bool,
+    pub reuse_port: bool,
+    pub keepalive: Option<Keepalive>,
+    pub proxy_protocol: bool, // accepted connections start with a PROXY protocol v2 header
+}
+
+impl Default for ListenerOptions {
+    fn default() -> Self {
+        Self {
+            dual_stack: false,
+            reuse_address: true, // same as `std::net::TcpListener::bind()` on Unix
+            reuse_port: false,
+            keepalive: None,
+            proxy_protocol: false,
+        }
+    }
+}
+
+/// Bind the listening socket(s) for `addr`.
+///
+/// In dual-stack mode, an unspecified address is served over both IPv4 and IPv6:
+/// a single `[::]` socket is used when the platform allows IPv4-mapped addresses,
+/// otherwise separate IPv4 and IPv6 sockets are bound.
+pub(crate) fn bind(addr: SocketAddr, options: &ListenerOptions) -> Result<Vec<TcpListener>> {
+    if !options.dual_stack || !addr.ip().is_unspecified() {
+        return Ok(vec![bind_socket(addr, None, options)?]);
+    }
+    let addr_v6 = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), addr.port());
+    match bind_socket(addr_v6, Some(false), options) {
+        Ok(listener) => Ok(vec![listener]),
+        Err(e) => {
+            warn!(
+                "dual-stack socket is not supported ({:#}), binding IPv4 and IPv6 separately",
+                e
+            );
+            let addr_v4 = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), addr.port());
+            let listener_v4 = bind_socket(addr_v4, None, options)?;
+            // the IPv4 socket may have been bound to a random port
+            let addr_v6 = SocketAddr::new(addr_v6.ip(), listener_v4.local_addr()?.port());
+            let listener_v6 = bind_socket(addr_v6, Some(true), options)?;
+            Ok(vec![listener_v4, listener_v6])
+        }
+    }
+}
+
+fn bind_socket(
+    addr: SocketAddr,
+    only_v6: Option<bool>,
+    options: &ListenerOptions,
+) -> Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))
+        .with_context(|| format!("failed to create socket for {}", addr))?;
+    socket
+        .set_reuse_address(options.reuse_address)
+        .context("failed to set SO_REUSEADDR")?;
+    if options.reuse_port {
+        set_reuse_port(&socket)?;
+    }
+    if let Some(only_v6) = only_v6 {
+        socket
+            .set_only_v6(only_v6)
+            .context("failed to set IPV6_V6ONLY")?;
+    }
+    socket
+        .bind(&addr.into())
+        .with_context(|| format!("failed to bind {}", addr))?;
+    socket
+        .listen(LISTEN_BACKLOG)
+        .with_context(|| format!("failed to listen on {}", addr))?;
+    Ok(socket.into())
+}
+
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn set_reuse_port(socket: &Socket) -> Result<()> {
+    socket
+        .set_reuse_port(true)
+        .context("failed to set SO_REUSEPORT")
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+fn set_reuse_port(_socket: &Socket) -> Result<()> {
+    bail!("SO_REUSEPORT is not supported on this platform")
+}
+
+/// Apply per-connection options to an accepted socket.
+pub(crate) fn configure_stream(stream: &TcpStream, options: &ListenerOptions) -> Result<()> {
+    if let Some(keepalive) = &options.keepalive {
+        SockRef::from(stream)
+            .set_tcp_keepalive(&tcp_keepalive(keepalive))
+            .context("failed to set TCP keepalive")?;
+    }
+    Ok(())
+}
+
+#[cfg(any(
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+))]
+fn tcp_keepalive(keepalive: &Keepalive) -> TcpKeepalive {
+    let mut result = TcpKeepalive::new().with_time(keepalive.idle);
+    if let Some(interval) = keepalive.interval {
+        result = result.with_interval(interval);
+    }
+    if let Some(retries) = keepalive.retries {
+        result = result.with_retries(retries);
+    }
+    result
+}
+
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+)))]
+fn tcp_keepalive(keepalive: &Keepalive) -> TcpKeepalive {
+    if keepalive.interval.is_some() || keepalive.retries.is_some() {
+        warn!("TCP keepalive interval and retries are not supported on this platform");
+    }
+    TcpKeepalive::new().with_time(keepalive.idle)
+}
+
+/// The client's details, as sent by a proxy (e.g. one terminating TLS)
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct ProxyHeader {
+    pub source: Option<SocketAddr>, // the client's address (unknown for the proxy's own checks)
+    pub authority: Option<String>,  // the hostname requested by the client
+}
+
+/// Read the PROXY protocol v2 header, which the proxy sends before the client's data.
+pub(crate) fn read_proxy_header(reader: &mut impl Read) -> Result<ProxyHeader> {
+    let mut header = [0u8; 16];
+    reader
+        .read_exact(&mut header)
+        .context("failed to read PROXY header")?;
+    ensure!(
+        header[..12] == PROXY_SIGNATURE,
+        "invalid PROXY v2 signature"
+    );
+    let (version, command) = (header[12] >> 4, header[12] & 0x0f);
+    ensure!(version == 2, "unsupported PROXY version {}", version);
+    let family = header[13] >> 4;
+    let mut payload = vec![0u8; u16::from_be_bytes([header[14], header[15]]) as usize];
+    reader
+        .read_exact(&mut payload)
+        .context("failed to read PROXY addresses")?;
+    match command {
+        0 => return Ok(ProxyHeader::default()), // LOCAL (e.g. the proxy's health checks)
+        1 => (),                                // PROXY
+        _ => bail!("unsupported PROXY command {}", command),
+    }
+    let addresses_len = match family {
+        0 => 0,   // UNSPEC
+        1 => 12,  // INET
+        2 => 36,  // INET6
+        3 => 216, // UNIX
+        _ => bail!("unsupported PROXY address family {}", family),
+    };
+    ensure!(payload.len() >= addresses_len, "truncated PROXY addresses");
+    let (addresses, mut tlvs) = payload.split_at(addresses_len);
+    let source = match family {
+        1 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Some(SocketAddr::new(ip.into(), port))
+        }
+        2 => {
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port))
+        }
+        _ => None,
+    };
+    let mut authority = None;
+    while !tlvs.is_empty() {
+        ensure!(tlvs.len() >= 3, "truncated PROXY TLV");
+        let len = u16::from_be_bytes([tlvs[1], tlvs[2]]) as usize;
+        ensure!(tlvs.len() >= 3 + len, "truncated PROXY TLV");
+        if tlvs[0] == PP2_TYPE_AUTHORITY {
+            let value =
+                std::str::from_utf8(&tlvs[3..3 + len]).context("invalid PROXY authority")?;
+            authority = Some(value.to_owned());
+        }
+        tlvs = &tlvs[3 + len..];
+    }
+    Ok(ProxyHeader { source, authority })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        bind, configure_stream, read_proxy_header, Keepalive, ListenerOptions, ProxyHeader,
+        PROXY_SIGNATURE,
+    };
+    use socket2::SockRef;
+    use std::io::Read;
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::time::Duration;
+
+    fn proxy_header(command: u8, family: u8, addresses: &[u8], tlvs: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut payload = addresses.to_vec();
+        for (kind, value) in tlvs {
+            payload.push(*kind);
+            payload.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            payload.extend_from_slice(value);
+        }
+        let mut header = PROXY_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family << 4 | 1); // over TCP
+        header.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        header.extend(payload);
+        header
+    }
+
+    fn local_addr(listeners: &[TcpListener]) -> SocketAddr {
+        assert_eq!(listeners.len(), 1);
+        listeners[0].local_addr().unwrap()
+    }
+
+    #[test]
+    fn test_proxy_header() {
+        // from 192.0.2.1:1234 to 192.0.2.2:50002, requesting "electrum.example.org"
+        let addresses = [192, 0, 2, 1, 192, 0, 2, 2, 0x04, 0xd2, 0xc3, 0x52];
+        let tlvs: &[(u8, &[u8])] = &[(0x01, b"h2"), (0x02, b"electrum.example.org")];
+        let mut data = proxy_header(1, 1, &addresses, tlvs);
+        data.extend_from_slice(b"{\"id\": 1}\n");
+        let mut reader = &data[..];
+        assert_eq!(
+            read_proxy_header(&mut reader).unwrap(),
+            ProxyHeader {
+                source: Some("192.0.2.1:1234".parse().unwrap()),
+                authority: Some("electrum.example.org".to_owned()),
+            }
+        );
+        // the client's data follows the header
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "{\"id\": 1}\n");
+
+        // IPv6, without the hostname (e.g. no TLS SNI)
+        let mut addresses = [0u8; 36];
+        addresses[15] = 1; // ::1
+        addresses[31] = 1;
+        addresses[32..].copy_from_slice(&[0x04, 0xd2, 0xc3, 0x52]);
+        let data = proxy_header(1, 2, &addresses, &[]);
+        assert_eq!(
+            read_proxy_header(&mut &data[..]).unwrap(),
+            ProxyHeader {
+                source: Some("[::1]:1234".parse().unwrap()),
+                authority: None,
+            }
+        );
+
+        // the proxy's own connections (e.g. health checks) have no client details
+        let data = proxy_header(0, 1, &[0; 12], &[]);
+        assert_eq!(
+            read_proxy_header(&mut &data[..]).unwrap(),
+            ProxyHeader::default()
+        );
+    }
+
+    #[test]
+    fn test_invalid_proxy_header() {
+        let err = |data: &[u8]| read_proxy_header(&mut &data[..]).unwrap_err().to_string();
+        assert_eq!(
+            err(b"{\"id\": 1, \"method\": \"server.version\"}\n"),
+            "invalid PROXY v2 signature"
+        );
+        assert_eq!(err(b"\r\n"), "failed to read PROXY header");
+        assert_eq!(
+            err(&proxy_header(2, 1, &[0; 12], &[])),
+            "unsupported PROXY command 2"
+        );
+        assert_eq!(
+            err(&proxy_header(1, 1, &[0; 8], &[])),
+            "truncated PROXY addresses"
+        );
+        let mut data = proxy_header(1, 1, &[0; 12], &[(0x02, b"example.org")]);
+        data[15] -= 1; // the TLV's value is cut short
+        data.pop();
+        assert_eq!(err(&data), "truncated PROXY TLV");
+        let mut data = proxy_header(1, 1, &[0; 12], &[]);
+        data[12] = 0x11; // version 1 uses a text header (without the hostname)
+        assert_eq!(err(&data), "unsupported PROXY version 1");
+    }
+
+    #[test]
+    fn test_bind_default() {
+        let options = ListenerOptions::default();
+        let listeners = bind("127.0.0.1:0".parse().unwrap(), &options).unwrap();
+        let addr = local_addr(&listeners);
+        assert!(addr.is_ipv4());
+        assert_ne!(addr.port(), 0);
+        TcpStream::connect(addr).unwrap();
+    }
+
+    #[test]
+    fn test_bind_dual_stack() {
+        let options = ListenerOptions {
+            dual_stack: true,
+            ..Default::default()
+        };
+        let listeners = match bind("0.0.0.0:0".parse().unwrap(), &options) {
+            Ok(listeners) => listeners,
+            Err(e) => {
+                eprintln!("skipping dual-stack test (IPv6 unavailable?): {:#}", e);
+                return;
+            }
+        };
+        let port = listeners[0].local_addr().unwrap().port();
+        TcpStream::connect(("127.0.0.1", port)).unwrap();
+        TcpStream::connect(("::1", port)).unwrap();
+    }
+
+    #[test]
+    fn test_bind_specific_address_ignores_dual_stack() {
+        let options = ListenerOptions {
+            dual_stack: true,
+            ..Default::default()
+        };
+        let listeners = bind("127.0.0.1:0".parse().unwrap(), &options).unwrap();
+        assert!(local_addr(&listeners).is_ipv4());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_bind_reuse_port() {
+        let options = ListenerOptions {
+            reuse_port: true,
+            ..Default::default()
+        };
+        let first = bind("127.0.0.1:0".parse().unwrap(), &options).unwrap();
+        let addr = local_addr(&first);
+        let second = bind(addr, &options).unwrap();
+        assert_eq!(local_addr(&second), addr);
+
+        let exclusive = ListenerOptions::default();
+        assert!(bind(addr, &exclusive).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_configure_keepalive() {
+        let listeners = bind("127.0.0.1:0".parse().unwrap(), &Default::default()).unwrap();
+        let stream = TcpStream::connect(local_addr(&listeners)).unwrap();
+        assert!(!SockRef::from(&stream).keepalive().unwrap());
+
+        let options = ListenerOptions {
+            keepalive: Some(Keepalive {
+                idle: Duration::from_secs(60),
+                interval: Some(Duration::from_secs(10)),
+                retries: Some(3),
+            }),
+            ..Default::default()
+        };
+        configure_stream(&stream, &options).unwrap();
+        let socket = SockRef::from(&stream);
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(60));
+        assert_eq!(
+            socket.keepalive_interval().unwrap(),
+            Duration::from_secs(10)
+        );
+        assert_eq!(socket.keepalive_retries().unwrap(), 3);
+    }
+}
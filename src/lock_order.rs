@@ -0,0 +1,101 @@
+//! Lock hierarchy: a thread may acquire a lock only while holding locks of lower levels.
+//!
+//! 1. `Daemon` p2p connection: held while fetching blocks, whose processing may use the cache.
+//! 2. `Cache` transactions: a leaf lock, so no daemon calls or other locks while holding it.
+//! 3. `Reputation` peers' state: a leaf lock, also acquired by the accepting threads.
+//! 4. `Webhooks` dead letters: a leaf lock, also acquired by the delivery threads.
+//! 5. `Scheduler` status builds' queues: a leaf lock (released before running the builds).
+//! 6. `HeadersTree` cached checkpoint tree: a leaf lock (released before building the tree).
+//! 7. `Peers` announced peers: a leaf lock, also acquired by the verification thread.
+//! 8. `Mirrors` last submission errors: a leaf lock, also acquired by the submission threads.
+//! 9. `Reservations` reserved outpoints: a leaf lock (released before selecting the UTXOs).
+//! 10. `StatusCache` unsubscribed statuses: a leaf lock (released before building the statuses).
+//!
+//! Per-client state is owned by the server loop (and passed as `&mut Client`), so it requires no
+//! locking. In debug builds, acquiring locks out of order panics.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Level {
+    DaemonP2P,
+    Cache,
+    Reputation,
+    Webhooks,
+    Scheduler,
+    HeadersTree,
+    Peers,
+    Mirrors,
+    Reservations,
+    StatusCache,
+}
+
+/// Should be held for the lifetime of the corresponding lock guard.
+#[must_use]
+pub(crate) struct Guard {
+    #[cfg(debug_assertions)]
+    level: Level,
+}
+
+#[cfg(debug_assertions)]
+thread_local! {
+    static HELD: std::cell::RefCell<Vec<Level>> = std::cell::RefCell::new(vec![]);
+}
+
+#[cfg(debug_assertions)]
+pub(crate) fn acquire(level: Level) -> Guard {
+    HELD.with(|held| {
+        let mut held = held.borrow_mut();
+        if let Some(last) = held.last() {
+            assert!(
+                *last < level,
+                "lock order violation: acquiring {:?} while holding {:?}",
+                level,
+                held
+            );
+        }
+        held.push(level);
+    });
+    Guard { level }
+}
+
+#[cfg(not(debug_assertions))]
+pub(crate) fn acquire(_level: Level) -> Guard {
+    Guard {}
+}
+
+#[cfg(debug_assertions)]
+impl Drop for Guard {
+    fn drop(&mut self) {
+        HELD.with(|held| {
+            let last = held.borrow_mut().pop();
+            debug_assert_eq!(last, Some(self.level));
+        });
+    }
+}
+
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use super::{acquire, Level};
+
+    #[test]
+    fn test_ordered() {
+        let _p2p = acquire(Level::DaemonP2P);
+        {
+            let _cache = acquire(Level::Cache);
+        }
+        let _cache = acquire(Level::Cache);
+    }
+
+    #[test]
+    #[should_panic(expected = "lock order violation")]
+    fn test_reversed() {
+        let _cache = acquire(Level::Cache);
+        let _p2p = acquire(Level::DaemonP2P);
+    }
+
+    #[test]
+    #[should_panic(expected = "lock order violation")]
+    fn test_reentrant() {
+        let _p2p = acquire(Level::DaemonP2P);
+        let _p2p_again = acquire(Level::DaemonP2P);
+    }
+}
@@ -4,16 +4,18 @@ use std::collections::{BTreeSet, HashMap, HashSet};
 use std::convert::TryFrom;
 use std::iter::FromIterator;
 use std::ops::Bound;
+use std::time::Instant;
 
 use bitcoin::hashes::Hash;
 use bitcoin::{Amount, OutPoint, Transaction, Txid};
 use bitcoincore_rpc::json;
 use rayon::prelude::*;
-use serde::ser::{Serialize, SerializeSeq, Serializer};
+use serde::ser::{Serialize, Serializer};
 
 use crate::{
     daemon::Daemon,
     metrics::{Gauge, Metrics},
+    protocol::FeeHistogramEntries,
     types::ScriptHash,
 };
 
@@ -22,18 +24,142 @@ pub(crate) struct Entry {
     pub tx: Transaction,
     pub fee: Amount,
     pub vsize: u64,
-    pub has_unconfirmed_inputs: bool,
+    pub ancestor_fee: Amount,   // including this transaction
+    pub ancestor_vsize: u64,    // including this transaction
+    pub ancestor_count: u64,    // including this transaction
+    pub descendant_fee: Amount, // including this transaction
+    pub descendant_vsize: u64,  // including this transaction
+    pub descendant_count: u64,  // including this transaction
 }
 
+impl Entry {
+    fn new(txid: Txid, tx: Transaction, entry: json::GetMempoolEntryResult) -> Self {
+        Self {
+            txid,
+            tx,
+            vsize: entry.vsize,
+            fee: entry.fees.base,
+            ancestor_fee: entry.fees.ancestor,
+            ancestor_vsize: entry.ancestor_size,
+            ancestor_count: entry.ancestor_count,
+            descendant_fee: entry.fees.descendant,
+            descendant_vsize: entry.descendant_size,
+            descendant_count: entry.descendant_count,
+        }
+    }
+
+    /// An entry paying 200 sat for 100 vB, without other mempool ancestors or descendants
+    #[cfg(test)]
+    pub(crate) fn dummy(tx: Transaction) -> Self {
+        Self {
+            txid: tx.txid(),
+            tx,
+            fee: Amount::from_sat(200),
+            vsize: 100,
+            ancestor_fee: Amount::from_sat(200),
+            ancestor_vsize: 100,
+            ancestor_count: 1,
+            descendant_fee: Amount::from_sat(200),
+            descendant_vsize: 100,
+            descendant_count: 1,
+        }
+    }
+
+    /// Fee rate of this transaction with its unconfirmed ancestors (in sat/vB)
+    pub(crate) fn ancestor_fee_rate(&self) -> f64 {
+        self.ancestor_fee.to_sat() as f64 / std::cmp::max(self.ancestor_vsize, 1) as f64
+    }
+
+    /// Fee rate used by bitcoind for evicting this transaction (with its descendants) when the
+    /// mempool is full: the higher of its own and its descendant package's fee rates (in sat/vB)
+    pub(crate) fn eviction_fee_rate(&self) -> f64 {
+        let fee_rate =
+            |fee: Amount, vsize: u64| fee.to_sat() as f64 / std::cmp::max(vsize, 1) as f64;
+        f64::max(
+            fee_rate(self.fee, self.vsize),
+            fee_rate(self.descendant_fee, self.descendant_vsize),
+        )
+    }
+
+    pub(crate) fn package_stats(&self) -> PackageStats {
+        PackageStats::new(
+            (self.ancestor_count, self.ancestor_vsize),
+            (self.descendant_count, self.descendant_vsize),
+        )
+    }
+}
+
+// bitcoind's default package limits (`-limitancestorcount`, `-limitancestorsize` and their
+// descendant counterparts), in transactions and vbytes
+const PACKAGE_COUNT_LIMIT: u64 = 25;
+const PACKAGE_VSIZE_LIMIT: u64 = 101_000;
+
+/// Unconfirmed ancestors of a mempool transaction, which may prevent spending its outputs
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct PackageStats {
+    pub ancestor_count: u64, // including this transaction
+    pub ancestor_size: u64,  // including this transaction (in vbytes)
+    pub spendable_now: bool,
+}
+
+impl PackageStats {
+    /// A new transaction spending this one is accepted by bitcoind only if it doesn't exceed the
+    /// package limits, as an additional descendant of this transaction and its ancestors.
+    /// Note that the descendants of the other ancestors are not checked.
+    fn new((ancestor_count, ancestor_size): (u64, u64), descendants: (u64, u64)) -> Self {
+        let within_limits =
+            |(count, vsize): (u64, u64)| count < PACKAGE_COUNT_LIMIT && vsize < PACKAGE_VSIZE_LIMIT;
+        Self {
+            ancestor_count,
+            ancestor_size,
+            spendable_now: within_limits((ancestor_count, ancestor_size))
+                && within_limits(descendants),
+        }
+    }
+}
+
+/// Daemon queries used for syncing the mempool
+pub(crate) trait MempoolDaemon: Sync {
+    fn mempool_txids(&self) -> Result<Vec<Txid>>;
+    fn mempool_entry(&self, txid: &Txid) -> Result<Entry>;
+    fn mempool_min_fee(&self) -> Result<Amount>; // per kvB
+}
+
+impl MempoolDaemon for Daemon {
+    fn mempool_txids(&self) -> Result<Vec<Txid>> {
+        self.get_mempool_txids()
+    }
+
+    fn mempool_entry(&self, txid: &Txid) -> Result<Entry> {
+        let tx = self.get_transaction(txid, None)?;
+        let entry = self.get_mempool_entry(txid)?;
+        Ok(Entry::new(*txid, tx, entry))
+    }
+
+    fn mempool_min_fee(&self) -> Result<Amount> {
+        self.get_mempool_min_fee()
+    }
+}
+
+/// Maximum number of new transactions to fetch in a single `Mempool::sync()` call, so loading a
+/// large mempool (e.g. after a restart) doesn't delay block syncing and serving requests.
+const LOAD_BATCH_SIZE: usize = 1000;
+
 /// Mempool current state
 pub(crate) struct Mempool {
     entries: HashMap<Txid, Entry>,
     by_funding: BTreeSet<(ScriptHash, Txid)>,
     by_spending: BTreeSet<(OutPoint, Txid)>,
     fees: FeeHistogram,
+    pending: Vec<Txid>, // new transactions, to be fetched by the next `sync()` calls
+    loaded: bool,       // set after the initial load is done
+    listed: Option<Instant>, // when the mempool transactions were last listed
+    min_fee: Option<Amount>, // per kvB, as of the last listing
+    version: u64,            // incremented when transactions are added or removed
     // stats
     vsize: Gauge,
     count: Gauge,
+    load: Gauge,
 }
 
 // Smallest possible txid
@@ -53,6 +179,11 @@ impl Mempool {
             by_funding: Default::default(),
             by_spending: Default::default(),
             fees: FeeHistogram::empty(),
+            pending: vec![],
+            loaded: false,
+            listed: None,
+            min_fee: None,
+            version: 0,
             vsize: metrics.gauge(
                 "mempool_txs_vsize",
                 "Total vsize of mempool transactions (in bytes)",
@@ -63,18 +194,74 @@ impl Mempool {
                 "Total number of mempool transactions",
                 "fee_rate",
             ),
+            load: metrics.gauge(
+                "mempool_load_txs",
+                "Number of loaded and pending mempool transactions",
+                "state",
+            ),
         }
     }
 
+    /// Until the initial load is done, the mempool is partial: its fee histogram is inaccurate,
+    /// and it's ignored by scripthash queries (see `filter_by_funding()`).
+    pub(crate) fn is_loaded(&self) -> bool {
+        self.loaded
+    }
+
     pub(crate) fn fees_histogram(&self) -> &FeeHistogram {
         &self.fees
     }
 
+    pub(crate) fn size(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// When this mempool view was last listed from the daemon (`None` before the first sync)
+    pub(crate) fn listed(&self) -> Option<Instant> {
+        self.listed
+    }
+
+    /// The minimal fee rate (in sat/vB) for entering the mempool (`None` before the first sync)
+    pub(crate) fn min_fee_rate(&self) -> Option<f64> {
+        self.min_fee.map(|fee| fee.to_sat() as f64 / 1000.0)
+    }
+
+    /// Changes whenever transactions are added to (or removed from) the mempool
+    pub(crate) fn version(&self) -> u64 {
+        self.version
+    }
+
     pub(crate) fn get(&self, txid: &Txid) -> Option<&Entry> {
         self.entries.get(txid)
     }
 
+    /// Whether the transaction spends outputs of other mempool transactions (checked against the
+    /// current mempool, since the entry's `depends` are outdated once its parents confirm).
+    pub(crate) fn has_unconfirmed_inputs(&self, entry: &Entry) -> bool {
+        entry
+            .tx
+            .input
+            .iter()
+            .any(|txin| self.entries.contains_key(&txin.previous_output.txid))
+    }
+
+    /// A loaded mempool of the given transactions (see `Entry::dummy()`)
+    #[cfg(test)]
+    pub(crate) fn from_txs(txs: Vec<Transaction>) -> Self {
+        let mut mempool = Self::new(&Metrics::dummy());
+        for tx in txs {
+            mempool.add_entry(Entry::dummy(tx));
+        }
+        mempool.loaded = true;
+        mempool
+    }
+
+    /// Returns no entries before the initial load is done, so scripthash statuses (and their
+    /// statushashes) don't change with each loaded batch.
     pub(crate) fn filter_by_funding(&self, scripthash: &ScriptHash) -> Vec<&Entry> {
+        if !self.loaded {
+            return vec![];
+        }
         let range = (
             Bound::Included((*scripthash, txid_min())),
             Bound::Included((*scripthash, txid_max())),
@@ -85,7 +272,11 @@ impl Mempool {
             .collect()
     }
 
+    /// Returns no entries before the initial load is done (see `filter_by_funding()`).
     pub(crate) fn filter_by_spending(&self, outpoint: &OutPoint) -> Vec<&Entry> {
+        if !self.loaded {
+            return vec![];
+        }
         let range = (
             Bound::Included((*outpoint, txid_min())),
             Bound::Included((*outpoint, txid_max())),
@@ -96,41 +287,58 @@ impl Mempool {
             .collect()
     }
 
-    pub fn sync(&mut self, daemon: &Daemon) {
-        let txids = match daemon.get_mempool_txids() {
-            Ok(txids) => txids,
-            Err(e) => {
-                warn!("mempool sync failed: {}", e);
-                return;
+    /// Fetch a batch of new transactions (the new txids are listed when there are no pending
+    /// ones). Returns `true` when there are no more pending transactions.
+    pub fn sync(&mut self, daemon: &dyn MempoolDaemon) -> bool {
+        if self.pending.is_empty() {
+            let txids = match daemon.mempool_txids() {
+                Ok(txids) => {
+                    self.listed = Some(Instant::now());
+                    txids
+                }
+                Err(e) => {
+                    warn!("mempool sync failed: {}", e);
+                    return true; // retry on the next sync
+                }
+            };
+            debug!("loading {} mempool transactions", txids.len());
+            match daemon.mempool_min_fee() {
+                Ok(fee) => self.min_fee = Some(fee),
+                Err(e) => warn!("failed to get mempool min fee: {}", e),
             }
-        };
-        debug!("loading {} mempool transactions", txids.len());
-
-        let new_txids = HashSet::<Txid>::from_iter(txids);
-        let old_txids = HashSet::<Txid>::from_iter(self.entries.keys().copied());
 
-        let to_add = &new_txids - &old_txids;
-        let to_remove = &old_txids - &new_txids;
+            let new_txids = HashSet::<Txid>::from_iter(txids);
+            let old_txids = HashSet::<Txid>::from_iter(self.entries.keys().copied());
 
-        let removed = to_remove.len();
-        for txid in to_remove {
-            self.remove_entry(txid);
+            let to_remove = &old_txids - &new_txids;
+            let removed = to_remove.len();
+            for txid in to_remove {
+                self.remove_entry(txid);
+            }
+            if removed > 0 {
+                self.version += 1;
+            }
+            self.pending = (&new_txids - &old_txids).into_iter().collect();
+            debug!(
+                "{} mempool txs: {} removed, {} pending",
+                self.entries.len(),
+                removed,
+                self.pending.len()
+            );
         }
-        let entries: Vec<_> = to_add
+        let batch = self
+            .pending
+            .split_off(self.pending.len().saturating_sub(LOAD_BATCH_SIZE));
+        let entries: Vec<Entry> = batch
             .par_iter()
-            .filter_map(|txid| {
-                match (
-                    daemon.get_transaction(txid, None),
-                    daemon.get_mempool_entry(txid),
-                ) {
-                    (Ok(tx), Ok(entry)) => Some((txid, tx, entry)),
-                    _ => None,
-                }
-            })
+            .filter_map(|txid| daemon.mempool_entry(txid).ok()) // may be already evicted
             .collect();
         let added = entries.len();
-        for (txid, tx, entry) in entries {
-            self.add_entry(*txid, tx, entry);
+        for entry in entries {
+            self.add_entry(entry);
+        }
+        if added > 0 {
+            self.version += 1;
         }
         self.fees = FeeHistogram::new(self.entries.values().map(|e| (e.fee, e.vsize)));
         for i in 0..FeeHistogram::BINS {
@@ -140,29 +348,31 @@ impl Mempool {
             self.vsize.set(&label, self.fees.vsize[bin_index] as f64);
             self.count.set(&label, self.fees.count[bin_index] as f64);
         }
+        self.load.set("loaded", self.entries.len() as f64);
+        self.load.set("pending", self.pending.len() as f64);
+        let done = self.pending.is_empty();
+        if done && !self.loaded {
+            info!("loaded {} mempool transactions", self.entries.len());
+            self.loaded = true;
+        }
         debug!(
-            "{} mempool txs: {} added, {} removed",
+            "{} mempool txs: {} added, {} pending",
             self.entries.len(),
             added,
-            removed,
+            self.pending.len(),
         );
+        done
     }
 
-    fn add_entry(&mut self, txid: Txid, tx: Transaction, entry: json::GetMempoolEntryResult) {
-        for txi in &tx.input {
+    fn add_entry(&mut self, entry: Entry) {
+        let txid = entry.txid;
+        for txi in &entry.tx.input {
             self.by_spending.insert((txi.previous_output, txid));
         }
-        for txo in &tx.output {
+        for txo in &entry.tx.output {
             let scripthash = ScriptHash::new(&txo.script_pubkey);
             self.by_funding.insert((scripthash, txid)); // may have duplicates
         }
-        let entry = Entry {
-            txid,
-            tx,
-            vsize: entry.vsize,
-            fee: entry.fees.base,
-            has_unconfirmed_inputs: !entry.depends.is_empty(),
-        };
         assert!(
             self.entries.insert(txid, entry).is_none(),
             "duplicate mempool txid"
@@ -211,6 +421,23 @@ impl FeeHistogram {
         Self::new(std::iter::empty())
     }
 
+    /// Approximate vsize of the transactions paying a higher fee rate (in sat/vB), counting only
+    /// the bins above the given fee rate's one.
+    pub(crate) fn vsize_above(&self, fee_rate: u64) -> u64 {
+        let index = usize::try_from(fee_rate.leading_zeros()).unwrap();
+        self.vsize[..index].iter().sum()
+    }
+
+    /// https://electrumx-spesmilo.readthedocs.io/en/latest/protocol-methods.html#mempool-get-fee-histogram
+    pub(crate) fn entries(&self) -> FeeHistogramEntries {
+        let fee_rates =
+            (0..FeeHistogram::BINS).map(|i| std::u64::MAX.checked_shr(i as u32).unwrap_or(0));
+        fee_rates
+            .zip(self.vsize.iter().copied())
+            .skip_while(|(_fee_rate, vsize)| *vsize == 0)
+            .collect()
+    }
+
     fn new(items: impl Iterator<Item = (Amount, u64)>) -> Self {
         let mut result = FeeHistogram::default();
         for (fee, vsize) in items {
@@ -233,23 +460,142 @@ impl Serialize for FeeHistogram {
     where
         S: Serializer,
     {
-        let mut seq = serializer.serialize_seq(Some(self.vsize.len()))?;
-        // https://electrumx-spesmilo.readthedocs.io/en/latest/protocol-methods.html#mempool-get-fee-histogram
-        let fee_rates =
-            (0..FeeHistogram::BINS).map(|i| std::u64::MAX.checked_shr(i as u32).unwrap_or(0));
-        fee_rates
-            .zip(self.vsize.iter().copied())
-            .skip_while(|(_fee_rate, vsize)| *vsize == 0)
-            .try_for_each(|element| seq.serialize_element(&element))?;
-        seq.end()
+        serializer.collect_seq(self.entries())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::FeeHistogram;
-    use bitcoin::Amount;
+    use super::{Entry, FeeHistogram, Mempool, MempoolDaemon, PackageStats, LOAD_BATCH_SIZE};
+    use crate::{metrics::Metrics, types::ScriptHash};
+    use anyhow::Result;
+    use bitcoin::{
+        absolute::LockTime, hashes::Hash, Amount, OutPoint, ScriptBuf, Transaction, TxIn, TxOut,
+        Txid,
+    };
     use serde_json::json;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn entry(index: u32) -> Entry {
+        let mut prev_txid = [0u8; 32];
+        prev_txid[..4].copy_from_slice(&index.to_le_bytes());
+        let tx = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::from_byte_array(prev_txid), 0),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: ScriptBuf::new(),
+            }],
+        };
+        Entry::dummy(tx)
+    }
+
+    struct MockDaemon {
+        txids: HashMap<Txid, u32>,
+        fetched: AtomicUsize,
+    }
+
+    impl MockDaemon {
+        fn new(indices: impl Iterator<Item = u32>) -> Self {
+            Self {
+                txids: indices.map(|index| (entry(index).txid, index)).collect(),
+                fetched: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    impl MempoolDaemon for MockDaemon {
+        fn mempool_txids(&self) -> Result<Vec<Txid>> {
+            Ok(self.txids.keys().copied().collect())
+        }
+
+        fn mempool_entry(&self, txid: &Txid) -> Result<Entry> {
+            self.fetched.fetch_add(1, Ordering::SeqCst);
+            let index = self.txids.get(txid).expect("unknown txid");
+            Ok(entry(*index))
+        }
+
+        fn mempool_min_fee(&self) -> Result<Amount> {
+            Ok(Amount::from_sat(1000))
+        }
+    }
+
+    #[test]
+    fn test_incremental_load() {
+        let daemon = MockDaemon::new(0..10_000);
+        let mut mempool = Mempool::new(&Metrics::dummy());
+        let scripthash = ScriptHash::new(&ScriptBuf::new());
+
+        // a single batch is fetched, so the server may handle requests (and sync new blocks)
+        // before the rest of the mempool is loaded
+        assert!(!mempool.sync(&daemon));
+        assert_eq!(daemon.fetched.load(Ordering::SeqCst), LOAD_BATCH_SIZE);
+        assert_eq!(mempool.entries.len(), LOAD_BATCH_SIZE);
+        assert_eq!(mempool.min_fee_rate(), Some(1.0));
+        assert!(!mempool.is_loaded());
+        assert!(mempool.filter_by_funding(&scripthash).is_empty()); // partial mempool is ignored
+
+        let mut batches = 1;
+        while !mempool.sync(&daemon) {
+            batches += 1;
+            assert!(!mempool.is_loaded());
+        }
+        assert_eq!(batches, 10);
+        assert_eq!(daemon.fetched.load(Ordering::SeqCst), 10_000);
+        assert!(mempool.is_loaded());
+        assert_eq!(mempool.filter_by_funding(&scripthash).len(), 10_000);
+        assert_eq!(json!(mempool.fees_histogram()), json!([[3, 1_000_000]]));
+
+        // later syncs fetch only the new transactions
+        let daemon = MockDaemon::new(5_000..10_010);
+        assert!(mempool.sync(&daemon));
+        assert_eq!(daemon.fetched.load(Ordering::SeqCst), 10);
+        assert_eq!(mempool.entries.len(), 5_010);
+        assert!(mempool.is_loaded());
+    }
+
+    #[test]
+    fn test_version() {
+        let mut mempool = Mempool::new(&Metrics::dummy());
+        assert!(mempool.sync(&MockDaemon::new(0..10)));
+        let version = mempool.version();
+        assert!(version > 0);
+
+        // unchanged mempool
+        assert!(mempool.sync(&MockDaemon::new(0..10)));
+        assert_eq!(mempool.version(), version);
+
+        // removed transactions
+        assert!(mempool.sync(&MockDaemon::new(0..5)));
+        assert!(mempool.version() > version);
+    }
+
+    #[test]
+    fn test_unconfirmed_inputs() {
+        let parent = entry(0);
+        let mut child = entry(1);
+        child.tx.input[0].previous_output = OutPoint::new(parent.txid, 0);
+        child.txid = child.tx.txid();
+        let child_txid = child.txid;
+
+        let mut mempool = Mempool::new(&Metrics::dummy());
+        mempool.add_entry(parent);
+        mempool.add_entry(child);
+        let parent = mempool.get(&entry(0).txid).unwrap();
+        let child = mempool.get(&child_txid).unwrap();
+        assert!(!mempool.has_unconfirmed_inputs(parent));
+        assert!(mempool.has_unconfirmed_inputs(child));
+
+        // the parent is confirmed
+        mempool.remove_entry(entry(0).txid);
+        let child = mempool.get(&child_txid).unwrap();
+        assert!(!mempool.has_unconfirmed_inputs(child));
+    }
 
     #[test]
     fn test_histogram() {
@@ -269,5 +615,38 @@ mod tests {
             json!(hist),
             json!([[15, 10], [7, 40], [3, 20], [1, 10], [0, 100]])
         );
+        assert_eq!(hist.vsize_above(20), 0);
+        assert_eq!(hist.vsize_above(8), 0); // in the highest bin
+        assert_eq!(hist.vsize_above(5), 10);
+        assert_eq!(hist.vsize_above(2), 50);
+        assert_eq!(hist.vsize_above(1), 70);
+        assert_eq!(hist.vsize_above(0), 80);
+    }
+
+    #[test]
+    fn test_eviction_fee_rate() {
+        let mut entry = entry(0);
+        assert_eq!(entry.eviction_fee_rate(), 2.0);
+        // a child paying a higher fee rate protects its parent from eviction
+        entry.descendant_fee = Amount::from_sat(1200);
+        entry.descendant_vsize = 200;
+        assert_eq!(entry.eviction_fee_rate(), 6.0);
+        // but a child paying a lower fee rate doesn't hurt it
+        entry.descendant_fee = Amount::from_sat(300);
+        assert_eq!(entry.eviction_fee_rate(), 2.0);
+    }
+
+    #[test]
+    fn test_package_stats() {
+        let stats = PackageStats::new((1, 200), (1, 200));
+        assert_eq!(
+            json!(stats),
+            json!({"ancestor_count": 1, "ancestor_size": 200, "spendable_now": true})
+        );
+        assert!(PackageStats::new((24, 100_000), (24, 100_000)).spendable_now);
+        assert!(!PackageStats::new((25, 5000), (1, 200)).spendable_now);
+        assert!(!PackageStats::new((2, 101_000), (1, 200)).spendable_now);
+        assert!(!PackageStats::new((1, 200), (25, 5000)).spendable_now);
+        assert!(!PackageStats::new((1, 200), (2, 101_000)).spendable_now);
     }
 }
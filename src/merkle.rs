@@ -1,4 +1,7 @@
-use bitcoin::{hash_types::TxMerkleNode, hashes::Hash, Txid};
+use anyhow::Result;
+use bitcoin::{
+    block::Header, consensus::deserialize, hash_types::TxMerkleNode, hashes::Hash, BlockHash, Txid,
+};
 
 pub(crate) struct Proof {
     proof: Vec<TxMerkleNode>,
@@ -8,35 +11,11 @@ pub(crate) struct Proof {
 impl Proof {
     pub(crate) fn create(txids: &[Txid], position: usize) -> Self {
         assert!(position < txids.len());
-        let mut offset = position;
-        let mut hashes: Vec<TxMerkleNode> = txids
+        let leaves = txids
             .iter()
             .map(|txid| TxMerkleNode::from_raw_hash(txid.to_raw_hash()))
             .collect();
-
-        let mut proof = vec![];
-        while hashes.len() > 1 {
-            if hashes.len() % 2 != 0 {
-                let last = *hashes.last().unwrap();
-                hashes.push(last);
-            }
-            offset = if offset % 2 == 0 {
-                offset + 1
-            } else {
-                offset - 1
-            };
-            proof.push(hashes[offset]);
-            offset /= 2;
-            hashes = hashes
-                .chunks(2)
-                .map(|pair| {
-                    let left = pair[0];
-                    let right = pair[1];
-                    let input = [&left[..], &right[..]].concat();
-                    TxMerkleNode::hash(&input)
-                })
-                .collect()
-        }
+        let proof = Tree::new(leaves).branch(position);
         Self { proof, position }
     }
 
@@ -50,14 +29,153 @@ impl Proof {
     pub(crate) fn position(&self) -> usize {
         self.position
     }
+
+    /// The merkle root committed by this proof (to be compared with the block header's)
+    pub(crate) fn root(&self, txid: Txid) -> TxMerkleNode {
+        let leaf = TxMerkleNode::from_raw_hash(txid.to_raw_hash());
+        compute_root(leaf, &self.proof, self.position)
+    }
+
+    pub(crate) fn into_branch(self) -> Vec<TxMerkleNode> {
+        self.proof
+    }
+}
+
+/// All the levels of a merkle tree, for creating multiple branches efficiently
+pub(crate) struct Tree {
+    levels: Vec<Vec<TxMerkleNode>>, // from the leaves to the root (odd-length levels are padded)
+}
+
+impl Tree {
+    pub(crate) fn new(leaves: Vec<TxMerkleNode>) -> Self {
+        assert!(!leaves.is_empty());
+        let mut levels = vec![leaves];
+        loop {
+            let level = levels.last_mut().unwrap();
+            if level.len() == 1 {
+                break;
+            }
+            if level.len() % 2 != 0 {
+                let last = *level.last().unwrap();
+                level.push(last);
+            }
+            let next = level
+                .chunks(2)
+                .map(|pair| hash_pair(&pair[0], &pair[1]))
+                .collect();
+            levels.push(next);
+        }
+        Self { levels }
+    }
+
+    /// Block hashes' tree, whose root commits to the header chain (as used by `cp_height`)
+    pub(crate) fn from_block_hashes(block_hashes: &[BlockHash]) -> Self {
+        let leaves = block_hashes
+            .iter()
+            .map(|hash| TxMerkleNode::from_raw_hash(hash.to_raw_hash()))
+            .collect();
+        Self::new(leaves)
+    }
+
+    pub(crate) fn root(&self) -> TxMerkleNode {
+        self.levels.last().unwrap()[0]
+    }
+
+    pub(crate) fn branch(&self, mut position: usize) -> Vec<TxMerkleNode> {
+        let levels = &self.levels[..self.levels.len() - 1]; // the root has no sibling
+        levels
+            .iter()
+            .map(|level| {
+                let sibling = level[position ^ 1];
+                position /= 2;
+                sibling
+            })
+            .collect()
+    }
+}
+
+fn hash_pair(left: &TxMerkleNode, right: &TxMerkleNode) -> TxMerkleNode {
+    let input = [&left[..], &right[..]].concat();
+    TxMerkleNode::hash(&input)
+}
+
+fn compute_root(leaf: TxMerkleNode, branch: &[TxMerkleNode], mut position: usize) -> TxMerkleNode {
+    let mut node = leaf;
+    for sibling in branch {
+        node = if position % 2 == 0 {
+            hash_pair(&node, sibling)
+        } else {
+            hash_pair(sibling, &node)
+        };
+        position /= 2;
+    }
+    node
+}
+
+/// A transaction's inclusion proof in its block, chained to the block's inclusion proof in the
+/// header chain up to a checkpoint height (as returned by `proofs.export`).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChainedProof {
+    pub txid: Txid,
+    pub height: usize,
+    pub header: String, // hex-encoded block header
+    pub tx_pos: usize,
+    pub tx_branch: Vec<TxMerkleNode>,
+    pub cp_height: usize,
+    pub header_branch: Vec<TxMerkleNode>,
+    pub cp_root: TxMerkleNode, // merkle root of the block hashes up to `cp_height`
+}
+
+/// Verify a chained proof against a checkpoint root, which should be obtained independently
+/// (e.g. published by the auditee, or computed from a trusted header chain up to `cp_height`).
+pub fn verify_chained_proof(proof: &ChainedProof, cp_root: &TxMerkleNode) -> Result<()> {
+    ensure!(
+        proof.height <= proof.cp_height,
+        "block height {} is above checkpoint height {}",
+        proof.height,
+        proof.cp_height
+    );
+    ensure!(
+        proof.cp_root == *cp_root,
+        "proof is for checkpoint root {}, instead of {}",
+        proof.cp_root,
+        cp_root
+    );
+    let header_bytes =
+        crate::hex::decode(&proof.header).map_err(|e| anyhow!("invalid header: {}", e))?;
+    let header: Header =
+        deserialize(&header_bytes).map_err(|e| anyhow!("invalid header: {}", e))?;
+
+    let leaf = TxMerkleNode::from_raw_hash(proof.txid.to_raw_hash());
+    let merkle_root = compute_root(leaf, &proof.tx_branch, proof.tx_pos);
+    ensure!(
+        merkle_root == header.merkle_root,
+        "transaction {} is not included in block {}",
+        proof.txid,
+        header.block_hash()
+    );
+
+    let leaf = TxMerkleNode::from_raw_hash(header.block_hash().to_raw_hash());
+    let root = compute_root(leaf, &proof.header_branch, proof.height);
+    ensure!(
+        root == *cp_root,
+        "block {} is not included at height {} in the checkpoint",
+        header.block_hash(),
+        proof.height
+    );
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use bitcoin::{consensus::encode::deserialize, Block, Txid};
+    use bitcoin::{
+        consensus::encode::{deserialize, serialize_hex},
+        hashes::Hash,
+        Block, BlockHash, Txid,
+    };
     use std::path::Path;
 
-    use super::Proof;
+    use super::{verify_chained_proof, ChainedProof, Proof, Tree};
 
     #[test]
     fn test_merkle() {
@@ -101,13 +219,87 @@ mod tests {
         );
     }
 
-    fn load_block_txids(block_hash_hex: &str) -> Vec<Txid> {
+    #[test]
+    fn test_chained_proof() {
+        let block = load_block("000000000000000002d249a3d89f63ef3fee203adcca7c24008c13fd854513f2");
+        let txids: Vec<Txid> = block.txdata.iter().map(|tx| tx.txid()).collect();
+        let (height, cp_height, tx_pos) = (5, 10, 6);
+        let block_hashes: Vec<BlockHash> = (0..=cp_height)
+            .map(|i| match i {
+                _ if i == height => block.block_hash(),
+                _ => BlockHash::from_byte_array([i as u8; 32]),
+            })
+            .collect();
+        let tree = Tree::from_block_hashes(&block_hashes);
+        let proof = ChainedProof {
+            txid: txids[tx_pos],
+            height,
+            header: serialize_hex(&block.header),
+            tx_pos,
+            tx_branch: Proof::create(&txids, tx_pos).into_branch(),
+            cp_height,
+            header_branch: tree.branch(height),
+            cp_root: tree.root(),
+        };
+        verify_chained_proof(&proof, &tree.root()).unwrap();
+        let root = Proof::create(&txids, tx_pos).root(txids[tx_pos]);
+        assert_eq!(root, block.header.merkle_root);
+        assert_ne!(root, Proof::create(&txids, tx_pos).root(txids[0]));
+
+        // the proof can be verified offline
+        let value = serde_json::to_value(&proof).unwrap();
+        let parsed: ChainedProof = serde_json::from_value(value).unwrap();
+        assert_eq!(parsed, proof);
+
+        let other_root = Tree::from_block_hashes(&block_hashes[..cp_height]).root();
+        let err = verify_chained_proof(&proof, &other_root).unwrap_err();
+        assert!(err.to_string().starts_with("proof is for checkpoint root"));
+
+        let mut bad = proof.clone();
+        bad.tx_pos += 1;
+        let err = verify_chained_proof(&bad, &tree.root()).unwrap_err();
+        assert!(err.to_string().contains("is not included in block"));
+
+        let mut bad = proof.clone();
+        bad.height += 1;
+        let err = verify_chained_proof(&bad, &tree.root()).unwrap_err();
+        assert!(err.to_string().contains("is not included at height 6"));
+
+        let mut bad = proof;
+        bad.height = cp_height + 1;
+        let err = verify_chained_proof(&bad, &tree.root()).unwrap_err();
+        assert!(err.to_string().contains("is above checkpoint height"));
+    }
+
+    #[test]
+    fn test_tree_branches() {
+        let block = load_block("00000000000000001203c1ea455e38612bdf36e9967fdead11935c8e22283ecc");
+        let txids: Vec<Txid> = block.txdata.iter().map(|tx| tx.txid()).collect();
+        let leaves = txids
+            .iter()
+            .map(|txid| bitcoin::hash_types::TxMerkleNode::from_raw_hash(txid.to_raw_hash()))
+            .collect();
+        let tree = Tree::new(leaves);
+        assert_eq!(tree.root(), block.header.merkle_root);
+        for &position in &[0, 1, 157, txids.len() - 1] {
+            assert_eq!(
+                tree.branch(position),
+                Proof::create(&txids, position).into_branch()
+            );
+        }
+    }
+
+    fn load_block(block_hash_hex: &str) -> Block {
         let path = Path::new("src")
             .join("tests")
             .join("blocks")
             .join(block_hash_hex);
         let data = std::fs::read(path).unwrap();
-        let block: Block = deserialize(&data).unwrap();
+        deserialize(&data).unwrap()
+    }
+
+    fn load_block_txids(block_hash_hex: &str) -> Vec<Txid> {
+        let block = load_block(block_hash_hex);
         block.txdata.iter().map(|tx| tx.txid()).collect()
     }
 }
@@ -1,3 +1,41 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/// Where (and whether) Prometheus metrics are served
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MetricsListener {
+    Disabled,
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+/// HTTP credentials required for scraping the metrics
+#[derive(Clone, PartialEq, Eq)]
+pub enum MetricsAuth {
+    Basic { user: String, password: String },
+    Bearer(String),
+}
+
+impl fmt::Debug for MetricsAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetricsAuth::Basic { user, .. } => f
+                .debug_tuple("Basic")
+                .field(&user)
+                .field(&"<sensitive>")
+                .finish(),
+            MetricsAuth::Bearer(_) => f.debug_tuple("Bearer").field(&"<sensitive>").finish(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MetricsOptions {
+    pub listener: MetricsListener,
+    pub auth: Option<MetricsAuth>,
+}
+
 #[cfg(feature = "metrics")]
 mod metrics_impl {
     use anyhow::{Context, Result};
@@ -5,45 +43,72 @@ mod metrics_impl {
     #[cfg(feature = "metrics_process")]
     use prometheus::process_collector::ProcessCollector;
 
-    use prometheus::{self, Encoder, HistogramOpts, HistogramVec, Registry};
-    use tiny_http::{Response, Server};
+    use parking_lot::Mutex;
+    use prometheus::{self, proto::MetricFamily, Encoder, HistogramOpts, HistogramVec, Registry};
+    use tiny_http::{Header, Request, Response, Server};
 
-    use std::net::SocketAddr;
+    use std::collections::{btree_map, BTreeMap, HashMap};
+    use std::sync::Arc;
 
+    use super::{MetricsAuth, MetricsListener, MetricsOptions};
     use crate::thread::spawn;
 
     pub struct Metrics {
         reg: Registry,
+        registries: Arc<Mutex<Vec<Registry>>>, // all registries served by the HTTP endpoint
     }
 
     impl Metrics {
-        pub fn new(addr: SocketAddr) -> Result<Self> {
+        pub fn new(options: &MetricsOptions) -> Result<Self> {
             let reg = Registry::new();
 
             #[cfg(feature = "metrics_process")]
             reg.register(Box::new(ProcessCollector::for_self()))
                 .expect("failed to register ProcessCollector");
 
-            let result = Self { reg };
-            let reg = result.reg.clone();
-            spawn("metrics", move || {
-                let server = Server::http(addr).unwrap();
-                for request in server.incoming_requests() {
-                    let mut buffer = vec![];
-                    prometheus::TextEncoder::new()
-                        .encode(&reg.gather(), &mut buffer)
-                        .context("failed to encode metrics")?;
-                    request
-                        .respond(Response::from_data(buffer))
-                        .context("failed to send HTTP response")?;
+            let result = Self {
+                reg: reg.clone(),
+                registries: Arc::new(Mutex::new(vec![reg])),
+            };
+            match bind(&options.listener)? {
+                Some(server) => {
+                    info!(
+                        "serving Prometheus metrics on {:?} (auth: {})",
+                        options.listener,
+                        options.auth.is_some()
+                    );
+                    let registries = Arc::clone(&result.registries);
+                    let auth = options.auth.clone();
+                    spawn("metrics", move || serve(server, registries, auth));
                 }
-                Ok(())
-            });
-
-            info!("serving Prometheus metrics on {}", addr);
+                None => info!("Prometheus metrics are disabled"),
+            }
             Ok(result)
         }
 
+        /// Unserved metrics (for testing)
+        #[cfg(test)]
+        pub fn dummy() -> Self {
+            let reg = Registry::new();
+            Self {
+                reg: reg.clone(),
+                registries: Arc::new(Mutex::new(vec![reg])),
+            }
+        }
+
+        /// Metrics of a specific network, served by the same HTTP endpoint (using a `network` label).
+        pub fn with_network(&self, network: &str) -> Result<Self> {
+            let mut labels = HashMap::new();
+            labels.insert("network".to_owned(), network.to_owned());
+            let reg = Registry::new_custom(None, Some(labels))
+                .context("failed to create metrics registry")?;
+            self.registries.lock().push(reg.clone());
+            Ok(Self {
+                reg,
+                registries: Arc::clone(&self.registries),
+            })
+        }
+
         pub fn histogram_vec(
             &self,
             name: &str,
@@ -67,6 +132,115 @@ mod metrics_impl {
                 .expect("failed to register Gauge");
             Gauge { gauge }
         }
+
+        pub fn counter(&self, name: &str, desc: &str, label: &str) -> Counter {
+            let opts = prometheus::Opts::new(name, desc);
+            let counter = prometheus::IntCounterVec::new(opts, &[label]).unwrap();
+            self.reg
+                .register(Box::new(counter.clone()))
+                .expect("failed to register Counter");
+            Counter { counter }
+        }
+
+        pub fn info(&self, name: &str, desc: &str, labels: &[&str]) -> Info {
+            let opts = prometheus::Opts::new(name, desc);
+            let gauge = prometheus::GaugeVec::new(opts, labels).unwrap();
+            self.reg
+                .register(Box::new(gauge.clone()))
+                .expect("failed to register Info");
+            Info {
+                gauge,
+                current: Arc::new(Mutex::new(None)),
+            }
+        }
+    }
+
+    fn bind(listener: &MetricsListener) -> Result<Option<Server>> {
+        let server = match listener {
+            MetricsListener::Disabled => return Ok(None),
+            MetricsListener::Tcp(addr) => Server::http(addr),
+            #[cfg(unix)]
+            MetricsListener::Unix(path) => Server::http_unix(path),
+            #[cfg(not(unix))]
+            MetricsListener::Unix(_) => bail!("Unix sockets are not supported on this platform"),
+        };
+        server
+            .map(Some)
+            .map_err(|e| anyhow!("failed to serve metrics on {:?}: {}", listener, e))
+    }
+
+    fn serve(
+        server: Server,
+        registries: Arc<Mutex<Vec<Registry>>>,
+        auth: Option<MetricsAuth>,
+    ) -> Result<()> {
+        for request in server.incoming_requests() {
+            if !is_authorized(&request, auth.as_ref()) {
+                let challenge = match auth {
+                    Some(MetricsAuth::Basic { .. }) => &b"Basic realm=\"electrs\""[..],
+                    _ => &b"Bearer"[..],
+                };
+                let header = Header::from_bytes(&b"WWW-Authenticate"[..], challenge)
+                    .expect("invalid header");
+                let response = Response::from_string("unauthorized")
+                    .with_status_code(401)
+                    .with_header(header);
+                request
+                    .respond(response)
+                    .context("failed to send HTTP response")?;
+                continue;
+            }
+            let mut buffer = vec![];
+            let families = gather(&registries.lock());
+            prometheus::TextEncoder::new()
+                .encode(&families, &mut buffer)
+                .context("failed to encode metrics")?;
+            request
+                .respond(Response::from_data(buffer))
+                .context("failed to send HTTP response")?;
+        }
+        Ok(())
+    }
+
+    fn is_authorized(request: &Request, auth: Option<&MetricsAuth>) -> bool {
+        let expected = match auth {
+            None => return true,
+            Some(MetricsAuth::Basic { user, password }) => {
+                format!("Basic {}", base64::encode(format!("{}:{}", user, password)))
+            }
+            Some(MetricsAuth::Bearer(token)) => format!("Bearer {}", token),
+        };
+        request
+            .headers()
+            .iter()
+            .filter(|header| header.field.equiv("Authorization"))
+            .any(|header| constant_time_eq(header.value.as_str(), &expected))
+    }
+
+    fn constant_time_eq(a: &str, b: &str) -> bool {
+        a.len() == b.len()
+            && a.bytes()
+                .zip(b.bytes())
+                .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+                == 0
+    }
+
+    /// Merge same-named metric families from different registries (e.g. per-network ones).
+    fn gather(registries: &[Registry]) -> Vec<MetricFamily> {
+        let mut families = BTreeMap::<String, MetricFamily>::new();
+        for mut family in registries.iter().flat_map(Registry::gather) {
+            match families.entry(family.get_name().to_owned()) {
+                btree_map::Entry::Vacant(e) => {
+                    e.insert(family);
+                }
+                btree_map::Entry::Occupied(mut e) => {
+                    for metric in family.take_metric().into_iter() {
+                        e.get_mut().mut_metric().push(metric);
+                    }
+                }
+            }
+        }
+        families.into_iter().map(|(_name, family)| family).collect()
     }
 
     #[derive(Clone)]
@@ -80,6 +254,40 @@ mod metrics_impl {
         }
     }
 
+    #[derive(Clone)]
+    pub struct Counter {
+        counter: prometheus::IntCounterVec,
+    }
+
+    impl Counter {
+        pub fn inc(&self, label: &str) {
+            self.counter.with_label_values(&[label]).inc()
+        }
+    }
+
+    /// Info-style metric: a single series (with value 1), whose labels are replaced on update
+    #[derive(Clone)]
+    pub struct Info {
+        gauge: prometheus::GaugeVec,
+        current: Arc<Mutex<Option<Vec<String>>>>,
+    }
+
+    impl Info {
+        pub fn set(&self, values: &[&str]) {
+            let mut current = self.current.lock();
+            if let Some(old) = current.as_ref() {
+                if old.iter().map(String::as_str).eq(values.iter().copied()) {
+                    return;
+                }
+                let old: Vec<&str> = old.iter().map(String::as_str).collect();
+                // don't keep reporting stale series
+                let _ = self.gauge.remove_label_values(&old);
+            }
+            self.gauge.with_label_values(values).set(1.0);
+            *current = Some(values.iter().map(|v| v.to_string()).collect());
+        }
+    }
+
     #[derive(Clone)]
     pub struct Histogram {
         hist: HistogramVec,
@@ -99,25 +307,122 @@ mod metrics_impl {
                 .observe_closure_duration(func)
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{bind, gather, serve, Metrics};
+        use crate::metrics::{MetricsAuth, MetricsListener};
+        use std::io::{Read, Write};
+        use std::net::{SocketAddr, TcpStream};
+        use std::sync::Arc;
+
+        /// Returns the HTTP status line of a metrics scrape
+        fn scrape(addr: SocketAddr, authorization: Option<&str>) -> String {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let mut request =
+                "GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n".to_owned();
+            if let Some(value) = authorization {
+                request += &format!("Authorization: {}\r\n", value);
+            }
+            request += "\r\n";
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response.lines().next().unwrap_or_default().to_owned()
+        }
+
+        fn start(auth: MetricsAuth) -> SocketAddr {
+            let listener = MetricsListener::Tcp("127.0.0.1:0".parse().unwrap());
+            let server = bind(&listener).unwrap().unwrap();
+            let addr = server.server_addr().to_ip().unwrap();
+            let metrics = Metrics::dummy();
+            let registries = Arc::clone(&metrics.registries);
+            std::thread::spawn(move || serve(server, registries, Some(auth)));
+            addr
+        }
+
+        #[test]
+        fn test_disabled() {
+            assert!(bind(&MetricsListener::Disabled).unwrap().is_none());
+        }
+
+        #[test]
+        fn test_bearer_auth() {
+            let addr = start(MetricsAuth::Bearer("secret".to_owned()));
+            assert_eq!(scrape(addr, None), "HTTP/1.1 401 Unauthorized");
+            assert_eq!(
+                scrape(addr, Some("Bearer wrong")),
+                "HTTP/1.1 401 Unauthorized"
+            );
+            assert_eq!(scrape(addr, Some("Bearer secret")), "HTTP/1.1 200 OK");
+        }
+
+        #[test]
+        fn test_basic_auth() {
+            let addr = start(MetricsAuth::Basic {
+                user: "user".to_owned(),
+                password: "pass".to_owned(),
+            });
+            let wrong = "Basic dXNlcjp3cm9uZw=="; // user:wrong
+            assert_eq!(scrape(addr, Some(wrong)), "HTTP/1.1 401 Unauthorized");
+            let valid = "Basic dXNlcjpwYXNz"; // user:pass
+            assert_eq!(scrape(addr, Some(valid)), "HTTP/1.1 200 OK");
+        }
+
+        #[test]
+        fn test_info() {
+            let metrics = Metrics::dummy();
+            let tip = metrics.info("electrs_chain_tip_info", "Chain tip", &["hash", "height"]);
+            let series = || {
+                let families = gather(&metrics.registries.lock());
+                let family = families
+                    .iter()
+                    .find(|f| f.get_name() == "electrs_chain_tip_info")
+                    .expect("missing family");
+                family
+                    .get_metric()
+                    .iter()
+                    .map(|m| {
+                        let labels: Vec<&str> =
+                            m.get_label().iter().map(|l| l.get_value()).collect();
+                        (labels.join(","), m.get_gauge().get_value())
+                    })
+                    .collect::<Vec<_>>()
+            };
+            for (hash, height) in &[("aa", "1"), ("bb", "2"), ("bb", "2"), ("cc", "2")] {
+                tip.set(&[*hash, *height]);
+                assert_eq!(series(), vec![(format!("{},{}", hash, height), 1.0)]);
+            }
+        }
+    }
 }
 
 #[cfg(feature = "metrics")]
-pub use metrics_impl::{Gauge, Histogram, Metrics};
+pub use metrics_impl::{Counter, Gauge, Histogram, Info, Metrics};
 
 #[cfg(not(feature = "metrics"))]
 mod metrics_fake {
     use anyhow::Result;
 
-    use std::net::SocketAddr;
+    use super::MetricsOptions;
 
     pub struct Metrics {}
 
     impl Metrics {
-        pub fn new(_addr: SocketAddr) -> Result<Self> {
+        pub fn new(_options: &MetricsOptions) -> Result<Self> {
             debug!("metrics collection is disabled");
             Ok(Self {})
         }
 
+        #[cfg(test)]
+        pub fn dummy() -> Self {
+            Self {}
+        }
+
+        pub fn with_network(&self, _network: &str) -> Result<Self> {
+            Ok(Self {})
+        }
+
         pub fn histogram_vec(
             &self,
             _name: &str,
@@ -131,6 +436,21 @@ mod metrics_fake {
         pub fn gauge(&self, _name: &str, _desc: &str, _label: &str) -> Gauge {
             Gauge {}
         }
+
+        pub fn counter(&self, _name: &str, _desc: &str, _label: &str) -> Counter {
+            Counter {}
+        }
+
+        pub fn info(&self, _name: &str, _desc: &str, _labels: &[&str]) -> Info {
+            Info {}
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct Counter {}
+
+    impl Counter {
+        pub fn inc(&self, _label: &str) {}
     }
 
     #[derive(Clone)]
@@ -140,6 +460,13 @@ mod metrics_fake {
         pub fn set(&self, _label: &str, _value: f64) {}
     }
 
+    #[derive(Clone)]
+    pub struct Info {}
+
+    impl Info {
+        pub fn set(&self, _values: &[&str]) {}
+    }
+
     #[derive(Clone)]
     pub struct Histogram {}
 
@@ -156,7 +483,7 @@ mod metrics_fake {
 }
 
 #[cfg(not(feature = "metrics"))]
-pub use metrics_fake::{Gauge, Histogram, Metrics};
+pub use metrics_fake::{Counter, Gauge, Histogram, Info, Metrics};
 
 pub(crate) fn default_duration_buckets() -> Vec<f64> {
     vec![
@@ -171,3 +498,19 @@ pub(crate) fn default_size_buckets() -> Vec<f64> {
         1e6, 2e6, 5e6, 1e7,
     ]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::MetricsAuth;
+
+    #[test]
+    fn test_auth_debug() {
+        let auth = MetricsAuth::Basic {
+            user: "user".to_owned(),
+            password: "pass".to_owned(),
+        };
+        assert_eq!(format!("{:?}", auth), "Basic(\"user\", \"<sensitive>\")");
+        let auth = MetricsAuth::Bearer("token".to_owned());
+        assert_eq!(format!("{:?}", auth), "Bearer(\"<sensitive>\")");
+    }
+}
@@ -0,0 +1,574 @@
+use anyhow::{Context, Result};
+use bitcoin::Network;
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+
+use std::{
+    cmp::min,
+    fs,
+    io::{Read, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    http::{parse_status, Url},
+    lock_order::{self, Level},
+    metrics::{Gauge, Metrics},
+    protocol::MirrorStats,
+    thread::spawn,
+};
+
+/// Maximum number of transactions pending submission, per mirror (older ones are dropped)
+const MAX_PENDING_TXS: usize = 1000;
+
+/// Larger responses are truncated (a `sendrawtransaction` response is much smaller)
+const MAX_RESPONSE_SIZE: u64 = 64 * 1024;
+
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(600);
+
+/// bitcoind's `RPC_VERIFY_ALREADY_IN_CHAIN` error code
+const ALREADY_IN_CHAIN: i64 = -27;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MirrorOptions {
+    pub path: PathBuf, // JSON list of mirror definitions (including their credentials)
+    pub max_attempts: u32, // give up a submission after this number of failed attempts
+    pub retry_delay: Duration, // before the first retry (doubled for each subsequent one)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Kind {
+    Bitcoind, // `sendrawtransaction` JSON-RPC call
+    Http,     // POST of the raw transaction's hex (e.g. an Esplora-compatible `/tx` endpoint)
+}
+
+impl Kind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Kind::Bitcoind => "bitcoind",
+            Kind::Http => "http",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Definition {
+    url: String,
+    kind: Kind,
+    #[serde(default)]
+    auth: Option<String>, // `USER:PASSWORD` for bitcoind's RPC
+    #[serde(default)]
+    network: Option<String>, // mirror only this network's broadcasts
+}
+
+#[derive(Default)]
+struct Stats {
+    submitted: AtomicUsize,
+    retries: AtomicUsize,
+    failed: AtomicUsize,
+    dropped: AtomicUsize,
+    last_error: Mutex<Option<String>>, // cleared after a successful submission
+}
+
+impl Stats {
+    fn set_last_error(&self, error: Option<String>) {
+        let _order = lock_order::acquire(Level::Mirrors);
+        *self.last_error.lock() = error;
+    }
+
+    fn to_wire(&self, endpoint: &Endpoint) -> MirrorStats {
+        let last_error = {
+            let _order = lock_order::acquire(Level::Mirrors);
+            self.last_error.lock().clone()
+        };
+        MirrorStats {
+            url: endpoint.url.clone(),
+            kind: endpoint.kind.as_str().to_owned(),
+            network: endpoint.network.map(|network| network.to_string()),
+            queued: endpoint.tx.len(),
+            submitted: self.submitted.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            healthy: last_error.is_none(),
+            last_error,
+        }
+    }
+}
+
+struct Endpoint {
+    url: String,
+    kind: Kind,
+    network: Option<Network>,
+    tx: Sender<String>,
+    rx: Receiver<String>, // for dropping the oldest transaction when the queue is full
+    stats: Arc<Stats>,
+}
+
+/// Asynchronous re-submission of broadcast transactions to external relay endpoints, by a
+/// separate thread per mirror (so their failures never affect the broadcasting clients).
+#[derive(Clone, Default)]
+pub(crate) struct Mirrors {
+    endpoints: Arc<Vec<Endpoint>>, // empty if disabled
+    network: Option<Network>,
+    dropped: Option<Gauge>,
+}
+
+impl Mirrors {
+    pub(crate) fn open(options: Option<&MirrorOptions>, metrics: &Metrics) -> Result<Self> {
+        let options = match options {
+            Some(options) => options,
+            None => return Ok(Self::default()),
+        };
+        let path = &options.path;
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("failed to read broadcast mirrors from {}", path.display()))?;
+        let definitions: Vec<Definition> = serde_json::from_str(&content)
+            .with_context(|| format!("invalid broadcast mirrors in {}", path.display()))?;
+        let endpoints = definitions
+            .into_iter()
+            .map(|definition| start(definition, options))
+            .collect::<Result<Vec<Endpoint>>>()?;
+        info!("mirroring broadcasts to {} endpoints", endpoints.len());
+        let dropped = metrics.gauge(
+            "broadcast_mirror_dropped",
+            "# of broadcast transactions dropped from a full mirror queue",
+            "url",
+        );
+        Ok(Self {
+            endpoints: Arc::new(endpoints),
+            network: None,
+            dropped: Some(dropped),
+        })
+    }
+
+    /// Mirror only the broadcasts of the given network (to the endpoints configured for it).
+    pub(crate) fn with_network(&self, network: Network) -> Self {
+        Self {
+            endpoints: Arc::clone(&self.endpoints),
+            network: Some(network),
+            dropped: self.dropped.clone(),
+        }
+    }
+
+    /// Queue a successfully broadcast transaction for submission to the matching mirrors.
+    pub(crate) fn broadcast(&self, raw_tx: &str) {
+        let endpoints = self
+            .endpoints
+            .iter()
+            .filter(|endpoint| endpoint.network.is_none() || endpoint.network == self.network);
+        for endpoint in endpoints {
+            let mut raw_tx = raw_tx.to_owned();
+            while let Err(TrySendError::Full(rejected)) = endpoint.tx.try_send(raw_tx) {
+                raw_tx = rejected;
+                if endpoint.rx.try_recv().is_err() {
+                    continue; // the queue was emptied meanwhile
+                }
+                let dropped = endpoint.stats.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                if let Some(gauge) = &self.dropped {
+                    gauge.set(&endpoint.url, dropped as f64);
+                }
+                if dropped % 100 == 1 {
+                    warn!(
+                        "broadcast mirror {} is lagging: {} transactions dropped",
+                        endpoint.url, dropped
+                    );
+                }
+            }
+        }
+    }
+
+    pub(crate) fn stats(&self) -> Vec<MirrorStats> {
+        self.endpoints
+            .iter()
+            .map(|endpoint| endpoint.stats.to_wire(endpoint))
+            .collect()
+    }
+}
+
+fn start(definition: Definition, options: &MirrorOptions) -> Result<Endpoint> {
+    let url = Url::parse(&definition.url)?;
+    let network = match &definition.network {
+        Some(network) => Some(
+            network
+                .parse::<Network>()
+                .with_context(|| format!("invalid network for mirror {}", definition.url))?,
+        ),
+        None => None,
+    };
+    let auth = match (definition.kind, definition.auth) {
+        (Kind::Bitcoind, Some(auth)) => {
+            ensure!(
+                auth.contains(':'),
+                "invalid auth for mirror {} (expected USER:PASSWORD)",
+                definition.url
+            );
+            Some(base64::encode(auth))
+        }
+        (Kind::Bitcoind, None) => bail!("missing auth for bitcoind mirror {}", definition.url),
+        (Kind::Http, Some(_)) => bail!("unexpected auth for HTTP mirror {}", definition.url),
+        (Kind::Http, None) => None,
+    };
+    let (tx, rx) = bounded(MAX_PENDING_TXS);
+    let stats = Arc::new(Stats::default());
+    let submission = Submission {
+        url,
+        kind: definition.kind,
+        auth,
+        max_attempts: options.max_attempts,
+        retry_delay: options.retry_delay,
+        stats: Arc::clone(&stats),
+    };
+    let queue = rx.clone();
+    spawn("broadcast_mirror", move || submission.run(queue));
+    Ok(Endpoint {
+        url: definition.url,
+        kind: definition.kind,
+        network,
+        tx,
+        rx,
+        stats,
+    })
+}
+
+struct Submission {
+    url: Url,
+    kind: Kind,
+    auth: Option<String>, // base64-encoded credentials
+    max_attempts: u32,
+    retry_delay: Duration,
+    stats: Arc<Stats>,
+}
+
+impl Submission {
+    fn run(self, rx: Receiver<String>) -> Result<()> {
+        for raw_tx in rx.iter() {
+            self.submit(&raw_tx);
+        }
+        Ok(())
+    }
+
+    /// Retry with exponential backoff, until the transaction is accepted or given up.
+    fn submit(&self, raw_tx: &str) {
+        let mut delay = self.retry_delay;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let err = match self.post(raw_tx) {
+                Ok(()) => {
+                    self.stats.submitted.fetch_add(1, Ordering::Relaxed);
+                    self.stats.set_last_error(None);
+                    return;
+                }
+                Err(err) => err,
+            };
+            self.stats.set_last_error(Some(format!("{:#}", err)));
+            if attempts >= self.max_attempts {
+                warn!(
+                    "failed to mirror broadcast after {} attempts: {:#}",
+                    attempts, err
+                );
+                self.stats.failed.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            debug!("retrying broadcast mirror in {:?}: {:#}", delay, err);
+            self.stats.retries.fetch_add(1, Ordering::Relaxed);
+            thread::sleep(delay);
+            delay = min(delay * 2, MAX_RETRY_DELAY);
+        }
+    }
+
+    fn post(&self, raw_tx: &str) -> Result<()> {
+        let (content_type, body) = match self.kind {
+            Kind::Bitcoind => {
+                let request = json!({
+                    "jsonrpc": "1.0",
+                    "id": "electrs-mirror",
+                    "method": "sendrawtransaction",
+                    "params": [raw_tx],
+                });
+                ("application/json", request.to_string())
+            }
+            Kind::Http => ("text/plain", raw_tx.to_owned()),
+        };
+        let url = &self.url;
+        let mut stream = url.connect()?;
+        let authorization = match &self.auth {
+            Some(auth) => format!("Authorization: Basic {}\r\n", auth),
+            None => String::new(),
+        };
+        let request = format!(
+            concat!(
+                "POST {} HTTP/1.1\r\n",
+                "Host: {}\r\n",
+                "Content-Type: {}\r\n",
+                "Content-Length: {}\r\n",
+                "{}",
+                "Connection: close\r\n\r\n",
+                "{}"
+            ),
+            url.path,
+            url.authority,
+            content_type,
+            body.len(),
+            authorization,
+            body
+        );
+        stream
+            .write_all(request.as_bytes())
+            .context("failed to send request")?;
+        let mut response = String::new();
+        stream
+            .take(MAX_RESPONSE_SIZE)
+            .read_to_string(&mut response)
+            .context("failed to read response")?;
+        let (head, body) = match response.find("\r\n\r\n") {
+            Some(i) => (&response[..i], &response[i + 4..]),
+            None => (response.as_str(), ""),
+        };
+        let status = parse_status(head.lines().next().unwrap_or_default())?;
+        match self.kind {
+            Kind::Bitcoind => check_rpc_response(status, body),
+            Kind::Http => {
+                ensure!((200..300).contains(&status), "HTTP status {}", status);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// bitcoind responds to failed RPC calls with an error status, and a JSON error in the body
+/// (a transaction which is already confirmed is considered as successfully submitted).
+fn check_rpc_response(status: u16, body: &str) -> Result<()> {
+    let response: Value = match serde_json::from_str(body) {
+        Ok(response) => response,
+        Err(_) => bail!("HTTP status {}", status),
+    };
+    let error = &response["error"];
+    if error.is_null() {
+        ensure!((200..300).contains(&status), "HTTP status {}", status);
+        return Ok(());
+    }
+    if error["code"].as_i64() == Some(ALREADY_IN_CHAIN) {
+        return Ok(());
+    }
+    bail!(
+        "RPC error {}: {}",
+        error["code"],
+        error["message"].as_str().unwrap_or_default()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_rpc_response, MirrorOptions, Mirrors};
+    use crate::metrics::Metrics;
+    use bitcoin::Network;
+    use crossbeam_channel::{unbounded, Receiver};
+    use serde_json::{json, Value};
+    use std::cmp::min;
+    use std::fs;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::path::Path;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    struct Request {
+        authorization: Option<String>,
+        body: String,
+    }
+
+    /// Responds to each request with the next response (repeating the last one).
+    fn serve(responses: Vec<(u16, &'static str)>) -> (String, Receiver<Request>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/tx", listener.local_addr().unwrap());
+        let (tx, rx) = unbounded();
+        thread::spawn(move || {
+            for (i, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                assert_eq!(request_line, "POST /tx HTTP/1.1\r\n");
+                let (mut len, mut authorization) = (0, None);
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.strip_prefix("Content-Length: ") {
+                        len = value.parse().unwrap();
+                    }
+                    if let Some(value) = line.strip_prefix("Authorization: ") {
+                        authorization = Some(value.to_owned());
+                    }
+                }
+                let mut body = vec![0; len];
+                reader.read_exact(&mut body).unwrap();
+                let body = String::from_utf8(body).unwrap();
+                let (status, content) = responses[min(i, responses.len() - 1)];
+                write!(
+                    stream,
+                    "HTTP/1.1 {} X\r\nContent-Length: {}\r\n\r\n{}",
+                    status,
+                    content.len(),
+                    content
+                )
+                .unwrap();
+                if tx
+                    .send(Request {
+                        authorization,
+                        body,
+                    })
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        });
+        (url, rx)
+    }
+
+    fn open(dir: &Path, definitions: Value, max_attempts: u32) -> Mirrors {
+        let path = dir.join("mirrors.json");
+        fs::write(&path, definitions.to_string()).unwrap();
+        let options = MirrorOptions {
+            path,
+            max_attempts,
+            retry_delay: Duration::from_millis(10),
+        };
+        Mirrors::open(Some(&options), &Metrics::dummy()).unwrap()
+    }
+
+    /// Submission statistics are updated after the endpoint responds.
+    fn wait_for_stats(mirrors: &Mirrors, key: &str, expected: usize) -> Value {
+        let start = Instant::now();
+        loop {
+            let stats = json!(mirrors.stats()[0]);
+            if stats[key] == json!(expected) {
+                return stats;
+            }
+            assert!(start.elapsed() < Duration::from_secs(10), "{}", stats);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_bitcoind_retries() {
+        let (url, requests) = serve(vec![
+            (
+                500,
+                r#"{"result":null,"error":{"code":-25,"message":"missing inputs"},"id":1}"#,
+            ),
+            (200, r#"{"result":"00","error":null,"id":1}"#),
+        ]);
+        let dir = tempfile::tempdir().unwrap();
+        let definitions = json!([{"url": url, "kind": "bitcoind", "auth": "user:pass"}]);
+        let mirrors = open(dir.path(), definitions, 3).with_network(Network::Regtest);
+
+        mirrors.broadcast("0100");
+        let first = requests.recv().unwrap();
+        let second = requests.recv().unwrap(); // retried after a failure
+        assert_eq!(first.body, second.body);
+        assert_eq!(
+            first.authorization.as_deref(),
+            Some(format!("Basic {}", base64::encode("user:pass")).as_str())
+        );
+        let request: Value = serde_json::from_str(&first.body).unwrap();
+        assert_eq!(request["method"], "sendrawtransaction");
+        assert_eq!(request["params"], json!(["0100"]));
+
+        let stats = wait_for_stats(&mirrors, "submitted", 1);
+        assert_eq!(stats["url"], json!(url));
+        assert_eq!(stats["kind"], "bitcoind");
+        assert_eq!(stats["retries"], 1);
+        assert_eq!(stats["failed"], 0);
+        assert_eq!(stats["healthy"], true);
+        assert_eq!(stats["queued"], 0);
+    }
+
+    #[test]
+    fn test_http_failures() {
+        let (url, requests) = serve(vec![(400, "bad tx")]);
+        let dir = tempfile::tempdir().unwrap();
+        let definitions = json!([
+            {"url": url, "kind": "http", "network": "testnet"},
+        ]);
+        let mirrors = open(dir.path(), definitions, 2);
+
+        mirrors.with_network(Network::Bitcoin).broadcast("0100"); // another network
+        mirrors.with_network(Network::Testnet).broadcast("0200");
+        for _ in 0..2 {
+            let request = requests.recv().unwrap();
+            assert_eq!(request.body, "0200");
+            assert_eq!(request.authorization, None);
+        }
+        let stats = wait_for_stats(&mirrors, "failed", 1);
+        assert_eq!(stats["submitted"], 0);
+        assert_eq!(stats["retries"], 1);
+        assert_eq!(stats["network"], "testnet");
+        assert_eq!(stats["healthy"], false);
+        assert_eq!(stats["last_error"], "HTTP status 400");
+        assert!(requests.try_recv().is_err()); // no more attempts
+    }
+
+    #[test]
+    fn test_rpc_response() {
+        assert!(check_rpc_response(200, r#"{"result":"00","error":null}"#).is_ok());
+        let in_chain = r#"{"result":null,"error":{"code":-27,"message":"already in chain"}}"#;
+        assert!(check_rpc_response(500, in_chain).is_ok());
+        let rejected = r#"{"result":null,"error":{"code":-26,"message":"dust"}}"#;
+        assert_eq!(
+            check_rpc_response(500, rejected).unwrap_err().to_string(),
+            "RPC error -26: dust"
+        );
+        assert_eq!(
+            check_rpc_response(401, "").unwrap_err().to_string(),
+            "HTTP status 401"
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mirrors.json");
+        let options = MirrorOptions {
+            path: path.clone(),
+            max_attempts: 1,
+            retry_delay: Duration::from_millis(10),
+        };
+        let metrics = Metrics::dummy();
+        assert!(Mirrors::open(Some(&options), &metrics).is_err()); // missing file
+        let invalid = vec![
+            json!([{"url": "https://example.com/", "kind": "http"}]),
+            json!([{"url": "http://example.com/", "kind": "electrum"}]),
+            json!([{"url": "http://example.com/", "kind": "bitcoind"}]),
+            json!([{"url": "http://example.com/", "kind": "bitcoind", "auth": "cookie"}]),
+            json!([{"url": "http://example.com/", "kind": "http", "auth": "user:pass"}]),
+            json!([{"url": "http://example.com/", "kind": "http", "network": "moon"}]),
+        ];
+        for definitions in invalid {
+            fs::write(&path, definitions.to_string()).unwrap();
+            assert!(
+                Mirrors::open(Some(&options), &metrics).is_err(),
+                "{}",
+                definitions
+            );
+        }
+        let mirrors = Mirrors::open(None, &metrics).unwrap();
+        mirrors.broadcast("0100");
+        assert!(mirrors.stats().is_empty());
+    }
+}
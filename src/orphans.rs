@@ -0,0 +1,184 @@
+use bitcoin::{Block, BlockHash, Txid};
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::types::ScriptHash;
+
+/// Orphaned blocks are forgotten when they are this deep below the tip (~1 week)
+const MAX_ORPHAN_DEPTH: usize = 1008;
+
+/// Bounds the record's memory usage (oldest transactions are forgotten first)
+const MAX_ORPHANED_TXS: usize = 10_000;
+
+/// A transaction confirmed in a block which was later orphaned by a reorg
+struct OrphanedTx {
+    txid: Txid,
+    blockhash: BlockHash,
+    height: usize,
+    funded: Vec<ScriptHash>, // by the transaction's outputs
+}
+
+/// Bounded record of the transactions confirmed in orphaned blocks (oldest first), so clients can
+/// find out what happened to a "confirmed" transaction which disappeared after a reorg.
+#[derive(Default)]
+pub(crate) struct Orphans {
+    txs: VecDeque<OrphanedTx>,
+    blocks: HashMap<BlockHash, usize>, // already recorded (with their heights)
+    latest: HashMap<Txid, (BlockHash, usize)>, // the latest orphaning of each transaction
+}
+
+impl Orphans {
+    /// Whether the orphaned block should be recorded (i.e. it's new, and not too deep)
+    pub(crate) fn is_new(&self, blockhash: &BlockHash, height: usize, tip_height: usize) -> bool {
+        height + MAX_ORPHAN_DEPTH >= tip_height && !self.blocks.contains_key(blockhash)
+    }
+
+    /// Record the orphaned block (its transactions may be added later, if the block is available)
+    pub(crate) fn add_block(&mut self, blockhash: BlockHash, height: usize) {
+        self.blocks.insert(blockhash, height);
+    }
+
+    pub(crate) fn add_txs(&mut self, blockhash: BlockHash, height: usize, block: &Block) {
+        for tx in &block.txdata {
+            let txid = tx.txid();
+            let funded = tx
+                .output
+                .iter()
+                .map(|txout| ScriptHash::new(&txout.script_pubkey))
+                .collect();
+            self.txs.push_back(OrphanedTx {
+                txid,
+                blockhash,
+                height,
+                funded,
+            });
+            self.latest.insert(txid, (blockhash, height));
+        }
+        while self.txs.len() > MAX_ORPHANED_TXS {
+            let tx = self.txs.pop_front().unwrap();
+            self.forget(&tx);
+        }
+    }
+
+    /// Forget the blocks (and their transactions) which are too deep below the tip.
+    pub(crate) fn prune(&mut self, tip_height: usize) {
+        let min_height = tip_height.saturating_sub(MAX_ORPHAN_DEPTH);
+        self.blocks.retain(|_, height| *height >= min_height);
+        let (kept, forgotten): (VecDeque<_>, VecDeque<_>) =
+            self.txs.drain(..).partition(|tx| tx.height >= min_height);
+        self.txs = kept;
+        for tx in forgotten {
+            self.forget(&tx);
+        }
+    }
+
+    fn forget(&mut self, tx: &OrphanedTx) {
+        if self.latest.get(&tx.txid) == Some(&(tx.blockhash, tx.height)) {
+            self.latest.remove(&tx.txid);
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.txs.is_empty()
+    }
+
+    /// The latest orphaned block containing the transaction, unless it was at the same `height`
+    /// (i.e. the transaction's height didn't change).
+    pub(crate) fn reorged_from(&self, txid: &Txid, height: usize) -> Option<BlockHash> {
+        self.latest
+            .get(txid)
+            .filter(|(_, orphaned_height)| *orphaned_height != height)
+            .map(|(blockhash, _)| *blockhash)
+    }
+
+    /// Orphaned transactions (funding the scripthash, if specified), oldest first
+    pub(crate) fn iter(
+        &self,
+        scripthash: Option<ScriptHash>,
+    ) -> impl Iterator<Item = (Txid, BlockHash, usize)> + '_ {
+        self.txs
+            .iter()
+            .filter(move |tx| scripthash.map_or(true, |s| tx.funded.contains(&s)))
+            .map(|tx| (tx.txid, tx.blockhash, tx.height))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Orphans, MAX_ORPHANED_TXS, MAX_ORPHAN_DEPTH};
+    use crate::types::ScriptHash;
+    use bitcoin::{
+        absolute::LockTime, block, hashes::Hash, Block, BlockHash, CompactTarget, ScriptBuf,
+        Transaction, TxMerkleNode, TxOut,
+    };
+
+    fn block(n: usize, txs: usize) -> Block {
+        let txdata = (0..txs)
+            .map(|i| Transaction {
+                version: 2,
+                lock_time: LockTime::from_consensus((n * MAX_ORPHANED_TXS + i) as u32),
+                input: vec![],
+                output: vec![TxOut {
+                    value: 1000,
+                    script_pubkey: ScriptBuf::from(vec![n as u8]),
+                }],
+            })
+            .collect();
+        Block {
+            header: block::Header {
+                version: block::Version::ONE,
+                prev_blockhash: BlockHash::all_zeros(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: 0,
+                bits: CompactTarget::from_consensus(0),
+                nonce: n as u32,
+            },
+            txdata,
+        }
+    }
+
+    #[test]
+    fn test_record() {
+        let mut orphans = Orphans::default();
+        let (b1, b2) = (block(1, 2), block(2, 1));
+        let h1 = b1.block_hash();
+        assert!(orphans.is_new(&h1, 100, 101));
+        assert!(!orphans.is_new(&h1, 100, 101 + MAX_ORPHAN_DEPTH)); // too deep
+        orphans.add_block(h1, 100);
+        orphans.add_txs(h1, 100, &b1);
+        assert!(!orphans.is_new(&h1, 100, 101));
+        orphans.add_block(b2.block_hash(), 100);
+        orphans.add_txs(b2.block_hash(), 100, &b2);
+
+        let script = ScriptHash::new(&ScriptBuf::from(vec![1]));
+        let funding: Vec<_> = orphans.iter(Some(script)).map(|(txid, ..)| txid).collect();
+        let expected: Vec<_> = b1.txdata.iter().map(|tx| tx.txid()).collect();
+        assert_eq!(funding, expected);
+        assert_eq!(orphans.iter(None).count(), 3);
+
+        // re-included at another height
+        let txid = expected[0];
+        assert_eq!(orphans.reorged_from(&txid, 101), Some(h1));
+        assert_eq!(orphans.reorged_from(&txid, 100), None);
+
+        orphans.prune(100 + MAX_ORPHAN_DEPTH);
+        assert_eq!(orphans.iter(None).count(), 3);
+        orphans.prune(101 + MAX_ORPHAN_DEPTH);
+        assert!(orphans.is_empty());
+        assert_eq!(orphans.reorged_from(&txid, 101), None);
+        assert!(orphans.is_new(&h1, 100, 100));
+    }
+
+    #[test]
+    fn test_bounded() {
+        let mut orphans = Orphans::default();
+        let (b1, b2) = (block(1, MAX_ORPHANED_TXS), block(2, 10));
+        orphans.add_txs(b1.block_hash(), 100, &b1);
+        orphans.add_txs(b2.block_hash(), 101, &b2);
+        assert_eq!(orphans.iter(None).count(), MAX_ORPHANED_TXS);
+        let oldest = b1.txdata[0].txid();
+        assert_eq!(orphans.reorged_from(&oldest, 200), None); // forgotten
+        let newest = b2.txdata[9].txid();
+        assert_eq!(orphans.reorged_from(&newest, 200), Some(b2.block_hash()));
+    }
+}
@@ -18,6 +18,7 @@ use bitcoin::{
 };
 use crossbeam_channel::{bounded, select, Receiver, Sender};
 
+use std::collections::{HashMap, VecDeque};
 use std::io::{self, ErrorKind, Write};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
 use std::sync::Arc;
@@ -52,6 +53,48 @@ impl Request {
     }
 }
 
+/// Blocks received ahead of their turn are kept until the preceding ones are received, so they are
+/// processed in the requested (i.e. height) order. Only the requested blocks are accepted, so the
+/// buffer is bounded by the request's size.
+struct Reorder {
+    expected: VecDeque<BlockHash>,
+    received: HashMap<BlockHash, Block>,
+}
+
+impl Reorder {
+    fn new(blockhashes: Vec<BlockHash>) -> Self {
+        Self {
+            expected: blockhashes.into(),
+            received: HashMap::new(),
+        }
+    }
+
+    /// The next block to be processed (`None` if all were processed)
+    fn next(&self) -> Option<BlockHash> {
+        self.expected.front().copied()
+    }
+
+    /// Returns the blocks that can be processed now (in the requested order).
+    fn push(&mut self, block: Block) -> Result<Vec<(BlockHash, Block)>> {
+        let hash = block.block_hash();
+        ensure!(
+            self.expected.contains(&hash) && !self.received.contains_key(&hash),
+            "got unexpected block {}",
+            hash
+        );
+        if self.next() != Some(hash) {
+            debug!("got block {} out of order", hash);
+        }
+        self.received.insert(hash, block);
+        let mut ready = vec![];
+        while let Some(block) = self.next().and_then(|next| self.received.remove(&next)) {
+            let hash = self.expected.pop_front().expect("missing next block");
+            ready.push((hash, block));
+        }
+        Ok(ready)
+    }
+}
+
 pub(crate) struct Connection {
     req_send: Sender<Request>,
     blocks_recv: Receiver<Block>,
@@ -106,17 +149,19 @@ impl Connection {
                 self.req_send.send(Request::get_blocks(&blockhashes))
             })?;
 
-            for hash in blockhashes {
-                let block = self.blocks_duration.observe_duration("response", || {
+            let mut reorder = Reorder::new(blockhashes);
+            while let Some(next) = reorder.next() {
+                let ready = self.blocks_duration.observe_duration("response", || {
                     let block = self
                         .blocks_recv
                         .recv()
-                        .with_context(|| format!("failed to get block {}", hash))?;
-                    ensure!(block.block_hash() == hash, "got unexpected block");
-                    Ok(block)
+                        .with_context(|| format!("failed to get block {}", next))?;
+                    reorder.push(block)
                 })?;
-                self.blocks_duration
-                    .observe_duration("process", || func(hash, block));
+                for (hash, block) in ready {
+                    self.blocks_duration
+                        .observe_duration("process", || func(hash, block));
+                }
             }
             Ok(())
         })
@@ -395,3 +440,50 @@ pub fn duration_to_seconds(d: Duration) -> f64 {
     let nanos = f64::from(d.subsec_nanos()) / 1e9;
     d.as_secs() as f64 + nanos
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Reorder;
+    use bitcoin::{blockdata::constants::genesis_block, Block, BlockHash, Network};
+
+    fn hashes(ready: Vec<(BlockHash, Block)>) -> Vec<BlockHash> {
+        ready.into_iter().map(|(hash, _block)| hash).collect()
+    }
+
+    #[test]
+    fn test_reorder() {
+        let blocks: Vec<Block> = [Network::Bitcoin, Network::Testnet, Network::Signet]
+            .iter()
+            .map(|network| genesis_block(*network))
+            .collect();
+        let blockhashes: Vec<BlockHash> = blocks.iter().map(Block::block_hash).collect();
+        let mut reorder = Reorder::new(blockhashes.clone());
+        assert_eq!(reorder.next(), Some(blockhashes[0]));
+
+        // kept until the preceding blocks are received
+        let ready = reorder.push(blocks[2].clone()).unwrap();
+        assert!(ready.is_empty());
+        assert_eq!(reorder.next(), Some(blockhashes[0]));
+        let err = reorder.push(blocks[2].clone()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("got unexpected block {}", blockhashes[2])
+        );
+
+        let ready = reorder.push(blocks[0].clone()).unwrap();
+        assert_eq!(hashes(ready), vec![blockhashes[0]]);
+        let ready = reorder.push(blocks[1].clone()).unwrap();
+        assert_eq!(hashes(ready), vec![blockhashes[1], blockhashes[2]]);
+        assert_eq!(reorder.next(), None);
+
+        // only the requested blocks are accepted
+        let err = reorder.push(blocks[0].clone()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("got unexpected block {}", blockhashes[0])
+        );
+        let regtest = genesis_block(Network::Regtest);
+        let mut reorder = Reorder::new(blockhashes);
+        assert!(reorder.push(regtest).is_err());
+    }
+}
@@ -0,0 +1,796 @@
+use anyhow::{Context, Result};
+use bitcoin::BlockHash;
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender, TrySendError};
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, BufReader, Read, Write},
+    net::{IpAddr, TcpStream, ToSocketAddrs},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    clock::Clock,
+    config::ELECTRS_VERSION,
+    hex::HashParam,
+    hostnames::normalize,
+    lock_order::{self, Level},
+    protocol::PeerEntry,
+    sanitize::Sanitized,
+    thread::spawn,
+};
+
+const DEFAULT_TCP_PORT: u16 = 50001;
+const DEFAULT_SSL_PORT: u16 = 50002;
+
+/// Maximum number of peers stored via `server.add_peer` (the oldest pending ones are evicted)
+const MAX_ANNOUNCED_PEERS: usize = 100;
+
+/// Maximum number of hosts per `server.add_peer` call (e.g. a clearnet and an onion one)
+const MAX_ANNOUNCED_HOSTS: usize = 4;
+
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Verified peers are verified again after this duration (and dropped if they fail)
+const REVERIFY_INTERVAL: Duration = Duration::from_secs(6 * 3600);
+
+/// Verified peers expire if they weren't verified again for this long (e.g. if verification is
+/// lagging), so announced peers can't stay advertised for good
+const VERIFIED_PEER_TTL: Duration = Duration::from_secs(24 * 3600);
+
+/// How often the verified peers are checked for re-verification
+const REVERIFY_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_VERIFY_RESPONSE_SIZE: u64 = 100_000;
+
+/// Another server, advertised to the clients
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct PeerServer {
+    ip: String, // the hostname, if the IP address is not configured (e.g. for onion services)
+    hostname: String,
+    features: Vec<String>, // e.g. "v1.4", "p10000", "t50001", "s50002"
+}
+
+impl PeerServer {
+    /// Parse an entry formatted as "hostname[@ip] feature...", e.g. "electrum.example.org t s50002".
+    fn parse(entry: &str) -> Result<Self> {
+        let mut parts = entry.split_whitespace();
+        let host = parts.next().context("missing hostname")?;
+        let mut host_parts = host.splitn(2, '@');
+        let hostname = check_hostname(host_parts.next().unwrap())?;
+        let ip = match host_parts.next() {
+            Some(ip) => ip
+                .parse::<IpAddr>()
+                .map_err(|_| anyhow!("invalid IP address '{}'", ip))?
+                .to_string(),
+            None => hostname.clone(),
+        };
+        let features: Vec<String> = parts.map(str::to_owned).collect();
+        for (i, feature) in features.iter().enumerate() {
+            check_feature(feature)?;
+            ensure!(
+                !features[..i]
+                    .iter()
+                    .any(|f| f.chars().next() == feature.chars().next()),
+                "duplicate feature '{}'",
+                feature
+            );
+        }
+        ensure!(
+            features
+                .iter()
+                .any(|f| f.starts_with('t') || f.starts_with('s')),
+            "missing TCP or SSL port (e.g. 't50001' or 's50002')"
+        );
+        Ok(Self {
+            ip,
+            hostname,
+            features,
+        })
+    }
+
+    fn port(&self, prefix: char, default: u16) -> Option<u16> {
+        let feature = self.features.iter().find(|f| f.starts_with(prefix))?;
+        Some(feature[1..].parse().unwrap_or(default)) // validated when parsed
+    }
+}
+
+fn check_hostname(hostname: &str) -> Result<String> {
+    let hostname = normalize(hostname);
+    ensure!(
+        !hostname.is_empty()
+            && hostname
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.'),
+        "invalid hostname '{}'",
+        Sanitized(&hostname)
+    );
+    Ok(hostname)
+}
+
+/// The feature's letter, followed by its value: the protocol version ('v'), the pruning limit
+/// ('p'), or the TCP ('t') and SSL ('s') ports (which may be omitted for the default ones).
+fn check_feature(feature: &str) -> Result<()> {
+    let mut chars = feature.chars();
+    let letter = chars.next();
+    let value = chars.as_str();
+    let valid = match letter {
+        Some('v') => {
+            !value.is_empty()
+                && value
+                    .split('.')
+                    .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()))
+        }
+        Some('p') => value.parse::<u64>().is_ok(),
+        Some('t') | Some('s') => value.is_empty() || value.parse::<u16>().map_or(false, |p| p > 0),
+        _ => false,
+    };
+    ensure!(valid, "invalid feature '{}'", Sanitized(feature));
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct Ports {
+    tcp_port: Option<u16>,
+    ssl_port: Option<u16>,
+}
+
+/// Another server's `server.features` result, sent via `server.add_peer` (other fields are ignored)
+#[derive(Deserialize)]
+pub(crate) struct Announcement {
+    genesis_hash: HashParam<BlockHash>,
+    hosts: BTreeMap<String, Ports>,
+    protocol_max: Option<String>,
+    pruning: Option<u64>,
+}
+
+impl Announcement {
+    fn servers(&self) -> Result<Vec<PeerServer>> {
+        ensure!(!self.hosts.is_empty(), "missing hosts");
+        ensure!(
+            self.hosts.len() <= MAX_ANNOUNCED_HOSTS,
+            "too many hosts: {} (at most {})",
+            self.hosts.len(),
+            MAX_ANNOUNCED_HOSTS
+        );
+        let mut common = vec![];
+        if let Some(version) = &self.protocol_max {
+            common.push(format!("v{}", version));
+        }
+        if let Some(pruning) = self.pruning {
+            common.push(format!("p{}", pruning));
+        }
+        self.hosts
+            .iter()
+            .map(|(hostname, ports)| {
+                let hostname = check_hostname(hostname)?;
+                let mut features = common.clone();
+                if let Some(port) = ports.tcp_port {
+                    features.push(format!("t{}", port));
+                }
+                if let Some(port) = ports.ssl_port {
+                    features.push(format!("s{}", port));
+                }
+                for feature in &features {
+                    check_feature(feature)?;
+                }
+                ensure!(
+                    ports.tcp_port.is_some() || ports.ssl_port.is_some(),
+                    "missing TCP or SSL port for '{}'",
+                    hostname
+                );
+                Ok(PeerServer {
+                    ip: hostname.clone(),
+                    hostname,
+                    features,
+                })
+            })
+            .collect()
+    }
+}
+
+struct Announced {
+    server: PeerServer,
+    genesis_hash: BlockHash,
+    // when its `server.features` were last fetched via TCP (and its genesis hash matched),
+    // `None` while pending
+    verified: Option<Instant>,
+}
+
+impl Announced {
+    fn is_verified(&self) -> bool {
+        self.verified.is_some()
+    }
+
+    /// SSL-only and onion peers can't be verified
+    fn verification(&self) -> Option<Verification> {
+        if self.server.hostname.ends_with(".onion") {
+            return None;
+        }
+        Some(Verification {
+            hostname: self.server.hostname.clone(),
+            tcp_port: self.server.port('t', DEFAULT_TCP_PORT)?,
+            genesis_hash: self.genesis_hash,
+        })
+    }
+}
+
+/// Drop the verified peers which weren't verified again in time.
+fn expire(announced: &mut Vec<Announced>, now: Instant) {
+    announced.retain(|a| match a.verified {
+        Some(verified) if now.saturating_duration_since(verified) >= VERIFIED_PEER_TTL => {
+            info!("peer {} expired", a.server.hostname);
+            false
+        }
+        _ => true,
+    });
+}
+
+/// The verified peers due for re-verification
+fn reverifications(announced: &[Announced], now: Instant) -> Vec<Verification> {
+    announced
+        .iter()
+        .filter(|a| match a.verified {
+            Some(verified) => now.saturating_duration_since(verified) >= REVERIFY_INTERVAL,
+            None => false,
+        })
+        .filter_map(Announced::verification)
+        .collect()
+}
+
+/// A peer to connect to (pending, or due for re-verification)
+struct Verification {
+    hostname: String,
+    tcp_port: u16,
+    genesis_hash: BlockHash,
+}
+
+/// Peer servers: statically configured ones (e.g. other instances run by the same operator),
+/// and ones announced via `server.add_peer` (advertised only after being verified).
+#[derive(Default)]
+pub(crate) struct Peers {
+    servers: Vec<PeerServer>,
+    announced: Arc<Mutex<Vec<Announced>>>, // ordered by their announcement
+    verification: Option<Sender<Verification>>, // `None` if disabled
+    clock: Clock,                          // for the verified peers' expiry
+}
+
+impl Peers {
+    /// Parse comma-separated entries (see `PeerServer::parse`), and optionally start verifying
+    /// the announced peers (by a separate thread, connecting back to them).
+    pub(crate) fn parse(value: Option<&str>, verify_announced: bool) -> Result<Self> {
+        let mut peers = Self::parse_servers(value)?;
+        if verify_announced {
+            let (tx, rx) = bounded(MAX_ANNOUNCED_PEERS);
+            let announced = Arc::clone(&peers.announced);
+            let clock = peers.clock.clone();
+            spawn("peer_verify", move || {
+                verify_announced_peers(rx, announced, clock)
+            });
+            peers.verification = Some(tx);
+        }
+        Ok(peers)
+    }
+
+    fn parse_servers(value: Option<&str>) -> Result<Self> {
+        let value = match value {
+            Some(value) => value,
+            None => return Ok(Self::default()),
+        };
+        let mut servers: Vec<PeerServer> = vec![];
+        for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let server =
+                PeerServer::parse(entry).with_context(|| format!("invalid peer '{}'", entry))?;
+            ensure!(
+                servers.iter().all(|s| s.hostname != server.hostname),
+                "duplicate peer '{}'",
+                server.hostname
+            );
+            servers.push(server);
+        }
+        info!("advertising {} peers", servers.len());
+        Ok(Self {
+            servers,
+            ..Self::default()
+        })
+    }
+
+    /// Store the announced hosts as pending peers (unless already known), after validating
+    /// them against our genesis block hash.
+    pub(crate) fn add(&self, announcement: &Announcement, genesis_hash: BlockHash) -> Result<()> {
+        let HashParam(announced_genesis_hash) = announcement.genesis_hash;
+        ensure!(
+            announced_genesis_hash == genesis_hash,
+            "genesis hash mismatch: {} (expected {})",
+            announced_genesis_hash,
+            genesis_hash
+        );
+        let servers = announcement.servers()?;
+        let _order = lock_order::acquire(Level::Peers);
+        let mut announced = self.announced.lock();
+        expire(&mut announced, self.clock.now());
+        for server in servers {
+            if self.servers.iter().any(|s| s.hostname == server.hostname) {
+                continue; // already configured
+            }
+            match announced
+                .iter()
+                .position(|a| a.server.hostname == server.hostname)
+            {
+                // re-announcing doesn't extend its expiry (only re-verification does)
+                Some(i) if announced[i].is_verified() => continue,
+                Some(i) => {
+                    announced.remove(i); // re-announced before being verified
+                }
+                None => {
+                    if announced.len() == MAX_ANNOUNCED_PEERS {
+                        let oldest_pending = announced
+                            .iter()
+                            .position(|a| !a.is_verified())
+                            .context("too many peers")?;
+                        announced.remove(oldest_pending);
+                    }
+                }
+            }
+            info!("peer {} announced: {:?}", server.hostname, server.features);
+            let peer = Announced {
+                server,
+                genesis_hash,
+                verified: None,
+            };
+            if let (Some(tx), Some(verification)) = (&self.verification, peer.verification()) {
+                if let Err(TrySendError::Full(_)) = tx.try_send(verification) {
+                    debug!("skipping verification of peer {}", peer.server.hostname);
+                }
+            }
+            announced.push(peer);
+        }
+        Ok(())
+    }
+
+    /// Formatted for `server.peers.subscribe` (as by ElectrumX)
+    pub(crate) fn subscribe_result(&self) -> Vec<PeerEntry> {
+        let _order = lock_order::acquire(Level::Peers);
+        let mut announced = self.announced.lock();
+        expire(&mut announced, self.clock.now());
+        let verified = announced
+            .iter()
+            .filter(|a| a.is_verified())
+            .map(|a| &a.server);
+        self.servers
+            .iter()
+            .chain(verified)
+            .map(|s| (s.ip.clone(), s.hostname.clone(), s.features.clone()))
+            .collect()
+    }
+
+    /// Advertised by `server.features`, if the client's hostname is one of the peers
+    pub(crate) fn hosts(&self, hostname: Option<&str>) -> Option<Value> {
+        let hostname = normalize(hostname?);
+        let server = self.servers.iter().find(|s| s.hostname == hostname)?;
+        let mut ports = json!({});
+        if let Some(port) = server.port('t', DEFAULT_TCP_PORT) {
+            ports["tcp_port"] = json!(port);
+        }
+        if let Some(port) = server.port('s', DEFAULT_SSL_PORT) {
+            ports["ssl_port"] = json!(port);
+        }
+        Some(json!({ hostname: ports }))
+    }
+}
+
+/// Verification connects only to publicly routable addresses (not to the server's own network).
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation())
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            let unicast = !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || first & 0xfe00 == 0xfc00 // unique local
+                || first & 0xffc0 == 0xfe80); // link-local
+            match ip.to_ipv4() {
+                Some(ip) if first == 0 => unicast && is_public(IpAddr::V4(ip)), // IPv4-mapped
+                _ => unicast,
+            }
+        }
+    }
+}
+
+/// Fetch the peer's `server.features` (after negotiating the protocol version).
+fn fetch_features(hostname: &str, port: u16) -> Result<Announcement> {
+    let addr = (hostname, port)
+        .to_socket_addrs()
+        .context("failed to resolve")?
+        .find(|addr| is_public(addr.ip()))
+        .context("no public address")?;
+    let stream = TcpStream::connect_timeout(&addr, VERIFY_TIMEOUT)?;
+    stream.set_read_timeout(Some(VERIFY_TIMEOUT))?;
+    stream.set_write_timeout(Some(VERIFY_TIMEOUT))?;
+    let client_name = format!("electrs/{}", ELECTRS_VERSION);
+    let requests = [
+        json!({"jsonrpc": "2.0", "id": 0, "method": "server.version", "params": [client_name, "1.4"]}),
+        json!({"jsonrpc": "2.0", "id": 1, "method": "server.features", "params": []}),
+    ];
+    let mut writer = &stream;
+    for request in &requests {
+        writeln!(writer, "{}", request)?;
+    }
+    let reader = BufReader::new(stream.take(MAX_VERIFY_RESPONSE_SIZE));
+    for line in reader.lines().take(requests.len()) {
+        let response: Value = serde_json::from_str(&line?)?;
+        if response["id"] == 1 {
+            return Ok(serde_json::from_value(response["result"].clone())?);
+        }
+    }
+    bail!("missing server.features response")
+}
+
+/// Verify the announced peers, and periodically re-verify the verified ones.
+fn verify_announced_peers(
+    rx: Receiver<Verification>,
+    announced: Arc<Mutex<Vec<Announced>>>,
+    clock: Clock,
+) -> Result<()> {
+    let mut next_check = clock.now() + REVERIFY_CHECK_INTERVAL;
+    loop {
+        match rx.recv_timeout(next_check.saturating_duration_since(clock.now())) {
+            Ok(verification) => verify(&verification, &announced, &clock),
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+        if clock.now() >= next_check {
+            let due = {
+                let _order = lock_order::acquire(Level::Peers);
+                let mut announced = announced.lock();
+                expire(&mut announced, clock.now());
+                reverifications(&announced, clock.now())
+            };
+            for verification in due {
+                verify(&verification, &announced, &clock);
+            }
+            next_check = clock.now() + REVERIFY_CHECK_INTERVAL;
+        }
+    }
+}
+
+fn verify(verification: &Verification, announced: &Mutex<Vec<Announced>>, clock: &Clock) {
+    let hostname = &verification.hostname;
+    let result = fetch_features(hostname, verification.tcp_port).and_then(|features| {
+        let HashParam(genesis_hash) = features.genesis_hash;
+        ensure!(
+            genesis_hash == verification.genesis_hash,
+            "genesis hash mismatch: {}",
+            genesis_hash
+        );
+        Ok(())
+    });
+    let _order = lock_order::acquire(Level::Peers);
+    record_verification(&mut announced.lock(), hostname, result, clock.now());
+}
+
+/// A verified peer stays advertised until its next re-verification, while a failed one is dropped.
+fn record_verification(
+    announced: &mut Vec<Announced>,
+    hostname: &str,
+    result: Result<()>,
+    now: Instant,
+) {
+    let i = match announced.iter().position(|a| a.server.hostname == hostname) {
+        Some(i) => i,
+        None => return, // evicted meanwhile
+    };
+    match result {
+        Ok(()) => {
+            info!("peer {} verified", hostname);
+            announced[i].verified = Some(now);
+        }
+        Err(e) => {
+            info!("peer {} failed verification: {:#}", hostname, e);
+            announced.remove(i);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        is_public, record_verification, reverifications, Announced, Peers, MAX_ANNOUNCED_PEERS,
+        REVERIFY_INTERVAL, VERIFIED_PEER_TTL,
+    };
+    use crate::clock::Clock;
+    use bitcoin::{blockdata::constants::genesis_block, BlockHash, Network};
+    use serde_json::{json, Value};
+    use std::time::Duration;
+
+    #[test]
+    fn test_subscribe_result() {
+        let peers = Peers::parse(
+            Some(
+                "a.example.org@203.0.113.5 v1.4 p10000 t50001 s50002, \
+             B.example.org. s, abcdefghijklmnop.onion t",
+            ),
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            json!(peers.subscribe_result()),
+            json!([
+                [
+                    "203.0.113.5",
+                    "a.example.org",
+                    ["v1.4", "p10000", "t50001", "s50002"]
+                ],
+                ["b.example.org", "b.example.org", ["s"]],
+                ["abcdefghijklmnop.onion", "abcdefghijklmnop.onion", ["t"]],
+            ])
+        );
+        assert_eq!(
+            json!(Peers::parse(None, false).unwrap().subscribe_result()),
+            json!([])
+        );
+        assert_eq!(
+            json!(Peers::parse(Some(" "), false).unwrap().subscribe_result()),
+            json!([])
+        );
+    }
+
+    #[test]
+    fn test_hosts() {
+        let peers =
+            Peers::parse(Some("a.example.org t50011 s50012, b.example.org s"), false).unwrap();
+        assert_eq!(
+            peers.hosts(Some("A.example.org")),
+            Some(json!({"a.example.org": {"tcp_port": 50011, "ssl_port": 50012}}))
+        );
+        assert_eq!(
+            peers.hosts(Some("b.example.org")),
+            Some(json!({"b.example.org": {"ssl_port": 50002}}))
+        );
+        assert_eq!(peers.hosts(Some("c.example.org")), None);
+        assert_eq!(peers.hosts(None), None);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let invalid = [
+            ("@203.0.113.5 t", "invalid hostname ''"),
+            ("a_b.example.org t", "invalid hostname 'a_b.example.org'"),
+            (
+                "a.example.org@localhost t",
+                "invalid IP address 'localhost'",
+            ),
+            (
+                "a.example.org",
+                "missing TCP or SSL port (e.g. 't50001' or 's50002')",
+            ),
+            (
+                "a.example.org v1.4",
+                "missing TCP or SSL port (e.g. 't50001' or 's50002')",
+            ),
+            ("a.example.org t0", "invalid feature 't0'"),
+            ("a.example.org t65536", "invalid feature 't65536'"),
+            ("a.example.org v1..4 t", "invalid feature 'v1..4'"),
+            ("a.example.org px t", "invalid feature 'px'"),
+            ("a.example.org x1 t", "invalid feature 'x1'"),
+            ("a.example.org t50001 t50011", "duplicate feature 't50011'"),
+            (
+                "a.example.org t, A.example.org s",
+                "duplicate peer 'a.example.org'",
+            ),
+        ];
+        for (value, cause) in &invalid {
+            let err = Peers::parse(Some(value), false).err().unwrap();
+            assert_eq!(err.root_cause().to_string(), *cause, "{}", value);
+        }
+    }
+
+    fn genesis_hash() -> BlockHash {
+        genesis_block(Network::Regtest).block_hash()
+    }
+
+    fn announce(peers: &Peers, hosts: Value) -> anyhow::Result<()> {
+        let announcement = json!({
+            "genesis_hash": genesis_hash(),
+            "hosts": hosts,
+            "protocol_max": "1.4",
+            "pruning": null,
+            "server_version": "ElectrumX 1.16.0",
+        });
+        peers.add(&serde_json::from_value(announcement)?, genesis_hash())
+    }
+
+    fn verify_all(peers: &Peers) {
+        for announced in peers.announced.lock().iter_mut() {
+            announced.verified = Some(peers.clock.now());
+        }
+    }
+
+    #[test]
+    fn test_add() {
+        let peers = Peers::parse(Some("a.example.org t"), false).unwrap();
+        let hosts = json!({
+            "b.example.org": {"tcp_port": 50001, "ssl_port": 50002},
+            "a.example.org": {"tcp_port": 1},
+        });
+        announce(&peers, hosts).unwrap();
+        // advertised only after being verified
+        let configured = json!(["a.example.org", "a.example.org", ["t"]]);
+        assert_eq!(json!(peers.subscribe_result()), json!([configured]));
+        verify_all(&peers);
+        let verified = json!([
+            "b.example.org",
+            "b.example.org",
+            ["v1.4", "t50001", "s50002"]
+        ]);
+        assert_eq!(
+            json!(peers.subscribe_result()),
+            json!([configured, verified])
+        );
+
+        // verified peers are not replaced
+        announce(&peers, json!({"B.example.org": {"tcp_port": 1}})).unwrap();
+        assert_eq!(
+            json!(peers.subscribe_result()),
+            json!([configured, verified])
+        );
+
+        let mut announcement: Value = json!({
+            "genesis_hash": genesis_block(Network::Testnet).block_hash(),
+            "hosts": {"c.example.org": {"tcp_port": 50001}},
+        });
+        let err = peers
+            .add(
+                &serde_json::from_value(announcement.clone()).unwrap(),
+                genesis_hash(),
+            )
+            .unwrap_err();
+        assert!(err.to_string().starts_with("genesis hash mismatch"));
+
+        announcement["hosts"]["c.example.org"]["tcp_port"] = json!(65536);
+        assert!(serde_json::from_value::<super::Announcement>(announcement).is_err());
+
+        let invalid = vec![
+            (json!({}), "missing hosts"),
+            (
+                json!({"a": {}, "b": {}, "c": {}, "d": {}, "e": {}}),
+                "too many hosts: 5 (at most 4)",
+            ),
+            (
+                json!({"c.example.org": {"tcp_port": null}}),
+                "missing TCP or SSL port for 'c.example.org'",
+            ),
+            (
+                json!({"c.example.org": {"ssl_port": 0}}),
+                "invalid feature 's0'",
+            ),
+            (
+                json!({"c_d.example.org": {"tcp_port": 1}}),
+                "invalid hostname 'c_d.example.org'",
+            ),
+        ];
+        for (hosts, msg) in invalid {
+            let err = announce(&peers, hosts.clone()).unwrap_err();
+            assert_eq!(err.to_string(), msg, "{}", hosts);
+        }
+        assert_eq!(peers.announced.lock().len(), 1);
+    }
+
+    #[test]
+    fn test_bounded() {
+        let peers = Peers::default();
+        for i in 0..=MAX_ANNOUNCED_PEERS {
+            let hostname = format!("{}.example.org", i);
+            announce(&peers, json!({ hostname: {"tcp_port": 50001} })).unwrap();
+        }
+        // the oldest pending peer is evicted
+        {
+            let announced = peers.announced.lock();
+            assert_eq!(announced.len(), MAX_ANNOUNCED_PEERS);
+            let hostnames: Vec<&str> = announced
+                .iter()
+                .map(|a: &Announced| a.server.hostname.as_str())
+                .collect();
+            assert_eq!(hostnames[0], "1.example.org");
+            assert_eq!(hostnames[MAX_ANNOUNCED_PEERS - 1], "100.example.org");
+        }
+        verify_all(&peers);
+        let err = announce(&peers, json!({"new.example.org": {"tcp_port": 50001}})).unwrap_err();
+        assert_eq!(err.to_string(), "too many peers");
+        assert_eq!(peers.subscribe_result().len(), MAX_ANNOUNCED_PEERS);
+    }
+
+    fn hostnames(peers: &Peers) -> Vec<String> {
+        peers
+            .subscribe_result()
+            .into_iter()
+            .map(|(_ip, hostname, _features)| hostname)
+            .collect()
+    }
+
+    #[test]
+    fn test_reverify_and_expire() {
+        let peers = Peers {
+            clock: Clock::mock(),
+            ..Peers::default()
+        };
+        let hosts = json!({
+            "a.example.org": {"tcp_port": 50001},
+            "b.example.org": {"tcp_port": 50001},
+            "abcdefghijklmnop.onion": {"tcp_port": 50001},
+        });
+        announce(&peers, hosts).unwrap();
+        let verify = |hostname: &str, result: anyhow::Result<()>| {
+            let mut announced = peers.announced.lock();
+            record_verification(&mut announced, hostname, result, peers.clock.now());
+        };
+        let due = || -> Vec<String> {
+            reverifications(&peers.announced.lock(), peers.clock.now())
+                .into_iter()
+                .map(|v| v.hostname)
+                .collect()
+        };
+        verify("a.example.org", Ok(()));
+        verify("b.example.org", Ok(()));
+        assert_eq!(hostnames(&peers), vec!["a.example.org", "b.example.org"]);
+        assert!(due().is_empty());
+
+        // verified peers are verified again, and dropped if they fail
+        peers.clock.advance(REVERIFY_INTERVAL);
+        assert_eq!(due(), vec!["a.example.org", "b.example.org"]);
+        verify("a.example.org", Ok(()));
+        verify("b.example.org", Err(anyhow!("connection refused")));
+        assert_eq!(hostnames(&peers), vec!["a.example.org"]);
+        assert!(due().is_empty());
+
+        // re-announcing doesn't extend the expiry of a peer which isn't verified again
+        let almost_expired = VERIFIED_PEER_TTL - Duration::from_secs(1);
+        peers.clock.advance(almost_expired);
+        announce(&peers, json!({"a.example.org": {"tcp_port": 50001}})).unwrap();
+        assert_eq!(hostnames(&peers), vec!["a.example.org"]);
+        peers.clock.advance(Duration::from_secs(1));
+        assert!(hostnames(&peers).is_empty());
+
+        // the expired peer can be announced (and verified) again
+        announce(&peers, json!({"a.example.org": {"tcp_port": 50001}})).unwrap();
+        assert!(hostnames(&peers).is_empty());
+        verify("a.example.org", Ok(()));
+        assert_eq!(hostnames(&peers), vec!["a.example.org"]);
+        // the onion peer stays pending (since it can't be verified)
+        assert_eq!(peers.announced.lock().len(), 2);
+    }
+
+    #[test]
+    fn test_is_public() {
+        let cases = [
+            ("8.8.8.8", true),
+            ("2001:4860:4860::8888", true),
+            ("::ffff:8.8.8.8", true),
+            ("10.1.2.3", false),
+            ("192.168.1.1", false),
+            ("127.0.0.1", false),
+            ("169.254.1.1", false),
+            ("0.0.0.0", false),
+            ("203.0.113.5", false),
+            ("::1", false),
+            ("::", false),
+            ("::ffff:127.0.0.1", false),
+            ("fd00::1", false),
+            ("fe80::1", false),
+            ("ff02::1", false),
+        ];
+        for (ip, public) in &cases {
+            assert_eq!(is_public(ip.parse().unwrap()), *public, "{}", ip);
+        }
+    }
+}
@@ -0,0 +1,1495 @@
+//! Typed parameters and results of the RPC methods served by electrs, so that the server's
+//! handlers and Rust clients agree on the wire format.
+//!
+//! Requests' parameters are positional, e.g. `Request::new(1, "blockchain.scripthash.get_balance",
+//! (scripthash, true))` for a verbose balance query. Results which are plain JSON values (e.g.
+//! `bool` or `String`) have no dedicated type.
+
+use bitcoin::{hash_types::TxMerkleNode, Amount, BlockHash, ScriptBuf, Txid};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+
+use std::net::IpAddr;
+
+pub use crate::electrum::{HealthFlags, HealthReport};
+pub use crate::merkle::ChainedProof;
+pub use crate::types::{ScriptHash, StatusHash};
+
+/// `blockchain.estimatefee` result for an unknown fee rate (allowed by Electrum protocol)
+const UNKNOWN_FEE: i64 = -1;
+
+/// A JSON-RPC request, with the method's positional parameters (e.g. a tuple)
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Request<P> {
+    pub jsonrpc: String,
+    pub id: Value,
+    pub method: String,
+    pub params: P,
+}
+
+impl<P> Request<P> {
+    pub fn new(id: u64, method: &str, params: P) -> Self {
+        Self {
+            jsonrpc: "2.0".to_owned(),
+            id: Value::from(id),
+            method: method.to_owned(),
+            params,
+        }
+    }
+}
+
+/// UTXO selection mode of `blockchain.scripthash.select_unspent`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectMode {
+    Any,
+    Confirmed,
+    /// Use unconfirmed UTXOs only if confirmed ones are insufficient
+    PreferConfirmed,
+}
+
+/// History order of `blockchain.scripthash.get_history_filter`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HistoryOrder {
+    Asc,
+    /// Newest first (starting with the mempool entries)
+    Desc,
+}
+
+/// UTXO selection strategy of `blockchain.scripthash.select_unspent`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectStrategy {
+    Default,
+    LargestFirst,
+    /// Branch-and-bound search for a changeless selection of confirmed UTXOs (falling back to
+    /// the default strategy if none is found)
+    Bnb,
+}
+
+/// `blockchain.scripthash.select_unspent` options, which may follow its `confirmed` parameter
+/// (instead of the positional ones). Omitted fields are the same as null (or `false`) ones.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SelectUnspentOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<SelectMode>, // overrides the `confirmed` flag
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub ignore_package_limits: bool, // only allowed in the `prefer_confirmed` mode
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub include_script_pubkey: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fee_rate: Option<f64>, // in sat/vB
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strategy: Option<SelectStrategy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_inputs: Option<usize>, // for each amount
+}
+
+/// `blockchain.headers.subscribe` result (and notification)
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HeadersSubscribeResult {
+    pub hex: String,
+    pub height: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decoded: Option<DecodedHeader>, // if requested by the subscription
+}
+
+/// The header's fields, named as by bitcoind's `getblockheader`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DecodedHeader {
+    pub version: i32,
+    pub previousblockhash: BlockHash,
+    pub merkleroot: TxMerkleNode,
+    pub time: u32,
+    pub mediantime: Option<u32>,
+    pub bits: String, // hex-encoded
+    pub nonce: u32,
+}
+
+/// `blockchain.block.txids.subscribe` result
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockTxidsSubscribeResult {
+    pub height: usize,
+    pub hash: BlockHash,
+}
+
+/// `blockchain.block.header` result, if `cp_height > 0` (otherwise, the hex-encoded header)
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HeaderProofResult {
+    pub header: String,
+    pub branch: Vec<TxMerkleNode>,
+    pub root: TxMerkleNode,
+}
+
+/// `blockchain.block.header_by_hash` result
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HeaderByHashResult {
+    pub height: usize,
+    pub hex: String,
+    pub stale: bool, // recently orphaned
+}
+
+/// `blockchain.block.headers` result (proving the last header's inclusion if `cp_height > 0`)
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HeadersResult {
+    pub count: usize,
+    pub hex: String,
+    pub max: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub branch: Option<Vec<TxMerkleNode>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub root: Option<TxMerkleNode>,
+}
+
+/// `blockchain.estimatefee` result: the fee rate in BTC/kB (`-1` on the wire if unknown)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FeeEstimate(pub Option<f64>);
+
+impl Serialize for FeeEstimate {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self.0 {
+            Some(fee_rate) => serializer.serialize_f64(fee_rate),
+            None => serializer.serialize_i64(UNKNOWN_FEE),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for FeeEstimate {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let fee_rate = f64::deserialize(deserializer)?;
+        Ok(FeeEstimate(if fee_rate < 0.0 {
+            None
+        } else {
+            Some(fee_rate)
+        }))
+    }
+}
+
+/// `blockchain.scripthash.get_balance` result (in sats)
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GetBalanceResponse {
+    pub confirmed: u64,
+    pub unconfirmed: i64,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub stale: bool, // the mempool is ignored, since it can't be synced
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<usize>, // of the index (if stale)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub totals: Option<BalanceTotals>, // if verbose
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub as_of_height: Option<usize>, // of the status' last sync (if verbose)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diagnostic: Option<EmptyResultInfo>, // if verbose and empty
+}
+
+/// Total amounts funded to and spent from a scripthash (in sats)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BalanceTotals {
+    pub received: u64,
+    pub sent: u64,
+}
+
+/// Explains an empty verbose result: whether the scripthash was ever funded, and how far the
+/// index and the mempool are synced
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EmptyResultInfo {
+    pub ever_seen: bool,
+    pub indexed_height: usize,
+    pub daemon_height: Option<u64>, // `None` if bitcoind is unreachable
+    pub mempool_checked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>, // if ever seen
+}
+
+/// A single history entry (of `blockchain.scripthash.get_history` and similar methods)
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryEntryWire {
+    pub tx_hash: Txid,
+    pub height: i64, // 0 or -1 for mempool entries (-1 if they have unconfirmed inputs)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fee: Option<u64>, // for mempool entries
+    #[serde(
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub tx_pos: Option<Option<u32>>, // if requested (`null` for mempool entries)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reorged_from: Option<BlockHash>, // if confirmed at another height before a reorg
+}
+
+/// Verbose history result (of `blockchain.scripthash.get_history` and similar methods)
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VerboseHistoryResult {
+    pub history: Vec<HistoryEntryWire>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub stale: bool, // the mempool is ignored (or the index is partial)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<usize>, // of the index (if stale)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<usize>, // number of unfiltered history entries (if filtered)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<usize>, // the filter's height range (if bounded)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub as_of_height: Option<usize>, // of the status' last sync
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diagnostic: Option<EmptyResultInfo>, // if empty
+}
+
+/// A single unspent output (of `blockchain.scripthash.listunspent` and `select_unspent`)
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UnspentEntryWire {
+    pub height: usize, // 0 for mempool entries
+    pub tx_hash: Txid,
+    pub tx_pos: u32,
+    pub value: u64,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub spent_in_mempool: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub script_pubkey: Option<ScriptBuf>, // if requested
+    // unconfirmed ancestors' stats (for mempool entries)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ancestor_count: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ancestor_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spendable_now: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unconfirmed: Option<bool>, // if selected preferring confirmed UTXOs
+}
+
+/// `blockchain.scripthash.listunspent` result, if stale (otherwise, the unspent outputs): the
+/// outputs are wrapped, so that a partial result isn't mistaken for a complete one
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StaleUnspentResult {
+    pub unspent: Vec<UnspentEntryWire>,
+    pub stale: bool,
+    pub height: usize, // of the index
+}
+
+/// `blockchain.scripthash.select_unspent` result, if a fee rate or a strategy is specified
+/// (otherwise, the selected UTXOs)
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SelectUnspentResult {
+    pub utxos: Vec<UnspentEntryWire>,
+    pub inputs: usize,
+    pub total_value: u64, // of the selected UTXOs (in sat)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input_vsize: Option<u64>, // if a fee rate is specified
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input_fee: Option<u64>, // if a fee rate is specified
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strategies: Option<Vec<SelectStrategy>>, // used for each amount (if specified)
+}
+
+/// `blockchain.transaction.mempool_info` result
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MempoolInfoResult {
+    pub txid: Txid,
+    pub vsize: u64,
+    pub fees: MempoolFees,
+    pub ancestor_count: u64,
+    pub descendant_count: u64,
+    pub fee_rate: f64, // including its ancestors (in sat/vB)
+    pub vsize_ahead: u64,
+    pub mempool_min_fee_rate: Option<f64>,
+    pub eviction_risk: bool,
+    pub partial: bool, // the histogram may be inaccurate
+}
+
+/// A mempool transaction's fees (in sats)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MempoolFees {
+    pub base: u64,
+    pub ancestor: u64,
+    pub descendant: u64,
+}
+
+/// `blockchain.transaction.search_prefix` result
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SearchPrefixResult {
+    pub matches: Vec<PrefixMatch>,
+    pub truncated: bool,
+    pub collision: bool, // the prefix is ambiguous
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PrefixMatch {
+    pub tx_hash: Txid,
+    pub height: usize,
+}
+
+/// `blockchain.outpoint.get_spender` result (or null, if the outpoint is unspent or unknown)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpenderResult {
+    pub spender_txid: Txid,
+    pub height: usize, // 0 if the spender is in the mempool
+}
+
+/// `blockchain.outpoint.subscribe` result (and notification): the funding transaction's height
+/// (if known), and its spender (if spent) - where 0 or -1 mean in the mempool
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutPointStatusResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spender_txhash: Option<Txid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spender_height: Option<i64>,
+}
+
+/// `blockchain.transaction.build_rbf` result: an unsigned replacement template, and the numbers
+/// used for building it (in sats)
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BuildRbfResult {
+    pub hex: String,
+    pub change_index: usize,
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub change_value: Amount,
+    pub vsize: u64,
+    pub fee_rate: f64, // in sat/vB
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub original_fee: Amount,
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub replaced_fee: Amount, // including the original's descendants
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub incremental_relay_fee: Amount, // in sat/kvB
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub fee: Amount,
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub additional_fee: Amount,
+}
+
+/// `blockchain.psbt.analyze` result: the PSBT's inputs (as currently seen by the server), and its
+/// implied fee (in sats)
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PsbtAnalysis {
+    pub txid: Txid, // of the unsigned transaction
+    pub inputs: Vec<PsbtInputAnalysis>,
+    #[serde(with = "bitcoin::amount::serde::as_sat::opt")]
+    pub input_value: Option<Amount>, // `None` unless all the inputs' values are known
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub output_value: Amount,
+    #[serde(with = "bitcoin::amount::serde::as_sat::opt")]
+    pub fee: Option<Amount>,
+    pub vsize: usize,
+    pub vsize_final: bool, // otherwise, the unsigned transaction's (signing will increase it)
+    pub fee_rate: Option<f64>, // in sat/vB
+    pub warnings: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PsbtInputAnalysis {
+    pub outpoint: String,
+    #[serde(with = "bitcoin::amount::serde::as_sat::opt")]
+    pub value: Option<Amount>, // `None` if unknown (to both the PSBT and the server)
+    pub has_witness_utxo: bool,
+    pub has_non_witness_utxo: bool,
+    pub finalized: bool,
+    pub status: String, // "unspent", "spent" or "unknown" (to the server)
+    pub confirmations: Option<usize>,
+    pub spent_by: Option<PsbtSpender>,
+    pub warnings: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PsbtSpender {
+    pub txid: Txid,
+    pub height: usize, // 0 for mempool transactions
+}
+
+/// `blockchain.transaction.get_merkle` result
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleResult {
+    pub block_height: usize,
+    pub pos: usize,
+    pub merkle: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub header_hex: Option<String>, // if requested
+}
+
+/// `blockchain.transaction.id_from_pos` result, if a merkle proof is requested (otherwise, it's
+/// just the txid)
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdFromPosResult {
+    pub tx_hash: Txid,
+    pub merkle: Vec<String>,
+}
+
+/// `blockchain.transaction.id_from_pos` result, as returned before matching the Electrum protocol
+/// (see `legacy_id_from_pos`)
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LegacyIdFromPosResult {
+    pub tx_id: Txid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub merkle: Option<Vec<String>>, // if requested
+}
+
+/// `blockchain.transactions.get` entry: either the transaction (as by `blockchain.transaction.get`)
+/// or an error, for each requested txid
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TransactionsGetEntry {
+    pub txid: Txid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// `proofs.export` result
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProofsExportResult {
+    pub cp_height: usize,
+    pub cp_block_hash: Option<BlockHash>,
+    pub cp_root: TxMerkleNode,
+    pub proofs: Vec<ExportedProof>,
+}
+
+/// Either a proof or an error, for each requested transaction
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportedProof {
+    pub txid: Txid,
+    pub height: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proof: Option<ChainedProof>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// `mempool.get_fee_histogram` entries: (fee rate in sat/vB, vsize)
+pub type FeeHistogramEntries = Vec<(u64, u64)>;
+
+/// Verbose `mempool.get_fee_histogram` result
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeeHistogramResult {
+    pub histogram: FeeHistogramEntries,
+    pub partial: bool, // inaccurate until the initial mempool load is done
+}
+
+/// `server.descriptor_info` result
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DescriptorInfoResult {
+    pub descriptor: String,
+    pub checksum: String,
+    pub script_type: String,
+    pub has_wildcard: bool,
+    pub scripthashes: Vec<ScriptHash>,
+}
+
+/// `server.features` result
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FeaturesResult {
+    pub genesis_hash: Option<BlockHash>,
+    pub hosts: Value, // keyed by hostname
+    pub protocol_max: String,
+    pub protocol_min: String,
+    pub pruning: Option<u64>,
+    pub server_version: String,
+    pub hash_function: String,
+    pub extensions: Extensions,
+}
+
+/// Optional (non-standard) server behaviors, which clients may need to handle
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Extensions {
+    pub error_codes: Value, // keyed by name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idle_unsubscribe: Option<IdleUnsubscribe>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cbor_codec: Option<CborCodec>,
+    pub history_deltas: HistoryDeltas,
+    pub notifications_debounce: NotificationsDebounce,
+    pub request_limits: RequestLimits,
+}
+
+/// Larger requests are rejected with a `resource-limit` error
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RequestLimits {
+    pub max_batch_calls: usize,
+    pub max_request_bytes: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdleUnsubscribe {
+    pub idle_secs: u64,
+    pub notification: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CborCodec {
+    pub method: String,
+    pub length_prefix: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryDeltas {
+    pub method: String,
+    pub max_entries: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NotificationsDebounce {
+    pub method: String,
+    pub max_ms: u64,
+}
+
+/// `server.peers.subscribe` entry: IP address (or hostname), hostname and features
+pub type PeerEntry = (String, String, Vec<String>);
+
+/// `server.info` result
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerInfoResult {
+    pub server_version: String,
+    pub txindex: bool,
+}
+
+/// `server.cache.stats` result
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CacheStatsResult {
+    pub tx: CacheStats,
+}
+
+/// Current size and cumulative lookups' stats (the latter are not reset by flushing)
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub bytes: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub hit_ratio: Option<f64>, // `None` before the first lookup
+}
+
+/// `server.cache.flush` result: the number of flushed entries
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheFlushResult {
+    pub tx: usize,
+    #[serde(default)]
+    pub txids: usize, // number of blocks
+}
+
+/// `server.bans.list` entry
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BanInfo {
+    pub ip: IpAddr,
+    pub remaining_secs: u64,
+    pub bans: u32, // including the current one
+    pub reason: String,
+}
+
+/// An orphaned transaction, and whether it was re-included (as of the query)
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OrphanedEntry {
+    pub tx_hash: Txid,
+    pub block_hash: BlockHash, // the orphaned block
+    pub height: usize,         // of the orphaned block
+    pub confirmed_height: Option<usize>,
+    pub in_mempool: bool,
+}
+
+/// `server.webhooks.stats` entry
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WebhookStats {
+    pub url: String,
+    pub queued: usize,
+    pub delivered: usize,
+    pub retries: usize,
+    pub dead_lettered: usize,
+    pub dropped: usize,
+    pub dead_letters: Vec<Value>, // the most recent ones
+}
+
+/// `server.broadcast_mirrors.stats` entry
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MirrorStats {
+    pub url: String,
+    pub kind: String,
+    pub network: Option<String>,
+    pub queued: usize,
+    pub submitted: usize,
+    pub retries: usize,
+    pub failed: usize,
+    pub dropped: usize,
+    pub healthy: bool,
+    pub last_error: Option<String>,
+}
+
+/// Distinguishes an explicit `null` (deserialized as `Some(None)`) from a missing field.
+fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        BalanceTotals, BanInfo, BlockTxidsSubscribeResult, BuildRbfResult, CacheFlushResult,
+        CacheStats, CacheStatsResult, CborCodec, ChainedProof, DecodedHeader, DescriptorInfoResult,
+        EmptyResultInfo, ExportedProof, Extensions, FeaturesResult, FeeEstimate,
+        FeeHistogramResult, GetBalanceResponse, HeaderByHashResult, HeaderProofResult,
+        HeadersResult, HeadersSubscribeResult, HealthFlags, HealthReport, HistoryDeltas,
+        HistoryEntryWire, HistoryOrder, IdFromPosResult, IdleUnsubscribe, LegacyIdFromPosResult,
+        MempoolFees, MempoolInfoResult, MerkleResult, MirrorStats, NotificationsDebounce,
+        OrphanedEntry, OutPointStatusResult, PeerEntry, PrefixMatch, ProofsExportResult,
+        PsbtAnalysis, PsbtInputAnalysis, PsbtSpender, Request, RequestLimits, ScriptHash,
+        SearchPrefixResult, SelectMode, SelectStrategy, SelectUnspentResult, ServerInfoResult,
+        SpenderResult, StaleUnspentResult, TransactionsGetEntry, UnspentEntryWire,
+        VerboseHistoryResult, WebhookStats,
+    };
+    use bitcoin::{hash_types::TxMerkleNode, hashes::Hash, Amount, BlockHash, ScriptBuf, Txid};
+    use serde::{de::DeserializeOwned, Serialize};
+    use serde_json::{json, Value};
+
+    use std::fmt::Debug;
+
+    /// The value is serialized as expected, and deserialized back.
+    fn round_trip<T>(value: T, expected: Value)
+    where
+        T: Serialize + DeserializeOwned + PartialEq + Debug,
+    {
+        let serialized = serde_json::to_value(&value).unwrap();
+        assert_eq!(serialized, expected);
+        assert_eq!(serde_json::from_value::<T>(serialized).unwrap(), value);
+    }
+
+    fn txid(n: u8) -> Txid {
+        Txid::from_byte_array([n; 32])
+    }
+
+    fn blockhash(n: u8) -> BlockHash {
+        BlockHash::from_byte_array([n; 32])
+    }
+
+    fn node(n: u8) -> TxMerkleNode {
+        TxMerkleNode::from_byte_array([n; 32])
+    }
+
+    fn hex32(n: u8) -> String {
+        format!("{:02x}", n).repeat(32)
+    }
+
+    #[test]
+    fn test_request() {
+        let scripthash = ScriptHash::from_byte_array([1; 32]);
+        round_trip(
+            Request::new(7, "blockchain.scripthash.get_balance", (scripthash, true)),
+            json!({
+                "jsonrpc": "2.0",
+                "id": 7,
+                "method": "blockchain.scripthash.get_balance",
+                "params": [hex32(1), true],
+            }),
+        );
+    }
+
+    #[test]
+    fn test_select_params() {
+        round_trip(SelectMode::PreferConfirmed, json!("prefer_confirmed"));
+        round_trip(HistoryOrder::Desc, json!("desc"));
+        round_trip(SelectStrategy::LargestFirst, json!("largest_first"));
+    }
+
+    #[test]
+    fn test_headers_subscribe() {
+        let result = HeadersSubscribeResult {
+            hex: "00".to_owned(),
+            height: 5,
+            decoded: None,
+        };
+        round_trip(result.clone(), json!({"hex": "00", "height": 5}));
+        let decoded = DecodedHeader {
+            version: 4,
+            previousblockhash: blockhash(1),
+            merkleroot: node(2),
+            time: 1000,
+            mediantime: None,
+            bits: "1d00ffff".to_owned(),
+            nonce: 3,
+        };
+        round_trip(
+            HeadersSubscribeResult {
+                decoded: Some(decoded),
+                ..result
+            },
+            json!({
+                "hex": "00",
+                "height": 5,
+                "decoded": {
+                    "version": 4,
+                    "previousblockhash": hex32(1),
+                    "merkleroot": hex32(2),
+                    "time": 1000,
+                    "mediantime": null,
+                    "bits": "1d00ffff",
+                    "nonce": 3,
+                },
+            }),
+        );
+    }
+
+    #[test]
+    fn test_block_results() {
+        round_trip(
+            BlockTxidsSubscribeResult {
+                height: 1,
+                hash: blockhash(1),
+            },
+            json!({"height": 1, "hash": hex32(1)}),
+        );
+        round_trip(
+            HeaderProofResult {
+                header: "00".to_owned(),
+                branch: vec![node(1)],
+                root: node(2),
+            },
+            json!({"header": "00", "branch": [hex32(1)], "root": hex32(2)}),
+        );
+        round_trip(
+            HeaderByHashResult {
+                height: 1,
+                hex: "00".to_owned(),
+                stale: true,
+            },
+            json!({"height": 1, "hex": "00", "stale": true}),
+        );
+        let headers = HeadersResult {
+            count: 1,
+            hex: "00".to_owned(),
+            max: 2016,
+            branch: None,
+            root: None,
+        };
+        round_trip(
+            headers.clone(),
+            json!({"count": 1, "hex": "00", "max": 2016}),
+        );
+        round_trip(
+            HeadersResult {
+                branch: Some(vec![]),
+                root: Some(node(3)),
+                ..headers
+            },
+            json!({"count": 1, "hex": "00", "max": 2016, "branch": [], "root": hex32(3)}),
+        );
+    }
+
+    #[test]
+    fn test_fee_estimate() {
+        round_trip(FeeEstimate(Some(0.0002)), json!(0.0002));
+        round_trip(FeeEstimate(None), json!(-1));
+        assert_eq!(json!(FeeEstimate(None)).to_string(), "-1");
+    }
+
+    #[test]
+    fn test_get_balance() {
+        let balance = GetBalanceResponse {
+            confirmed: 10,
+            unconfirmed: -3,
+            stale: false,
+            height: None,
+            totals: None,
+            as_of_height: None,
+            diagnostic: None,
+        };
+        round_trip(balance.clone(), json!({"confirmed": 10, "unconfirmed": -3}));
+        let diagnostic = EmptyResultInfo {
+            ever_seen: true,
+            indexed_height: 100,
+            daemon_height: None,
+            mempool_checked: false,
+            reason: Some("all_spent".to_owned()),
+        };
+        round_trip(
+            GetBalanceResponse {
+                stale: true,
+                height: Some(100),
+                totals: Some(BalanceTotals {
+                    received: 20,
+                    sent: 10,
+                }),
+                as_of_height: Some(99),
+                diagnostic: Some(diagnostic),
+                ..balance
+            },
+            json!({
+                "confirmed": 10,
+                "unconfirmed": -3,
+                "stale": true,
+                "height": 100,
+                "totals": {"received": 20, "sent": 10},
+                "as_of_height": 99,
+                "diagnostic": {
+                    "ever_seen": true,
+                    "indexed_height": 100,
+                    "daemon_height": null,
+                    "mempool_checked": false,
+                    "reason": "all_spent",
+                },
+            }),
+        );
+    }
+
+    #[test]
+    fn test_history() {
+        let confirmed = HistoryEntryWire {
+            tx_hash: txid(1),
+            height: 100,
+            fee: None,
+            tx_pos: Some(Some(2)),
+            reorged_from: Some(blockhash(2)),
+        };
+        let unconfirmed = HistoryEntryWire {
+            tx_hash: txid(3),
+            height: -1,
+            fee: Some(200),
+            tx_pos: Some(None),
+            reorged_from: None,
+        };
+        round_trip(
+            VerboseHistoryResult {
+                history: vec![confirmed, unconfirmed.clone()],
+                stale: false,
+                height: None,
+                total: None,
+                from: None,
+                to: None,
+                as_of_height: Some(100),
+                diagnostic: None,
+            },
+            json!({
+                "history": [
+                    {"tx_hash": hex32(1), "height": 100, "tx_pos": 2, "reorged_from": hex32(2)},
+                    {"tx_hash": hex32(3), "height": -1, "fee": 200, "tx_pos": null},
+                ],
+                "as_of_height": 100,
+            }),
+        );
+        round_trip(
+            VerboseHistoryResult {
+                history: vec![],
+                stale: false,
+                height: None,
+                total: Some(5),
+                from: Some(200),
+                to: None,
+                as_of_height: Some(100),
+                diagnostic: None,
+            },
+            json!({"history": [], "total": 5, "from": 200, "as_of_height": 100}),
+        );
+        round_trip(
+            VerboseHistoryResult {
+                history: vec![],
+                stale: true,
+                height: Some(50),
+                total: None,
+                from: None,
+                to: None,
+                as_of_height: None,
+                diagnostic: None,
+            },
+            json!({"history": [], "stale": true, "height": 50}),
+        );
+        round_trip(
+            HistoryEntryWire {
+                tx_pos: None,
+                ..unconfirmed
+            },
+            json!({"tx_hash": hex32(3), "height": -1, "fee": 200}),
+        );
+    }
+
+    #[test]
+    fn test_unspent() {
+        let entry = UnspentEntryWire {
+            height: 0,
+            tx_hash: txid(1),
+            tx_pos: 1,
+            value: 1000,
+            spent_in_mempool: false,
+            script_pubkey: None,
+            ancestor_count: None,
+            ancestor_size: None,
+            spendable_now: None,
+            unconfirmed: None,
+        };
+        round_trip(
+            entry.clone(),
+            json!({"height": 0, "tx_hash": hex32(1), "tx_pos": 1, "value": 1000}),
+        );
+        let entry = UnspentEntryWire {
+            spent_in_mempool: true,
+            script_pubkey: Some(ScriptBuf::from(vec![0x51])),
+            ancestor_count: Some(2),
+            ancestor_size: Some(300),
+            spendable_now: Some(true),
+            unconfirmed: Some(true),
+            ..entry
+        };
+        round_trip(
+            entry.clone(),
+            json!({
+                "height": 0,
+                "tx_hash": hex32(1),
+                "tx_pos": 1,
+                "value": 1000,
+                "spent_in_mempool": true,
+                "script_pubkey": "51",
+                "ancestor_count": 2,
+                "ancestor_size": 300,
+                "spendable_now": true,
+                "unconfirmed": true,
+            }),
+        );
+        round_trip(
+            StaleUnspentResult {
+                unspent: vec![entry.clone()],
+                stale: true,
+                height: 50,
+            },
+            json!({
+                "unspent": [{
+                    "height": 0,
+                    "tx_hash": hex32(1),
+                    "tx_pos": 1,
+                    "value": 1000,
+                    "spent_in_mempool": true,
+                    "script_pubkey": "51",
+                    "ancestor_count": 2,
+                    "ancestor_size": 300,
+                    "spendable_now": true,
+                    "unconfirmed": true,
+                }],
+                "stale": true,
+                "height": 50,
+            }),
+        );
+        round_trip(
+            SelectUnspentResult {
+                utxos: vec![],
+                inputs: 0,
+                total_value: 0,
+                input_vsize: Some(0),
+                input_fee: Some(0),
+                strategies: Some(vec![SelectStrategy::Bnb]),
+            },
+            json!({
+                "utxos": [],
+                "inputs": 0,
+                "total_value": 0,
+                "input_vsize": 0,
+                "input_fee": 0,
+                "strategies": ["bnb"],
+            }),
+        );
+    }
+
+    #[test]
+    fn test_transaction_results() {
+        round_trip(
+            MempoolInfoResult {
+                txid: txid(1),
+                vsize: 200,
+                fees: MempoolFees {
+                    base: 400,
+                    ancestor: 500,
+                    descendant: 400,
+                },
+                ancestor_count: 2,
+                descendant_count: 1,
+                fee_rate: 1.5,
+                vsize_ahead: 1000,
+                mempool_min_fee_rate: Some(1.0),
+                eviction_risk: false,
+                partial: false,
+            },
+            json!({
+                "txid": hex32(1),
+                "vsize": 200,
+                "fees": {"base": 400, "ancestor": 500, "descendant": 400},
+                "ancestor_count": 2,
+                "descendant_count": 1,
+                "fee_rate": 1.5,
+                "vsize_ahead": 1000,
+                "mempool_min_fee_rate": 1.0,
+                "eviction_risk": false,
+                "partial": false,
+            }),
+        );
+        round_trip(
+            SearchPrefixResult {
+                matches: vec![PrefixMatch {
+                    tx_hash: txid(1),
+                    height: 10,
+                }],
+                truncated: false,
+                collision: false,
+            },
+            json!({
+                "matches": [{"tx_hash": hex32(1), "height": 10}],
+                "truncated": false,
+                "collision": false,
+            }),
+        );
+        round_trip(
+            SpenderResult {
+                spender_txid: txid(2),
+                height: 0,
+            },
+            json!({"spender_txid": hex32(2), "height": 0}),
+        );
+        round_trip(
+            MerkleResult {
+                block_height: 10,
+                pos: 1,
+                merkle: vec![hex32(2)],
+                header_hex: Some("00".to_owned()),
+            },
+            json!({"block_height": 10, "pos": 1, "merkle": [hex32(2)], "header_hex": "00"}),
+        );
+        round_trip(
+            IdFromPosResult {
+                tx_hash: txid(1),
+                merkle: vec![hex32(2)],
+            },
+            json!({"tx_hash": hex32(1), "merkle": [hex32(2)]}),
+        );
+        round_trip(
+            LegacyIdFromPosResult {
+                tx_id: txid(1),
+                merkle: None,
+            },
+            json!({ "tx_id": hex32(1) }),
+        );
+        round_trip(
+            vec![
+                TransactionsGetEntry {
+                    txid: txid(1),
+                    result: Some(json!("0200000000000000")),
+                    error: None,
+                },
+                TransactionsGetEntry {
+                    txid: txid(2),
+                    result: None,
+                    error: Some("transaction not found".to_owned()),
+                },
+            ],
+            json!([
+                {"txid": hex32(1), "result": "0200000000000000"},
+                {"txid": hex32(2), "error": "transaction not found"},
+            ]),
+        );
+    }
+
+    #[test]
+    fn test_outpoint_status() {
+        round_trip(OutPointStatusResult::default(), json!({})); // unknown funding transaction
+        round_trip(
+            OutPointStatusResult {
+                height: Some(10),
+                spender_txhash: Some(txid(2)),
+                spender_height: Some(-1),
+            },
+            json!({"height": 10, "spender_txhash": hex32(2), "spender_height": -1}),
+        );
+    }
+
+    #[test]
+    fn test_rbf_and_psbt() {
+        round_trip(
+            BuildRbfResult {
+                hex: "0200".to_owned(),
+                change_index: 1,
+                change_value: Amount::from_sat(18_200),
+                vsize: 200,
+                fee_rate: 10.0,
+                original_fee: Amount::from_sat(200),
+                replaced_fee: Amount::from_sat(500),
+                incremental_relay_fee: Amount::from_sat(1000),
+                fee: Amount::from_sat(2000),
+                additional_fee: Amount::from_sat(1800),
+            },
+            json!({
+                "hex": "0200",
+                "change_index": 1,
+                "change_value": 18_200,
+                "vsize": 200,
+                "fee_rate": 10.0,
+                "original_fee": 200,
+                "replaced_fee": 500,
+                "incremental_relay_fee": 1000,
+                "fee": 2000,
+                "additional_fee": 1800,
+            }),
+        );
+        let input = |value, spent_by, warnings: &[&str]| PsbtInputAnalysis {
+            outpoint: format!("{}:0", hex32(1)),
+            value,
+            has_witness_utxo: true,
+            has_non_witness_utxo: false,
+            finalized: false,
+            status: match spent_by {
+                Some(_) => "spent".to_owned(),
+                None => "unspent".to_owned(),
+            },
+            confirmations: Some(0),
+            spent_by,
+            warnings: warnings.iter().map(|w| w.to_string()).collect(),
+        };
+        round_trip(
+            PsbtAnalysis {
+                txid: txid(3),
+                inputs: vec![
+                    input(Some(Amount::from_sat(30_000)), None, &[]),
+                    input(
+                        None,
+                        Some(PsbtSpender {
+                            txid: txid(9),
+                            height: 0,
+                        }),
+                        &["missing_utxo"],
+                    ),
+                ],
+                input_value: None,
+                output_value: Amount::from_sat(25_000),
+                fee: None,
+                vsize: 150,
+                vsize_final: false,
+                fee_rate: None,
+                warnings: vec!["spent_inputs".to_owned()],
+            },
+            json!({
+                "txid": hex32(3),
+                "inputs": [
+                    {
+                        "outpoint": format!("{}:0", hex32(1)),
+                        "value": 30_000,
+                        "has_witness_utxo": true,
+                        "has_non_witness_utxo": false,
+                        "finalized": false,
+                        "status": "unspent",
+                        "confirmations": 0,
+                        "spent_by": null,
+                        "warnings": [],
+                    },
+                    {
+                        "outpoint": format!("{}:0", hex32(1)),
+                        "value": null,
+                        "has_witness_utxo": true,
+                        "has_non_witness_utxo": false,
+                        "finalized": false,
+                        "status": "spent",
+                        "confirmations": 0,
+                        "spent_by": {"txid": hex32(9), "height": 0},
+                        "warnings": ["missing_utxo"],
+                    },
+                ],
+                "input_value": null,
+                "output_value": 25_000,
+                "fee": null,
+                "vsize": 150,
+                "vsize_final": false,
+                "fee_rate": null,
+                "warnings": ["spent_inputs"],
+            }),
+        );
+    }
+
+    #[test]
+    fn test_proofs_export() {
+        let proof = ChainedProof {
+            txid: txid(1),
+            height: 1,
+            header: "00".to_owned(),
+            tx_pos: 0,
+            tx_branch: vec![],
+            cp_height: 2,
+            header_branch: vec![node(2)],
+            cp_root: node(3),
+        };
+        round_trip(
+            ProofsExportResult {
+                cp_height: 2,
+                cp_block_hash: Some(blockhash(4)),
+                cp_root: node(3),
+                proofs: vec![
+                    ExportedProof {
+                        txid: txid(1),
+                        height: 1,
+                        proof: Some(proof.clone()),
+                        error: None,
+                    },
+                    ExportedProof {
+                        txid: txid(5),
+                        height: 3,
+                        proof: None,
+                        error: Some("height 3 is above cp_height 2".to_owned()),
+                    },
+                ],
+            },
+            json!({
+                "cp_height": 2,
+                "cp_block_hash": hex32(4),
+                "cp_root": hex32(3),
+                "proofs": [
+                    {"txid": hex32(1), "height": 1, "proof": json!(proof)},
+                    {"txid": hex32(5), "height": 3, "error": "height 3 is above cp_height 2"},
+                ],
+            }),
+        );
+    }
+
+    #[test]
+    fn test_fee_histogram() {
+        round_trip(
+            FeeHistogramResult {
+                histogram: vec![(10, 1000), (1, 500)],
+                partial: true,
+            },
+            json!({"histogram": [[10, 1000], [1, 500]], "partial": true}),
+        );
+    }
+
+    #[test]
+    fn test_server_results() {
+        round_trip(
+            DescriptorInfoResult {
+                descriptor: "wpkh(...)".to_owned(),
+                checksum: "abcdefgh".to_owned(),
+                script_type: "wpkh".to_owned(),
+                has_wildcard: false,
+                scripthashes: vec![ScriptHash::from_byte_array([1; 32])],
+            },
+            json!({
+                "descriptor": "wpkh(...)",
+                "checksum": "abcdefgh",
+                "script_type": "wpkh",
+                "has_wildcard": false,
+                "scripthashes": [hex32(1)],
+            }),
+        );
+        round_trip(
+            ServerInfoResult {
+                server_version: "electrs/0.10.0".to_owned(),
+                txindex: true,
+            },
+            json!({"server_version": "electrs/0.10.0", "txindex": true}),
+        );
+        let peer: PeerEntry = (
+            "203.0.113.5".to_owned(),
+            "a.example.org".to_owned(),
+            vec!["t".to_owned()],
+        );
+        round_trip(vec![peer], json!([["203.0.113.5", "a.example.org", ["t"]]]));
+    }
+
+    #[test]
+    fn test_features() {
+        let extensions = Extensions {
+            error_codes: json!({}),
+            idle_unsubscribe: Some(IdleUnsubscribe {
+                idle_secs: 60,
+                notification: "blockchain.scripthash.unsubscribed".to_owned(),
+            }),
+            cbor_codec: Some(CborCodec {
+                method: "server.codec".to_owned(),
+                length_prefix: "u32be".to_owned(),
+            }),
+            history_deltas: HistoryDeltas {
+                method: "server.history_deltas".to_owned(),
+                max_entries: 100,
+            },
+            notifications_debounce: NotificationsDebounce {
+                method: "server.notifications.debounce".to_owned(),
+                max_ms: 1000,
+            },
+            request_limits: RequestLimits {
+                max_batch_calls: 100,
+                max_request_bytes: 1_000_000,
+            },
+        };
+        round_trip(
+            FeaturesResult {
+                genesis_hash: Some(blockhash(0)),
+                hosts: json!({"a.example.org": {"tcp_port": 50001}}),
+                protocol_max: "1.4".to_owned(),
+                protocol_min: "1.4".to_owned(),
+                pruning: None,
+                server_version: "electrs/0.10.0".to_owned(),
+                hash_function: "sha256".to_owned(),
+                extensions,
+            },
+            json!({
+                "genesis_hash": hex32(0),
+                "hosts": {"a.example.org": {"tcp_port": 50001}},
+                "protocol_max": "1.4",
+                "protocol_min": "1.4",
+                "pruning": null,
+                "server_version": "electrs/0.10.0",
+                "hash_function": "sha256",
+                "extensions": {
+                    "error_codes": {},
+                    "idle_unsubscribe": {
+                        "idle_secs": 60,
+                        "notification": "blockchain.scripthash.unsubscribed",
+                    },
+                    "cbor_codec": {"method": "server.codec", "length_prefix": "u32be"},
+                    "history_deltas": {"method": "server.history_deltas", "max_entries": 100},
+                    "notifications_debounce": {
+                        "method": "server.notifications.debounce",
+                        "max_ms": 1000,
+                    },
+                    "request_limits": {"max_batch_calls": 100, "max_request_bytes": 1_000_000},
+                },
+            }),
+        );
+    }
+
+    #[test]
+    fn test_admin_results() {
+        round_trip(
+            CacheStatsResult {
+                tx: CacheStats {
+                    entries: 1,
+                    bytes: 100,
+                    hits: 3,
+                    misses: 1,
+                    hit_ratio: Some(0.75),
+                },
+            },
+            json!({"tx": {"entries": 1, "bytes": 100, "hits": 3, "misses": 1, "hit_ratio": 0.75}}),
+        );
+        round_trip(
+            CacheFlushResult { tx: 1, txids: 2 },
+            json!({"tx": 1, "txids": 2}),
+        );
+        round_trip(
+            BanInfo {
+                ip: "203.0.113.5".parse().unwrap(),
+                remaining_secs: 60,
+                bans: 1,
+                reason: "parse_error".to_owned(),
+            },
+            json!({
+                "ip": "203.0.113.5",
+                "remaining_secs": 60,
+                "bans": 1,
+                "reason": "parse_error",
+            }),
+        );
+        round_trip(
+            OrphanedEntry {
+                tx_hash: txid(1),
+                block_hash: blockhash(2),
+                height: 10,
+                confirmed_height: Some(11),
+                in_mempool: false,
+            },
+            json!({
+                "tx_hash": hex32(1),
+                "block_hash": hex32(2),
+                "height": 10,
+                "confirmed_height": 11,
+                "in_mempool": false,
+            }),
+        );
+        round_trip(
+            WebhookStats {
+                url: "http://127.0.0.1:8080/".to_owned(),
+                queued: 0,
+                delivered: 2,
+                retries: 1,
+                dead_lettered: 0,
+                dropped: 0,
+                dead_letters: vec![],
+            },
+            json!({
+                "url": "http://127.0.0.1:8080/",
+                "queued": 0,
+                "delivered": 2,
+                "retries": 1,
+                "dead_lettered": 0,
+                "dropped": 0,
+                "dead_letters": [],
+            }),
+        );
+        round_trip(
+            MirrorStats {
+                url: "http://127.0.0.1:8332/".to_owned(),
+                kind: "bitcoind".to_owned(),
+                network: None,
+                queued: 0,
+                submitted: 1,
+                retries: 0,
+                failed: 1,
+                dropped: 0,
+                healthy: false,
+                last_error: Some("HTTP status 500".to_owned()),
+            },
+            json!({
+                "url": "http://127.0.0.1:8332/",
+                "kind": "bitcoind",
+                "network": null,
+                "queued": 0,
+                "submitted": 1,
+                "retries": 0,
+                "failed": 1,
+                "dropped": 0,
+                "healthy": false,
+                "last_error": "HTTP status 500",
+            }),
+        );
+    }
+
+    #[test]
+    fn test_health_report() {
+        round_trip(
+            HealthReport {
+                height: 10,
+                tip: blockhash(1),
+                flags: HealthFlags {
+                    synced: true,
+                    daemon_reachable: true,
+                    index_writable: true,
+                    mempool_partial: false,
+                    stale: false,
+                    degraded: false,
+                },
+                daemon_height: Some(10),
+                last_sync_secs: None,
+                mempool_txs: 0,
+                mempool_age_secs: None,
+                tx_cache_entries: 0,
+                tx_cache_bytes: 0,
+            },
+            json!({
+                "height": 10,
+                "tip": hex32(1),
+                "synced": true,
+                "daemon_reachable": true,
+                "index_writable": true,
+                "mempool_partial": false,
+                "stale": false,
+                "degraded": false,
+                "daemon_height": 10,
+                "last_sync_secs": null,
+                "mempool_txs": 0,
+                "mempool_age_secs": null,
+                "tx_cache_entries": 0,
+                "tx_cache_bytes": 0,
+            }),
+        );
+    }
+}
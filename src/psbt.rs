@@ -0,0 +1,314 @@
+use anyhow::Result;
+use bitcoin::{
+    psbt::{self, PartiallySignedTransaction},
+    Amount, OutPoint, TxOut,
+};
+
+use crate::protocol::{PsbtAnalysis, PsbtInputAnalysis, PsbtSpender};
+
+/// Lower fee rates (in sat/vB) are not relayed by default
+const MIN_RELAY_FEE_RATE: f64 = 1.0;
+
+/// Higher fee rates (in sat/vB) are rejected by bitcoind's default `-maxfeerate` (0.1 BTC/kvB)
+const MAX_SANE_FEE_RATE: f64 = 10_000.0;
+
+/// What the server knows about an input's previous output
+pub(crate) struct Prevout {
+    pub txout: TxOut,
+    pub confirmations: usize, // 0 for mempool transactions
+    pub spent_by: Option<PsbtSpender>,
+}
+
+/// The part of the PSBT which failed to parse (if the parser's error tells it)
+fn error_section(err: &psbt::Error) -> Option<&'static str> {
+    match err {
+        psbt::Error::InvalidMagic | psbt::Error::InvalidSeparator => Some("header"),
+        psbt::Error::MustHaveUnsignedTx
+        | psbt::Error::UnsignedTxHasScriptSigs
+        | psbt::Error::UnsignedTxHasScriptWitnesses => Some("unsigned transaction"),
+        _ => None,
+    }
+}
+
+/// Decode a base64-encoded PSBT
+pub(crate) fn parse(base64_psbt: &str) -> Result<PartiallySignedTransaction> {
+    let bytes = base64::decode(base64_psbt.trim())
+        .map_err(|e| anyhow!("invalid PSBT encoding (expected base64): {}", e))?;
+    PartiallySignedTransaction::deserialize(&bytes).map_err(|e| match error_section(&e) {
+        Some(section) => anyhow!("invalid PSBT {}: {}", section, e),
+        None => anyhow!("invalid PSBT: {}", e),
+    })
+}
+
+fn analyze_input(
+    outpoint: OutPoint,
+    input: &psbt::Input,
+    prevout: Option<Prevout>,
+) -> PsbtInputAnalysis {
+    let mut warnings = vec![];
+    let non_witness_txout = match &input.non_witness_utxo {
+        Some(tx) if tx.txid() != outpoint.txid => {
+            warnings.push("non_witness_utxo_mismatch");
+            None
+        }
+        Some(tx) => tx.output.get(outpoint.vout as usize),
+        None => None,
+    };
+    let psbt_txout = input.witness_utxo.as_ref().or(non_witness_txout);
+    let server_txout = prevout.as_ref().map(|prevout| &prevout.txout);
+    if psbt_txout.is_none() {
+        warnings.push("missing_utxo");
+    }
+    if let (Some(psbt_txout), Some(server_txout)) = (psbt_txout, server_txout) {
+        if psbt_txout != server_txout {
+            warnings.push("utxo_mismatch");
+        }
+    }
+    // the server's view is preferred (the PSBT's data may be wrong)
+    let txout = server_txout.or(psbt_txout);
+    let spends_witness_program =
+        txout.map_or(false, |txout| txout.script_pubkey.is_witness_program());
+    if spends_witness_program && input.witness_utxo.is_none() {
+        warnings.push("missing_witness_utxo");
+    }
+    let value = txout.map(|txout| Amount::from_sat(txout.value));
+    let (status, confirmations, spent_by) = match prevout {
+        None => ("unknown", None, None),
+        Some(Prevout {
+            spent_by: None,
+            confirmations,
+            ..
+        }) => ("unspent", Some(confirmations), None),
+        Some(Prevout {
+            spent_by: Some(spender),
+            confirmations,
+            ..
+        }) => ("spent", Some(confirmations), Some(spender)),
+    };
+    PsbtInputAnalysis {
+        outpoint: outpoint.to_string(),
+        value,
+        has_witness_utxo: input.witness_utxo.is_some(),
+        has_non_witness_utxo: input.non_witness_utxo.is_some(),
+        finalized: input.final_script_sig.is_some() || input.final_script_witness.is_some(),
+        status: status.to_owned(),
+        confirmations,
+        spent_by,
+        warnings: to_strings(warnings),
+    }
+}
+
+/// Analyze the PSBT's inputs (using `resolve` for looking up their previous outputs), and its
+/// implied fee. The PSBT is not modified (nor finalized or broadcast).
+pub(crate) fn analyze(
+    psbt: &PartiallySignedTransaction,
+    mut resolve: impl FnMut(OutPoint) -> Result<Option<Prevout>>,
+) -> Result<PsbtAnalysis> {
+    let tx = &psbt.unsigned_tx;
+    let inputs = tx
+        .input
+        .iter()
+        .zip(&psbt.inputs)
+        .map(|(txin, input)| {
+            let outpoint = txin.previous_output;
+            Ok(analyze_input(outpoint, input, resolve(outpoint)?))
+        })
+        .collect::<Result<Vec<PsbtInputAnalysis>>>()?;
+
+    let mut warnings = vec![];
+    if inputs.iter().any(|input| input.status == "spent") {
+        warnings.push("spent_inputs");
+    }
+    if inputs.iter().any(|input| input.status == "unknown") {
+        warnings.push("unknown_inputs");
+    }
+    let input_value = inputs
+        .iter()
+        .try_fold(0u64, |sum, input| sum.checked_add(input.value?.to_sat()))
+        .map(Amount::from_sat);
+    let output_value = tx
+        .output
+        .iter()
+        .try_fold(0u64, |sum, txout| sum.checked_add(txout.value))
+        .map(Amount::from_sat)
+        .ok_or_else(|| anyhow!("invalid PSBT: output values overflow"))?;
+    let fee = input_value.and_then(|input_value| input_value.checked_sub(output_value));
+    if input_value.is_some() && fee.is_none() {
+        warnings.push("negative_fee");
+    }
+
+    let vsize_final = inputs.iter().all(|input| input.finalized);
+    let vsize = if vsize_final {
+        psbt.clone().extract_tx().vsize()
+    } else {
+        tx.vsize()
+    };
+    let fee_rate = fee.map(|fee| fee.to_sat() as f64 / vsize as f64);
+    if let Some(fee_rate) = fee_rate {
+        if fee_rate < MIN_RELAY_FEE_RATE {
+            warnings.push("low_fee_rate");
+        }
+        if fee_rate > MAX_SANE_FEE_RATE {
+            warnings.push("absurd_fee_rate");
+        }
+    }
+    Ok(PsbtAnalysis {
+        txid: tx.txid(),
+        inputs,
+        input_value,
+        output_value,
+        fee,
+        vsize,
+        vsize_final,
+        fee_rate,
+        warnings: to_strings(warnings),
+    })
+}
+
+fn to_strings(warnings: Vec<&str>) -> Vec<String> {
+    warnings.into_iter().map(str::to_owned).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{analyze, parse, Prevout};
+    use crate::protocol::PsbtSpender;
+    use anyhow::Result;
+    use bitcoin::{
+        absolute::LockTime, hashes::Hash, psbt::PartiallySignedTransaction, Amount, OutPoint,
+        PubkeyHash, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, WPubkeyHash, Witness,
+    };
+    use serde_json::json;
+
+    fn outpoint(n: u8) -> OutPoint {
+        OutPoint::new(Txid::from_byte_array([n; 32]), 0)
+    }
+
+    fn txout(value: u64) -> TxOut {
+        TxOut {
+            value,
+            script_pubkey: ScriptBuf::new_v0_p2wpkh(&WPubkeyHash::from_byte_array([1; 20])),
+        }
+    }
+
+    fn psbt() -> PartiallySignedTransaction {
+        let input = |n| TxIn {
+            previous_output: outpoint(n),
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        };
+        let tx = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![input(1), input(2), input(3)],
+            output: vec![TxOut {
+                value: 55_000,
+                script_pubkey: ScriptBuf::new_p2pkh(&PubkeyHash::from_byte_array([2; 20])),
+            }],
+        };
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(txout(30_000));
+        psbt.inputs[2].witness_utxo = Some(txout(1_000)); // doesn't match the server's
+        psbt
+    }
+
+    #[test]
+    fn test_parse() {
+        let psbt = psbt();
+        let encoded = base64::encode(psbt.serialize());
+        assert_eq!(parse(&encoded).unwrap(), psbt);
+        assert_eq!(parse(&format!(" {}\n", encoded)).unwrap(), psbt);
+
+        let err = parse("not base64!").unwrap_err();
+        assert!(err.to_string().contains("base64"), "{}", err);
+        let err = parse(&base64::encode(b"abcd\xff")).unwrap_err();
+        assert!(err.to_string().starts_with("invalid PSBT"), "{}", err);
+        let mut truncated = psbt.serialize();
+        truncated.truncate(truncated.len() - 10);
+        assert!(parse(&base64::encode(truncated)).is_err());
+    }
+
+    #[test]
+    fn test_analyze() {
+        let psbt = psbt();
+        let analysis = analyze(&psbt, |outpoint| {
+            Ok(match outpoint.txid.to_byte_array()[0] {
+                1 => Some(Prevout {
+                    txout: txout(30_000),
+                    confirmations: 6,
+                    spent_by: None,
+                }),
+                2 => Some(Prevout {
+                    txout: txout(20_000),
+                    confirmations: 0,
+                    spent_by: Some(PsbtSpender {
+                        txid: Txid::from_byte_array([9; 32]),
+                        height: 0,
+                    }),
+                }),
+                3 => Some(Prevout {
+                    txout: txout(10_000),
+                    confirmations: 100,
+                    spent_by: None,
+                }),
+                _ => None,
+            })
+        })
+        .unwrap();
+        let value = json!(analysis);
+        assert_eq!(value["input_value"], 60_000);
+        assert_eq!(value["output_value"], 55_000);
+        assert_eq!(value["fee"], 5_000);
+        assert_eq!(value["vsize_final"], false);
+        assert_eq!(value["vsize"], psbt.unsigned_tx.vsize());
+        assert_eq!(value["warnings"], json!(["spent_inputs"]));
+
+        let inputs = &value["inputs"];
+        assert_eq!(inputs[0]["status"], "unspent");
+        assert_eq!(inputs[0]["confirmations"], 6);
+        assert_eq!(inputs[0]["warnings"], json!([]));
+        assert_eq!(inputs[1]["status"], "spent");
+        assert_eq!(inputs[1]["spent_by"]["height"], 0);
+        assert_eq!(
+            inputs[1]["warnings"],
+            json!(["missing_utxo", "missing_witness_utxo"])
+        );
+        assert_eq!(inputs[2]["value"], 10_000); // as seen by the server
+        assert_eq!(inputs[2]["warnings"], json!(["utxo_mismatch"]));
+
+        // unknown to the server, so only the PSBT's values can be used
+        let analysis = analyze(&psbt, |_| Ok(None)).unwrap();
+        let value = json!(analysis);
+        assert_eq!(value["input_value"], json!(null));
+        assert_eq!(value["fee"], json!(null));
+        assert_eq!(value["fee_rate"], json!(null));
+        assert_eq!(value["warnings"], json!(["unknown_inputs"]));
+        assert_eq!(value["inputs"][0]["value"], 30_000);
+        assert_eq!(value["inputs"][1]["value"], json!(null));
+    }
+
+    /// All the inputs are unspent, and have the same value
+    fn resolve(value: u64) -> impl FnMut(OutPoint) -> Result<Option<Prevout>> {
+        move |_| {
+            Ok(Some(Prevout {
+                txout: txout(value),
+                confirmations: 1,
+                spent_by: None,
+            }))
+        }
+    }
+
+    #[test]
+    fn test_fee_warnings() {
+        let psbt = psbt();
+        // 3 inputs of 18_000 sat, paying 55_000 sat
+        let warnings = json!(analyze(&psbt, resolve(18_000)).unwrap())["warnings"].clone();
+        assert_eq!(warnings, json!(["negative_fee"]));
+        let warnings = json!(analyze(&psbt, resolve(18_334)).unwrap())["warnings"].clone();
+        assert_eq!(warnings, json!(["low_fee_rate"]));
+        let warnings =
+            json!(analyze(&psbt, resolve(Amount::ONE_BTC.to_sat())).unwrap())["warnings"].clone();
+        assert_eq!(warnings, json!(["absurd_fee_rate"]));
+    }
+}
@@ -0,0 +1,254 @@
+use anyhow::Result;
+use bitcoin::{consensus::encode::serialize_hex, Amount, ScriptBuf, Transaction, Witness};
+
+use crate::protocol::BuildRbfResult;
+
+/// Higher fee rates (in sat/vB) are rejected, since bitcoind wouldn't broadcast the replacement
+/// anyway (its default `-maxfeerate` is 10000 sat/vB), and their fees may overflow.
+const MAX_FEE_RATE: f64 = 10_000.0;
+
+/// A mempool transaction to be replaced
+pub(crate) struct Original<'a> {
+    pub tx: &'a Transaction,
+    pub fee: Amount,
+    pub vsize: u64,
+    pub descendant_fee: Amount, // including this transaction (all of them are replaced)
+}
+
+/// The output paying for the fee bump
+pub(crate) enum Change<'a> {
+    Index(usize),              // specified by the caller
+    PayingTo(&'a [ScriptBuf]), // paying back to one of the inputs' scripts
+}
+
+fn find_change(tx: &Transaction, change: Change) -> Result<usize> {
+    match change {
+        Change::Index(index) => {
+            ensure!(
+                index < tx.output.len(),
+                "invalid change output index {} (transaction has {} outputs)",
+                index,
+                tx.output.len()
+            );
+            Ok(index)
+        }
+        Change::PayingTo(scripts) => {
+            let mut indices = tx
+                .output
+                .iter()
+                .enumerate()
+                .filter(|(_, txout)| scripts.contains(&txout.script_pubkey))
+                .map(|(index, _)| index);
+            match (indices.next(), indices.next()) {
+                (Some(index), None) => Ok(index),
+                (None, _) => {
+                    bail!("no output pays back to an input's script (specify the change output)")
+                }
+                (Some(_), Some(_)) => bail!(
+                    "several outputs pay back to an input's script (specify the change output)"
+                ),
+            }
+        }
+    }
+}
+
+/// Build a template replacing `original` (and its descendants), paying at least `fee_rate` (in
+/// sat/vB) and satisfying BIP-125 rules: the replacement must pay for the replaced transactions,
+/// and for its own relay at `incremental_relay_fee` (in sat/kvB). The additional fee is deducted
+/// from the change output, which must remain above the dust limit.
+///
+/// The original's vsize is used, assuming the template is signed using the same keys (only an
+/// output's value is changed, so the transaction's size is the same).
+pub(crate) fn build(
+    original: &Original,
+    change: Change,
+    fee_rate: f64,
+    incremental_relay_fee: Amount,
+) -> Result<BuildRbfResult> {
+    let tx = original.tx;
+    ensure!(
+        tx.is_explicitly_rbf(),
+        "transaction {} doesn't signal replaceability (BIP-125)",
+        tx.txid()
+    );
+    ensure!(
+        fee_rate.is_finite() && fee_rate > 0.0 && fee_rate <= MAX_FEE_RATE,
+        "invalid fee rate: {} (must be positive, up to {} sat/vB)",
+        fee_rate,
+        MAX_FEE_RATE
+    );
+    let change_index = find_change(tx, change)?;
+
+    let vsize = original.vsize;
+    let overflow = || anyhow!("fee overflow (vsize: {}, fee rate: {})", vsize, fee_rate);
+    let by_rate = Amount::from_sat((fee_rate * vsize as f64).ceil() as u64);
+    let relay = incremental_relay_fee
+        .to_sat()
+        .checked_mul(vsize)
+        .map(|fee| Amount::from_sat((fee + 999) / 1000))
+        .ok_or_else(overflow)?;
+    let fee = original
+        .descendant_fee
+        .checked_add(relay)
+        .map(|bip125_fee| std::cmp::max(by_rate, bip125_fee))
+        .ok_or_else(overflow)?;
+    let additional_fee = fee.checked_sub(original.fee).ok_or_else(overflow)?;
+
+    let txout = &tx.output[change_index];
+    let dust = txout.script_pubkey.dust_value();
+    let min_change = additional_fee.checked_add(dust).ok_or_else(overflow)?;
+    ensure!(
+        txout.value >= min_change.to_sat(),
+        "change output #{} ({} sat) can't absorb the additional fee ({} sat) above the dust limit ({} sat)",
+        change_index,
+        txout.value,
+        additional_fee.to_sat(),
+        dust.to_sat()
+    );
+    let change_value = Amount::from_sat(txout.value) - additional_fee;
+
+    let mut template = tx.clone();
+    template.output[change_index].value = change_value.to_sat();
+    for txin in &mut template.input {
+        txin.script_sig = ScriptBuf::new();
+        txin.witness = Witness::new();
+    }
+    Ok(BuildRbfResult {
+        hex: serialize_hex(&template),
+        change_index,
+        change_value,
+        vsize,
+        fee_rate,
+        original_fee: original.fee,
+        replaced_fee: original.descendant_fee,
+        incremental_relay_fee,
+        fee,
+        additional_fee,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build, Change, Original};
+    use bitcoin::{
+        absolute::LockTime, consensus::deserialize, hashes::Hash, Amount, OutPoint, PubkeyHash,
+        ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, Witness,
+    };
+
+    fn script(n: u8) -> ScriptBuf {
+        ScriptBuf::new_p2pkh(&PubkeyHash::from_byte_array([n; 20]))
+    }
+
+    fn transaction(sequence: Sequence) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::from_byte_array([1; 32]), 0),
+                script_sig: ScriptBuf::from(vec![1, 2, 3]),
+                sequence,
+                witness: Witness::new(),
+            }],
+            output: vec![
+                TxOut {
+                    value: 50_000,
+                    script_pubkey: script(1), // payment
+                },
+                TxOut {
+                    value: 20_000,
+                    script_pubkey: script(2), // change
+                },
+            ],
+        }
+    }
+
+    fn original(tx: &Transaction) -> Original {
+        Original {
+            tx,
+            fee: Amount::from_sat(200),
+            vsize: 200,
+            descendant_fee: Amount::from_sat(500),
+        }
+    }
+
+    #[test]
+    fn test_build() {
+        let tx = transaction(Sequence::ENABLE_RBF_NO_LOCKTIME);
+        let inputs = [script(2)];
+        let incremental = Amount::from_sat(1000);
+
+        // the fee rate dominates: 10 sat/vB * 200 vB
+        let result = build(&original(&tx), Change::PayingTo(&inputs), 10.0, incremental).unwrap();
+        assert_eq!(result.change_index, 1);
+        assert_eq!(result.fee, Amount::from_sat(2000));
+        assert_eq!(result.additional_fee, Amount::from_sat(1800));
+        assert_eq!(result.change_value, Amount::from_sat(18_200));
+
+        let template: Transaction = deserialize(&crate::hex::decode(&result.hex).unwrap()).unwrap();
+        assert_eq!(
+            template.input[0].previous_output,
+            tx.input[0].previous_output
+        );
+        assert_eq!(template.input[0].sequence, tx.input[0].sequence);
+        assert!(template.input[0].script_sig.is_empty());
+        assert_eq!(template.output[0], tx.output[0]);
+        assert_eq!(template.output[1].value, 18_200);
+
+        // BIP-125 dominates: the replaced fees (including descendants) and 1 sat/vB * 200 vB
+        let result = build(&original(&tx), Change::Index(1), 1.5, incremental).unwrap();
+        assert_eq!(result.fee, Amount::from_sat(700));
+        assert_eq!(result.additional_fee, Amount::from_sat(500));
+    }
+
+    #[test]
+    fn test_invalid() {
+        let incremental = Amount::from_sat(1000);
+        let tx = transaction(Sequence::MAX);
+        let err = build(&original(&tx), Change::Index(1), 10.0, incremental).unwrap_err();
+        assert!(err.to_string().contains("replaceability"), "{}", err);
+
+        let tx = transaction(Sequence::ENABLE_RBF_NO_LOCKTIME);
+        let errors = [
+            build(&original(&tx), Change::Index(2), 10.0, incremental),
+            build(&original(&tx), Change::Index(1), -1.0, incremental),
+            build(&original(&tx), Change::Index(1), f64::NAN, incremental),
+            build(&original(&tx), Change::Index(1), 1e18, incremental), // mustn't overflow
+            build(&original(&tx), Change::Index(1), f64::MAX, incremental),
+            build(
+                &original(&tx),
+                Change::PayingTo(&[script(3)]),
+                10.0,
+                incremental,
+            ),
+            build(
+                &original(&tx),
+                Change::PayingTo(&[script(1), script(2)]),
+                10.0,
+                incremental,
+            ),
+            // the change can't absorb the bump
+            build(&original(&tx), Change::Index(1), 100.0, incremental),
+        ];
+        for result in errors.iter() {
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    fn test_fee_overflow() {
+        let tx = transaction(Sequence::ENABLE_RBF_NO_LOCKTIME);
+        let mut original = original(&tx);
+        original.descendant_fee = Amount::from_sat(u64::MAX - 100);
+        let err = build(&original, Change::Index(1), 10.0, Amount::from_sat(1000)).unwrap_err();
+        assert!(err.to_string().contains("fee overflow"), "{}", err);
+
+        let err = build(
+            &original,
+            Change::Index(1),
+            10.0,
+            Amount::from_sat(u64::MAX),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("fee overflow"), "{}", err);
+    }
+}
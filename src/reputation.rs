@@ -0,0 +1,574 @@
+use anyhow::{Context, Result};
+use parking_lot::Mutex;
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    clock::Clock,
+    lock_order::{self, Level},
+    metrics::{Gauge, Metrics},
+    protocol::BanInfo,
+};
+
+/// Maximum number of tracked peers (the least suspicious ones are evicted first)
+const MAX_TRACKED_PEERS: usize = 10_000;
+
+/// Violations' score is halved after this duration
+const SCORE_HALF_LIFE: Duration = Duration::from_secs(600);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReputationOptions {
+    pub ban_threshold: u32, // ban a peer when its violations' score reaches this value
+    pub ban_duration: Duration, // of the first ban (doubled for each subsequent one)
+    pub max_ban_duration: Duration,
+    pub path: Option<PathBuf>, // persist the bans into this file
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Violation {
+    ParseError, // malformed JSON or JSON-RPC request
+    SlowPeer,   // disconnected for not reading its responses
+}
+
+impl Violation {
+    fn as_str(self) -> &'static str {
+        match self {
+            Violation::ParseError => "parse_error",
+            Violation::SlowPeer => "slow_peer",
+        }
+    }
+
+    fn score(self) -> f64 {
+        match self {
+            Violation::ParseError => 1.0,
+            Violation::SlowPeer => 5.0, // each one costs up to `MAX_PENDING_BATCHES` responses
+        }
+    }
+}
+
+struct PeerState {
+    score: f64,
+    updated: Instant,
+    bans: u32, // previous bans, for increasing the next ban's duration
+    banned_until: Option<Instant>,
+    reason: String, // of the last ban
+}
+
+impl PeerState {
+    fn new(now: Instant) -> Self {
+        Self {
+            score: 0.0,
+            updated: now,
+            bans: 0,
+            banned_until: None,
+            reason: String::new(),
+        }
+    }
+
+    fn is_banned(&self, now: Instant) -> bool {
+        self.banned_until.map_or(false, |until| until > now)
+    }
+
+    /// Decay the score, and forget past bans after `forget_after` of good behaviour.
+    fn update(&mut self, now: Instant, forget_after: Duration) {
+        let elapsed = now.saturating_duration_since(self.updated);
+        self.score *= 0.5f64.powf(elapsed.as_secs_f64() / SCORE_HALF_LIFE.as_secs_f64());
+        self.updated = now;
+        if let Some(until) = self.banned_until {
+            if until + forget_after <= now {
+                self.bans = 0;
+                self.banned_until = None;
+            }
+        }
+    }
+}
+
+/// A persisted ban (the scores are not persisted)
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct BanEntry {
+    ip: IpAddr,
+    banned_until: u64, // UNIX timestamp (in seconds)
+    bans: u32,
+    reason: String,
+}
+
+struct Inner {
+    options: ReputationOptions,
+    peers: HashMap<IpAddr, PeerState>,
+    ban_counts: HashMap<&'static str, usize>,
+    gauge: Gauge,
+    clock: Clock,
+}
+
+impl Inner {
+    fn new(options: ReputationOptions, metrics: &Metrics) -> Self {
+        let gauge = metrics.gauge("peer_bans", "# of peer bans (since startup)", "reason");
+        Self {
+            options,
+            peers: HashMap::new(),
+            ban_counts: HashMap::new(),
+            gauge,
+            clock: Clock::default(),
+        }
+    }
+
+    fn ban_remaining(&self, ip: IpAddr, now: Instant) -> Option<Duration> {
+        let peer = self.peers.get(&ip)?;
+        if !peer.is_banned(now) {
+            return None;
+        }
+        Some(peer.banned_until?.saturating_duration_since(now))
+    }
+
+    fn record(&mut self, ip: IpAddr, violation: Violation, now: Instant) {
+        if !self.peers.contains_key(&ip) && self.peers.len() >= MAX_TRACKED_PEERS {
+            self.evict(now);
+        }
+        let peer = self.peers.entry(ip).or_insert_with(|| PeerState::new(now));
+        if peer.is_banned(now) {
+            return; // e.g. the connection is being closed
+        }
+        peer.update(now, self.options.max_ban_duration);
+        peer.score += violation.score();
+        if peer.score < f64::from(self.options.ban_threshold) {
+            return;
+        }
+        let duration = ban_duration(&self.options, peer.bans);
+        peer.score = 0.0;
+        peer.bans += 1;
+        peer.banned_until = Some(now + duration);
+        peer.reason = violation.as_str().to_owned();
+        warn!(
+            "banning {} for {}s due to {} (ban #{})",
+            ip,
+            duration.as_secs(),
+            peer.reason,
+            peer.bans
+        );
+        let count = self.ban_counts.entry(violation.as_str()).or_default();
+        *count += 1;
+        self.gauge.set(violation.as_str(), *count as f64);
+        self.save(now);
+    }
+
+    /// Drop the least suspicious peer (preferring unbanned ones).
+    fn evict(&mut self, now: Instant) {
+        let forget_after = self.options.max_ban_duration;
+        let victim = self
+            .peers
+            .iter_mut()
+            .map(|(ip, peer)| {
+                peer.update(now, forget_after);
+                ((peer.is_banned(now), peer.bans, peer.score), *ip)
+            })
+            .min_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
+            .map(|(_, ip)| ip);
+        if let Some(ip) = victim {
+            self.peers.remove(&ip);
+        }
+    }
+
+    fn unban(&mut self, ip: IpAddr, now: Instant) -> bool {
+        let banned = match self.peers.remove(&ip) {
+            Some(peer) => peer.is_banned(now),
+            None => false,
+        };
+        if banned {
+            info!("unbanned {}", ip);
+            self.save(now);
+        }
+        banned
+    }
+
+    fn bans(&self, now: Instant) -> Vec<BanInfo> {
+        let mut bans: Vec<(&IpAddr, &PeerState)> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.is_banned(now))
+            .collect();
+        bans.sort_unstable_by_key(|(ip, _)| **ip);
+        bans.into_iter()
+            .map(|(ip, peer)| {
+                let remaining = self.ban_remaining(*ip, now).unwrap_or_default();
+                BanInfo {
+                    ip: *ip,
+                    remaining_secs: remaining.as_secs(),
+                    bans: peer.bans,
+                    reason: peer.reason.clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// Peers whose bans are still remembered (so repeat offenders are banned for longer).
+    fn entries(&self, now: Instant, wall_now: SystemTime) -> Vec<BanEntry> {
+        self.peers
+            .iter()
+            .filter(|(_, peer)| peer.bans > 0)
+            .map(|(ip, peer)| BanEntry {
+                ip: *ip,
+                banned_until: peer
+                    .banned_until
+                    .map_or(0, |until| unix_secs(until, now, wall_now)),
+                bans: peer.bans,
+                reason: peer.reason.clone(),
+            })
+            .collect()
+    }
+
+    fn save(&mut self, now: Instant) {
+        let path = match &self.options.path {
+            Some(path) => path,
+            None => return,
+        };
+        let forget_after = self.options.max_ban_duration;
+        self.peers
+            .values_mut()
+            .for_each(|peer| peer.update(now, forget_after));
+        if let Err(e) = save_entries(path, &self.entries(now, SystemTime::now())) {
+            warn!("failed to save peer bans: {:#}", e);
+        }
+    }
+
+    fn load(&mut self, path: &Path, now: Instant, wall_now: SystemTime) -> Result<()> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+        };
+        let entries: Vec<BanEntry> = serde_json::from_slice(&data)
+            .with_context(|| format!("invalid peer bans file {}", path.display()))?;
+        for entry in entries.into_iter().take(MAX_TRACKED_PEERS) {
+            let mut peer = PeerState::new(now);
+            peer.bans = entry.bans;
+            peer.banned_until = Some(from_unix_secs(entry.banned_until, now, wall_now));
+            peer.reason = entry.reason;
+            peer.update(now, self.options.max_ban_duration);
+            if peer.bans > 0 {
+                self.peers.insert(entry.ip, peer);
+            }
+        }
+        info!(
+            "loaded {} banned peers from {}",
+            self.peers
+                .values()
+                .filter(|peer| peer.is_banned(now))
+                .count(),
+            path.display()
+        );
+        Ok(())
+    }
+}
+
+fn ban_duration(options: &ReputationOptions, previous_bans: u32) -> Duration {
+    options
+        .ban_duration
+        .checked_mul(1 << previous_bans.min(16))
+        .map_or(options.max_ban_duration, |duration| {
+            duration.min(options.max_ban_duration)
+        })
+}
+
+/// Bans expire according to the monotonic clock, and are persisted as UNIX timestamps (converted
+/// using the wall clock only when saved and loaded).
+fn unix_secs(time: Instant, now: Instant, wall_now: SystemTime) -> u64 {
+    let wall_time = match time.checked_duration_since(now) {
+        Some(remaining) => wall_now + remaining,
+        None => wall_now
+            .checked_sub(now.saturating_duration_since(time))
+            .unwrap_or(UNIX_EPOCH),
+    };
+    wall_time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn from_unix_secs(secs: u64, now: Instant, wall_now: SystemTime) -> Instant {
+    let wall_time = UNIX_EPOCH + Duration::from_secs(secs);
+    match wall_time.duration_since(wall_now) {
+        Ok(remaining) => now + remaining,
+        Err(e) => now.checked_sub(e.duration()).unwrap_or(now), // already expired
+    }
+}
+
+/// Replace the file atomically, so a crash doesn't lose the previous bans.
+fn save_entries(path: &Path, entries: &[BanEntry]) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let data = serde_json::to_vec(entries).context("failed to serialize peer bans")?;
+    fs::write(&tmp, data).with_context(|| format!("failed to write {}", tmp.display()))?;
+    fs::rename(&tmp, path).with_context(|| format!("failed to replace {}", path.display()))
+}
+
+/// Per-IP reputation, shared by all networks' listeners (disabled by default).
+#[derive(Clone, Default)]
+pub(crate) struct Reputation {
+    inner: Option<Arc<Mutex<Inner>>>, // `None` if disabled
+}
+
+impl Reputation {
+    pub(crate) fn open(options: Option<&ReputationOptions>, metrics: &Metrics) -> Result<Self> {
+        let options = match options {
+            Some(options) => options,
+            None => return Ok(Self::default()),
+        };
+        let mut inner = Inner::new(options.clone(), metrics);
+        if let Some(path) = &options.path {
+            let now = inner.clock.now();
+            inner.load(path, now, SystemTime::now())?;
+        }
+        Ok(Self {
+            inner: Some(Arc::new(Mutex::new(inner))),
+        })
+    }
+
+    fn with_inner<T>(&self, f: impl FnOnce(&mut Inner, Instant) -> T) -> Option<T> {
+        let inner = self.inner.as_ref()?;
+        let _order = lock_order::acquire(Level::Reputation);
+        let mut inner = inner.lock();
+        let now = inner.clock.now();
+        Some(f(&mut inner, now))
+    }
+
+    /// Remaining ban duration of the given peer (if banned).
+    pub(crate) fn ban_remaining(&self, ip: IpAddr) -> Option<Duration> {
+        self.with_inner(|inner, now| inner.ban_remaining(ip, now))
+            .flatten()
+    }
+
+    pub(crate) fn record(&self, peer: Option<SocketAddr>, violation: Violation) {
+        if let Some(peer) = peer {
+            self.with_inner(|inner, now| inner.record(peer.ip(), violation, now));
+        }
+    }
+
+    /// Fails if the peer got banned (so it should be disconnected).
+    pub(crate) fn check(&self, peer: Option<SocketAddr>) -> Result<()> {
+        match peer.and_then(|peer| self.ban_remaining(peer.ip())) {
+            Some(remaining) => bail!("peer is banned for {}s", remaining.as_secs()),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn bans(&self) -> Vec<BanInfo> {
+        self.with_inner(|inner, now| inner.bans(now))
+            .unwrap_or_default()
+    }
+
+    /// Returns whether the peer was banned.
+    pub(crate) fn unban(&self, ip: IpAddr) -> bool {
+        self.with_inner(|inner, now| inner.unban(ip, now))
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ban_duration, BanEntry, Inner, PeerState, Reputation, ReputationOptions, Violation,
+        MAX_TRACKED_PEERS, SCORE_HALF_LIFE,
+    };
+    use crate::{clock::Clock, metrics::Metrics};
+    use parking_lot::Mutex;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    fn options(path: Option<PathBuf>) -> ReputationOptions {
+        ReputationOptions {
+            ban_threshold: 10,
+            ban_duration: Duration::from_secs(60),
+            max_ban_duration: Duration::from_secs(3600),
+            path,
+        }
+    }
+
+    fn ip(n: u32) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::from(n))
+    }
+
+    #[test]
+    fn test_ban_duration() {
+        let options = options(None);
+        let secs: Vec<u64> = (0..8)
+            .map(|bans| ban_duration(&options, bans).as_secs())
+            .collect();
+        assert_eq!(secs, vec![60, 120, 240, 480, 960, 1920, 3600, 3600]);
+        assert_eq!(ban_duration(&options, u32::MAX).as_secs(), 3600);
+    }
+
+    #[test]
+    fn test_repeated_bans() {
+        let mut inner = Inner::new(options(None), &Metrics::dummy());
+        let start = Clock::mock().now();
+        for _ in 0..9 {
+            inner.record(ip(1), Violation::ParseError, start);
+        }
+        assert_eq!(inner.ban_remaining(ip(1), start), None);
+        inner.record(ip(1), Violation::ParseError, start);
+        assert_eq!(
+            inner.ban_remaining(ip(1), start),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(inner.ban_remaining(ip(2), start), None);
+
+        // violations during the ban are ignored
+        inner.record(ip(1), Violation::SlowPeer, start);
+        assert_eq!(inner.ban_counts["parse_error"], 1);
+
+        // the second ban is longer
+        let now = start + Duration::from_secs(61);
+        assert_eq!(inner.ban_remaining(ip(1), now), None);
+        inner.record(ip(1), Violation::SlowPeer, now);
+        inner.record(ip(1), Violation::SlowPeer, now);
+        assert_eq!(
+            inner.ban_remaining(ip(1), now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(inner.ban_counts["slow_peer"], 1);
+
+        let bans = inner.bans(now);
+        assert_eq!(bans.len(), 1);
+        assert_eq!(bans[0]["ip"], "0.0.0.1");
+        assert_eq!(bans[0]["bans"], 2);
+        assert_eq!(bans[0]["reason"], "slow_peer");
+
+        // past bans are forgotten after the maximal ban duration
+        let now = now + Duration::from_secs(120 + 3600);
+        for _ in 0..2 {
+            inner.record(ip(1), Violation::SlowPeer, now);
+        }
+        assert_eq!(
+            inner.ban_remaining(ip(1), now),
+            Some(Duration::from_secs(60))
+        );
+
+        assert!(inner.unban(ip(1), now));
+        assert!(!inner.unban(ip(1), now));
+        assert_eq!(inner.ban_remaining(ip(1), now), None);
+        assert!(inner.bans(now).is_empty());
+    }
+
+    #[test]
+    fn test_score_decay() {
+        let mut inner = Inner::new(options(None), &Metrics::dummy());
+        let mut now = Clock::mock().now();
+        // a slow trickle of violations doesn't result in a ban
+        for _ in 0..100 {
+            inner.record(ip(1), Violation::SlowPeer, now);
+            now += SCORE_HALF_LIFE * 2;
+            assert_eq!(inner.ban_remaining(ip(1), now), None);
+        }
+    }
+
+    #[test]
+    fn test_bounded_peers() {
+        let mut inner = Inner::new(options(None), &Metrics::dummy());
+        let now = Clock::mock().now();
+        for _ in 0..2 {
+            inner.record(ip(0), Violation::SlowPeer, now);
+        }
+        assert!(inner.ban_remaining(ip(0), now).is_some());
+        for n in 1..=(MAX_TRACKED_PEERS as u32) {
+            inner.record(ip(n), Violation::ParseError, now);
+        }
+        assert_eq!(inner.peers.len(), MAX_TRACKED_PEERS);
+        assert!(inner.ban_remaining(ip(0), now).is_some()); // banned peers are evicted last
+    }
+
+    #[test]
+    fn test_persistence() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bans.json");
+        let now = Clock::mock().now();
+
+        let mut inner = Inner::new(options(Some(path.clone())), &Metrics::dummy());
+        inner.load(&path, now, SystemTime::now()).unwrap(); // missing file
+        for _ in 0..10 {
+            inner.record(ip(1), Violation::ParseError, now);
+            inner.record(ip(2), Violation::ParseError, now);
+        }
+        inner.record(ip(3), Violation::ParseError, now);
+        assert!(inner.unban(ip(2), now));
+
+        let mut loaded = Inner::new(options(Some(path.clone())), &Metrics::dummy());
+        loaded.load(&path, now, SystemTime::now()).unwrap();
+        assert_eq!(loaded.peers.len(), 1);
+        let remaining = loaded.ban_remaining(ip(1), now).unwrap();
+        assert!(remaining <= Duration::from_secs(60));
+        assert!(remaining >= Duration::from_secs(59));
+
+        let wall_now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut entries = loaded.entries(now, wall_now);
+        assert_eq!(entries.len(), 1);
+        let entry = entries.pop().unwrap();
+        assert_eq!(
+            entry,
+            BanEntry {
+                ip: ip(1),
+                banned_until: 1_000_000 + remaining.as_secs(),
+                bans: 1,
+                reason: "parse_error".to_owned(),
+            }
+        );
+
+        // restarted after the ban has expired (according to the wall clock)
+        let mut expired = Inner::new(options(Some(path.clone())), &Metrics::dummy());
+        let later = SystemTime::now() + Duration::from_secs(61);
+        expired.load(&path, now, later).unwrap();
+        assert_eq!(expired.ban_remaining(ip(1), now), None);
+        assert_eq!(expired.peers.len(), 1); // the previous ban is still remembered
+
+        std::fs::write(&path, "[{").unwrap();
+        let mut invalid = Inner::new(options(Some(path.clone())), &Metrics::dummy());
+        assert!(invalid.load(&path, now, SystemTime::now()).is_err());
+    }
+
+    #[test]
+    fn test_expiry() {
+        let clock = Clock::mock();
+        let mut inner = Inner::new(options(None), &Metrics::dummy());
+        inner.clock = clock.clone();
+        let reputation = Reputation {
+            inner: Some(Arc::new(Mutex::new(inner))),
+        };
+        let peer = Some(SocketAddr::new(ip(1), 50001));
+        for _ in 0..10 {
+            reputation.record(peer, Violation::ParseError);
+        }
+        assert!(reputation.check(peer).is_err());
+
+        // bans expire according to the monotonic clock
+        clock.advance(Duration::from_secs(59));
+        assert_eq!(
+            reputation.ban_remaining(ip(1)),
+            Some(Duration::from_secs(1))
+        );
+        clock.advance(Duration::from_secs(1));
+        assert!(reputation.check(peer).is_ok());
+        assert!(reputation.bans().is_empty());
+    }
+
+    #[test]
+    fn test_forget_bans() {
+        let now = Clock::mock().now();
+        let mut peer = PeerState::new(now);
+        peer.bans = 3;
+        peer.banned_until = Some(now + Duration::from_secs(10));
+        peer.update(now + Duration::from_secs(20), Duration::from_secs(10));
+        assert_eq!(peer.bans, 3);
+        peer.update(now + Duration::from_secs(30), Duration::from_secs(10));
+        assert_eq!(peer.bans, 0);
+        assert_eq!(peer.banned_until, None);
+    }
+}
@@ -0,0 +1,308 @@
+use bitcoin::OutPoint;
+use parking_lot::Mutex;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::{
+    cancel::CancelToken,
+    lock_order::{self, Level},
+    metrics::{Gauge, Metrics},
+    types::ScriptHash,
+};
+
+/// The earliest reservations are dropped when exceeded
+const MAX_RESERVED_OUTPOINTS: usize = 100_000;
+
+/// The client which reserved an outpoint (for one of its scripthashes): only it may release the
+/// reservation, which is dropped when the client disconnects (i.e. when its token is cancelled).
+#[derive(Clone)]
+pub(crate) struct Owner {
+    pub(crate) scripthash: ScriptHash,
+    pub(crate) client: CancelToken,
+}
+
+impl Owner {
+    fn is(&self, other: &Owner) -> bool {
+        self.scripthash == other.scripthash && self.client.same_as(&other.client)
+    }
+}
+
+struct Reservation {
+    expiry: Instant,
+    owner: Owner,
+}
+
+#[derive(Default)]
+struct State {
+    reserved: HashMap<OutPoint, Reservation>,
+    order: VecDeque<(OutPoint, Instant)>, // earliest expiry first (including released ones)
+}
+
+impl State {
+    fn pop_front(&mut self) {
+        if let Some((outpoint, expiry)) = self.order.pop_front() {
+            // unless it was released (or reserved again) meanwhile
+            if self.reserved.get(&outpoint).map(|r| r.expiry) == Some(expiry) {
+                self.reserved.remove(&outpoint);
+            }
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((_, expiry)) = self.order.front() {
+            if *expiry > now {
+                break;
+            }
+            self.pop_front();
+        }
+    }
+
+    /// Whether the outpoint is reserved (dropping its reservation if the owner has disconnected)
+    fn is_reserved(&mut self, outpoint: &OutPoint) -> bool {
+        let disconnected = match self.reserved.get(outpoint) {
+            Some(reservation) => reservation.owner.client.is_cancelled(),
+            None => return false,
+        };
+        if disconnected {
+            self.reserved.remove(outpoint);
+        }
+        !disconnected
+    }
+}
+
+/// Outpoints returned by `blockchain.scripthash.select_unspent`, which are excluded from the
+/// following selections (by all clients) until they expire, are released by their owner, or their
+/// owner disconnects, so concurrent clients (e.g. payout workers sharing a scripthash) don't build
+/// conflicting transactions.
+///
+/// Reservations are best-effort: they are kept in memory by each server (so they are neither
+/// shared between servers nor persisted), and the earliest ones are dropped when too many are held.
+pub(crate) struct Reservations {
+    ttl: Option<Duration>, // disabled if `None`
+    state: Mutex<State>,
+    gauge: Gauge,
+}
+
+impl Reservations {
+    pub(crate) fn new(ttl: Option<Duration>, metrics: &Metrics) -> Self {
+        let gauge = metrics.gauge(
+            "utxo_reservations",
+            "# of outpoints reserved by `blockchain.scripthash.select_unspent`",
+            "type",
+        );
+        gauge.set("outpoints", 0.0);
+        Self {
+            ttl,
+            state: Mutex::new(State::default()),
+            gauge,
+        }
+    }
+
+    /// Reserve the outpoints for the configured TTL (renewing their existing reservations).
+    pub(crate) fn reserve(
+        &self,
+        owner: &Owner,
+        outpoints: impl Iterator<Item = OutPoint>,
+        now: Instant,
+    ) {
+        let ttl = match self.ttl {
+            Some(ttl) => ttl,
+            None => return,
+        };
+        let expiry = now + ttl;
+        let _order = lock_order::acquire(Level::Reservations);
+        let mut state = self.state.lock();
+        state.expire(now);
+        for outpoint in outpoints {
+            let owner = owner.clone();
+            state
+                .reserved
+                .insert(outpoint, Reservation { expiry, owner });
+            state.order.push_back((outpoint, expiry));
+        }
+        while state.order.len() > MAX_RESERVED_OUTPOINTS {
+            state.pop_front();
+        }
+        self.update_gauge(&state);
+    }
+
+    /// The given outpoints which are currently reserved (by any client)
+    pub(crate) fn reserved(
+        &self,
+        outpoints: impl Iterator<Item = OutPoint>,
+        now: Instant,
+    ) -> HashSet<OutPoint> {
+        if self.ttl.is_none() {
+            return HashSet::new();
+        }
+        let _order = lock_order::acquire(Level::Reservations);
+        let mut state = self.state.lock();
+        state.expire(now);
+        let reserved = outpoints
+            .filter(|outpoint| state.is_reserved(outpoint))
+            .collect();
+        self.update_gauge(&state);
+        reserved
+    }
+
+    /// Release the owner's reservations of the outpoints early, returning how many were released
+    /// (the ones reserved by other clients, or for other scripthashes, are kept).
+    pub(crate) fn release(
+        &self,
+        owner: &Owner,
+        outpoints: impl Iterator<Item = OutPoint>,
+        now: Instant,
+    ) -> usize {
+        let _order = lock_order::acquire(Level::Reservations);
+        let mut state = self.state.lock();
+        state.expire(now);
+        let released = outpoints
+            .filter(|outpoint| {
+                let owned = state
+                    .reserved
+                    .get(outpoint)
+                    .map_or(false, |r| r.owner.is(owner));
+                if owned {
+                    state.reserved.remove(outpoint);
+                }
+                owned
+            })
+            .count();
+        self.update_gauge(&state);
+        released
+    }
+
+    fn update_gauge(&self, state: &State) {
+        self.gauge.set("outpoints", state.reserved.len() as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Owner, Reservations, MAX_RESERVED_OUTPOINTS};
+    use crate::{cancel::CancelToken, metrics::Metrics, types::ScriptHash};
+    use bitcoin::{hashes::Hash, OutPoint, Txid};
+    use std::time::{Duration, Instant};
+
+    fn outpoint(vout: u32) -> OutPoint {
+        OutPoint::new(Txid::from_byte_array([1; 32]), vout)
+    }
+
+    fn owner(n: u8) -> Owner {
+        Owner {
+            scripthash: ScriptHash::from_byte_array([n; 32]),
+            client: CancelToken::default(),
+        }
+    }
+
+    fn reserved(reservations: &Reservations, vouts: &[u32], now: Instant) -> Vec<u32> {
+        let outpoints = vouts.iter().map(|vout| outpoint(*vout));
+        let mut reserved: Vec<u32> = reservations
+            .reserved(outpoints, now)
+            .into_iter()
+            .map(|outpoint| outpoint.vout)
+            .collect();
+        reserved.sort_unstable();
+        reserved
+    }
+
+    #[test]
+    fn test_expiry() {
+        let ttl = Duration::from_secs(60);
+        let reservations = Reservations::new(Some(ttl), &Metrics::dummy());
+        let owner = owner(1);
+        let start = Instant::now();
+        reservations.reserve(&owner, vec![outpoint(0), outpoint(1)].into_iter(), start);
+        assert_eq!(reserved(&reservations, &[0, 1, 2], start), vec![0, 1]);
+
+        // renewed reservations expire later
+        let later = start + Duration::from_secs(30);
+        reservations.reserve(&owner, vec![outpoint(1)].into_iter(), later);
+        assert_eq!(reserved(&reservations, &[0, 1, 2], start + ttl), vec![1]);
+        assert!(reserved(&reservations, &[0, 1, 2], later + ttl).is_empty());
+    }
+
+    #[test]
+    fn test_release() {
+        let reservations = Reservations::new(Some(Duration::from_secs(60)), &Metrics::dummy());
+        let owner = owner(1);
+        let now = Instant::now();
+        reservations.reserve(&owner, (0..3).map(outpoint), now);
+        let released =
+            reservations.release(&owner, vec![outpoint(1), outpoint(5)].into_iter(), now);
+        assert_eq!(released, 1);
+        assert_eq!(reserved(&reservations, &[0, 1, 2], now), vec![0, 2]);
+
+        // a released reservation can be taken again
+        reservations.reserve(&owner, vec![outpoint(1)].into_iter(), now);
+        assert_eq!(reserved(&reservations, &[0, 1, 2], now), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_release_by_owner() {
+        let reservations = Reservations::new(Some(Duration::from_secs(60)), &Metrics::dummy());
+        let owner = owner(1);
+        let now = Instant::now();
+        reservations.reserve(&owner, (0..2).map(outpoint), now);
+
+        // neither another client (of the same scripthash) nor another scripthash of the owner
+        let other_client = Owner {
+            client: CancelToken::default(),
+            ..owner.clone()
+        };
+        let other_scripthash = Owner {
+            scripthash: ScriptHash::from_byte_array([2; 32]),
+            client: owner.client.clone(),
+        };
+        for other in [other_client, other_scripthash].iter() {
+            assert_eq!(reservations.release(other, (0..2).map(outpoint), now), 0);
+        }
+        assert_eq!(reserved(&reservations, &[0, 1], now), vec![0, 1]);
+        assert_eq!(reservations.release(&owner, (0..2).map(outpoint), now), 2);
+    }
+
+    #[test]
+    fn test_disconnected_owner() {
+        let reservations = Reservations::new(Some(Duration::from_secs(60)), &Metrics::dummy());
+        let (first, second) = (owner(1), owner(1));
+        let now = Instant::now();
+        reservations.reserve(&first, vec![outpoint(0)].into_iter(), now);
+        reservations.reserve(&second, vec![outpoint(1)].into_iter(), now);
+
+        first.client.cancel();
+        assert_eq!(reserved(&reservations, &[0, 1], now), vec![1]);
+        assert_eq!(reservations.state.lock().reserved.len(), 1);
+    }
+
+    #[test]
+    fn test_bounded() {
+        let reservations = Reservations::new(Some(Duration::from_secs(60)), &Metrics::dummy());
+        let now = Instant::now();
+        let count = MAX_RESERVED_OUTPOINTS as u32 + 10;
+        reservations.reserve(&owner(1), (0..count).map(outpoint), now);
+        // the earliest reservations are dropped
+        assert!(reserved(&reservations, &[0, 9], now).is_empty());
+        assert_eq!(
+            reserved(&reservations, &[10, count - 1], now),
+            vec![10, count - 1]
+        );
+        assert_eq!(
+            reservations.state.lock().reserved.len(),
+            MAX_RESERVED_OUTPOINTS
+        );
+    }
+
+    #[test]
+    fn test_disabled() {
+        let reservations = Reservations::new(None, &Metrics::dummy());
+        let owner = owner(1);
+        let now = Instant::now();
+        reservations.reserve(&owner, vec![outpoint(0)].into_iter(), now);
+        assert!(reserved(&reservations, &[0], now).is_empty());
+        assert_eq!(
+            reservations.release(&owner, vec![outpoint(0)].into_iter(), now),
+            0
+        );
+    }
+}
@@ -0,0 +1,58 @@
+use std::fmt;
+
+/// Maximum number of characters to display (the rest are omitted)
+const MAX_DISPLAYED_CHARS: usize = 200;
+
+/// Displays a client-supplied string, so it can be safely logged or echoed back:
+/// control (and bidirectional override) characters are escaped, and long strings are truncated.
+pub(crate) struct Sanitized<'a>(pub &'a str);
+
+fn is_unsafe(c: char) -> bool {
+    c.is_control() || matches!(c, '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}')
+}
+
+impl fmt::Display for Sanitized<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut chars = self.0.chars();
+        for c in chars.by_ref().take(MAX_DISPLAYED_CHARS) {
+            if is_unsafe(c) {
+                write!(f, "{}", c.escape_unicode())?;
+            } else {
+                write!(f, "{}", c)?;
+            }
+        }
+        let omitted = chars.count();
+        if omitted > 0 {
+            write!(f, "...({} more characters)", omitted)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Sanitized, MAX_DISPLAYED_CHARS};
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(Sanitized("Electrum 4.4.6").to_string(), "Electrum 4.4.6");
+        assert_eq!(
+            Sanitized("\x1b[31mred\x1b[0m\r\n").to_string(),
+            "\\u{1b}[31mred\\u{1b}[0m\\u{d}\\u{a}"
+        );
+        assert_eq!(Sanitized("abc\u{202e}fed").to_string(), "abc\\u{202e}fed");
+        assert_eq!(Sanitized("ünïcödé").to_string(), "ünïcödé");
+    }
+
+    #[test]
+    fn test_truncate() {
+        let long = "x".repeat(MAX_DISPLAYED_CHARS + 1000);
+        let sanitized = Sanitized(&long).to_string();
+        assert_eq!(
+            sanitized,
+            format!("{}...(1000 more characters)", &long[..MAX_DISPLAYED_CHARS])
+        );
+        let exact = "y".repeat(MAX_DISPLAYED_CHARS);
+        assert_eq!(Sanitized(&exact).to_string(), exact);
+    }
+}
@@ -0,0 +1,345 @@
+use anyhow::Result;
+use parking_lot::{Condvar, Mutex};
+use rayon::prelude::*;
+
+use std::cell::Cell;
+
+use crate::{
+    cancel::CancelToken,
+    lock_order::{self, Level},
+    metrics::{Gauge, Metrics},
+};
+
+/// High-priority builds granted while low-priority ones are waiting, before letting one through
+const MAX_LOW_PRIORITY_SKIPS: usize = 8;
+
+/// Status builds' scheduling tier, tagged by the requesting handler
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Priority {
+    Interactive, // e.g. a single subscription or balance query
+    Bulk,        // e.g. a large batch of subscriptions
+}
+
+impl Priority {
+    fn index(self) -> usize {
+        match self {
+            Priority::Interactive => 0,
+            Priority::Bulk => 1,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Priority::Interactive => "interactive",
+            Priority::Bulk => "bulk",
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    running: usize,
+    queued: [usize; 2],  // builds not started yet (per tier)
+    waiting: [usize; 2], // threads waiting for a permit (per tier)
+    low_skips: usize,    // interactive builds granted while bulk ones were waiting
+}
+
+impl State {
+    fn can_start(&self, priority: Priority, workers: usize) -> bool {
+        if self.running >= workers {
+            return false;
+        }
+        let starving = self.low_skips >= MAX_LOW_PRIORITY_SKIPS;
+        match priority {
+            Priority::Interactive => !(starving && self.waiting[Priority::Bulk.index()] > 0),
+            Priority::Bulk => starving || self.waiting[Priority::Interactive.index()] == 0,
+        }
+    }
+}
+
+thread_local! {
+    // nested builds (e.g. stolen by a rayon worker) reuse the running build's permit
+    static HOLDS_PERMIT: Cell<bool> = Cell::new(false);
+}
+
+/// Bounds the concurrent status builds, letting interactive ones preempt queued bulk builds
+/// (while still letting a bulk build start after `MAX_LOW_PRIORITY_SKIPS` interactive ones).
+pub(crate) struct Scheduler {
+    workers: usize,
+    state: Mutex<State>,
+    condvar: Condvar,
+    gauge: Gauge,
+}
+
+impl Scheduler {
+    pub(crate) fn new(workers: usize, metrics: &Metrics) -> Self {
+        let gauge = metrics.gauge(
+            "status_queue_depth",
+            "Number of queued status builds",
+            "priority",
+        );
+        Self {
+            workers: workers.max(1),
+            state: Mutex::new(State::default()),
+            condvar: Condvar::new(),
+            gauge,
+        }
+    }
+
+    /// Number of builds not started yet (per tier)
+    pub(crate) fn queued(&self, priority: Priority) -> usize {
+        let _order = lock_order::acquire(Level::Scheduler);
+        self.state.lock().queued[priority.index()]
+    }
+
+    /// Run a single build (on the current thread), after waiting for its turn.
+    /// Fails if `cancel` is set before the build starts.
+    pub(crate) fn run<T>(
+        &self,
+        priority: Priority,
+        cancel: &CancelToken,
+        build: impl FnOnce() -> T,
+    ) -> Result<T> {
+        self.enqueue(priority, 1);
+        self.start(priority, cancel, build)
+    }
+
+    /// Run the builds (in parallel), queueing all of them before the first one starts.
+    /// If `cancel` is set, the builds not started yet are skipped (without waiting for their
+    /// turn), but the running ones are completed, since they may fill shared caches.
+    pub(crate) fn run_all<I, T, F>(
+        &self,
+        priority: Priority,
+        items: Vec<I>,
+        cancel: &CancelToken,
+        build: F,
+    ) -> Result<Vec<T>>
+    where
+        I: Send,
+        T: Send,
+        F: Fn(I) -> T + Sync,
+    {
+        self.enqueue(priority, items.len());
+        // every item is dequeued (even after a cancellation), before failing
+        let results: Vec<Result<T>> = items
+            .into_par_iter()
+            .map(|item| self.start(priority, cancel, || build(item)))
+            .collect();
+        results.into_iter().collect()
+    }
+
+    fn enqueue(&self, priority: Priority, count: usize) {
+        let _order = lock_order::acquire(Level::Scheduler);
+        let mut state = self.state.lock();
+        state.queued[priority.index()] += count;
+        self.update_gauge(&state, priority);
+    }
+
+    fn start<T>(
+        &self,
+        priority: Priority,
+        cancel: &CancelToken,
+        build: impl FnOnce() -> T,
+    ) -> Result<T> {
+        if HOLDS_PERMIT.with(|holds| holds.get()) || cancel.is_cancelled() {
+            self.dequeue(priority); // no need to wait for a permit
+            cancel.check()?;
+            return Ok(build());
+        }
+        let _permit = self.acquire(priority);
+        cancel.check()?; // cancelled while waiting (the permit is released right away)
+        Ok(build())
+    }
+
+    fn acquire(&self, priority: Priority) -> Permit<'_> {
+        let _order = lock_order::acquire(Level::Scheduler);
+        let mut state = self.state.lock();
+        state.waiting[priority.index()] += 1;
+        while !state.can_start(priority, self.workers) {
+            self.condvar.wait(&mut state);
+        }
+        state.waiting[priority.index()] -= 1;
+        state.running += 1;
+        match priority {
+            Priority::Interactive if state.waiting[Priority::Bulk.index()] > 0 => {
+                state.low_skips += 1
+            }
+            Priority::Interactive => (),
+            Priority::Bulk => state.low_skips = 0,
+        }
+        state.queued[priority.index()] -= 1;
+        self.update_gauge(&state, priority);
+        HOLDS_PERMIT.with(|holds| holds.set(true));
+        Permit { scheduler: self }
+    }
+
+    fn dequeue(&self, priority: Priority) {
+        let _order = lock_order::acquire(Level::Scheduler);
+        let mut state = self.state.lock();
+        state.queued[priority.index()] -= 1;
+        self.update_gauge(&state, priority);
+    }
+
+    fn update_gauge(&self, state: &State, priority: Priority) {
+        let queued = state.queued[priority.index()];
+        self.gauge.set(priority.label(), queued as f64);
+    }
+}
+
+/// Released when the build is done (even if it panics)
+struct Permit<'a> {
+    scheduler: &'a Scheduler,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        HOLDS_PERMIT.with(|holds| holds.set(false));
+        let _order = lock_order::acquire(Level::Scheduler);
+        self.scheduler.state.lock().running -= 1;
+        self.scheduler.condvar.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Priority, Scheduler, MAX_LOW_PRIORITY_SKIPS};
+    use crate::{cancel::CancelToken, metrics::Metrics};
+    use crossbeam_channel::unbounded;
+
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    fn wait_until(condition: impl Fn() -> bool) {
+        let start = Instant::now();
+        while !condition() {
+            assert!(start.elapsed() < Duration::from_secs(10), "timed out");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_interactive_preempts_bulk() {
+        let scheduler = Arc::new(Scheduler::new(2, &Metrics::dummy()));
+        let (started_tx, started_rx) = unbounded();
+        let (release_tx, release_rx) = unbounded::<()>();
+        let bulk = {
+            let scheduler = Arc::clone(&scheduler);
+            thread::spawn(move || {
+                let pool = rayon::ThreadPoolBuilder::new()
+                    .num_threads(4)
+                    .build()
+                    .unwrap();
+                pool.install(|| {
+                    let items: Vec<usize> = (0..100).collect();
+                    let cancel = CancelToken::default();
+                    scheduler.run_all(Priority::Bulk, items, &cancel, |_| {
+                        started_tx.send(()).unwrap();
+                        let _ = release_rx.recv(); // a single token, or until all are released
+                    })
+                })
+            })
+        };
+        // both permits are used by bulk builds
+        started_rx.recv().unwrap();
+        started_rx.recv().unwrap();
+
+        let interactive = {
+            let scheduler = Arc::clone(&scheduler);
+            thread::spawn(move || {
+                let cancel = CancelToken::default();
+                scheduler.run(Priority::Interactive, &cancel, || {
+                    scheduler.queued(Priority::Bulk)
+                })
+            })
+        };
+        wait_until(|| scheduler.state.lock().waiting[Priority::Interactive.index()] == 1);
+        // the first permit to be released goes to the interactive build
+        release_tx.send(()).unwrap();
+        let queued = interactive.join().unwrap().unwrap();
+        assert_eq!(queued, 98); // only waited for a running bulk build (not the queued ones)
+        assert_eq!(scheduler.queued(Priority::Interactive), 0);
+
+        drop(release_tx);
+        assert_eq!(bulk.join().unwrap().unwrap().len(), 100);
+        assert_eq!(scheduler.queued(Priority::Bulk), 0);
+    }
+
+    #[test]
+    fn test_bulk_not_starved() {
+        let scheduler = Scheduler::new(1, &Metrics::dummy());
+        let mut state = scheduler.state.lock();
+        state.waiting = [1, 1];
+        assert!(state.can_start(Priority::Interactive, 1));
+        assert!(!state.can_start(Priority::Bulk, 1));
+
+        state.low_skips = MAX_LOW_PRIORITY_SKIPS;
+        assert!(!state.can_start(Priority::Interactive, 1));
+        assert!(state.can_start(Priority::Bulk, 1));
+
+        state.running = 1;
+        assert!(!state.can_start(Priority::Bulk, 1));
+    }
+
+    #[test]
+    fn test_nested() {
+        let scheduler = Scheduler::new(1, &Metrics::dummy());
+        let cancel = CancelToken::default();
+        let results = scheduler.run_all(Priority::Bulk, vec![1, 2, 3], &cancel, |n| {
+            // nested builds reuse the permit (instead of waiting for it forever)
+            scheduler
+                .run(Priority::Interactive, &cancel, || n * 10)
+                .unwrap()
+        });
+        assert_eq!(results.unwrap(), vec![10, 20, 30]);
+        assert_eq!(scheduler.queued(Priority::Bulk), 0);
+        assert_eq!(scheduler.queued(Priority::Interactive), 0);
+    }
+
+    #[test]
+    fn test_cancelled_batch() {
+        let scheduler = Arc::new(Scheduler::new(2, &Metrics::dummy()));
+        let cancel = CancelToken::default();
+        let done = Arc::new(AtomicUsize::new(0));
+        let (started_tx, started_rx) = unbounded();
+        let (release_tx, release_rx) = unbounded::<()>();
+        let batch = {
+            let scheduler = Arc::clone(&scheduler);
+            let cancel = cancel.clone();
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                let pool = rayon::ThreadPoolBuilder::new()
+                    .num_threads(4)
+                    .build()
+                    .unwrap();
+                pool.install(|| {
+                    let items: Vec<usize> = (0..100).collect();
+                    scheduler.run_all(Priority::Bulk, items, &cancel, |_| {
+                        started_tx.send(()).unwrap();
+                        let _ = release_rx.recv(); // until all are released
+                        done.fetch_add(1, Ordering::SeqCst);
+                    })
+                })
+            })
+        };
+        started_rx.recv().unwrap();
+        started_rx.recv().unwrap();
+
+        // the client disconnects mid-batch
+        cancel.cancel();
+        drop(release_tx);
+        assert!(batch.join().unwrap().is_err());
+        // only the running builds were completed
+        assert_eq!(done.load(Ordering::SeqCst), 2);
+        assert_eq!(scheduler.queued(Priority::Bulk), 0);
+
+        // the permits are released, so other builds don't wait
+        let result = scheduler.run(Priority::Bulk, &CancelToken::default(), || 42);
+        assert_eq!(result.unwrap(), 42);
+    }
+}
@@ -1,54 +1,222 @@
 use anyhow::{Context, Result};
-use crossbeam_channel::{select, unbounded, Sender};
+use bitcoin::BlockHash;
+use crossbeam_channel::{bounded, unbounded, Receiver, Select, Sender, TrySendError};
+use parking_lot::Mutex;
 use rayon::prelude::*;
+use serde_json::{json, Value};
 
 use std::{
     collections::hash_map::HashMap,
-    io::{BufRead, BufReader, Write},
+    io::{BufReader, Write},
     iter::once,
-    net::{Shutdown, TcpListener, TcpStream},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use crate::{
+    audit::AuditLog,
+    cancel::CancelToken,
+    codec::{Codec, Frame},
     config::Config,
-    electrum::{Client, Rpc},
+    electrum::{self, Client, HealthReport, Rpc},
+    listener::{self, ListenerOptions},
     metrics::{self, Metrics},
-    signals::ExitError,
+    mirrors::Mirrors,
+    reputation::{Reputation, Violation},
+    signals::{ExitError, ExitFlag, Signal},
     thread::spawn,
+    webhooks::Webhooks,
 };
 
+/// Maximum number of message batches (responses or notifications) pending to be sent to a peer
+const MAX_PENDING_BATCHES: usize = 100;
+
 struct Peer {
     id: usize,
     client: Client,
     stream: TcpStream,
+    pending: Sender<Batch>, // sent (in order) by the peer's `send_loop` thread
+    codec: Codec,           // of the sent messages
+    codec_tx: Sender<Codec>, // for reading the following requests (by `recv_loop`)
+    cancel: CancelToken,    // set when the peer is removed, or when sending fails
+}
+
+/// A new peer's connection
+struct Connection {
+    stream: TcpStream,
+    peer_addr: Option<SocketAddr>, // the client's address (as sent by the proxy, if used)
+    hostname: Option<String>,      // requested by the client (sent only by the proxy, if used)
+    admin: bool,                   // accepted on the admin RPC address
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> Self {
+        let peer_addr = stream.peer_addr().ok();
+        Self {
+            stream,
+            peer_addr,
+            hostname: None,
+            admin: false,
+        }
+    }
+}
+
+/// Where a listener's connections are served
+#[derive(Clone, Copy, Default)]
+struct Endpoint {
+    network: usize, // index into the served networks
+    admin: bool,    // allowed to call the admin methods (see `--electrum-admin-addr`)
+}
+
+struct Batch {
+    frames: Vec<Vec<u8>>,
+    notified: Option<Arc<TipNotified>>, // set if the batch notifies a new tip
 }
 
 impl Peer {
-    fn new(id: usize, stream: TcpStream) -> Self {
-        let client = Client::default();
-        Self { id, client, stream }
+    fn new(
+        id: usize,
+        conn: Connection,
+        codec_tx: Sender<Codec>,
+        cancel: CancelToken,
+    ) -> Result<Self> {
+        let stream = conn.stream;
+        let client = Client::new(conn.peer_addr, conn.hostname)
+            .with_cancel(cancel.clone())
+            .with_admin(conn.admin);
+        let (pending, rx) = bounded(MAX_PENDING_BATCHES);
+        let writer = stream.try_clone().context("failed to clone TCP stream")?;
+        let send_cancel = cancel.clone();
+        spawn("send_loop", move || send_loop(id, writer, rx, send_cancel));
+        Ok(Self {
+            id,
+            client,
+            stream,
+            pending,
+            codec: Codec::Json,
+            codec_tx,
+            cancel,
+        })
     }
 
-    fn send(&mut self, values: Vec<String>) -> Result<()> {
-        for mut value in values {
-            debug!("{}: send {}", self.id, value);
-            value += "\n";
-            self.stream
-                .write_all(value.as_bytes())
-                .with_context(|| format!("failed to send response: {:?}", value))?;
+    /// Queue the messages for sending, so a slow peer doesn't block the server.
+    fn send(&mut self, values: Vec<Value>) -> Result<()> {
+        self.send_batch(values, None)
+    }
+
+    /// Queue the messages for sending, marking `notified` as written after they are sent.
+    fn send_batch(&mut self, values: Vec<Value>, notified: Option<Arc<TipNotified>>) -> Result<()> {
+        if values.is_empty() {
+            return Ok(());
+        }
+        let codec = self.codec;
+        let frames = values
+            .iter()
+            .map(|value| {
+                debug!("{}: send {}", self.id, value);
+                codec.encode(value)
+            })
+            .collect();
+        match self.pending.try_send(Batch { frames, notified }) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => bail!(
+                "peer is too slow ({} pending message batches)",
+                MAX_PENDING_BATCHES
+            ),
+            Err(TrySendError::Disconnected(_)) => bail!("failed to send messages"),
         }
-        Ok(())
+    }
+
+    /// Apply the codec negotiated by the last request (to the following messages), and let
+    /// `recv_loop` resume reading the peer's requests.
+    fn switch_codec(&mut self) -> Result<()> {
+        self.codec = self.client.codec();
+        self.codec_tx
+            .send(self.codec)
+            .context("failed to switch codec")
+    }
+
+    /// Whether the peer doesn't read its messages fast enough.
+    fn is_slow(&self) -> bool {
+        self.pending.is_full()
     }
 
     fn disconnect(self) {
-        if let Err(e) = self.stream.shutdown(Shutdown::Both) {
-            warn!("{}: failed to shutdown TCP connection {}", self.id, e)
+        disconnect(self.id, &self.stream)
+    }
+}
+
+impl Drop for Peer {
+    fn drop(&mut self) {
+        // abandon the peer's long-running requests (if any), since it was removed
+        self.cancel.cancel();
+    }
+}
+
+fn disconnect(peer_id: usize, stream: &TcpStream) {
+    if let Err(e) = stream.shutdown(Shutdown::Both) {
+        warn!("{}: failed to shutdown TCP connection {}", peer_id, e)
+    }
+}
+
+/// Send the peer's pending messages, until it's dropped (or the connection fails).
+fn send_loop(
+    peer_id: usize,
+    mut stream: TcpStream,
+    rx: Receiver<Batch>,
+    cancel: CancelToken,
+) -> Result<()> {
+    for batch in rx {
+        for frame in batch.frames {
+            if let Err(e) = stream.write_all(&frame) {
+                cancel.cancel(); // nobody will read the responses of the peer's running requests
+                disconnect(peer_id, &stream); // stop receiving the peer's requests
+                return Err(e)
+                    .with_context(|| format!("failed to send response ({} bytes)", frame.len()));
+            }
+        }
+        if let Some(notified) = batch.notified {
+            notified.written();
+        }
+    }
+    Ok(())
+}
+
+/// The served networks' latest health reports (see `Rpc::health()`), for embedders' readiness
+/// checks. Its clones share the same reports.
+#[derive(Clone, Default)]
+pub struct HealthMonitor {
+    reports: Arc<Mutex<Vec<HealthReport>>>,
+}
+
+impl HealthMonitor {
+    /// One report per served network (in configuration order), refreshed after each sync.
+    /// Empty until the index is opened.
+    pub fn reports(&self) -> Vec<HealthReport> {
+        self.reports.lock().clone()
+    }
+
+    fn update(&self, network: usize, report: HealthReport) {
+        let mut reports = self.reports.lock();
+        if network < reports.len() {
+            reports[network] = report;
+        } else {
+            reports.push(report); // networks are synced in order
         }
     }
 }
 
 pub fn run() -> Result<()> {
-    let result = serve();
+    run_monitored(HealthMonitor::default())
+}
+
+/// Run the server (as `run()`), updating the given monitor's health reports.
+pub fn run_monitored(monitor: HealthMonitor) -> Result<()> {
+    let result = serve(&monitor);
     if let Err(e) = &result {
         for cause in e.chain() {
             if cause.downcast_ref::<ExitError>().is_some() {
@@ -60,15 +228,267 @@ pub fn run() -> Result<()> {
     result.context("electrs failed")
 }
 
-fn serve() -> Result<()> {
+/// Measures the latency of new block notifications, starting from bitcoind's new block signal
+struct BlockLatency {
+    received: Option<Instant>, // when the last new block signal was received
+    latency: metrics::Histogram,
+}
+
+impl BlockLatency {
+    fn new(metrics: &Metrics) -> Self {
+        let latency = metrics.histogram_vec(
+            "block_notification_latency",
+            "Latency of new block notifications (in seconds)",
+            "step",
+            metrics::default_duration_buckets(),
+        );
+        Self {
+            received: None,
+            latency,
+        }
+    }
+
+    /// Returns the new tip's tracker, to be sent with its `blockchain.headers.subscribe`
+    /// notifications (unless the new block wasn't signalled, e.g. found by polling).
+    fn indexed(&mut self, tip: BlockHash) -> Option<Arc<TipNotified>> {
+        let received = self.received.take()?;
+        let indexed = Instant::now();
+        let index_secs = indexed.saturating_duration_since(received).as_secs_f64();
+        self.latency.observe("index", index_secs);
+        Some(Arc::new(TipNotified {
+            tip,
+            received,
+            indexed,
+            written: Mutex::new(None),
+            latency: self.latency.clone(),
+        }))
+    }
+}
+
+/// Shared by the batches notifying a new tip. The latency is observed when it's dropped, i.e. after
+/// all the batches were written to the peers' sockets (or dropped, if their peers disconnected).
+struct TipNotified {
+    tip: BlockHash,
+    received: Instant,
+    indexed: Instant,
+    written: Mutex<Option<Instant>>, // when the last notification was written to a peer's socket
+    latency: metrics::Histogram,
+}
+
+impl TipNotified {
+    fn written(&self) {
+        *self.written.lock() = Some(Instant::now());
+    }
+
+    fn last_write(&self) -> Option<Instant> {
+        *self.written.lock()
+    }
+}
+
+impl Drop for TipNotified {
+    fn drop(&mut self) {
+        let (received, indexed) = (self.received, self.indexed);
+        let index_secs = indexed.saturating_duration_since(received).as_secs_f64();
+        match self.last_write() {
+            Some(written) => {
+                let notify_secs = written.saturating_duration_since(indexed).as_secs_f64();
+                let total_secs = written.saturating_duration_since(received).as_secs_f64();
+                self.latency.observe("notify", notify_secs);
+                self.latency.observe("total", total_secs);
+                info!(
+                    "block {} latency: indexed after {:.3}s, notified after {:.3}s (total {:.3}s)",
+                    self.tip, index_secs, notify_secs, total_secs
+                );
+            }
+            None => info!(
+                "block {} latency: indexed after {:.3}s, no peers subscribed to headers",
+                self.tip, index_secs
+            ),
+        }
+    }
+}
+
+/// Number of clients whose subscriptions' memory usage is exported
+const TOP_CLIENTS: usize = 5;
+
+/// Electrum RPC server of a single network (with its own index, daemon and peers)
+struct NetworkServer {
+    rpc: Rpc,
+    peers: HashMap<usize, Peer>,
+    new_block_rx: Receiver<()>,
+    block_latency: BlockLatency,
+    client_memory: metrics::Gauge,
+    client_subscriptions: metrics::Gauge,
+}
+
+impl NetworkServer {
+    fn new(
+        config: &Config,
+        metrics: Metrics,
+        exit_flag: &ExitFlag,
+        audit_log: AuditLog,
+        reputation: Reputation,
+        webhooks: Webhooks,
+        mirrors: Mirrors,
+    ) -> Result<Self> {
+        let block_latency = BlockLatency::new(&metrics);
+        let client_memory = metrics.gauge(
+            "client_memory_usage",
+            "Approximate memory used by the largest clients' subscriptions (in bytes)",
+            "rank",
+        );
+        let client_subscriptions = metrics.gauge(
+            "client_subscriptions",
+            "# of scripthashes subscribed by the largest clients",
+            "rank",
+        );
+        let rpc = Rpc::new(
+            config,
+            metrics,
+            exit_flag.clone(),
+            audit_log,
+            reputation,
+            webhooks,
+            mirrors,
+        )?;
+        let new_block_rx = rpc.new_block_notification();
+        Ok(Self {
+            rpc,
+            peers: HashMap::new(),
+            new_block_rx,
+            block_latency,
+            client_memory,
+            client_subscriptions,
+        })
+    }
+
+    /// The earliest time when a peer's debounced notifications should be flushed
+    fn debounce_deadline(&self) -> Option<Instant> {
+        self.peers
+            .values()
+            .filter_map(|peer| peer.client.debounce_deadline())
+            .min()
+    }
+
+    /// Send the peers' debounced notifications right away (e.g. before shutdown).
+    fn flush_notifications(&mut self) {
+        for peer in self.peers.values_mut() {
+            let notifications = peer.client.flush_notifications();
+            if let Err(e) = peer.send(notifications) {
+                warn!("{}: failed to flush notifications: {}", peer.id, e);
+            }
+        }
+    }
+
+    fn observe_clients(&self) {
+        observe_top_clients(&self.client_memory, &self.peers, Client::memory_usage);
+        observe_top_clients(
+            &self.client_subscriptions,
+            &self.peers,
+            Client::subscriptions,
+        );
+    }
+
+    fn sync(&mut self, duration: &metrics::Histogram) -> Result<bool> {
+        let prev_tip = self.rpc.tip();
+        let rpc = &mut self.rpc;
+        // sync a batch of blocks
+        let done = duration.observe_duration("sync", || rpc.sync().context("sync failed"))?;
+        let tip = self.rpc.tip();
+        let notified = if tip != prev_tip {
+            self.block_latency.indexed(tip)
+        } else {
+            None
+        };
+        let peers = std::mem::take(&mut self.peers);
+        self.peers = duration.observe_duration("notify", || {
+            notify_peers(&self.rpc, peers, notified.as_ref()) // peers are disconnected on error
+        });
+        self.observe_clients();
+        if let Err(e) = self.rpc.update_webhooks() {
+            warn!("failed to notify webhooks: {:#}", e);
+        }
+        Ok(done) // the latency is observed after the notifications are written
+    }
+}
+
+/// Set the gauge to the largest clients' values (by their rank).
+fn observe_top_clients(
+    gauge: &metrics::Gauge,
+    peers: &HashMap<usize, Peer>,
+    f: fn(&Client) -> usize,
+) {
+    let values = peers.values().map(|peer| f(&peer.client)).collect();
+    for (rank, value) in top_values(values).into_iter().enumerate() {
+        gauge.set(&(rank + 1).to_string(), value as f64);
+    }
+}
+
+/// The `TOP_CLIENTS` largest values (in descending order), padded with zeros, so the ranks of
+/// disconnected clients are reset.
+fn top_values(mut values: Vec<usize>) -> Vec<usize> {
+    values.sort_unstable_by(|a, b| b.cmp(a));
+    values.resize(TOP_CLIENTS, 0);
+    values
+}
+
+enum Wakeup {
+    Signal,
+    Events(Vec<Event>),
+    NewBlock(usize),
+    Disconnected,
+    Timeout,
+}
+
+fn serve(monitor: &HealthMonitor) -> Result<()> {
     let config = Config::from_args();
-    let metrics = Metrics::new(config.monitoring_addr)?;
+    let metrics = Metrics::new(&config.monitoring)?;
+    let signal = Signal::new();
+    let audit_log = AuditLog::open(config.audit_log.as_ref())?;
+    let reputation = Reputation::open(config.peer_reputation.as_ref(), &metrics)?;
+    let webhooks = Webhooks::open(config.webhooks.as_ref())?;
+    let mirrors = Mirrors::open(config.broadcast_mirrors.as_ref(), &metrics)?;
 
+    let configs: Vec<&Config> = once(&config).chain(&config.extra_networks).collect();
     let (server_tx, server_rx) = unbounded();
     if !config.disable_electrum_rpc {
-        let listener = TcpListener::bind(config.electrum_rpc_addr)?;
-        info!("serving Electrum RPC on {}", listener.local_addr()?);
-        spawn("accept_loop", || accept_loop(listener, server_tx)); // detach accepting thread
+        let options = &config.electrum_listener;
+        info!(
+            "Electrum RPC listener options: dual_stack={}, reuse_address={}, reuse_port={}, keepalive={:?}, proxy_protocol={}",
+            options.dual_stack, options.reuse_address, options.reuse_port, options.keepalive, options.proxy_protocol
+        );
+        let next_peer_id = Arc::new(AtomicUsize::new(0));
+        for (network, c) in configs.iter().enumerate() {
+            let addrs = once((c.electrum_rpc_addr, false))
+                .chain(c.electrum_admin_addr.map(|addr| (addr, true)));
+            for (addr, admin) in addrs {
+                for listener in listener::bind(addr, options)? {
+                    info!(
+                        "serving {}Electrum RPC for {} on {}",
+                        if admin { "admin " } else { "" },
+                        c.network,
+                        listener.local_addr()?
+                    );
+                    let endpoint = Endpoint { network, admin };
+                    let tx = server_tx.clone();
+                    let next_peer_id = Arc::clone(&next_peer_id);
+                    let options = options.clone();
+                    let reputation = reputation.clone();
+                    let size_limit = c.request_size_limit;
+                    spawn("accept_loop", move || {
+                        accept_loop(
+                            listener,
+                            endpoint,
+                            tx,
+                            next_peer_id,
+                            options,
+                            reputation,
+                            size_limit,
+                        )
+                    }); // detach accepting thread
+                }
+            }
+        }
     };
 
     let server_batch_size = metrics.histogram_vec(
@@ -83,15 +503,42 @@ fn serve() -> Result<()> {
         "step",
         metrics::default_duration_buckets(),
     );
-    let mut rpc = Rpc::new(&config, metrics)?;
+    let mut networks: Vec<NetworkServer> = if configs.len() == 1 {
+        vec![NetworkServer::new(
+            &config,
+            metrics,
+            signal.exit_flag(),
+            audit_log,
+            reputation,
+            webhooks,
+            mirrors,
+        )?]
+    } else {
+        // label each network's metrics, to tell them apart
+        configs
+            .iter()
+            .map(|c| {
+                let network = c.network.to_string();
+                let network_metrics = metrics.with_network(&network)?;
+                let network_audit_log = audit_log.with_network(&network);
+                let network_webhooks = webhooks.with_network(&network);
+                NetworkServer::new(
+                    c,
+                    network_metrics,
+                    signal.exit_flag(),
+                    network_audit_log,
+                    reputation.clone(), // shared by all networks
+                    network_webhooks,
+                    mirrors.clone(), // filtered by each network's `Rpc`
+                )
+            })
+            .collect::<Result<_>>()?
+    };
 
-    let new_block_rx = rpc.new_block_notification();
-    let mut peers = HashMap::<usize, Peer>::new();
     loop {
         // initial sync and compaction may take a few hours
         while server_rx.is_empty() {
-            let done = duration.observe_duration("sync", || rpc.sync().context("sync failed"))?; // sync a batch of blocks
-            peers = duration.observe_duration("notify", || notify_peers(&rpc, peers)); // peers are disconnected on error
+            let done = sync_networks(&mut networks, &duration, monitor)?;
             if !done {
                 continue; // more blocks to sync
             }
@@ -100,150 +547,797 @@ fn serve() -> Result<()> {
             }
             break;
         }
-        duration.observe_duration("select", || -> Result<()> {
-            select! {
-                // Handle signals for graceful shutdown
-                recv(rpc.signal().receiver()) -> result => {
-                    result.context("signal channel disconnected")?;
-                    rpc.signal().exit_flag().poll().context("RPC server interrupted")?;
-                },
-                // Handle new blocks' notifications
-                recv(new_block_rx) -> result => match result {
-                    Ok(_) => (), // sync and update
-                    Err(_) => {
-                        info!("disconnected from bitcoind");
-                        return Ok(());
-                    }
-                },
-                // Handle Electrum RPC requests
-                recv(server_rx) -> event => {
-                    let first = once(event.context("server disconnected")?);
-                    let rest = server_rx.iter().take(server_rx.len());
-                    let events: Vec<Event> = first.chain(rest).collect();
-                    server_batch_size.observe("recv", events.len() as f64);
-                    duration.observe_duration("handle", || handle_events(&rpc, &mut peers, events));
-                },
-                default(config.wait_duration) => (), // sync and update
+        let wakeup = duration.observe_duration("select", || -> Result<Wakeup> {
+            let mut select = Select::new();
+            let signal_index = select.recv(signal.receiver());
+            let server_index = select.recv(&server_rx);
+            let new_block_indices: Vec<usize> = networks
+                .iter()
+                .map(|network| select.recv(&network.new_block_rx))
+                .collect();
+            // wake up to flush debounced notifications (by syncing)
+            let timeout = networks
+                .iter()
+                .filter_map(NetworkServer::debounce_deadline)
+                .min()
+                .map_or(config.wait_duration, |deadline| {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    std::cmp::min(remaining, config.wait_duration)
+                });
+            let oper = match select.select_timeout(timeout) {
+                Ok(oper) => oper,
+                Err(_) => return Ok(Wakeup::Timeout), // sync and update
             };
-            Ok(())
+            let index = oper.index();
+            // Handle signals for graceful shutdown
+            if index == signal_index {
+                oper.recv(signal.receiver())
+                    .context("signal channel disconnected")?;
+                return Ok(Wakeup::Signal);
+            }
+            // Handle Electrum RPC requests
+            if index == server_index {
+                let first = once(oper.recv(&server_rx).context("server disconnected")?);
+                let rest = server_rx.iter().take(server_rx.len());
+                return Ok(Wakeup::Events(first.chain(rest).collect()));
+            }
+            // Handle new blocks' notifications
+            let i = new_block_indices
+                .iter()
+                .position(|&new_block_index| new_block_index == index)
+                .expect("unexpected select index");
+            Ok(match oper.recv(&networks[i].new_block_rx) {
+                Ok(_) => Wakeup::NewBlock(i), // sync and update
+                Err(_) => Wakeup::Disconnected,
+            })
         })?;
+        match wakeup {
+            Wakeup::Signal => {
+                let result = signal.exit_flag().poll().context("RPC server interrupted");
+                if result.is_err() {
+                    networks
+                        .iter_mut()
+                        .for_each(NetworkServer::flush_notifications);
+                }
+                result?
+            }
+            Wakeup::Events(events) => {
+                server_batch_size.observe("recv", events.len() as f64);
+                duration.observe_duration("handle", || handle_events(&mut networks, events));
+            }
+            Wakeup::Disconnected => {
+                info!("disconnected from bitcoind");
+                return Ok(());
+            }
+            Wakeup::NewBlock(i) => {
+                let received = &mut networks[i].block_latency.received;
+                received.get_or_insert_with(Instant::now); // keep the earliest signal
+            }
+            Wakeup::Timeout => (),
+        }
     }
 }
 
-fn notify_peers(rpc: &Rpc, peers: HashMap<usize, Peer>) -> HashMap<usize, Peer> {
+/// Sync the networks concurrently, so a network's (initial) sync doesn't delay the others'.
+/// Returns whether all of them are done.
+fn sync_networks(
+    networks: &mut [NetworkServer],
+    duration: &metrics::Histogram,
+    monitor: &HealthMonitor,
+) -> Result<bool> {
+    let results: Vec<Result<bool>> = networks
+        .par_iter_mut()
+        .map(|network| network.sync(duration))
+        .collect();
+    let mut done = true;
+    for (i, (network, result)) in networks.iter().zip(results).enumerate() {
+        monitor.update(i, network.rpc.health());
+        done &= result?;
+    }
+    Ok(done)
+}
+
+/// Returns the remaining peers. The notifications are generated (in parallel) against the current
+/// chain, so they are consistent and ordered per peer, but slow peers' sending doesn't delay the
+/// next sync.
+fn notify_peers(
+    rpc: &Rpc,
+    peers: HashMap<usize, Peer>,
+    notified: Option<&Arc<TipNotified>>,
+) -> HashMap<usize, Peer> {
     peers
         .into_par_iter()
-        .filter_map(|(_, mut peer)| match notify_peer(rpc, &mut peer) {
-            Ok(()) => Some((peer.id, peer)),
-            Err(e) => {
-                error!("failed to notify peer {}: {}", peer.id, e);
+        .filter_map(|(id, mut peer)| {
+            if let Err(e) = notify_peer(rpc, &mut peer, notified) {
+                error!("failed to notify peer {}: {}", id, e);
                 peer.disconnect();
-                None
+                return None;
             }
+            Some((id, peer))
         })
         .collect()
 }
 
-fn notify_peer(rpc: &Rpc, peer: &mut Peer) -> Result<()> {
+/// `notified` tracks the writing of the new tip's notification (if the peer is subscribed).
+fn notify_peer(rpc: &Rpc, peer: &mut Peer, notified: Option<&Arc<TipNotified>>) -> Result<()> {
+    let new_tip = match peer.client.tip() {
+        Some(tip) => tip != rpc.tip(),
+        None => false, // not subscribed to headers
+    };
     let notifications = rpc
         .update_client(&mut peer.client)
         .context("failed to generate notifications")?;
-    peer.send(notifications)
+    let notified = if new_tip { notified.cloned() } else { None };
+    peer.send_batch(notifications, notified)
         .context("failed to send notifications")
 }
 
 struct Event {
+    network: usize, // index into the served networks
     peer_id: usize,
     msg: Message,
 }
 
 enum Message {
-    New(TcpStream),
-    Request(String),
+    New(Connection, Sender<Codec>, CancelToken),
+    Request(Frame),
     Done,
 }
 
-fn handle_events(rpc: &Rpc, peers: &mut HashMap<usize, Peer>, events: Vec<Event>) {
-    let mut events_by_peer = HashMap::<usize, Vec<Message>>::new();
-    events
+fn handle_events(networks: &mut [NetworkServer], events: Vec<Event>) {
+    let mut events_by_peer = HashMap::<(usize, usize), Vec<Message>>::new();
+    events.into_iter().for_each(|e| {
+        events_by_peer
+            .entry((e.network, e.peer_id))
+            .or_default()
+            .push(e.msg)
+    });
+    // the peers are taken out of their maps while being handled (and put back if still connected)
+    let tasks: Vec<(usize, usize, Option<Peer>, Vec<Message>)> = events_by_peer
         .into_iter()
-        .for_each(|e| events_by_peer.entry(e.peer_id).or_default().push(e.msg));
-    for (peer_id, messages) in events_by_peer {
-        handle_peer_events(rpc, peers, peer_id, messages);
+        .map(|((network, peer_id), messages)| {
+            let peer = networks[network].peers.remove(&peer_id);
+            (network, peer_id, peer, messages)
+        })
+        .collect();
+    let rpcs: Vec<&Rpc> = networks.iter().map(|network| &network.rpc).collect();
+    let peers = handle_concurrently(tasks, |(network, peer_id, peer, messages)| {
+        let peer = handle_peer_events(rpcs[network], peer, peer_id, messages)?;
+        Some((network, peer))
+    });
+    for (network, peer) in peers {
+        networks[network].peers.insert(peer.id, peer);
     }
 }
 
+/// Each peer's messages are handled in order, but different peers are handled in parallel, so a
+/// peer's interactive requests don't wait for another peer's bulk subscriptions (see `Scheduler`).
+fn handle_concurrently<T: Send, R: Send>(
+    tasks: Vec<T>,
+    handle: impl Fn(T) -> Option<R> + Sync + Send,
+) -> Vec<R> {
+    tasks.into_par_iter().filter_map(handle).collect()
+}
+
+/// Returns the peer, unless it was disconnected.
 fn handle_peer_events(
     rpc: &Rpc,
-    peers: &mut HashMap<usize, Peer>,
+    mut peer: Option<Peer>,
     peer_id: usize,
     messages: Vec<Message>,
-) {
-    let mut lines = vec![];
+) -> Option<Peer> {
+    let mut frames = vec![];
     let mut done = false;
     for msg in messages {
         match msg {
-            Message::New(stream) => {
-                debug!("{}: connected", peer_id);
-                peers.insert(peer_id, Peer::new(peer_id, stream));
+            Message::New(conn, codec_tx, cancel) => {
+                if !rpc.accepts_connections() {
+                    debug!("{}: refused until the initial sync is done", peer_id);
+                    disconnect(peer_id, &conn.stream);
+                    return None;
+                }
+                match Peer::new(peer_id, conn, codec_tx, cancel) {
+                    Ok(new_peer) => {
+                        debug!("{}: connected", peer_id);
+                        peer = Some(new_peer);
+                    }
+                    Err(e) => {
+                        warn!("{}: failed to connect: {:#}", peer_id, e);
+                        return None;
+                    }
+                }
             }
-            Message::Request(line) => lines.push(line),
+            Message::Request(frame) => frames.push(frame),
             Message::Done => {
                 done = true;
                 break;
             }
         }
     }
-    let result = match peers.get_mut(&peer_id) {
-        Some(peer) => {
-            let responses = rpc.handle_requests(&mut peer.client, &lines);
-            peer.send(responses)
-        }
-        None => return, // unknown peer
-    };
+    let mut peer = peer?; // unknown peer
+
+    // `recv_loop` waits for the codec switch's result (before reading more requests)
+    let switching = frames
+        .iter()
+        .any(|frame| frame.as_json().map_or(false, electrum::is_codec_request));
+    let responses = rpc.handle_requests(&mut peer.client, &frames);
+    let result = peer
+        .send(responses)
+        .and_then(|()| {
+            if switching {
+                peer.switch_codec()
+            } else {
+                Ok(())
+            }
+        })
+        .and_then(|()| rpc.reputation().check(peer.client.peer_addr()));
     if let Err(e) = result {
         error!("{}: disconnecting due to {}", peer_id, e);
-        peers.remove(&peer_id).unwrap().disconnect();
+        if peer.is_slow() {
+            rpc.reputation()
+                .record(peer.client.peer_addr(), Violation::SlowPeer);
+        }
+        peer.disconnect();
+        None
     } else if done {
-        peers.remove(&peer_id); // already disconnected, just remove from peers' map
+        // already disconnected, just remove from peers' map (sending what the peer may still read)
+        let notifications = peer.client.flush_notifications();
+        if let Err(e) = peer.send(notifications) {
+            debug!("{}: failed to flush notifications: {}", peer_id, e);
+        }
+        None
+    } else {
+        Some(peer)
     }
 }
 
-fn accept_loop(listener: TcpListener, server_tx: Sender<Event>) -> Result<()> {
-    for (peer_id, conn) in listener.incoming().enumerate() {
+fn accept_loop(
+    listener: TcpListener,
+    endpoint: Endpoint,
+    server_tx: Sender<Event>,
+    next_peer_id: Arc<AtomicUsize>,
+    options: ListenerOptions,
+    reputation: Reputation,
+    size_limit: usize, // of the received requests
+) -> Result<()> {
+    for conn in listener.incoming() {
         let stream = conn.context("failed to accept")?;
+        let ban = stream
+            .peer_addr()
+            .ok()
+            .and_then(|addr| reputation.ban_remaining(addr.ip()));
+        if let Some(remaining) = ban {
+            refuse(stream, remaining);
+            continue;
+        }
+        if let Err(e) = listener::configure_stream(&stream, &options) {
+            warn!("failed to configure accepted connection: {:#}", e);
+        }
+        let peer_id = next_peer_id.fetch_add(1, Ordering::Relaxed); // unique across all networks
         let tx = server_tx.clone();
+        let proxy_protocol = options.proxy_protocol;
         spawn("recv_loop", move || {
-            let result = recv_loop(peer_id, &stream, tx);
-            if let Err(e) = stream.shutdown(Shutdown::Read) {
-                warn!("{}: failed to shutdown TCP receiving {}", peer_id, e)
-            }
-            result
+            recv_peer(endpoint, peer_id, stream, tx, size_limit, proxy_protocol)
         });
     }
     Ok(())
 }
 
-fn recv_loop(peer_id: usize, stream: &TcpStream, server_tx: Sender<Event>) -> Result<()> {
-    let msg = Message::New(stream.try_clone()?);
-    server_tx.send(Event { peer_id, msg })?;
+/// Receive the peer's requests until it stops sending them. The peer may still read the responses
+/// of its queued requests (e.g. after half-closing the connection, as done by `nc -N`), so they are
+/// cancelled only when the peer is removed, or when its responses can't be sent.
+fn recv_peer(
+    endpoint: Endpoint,
+    peer_id: usize,
+    stream: TcpStream,
+    server_tx: Sender<Event>,
+    size_limit: usize,
+    proxy_protocol: bool,
+) -> Result<()> {
+    let cancel = CancelToken::default();
+    let result = recv_loop(
+        endpoint,
+        peer_id,
+        &stream,
+        server_tx,
+        cancel,
+        size_limit,
+        proxy_protocol,
+    );
+    if let Err(e) = stream.shutdown(Shutdown::Read) {
+        warn!("{}: failed to shutdown TCP receiving {}", peer_id, e)
+    }
+    result
+}
+
+/// Reply to a banned peer with a single error line, and close the connection.
+fn refuse(mut stream: TcpStream, remaining: Duration) {
+    let msg = json!({
+        "jsonrpc": "2.0",
+        "id": null,
+        "error": {"code": 1, "message": format!("banned for {}s", remaining.as_secs())},
+    });
+    if let Err(e) = writeln!(stream, "{}", msg) {
+        debug!("failed to refuse banned peer: {}", e);
+    }
+    if let Err(e) = stream.shutdown(Shutdown::Both) {
+        debug!("failed to shutdown banned peer's connection: {}", e);
+    }
+}
+
+fn recv_loop(
+    endpoint: Endpoint,
+    peer_id: usize,
+    stream: &TcpStream,
+    server_tx: Sender<Event>,
+    cancel: CancelToken,
+    size_limit: usize,
+    proxy_protocol: bool, // the proxy sends the client's address and requested hostname
+) -> Result<()> {
+    let mut reader = BufReader::new(stream);
+    let network = endpoint.network;
+    let mut conn = Connection::new(stream.try_clone()?);
+    conn.admin = endpoint.admin;
+    if proxy_protocol {
+        let header = listener::read_proxy_header(&mut reader)
+            .with_context(|| format!("{}: invalid PROXY header", peer_id))?;
+        conn.peer_addr = header.source.or(conn.peer_addr); // the proxy's own connections
+        conn.hostname = header.authority;
+    }
+    let (codec_tx, codec_rx) = bounded(1);
+    let msg = Message::New(conn, codec_tx, cancel);
+    server_tx.send(Event {
+        network,
+        peer_id,
+        msg,
+    })?;
 
+    let mut codec = Codec::Json;
     let mut first_line = true;
-    for line in BufReader::new(stream).lines() {
-        if let Err(e) = &line {
+    loop {
+        let frame = codec.read(&mut reader, size_limit);
+        if let Err(e) = &frame {
             if first_line && e.kind() == std::io::ErrorKind::InvalidData {
                 warn!("InvalidData on first line may indicate client attempted to connect using SSL when server expects unencrypted communication.")
             }
         }
-        let line = line.with_context(|| format!("{}: recv failed", peer_id))?;
-        debug!("{}: recv {}", peer_id, line);
-        let msg = Message::Request(line);
-        server_tx.send(Event { peer_id, msg })?;
+        let frame = match frame.with_context(|| format!("{}: recv failed", peer_id))? {
+            Some(frame) => frame,
+            None => break,
+        };
+        debug!("{}: recv {}", peer_id, frame);
+        let switching = frame.as_json().map_or(false, electrum::is_codec_request);
+        let msg = Message::Request(frame);
+        server_tx.send(Event {
+            network,
+            peer_id,
+            msg,
+        })?;
+        if switching {
+            codec = codec_rx
+                .recv()
+                .with_context(|| format!("{}: codec switch failed", peer_id))?;
+        }
         first_line = false;
     }
 
     debug!("{}: disconnected", peer_id);
     let msg = Message::Done;
-    server_tx.send(Event { peer_id, msg })?;
+    server_tx.send(Event {
+        network,
+        peer_id,
+        msg,
+    })?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        handle_concurrently, recv_peer, top_values, BlockLatency, Connection, Endpoint,
+        HealthMonitor, Message, Peer, TipNotified, MAX_PENDING_BATCHES, TOP_CLIENTS,
+    };
+    use crate::{
+        cancel::CancelToken,
+        codec::Codec,
+        electrum::{HealthFlags, HealthReport},
+        metrics::Metrics,
+        scheduler::{Priority, Scheduler},
+    };
+    use bitcoin::{hashes::Hash, BlockHash};
+    use crossbeam_channel::{bounded, unbounded, Sender};
+    use serde_json::json;
+    use socket2::SockRef;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn wait_until(mut condition: impl FnMut() -> bool) {
+        let start = Instant::now();
+        while !condition() {
+            assert!(start.elapsed() < Duration::from_secs(10), "timed out");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn connect() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (server, client)
+    }
+
+    fn new_peer(
+        id: usize,
+        stream: TcpStream,
+        codec_tx: Sender<Codec>,
+        cancel: CancelToken,
+    ) -> Peer {
+        Peer::new(id, Connection::new(stream), codec_tx, cancel).unwrap()
+    }
+
+    fn new_tip(latency: &mut BlockLatency) -> Arc<TipNotified> {
+        latency.received = Some(Instant::now());
+        latency.indexed(BlockHash::all_zeros()).unwrap()
+    }
+
+    #[test]
+    fn test_top_values() {
+        assert_eq!(top_values(vec![]), vec![0; TOP_CLIENTS]);
+        assert_eq!(top_values(vec![3, 10, 1]), vec![10, 3, 1, 0, 0]);
+        let values: Vec<usize> = (1..=10).collect();
+        assert_eq!(top_values(values), vec![10, 9, 8, 7, 6]);
+    }
+
+    #[test]
+    fn test_health_monitor() {
+        let report = |height| HealthReport {
+            height,
+            tip: BlockHash::all_zeros(),
+            flags: HealthFlags {
+                synced: true,
+                daemon_reachable: true,
+                index_writable: true,
+                mempool_partial: false,
+                stale: false,
+                degraded: false,
+            },
+            daemon_height: Some(height),
+            last_sync_secs: None,
+            mempool_txs: 0,
+            mempool_age_secs: None,
+            tx_cache_entries: 0,
+            tx_cache_bytes: 0,
+        };
+        let monitor = HealthMonitor::default();
+        let embedder = monitor.clone();
+        assert!(embedder.reports().is_empty());
+        monitor.update(0, report(10));
+        monitor.update(1, report(20)); // another network
+        monitor.update(0, report(11));
+        let heights: Vec<usize> = embedder.reports().iter().map(|r| r.height).collect();
+        assert_eq!(heights, vec![11, 20]);
+    }
+
+    #[test]
+    fn test_ordered_sending() {
+        let (server, client) = connect();
+        let (codec_tx, _codec_rx) = bounded(1);
+        let mut peer = new_peer(0, server, codec_tx, CancelToken::default());
+        let mut expected = vec![];
+        for i in 0..10 {
+            let batch = vec![json!(format!("{}a", i)), json!(format!("{}b", i))];
+            expected.extend(batch.iter().map(|value| value.to_string()));
+            peer.send(batch).unwrap();
+        }
+        drop(peer); // the pending messages are still sent
+        let lines: Vec<String> = BufReader::new(client)
+            .lines()
+            .map(|line| line.unwrap())
+            .collect();
+        assert_eq!(lines, expected);
+    }
+
+    #[test]
+    fn test_codec_switch() {
+        let (server, client) = connect();
+        let (codec_tx, codec_rx) = bounded(1);
+        let mut peer = new_peer(0, server, codec_tx, CancelToken::default());
+        peer.send(vec![json!("cbor")]).unwrap();
+        // the codec is switched only if the client negotiated it
+        peer.switch_codec().unwrap();
+        assert_eq!(codec_rx.recv().unwrap(), Codec::Json);
+        peer.codec = Codec::Cbor;
+        peer.send(vec![json!({"id": 1}), json!({"id": 2})]).unwrap();
+        drop(peer);
+
+        let mut reader = BufReader::new(client);
+        let frame = Codec::Json.read(&mut reader, usize::MAX).unwrap().unwrap();
+        assert_eq!(frame.decode().unwrap(), json!("cbor"));
+        for id in 1..=2 {
+            let frame = Codec::Cbor.read(&mut reader, usize::MAX).unwrap().unwrap();
+            assert_eq!(frame.decode().unwrap(), json!({ "id": id }));
+        }
+        assert!(Codec::Cbor.read(&mut reader, usize::MAX).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_slow_peer() {
+        let (server, _client) = connect(); // never reads its messages
+        let (codec_tx, _codec_rx) = bounded(1);
+        let mut peer = new_peer(0, server, codec_tx, CancelToken::default());
+        let batch = vec![json!("x".repeat(100_000))];
+        let mut batches = 0;
+        let err = loop {
+            // sending doesn't block (or this test would hang), even after the socket's buffers
+            // are full
+            match peer.send(batch.clone()) {
+                Ok(()) => batches += 1,
+                Err(e) => break e,
+            }
+        };
+        assert!(batches >= MAX_PENDING_BATCHES);
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "peer is too slow ({} pending message batches)",
+                MAX_PENDING_BATCHES
+            )
+        );
+    }
+
+    #[test]
+    fn test_notified_at_write() {
+        let (server, mut client) = connect();
+        // small socket buffers, so that the notification can't be written before it's read
+        SockRef::from(&server).set_send_buffer_size(4096).unwrap();
+        SockRef::from(&client).set_recv_buffer_size(4096).unwrap();
+        let (codec_tx, _codec_rx) = bounded(1);
+        let mut peer = new_peer(0, server, codec_tx, CancelToken::default());
+        let mut latency = BlockLatency::new(&Metrics::dummy());
+        assert!(latency.indexed(BlockHash::all_zeros()).is_none()); // not signalled
+
+        let notified = new_tip(&mut latency);
+        let batch = vec![json!("x".repeat(1_000_000))];
+        peer.send_batch(batch, Some(Arc::clone(&notified))).unwrap();
+        let mut first = [0u8];
+        client.read_exact(&mut first).unwrap();
+        assert_eq!(notified.last_write(), None); // being written, but not completely
+
+        let reading = Instant::now();
+        drop(peer);
+        let mut buf = vec![];
+        client.read_to_end(&mut buf).unwrap();
+        assert_eq!(1 + buf.len(), 1_000_003); // quoted, with a newline
+        wait_until(|| notified.last_write().is_some());
+        assert!(notified.last_write().unwrap() >= reading);
+    }
+
+    /// Notifies many blocks to a fast peer and slow ones (which are disconnected instead of
+    /// delaying the next syncs): `cargo test test_slow_peers_stress -- --ignored`
+    #[test]
+    #[ignore]
+    fn test_slow_peers_stress() {
+        let (server, client) = connect();
+        let reader = thread::spawn(move || BufReader::new(client).lines().count());
+        let (codec_tx, _codec_rx) = bounded(1);
+        let mut peers = vec![new_peer(0, server, codec_tx, CancelToken::default())];
+        let mut slow_clients = vec![]; // never read their notifications
+        for id in 1..=10 {
+            let (server, client) = connect();
+            let (codec_tx, _codec_rx) = bounded(1);
+            peers.push(new_peer(id, server, codec_tx, CancelToken::default()));
+            slow_clients.push(client);
+        }
+        let mut latency = BlockLatency::new(&Metrics::dummy());
+        let notification = json!("x".repeat(100_000));
+        let blocks = 300;
+        let mut tips = vec![];
+        for _ in 0..blocks {
+            let notified = new_tip(&mut latency);
+            peers = peers
+                .into_iter()
+                .filter_map(|mut peer| {
+                    let batch = vec![notification.clone()];
+                    match peer.send_batch(batch, Some(Arc::clone(&notified))) {
+                        Ok(()) => Some(peer),
+                        Err(_) => {
+                            peer.disconnect();
+                            None
+                        }
+                    }
+                })
+                .collect();
+            // notifying doesn't wait for the slow peers (or this test would hang)
+            tips.push(notified);
+        }
+        // the slow peers were disconnected, and the fast peer got all the notifications
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].id, 0);
+        drop(peers);
+        assert_eq!(reader.join().unwrap(), blocks);
+        wait_until(|| tips.iter().all(|notified| notified.last_write().is_some()));
+    }
+
+    #[test]
+    fn test_half_closed_peer() {
+        let (server, mut client) = connect();
+        let (server_tx, server_rx) = unbounded();
+        let receiver = thread::spawn(move || {
+            recv_peer(Endpoint::default(), 0, server, server_tx, usize::MAX, false)
+        });
+        client.write_all(b"{\"id\": 1}\n{\"id\": 2}\n").unwrap();
+        client.shutdown(Shutdown::Write).unwrap(); // e.g. `echo ... | nc -N`
+        receiver.join().unwrap().unwrap();
+
+        let mut messages = server_rx.iter().map(|event| event.msg);
+        let (conn, codec_tx, cancel) = match messages.next() {
+            Some(Message::New(conn, codec_tx, cancel)) => (conn, codec_tx, cancel),
+            _ => panic!("missing new peer"),
+        };
+        assert!(!conn.admin);
+        let requests = messages
+            .by_ref()
+            .take_while(|msg| matches!(msg, Message::Request(_)))
+            .count();
+        assert_eq!(requests, 2);
+        assert!(messages.next().is_none()); // after `Message::Done`
+
+        // the queued requests are still handled and answered
+        assert!(!cancel.is_cancelled());
+        let mut peer = Peer::new(0, conn, codec_tx, cancel.clone()).unwrap();
+        peer.send(vec![json!({"id": 1}), json!({"id": 2})]).unwrap();
+        drop(peer); // removed after answering
+        assert!(cancel.is_cancelled());
+        let lines: Vec<String> = BufReader::new(client)
+            .lines()
+            .map(|line| line.unwrap())
+            .collect();
+        assert_eq!(lines, vec![r#"{"id":1}"#, r#"{"id":2}"#]);
+    }
+
+    #[test]
+    fn test_proxy_protocol() {
+        let (server, mut client) = connect();
+        let (server_tx, server_rx) = unbounded();
+        let endpoint = Endpoint {
+            network: 0,
+            admin: true,
+        };
+        let receiver =
+            thread::spawn(move || recv_peer(endpoint, 0, server, server_tx, usize::MAX, true));
+        // PROXY v2 header, from 192.0.2.1:1234 to 192.0.2.2:50002 requesting "example.org"
+        let mut header = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x1a".to_vec();
+        header.extend_from_slice(&[192, 0, 2, 1, 192, 0, 2, 2, 0x04, 0xd2, 0xc3, 0x52]);
+        header.extend_from_slice(b"\x02\x00\x0bexample.org");
+        client.write_all(&header).unwrap();
+        client.write_all(b"{\"id\": 1}\n").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        receiver.join().unwrap().unwrap();
+
+        let mut messages = server_rx.iter().map(|event| event.msg);
+        match messages.next() {
+            Some(Message::New(conn, _, _)) => {
+                assert_eq!(conn.peer_addr, Some("192.0.2.1:1234".parse().unwrap()));
+                assert_eq!(conn.hostname.as_deref(), Some("example.org"));
+                assert!(conn.admin);
+            }
+            _ => panic!("missing new peer"),
+        };
+        match messages.next() {
+            Some(Message::Request(frame)) => {
+                assert_eq!(frame.decode().unwrap(), json!({"id": 1}))
+            }
+            _ => panic!("missing request"),
+        }
+
+        // plain connections are refused
+        let (server, mut client) = connect();
+        let (server_tx, server_rx) = unbounded();
+        let receiver = thread::spawn(move || {
+            recv_peer(Endpoint::default(), 0, server, server_tx, usize::MAX, true)
+        });
+        client
+            .write_all(b"{\"id\": 1, \"method\": \"server.ping\"}\n")
+            .unwrap();
+        let err = receiver.join().unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "0: invalid PROXY header");
+        assert!(server_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_disconnect_mid_batch() {
+        let (server, client) = connect();
+        let (codec_tx, _codec_rx) = bounded(1);
+        let cancel = CancelToken::default();
+        let mut peer = new_peer(0, server, codec_tx, cancel.clone());
+        let scheduler = Arc::new(Scheduler::new(2, &Metrics::dummy()));
+        let done = Arc::new(AtomicUsize::new(0));
+        let (started_tx, started_rx) = unbounded();
+        let (release_tx, release_rx) = unbounded::<()>();
+        let batch = {
+            let scheduler = Arc::clone(&scheduler);
+            let cancel = cancel.clone();
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                let pool = rayon::ThreadPoolBuilder::new()
+                    .num_threads(4)
+                    .build()
+                    .unwrap();
+                pool.install(|| {
+                    let items: Vec<usize> = (0..100).collect();
+                    scheduler.run_all(Priority::Bulk, items, &cancel, |_| {
+                        started_tx.send(()).unwrap();
+                        let _ = release_rx.recv(); // until all are released
+                        done.fetch_add(1, Ordering::SeqCst);
+                    })
+                })
+            })
+        };
+        // both permits are used by the running builds
+        started_rx.recv().unwrap();
+        started_rx.recv().unwrap();
+
+        // the client disconnects mid-batch, so sending its messages fails
+        drop(client);
+        wait_until(|| {
+            let _ = peer.send(vec![json!("x".repeat(10_000))]);
+            cancel.is_cancelled()
+        });
+        // the queued builds are abandoned (after the running ones are done)
+        drop(release_tx);
+        assert!(batch.join().unwrap().is_err());
+        assert_eq!(done.load(Ordering::SeqCst), 2);
+        assert_eq!(scheduler.queued(Priority::Bulk), 0);
+    }
+
+    #[test]
+    fn test_concurrent_peers() {
+        let scheduler = Scheduler::new(2, &Metrics::dummy());
+        let cancel = CancelToken::default();
+        let bulk_done = AtomicUsize::new(0);
+        let (started_tx, started_rx) = unbounded();
+        let (release_tx, release_rx) = unbounded();
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        // a peer subscribes a large batch, and then another peer subscribes a single scripthash
+        let results = pool.install(|| {
+            handle_concurrently(vec![Priority::Bulk, Priority::Interactive], |priority| {
+                match priority {
+                    Priority::Bulk => {
+                        let items: Vec<usize> = (0..100).collect();
+                        scheduler
+                            .run_all(priority, items, &cancel, |item| {
+                                if item == 0 {
+                                    // the batch isn't done until the other peer is handled
+                                    started_tx.send(()).unwrap();
+                                    release_rx.recv().unwrap();
+                                }
+                                bulk_done.fetch_add(1, Ordering::SeqCst);
+                            })
+                            .unwrap();
+                        None
+                    }
+                    Priority::Interactive => {
+                        started_rx.recv().unwrap();
+                        scheduler.run(priority, &cancel, || ()).unwrap();
+                        let bulk_done = bulk_done.load(Ordering::SeqCst);
+                        release_tx.send(()).unwrap();
+                        Some(bulk_done)
+                    }
+                }
+            })
+        });
+        // the interactive build isn't queued behind the other peer's bulk builds
+        assert_eq!(results.len(), 1);
+        assert!(results[0] < 100);
+        assert_eq!(bulk_done.load(Ordering::SeqCst), 100);
+    }
+}
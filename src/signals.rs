@@ -28,7 +28,7 @@ pub(crate) struct ExitFlag {
 }
 
 impl ExitFlag {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         ExitFlag {
             flag: Arc::new(AtomicBool::new(false)),
         }
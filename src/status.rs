@@ -1,28 +1,45 @@
 use anyhow::Result;
 use bitcoin::{
     hashes::{sha256, Hash, HashEngine},
-    Amount, Block, BlockHash, OutPoint, SignedAmount, Transaction, Txid,
+    Amount, Block, BlockHash, OutPoint, Script, ScriptBuf, SignedAmount, Transaction, Txid,
 };
 use rayon::prelude::*;
 use serde::ser::{Serialize, Serializer};
+use serde_json::json;
 
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::{hash_map::Entry, BTreeMap, HashMap, HashSet};
 use std::convert::TryFrom;
+use std::mem::size_of;
 
 use crate::{
     cache::Cache,
     chain::Chain,
-    daemon::Daemon,
-    index::Index,
+    errors::{CodedError, ErrorCode},
+    index::{BlockDaemon, Index},
     mempool::Mempool,
+    protocol::{
+        BalanceTotals, GetBalanceResponse, HistoryEntryWire, OutPointStatusResult, UnspentEntryWire,
+    },
     types::{ScriptHash, StatusHash},
 };
 
+/// Larger history changes are not notified as deltas (see `HistoryDelta`)
+pub(crate) const MAX_DELTA_ENTRIES: usize = 100;
+
+/// The message is the same as ElectrumX's (starting with "history too large"), which clients
+/// already handle.
+fn history_too_large(message: String, limit: usize) -> anyhow::Error {
+    CodedError::new(ErrorCode::HistoryTooLarge, message)
+        .with_data(json!({ "limit": limit }))
+        .into()
+}
+
 /// Given a scripthash, store relevant inputs and outputs of a specific transaction
 struct TxEntry {
     txid: Txid,
     outputs: Vec<TxOutput>, // relevant funded outputs and their amounts
     spent: Vec<OutPoint>,   // relevant spent outpoints
+    pos: Option<u32>,       // position in its block (for confirmed entries)
 }
 
 struct TxOutput {
@@ -36,9 +53,16 @@ impl TxEntry {
             txid,
             outputs: Vec::new(),
             spent: Vec::new(),
+            pos: None,
         }
     }
 
+    fn memory_usage(&self) -> usize {
+        size_of::<Self>()
+            + self.outputs.len() * size_of::<TxOutput>()
+            + self.spent.len() * size_of::<OutPoint>()
+    }
+
     /// Relevant (scripthash-wise) funded outpoints
     fn funding_outpoints(&self) -> impl Iterator<Item = OutPoint> + '_ {
         make_outpoints(self.txid, &self.outputs)
@@ -48,12 +72,17 @@ impl TxEntry {
 // Confirmation height of a transaction or its mempool state:
 // https://electrumx-spesmilo.readthedocs.io/en/latest/protocol-methods.html#blockchain-scripthash-get-history
 // https://electrumx-spesmilo.readthedocs.io/en/latest/protocol-methods.html#blockchain-scripthash-get-mempool
+#[derive(Clone, Debug, PartialEq, Eq)]
 enum Height {
     Confirmed { height: usize },
     Unconfirmed { has_unconfirmed_inputs: bool },
 }
 
 impl Height {
+    fn is_confirmed(&self) -> bool {
+        matches!(self, Self::Confirmed { .. })
+    }
+
     fn as_i64(&self) -> i64 {
         match self {
             Self::Confirmed { height } => i64::try_from(*height).unwrap(),
@@ -85,7 +114,7 @@ impl std::fmt::Display for Height {
 // A single history entry:
 // https://electrumx-spesmilo.readthedocs.io/en/latest/protocol-methods.html#blockchain-scripthash-get-history
 // https://electrumx-spesmilo.readthedocs.io/en/latest/protocol-methods.html#blockchain-scripthash-get-mempool
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub(crate) struct HistoryEntry {
     #[serde(rename = "tx_hash")]
     txid: Txid,
@@ -95,6 +124,8 @@ pub(crate) struct HistoryEntry {
         with = "bitcoin::amount::serde::as_sat::opt"
     )]
     fee: Option<Amount>,
+    #[serde(skip)]
+    tx_pos: Option<u32>, // position in its block (for confirmed entries)
 }
 
 impl HistoryEntry {
@@ -108,6 +139,7 @@ impl HistoryEntry {
             txid,
             height: Height::Confirmed { height },
             fee: None,
+            tx_pos: None,
         }
     }
 
@@ -118,10 +150,75 @@ impl HistoryEntry {
                 has_unconfirmed_inputs,
             },
             fee: Some(fee),
+            tx_pos: None,
+        }
+    }
+
+    pub(crate) fn is_confirmed(&self) -> bool {
+        self.height.is_confirmed()
+    }
+
+    pub(crate) fn txid(&self) -> Txid {
+        self.txid
+    }
+
+    pub(crate) fn confirmed_height(&self) -> Option<usize> {
+        match self.height {
+            Height::Confirmed { height } => Some(height),
+            Height::Unconfirmed { .. } => None,
+        }
+    }
+
+    pub(crate) fn tx_pos(&self) -> Option<u32> {
+        self.tx_pos
+    }
+
+    /// Entries with the same key are hashed the same way into the statushash.
+    fn key(&self) -> (Txid, i64) {
+        (self.txid, self.height.as_i64())
+    }
+
+    pub(crate) fn to_wire(&self) -> HistoryEntryWire {
+        HistoryEntryWire {
+            tx_hash: self.txid,
+            height: self.height.as_i64(),
+            fee: self.fee.map(Amount::to_sat),
+            tx_pos: None,
+            reorged_from: None,
         }
     }
 }
 
+/// The history's change by the last sync: its last `removed` entries were replaced by the `added`
+/// ones, so applying it to the previous history results in the current one (and its statushash).
+#[derive(Serialize)]
+pub(crate) struct HistoryDelta {
+    removed: Vec<HistoryEntry>,
+    added: Vec<HistoryEntry>,
+}
+
+impl HistoryDelta {
+    /// Returns `None` if the change is too large to be notified.
+    fn new(old: &mut Vec<HistoryEntry>, new: &[HistoryEntry]) -> Option<Self> {
+        let common = old
+            .iter()
+            .zip(new)
+            .take_while(|(old, new)| old.key() == new.key())
+            .count();
+        if old.len() + new.len() - 2 * common > MAX_DELTA_ENTRIES {
+            return None;
+        }
+        Some(Self {
+            removed: old.split_off(common),
+            added: new[common..].to_vec(),
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.removed.len() + self.added.len()
+    }
+}
+
 /// ScriptHash subscription status
 pub struct ScriptHashStatus {
     scripthash: ScriptHash, // specific scripthash to be queried
@@ -129,7 +226,18 @@ pub struct ScriptHashStatus {
     confirmed: HashMap<BlockHash, Vec<TxEntry>>, // confirmed entries, partitioned per block (may contain stale blocks)
     mempool: Vec<TxEntry>,                       // unconfirmed entries
     history: Vec<HistoryEntry>,                  // computed from confirmed and mempool entries
+    delta: Option<HistoryDelta>,                 // history's change by the last sync (if small)
     statushash: Option<StatusHash>,              // computed from history
+    memory_usage: usize,                         // approximate (in bytes), computed on sync
+    script_pubkey: Option<ScriptBuf>,            // shared by all funded outputs (if resolved)
+    as_of: Option<AsOf>,                         // index tip at the last successful sync
+}
+
+/// The index tip at which a status was last successfully synced
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct AsOf {
+    height: usize,
+    blockhash: BlockHash,
 }
 
 /// Specific scripthash balance
@@ -141,6 +249,39 @@ pub(crate) struct Balance {
     mempool_delta: SignedAmount,
 }
 
+/// Total amounts funded to and spent from a scripthash: a self-transfer counts both its spent
+/// outputs (as sent) and its new ones (as received), so that `received - sent` is the balance.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub(crate) struct Totals {
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    received: Amount,
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    sent: Amount,
+}
+
+impl Balance {
+    pub(crate) fn to_wire(&self) -> GetBalanceResponse {
+        GetBalanceResponse {
+            confirmed: self.confirmed_balance.to_sat(),
+            unconfirmed: self.mempool_delta.to_sat(),
+            stale: false,
+            height: None,
+            totals: None,
+            as_of_height: None,
+            diagnostic: None,
+        }
+    }
+}
+
+impl Totals {
+    pub(crate) fn to_wire(self) -> BalanceTotals {
+        BalanceTotals {
+            received: self.received.to_sat(),
+            sent: self.sent.to_sat(),
+        }
+    }
+}
+
 // A single unspent transaction output entry:
 // https://electrumx-spesmilo.readthedocs.io/en/latest/protocol-methods.html#blockchain-scripthash-listunspent
 #[derive(Serialize, Clone)]
@@ -150,14 +291,69 @@ pub(crate) struct UnspentEntry {
     tx_pos: u32,
     #[serde(with = "bitcoin::amount::serde::as_sat")]
     pub value: Amount,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub spent_in_mempool: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub script_pubkey: Option<ScriptBuf>, // included on request (e.g. for PSBT construction)
+}
+
+impl UnspentEntry {
+    pub(crate) fn outpoint(&self) -> OutPoint {
+        OutPoint {
+            txid: self.tx_hash,
+            vout: self.tx_pos,
+        }
+    }
+
+    pub(crate) fn to_wire(&self) -> UnspentEntryWire {
+        UnspentEntryWire {
+            height: self.height,
+            tx_hash: self.tx_hash,
+            tx_pos: self.tx_pos,
+            value: self.value.to_sat(),
+            spent_in_mempool: self.spent_in_mempool,
+            script_pubkey: self.script_pubkey.clone(),
+            ancestor_count: None,
+            ancestor_size: None,
+            spendable_now: None,
+            unconfirmed: None,
+        }
+    }
+}
+
+#[cfg(test)]
+impl UnspentEntry {
+    pub(crate) fn new(height: usize, tx_hash: Txid, tx_pos: u32, value: Amount) -> Self {
+        Self {
+            height,
+            tx_hash,
+            tx_pos,
+            value,
+            spent_in_mempool: false,
+            script_pubkey: None,
+        }
+    }
+}
+
+/// A confirmed transaction, with its relevant (scripthash-wise) funded outputs and spent outpoints
+#[cfg_attr(not(feature = "sqlite_export"), allow(dead_code))]
+pub(crate) struct ConfirmedTx<'a> {
+    pub height: usize,
+    pub txid: Txid,
+    pub funding: Vec<(OutPoint, Amount)>,
+    pub spending: &'a [OutPoint],
 }
 
 #[derive(Default)]
 struct Unspent {
     // mapping an outpoint to its value & confirmation height
     outpoints: HashMap<OutPoint, (Amount, usize)>,
+    // outpoints spent by mempool transactions (removed from `outpoints`)
+    mempool_spent: HashMap<OutPoint, (Amount, usize)>,
     confirmed_balance: Amount,
     mempool_delta: SignedAmount,
+    confirmed_totals: Totals,
+    totals: Totals, // including the mempool transactions
 }
 
 impl Unspent {
@@ -172,26 +368,72 @@ impl Unspent {
             .for_each(|e| unspent.remove(e));
 
         unspent.confirmed_balance = unspent.balance();
+        unspent.confirmed_totals = unspent.totals;
 
-        status.mempool.iter().for_each(|e| unspent.insert(e, 0)); // mempool height = 0
-        status.mempool.iter().for_each(|e| unspent.remove(e));
+        status
+            .mempool
+            .iter()
+            .for_each(|e| unspent.insert_unconfirmed(e));
+        status
+            .mempool
+            .iter()
+            .for_each(|e| unspent.remove_mempool_spent(e));
 
         unspent.mempool_delta =
             unspent.balance().to_signed().unwrap() - unspent.confirmed_balance.to_signed().unwrap();
 
+        #[cfg(debug_assertions)]
+        unspent.check_balance();
         unspent
     }
 
-    fn into_entries(self) -> Vec<UnspentEntry> {
-        self.outpoints
-            .into_iter()
-            .map(|(outpoint, (value, height))| UnspentEntry {
-                height,
-                tx_hash: outpoint.txid,
-                tx_pos: outpoint.vout,
-                value,
-            })
-            .collect()
+    /// Drop the mempool transactions' effects (restoring the outputs spent by them).
+    fn confirmed_only(mut self) -> Self {
+        self.outpoints.retain(|_, (_, height)| *height > 0);
+        let mempool_spent = std::mem::take(&mut self.mempool_spent);
+        self.outpoints.extend(
+            mempool_spent
+                .into_iter()
+                .filter(|(_, (_, height))| *height > 0),
+        );
+        self.mempool_delta = SignedAmount::ZERO;
+        self.totals = self.confirmed_totals;
+        #[cfg(debug_assertions)]
+        self.check_balance();
+        self
+    }
+
+    /// The balance must match the unspent outputs: confirmed ones (including those spent by
+    /// mempool transactions) make up the confirmed balance, and all the unspent ones (excluding
+    /// those spent by mempool transactions) make up the total balance.
+    #[cfg(debug_assertions)]
+    fn check_balance(&self) {
+        let confirmed = self
+            .outpoints
+            .values()
+            .chain(self.mempool_spent.values())
+            .filter(|(_, height)| *height > 0)
+            .fold(Amount::ZERO, |acc, (value, _)| acc + *value);
+        assert_eq!(
+            confirmed, self.confirmed_balance,
+            "inconsistent confirmed balance"
+        );
+        let total = self.confirmed_balance.to_signed().unwrap() + self.mempool_delta;
+        assert_eq!(
+            total,
+            self.balance().to_signed().unwrap(),
+            "inconsistent total balance"
+        );
+        assert_eq!(
+            self.confirmed_totals.received - self.confirmed_totals.sent,
+            self.confirmed_balance,
+            "inconsistent confirmed totals"
+        );
+        assert_eq!(
+            self.totals.received - self.totals.sent,
+            self.balance(),
+            "inconsistent totals"
+        );
     }
 
     fn balance(&self) -> Amount {
@@ -206,17 +448,91 @@ impl Unspent {
                 txid: entry.txid,
                 vout: output.index,
             };
-            self.outpoints.insert(outpoint, (output.value, height));
+            if self
+                .outpoints
+                .insert(outpoint, (output.value, height))
+                .is_none()
+            {
+                self.totals.received += output.value;
+            }
+        }
+    }
+
+    /// Confirmed outputs are kept, in case the mempool view predates their confirmation.
+    fn insert_unconfirmed(&mut self, entry: &TxEntry) {
+        for output in &entry.outputs {
+            let outpoint = OutPoint {
+                txid: entry.txid,
+                vout: output.index,
+            };
+            if let Entry::Vacant(e) = self.outpoints.entry(outpoint) {
+                e.insert((output.value, 0)); // mempool height = 0
+                self.totals.received += output.value;
+            }
         }
     }
 
     fn remove(&mut self, entry: &TxEntry) {
         for spent in &entry.spent {
-            self.outpoints.remove(spent);
+            if let Some((value, _height)) = self.outpoints.remove(spent) {
+                self.totals.sent += value;
+            }
+        }
+    }
+
+    fn remove_mempool_spent(&mut self, entry: &TxEntry) {
+        for spent in &entry.spent {
+            if let Some(value) = self.outpoints.remove(spent) {
+                self.totals.sent += value.0;
+                self.mempool_spent.insert(*spent, value);
+            }
         }
     }
 }
 
+/// A scripthash's unspent outputs and balance, computed together from its status (so that a single
+/// query can't observe them at different sync states).
+pub(crate) struct UnspentSnapshot(Unspent, Option<ScriptBuf>, Option<usize>);
+
+impl UnspentSnapshot {
+    /// Ignore the mempool transactions (e.g. if the mempool can't be synced)
+    pub(crate) fn confirmed_only(self) -> Self {
+        Self(self.0.confirmed_only(), self.1, self.2)
+    }
+
+    /// The index height at which the status was last synced
+    pub(crate) fn as_of_height(&self) -> Option<usize> {
+        self.2
+    }
+
+    /// The scriptPubKey of all the unspent outputs (unless it wasn't resolved on sync)
+    pub(crate) fn script_pubkey(&self) -> Option<&Script> {
+        self.1.as_deref()
+    }
+
+    pub(crate) fn balance(&self) -> Balance {
+        Balance {
+            confirmed_balance: self.0.confirmed_balance,
+            mempool_delta: self.0.mempool_delta,
+        }
+    }
+
+    pub(crate) fn totals(&self) -> Totals {
+        self.0.totals
+    }
+
+    pub(crate) fn entries(&self) -> Vec<UnspentEntry> {
+        make_unspent_entries(&self.0.outpoints, false).collect()
+    }
+
+    /// Include the outpoints spent by mempool transactions, flagged as such.
+    pub(crate) fn entries_with_mempool_spent(&self) -> Vec<UnspentEntry> {
+        make_unspent_entries(&self.0.outpoints, false)
+            .chain(make_unspent_entries(&self.0.mempool_spent, true))
+            .collect()
+    }
+}
+
 impl ScriptHashStatus {
     /// Return non-synced (empty) status for a given script hash.
     pub fn new(scripthash: ScriptHash) -> Self {
@@ -226,7 +542,11 @@ impl ScriptHashStatus {
             confirmed: HashMap::new(),
             mempool: Vec::new(),
             history: Vec::new(),
+            delta: None,
             statushash: None,
+            memory_usage: size_of::<Self>(),
+            script_pubkey: None,
+            as_of: None,
         }
     }
 
@@ -260,42 +580,107 @@ impl ScriptHashStatus {
     }
 
     pub(crate) fn get_unspent(&self, chain: &Chain) -> Vec<UnspentEntry> {
-        Unspent::build(self, chain).into_entries()
+        self.get_unspent_snapshot(chain).entries()
     }
 
-    pub(crate) fn get_balance(&self, chain: &Chain) -> Balance {
-        let unspent = Unspent::build(self, chain);
-        Balance {
-            confirmed_balance: unspent.confirmed_balance,
-            mempool_delta: unspent.mempool_delta,
-        }
+    /// Both the unspent outputs and the balance should be derived from a single snapshot.
+    pub(crate) fn get_unspent_snapshot(&self, chain: &Chain) -> UnspentSnapshot {
+        UnspentSnapshot(
+            Unspent::build(self, chain),
+            self.script_pubkey.clone(),
+            self.as_of_height(),
+        )
+    }
+
+    pub(crate) fn get_history(
+        &self,
+        from: &Option<usize>,
+        to: &Option<usize>,
+    ) -> Vec<&HistoryEntry> {
+        self.history_iter(from, to, false).collect()
     }
 
-    pub(crate) fn get_history(&self, from: &Option<usize>, to: &Option<usize>) -> Vec<&HistoryEntry> {
-        let filter = self.history
+    /// Get the mempool history entries (transactions with unconfirmed parents last).
+    pub(crate) fn get_mempool(&self) -> Vec<&HistoryEntry> {
+        self.history
             .iter()
-            .filter(|item| {
-                let height = item.height.as_i64();
-                match (from, to) {
-                    (Some(from), Some(to)) => *from as i64 <= height && height <= *to as i64,
-                    (Some(from), None) => *from as i64 <= height,
-                    (None, Some(to)) => height <= *to as i64,
-                    (None, None) => true,
-                }
-            })
-            .collect::<Vec<&HistoryEntry>>();
-        filter
+            .skip_while(|e| e.height.is_confirmed())
+            .collect()
+    }
+
+    /// Iterate over the history entries within the given height range: oldest-first, or
+    /// newest-first if `reverse` is set (mempool entries, then confirmed ones by descending height).
+    /// Since confirmed entries are sorted by height, iteration stops when leaving the range.
+    pub(crate) fn history_iter<'a>(
+        &'a self,
+        from: &Option<usize>,
+        to: &Option<usize>,
+        reverse: bool,
+    ) -> Box<dyn Iterator<Item = &'a HistoryEntry> + 'a> {
+        let from = from.map(|height| height as i64);
+        let to = to.map(|height| height as i64);
+        let above_from =
+            move |e: &&HistoryEntry| from.map_or(true, |from| from <= e.height.as_i64());
+        let below_to = move |e: &&HistoryEntry| to.map_or(true, |to| e.height.as_i64() <= to);
+
+        let split = self
+            .history
+            .iter()
+            .position(|e| !e.height.is_confirmed())
+            .unwrap_or_else(|| self.history.len());
+        let (confirmed, mempool) = self.history.split_at(split);
+        let mempool = mempool.iter().filter(move |e| above_from(e) && below_to(e));
+        if reverse {
+            let confirmed = confirmed
+                .iter()
+                .rev()
+                .skip_while(move |e| !below_to(e))
+                .take_while(above_from);
+            Box::new(mempool.rev().chain(confirmed))
+        } else {
+            let confirmed = confirmed
+                .iter()
+                .skip_while(move |e| !above_from(e))
+                .take_while(below_to);
+            Box::new(confirmed.chain(mempool))
+        }
     }
 
-    /// Collect all confirmed history entries (in block order).
+    /// The number of history entries (confirmed and unconfirmed)
+    pub(crate) fn history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    /// Collect all confirmed history entries (in block order, and by position within each block).
     fn get_confirmed_history(&self, chain: &Chain) -> Vec<HistoryEntry> {
         self.confirmed_height_entries(chain)
             .collect::<BTreeMap<usize, &[TxEntry]>>()
             .into_iter()
             .flat_map(|(height, entries)| {
-                entries
-                    .iter()
-                    .map(move |e| HistoryEntry::confirmed(e.txid, height))
+                entries.iter().map(move |e| HistoryEntry {
+                    tx_pos: e.pos,
+                    ..HistoryEntry::confirmed(e.txid, height)
+                })
+            })
+            .collect()
+    }
+
+    /// Collect all confirmed transactions (in block order), with their relevant inputs and outputs.
+    #[cfg_attr(not(feature = "sqlite_export"), allow(dead_code))]
+    pub(crate) fn get_confirmed_txs<'a>(&'a self, chain: &'a Chain) -> Vec<ConfirmedTx<'a>> {
+        self.confirmed_height_entries(chain)
+            .collect::<BTreeMap<usize, &[TxEntry]>>()
+            .into_iter()
+            .flat_map(|(height, entries)| {
+                entries.iter().map(move |e| ConfirmedTx {
+                    height,
+                    txid: e.txid,
+                    funding: e
+                        .funding_outpoints()
+                        .zip(e.outputs.iter().map(|output| output.value))
+                        .collect(),
+                    spending: &e.spent,
+                })
             })
             .collect()
     }
@@ -306,25 +691,34 @@ impl ScriptHashStatus {
             .mempool
             .iter()
             .filter_map(|e| mempool.get(&e.txid))
+            .map(|e| (mempool.has_unconfirmed_inputs(e), e))
             .collect::<Vec<_>>();
-        entries.sort_by_key(|e| (e.has_unconfirmed_inputs, e.txid));
+        entries.sort_by_key(|(has_unconfirmed_inputs, e)| (*has_unconfirmed_inputs, e.txid));
         entries
             .into_iter()
-            .map(|e| HistoryEntry::unconfirmed(e.txid, e.has_unconfirmed_inputs, e.fee))
+            .map(|(has_unconfirmed_inputs, e)| {
+                HistoryEntry::unconfirmed(e.txid, has_unconfirmed_inputs, e.fee)
+            })
             .collect()
     }
 
     /// Apply func only on the new blocks (fetched from daemon).
-    fn for_new_blocks<B, F>(&self, blockhashes: B, daemon: &Daemon, func: F) -> Result<()>
+    fn for_new_blocks<B, F>(
+        &self,
+        blockhashes: B,
+        daemon: &dyn BlockDaemon,
+        mut func: F,
+    ) -> Result<()>
     where
         B: IntoIterator<Item = BlockHash>,
         F: FnMut(BlockHash, Block),
     {
-        daemon.for_blocks(
+        daemon.blocks(
             blockhashes
                 .into_iter()
-                .filter(|blockhash| !self.confirmed.contains_key(blockhash)),
-            func,
+                .filter(|blockhash| !self.confirmed.contains_key(blockhash))
+                .collect(),
+            &mut func,
         )
     }
 
@@ -333,14 +727,27 @@ impl ScriptHashStatus {
     fn sync_confirmed(
         &self,
         index: &Index,
-        daemon: &Daemon,
+        daemon: &dyn BlockDaemon,
         cache: &Cache,
         outpoints: &mut HashSet<OutPoint>,
+        max_history_len: Option<usize>,
     ) -> Result<HashMap<BlockHash, Vec<TxEntry>>> {
         let scripthash = self.scripthash;
         let mut result = HashMap::<BlockHash, HashMap<usize, TxEntry>>::new();
 
         let funding_blockhashes = index.limit_result(index.filter_by_funding(scripthash))?;
+        if let Some(limit) = max_history_len {
+            // each funding block contains at least one history entry, so the blocks are not
+            // fetched if there are too many of them
+            let blocks = funding_blockhashes.iter().collect::<HashSet<_>>().len();
+            if blocks > limit {
+                let message = format!(
+                    "history too large (at least {} entries, limit {})",
+                    blocks, limit
+                );
+                return Err(history_too_large(message, limit));
+            }
+        }
         self.for_new_blocks(funding_blockhashes, daemon, |blockhash, block| {
             let block_entries = result.entry(blockhash).or_default();
             filter_block_txs(block, |tx| filter_outputs(tx, scripthash)).for_each(
@@ -389,7 +796,10 @@ impl ScriptHashStatus {
                     .into_iter()
                     .collect::<BTreeMap<usize, TxEntry>>()
                     .into_iter()
-                    .map(|(_pos, entry)| entry)
+                    .map(|(pos, entry)| TxEntry {
+                        pos: u32::try_from(pos).ok(),
+                        ..entry
+                    })
                     .collect::<Vec<TxEntry>>();
                 (blockhash, sorted_entries)
             })
@@ -432,18 +842,21 @@ impl ScriptHashStatus {
 
     /// Sync with currently confirmed txs and mempool, downloading non-cached transactions via p2p protocol.
     /// After a successful sync, scripthash status is updated.
+    /// Fails if the history has more than `max_history_len` entries (aborting as early as possible).
     pub(crate) fn sync(
         &mut self,
         index: &Index,
         mempool: &Mempool,
-        daemon: &Daemon,
+        daemon: &dyn BlockDaemon,
         cache: &Cache,
+        max_history_len: Option<usize>,
     ) -> Result<()> {
         let mut outpoints: HashSet<OutPoint> = self.confirmed_outpoints(index.chain());
 
         let new_tip = index.chain().tip();
         if self.tip != new_tip {
-            let update = self.sync_confirmed(index, daemon, cache, &mut outpoints)?;
+            let update =
+                self.sync_confirmed(index, daemon, cache, &mut outpoints, max_history_len)?;
             self.confirmed.extend(update);
             self.tip = new_tip;
         }
@@ -454,23 +867,119 @@ impl ScriptHashStatus {
                 self.confirmed.len()
             );
         }
+        let confirmed_history = self.get_confirmed_history(index.chain());
         self.mempool = self.sync_mempool(mempool, cache, &mut outpoints);
+        exclude_confirmed(&mut self.mempool, &confirmed_history);
         if !self.mempool.is_empty() {
             debug!("{} mempool transactions", self.mempool.len());
         }
-        self.history.clear();
-        self.history
-            .extend(self.get_confirmed_history(index.chain()));
-        self.history.extend(self.get_mempool_history(mempool));
+        let mut history = confirmed_history;
+        history.extend(self.get_mempool_history(mempool));
+        if let Some(limit) = max_history_len {
+            if history.len() > limit {
+                let message = format!(
+                    "history too large ({} entries, limit {})",
+                    history.len(),
+                    limit
+                );
+                return Err(history_too_large(message, limit));
+            }
+        }
+        self.set_history(history);
 
-        self.statushash = compute_status_hash(&self.history);
+        if self.script_pubkey.is_none() {
+            self.script_pubkey = self.cached_script_pubkey(cache);
+        }
+        self.memory_usage = self.compute_memory_usage();
+        self.as_of = Some(AsOf {
+            height: index.chain().height(),
+            blockhash: new_tip,
+        });
         Ok(())
     }
 
+    /// All the funded outputs share the same scriptPubKey (since it's hashed into the scripthash),
+    /// so it can be copied from any funding transaction - which were just cached by `sync()`.
+    fn cached_script_pubkey(&self, cache: &Cache) -> Option<ScriptBuf> {
+        let scripthash = self.scripthash;
+        self.confirmed
+            .values()
+            .flatten()
+            .chain(&self.mempool)
+            .flat_map(TxEntry::funding_outpoints)
+            .find_map(|outpoint| {
+                cache.get_tx(&outpoint.txid, |tx| {
+                    let vout = usize::try_from(outpoint.vout).ok()?;
+                    let script = &tx.output.get(vout)?.script_pubkey;
+                    if ScriptHash::new(script) == scripthash {
+                        Some(script.clone())
+                    } else {
+                        None
+                    }
+                })?
+            })
+    }
+
+    fn set_history(&mut self, history: Vec<HistoryEntry>) {
+        self.delta = HistoryDelta::new(&mut self.history, &history);
+        self.history = history;
+        self.statushash = compute_status_hash(&self.history);
+    }
+
     /// Get current status hash.
     pub fn statushash(&self) -> Option<StatusHash> {
         self.statushash
     }
+
+    /// The index height at which this status was last successfully synced (`None` if never)
+    pub(crate) fn as_of_height(&self) -> Option<usize> {
+        self.as_of.map(|as_of| as_of.height)
+    }
+
+    /// Whether this status lags the chain's tip by more than `max_lag` blocks, or was synced to
+    /// a block which was since reorged out (so it should be refreshed before answering a query).
+    pub(crate) fn is_lagging(&self, chain: &Chain, max_lag: usize) -> bool {
+        match self.as_of {
+            Some(AsOf { height, blockhash }) => {
+                chain.get_block_hash(height) != Some(blockhash)
+                    || chain.height().saturating_sub(height) > max_lag
+            }
+            None => true,
+        }
+    }
+
+    /// The history's change by the last sync (`None` if it was too large)
+    pub(crate) fn delta(&self) -> Option<&HistoryDelta> {
+        self.delta.as_ref()
+    }
+
+    /// Approximate memory used by this status (in bytes), as of its last sync
+    pub(crate) fn memory_usage(&self) -> usize {
+        self.memory_usage
+    }
+
+    fn compute_memory_usage(&self) -> usize {
+        let entries: usize = self
+            .confirmed
+            .values()
+            .flatten()
+            .chain(&self.mempool)
+            .map(TxEntry::memory_usage)
+            .sum();
+        size_of::<Self>()
+            + self.confirmed.len() * size_of::<(BlockHash, Vec<TxEntry>)>()
+            + entries
+            + self.history.len() * size_of::<HistoryEntry>()
+            + self.delta.as_ref().map_or(0, HistoryDelta::len) * size_of::<HistoryEntry>()
+            + self.script_pubkey.as_ref().map_or(0, |script| script.len())
+    }
+}
+
+/// Keep a single entry per transaction: the mempool view may predate the latest block, so a
+/// transaction (e.g. a self-transfer, both funding and spending the scripthash) may appear in both.
+fn exclude_confirmed(mempool: &mut Vec<TxEntry>, confirmed: &[HistoryEntry]) {
+    let confirmed_txids: HashSet<Txid> = confirmed.iter().map(|e| e.txid).collect();
+    mempool.retain(|e| !confirmed_txids.contains(&e.txid));
 }
 
 fn make_outpoints(txid: Txid, outputs: &[TxOutput]) -> impl Iterator<Item = OutPoint> + '_ {
@@ -479,6 +988,22 @@ fn make_outpoints(txid: Txid, outputs: &[TxOutput]) -> impl Iterator<Item = OutP
         .map(move |out| OutPoint::new(txid, out.index))
 }
 
+fn make_unspent_entries(
+    outpoints: &HashMap<OutPoint, (Amount, usize)>,
+    spent_in_mempool: bool,
+) -> impl Iterator<Item = UnspentEntry> + '_ {
+    outpoints
+        .iter()
+        .map(move |(outpoint, (value, height))| UnspentEntry {
+            height: *height,
+            tx_hash: outpoint.txid,
+            tx_pos: outpoint.vout,
+            value: *value,
+            spent_in_mempool,
+            script_pubkey: None,
+        })
+}
+
 fn filter_outputs(tx: &Transaction, scripthash: ScriptHash) -> Vec<TxOutput> {
     let outputs = tx.output.iter().zip(0u32..);
     outputs
@@ -551,11 +1076,910 @@ fn filter_block_txs<T: Send>(
         .into_iter()
 }
 
+/// A subscribed transaction (or a subscribed outpoint's funding or spending one), with its
+/// confirming block (so that it's looked up again only after a reorg).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct WatchedTx {
+    pub(crate) txid: Txid,
+    height: Height,
+    blockhash: Option<BlockHash>, // if confirmed
+}
+
+impl WatchedTx {
+    pub(crate) fn confirmed(txid: Txid, height: usize, blockhash: BlockHash) -> Self {
+        Self {
+            txid,
+            height: Height::Confirmed { height },
+            blockhash: Some(blockhash),
+        }
+    }
+
+    pub(crate) fn unconfirmed(txid: Txid, has_unconfirmed_inputs: bool) -> Self {
+        Self {
+            txid,
+            height: Height::Unconfirmed {
+                has_unconfirmed_inputs,
+            },
+            blockhash: None,
+        }
+    }
+
+    /// The confirmed height, or 0 if in the mempool (-1 if having unconfirmed inputs)
+    pub(crate) fn height(&self) -> i64 {
+        self.height.as_i64()
+    }
+
+    /// The number of confirmations by the given chain (0 if unconfirmed)
+    pub(crate) fn confirmations(&self, chain: &Chain) -> usize {
+        match self.height {
+            Height::Confirmed { height } => (chain.height() + 1).saturating_sub(height),
+            Height::Unconfirmed { .. } => 0,
+        }
+    }
+
+    /// Whether it's still confirmed by the same block (unconfirmed ones should be looked up again)
+    pub(crate) fn is_confirmed_in(&self, chain: &Chain) -> bool {
+        match (&self.height, self.blockhash) {
+            (Height::Confirmed { height }, Some(blockhash)) => {
+                chain.get_block_hash(*height) == Some(blockhash)
+            }
+            _ => false,
+        }
+    }
+
+    /// The current state: the mempool one (if any), or the previous one if it's still confirmed by
+    /// the same block, or else the confirmed one found by `lookup` (e.g. after a reorg replaced
+    /// the previous one's block).
+    pub(crate) fn update(
+        mempool: Option<WatchedTx>,
+        previous: Option<&WatchedTx>,
+        chain: &Chain,
+        lookup: impl FnOnce() -> Result<Option<WatchedTx>>,
+    ) -> Result<Option<WatchedTx>> {
+        if mempool.is_some() {
+            return Ok(mempool);
+        }
+        if let Some(tx) = previous.filter(|tx| tx.is_confirmed_in(chain)) {
+            return Ok(Some(tx.clone()));
+        }
+        lookup()
+    }
+}
+
+// A subscribed outpoint's status:
+// https://electrum-protocol.readthedocs.io/en/latest/protocol-methods.html#blockchain-outpoint-subscribe
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct OutPointStatus {
+    pub(crate) funding: Option<WatchedTx>,
+    pub(crate) spender: Option<WatchedTx>,
+}
+
+impl OutPointStatus {
+    /// The funding transaction's `height` (if it's known), with the `spender_txhash` and its
+    /// `spender_height` (if the outpoint is spent).
+    pub(crate) fn to_wire(&self) -> OutPointStatusResult {
+        OutPointStatusResult {
+            height: self.funding.as_ref().map(WatchedTx::height),
+            spender_txhash: self.spender.as_ref().map(|spender| spender.txid),
+            spender_height: self.spender.as_ref().map(WatchedTx::height),
+        }
+    }
+
+    /// The outpoint's current spender (see `WatchedTx::update`), where `lookup` returns the
+    /// confirmed spender's txid and height.
+    pub(crate) fn next_spender(
+        &self,
+        mempool_spender: Option<WatchedTx>,
+        chain: &Chain,
+        lookup: impl FnOnce() -> Result<Option<(Txid, usize)>>,
+    ) -> Result<Option<WatchedTx>> {
+        WatchedTx::update(mempool_spender, self.spender.as_ref(), chain, || {
+            Ok(lookup()?.and_then(|(txid, height)| {
+                let blockhash = chain.get_block_hash(height)?;
+                Some(WatchedTx::confirmed(txid, height, blockhash))
+            }))
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::HistoryEntry;
-    use bitcoin::Amount;
+    use super::{
+        compute_status_hash, exclude_confirmed, history_too_large, AsOf, HistoryDelta,
+        HistoryEntry, OutPointStatus, ScriptHashStatus, Totals, TxEntry, TxOutput, UnspentEntry,
+        WatchedTx, MAX_DELTA_ENTRIES,
+    };
+    use crate::cache::Cache;
+    use crate::chain::{Chain, NewHeader};
+    use crate::config::DbProfile;
+    use crate::db::DBStore;
+    use crate::errors::{find_coded, ErrorCode};
+    use crate::index::{Index, MockBlockDaemon};
+    use crate::mempool::Mempool;
+    use crate::metrics::Metrics;
+    use crate::signals::ExitFlag;
+    use crate::types::ScriptHash;
+    use anyhow::{Context, Result};
+    use bitcoin::{
+        absolute::LockTime, hashes::Hash, Amount, BlockHash, Network, OutPoint, Script, ScriptBuf,
+        SignedAmount, Transaction, TxIn, TxOut, Txid,
+    };
     use serde_json::json;
+    use std::collections::HashMap;
+    use std::mem::size_of;
+
+    fn txid(n: u8) -> Txid {
+        format!("{:064x}", n).parse().unwrap()
+    }
+
+    fn funding(txid: Txid, value: u64) -> TxEntry {
+        TxEntry {
+            txid,
+            outputs: vec![TxOutput {
+                index: 0,
+                value: Amount::from_sat(value),
+            }],
+            spent: vec![],
+            pos: None,
+        }
+    }
+
+    /// A transaction funding the given script (made unique by its input)
+    fn funding_tx(script: &Script, n: u8) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(txid(n), 0),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: script.to_owned(),
+            }],
+        }
+    }
+
+    /// Spends the given outpoints, funding a single output (of the same scripthash)
+    fn self_transfer(txid: Txid, spent: Vec<OutPoint>, value: u64) -> TxEntry {
+        TxEntry {
+            spent,
+            ..funding(txid, value)
+        }
+    }
+
+    fn totals(received: u64, sent: u64) -> Totals {
+        Totals {
+            received: Amount::from_sat(received),
+            sent: Amount::from_sat(sent),
+        }
+    }
+
+    fn summary(mut entries: Vec<UnspentEntry>) -> Vec<(usize, Txid, bool)> {
+        entries.sort_by_key(|e| e.tx_hash);
+        entries
+            .into_iter()
+            .map(|e| (e.height, e.tx_hash, e.spent_in_mempool))
+            .collect()
+    }
+
+    fn history(
+        status: &ScriptHashStatus,
+        from: Option<usize>,
+        to: Option<usize>,
+        reverse: bool,
+    ) -> Vec<String> {
+        status
+            .history_iter(&from, &to, reverse)
+            .map(|e| {
+                format!(
+                    "{}@{}",
+                    e.txid.to_string().trim_start_matches('0'),
+                    e.height
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_history_iter() {
+        let mut status = ScriptHashStatus::new(ScriptHash::new(Script::empty()));
+        let fee = Amount::from_sat(100);
+        status.history = vec![
+            HistoryEntry::confirmed(txid(1), 100),
+            HistoryEntry::confirmed(txid(2), 101),
+            HistoryEntry::confirmed(txid(3), 101),
+            HistoryEntry::confirmed(txid(4), 105),
+            HistoryEntry::unconfirmed(txid(5), false, fee),
+            HistoryEntry::unconfirmed(txid(6), true, fee),
+        ];
+
+        let all = vec!["1@100", "2@101", "3@101", "4@105", "5@0", "6@-1"];
+        assert_eq!(history(&status, None, None, false), all);
+        assert_eq!(
+            history(&status, None, None, true),
+            vec!["6@-1", "5@0", "4@105", "3@101", "2@101", "1@100"]
+        );
+        assert_eq!(status.get_history(&None, &None).len(), all.len());
+        let mempool: Vec<Txid> = status.get_mempool().iter().map(|e| e.txid).collect();
+        assert_eq!(mempool, vec![txid(5), txid(6)]);
+
+        // the height range is inclusive, and excludes mempool entries when `from` is set
+        assert_eq!(
+            history(&status, Some(101), Some(104), false),
+            vec!["2@101", "3@101"]
+        );
+        assert_eq!(
+            history(&status, Some(101), Some(104), true),
+            vec!["3@101", "2@101"]
+        );
+        assert_eq!(
+            history(&status, Some(101), None, true),
+            vec!["4@105", "3@101", "2@101"]
+        );
+        assert_eq!(
+            history(&status, None, Some(100), true),
+            vec!["6@-1", "5@0", "1@100"]
+        );
+        assert_eq!(history(&status, Some(0), Some(0), true), vec!["5@0"]);
+        assert!(history(&status, Some(102), Some(104), true).is_empty());
+    }
+
+    /// Replay the notified delta on the client's copy of the history.
+    fn apply(history: &mut Vec<HistoryEntry>, delta: &HistoryDelta) {
+        let expected: Vec<_> = delta.removed.iter().map(HistoryEntry::key).collect();
+        let common = history.len() - expected.len();
+        let removed: Vec<_> = history.drain(common..).map(|e| e.key()).collect();
+        assert_eq!(removed, expected);
+        history.extend(delta.added.iter().cloned());
+    }
+
+    #[test]
+    fn test_history_deltas() {
+        let mut status = ScriptHashStatus::new(ScriptHash::new(Script::empty()));
+        let fee = Amount::from_sat(100);
+        let mut client_history = vec![];
+
+        let funded = vec![HistoryEntry::unconfirmed(txid(1), false, fee)];
+        let confirmation = vec![HistoryEntry::confirmed(txid(1), 101)];
+        let mut unconfirmed = confirmation.clone();
+        unconfirmed.push(HistoryEntry::unconfirmed(txid(2), false, fee));
+        let mut replacement = confirmation.clone();
+        replacement.push(HistoryEntry::unconfirmed(txid(3), true, fee * 2));
+
+        let steps = vec![
+            (funded, 0, 1),
+            (confirmation, 1, 1),
+            (unconfirmed, 0, 1),
+            (replacement, 1, 1), // RBF
+        ];
+        for (history, removed, added) in steps {
+            status.set_history(history);
+            let delta = status.delta().unwrap();
+            assert_eq!((delta.removed.len(), delta.added.len()), (removed, added));
+            apply(&mut client_history, delta);
+            assert_eq!(compute_status_hash(&client_history), status.statushash());
+        }
+        assert_eq!(
+            json!(status.delta()),
+            json!({
+                "removed": [{"tx_hash": txid(2), "height": 0, "fee": 100}],
+                "added": [{"tx_hash": txid(3), "height": -1, "fee": 200}],
+            })
+        );
+
+        // an unchanged history results in an empty delta
+        status.set_history(client_history.clone());
+        let delta = status.delta().unwrap();
+        assert!(delta.removed.is_empty() && delta.added.is_empty());
+
+        // large changes are not notified as deltas
+        let large: Vec<HistoryEntry> = (0..=MAX_DELTA_ENTRIES)
+            .map(|n| HistoryEntry::confirmed(txid(n as u8), 200 + n))
+            .collect();
+        status.set_history(large);
+        assert!(status.delta().is_none());
+    }
+
+    #[test]
+    fn test_outpoint_status() {
+        let mut chain = Chain::new(Network::Regtest);
+        let mut header = *chain.get_block_header(0).unwrap();
+        header.prev_blockhash = chain.tip();
+        chain.update(vec![NewHeader::from((header, 1))]);
+
+        let mut status = OutPointStatus::default();
+        assert_eq!(json!(status.to_wire()), json!({})); // unknown funding transaction
+
+        let funding = WatchedTx::confirmed(txid(1), 1, chain.tip());
+        assert!(funding.is_confirmed_in(&chain));
+        assert_eq!(funding.confirmations(&chain), 1);
+        assert_eq!(funding.height(), 1);
+        status.funding = Some(funding);
+        assert_eq!(json!(status.to_wire()), json!({"height": 1}));
+
+        let spender = WatchedTx::unconfirmed(txid(2), true);
+        assert!(!spender.is_confirmed_in(&chain));
+        assert_eq!(spender.confirmations(&chain), 0);
+        assert_eq!(spender.height(), -1);
+        status.spender = Some(spender);
+        assert_eq!(
+            json!(status.to_wire()),
+            json!({"height": 1, "spender_txhash": txid(2), "spender_height": -1})
+        );
+
+        // replaced by a reorg
+        let stale = WatchedTx::confirmed(txid(1), 1, BlockHash::all_zeros());
+        assert!(!stale.is_confirmed_in(&chain));
+    }
+
+    #[test]
+    fn test_outpoint_spender() {
+        let mut chain = Chain::new(Network::Regtest);
+        let mut header = *chain.get_block_header(0).unwrap();
+        header.prev_blockhash = chain.tip();
+        chain.update(vec![NewHeader::from((header, 1))]);
+        let not_called = || -> Result<Option<(Txid, usize)>> { panic!("unexpected lookup") };
+
+        // spent in the mempool
+        let status = OutPointStatus::default();
+        let mempool_spender = WatchedTx::unconfirmed(txid(2), false);
+        let spender = status.next_spender(Some(mempool_spender.clone()), &chain, not_called);
+        assert_eq!(spender.unwrap(), Some(mempool_spender));
+
+        // spent in a block
+        let spender = status.next_spender(None, &chain, || Ok(Some((txid(2), 1))));
+        let confirmed = WatchedTx::confirmed(txid(2), 1, chain.tip());
+        assert_eq!(spender.unwrap(), Some(confirmed.clone()));
+        let status = OutPointStatus {
+            funding: None,
+            spender: Some(confirmed),
+        };
+        let spender = status.next_spender(None, &chain, not_called).unwrap();
+        assert_eq!(spender, status.spender); // still confirmed by the same block
+
+        // the spending block is replaced by a reorg (without the spend)
+        header.time += 1;
+        chain.update(vec![NewHeader::from((header, 1))]);
+        let spender = status.next_spender(None, &chain, || Ok(None));
+        assert_eq!(spender.unwrap(), None);
+
+        // ... or the spend is confirmed again by the new block
+        let spender = status.next_spender(None, &chain, || Ok(Some((txid(2), 1))));
+        let reconfirmed = WatchedTx::confirmed(txid(2), 1, chain.tip());
+        assert_eq!(spender.unwrap(), Some(reconfirmed));
+
+        // lookup errors are propagated
+        let spender = status.next_spender(None, &chain, || bail!("daemon failed"));
+        assert!(spender.is_err());
+    }
+
+    #[test]
+    fn test_unspent_spent_in_mempool() {
+        let mut chain = Chain::new(Network::Regtest);
+        let mut header = *chain.get_block_header(0).unwrap();
+        header.prev_blockhash = chain.tip();
+        chain.update(vec![NewHeader::from((header, 1))]);
+
+        let mut status = ScriptHashStatus::new(ScriptHash::new(Script::empty()));
+        status.confirmed.insert(
+            chain.tip(),
+            vec![funding(txid(1), 1000), funding(txid(2), 2000)],
+        );
+        let mut spending = funding(txid(3), 500); // spends the first output, with a change
+        spending.spent.push(OutPoint::new(txid(1), 0));
+        status.mempool.push(spending);
+
+        assert_eq!(
+            summary(status.get_unspent(&chain)),
+            vec![(1, txid(2), false), (0, txid(3), false)]
+        );
+        let entries = status
+            .get_unspent_snapshot(&chain)
+            .entries_with_mempool_spent();
+        assert_eq!(
+            summary(entries.clone()),
+            vec![(1, txid(1), true), (1, txid(2), false), (0, txid(3), false)]
+        );
+        let spent = entries.iter().find(|e| e.spent_in_mempool).unwrap();
+        assert_eq!(spent.outpoint(), OutPoint::new(txid(1), 0));
+        assert_eq!(
+            json!(spent),
+            json!({"height": 1, "tx_hash": txid(1), "tx_pos": 0, "value": 1000, "spent_in_mempool": true})
+        );
+        let unspent = entries.iter().find(|e| e.tx_hash == txid(2)).unwrap();
+        assert_eq!(
+            json!(unspent),
+            json!({"height": 1, "tx_hash": txid(2), "tx_pos": 0, "value": 2000})
+        );
+    }
+
+    #[test]
+    fn test_self_transfers() {
+        let mut chain = Chain::new(Network::Regtest);
+        let mut header = *chain.get_block_header(0).unwrap();
+        for height in 1..=2 {
+            header.prev_blockhash = chain.tip();
+            chain.update(vec![NewHeader::from((header, height))]);
+        }
+        let mut status = ScriptHashStatus::new(ScriptHash::new(Script::empty()));
+        let blockhash = |height| chain.get_block_hash(height).unwrap();
+        status
+            .confirmed
+            .insert(blockhash(1), vec![funding(txid(1), 1000)]);
+        let confirmed = self_transfer(txid(2), vec![OutPoint::new(txid(1), 0)], 900);
+        status.confirmed.insert(blockhash(2), vec![confirmed]);
+        let unconfirmed = self_transfer(txid(3), vec![OutPoint::new(txid(2), 0)], 800);
+        status.mempool.push(unconfirmed);
+
+        let history: Vec<(Txid, i64)> = status
+            .get_confirmed_history(&chain)
+            .into_iter()
+            .map(|e| (e.txid, e.height.as_i64()))
+            .collect();
+        assert_eq!(history, vec![(txid(1), 1), (txid(2), 2)]);
+
+        // each self-transfer replaces the spent outpoint by the new one
+        let snapshot = status.get_unspent_snapshot(&chain);
+        assert_eq!(summary(snapshot.entries()), vec![(0, txid(3), false)]);
+        let balance = snapshot.balance();
+        assert_eq!(balance.confirmed_balance, Amount::from_sat(900));
+        assert_eq!(balance.mempool_delta, SignedAmount::from_sat(-100));
+        assert_eq!(snapshot.totals(), totals(1000 + 900 + 800, 1000 + 900));
+        assert_eq!(
+            json!(snapshot.totals()),
+            json!({"received": 2700, "sent": 1900})
+        );
+
+        let snapshot = snapshot.confirmed_only();
+        assert_eq!(summary(snapshot.entries()), vec![(2, txid(2), false)]);
+        assert_eq!(snapshot.totals(), totals(1000 + 900, 1000));
+
+        // a stale mempool view may still contain a confirmed self-transfer
+        let mut mempool = vec![
+            self_transfer(txid(2), vec![OutPoint::new(txid(1), 0)], 900),
+            self_transfer(txid(3), vec![OutPoint::new(txid(2), 0)], 800),
+        ];
+        exclude_confirmed(&mut mempool, &status.get_confirmed_history(&chain));
+        let txids: Vec<Txid> = mempool.iter().map(|e| e.txid).collect();
+        assert_eq!(txids, vec![txid(3)]);
+
+        // even if it's not excluded, its outputs and inputs are counted once
+        status.mempool.insert(
+            0,
+            self_transfer(txid(2), vec![OutPoint::new(txid(1), 0)], 900),
+        );
+        let snapshot = status.get_unspent_snapshot(&chain);
+        assert_eq!(summary(snapshot.entries()), vec![(0, txid(3), false)]);
+        assert_eq!(snapshot.totals(), totals(2700, 1900));
+    }
+
+    #[test]
+    fn test_consolidation() {
+        let mut chain = Chain::new(Network::Regtest);
+        let mut header = *chain.get_block_header(0).unwrap();
+        header.prev_blockhash = chain.tip();
+        chain.update(vec![NewHeader::from((header, 1))]);
+
+        let mut status = ScriptHashStatus::new(ScriptHash::new(Script::empty()));
+        let mut batch = funding(txid(1), 1000);
+        for index in 1..3 {
+            batch.outputs.push(TxOutput {
+                index,
+                value: Amount::from_sat(1000),
+            });
+        }
+        status
+            .confirmed
+            .insert(chain.tip(), vec![batch, funding(txid(2), 2000)]);
+        let spent = vec![
+            OutPoint::new(txid(1), 0),
+            OutPoint::new(txid(1), 1),
+            OutPoint::new(txid(1), 2),
+            OutPoint::new(txid(2), 0),
+        ];
+        status
+            .mempool
+            .push(self_transfer(txid(3), spent.clone(), 4900));
+
+        let snapshot = status.get_unspent_snapshot(&chain);
+        assert_eq!(summary(snapshot.entries()), vec![(0, txid(3), false)]);
+        let spent_entries: Vec<OutPoint> = snapshot
+            .entries_with_mempool_spent()
+            .into_iter()
+            .filter(|e| e.spent_in_mempool)
+            .map(|e| e.outpoint())
+            .collect();
+        assert_eq!(spent_entries.len(), spent.len());
+        assert!(spent
+            .iter()
+            .all(|outpoint| spent_entries.contains(outpoint)));
+        let balance = snapshot.balance();
+        assert_eq!(balance.confirmed_balance, Amount::from_sat(5000));
+        assert_eq!(balance.mempool_delta, SignedAmount::from_sat(-100));
+        assert_eq!(snapshot.totals(), totals(5000 + 4900, 5000));
+
+        // once confirmed (in the same block), the transaction has a single entry
+        let mut confirmed = status.confirmed.remove(&chain.tip()).unwrap();
+        confirmed.push(status.mempool.pop().unwrap());
+        status.confirmed.insert(chain.tip(), confirmed);
+        let history = status.get_confirmed_history(&chain);
+        let txids: Vec<Txid> = history.iter().map(|e| e.txid).collect();
+        assert_eq!(txids, vec![txid(1), txid(2), txid(3)]);
+        let snapshot = status.get_unspent_snapshot(&chain);
+        assert_eq!(summary(snapshot.entries()), vec![(1, txid(3), false)]);
+        assert_eq!(snapshot.balance().confirmed_balance, Amount::from_sat(4900));
+        assert_eq!(snapshot.totals(), totals(5000 + 4900, 5000));
+    }
+
+    /// Deterministic pseudo-random numbers (xorshift64), for generating synthetic histories
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+    }
+
+    /// Append a transaction spending some of the available outputs, and funding new ones.
+    fn random_tx(
+        rng: &mut Rng,
+        n: u32,
+        available: &mut Vec<(OutPoint, u64)>,
+        funded: &mut Vec<(OutPoint, u64)>,
+    ) -> TxEntry {
+        let mut bytes = [0u8; 32];
+        bytes[..4].copy_from_slice(&n.to_le_bytes());
+        let mut entry = TxEntry::new(Txid::from_byte_array(bytes));
+        for _ in 0..rng.below(3) {
+            if !available.is_empty() {
+                let (outpoint, _value) = available.swap_remove(rng.below(available.len()));
+                entry.spent.push(outpoint);
+            }
+        }
+        for index in 0..(rng.below(3) as u32) {
+            let value = 1 + rng.below(10_000) as u64;
+            entry.outputs.push(TxOutput {
+                index,
+                value: Amount::from_sat(value),
+            });
+            funded.push((OutPoint::new(entry.txid, index), value));
+        }
+        entry
+    }
+
+    fn total(entries: &[UnspentEntry]) -> Amount {
+        entries.iter().fold(Amount::ZERO, |acc, e| acc + e.value)
+    }
+
+    #[test]
+    fn test_unspent_snapshot_consistency() {
+        let mut chain = Chain::new(Network::Regtest);
+        let mut header = *chain.get_block_header(0).unwrap();
+        for height in 1..=10 {
+            header.prev_blockhash = chain.tip();
+            chain.update(vec![NewHeader::from((header, height))]);
+        }
+        for seed in 1..=100 {
+            let mut rng = Rng(seed);
+            let mut n = 0;
+            let mut status = ScriptHashStatus::new(ScriptHash::new(Script::empty()));
+            let mut available = vec![];
+            for height in 1..=10 {
+                let mut funded = vec![];
+                let entries = (0..rng.below(4))
+                    .map(|_| {
+                        n += 1;
+                        random_tx(&mut rng, n, &mut available, &mut funded)
+                    })
+                    .collect();
+                available.extend(funded); // spent by the next blocks
+                let blockhash = chain.get_block_hash(height).unwrap();
+                status.confirmed.insert(blockhash, entries);
+            }
+            let confirmed: u64 = available.iter().map(|(_, value)| value).sum();
+            for _ in 0..rng.below(6) {
+                let mut funded = vec![];
+                n += 1;
+                let entry = random_tx(&mut rng, n, &mut available, &mut funded);
+                status.mempool.push(entry);
+                available.extend(funded); // may be spent by the next mempool transactions
+            }
+            let expected: u64 = available.iter().map(|(_, value)| value).sum();
+
+            let snapshot = status.get_unspent_snapshot(&chain);
+            let balance = snapshot.balance();
+            assert_eq!(balance.confirmed_balance, Amount::from_sat(confirmed));
+            let total_balance =
+                balance.confirmed_balance.to_signed().unwrap() + balance.mempool_delta;
+            assert_eq!(
+                total_balance,
+                Amount::from_sat(expected).to_signed().unwrap()
+            );
+
+            // sum(unspent values) = confirmed + unconfirmed balance
+            let entries = snapshot.entries();
+            assert_eq!(entries.len(), available.len());
+            assert_eq!(total(&entries).to_signed().unwrap(), total_balance);
+
+            // the confirmed outputs spent by mempool transactions are part of the confirmed balance
+            let with_spent = snapshot.entries_with_mempool_spent();
+            let (spent, unspent): (Vec<UnspentEntry>, Vec<UnspentEntry>) =
+                with_spent.into_iter().partition(|e| e.spent_in_mempool);
+            assert_eq!(total(&unspent), total(&entries));
+            let confirmed_spent: Vec<UnspentEntry> =
+                spent.into_iter().filter(|e| e.height > 0).collect();
+            let confirmed_unspent: Vec<UnspentEntry> =
+                unspent.into_iter().filter(|e| e.height > 0).collect();
+            assert_eq!(
+                total(&confirmed_spent) + total(&confirmed_unspent),
+                balance.confirmed_balance
+            );
+
+            // ignoring the mempool, the confirmed outputs are restored
+            let snapshot = snapshot.confirmed_only();
+            let balance = snapshot.balance();
+            assert_eq!(balance.confirmed_balance, Amount::from_sat(confirmed));
+            assert_eq!(balance.mempool_delta, SignedAmount::ZERO);
+            let entries = snapshot.entries();
+            assert!(entries.iter().all(|e| e.height > 0));
+            assert_eq!(total(&entries), balance.confirmed_balance);
+        }
+    }
+
+    #[test]
+    fn test_confirmed_txs() {
+        let mut chain = Chain::new(Network::Regtest);
+        let mut header = *chain.get_block_header(0).unwrap();
+        header.prev_blockhash = chain.tip();
+        chain.update(vec![NewHeader::from((header, 1))]);
+
+        let mut status = ScriptHashStatus::new(ScriptHash::new(Script::empty()));
+        let mut spending = funding(txid(2), 500);
+        spending.spent.push(OutPoint::new(txid(1), 0));
+        spending.pos = Some(5);
+        let funded = TxEntry {
+            pos: Some(1),
+            ..funding(txid(1), 1000)
+        };
+        status.confirmed.insert(chain.tip(), vec![funded, spending]);
+        status
+            .confirmed
+            .insert(BlockHash::all_zeros(), vec![funding(txid(3), 100)]); // stale block
+
+        let history: Vec<_> = status
+            .get_confirmed_history(&chain)
+            .iter()
+            .map(|e| (e.txid, e.tx_pos()))
+            .collect();
+        assert_eq!(history, vec![(txid(1), Some(1)), (txid(2), Some(5))]);
+
+        let txs = status.get_confirmed_txs(&chain);
+        assert_eq!(txs.len(), 2);
+        assert_eq!((txs[0].height, txs[0].txid), (1, txid(1)));
+        assert_eq!(
+            txs[0].funding,
+            vec![(OutPoint::new(txid(1), 0), Amount::from_sat(1000))]
+        );
+        assert!(txs[0].spending.is_empty());
+        assert_eq!((txs[1].height, txs[1].txid), (1, txid(2)));
+        assert_eq!(txs[1].spending, &[OutPoint::new(txid(1), 0)]);
+    }
+
+    #[test]
+    fn test_cached_script_pubkey() {
+        use crate::{cache::Cache, metrics::Metrics};
+        use bitcoin::{absolute::LockTime, ScriptBuf, Transaction, TxOut};
+
+        let script_pubkey = ScriptBuf::from(vec![0x51]); // OP_TRUE
+        let tx = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output: vec![
+                TxOut {
+                    value: 1000,
+                    script_pubkey: ScriptBuf::new(),
+                },
+                TxOut {
+                    value: 2000,
+                    script_pubkey: script_pubkey.clone(),
+                },
+            ],
+        };
+        let mut status = ScriptHashStatus::new(ScriptHash::new(&script_pubkey));
+        let mut entry = funding(tx.txid(), 2000);
+        entry.outputs[0].index = 1;
+        status.mempool.push(entry);
+
+        let cache = Cache::new(&Metrics::dummy());
+        assert_eq!(status.cached_script_pubkey(&cache), None); // evicted (or not cached)
+        cache.add_tx(tx.txid(), || tx);
+        assert_eq!(status.cached_script_pubkey(&cache), Some(script_pubkey));
+    }
+
+    #[test]
+    fn test_sync_script_pubkey() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DBStore::open(dir.path(), DbProfile::default(), None, false).unwrap();
+        let metrics = Metrics::dummy();
+        let chain = Chain::new(Network::Regtest);
+        let mut index = Index::load(store, chain, &metrics, 10, None, 0, false).unwrap();
+        let (mempool, cache) = (Mempool::new(&metrics), Cache::new(&metrics));
+        let mut daemon = MockBlockDaemon::new(*index.chain().get_block_header(0).unwrap());
+
+        let script = ScriptBuf::from(vec![0x51]);
+        let tx = funding_tx(&script, 1);
+        let txid = tx.txid();
+        daemon.push(vec![tx]);
+        while !index.sync(&daemon, &ExitFlag::new()).unwrap() {}
+
+        let mut status = ScriptHashStatus::new(ScriptHash::new(&script));
+        let snapshot = status.get_unspent_snapshot(index.chain());
+        assert_eq!(snapshot.script_pubkey(), None);
+        status
+            .sync(&index, &mempool, &daemon, &cache, None)
+            .unwrap();
+        // taken from the funding transaction, cached while syncing
+        assert!(cache.contains_tx(&txid));
+        let snapshot = status.get_unspent_snapshot(index.chain());
+        assert_eq!(snapshot.script_pubkey(), Some(script.as_script()));
+        let entries = snapshot.entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].outpoint(), OutPoint::new(txid, 0));
+    }
+
+    #[test]
+    fn test_memory_usage() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DBStore::open(dir.path(), DbProfile::default(), None, false).unwrap();
+        let metrics = Metrics::dummy();
+        let chain = Chain::new(Network::Regtest);
+        let mut index = Index::load(store, chain, &metrics, 10, None, 0, false).unwrap();
+        let cache = Cache::new(&metrics);
+        let mut daemon = MockBlockDaemon::new(*index.chain().get_block_header(0).unwrap());
+
+        let script = ScriptBuf::from(vec![0x51]);
+        let mut status = ScriptHashStatus::new(ScriptHash::new(&script));
+        let empty = status.memory_usage();
+        assert_eq!(empty, size_of::<ScriptHashStatus>());
+
+        let tx = funding_tx(&script, 1);
+        let funded = OutPoint::new(tx.txid(), 0);
+        daemon.push(vec![tx]);
+        while !index.sync(&daemon, &ExitFlag::new()).unwrap() {}
+        let mempool = Mempool::new(&metrics);
+        status
+            .sync(&index, &mempool, &daemon, &cache, None)
+            .unwrap();
+        let confirmed = status.memory_usage();
+        assert!(confirmed > empty);
+
+        daemon.push(vec![funding_tx(&script, 2), funding_tx(&script, 3)]);
+        while !index.sync(&daemon, &ExitFlag::new()).unwrap() {}
+        assert_eq!(status.memory_usage(), confirmed); // updated only on sync
+        status
+            .sync(&index, &mempool, &daemon, &cache, None)
+            .unwrap();
+        let more_confirmed = status.memory_usage();
+        assert!(more_confirmed > confirmed);
+
+        // an unconfirmed transaction spends one of the funded outputs
+        let spending = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: funded,
+                ..Default::default()
+            }],
+            output: vec![],
+        };
+        let mempool = Mempool::from_txs(vec![spending]);
+        status
+            .sync(&index, &mempool, &daemon, &cache, None)
+            .unwrap();
+        assert!(status.memory_usage() > more_confirmed);
+        assert_eq!(status.memory_usage(), status.compute_memory_usage());
+    }
+
+    #[test]
+    fn test_lagging_status() {
+        let mut chain = Chain::new(Network::Regtest);
+        let genesis = *chain.get_block_header(0).unwrap();
+        let mut header = genesis;
+        for height in 1..=3 {
+            header.prev_blockhash = chain.tip();
+            chain.update(vec![NewHeader::from((header, height))]);
+        }
+        let mut status = ScriptHashStatus::new(ScriptHash::new(Script::empty()));
+        assert_eq!(status.as_of_height(), None);
+        assert!(status.is_lagging(&chain, 10)); // never synced
+
+        // left stale at height 1, while the tip is at height 3
+        status.as_of = Some(AsOf {
+            height: 1,
+            blockhash: chain.get_block_hash(1).unwrap(),
+        });
+        assert_eq!(status.as_of_height(), Some(1));
+        assert!(status.is_lagging(&chain, 0));
+        assert!(status.is_lagging(&chain, 1));
+        assert!(!status.is_lagging(&chain, 2));
+
+        // the block it was synced to is reorged out (at the same height)
+        let mut fork = genesis;
+        fork.prev_blockhash = chain.get_block_hash(0).unwrap();
+        fork.time += 1;
+        chain.update(vec![NewHeader::from((fork, 1))]);
+        assert_eq!(chain.height(), 1);
+        assert!(status.is_lagging(&chain, 2));
+
+        status.as_of = Some(AsOf {
+            height: 1,
+            blockhash: chain.tip(),
+        });
+        assert!(!status.is_lagging(&chain, 0));
+    }
+
+    #[test]
+    fn test_unconfirmed_chain_heights() {
+        let tx = |prev_txid: Txid| Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(prev_txid, 0),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: ScriptBuf::new(),
+            }],
+        };
+        let parent = tx(txid(1)); // spends a confirmed output
+        let child = tx(parent.txid());
+        let grandchild = tx(child.txid());
+        let txids = [parent.txid(), child.txid(), grandchild.txid()];
+        let mempool = Mempool::from_txs(vec![parent, child, grandchild]);
+
+        let mut status = ScriptHashStatus::new(ScriptHash::new(Script::empty()));
+        status.mempool = txids.iter().map(|txid| funding(*txid, 1000)).collect();
+        let history = status.get_mempool_history(&mempool);
+        let heights: HashMap<Txid, i64> = history
+            .iter()
+            .map(|e| (e.txid, e.to_wire().height))
+            .collect();
+        assert_eq!(heights[&txids[0]], 0);
+        assert_eq!(heights[&txids[1]], -1);
+        assert_eq!(heights[&txids[2]], -1);
+        assert_eq!(history[0].txid, txids[0]); // entries with unconfirmed inputs are last
+    }
+
+    #[test]
+    fn test_history_wire_fee() {
+        let history = |fee| {
+            vec![
+                HistoryEntry::confirmed(txid(1), 100),
+                HistoryEntry::unconfirmed(txid(2), false, Amount::from_sat(fee)),
+                HistoryEntry::unconfirmed(txid(3), true, Amount::from_sat(fee)),
+            ]
+        };
+        let wire: Vec<_> = history(123).iter().map(HistoryEntry::to_wire).collect();
+        assert_eq!(
+            json!(wire),
+            json!([
+                {"tx_hash": txid(1), "height": 100},
+                {"tx_hash": txid(2), "height": 0, "fee": 123},
+                {"tx_hash": txid(3), "height": -1, "fee": 123},
+            ])
+        );
+        // the fee isn't part of the status
+        assert_eq!(
+            compute_status_hash(&history(123)),
+            compute_status_hash(&history(456))
+        );
+    }
 
     #[test]
     fn test_txinfo_json() {
@@ -579,4 +2003,63 @@ mod tests {
             json!({"tx_hash": "5b75086dafeede555fc8f9a810d8b10df57c46f9f176ccc3dd8d2fa20edd685b", "height": 0, "fee": 123})
         );
     }
+
+    #[test]
+    fn test_history_too_large() {
+        let message = "history too large (5 entries, limit 3)".to_owned();
+        let err = history_too_large(message, 3).context("failed to build status");
+        let coded = find_coded(&err).unwrap();
+        assert_eq!(coded.code(), ErrorCode::HistoryTooLarge);
+        assert_eq!(
+            coded.to_value(),
+            json!({
+                "code": 10,
+                "message": "history too large (5 entries, limit 3)",
+                "data": {"limit": 3},
+            })
+        );
+    }
+
+    #[test]
+    fn test_max_history_len() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DBStore::open(dir.path(), DbProfile::default(), None, false).unwrap();
+        let metrics = Metrics::dummy();
+        let chain = Chain::new(Network::Regtest);
+        let mut index = Index::load(store, chain, &metrics, 10, None, 0, false).unwrap();
+        let (mempool, cache) = (Mempool::new(&metrics), Cache::new(&metrics));
+
+        let script = ScriptBuf::from(vec![0x51]);
+        let mut daemon = MockBlockDaemon::new(*index.chain().get_block_header(0).unwrap());
+        daemon.push(vec![funding_tx(&script, 1)]);
+        daemon.push(vec![funding_tx(&script, 2), funding_tx(&script, 3)]);
+        while !index.sync(&daemon, &ExitFlag::new()).unwrap() {}
+        let fetched = daemon.fetched();
+
+        let sync = |status: &mut ScriptHashStatus, limit| {
+            status
+                .sync(&index, &mempool, &daemon, &cache, Some(limit))
+                .map_err(|err| {
+                    let coded = find_coded(&err).unwrap();
+                    assert_eq!(coded.code(), ErrorCode::HistoryTooLarge);
+                    coded.to_value()["message"].as_str().unwrap().to_owned()
+                })
+        };
+        let mut status = ScriptHashStatus::new(ScriptHash::new(&script));
+        // more funding blocks than the limit, so no block is fetched
+        assert_eq!(
+            sync(&mut status, 1),
+            Err("history too large (at least 2 entries, limit 1)".to_owned())
+        );
+        assert_eq!(daemon.fetched(), fetched);
+        // more history entries than the limit, found after fetching the blocks
+        assert_eq!(
+            sync(&mut status, 2),
+            Err("history too large (3 entries, limit 2)".to_owned())
+        );
+        assert_eq!(status.history_len(), 0);
+
+        assert_eq!(sync(&mut status, 3), Ok(()));
+        assert_eq!(status.history_len(), 3);
+    }
 }
@@ -0,0 +1,228 @@
+use parking_lot::Mutex;
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::{
+    lock_order::{self, Level},
+    metrics::{Counter, Gauge, Metrics},
+    status::ScriptHashStatus,
+    types::ScriptHash,
+};
+
+#[derive(Default)]
+struct State {
+    statuses: HashMap<ScriptHash, (Arc<ScriptHashStatus>, Instant)>, // with their expiry
+    order: VecDeque<(ScriptHash, Instant)>, // earliest expiry first (including replaced ones)
+}
+
+impl State {
+    fn pop_front(&mut self) {
+        if let Some((scripthash, expiry)) = self.order.pop_front() {
+            // unless it was built again meanwhile
+            if self.statuses.get(&scripthash).map(|(_, e)| e) == Some(&expiry) {
+                self.statuses.remove(&scripthash);
+            }
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((_, expiry)) = self.order.front() {
+            if *expiry > now {
+                break;
+            }
+            self.pop_front();
+        }
+    }
+}
+
+/// Recently built statuses of unsubscribed scripthashes, so clients polling the same scripthash
+/// (without subscribing to it) don't rescan the index on each query.
+///
+/// The statuses are shared by all clients, and are dropped when they expire, when too many are
+/// held (the earliest built ones first), or when the tip or the mempool change (see `clear()`).
+pub(crate) struct StatusCache {
+    ttl: Option<Duration>, // disabled if `None`
+    capacity: usize,
+    state: Mutex<State>,
+    lookups: Counter,
+    gauge: Gauge,
+}
+
+impl StatusCache {
+    pub(crate) fn new(ttl: Option<Duration>, capacity: usize, metrics: &Metrics) -> Self {
+        let lookups = metrics.counter(
+            "status_cache_lookups",
+            "# of unsubscribed scripthash statuses looked up in the cache",
+            "result",
+        );
+        let gauge = metrics.gauge(
+            "status_cache",
+            "# of cached unsubscribed scripthash statuses",
+            "type",
+        );
+        gauge.set("statuses", 0.0);
+        Self {
+            ttl: ttl.filter(|_| capacity > 0),
+            capacity,
+            state: Mutex::new(State::default()),
+            lookups,
+            gauge,
+        }
+    }
+
+    /// The scripthash's cached status (unless expired)
+    pub(crate) fn get(
+        &self,
+        scripthash: &ScriptHash,
+        now: Instant,
+    ) -> Option<Arc<ScriptHashStatus>> {
+        self.ttl?;
+        let _order = lock_order::acquire(Level::StatusCache);
+        let mut state = self.state.lock();
+        state.expire(now);
+        self.update_gauge(&state);
+        let result = state
+            .statuses
+            .get(scripthash)
+            .map(|(status, _)| Arc::clone(status));
+        self.lookups
+            .inc(if result.is_some() { "hits" } else { "misses" });
+        result
+    }
+
+    /// Whether the scripthash's status is cached (without counting it as a lookup)
+    pub(crate) fn contains(&self, scripthash: &ScriptHash, now: Instant) -> bool {
+        if self.ttl.is_none() {
+            return false;
+        }
+        let _order = lock_order::acquire(Level::StatusCache);
+        let state = self.state.lock();
+        state
+            .statuses
+            .get(scripthash)
+            .map_or(false, |(_, expiry)| *expiry > now)
+    }
+
+    /// Cache a newly built status for the configured TTL (if enabled).
+    pub(crate) fn insert(
+        &self,
+        scripthash: ScriptHash,
+        status: ScriptHashStatus,
+        now: Instant,
+    ) -> Arc<ScriptHashStatus> {
+        let status = Arc::new(status);
+        let ttl = match self.ttl {
+            Some(ttl) => ttl,
+            None => return status,
+        };
+        let expiry = now + ttl;
+        let _order = lock_order::acquire(Level::StatusCache);
+        let mut state = self.state.lock();
+        state.expire(now);
+        state
+            .statuses
+            .insert(scripthash, (Arc::clone(&status), expiry));
+        state.order.push_back((scripthash, expiry));
+        while state.statuses.len() > self.capacity {
+            state.pop_front();
+        }
+        self.update_gauge(&state);
+        status
+    }
+
+    /// Drop all cached statuses (e.g. after a new block or mempool transactions).
+    pub(crate) fn clear(&self) {
+        if self.ttl.is_none() {
+            return;
+        }
+        let _order = lock_order::acquire(Level::StatusCache);
+        let mut state = self.state.lock();
+        *state = State::default();
+        self.update_gauge(&state);
+    }
+
+    fn update_gauge(&self, state: &State) {
+        self.gauge.set("statuses", state.statuses.len() as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StatusCache;
+    use crate::{metrics::Metrics, status::ScriptHashStatus, types::ScriptHash};
+    use bitcoin::ScriptBuf;
+    use std::time::{Duration, Instant};
+
+    fn scripthash(i: u8) -> ScriptHash {
+        ScriptHash::new(&ScriptBuf::from(vec![i]))
+    }
+
+    fn insert(cache: &StatusCache, i: u8, now: Instant) {
+        cache.insert(scripthash(i), ScriptHashStatus::new(scripthash(i)), now);
+    }
+
+    fn cached(cache: &StatusCache, indices: &[u8], now: Instant) -> Vec<u8> {
+        indices
+            .iter()
+            .copied()
+            .filter(|i| cache.get(&scripthash(*i), now).is_some())
+            .collect()
+    }
+
+    #[test]
+    fn test_expiry() {
+        let ttl = Duration::from_secs(5);
+        let cache = StatusCache::new(Some(ttl), 10, &Metrics::dummy());
+        let start = Instant::now();
+        insert(&cache, 0, start);
+        insert(&cache, 1, start + Duration::from_secs(3));
+        assert_eq!(cached(&cache, &[0, 1, 2], start), vec![0, 1]);
+        assert!(cache.contains(&scripthash(0), start));
+
+        // rebuilt statuses expire later
+        insert(&cache, 0, start + Duration::from_secs(4));
+        assert_eq!(cached(&cache, &[0, 1], start + ttl), vec![0, 1]);
+        assert_eq!(
+            cached(&cache, &[0, 1], start + Duration::from_secs(8)),
+            vec![0]
+        );
+        assert!(cached(&cache, &[0, 1], start + Duration::from_secs(9)).is_empty());
+    }
+
+    #[test]
+    fn test_capacity() {
+        let cache = StatusCache::new(Some(Duration::from_secs(5)), 3, &Metrics::dummy());
+        let now = Instant::now();
+        for i in 0..5 {
+            insert(&cache, i, now);
+        }
+        // the earliest built statuses are dropped
+        assert_eq!(cached(&cache, &[0, 1, 2, 3, 4], now), vec![2, 3, 4]);
+        assert_eq!(cache.state.lock().statuses.len(), 3);
+    }
+
+    #[test]
+    fn test_clear() {
+        let cache = StatusCache::new(Some(Duration::from_secs(5)), 10, &Metrics::dummy());
+        let now = Instant::now();
+        insert(&cache, 0, now);
+        cache.clear();
+        assert!(cached(&cache, &[0], now).is_empty());
+        assert!(cache.state.lock().order.is_empty());
+    }
+
+    #[test]
+    fn test_disabled() {
+        let now = Instant::now();
+        for cache in &[
+            StatusCache::new(None, 10, &Metrics::dummy()),
+            StatusCache::new(Some(Duration::from_secs(5)), 0, &Metrics::dummy()),
+        ] {
+            insert(cache, 0, now);
+            assert!(cached(cache, &[0], now).is_empty());
+            assert!(!cache.contains(&scripthash(0), now));
+        }
+    }
+}
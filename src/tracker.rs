@@ -1,25 +1,34 @@
 use anyhow::{Context, Result};
-use bitcoin::{BlockHash, Transaction, Txid};
+use bitcoin::{BlockHash, OutPoint, Transaction, Txid};
+
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
 
 use crate::{
     cache::Cache,
     chain::Chain,
     config::Config,
+    consistency,
     daemon::Daemon,
     db::DBStore,
-    index::Index,
-    mempool::{FeeHistogram, Mempool},
+    index::{BlockDaemon, BlockEvent, Index},
+    mempool::{Entry, FeeHistogram, Mempool, PackageStats},
     metrics::Metrics,
+    orphans::Orphans,
+    protocol::OrphanedEntry,
     signals::ExitFlag,
-    status::{Balance, ScriptHashStatus, UnspentEntry},
+    status::{OutPointStatus, ScriptHashStatus, UnspentEntry, UnspentSnapshot, WatchedTx},
+    types::ScriptHash,
 };
 
 /// Electrum protocol subscriptions' tracker
 pub struct Tracker {
     index: Index,
     mempool: Mempool,
+    orphans: Orphans,
     metrics: Metrics,
     ignore_mempool: bool,
+    max_history_len: Option<usize>, // of the newly built statuses
 }
 
 pub(crate) enum Error {
@@ -27,8 +36,22 @@ pub(crate) enum Error {
 }
 
 impl Tracker {
-    pub fn new(config: &Config, metrics: Metrics) -> Result<Self> {
-        let store = DBStore::open(&config.db_path, config.auto_reindex)?;
+    pub fn new(config: &Config, metrics: Metrics, daemon: &Daemon) -> Result<Self> {
+        let store = DBStore::open(
+            &config.db_path,
+            config.db_profile,
+            config.db_memory_limit,
+            config.auto_reindex,
+        )?;
+        let store = consistency::check(
+            store,
+            config.network,
+            daemon,
+            config.force_reindex_on_mismatch,
+        )?
+        .set_txid_prefix_index(config.txid_prefix_index, config.auto_reindex)?
+        .set_spender_index(config.spender_index, config.auto_reindex)?;
+        store.set_block_txids_index(config.block_txids_index);
         let chain = Chain::new(config.network);
         Ok(Self {
             index: Index::load(
@@ -38,11 +61,14 @@ impl Tracker {
                 config.index_batch_size,
                 config.index_lookup_limit,
                 config.reindex_last_blocks,
+                config.block_txids_subscription,
             )
             .context("failed to open index")?,
             mempool: Mempool::new(&metrics),
+            orphans: Orphans::default(),
             metrics,
             ignore_mempool: config.ignore_mempool,
+            max_history_len: config.max_history_len,
         })
     }
 
@@ -62,15 +88,144 @@ impl Tracker {
         status.get_unspent(self.index.chain())
     }
 
+    /// Both the unspent outputs and the balance of a query should be derived from it.
+    pub(crate) fn get_unspent_snapshot(&self, status: &ScriptHashStatus) -> UnspentSnapshot {
+        status.get_unspent_snapshot(self.index.chain())
+    }
+
+    /// Drop the entries spent by transactions in the current mempool, since a status may predate
+    /// them (otherwise, clients racing each other may build conflicting transactions).
+    pub(crate) fn exclude_mempool_spent(&self, entries: &mut Vec<UnspentEntry>) {
+        entries.retain(|utxo| self.mempool.filter_by_spending(&utxo.outpoint()).is_empty());
+    }
+
+    /// Whether the scripthash was ever funded (by a confirmed or a mempool transaction), without
+    /// loading its history. May return a false positive, since the index stores hash prefixes.
+    pub(crate) fn is_used(&self, scripthash: ScriptHash) -> bool {
+        self.index.filter_by_funding(scripthash).next().is_some()
+            || !self.mempool.filter_by_funding(&scripthash).is_empty()
+    }
+
+    /// Confirmed transactions matching a txid hex prefix (see `Index::search_txid_prefix`)
+    pub(crate) fn search_txid_prefix<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> Result<impl Iterator<Item = (Txid, usize)> + 'a> {
+        self.index.search_txid_prefix(prefix)
+    }
+
+    /// Whether the mempool is synced (it's synced only after the index)
+    pub(crate) fn is_mempool_synced(&self) -> bool {
+        !self.ignore_mempool && self.index.is_ready() && self.mempool.is_loaded()
+    }
+
+    /// Whether the initial mempool load is in progress
+    pub(crate) fn is_mempool_partial(&self) -> bool {
+        !self.ignore_mempool && !self.mempool.is_loaded()
+    }
+
+    /// The number of mempool transactions, and when they were last listed
+    pub(crate) fn mempool_view(&self) -> (usize, Option<Instant>) {
+        (self.mempool.size(), self.mempool.listed())
+    }
+
+    pub(crate) fn daemon_height(&self) -> Option<usize> {
+        self.index.daemon_height()
+    }
+
+    pub(crate) fn mempool_version(&self) -> u64 {
+        self.mempool.version()
+    }
+
+    pub(crate) fn mempool_contains(&self, txid: &Txid) -> bool {
+        self.mempool.get(txid).is_some()
+    }
+
+    pub(crate) fn mempool_entry(&self, txid: &Txid) -> Option<&Entry> {
+        self.mempool.get(txid)
+    }
+
+    /// The minimal fee rate (in sat/vB) for entering the mempool
+    pub(crate) fn mempool_min_fee_rate(&self) -> Option<f64> {
+        self.mempool.min_fee_rate()
+    }
+
+    /// Ancestor fee rate (in sat/vB) of a mempool transaction
+    pub(crate) fn ancestor_fee_rate(&self, txid: &Txid) -> Option<f64> {
+        self.mempool
+            .get(txid)
+            .map(|entry| entry.ancestor_fee_rate())
+    }
+
+    /// Unconfirmed ancestors' stats of a mempool transaction
+    pub(crate) fn package_stats(&self, txid: &Txid) -> Option<PackageStats> {
+        self.mempool.get(txid).map(Entry::package_stats)
+    }
+
     pub(crate) fn sync(&mut self, daemon: &Daemon, exit_flag: &ExitFlag) -> Result<bool> {
         let done = self.index.sync(daemon, exit_flag)?;
+        self.record_orphans(daemon);
         if done && !self.ignore_mempool {
-            self.mempool.sync(daemon);
+            // a large mempool is loaded in batches, so keep syncing until it's fully loaded
+            let loaded = self.mempool.sync(daemon);
             // TODO: double check tip - and retry on diff
+            return Ok(loaded);
         }
         Ok(done)
     }
 
+    /// Record the transactions of the newly orphaned blocks (if bitcoind still has them)
+    fn record_orphans(&mut self, daemon: &Daemon) {
+        let chain = self.index.chain();
+        let tip_height = chain.height();
+        let orphans = &mut self.orphans;
+        orphans.prune(tip_height);
+        let new_blocks: Vec<(BlockHash, usize)> = chain
+            .stale_blocks()
+            .filter(|(blockhash, height)| orphans.is_new(blockhash, *height, tip_height))
+            .collect();
+        for (blockhash, height) in new_blocks {
+            orphans.add_block(blockhash, height); // not to be fetched again
+            match daemon.get_block(blockhash) {
+                Ok(block) => orphans.add_txs(blockhash, height, &block),
+                Err(e) => warn!("failed to record orphaned block: {:#}", e),
+            }
+        }
+    }
+
+    /// The transactions of the recently orphaned blocks (funding the scripthash, if specified),
+    /// and whether they were confirmed again (or are in the mempool)
+    pub(crate) fn orphaned(&self, scripthash: Option<ScriptHash>) -> Vec<OrphanedEntry> {
+        let chain = self.index.chain();
+        self.orphans
+            .iter(scripthash)
+            .map(|(txid, blockhash, height)| OrphanedEntry {
+                tx_hash: txid,
+                block_hash: blockhash,
+                height,
+                // txids are indexed by their prefix, so this may rarely be a false positive
+                confirmed_height: self
+                    .index
+                    .filter_by_txid(txid)
+                    .find_map(|blockhash| chain.get_block_height(&blockhash)),
+                in_mempool: self.mempool.get(&txid).is_some(),
+            })
+            .collect()
+    }
+
+    /// The orphaned block which contained the transaction, if it's now confirmed at another height
+    pub(crate) fn reorged_from(&self, txid: &Txid, height: usize) -> Option<BlockHash> {
+        self.orphans.reorged_from(txid, height)
+    }
+
+    pub(crate) fn has_orphans(&self) -> bool {
+        !self.orphans.is_empty()
+    }
+
+    pub(crate) fn take_block_events(&mut self) -> Vec<BlockEvent> {
+        self.index.take_block_events()
+    }
+
     pub(crate) fn status(&self) -> Result<(), Error> {
         if self.index.is_ready() {
             return Ok(());
@@ -81,16 +236,25 @@ impl Tracker {
     pub(crate) fn update_scripthash_status(
         &self,
         status: &mut ScriptHashStatus,
-        daemon: &Daemon,
+        daemon: &dyn BlockDaemon,
         cache: &Cache,
     ) -> Result<bool> {
         let prev_statushash = status.statushash();
-        status.sync(&self.index, &self.mempool, daemon, cache)?;
+        status.sync(&self.index, &self.mempool, daemon, cache, None)?;
         Ok(prev_statushash != status.statushash())
     }
 
-    pub(crate) fn get_balance(&self, status: &ScriptHashStatus) -> Balance {
-        status.get_balance(self.chain())
+    /// Build a new scripthash status, unless its history is longer than `max_history_len` (while
+    /// the already built statuses keep being updated, e.g. for existing subscriptions).
+    pub(crate) fn new_scripthash_status(
+        &self,
+        scripthash: ScriptHash,
+        daemon: &dyn BlockDaemon,
+        cache: &Cache,
+    ) -> Result<ScriptHashStatus> {
+        let mut status = ScriptHashStatus::new(scripthash);
+        status.sync(&self.index, &self.mempool, daemon, cache, self.max_history_len)?;
+        Ok(status)
     }
 
     pub(crate) fn lookup_transaction(
@@ -114,4 +278,277 @@ impl Tracker {
         })?;
         Ok(result)
     }
+
+    /// Look up several transactions, fetching each candidate block once (even if it confirms
+    /// more than one of them). Unconfirmed (or unknown) transactions are missing from the result.
+    pub(crate) fn lookup_transactions(
+        &self,
+        daemon: &Daemon,
+        txids: &[Txid],
+    ) -> Result<HashMap<Txid, (BlockHash, Transaction)>> {
+        let wanted: HashSet<Txid> = txids.iter().copied().collect();
+        let blockhashes: HashSet<BlockHash> = wanted
+            .iter()
+            .flat_map(|txid| self.index.filter_by_txid(*txid))
+            .collect();
+        let mut result = HashMap::with_capacity(wanted.len());
+        daemon.for_blocks(blockhashes, |blockhash, block| {
+            for tx in block.txdata {
+                let txid = tx.txid();
+                if wanted.contains(&txid) {
+                    // see the BIP-30 note above (the first matching block is used)
+                    result.entry(txid).or_insert((blockhash, tx));
+                }
+            }
+        })?;
+        Ok(result)
+    }
+
+    /// Look up the outpoint's funding and spending transactions (the confirmed ones only if they
+    /// were not confirmed before, or were reorged out), returning whether its status has changed.
+    pub(crate) fn update_outpoint_status(
+        &self,
+        outpoint: OutPoint,
+        status: &mut OutPointStatus,
+        daemon: &Daemon,
+    ) -> Result<bool> {
+        let chain = self.index.chain();
+        let funding = self.lookup_watched_tx(outpoint.txid, status.funding.as_ref(), daemon)?;
+        let mempool_spender = self
+            .mempool
+            .filter_by_spending(&outpoint)
+            .first()
+            .map(|entry| {
+                WatchedTx::unconfirmed(entry.txid, self.mempool.has_unconfirmed_inputs(entry))
+            });
+        let spender = status.next_spender(mempool_spender, chain, || {
+            self.lookup_spender(daemon, outpoint)
+        })?;
+        let new_status = OutPointStatus { funding, spender };
+        let changed = *status != new_status;
+        *status = new_status;
+        Ok(changed)
+    }
+
+    /// Look up the subscribed transaction (if it wasn't confirmed before, or was reorged out),
+    /// returning whether its status has changed.
+    pub(crate) fn update_tx_status(
+        &self,
+        txid: Txid,
+        status: &mut Option<WatchedTx>,
+        daemon: &Daemon,
+    ) -> Result<bool> {
+        let new_status = self.lookup_watched_tx(txid, status.as_ref(), daemon)?;
+        let changed = *status != new_status;
+        *status = new_status;
+        Ok(changed)
+    }
+
+    /// The transaction's current state, unless it's unknown. Confirmed transactions are fetched
+    /// only if they were not confirmed by the `previous` state's block.
+    fn lookup_watched_tx(
+        &self,
+        txid: Txid,
+        previous: Option<&WatchedTx>,
+        daemon: &Daemon,
+    ) -> Result<Option<WatchedTx>> {
+        let unconfirmed = self
+            .mempool
+            .get(&txid)
+            .map(|entry| WatchedTx::unconfirmed(txid, self.mempool.has_unconfirmed_inputs(entry)));
+        let chain = self.index.chain();
+        WatchedTx::update(unconfirmed, previous, chain, || {
+            Ok(self
+                .lookup_transaction(daemon, txid)?
+                .and_then(|(blockhash, _tx)| {
+                    let height = chain.get_block_height(&blockhash)?;
+                    Some(WatchedTx::confirmed(txid, height, blockhash))
+                }))
+        })
+    }
+
+    /// A confirmed block's txids (in their block order), from the block txids index if enabled.
+    /// Blocks indexed without their txids are fetched from bitcoind, and backfilled.
+    pub(crate) fn get_block_txids(
+        &self,
+        daemon: &Daemon,
+        blockhash: BlockHash,
+    ) -> Result<Vec<Txid>> {
+        if let Some(txids) = self.index.get_block_txids(blockhash) {
+            return Ok(txids);
+        }
+        let txids = daemon.get_block_txids(blockhash)?;
+        if let Err(e) = self.index.add_block_txids(blockhash, &txids) {
+            warn!("failed to backfill block {} txids: {:#}", blockhash, e);
+        }
+        Ok(txids)
+    }
+
+    /// The transaction spending the outpoint (with its height, or 0 if it's in the mempool)
+    pub(crate) fn lookup_spender(
+        &self,
+        daemon: &Daemon,
+        outpoint: OutPoint,
+    ) -> Result<Option<(Txid, usize)>> {
+        if let Some(entry) = self.mempool.filter_by_spending(&outpoint).first() {
+            return Ok(Some((entry.txid, 0)));
+        }
+        if self.index.has_spender_index() {
+            return self.index.lookup_spender(outpoint);
+        }
+        // Note: the index may return false positives (matching only the outpoint's prefix)
+        let blockhashes = self.index.filter_by_spending(outpoint);
+        let chain = self.index.chain();
+        let mut result = None;
+        daemon.for_blocks(blockhashes, |blockhash, block| {
+            if result.is_some() {
+                return;
+            }
+            let spender = block
+                .txdata
+                .iter()
+                .find(|tx| tx.input.iter().any(|txin| txin.previous_output == outpoint));
+            if let Some(tx) = spender {
+                let height = chain.get_block_height(&blockhash).expect("missing block");
+                result = Some((tx.txid(), height));
+            }
+        })?;
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Tracker;
+    use crate::{
+        cache::Cache,
+        chain::Chain,
+        config::DbProfile,
+        db::DBStore,
+        errors::{find_coded, ErrorCode},
+        index::{Index, MockBlockDaemon},
+        mempool::Mempool,
+        metrics::Metrics,
+        orphans::Orphans,
+        signals::ExitFlag,
+        status::UnspentEntry,
+        types::ScriptHash,
+    };
+    use bitcoin::{
+        absolute::LockTime, Network, OutPoint, Script, ScriptBuf, Transaction, TxIn, TxOut,
+    };
+    use std::collections::HashSet;
+    use std::iter::FromIterator;
+    use std::path::Path;
+
+    fn new_tracker(dir: &Path, max_history_len: Option<usize>) -> (Tracker, MockBlockDaemon) {
+        let store = DBStore::open(dir, DbProfile::default(), None, false).unwrap();
+        let metrics = Metrics::dummy();
+        let chain = Chain::new(Network::Regtest);
+        let index = Index::load(store, chain, &metrics, 10, None, 0, false).unwrap();
+        let daemon = MockBlockDaemon::new(*index.chain().get_block_header(0).unwrap());
+        let tracker = Tracker {
+            index,
+            mempool: Mempool::new(&metrics),
+            orphans: Orphans::default(),
+            metrics,
+            ignore_mempool: false,
+            max_history_len,
+        };
+        (tracker, daemon)
+    }
+
+    fn funding_tx(script: &Script, n: u32) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: LockTime::from_consensus(n),
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: script.to_owned(),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_max_history_len() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut tracker, mut daemon) = new_tracker(dir.path(), Some(2));
+        let cache = Cache::new(&Metrics::dummy());
+
+        let script = ScriptBuf::from(vec![0x51]);
+        let scripthash = ScriptHash::new(&script);
+        daemon.push(vec![funding_tx(&script, 1)]);
+        daemon.push(vec![funding_tx(&script, 2)]);
+        while !tracker.index.sync(&daemon, &ExitFlag::new()).unwrap() {}
+        let mut subscribed = tracker
+            .new_scripthash_status(scripthash, &daemon, &cache)
+            .unwrap();
+        assert_eq!(subscribed.history_len(), 2);
+
+        // the history grows beyond the limit
+        daemon.push(vec![funding_tx(&script, 3)]);
+        while !tracker.index.sync(&daemon, &ExitFlag::new()).unwrap() {}
+        let err = tracker
+            .new_scripthash_status(scripthash, &daemon, &cache)
+            .unwrap_err();
+        assert_eq!(find_coded(&err).unwrap().code(), ErrorCode::HistoryTooLarge);
+        // while the already built statuses (e.g. subscribed ones) keep being updated
+        assert!(tracker
+            .update_scripthash_status(&mut subscribed, &daemon, &cache)
+            .unwrap());
+        assert_eq!(subscribed.history_len(), 3);
+    }
+
+    #[test]
+    fn test_exclude_mempool_spent() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut tracker, mut daemon) = new_tracker(dir.path(), None);
+        let cache = Cache::new(&Metrics::dummy());
+
+        let script = ScriptBuf::from(vec![0x51]);
+        let scripthash = ScriptHash::new(&script);
+        let txs = vec![funding_tx(&script, 1), funding_tx(&script, 2)];
+        let spent = OutPoint::new(txs[0].txid(), 0);
+        let unspent = OutPoint::new(txs[1].txid(), 0);
+        daemon.push(txs);
+        while !tracker.index.sync(&daemon, &ExitFlag::new()).unwrap() {}
+        let status = tracker
+            .new_scripthash_status(scripthash, &daemon, &cache)
+            .unwrap();
+
+        // an unconfirmed transaction spends one of the confirmed UTXOs (to another script)
+        let spending_tx = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: spent,
+                ..TxIn::default()
+            }],
+            output: vec![TxOut {
+                value: 900,
+                script_pubkey: ScriptBuf::from(vec![0x52]),
+            }],
+        };
+        tracker.mempool = Mempool::from_txs(vec![spending_tx]);
+        let outpoints = |entries: &[UnspentEntry]| -> HashSet<OutPoint> {
+            entries.iter().map(UnspentEntry::outpoint).collect()
+        };
+
+        // the status was synced before the spending transaction was seen
+        let mut entries = tracker.get_unspent_snapshot(&status).entries();
+        assert_eq!(
+            outpoints(&entries),
+            HashSet::from_iter(vec![spent, unspent])
+        );
+        tracker.exclude_mempool_spent(&mut entries);
+        assert_eq!(outpoints(&entries), HashSet::from_iter(vec![unspent]));
+
+        // a newly synced status doesn't contain it in the first place
+        let status = tracker
+            .new_scripthash_status(scripthash, &daemon, &cache)
+            .unwrap();
+        let entries = tracker.get_unspent(&status);
+        assert_eq!(outpoints(&entries), HashSet::from_iter(vec![unspent]));
+    }
 }
@@ -0,0 +1,375 @@
+use bitcoin::{
+    blockdata::{opcodes, script::Instruction},
+    consensus::encode::serialize_hex,
+    secp256k1::XOnlyPublicKey,
+    Address, Amount, Network, PublicKey, Script, Transaction, TxIn,
+};
+use serde_json::{json, Map, Value};
+
+use crate::{descriptor::with_checksum, hex};
+
+/// Larger scripts are unspendable (as are the ones starting with `OP_RETURN`)
+const MAX_SCRIPT_SIZE: usize = 10_000;
+
+const OP_PUSHNUM_NEG1: u8 = 0x4f;
+const OP_PUSHNUM_1: u8 = 0x51;
+const OP_PUSHNUM_16: u8 = 0x60;
+const OP_RETURN: u8 = 0x6a;
+const OP_CHECKMULTISIG: u8 = 0xae;
+const OP_CHECKLOCKTIMEVERIFY: u8 = 0xb1;
+const OP_CHECKSEQUENCEVERIFY: u8 = 0xb2;
+const OP_CHECKSIGADD: u8 = 0xba;
+const OP_INVALIDOPCODE: u8 = 0xff;
+
+/// A transaction's fields, as returned by bitcoind's verbose `getrawtransaction` (excluding the
+/// block-related ones), or `None` if an output's descriptor can't be inferred the way bitcoind
+/// does it (e.g. for bare multisig outputs), so the transaction should be fetched from bitcoind.
+pub(crate) fn transaction_info(tx: &Transaction, network: Network) -> Option<Value> {
+    let coinbase = tx.is_coin_base();
+    let vin: Vec<Value> = tx
+        .input
+        .iter()
+        .map(|txin| input_info(txin, coinbase))
+        .collect();
+    let vout = tx
+        .output
+        .iter()
+        .enumerate()
+        .map(|(n, txout)| {
+            Some(json!({
+                "value": Amount::from_sat(txout.value).to_btc(),
+                "n": n,
+                "scriptPubKey": script_pubkey_info(&txout.script_pubkey, network)?,
+            }))
+        })
+        .collect::<Option<Vec<Value>>>()?;
+    Some(json!({
+        "txid": tx.txid(),
+        "hash": tx.wtxid(),
+        "version": tx.version,
+        "size": tx.size(),
+        "vsize": tx.vsize(),
+        "weight": tx.weight().to_wu(),
+        "locktime": tx.lock_time.to_consensus_u32(),
+        "vin": vin,
+        "vout": vout,
+        "hex": serialize_hex(tx),
+    }))
+}
+
+fn input_info(txin: &TxIn, coinbase: bool) -> Value {
+    let mut info = Map::new();
+    if coinbase {
+        info.insert(
+            "coinbase".to_owned(),
+            json!(hex::encode(txin.script_sig.as_bytes())),
+        );
+    } else {
+        info.insert("txid".to_owned(), json!(txin.previous_output.txid));
+        info.insert("vout".to_owned(), json!(txin.previous_output.vout));
+        info.insert(
+            "scriptSig".to_owned(),
+            json!({
+                "asm": script_asm(&txin.script_sig, true),
+                "hex": hex::encode(txin.script_sig.as_bytes()),
+            }),
+        );
+    }
+    if !txin.witness.is_empty() {
+        let items: Vec<String> = txin.witness.iter().map(hex::encode).collect();
+        info.insert("txinwitness".to_owned(), json!(items));
+    }
+    info.insert("sequence".to_owned(), json!(txin.sequence.0));
+    Value::Object(info)
+}
+
+/// The output script's fields (named and typed as by bitcoind), including its inferred descriptor
+fn script_pubkey_info(script: &Script, network: Network) -> Option<Value> {
+    let bytes = script.as_bytes();
+    let raw = || with_checksum(&format!("raw({})", hex::encode(bytes)));
+    let mut address = None;
+    let (script_type, desc) = if script.is_p2pk() {
+        let key = &bytes[1..bytes.len() - 1];
+        PublicKey::from_slice(key).ok()?; // bitcoind may not infer a descriptor for invalid keys
+        (
+            "pubkey",
+            with_checksum(&format!("pk({})", hex::encode(key))),
+        )
+    } else if let Ok(addr) = Address::from_script(script, network) {
+        let script_type = if script.is_p2pkh() {
+            "pubkeyhash"
+        } else if script.is_p2sh() {
+            "scripthash"
+        } else if script.is_v0_p2wpkh() {
+            "witness_v0_keyhash"
+        } else if script.is_v0_p2wsh() {
+            "witness_v0_scripthash"
+        } else if script.is_v1_p2tr() {
+            "witness_v1_taproot"
+        } else {
+            "witness_unknown"
+        };
+        // bitcoind (since v24) infers `rawtr(...)` descriptors for valid taproot output keys
+        let desc = if script.is_v1_p2tr() && XOnlyPublicKey::from_slice(&bytes[2..]).is_ok() {
+            format!("rawtr({})", hex::encode(&bytes[2..]))
+        } else {
+            format!("addr({})", addr)
+        };
+        address = Some(addr.to_string());
+        (script_type, with_checksum(&desc))
+    } else if bytes.last() == Some(&OP_CHECKMULTISIG) {
+        return None; // bitcoind infers a `multi(...)` descriptor for valid bare multisig outputs
+    } else if bytes.first() == Some(&OP_RETURN) && is_push_only(&bytes[1..]) {
+        ("nulldata", raw())
+    } else {
+        ("nonstandard", raw())
+    };
+    let mut info = Map::new();
+    info.insert("asm".to_owned(), json!(script_asm(script, false)));
+    info.insert("desc".to_owned(), json!(desc));
+    info.insert("hex".to_owned(), json!(hex::encode(bytes)));
+    if let Some(address) = address {
+        info.insert("address".to_owned(), json!(address));
+    }
+    info.insert("type".to_owned(), json!(script_type));
+    Some(Value::Object(info))
+}
+
+fn is_push_only(bytes: &[u8]) -> bool {
+    Script::from_bytes(bytes)
+        .instructions()
+        .all(|instruction| match instruction {
+            Ok(Instruction::PushBytes(_)) => true,
+            Ok(Instruction::Op(op)) => op.to_u8() <= OP_PUSHNUM_16,
+            Err(_) => false,
+        })
+}
+
+/// Disassemble the script as bitcoind does: short pushes are shown as numbers, and signatures'
+/// sighash types are decoded (if requested, for input scripts).
+fn script_asm(script: &Script, decode_sighash: bool) -> String {
+    let bytes = script.as_bytes();
+    let unspendable = bytes.first() == Some(&OP_RETURN) || bytes.len() > MAX_SCRIPT_SIZE;
+    let mut parts = vec![];
+    for instruction in script.instructions() {
+        match instruction {
+            Ok(Instruction::PushBytes(push)) => {
+                let data = push.as_bytes();
+                if data.len() <= 4 {
+                    parts.push(script_num(data).to_string());
+                } else if decode_sighash && !unspendable {
+                    parts.push(signature_asm(data));
+                } else {
+                    parts.push(hex::encode(data));
+                }
+            }
+            Ok(Instruction::Op(op)) => parts.push(op_name(op)),
+            Err(_) => {
+                parts.push("[error]".to_owned());
+                break;
+            }
+        }
+    }
+    parts.join(" ")
+}
+
+fn op_name(op: opcodes::All) -> String {
+    match op.to_u8() {
+        OP_PUSHNUM_NEG1 => "-1".to_owned(),
+        n @ OP_PUSHNUM_1..=OP_PUSHNUM_16 => (n - OP_PUSHNUM_1 + 1).to_string(),
+        OP_CHECKLOCKTIMEVERIFY => "OP_CHECKLOCKTIMEVERIFY".to_owned(),
+        OP_CHECKSEQUENCEVERIFY => "OP_CHECKSEQUENCEVERIFY".to_owned(),
+        n if n > OP_CHECKSIGADD && n < OP_INVALIDOPCODE => "OP_UNKNOWN".to_owned(),
+        _ => op.to_string(),
+    }
+}
+
+/// Decode a (non-minimally encoded) script number
+fn script_num(data: &[u8]) -> i64 {
+    let mut result = data
+        .iter()
+        .rev()
+        .fold(0i64, |acc, byte| (acc << 8) | i64::from(*byte));
+    match data.last() {
+        Some(last) if last & 0x80 != 0 => {
+            result &= !(0x80i64 << (8 * (data.len() - 1)));
+            -result
+        }
+        _ => result,
+    }
+}
+
+/// Pushed data, with its sighash type decoded if it's a strictly encoded signature
+fn signature_asm(data: &[u8]) -> String {
+    let (last, sig) = data.split_last().expect("empty signature");
+    let sighash = match last {
+        0x01 => "ALL",
+        0x81 => "ALL|ANYONECANPAY",
+        0x02 => "NONE",
+        0x82 => "NONE|ANYONECANPAY",
+        0x03 => "SINGLE",
+        0x83 => "SINGLE|ANYONECANPAY",
+        _ => return hex::encode(data),
+    };
+    if !is_valid_signature_encoding(data) {
+        return hex::encode(data);
+    }
+    format!("{}[{}]", hex::encode(sig), sighash)
+}
+
+/// Strict DER encoding check (see BIP-66), including the trailing sighash type byte
+fn is_valid_signature_encoding(sig: &[u8]) -> bool {
+    let len = sig.len();
+    if !(9..=73).contains(&len) || sig[0] != 0x30 || sig[1] as usize != len - 3 {
+        return false;
+    }
+    let len_r = sig[3] as usize;
+    if 5 + len_r >= len {
+        return false;
+    }
+    let len_s = sig[5 + len_r] as usize;
+    if len_r + len_s + 7 != len {
+        return false;
+    }
+    // R element
+    if sig[2] != 0x02 || len_r == 0 || sig[4] & 0x80 != 0 {
+        return false;
+    }
+    if len_r > 1 && sig[4] == 0x00 && sig[5] & 0x80 == 0 {
+        return false;
+    }
+    // S element
+    if sig[len_r + 4] != 0x02 || len_s == 0 || sig[len_r + 6] & 0x80 != 0 {
+        return false;
+    }
+    !(len_s > 1 && sig[len_r + 6] == 0x00 && sig[len_r + 7] & 0x80 == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{script_asm, script_num, script_pubkey_info, transaction_info};
+    use crate::hex;
+    use bitcoin::{blockdata::constants::genesis_block, Network, Script, ScriptBuf};
+    use serde_json::json;
+
+    fn script(value: &str) -> ScriptBuf {
+        ScriptBuf::from(hex::decode(value).unwrap())
+    }
+
+    #[test]
+    fn test_genesis_coinbase() {
+        let tx = &genesis_block(Network::Bitcoin).txdata[0];
+        let info = transaction_info(tx, Network::Bitcoin).unwrap();
+        let pubkey = "04678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5f";
+        assert_eq!(
+            info["txid"],
+            json!("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b")
+        );
+        assert_eq!(info["hash"], info["txid"]);
+        assert_eq!(
+            (&info["size"], &info["vsize"], &info["weight"]),
+            (&json!(204), &json!(204), &json!(816))
+        );
+        assert_eq!(
+            (&info["version"], &info["locktime"]),
+            (&json!(1), &json!(0))
+        );
+        assert_eq!(
+            info["vin"],
+            json!([{
+                "coinbase": "04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73",
+                "sequence": 4294967295u32,
+            }])
+        );
+        assert_eq!(
+            info["vout"],
+            json!([{
+                "value": 50.0,
+                "n": 0,
+                "scriptPubKey": {
+                    "asm": format!("{} OP_CHECKSIG", pubkey),
+                    "desc": format!("pk({})#vlz6ztea", pubkey),
+                    "hex": format!("41{}ac", pubkey),
+                    "type": "pubkey",
+                },
+            }])
+        );
+    }
+
+    #[test]
+    fn test_script_pubkey() {
+        let p2pkh = script("76a91489abcdefabbaabbaabbaabbaabbaabbaabbaabba88ac");
+        let info = script_pubkey_info(&p2pkh, Network::Bitcoin).unwrap();
+        assert_eq!(
+            info["asm"],
+            json!("OP_DUP OP_HASH160 89abcdefabbaabbaabbaabbaabbaabbaabbaabba OP_EQUALVERIFY OP_CHECKSIG")
+        );
+        assert_eq!(info["type"], json!("pubkeyhash"));
+        let address = info["address"].as_str().unwrap();
+        assert!(info["desc"]
+            .as_str()
+            .unwrap()
+            .starts_with(&format!("addr({})#", address)));
+
+        let p2wpkh = script("0014751e76e8199196d454941c45d1b3a323f1433bd6");
+        let info = script_pubkey_info(&p2wpkh, Network::Bitcoin).unwrap();
+        assert_eq!(info["type"], json!("witness_v0_keyhash"));
+        assert_eq!(
+            info["address"],
+            json!("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4")
+        );
+        assert_eq!(
+            info["asm"],
+            json!("0 751e76e8199196d454941c45d1b3a323f1433bd6")
+        );
+
+        let nulldata = script("6a0568656c6c6f");
+        let info = script_pubkey_info(&nulldata, Network::Bitcoin).unwrap();
+        assert_eq!(info["type"], json!("nulldata"));
+        assert_eq!(info["asm"], json!("OP_RETURN 68656c6c6f"));
+        assert!(info.get("address").is_none());
+        assert!(info["desc"]
+            .as_str()
+            .unwrap()
+            .starts_with("raw(6a0568656c6c6f)#"));
+
+        let nonstandard = script("6a61"); // OP_RETURN OP_NOP
+        let info = script_pubkey_info(&nonstandard, Network::Bitcoin).unwrap();
+        assert_eq!(info["type"], json!("nonstandard"));
+
+        // 1-of-1 bare multisig is left to bitcoind
+        let multisig =
+            script("5121031b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f51ae");
+        assert!(script_pubkey_info(&multisig, Network::Bitcoin).is_none());
+    }
+
+    #[test]
+    fn test_script_asm() {
+        assert_eq!(script_num(&[]), 0);
+        assert_eq!(script_num(&[0x01]), 1);
+        assert_eq!(script_num(&[0x81]), -1);
+        assert_eq!(script_num(&[0xff, 0x00]), 255);
+        assert_eq!(script_num(&[0xff, 0x80]), -255);
+
+        // OP_1NEGATE OP_16 OP_CHECKLOCKTIMEVERIFY OP_CHECKSEQUENCEVERIFY 0xbb
+        let ops = script("4f60b1b2bb");
+        assert_eq!(
+            script_asm(&ops, false),
+            "-1 16 OP_CHECKLOCKTIMEVERIFY OP_CHECKSEQUENCEVERIFY OP_UNKNOWN"
+        );
+        assert_eq!(script_asm(&script("4c05"), false), "[error]"); // truncated push
+
+        let sig = "3044022033a7c9bd8b02bdd44cf7ac87d4ac0ac6c01e3c5fa3c7d1f8436d0c2e5a7e0e7b02205e0d9b51ae3a34dd6ac3b4b8e2b8bca0ae0d6e6b4b4ba3ac36e3f24ed7b0e3b6";
+        let pubkey = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+        let script_sig = script(&format!("47{}0121{}", sig, pubkey));
+        assert_eq!(
+            script_asm(&script_sig, true),
+            format!("{}[ALL] {}", sig, pubkey)
+        );
+        assert_eq!(
+            script_asm(&script_sig, false),
+            format!("{}01 {}", sig, pubkey)
+        );
+        assert_eq!(script_asm(Script::from_bytes(&[]), true), "");
+    }
+}
@@ -6,7 +6,7 @@ use bitcoin::blockdata::block::Header as BlockHeader;
 use bitcoin::{
     consensus::encode::{deserialize, serialize, Decodable, Encodable},
     hashes::{hash_newtype, sha256, Hash},
-    OutPoint, Script, Txid,
+    BlockHash, OutPoint, Script, Txid,
 };
 
 use crate::db;
@@ -154,6 +154,108 @@ impl TxidRow {
 
 // ***************************************************************************
 
+/// Full txid (in display byte order, so that hex prefixes can be scanned) and its confirmed height.
+#[derive(Debug, PartialEq)]
+pub(crate) struct TxidPrefixRow {
+    txid: Txid,
+    height: Height,
+}
+
+impl TxidPrefixRow {
+    const TXID_LEN: usize = 32;
+
+    pub(crate) fn new(txid: Txid, height: usize) -> Self {
+        Self {
+            txid,
+            height: Height::try_from(height).expect("invalid height"),
+        }
+    }
+
+    pub(crate) fn to_db_row(&self) -> db::Row {
+        let mut row = Vec::with_capacity(Self::TXID_LEN + 4);
+        row.extend(self.txid.to_byte_array().iter().rev());
+        row.extend_from_slice(&self.height.to_le_bytes());
+        row.into_boxed_slice()
+    }
+
+    pub(crate) fn from_db_row(row: &[u8]) -> Self {
+        assert_eq!(row.len(), Self::TXID_LEN + 4, "bad TxidPrefixRow");
+        let mut txid = [0u8; 32];
+        txid.copy_from_slice(&row[..Self::TXID_LEN]);
+        txid.reverse();
+        let mut height = [0u8; 4];
+        height.copy_from_slice(&row[Self::TXID_LEN..]);
+        Self {
+            txid: Txid::from_byte_array(txid),
+            height: Height::from_le_bytes(height),
+        }
+    }
+
+    pub(crate) fn txid(&self) -> Txid {
+        self.txid
+    }
+
+    pub(crate) fn height(&self) -> usize {
+        usize::try_from(self.height).expect("invalid height")
+    }
+}
+
+// ***************************************************************************
+
+/// A block's txids (in their block order), keyed by its hash - so the rows of blocks which were
+/// replaced by a reorg are never looked up.
+pub(crate) struct BlockTxidsRow;
+
+impl BlockTxidsRow {
+    const TXID_LEN: usize = 32;
+
+    pub(crate) fn key(blockhash: BlockHash) -> db::Row {
+        serialize(&blockhash).into_boxed_slice()
+    }
+
+    pub(crate) fn value(txids: &[Txid]) -> db::Row {
+        let mut value = Vec::with_capacity(txids.len() * Self::TXID_LEN);
+        for txid in txids {
+            value.extend_from_slice(&txid.to_byte_array());
+        }
+        value.into_boxed_slice()
+    }
+
+    pub(crate) fn txids(value: &[u8]) -> Vec<Txid> {
+        assert_eq!(value.len() % Self::TXID_LEN, 0, "bad BlockTxidsRow");
+        value
+            .chunks_exact(Self::TXID_LEN)
+            .map(|bytes| {
+                let mut txid = [0u8; Self::TXID_LEN];
+                txid.copy_from_slice(bytes);
+                Txid::from_byte_array(txid)
+            })
+            .collect()
+    }
+}
+
+// ***************************************************************************
+
+/// The transaction spending an outpoint, and its confirming block - by its hash, so that spends
+/// in blocks which were replaced by a reorg can be told apart (and overwritten if re-confirmed).
+pub(crate) struct SpenderRow;
+
+impl SpenderRow {
+    pub(crate) fn key(outpoint: OutPoint) -> db::Row {
+        serialize(&outpoint).into_boxed_slice()
+    }
+
+    pub(crate) fn value(spender: Txid, blockhash: BlockHash) -> db::Row {
+        serialize(&(spender, blockhash)).into_boxed_slice()
+    }
+
+    pub(crate) fn spender(value: &[u8]) -> (Txid, BlockHash) {
+        deserialize(value).expect("bad SpenderRow")
+    }
+}
+
+// ***************************************************************************
+
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct HeaderRow {
     pub(crate) header: BlockHeader,
@@ -177,8 +279,11 @@ impl HeaderRow {
 
 #[cfg(test)]
 mod tests {
-    use crate::types::{spending_prefix, HashPrefixRow, ScriptHash, ScriptHashRow, TxidRow};
-    use bitcoin::{Address, OutPoint, Txid};
+    use crate::types::{
+        spending_prefix, BlockTxidsRow, HashPrefixRow, ScriptHash, ScriptHashRow, SpenderRow,
+        TxidPrefixRow, TxidRow,
+    };
+    use bitcoin::{Address, BlockHash, OutPoint, Txid};
     use hex_lit::hex;
     use serde_json::{from_str, json};
 
@@ -254,6 +359,71 @@ mod tests {
         assert_eq!(&*row2.to_db_row(), &hex!("68b45f58b674e94ee8660100"));
     }
 
+    #[test]
+    fn test_txid_prefix_row() {
+        let hex = "d5d27987d2a3dfc724e359870c6644b40e497bdc0589a033220fe15429d88599";
+        let txid = Txid::from_str(hex).unwrap();
+
+        let row1 = TxidPrefixRow::new(txid, 91812);
+        let db_row = row1.to_db_row();
+        // display byte order => rows can be scanned by a txid hex prefix
+        assert_eq!(
+            &db_row[..32],
+            &hex!("d5d27987d2a3dfc724e359870c6644b40e497bdc0589a033220fe15429d88599")[..]
+        );
+        assert_eq!(&db_row[32..], &hex!("a4660100"));
+        let row2 = TxidPrefixRow::from_db_row(&db_row);
+        assert_eq!(row1, row2);
+        assert_eq!(row2.txid(), txid);
+        assert_eq!(row2.height(), 91812);
+    }
+
+    #[test]
+    fn test_block_txids_row() {
+        let blockhash: BlockHash =
+            "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"
+                .parse()
+                .unwrap();
+        let key = BlockTxidsRow::key(blockhash);
+        assert_eq!(
+            &*key,
+            &hex!("6fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000")[..]
+        );
+
+        let txids: Vec<Txid> = [
+            "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+            "d5d27987d2a3dfc724e359870c6644b40e497bdc0589a033220fe15429d88599",
+        ]
+        .iter()
+        .map(|txid| txid.parse().unwrap())
+        .collect();
+        let value = BlockTxidsRow::value(&txids);
+        assert_eq!(value.len(), 64);
+        assert_eq!(BlockTxidsRow::txids(&value), txids);
+        assert!(BlockTxidsRow::txids(&BlockTxidsRow::value(&[])).is_empty());
+    }
+
+    #[test]
+    fn test_spender_row() {
+        let txid: Txid = "d5d27987d2a3dfc724e359870c6644b40e497bdc0589a033220fe15429d88599"
+            .parse()
+            .unwrap();
+        let key = SpenderRow::key(OutPoint { txid, vout: 1 });
+        assert_eq!(key.len(), 36);
+        assert_eq!(&key[32..], &hex!("01000000"));
+
+        let spender: Txid = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b"
+            .parse()
+            .unwrap();
+        let blockhash: BlockHash =
+            "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"
+                .parse()
+                .unwrap();
+        let value = SpenderRow::value(spender, blockhash);
+        assert_eq!(value.len(), 64);
+        assert_eq!(SpenderRow::spender(&value), (spender, blockhash));
+    }
+
     #[test]
     fn test_spending_prefix() {
         let txid = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f"
@@ -0,0 +1,486 @@
+use anyhow::{Context, Result};
+use bitcoin::{
+    hashes::{hmac, sha256, HashEngine},
+    Txid,
+};
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+
+use std::{
+    cmp::min,
+    collections::{HashSet, VecDeque},
+    fs,
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    hex::HashParam,
+    http::{parse_status, Url},
+    lock_order::{self, Level},
+    protocol::WebhookStats,
+    thread::spawn,
+    types::{ScriptHash, StatusHash},
+};
+
+/// Maximum number of events pending delivery, per webhook (newer ones are dropped)
+const MAX_PENDING_EVENTS: usize = 10_000;
+
+/// Maximum number of undeliverable events kept for inspection, per webhook (older ones are dropped)
+const MAX_DEAD_LETTERS: usize = 100;
+
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(600);
+
+const SIGNATURE_HEADER: &str = "X-Electrs-Signature";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WebhookOptions {
+    pub path: PathBuf,     // JSON list of webhook definitions (including their secrets)
+    pub max_attempts: u32, // dead-letter an event after this number of failed deliveries
+    pub retry_delay: Duration, // before the first retry (doubled for each subsequent one)
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Definition {
+    url: String,
+    secret: String,
+    #[serde(default)]
+    scripthashes: Vec<HashParam<ScriptHash>>,
+    #[serde(default)]
+    broadcasts: bool, // notify about all transactions broadcast via this server
+}
+
+#[derive(Default)]
+struct Stats {
+    delivered: AtomicUsize,
+    retries: AtomicUsize,
+    dead_lettered: AtomicUsize,
+    dropped: AtomicUsize,
+    dead_letters: Mutex<VecDeque<Value>>,
+}
+
+impl Stats {
+    fn dead_letter(&self, event: Value, attempts: u32, error: String) {
+        self.dead_lettered.fetch_add(1, Ordering::Relaxed);
+        let _order = lock_order::acquire(Level::Webhooks);
+        let mut dead_letters = self.dead_letters.lock();
+        if dead_letters.len() == MAX_DEAD_LETTERS {
+            dead_letters.pop_front();
+        }
+        dead_letters.push_back(json!({"event": event, "attempts": attempts, "error": error}));
+    }
+
+    fn to_wire(&self, url: &str, queued: usize) -> WebhookStats {
+        let _order = lock_order::acquire(Level::Webhooks);
+        WebhookStats {
+            url: url.to_owned(),
+            queued,
+            delivered: self.delivered.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            dead_lettered: self.dead_lettered.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            dead_letters: self.dead_letters.lock().iter().cloned().collect(),
+        }
+    }
+}
+
+struct Endpoint {
+    url: String,
+    scripthashes: HashSet<ScriptHash>,
+    broadcasts: bool,
+    tx: Sender<Value>,
+    stats: Arc<Stats>,
+}
+
+/// Signed HTTP notifications of scripthash status changes and transaction broadcasts, delivered
+/// by a separate thread per webhook (so neither sync nor RPC handling waits for the endpoints).
+#[derive(Clone, Default)]
+pub(crate) struct Webhooks {
+    endpoints: Arc<Vec<Endpoint>>, // empty if disabled
+    network: Option<String>,
+}
+
+impl Webhooks {
+    pub(crate) fn open(options: Option<&WebhookOptions>) -> Result<Self> {
+        let options = match options {
+            Some(options) => options,
+            None => return Ok(Self::default()),
+        };
+        let path = &options.path;
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("failed to read webhooks from {}", path.display()))?;
+        let definitions: Vec<Definition> = serde_json::from_str(&content)
+            .with_context(|| format!("invalid webhooks in {}", path.display()))?;
+        let endpoints = definitions
+            .into_iter()
+            .map(|definition| start(definition, options))
+            .collect::<Result<Vec<Endpoint>>>()?;
+        info!("delivering events to {} webhooks", endpoints.len());
+        Ok(Self {
+            endpoints: Arc::new(endpoints),
+            network: None,
+        })
+    }
+
+    /// Label the events with the given network (when serving multiple networks).
+    pub(crate) fn with_network(&self, network: &str) -> Self {
+        Self {
+            endpoints: Arc::clone(&self.endpoints),
+            network: Some(network.to_owned()),
+        }
+    }
+
+    /// The scripthashes whose status should be tracked (for all webhooks)
+    pub(crate) fn scripthashes(&self) -> HashSet<ScriptHash> {
+        self.endpoints
+            .iter()
+            .flat_map(|endpoint| endpoint.scripthashes.iter().copied())
+            .collect()
+    }
+
+    /// A new (or confirmed) transaction has changed the scripthash's status
+    pub(crate) fn scripthash_status(
+        &self,
+        scripthash: ScriptHash,
+        statushash: Option<StatusHash>,
+        height: usize,
+    ) {
+        let event = json!({
+            "event": "scripthash.status",
+            "scripthash": scripthash,
+            "status": statushash,
+            "height": height,
+        });
+        self.send(event, |endpoint| {
+            endpoint.scripthashes.contains(&scripthash)
+        });
+    }
+
+    pub(crate) fn broadcast(&self, txid: Txid) {
+        let event = json!({"event": "transaction.broadcast", "txid": txid});
+        self.send(event, |endpoint| endpoint.broadcasts);
+    }
+
+    pub(crate) fn stats(&self) -> Vec<WebhookStats> {
+        self.endpoints
+            .iter()
+            .map(|endpoint| endpoint.stats.to_wire(&endpoint.url, endpoint.tx.len()))
+            .collect()
+    }
+
+    fn send(&self, mut event: Value, filter: impl Fn(&Endpoint) -> bool) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        event["time"] = json!(now.as_secs());
+        if let Some(network) = &self.network {
+            event["network"] = json!(network);
+        }
+        for endpoint in self.endpoints.iter().filter(|endpoint| filter(endpoint)) {
+            if let Err(TrySendError::Full(_)) = endpoint.tx.try_send(event.clone()) {
+                let dropped = endpoint.stats.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                if dropped % 1000 == 1 {
+                    warn!(
+                        "webhook {} is lagging: {} events dropped",
+                        endpoint.url, dropped
+                    );
+                }
+            }
+        }
+    }
+}
+
+fn start(definition: Definition, options: &WebhookOptions) -> Result<Endpoint> {
+    let url = Url::parse(&definition.url)?;
+    ensure!(
+        !definition.secret.is_empty(),
+        "missing secret for webhook {}",
+        definition.url
+    );
+    let (tx, rx) = bounded(MAX_PENDING_EVENTS);
+    let stats = Arc::new(Stats::default());
+    let delivery = Delivery {
+        url,
+        secret: definition.secret.into_bytes(),
+        max_attempts: options.max_attempts,
+        retry_delay: options.retry_delay,
+        stats: Arc::clone(&stats),
+    };
+    spawn("webhook", move || delivery.run(rx));
+    Ok(Endpoint {
+        url: definition.url,
+        scripthashes: definition
+            .scripthashes
+            .into_iter()
+            .map(|HashParam(scripthash)| scripthash)
+            .collect(),
+        broadcasts: definition.broadcasts,
+        tx,
+        stats,
+    })
+}
+
+struct Delivery {
+    url: Url,
+    secret: Vec<u8>,
+    max_attempts: u32,
+    retry_delay: Duration,
+    stats: Arc<Stats>,
+}
+
+impl Delivery {
+    fn run(self, rx: Receiver<Value>) -> Result<()> {
+        for event in rx.iter() {
+            self.deliver(event);
+        }
+        Ok(())
+    }
+
+    /// Retry with exponential backoff, until the event is delivered or dead-lettered.
+    fn deliver(&self, event: Value) {
+        let body = event.to_string();
+        let signature = sign(&self.secret, body.as_bytes());
+        let mut delay = self.retry_delay;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let err = match self.post(&body, &signature) {
+                Ok(()) => {
+                    self.stats.delivered.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                Err(err) => err,
+            };
+            if attempts >= self.max_attempts {
+                warn!(
+                    "dead-lettering webhook event after {} attempts: {:#}",
+                    attempts, err
+                );
+                self.stats
+                    .dead_letter(event, attempts, format!("{:#}", err));
+                return;
+            }
+            debug!("retrying webhook event in {:?}: {:#}", delay, err);
+            self.stats.retries.fetch_add(1, Ordering::Relaxed);
+            thread::sleep(delay);
+            delay = min(delay * 2, MAX_RETRY_DELAY);
+        }
+    }
+
+    /// The event is delivered if the endpoint responds with a 2xx status.
+    fn post(&self, body: &str, signature: &str) -> Result<()> {
+        let url = &self.url;
+        let mut stream = url.connect()?;
+        let request = format!(
+            concat!(
+                "POST {} HTTP/1.1\r\n",
+                "Host: {}\r\n",
+                "Content-Type: application/json\r\n",
+                "Content-Length: {}\r\n",
+                "{}: sha256={}\r\n",
+                "Connection: close\r\n\r\n",
+                "{}"
+            ),
+            url.path,
+            url.authority,
+            body.len(),
+            SIGNATURE_HEADER,
+            signature,
+            body
+        );
+        stream
+            .write_all(request.as_bytes())
+            .context("failed to send request")?;
+        let mut status_line = String::new();
+        BufReader::new(stream)
+            .read_line(&mut status_line)
+            .context("failed to read response")?;
+        let status = parse_status(&status_line)?;
+        ensure!((200..300).contains(&status), "HTTP status {}", status);
+        Ok(())
+    }
+}
+
+/// HMAC-SHA256 of the request body, allowing the endpoint to authenticate the events.
+fn sign(secret: &[u8], body: &[u8]) -> String {
+    let mut engine = hmac::HmacEngine::<sha256::Hash>::new(secret);
+    engine.input(body);
+    hmac::Hmac::<sha256::Hash>::from_engine(engine).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sign, WebhookOptions, Webhooks, SIGNATURE_HEADER};
+    use crate::types::ScriptHash;
+    use bitcoin::{hashes::Hash, ScriptBuf, Txid};
+    use crossbeam_channel::{unbounded, Receiver};
+    use serde_json::{json, Value};
+    use std::fs;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::path::Path;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    struct Request {
+        signature: String,
+        body: String,
+    }
+
+    /// Responds to each request with the next status (repeating the last one).
+    fn serve(statuses: Vec<u16>) -> (String, Receiver<Request>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = unbounded();
+        thread::spawn(move || {
+            for (i, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                assert_eq!(request_line, "POST /hook HTTP/1.1\r\n");
+                let (mut len, mut signature) = (0, String::new());
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    let mut parts = line.splitn(2, ": ");
+                    match (parts.next().unwrap(), parts.next().unwrap()) {
+                        ("Content-Length", value) => len = value.parse().unwrap(),
+                        (name, value) if name == SIGNATURE_HEADER => signature = value.to_owned(),
+                        _ => (),
+                    }
+                }
+                let mut body = vec![0u8; len];
+                reader.read_exact(&mut body).unwrap();
+                let body = String::from_utf8(body).unwrap();
+                let status = statuses[i.min(statuses.len() - 1)];
+                let response = format!("HTTP/1.1 {} X\r\nContent-Length: 0\r\n\r\n", status);
+                stream.write_all(response.as_bytes()).unwrap();
+                if tx.send(Request { signature, body }).is_err() {
+                    return;
+                }
+            }
+        });
+        (url, rx)
+    }
+
+    fn open(dir: &Path, definitions: Value, max_attempts: u32) -> Webhooks {
+        let path = dir.join("webhooks.json");
+        fs::write(&path, definitions.to_string()).unwrap();
+        let options = WebhookOptions {
+            path,
+            max_attempts,
+            retry_delay: Duration::from_millis(10),
+        };
+        Webhooks::open(Some(&options)).unwrap()
+    }
+
+    /// Delivery statistics are updated after the endpoint responds.
+    fn wait_for_stats(webhooks: &Webhooks, key: &str, expected: usize) -> Value {
+        let start = Instant::now();
+        loop {
+            let stats = json!(webhooks.stats()[0]);
+            if stats[key] == json!(expected) {
+                return stats;
+            }
+            assert!(start.elapsed() < Duration::from_secs(10), "{}", stats);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_signed_retries() {
+        let (url, requests) = serve(vec![500, 200]);
+        let scripthash = ScriptHash::new(&ScriptBuf::new());
+        let dir = tempfile::tempdir().unwrap();
+        let definitions = json!([{"url": url, "secret": "secret", "scripthashes": [scripthash]}]);
+        let webhooks = open(dir.path(), definitions, 3).with_network("regtest");
+        assert_eq!(
+            webhooks.scripthashes().into_iter().collect::<Vec<_>>(),
+            vec![scripthash]
+        );
+
+        webhooks.broadcast(Txid::all_zeros()); // not configured
+        webhooks.scripthash_status(scripthash, None, 100);
+        let first = requests.recv().unwrap();
+        let second = requests.recv().unwrap(); // retried after a failure
+        assert_eq!(first.body, second.body);
+        assert_eq!(
+            first.signature,
+            format!("sha256={}", sign(b"secret", first.body.as_bytes()))
+        );
+        let event: Value = serde_json::from_str(&first.body).unwrap();
+        assert_eq!(event["event"], "scripthash.status");
+        assert_eq!(event["scripthash"], json!(scripthash));
+        assert_eq!(event["status"], Value::Null);
+        assert_eq!(event["height"], 100);
+        assert_eq!(event["network"], "regtest");
+
+        let stats = wait_for_stats(&webhooks, "delivered", 1);
+        assert_eq!(stats["url"], json!(url));
+        assert_eq!(stats["retries"], 1);
+        assert_eq!(stats["dead_lettered"], 0);
+        assert_eq!(stats["queued"], 0);
+    }
+
+    #[test]
+    fn test_dead_letters() {
+        let (url, requests) = serve(vec![503]);
+        let dir = tempfile::tempdir().unwrap();
+        let definitions = json!([{"url": url, "secret": "secret", "broadcasts": true}]);
+        let webhooks = open(dir.path(), definitions, 2);
+
+        let txid = Txid::all_zeros();
+        webhooks.broadcast(txid);
+        for _ in 0..2 {
+            let request = requests.recv().unwrap();
+            let event: Value = serde_json::from_str(&request.body).unwrap();
+            assert_eq!(event["event"], "transaction.broadcast");
+            assert_eq!(event["txid"], json!(txid));
+        }
+        let stats = wait_for_stats(&webhooks, "dead_lettered", 1);
+        assert_eq!(stats["delivered"], 0);
+        assert_eq!(stats["retries"], 1);
+        let dead_letter = &stats["dead_letters"][0];
+        assert_eq!(dead_letter["event"]["txid"], json!(txid));
+        assert_eq!(dead_letter["attempts"], 2);
+        assert_eq!(dead_letter["error"], "HTTP status 503");
+        assert!(requests.try_recv().is_err()); // no more attempts
+    }
+
+    #[test]
+    fn test_invalid() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("webhooks.json");
+        let options = WebhookOptions {
+            path: path.clone(),
+            max_attempts: 1,
+            retry_delay: Duration::from_millis(10),
+        };
+        assert!(Webhooks::open(Some(&options)).is_err()); // missing file
+        let invalid = vec![
+            json!([{"url": "https://example.com/", "secret": "secret"}]),
+            json!([{"url": "http://example.com/", "secret": ""}]),
+            json!([{"url": "http://example.com/", "secret": "s", "scripthashes": ["00"]}]),
+        ];
+        for definitions in invalid {
+            fs::write(&path, definitions.to_string()).unwrap();
+            assert!(Webhooks::open(Some(&options)).is_err(), "{}", definitions);
+        }
+        let webhooks = Webhooks::open(None).unwrap();
+        webhooks.broadcast(Txid::all_zeros());
+        assert!(webhooks.stats().is_empty());
+    }
+}
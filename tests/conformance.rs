@@ -0,0 +1,272 @@
+//! Electrum protocol conformance tests, run against a live server:
+//!
+//! ```text
+//! ELECTRS_CONFORMANCE_ADDR=127.0.0.1:60401 cargo test --test conformance -- --ignored
+//! ```
+//!
+//! They are also run by the integration tests (`tests/run.sh`), against the regtest server.
+//!
+//! Each response's "shape" (its field names and JSON types) is compared with a golden fixture
+//! from `tests/conformance/`. Fixtures may be (re)generated by running the tests against a
+//! reference server (e.g. ElectrumX or Fulcrum) with `ELECTRS_CONFORMANCE_UPDATE=1`.
+//!
+//! Fixture syntax: a leaf is a JSON type name ("string", "integer", "number", "boolean",
+//! "null", "object" or "array"), an object lists its required fields (additional fields are
+//! allowed) and an array contains the shape of its elements (`[]` allows any elements).
+//!
+//! Standard methods' results are also decoded as their `electrs::protocol` types.
+
+use electrs::protocol::{
+    FeeEstimate, GetBalanceResponse, HeadersSubscribeResult, MerkleResult, UnspentEntryWire,
+};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Map, Value};
+
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:60401"; // started by `tests/run.sh` (regtest)
+
+struct Client {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    next_id: u64,
+    version: Value, // `server.version` may be sent only once per connection
+}
+
+impl Client {
+    fn connect() -> Self {
+        let addr = env::var("ELECTRS_CONFORMANCE_ADDR").unwrap_or_else(|_| DEFAULT_ADDR.to_owned());
+        let writer = TcpStream::connect(&addr)
+            .unwrap_or_else(|e| panic!("failed to connect to {}: {}", addr, e));
+        let reader = BufReader::new(writer.try_clone().unwrap());
+        let mut client = Self {
+            reader,
+            writer,
+            next_id: 0,
+            version: Value::Null,
+        };
+        client.version = client.call("server.version", json!(["conformance", "1.4"]));
+        client
+    }
+
+    fn call(&mut self, method: &str, params: Value) -> Value {
+        self.next_id += 1;
+        let request =
+            json!({"jsonrpc": "2.0", "id": self.next_id, "method": method, "params": params});
+        let mut line = request.to_string();
+        line.push('\n');
+        self.writer.write_all(line.as_bytes()).unwrap();
+
+        loop {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            assert!(!line.is_empty(), "{}: server disconnected", method);
+            let mut response: Value = serde_json::from_str(&line).unwrap();
+            if response.get("id").is_none() {
+                continue; // skip notifications
+            }
+            assert_eq!(
+                response["id"],
+                json!(self.next_id),
+                "{}: unexpected id",
+                method
+            );
+            if let Some(error) = response.get("error") {
+                panic!("{} failed: {}", method, error);
+            }
+            return response["result"].take();
+        }
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(n) if n.is_f64() => "number",
+        Value::Number(_) => "integer",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Returns the shape of `value`, as used by the golden fixtures.
+fn shape(value: &Value) -> Value {
+    match value {
+        Value::Array(values) => Value::Array(values.first().map(shape).into_iter().collect()),
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(key, value)| (key.clone(), shape(value)))
+                .collect::<Map<String, Value>>(),
+        ),
+        leaf => json!(type_name(leaf)),
+    }
+}
+
+/// Collects mismatches between `value` and its `expected` shape.
+fn compare(path: &str, expected: &Value, value: &Value, diffs: &mut Vec<String>) {
+    match (expected, value) {
+        (Value::String(name), _) => {
+            let actual = type_name(value);
+            // integers are valid numbers (e.g. `-1` for an unknown fee estimate)
+            let matches = name == actual || (name == "number" && actual == "integer");
+            if !matches {
+                diffs.push(format!(
+                    "{}: expected {}, got {} ({})",
+                    path, name, actual, value
+                ));
+            }
+        }
+        (Value::Object(fields), Value::Object(values)) => {
+            for (key, field) in fields {
+                let field_path = format!("{}.{}", path, key);
+                match values.get(key) {
+                    Some(value) => compare(&field_path, field, value, diffs),
+                    None => diffs.push(format!("{}: missing field", field_path)),
+                }
+            }
+        }
+        (Value::Array(shapes), Value::Array(values)) => {
+            if let Some(element) = shapes.first() {
+                for (i, value) in values.iter().enumerate() {
+                    compare(&format!("{}[{}]", path, i), element, value, diffs);
+                }
+            }
+        }
+        _ => diffs.push(format!(
+            "{}: expected {}, got {}",
+            path,
+            type_name(expected),
+            type_name(value)
+        )),
+    }
+}
+
+/// The fixtures' directory (may be overridden, e.g. when running a prebuilt test binary)
+fn fixtures_dir() -> PathBuf {
+    env::var_os("ELECTRS_CONFORMANCE_FIXTURES").map_or_else(
+        || {
+            [env!("CARGO_MANIFEST_DIR"), "tests", "conformance"]
+                .iter()
+                .collect()
+        },
+        PathBuf::from,
+    )
+}
+
+fn check(method: &str, result: &Value) {
+    let path = fixtures_dir().join(format!("{}.json", method));
+    if env::var_os("ELECTRS_CONFORMANCE_UPDATE").is_some() {
+        let golden = serde_json::to_string_pretty(&shape(result)).unwrap();
+        fs::write(&path, golden + "\n").unwrap();
+        return;
+    }
+    let golden = fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("failed to read {}: {}", path.display(), e));
+    let expected: Value = serde_json::from_str(&golden).unwrap();
+    let mut diffs = vec![];
+    compare(method, &expected, result, &mut diffs);
+    assert!(
+        diffs.is_empty(),
+        "{} doesn't match {}:\n{}\nresult: {}",
+        method,
+        path.display(),
+        diffs.join("\n"),
+        result
+    );
+}
+
+/// Like `check()`, also decoding the result as its typed definition.
+fn check_typed<T: DeserializeOwned>(method: &str, result: &Value) {
+    check(method, result);
+    if let Err(e) = serde_json::from_value::<T>(result.clone()) {
+        panic!("{}: failed to decode {}: {}", method, result, e);
+    }
+}
+
+/// Scripthash to query (`tests/run.sh` uses its mining address, which is funded).
+fn scripthash() -> String {
+    env::var("ELECTRS_CONFORMANCE_SCRIPTHASH").unwrap_or_else(|_| {
+        // SHA256 of an empty script
+        "55b852781b9995a44c939b64e441ae2724b96f99c8f4fb9a141cfc9842c4b0e3".to_owned()
+    })
+}
+
+#[test]
+#[ignore] // requires a running server
+fn conformance_server() {
+    let mut client = Client::connect();
+    check_typed::<(String, String)>("server.version", &client.version);
+    check(
+        "server.features",
+        &client.call("server.features", json!([])),
+    );
+}
+
+#[test]
+#[ignore] // requires a running server
+fn conformance_blockchain() {
+    let mut client = Client::connect();
+    check_typed::<HeadersSubscribeResult>(
+        "blockchain.headers.subscribe",
+        &client.call("blockchain.headers.subscribe", json!([])),
+    );
+    check_typed::<FeeEstimate>(
+        "blockchain.estimatefee",
+        &client.call("blockchain.estimatefee", json!([6])),
+    );
+
+    // without a merkle proof, the result is just the txid
+    let txid = client.call("blockchain.transaction.id_from_pos", json!([1, 0, false]));
+    assert!(txid.is_string(), "unexpected id_from_pos result: {}", txid);
+    check_typed::<MerkleResult>(
+        "blockchain.transaction.get_merkle",
+        &client.call("blockchain.transaction.get_merkle", json!([txid, 1])),
+    );
+}
+
+#[test]
+#[ignore] // requires a running server
+fn conformance_scripthash() {
+    let mut client = Client::connect();
+    let scripthash = scripthash();
+    check_typed::<GetBalanceResponse>(
+        "blockchain.scripthash.get_balance",
+        &client.call("blockchain.scripthash.get_balance", json!([scripthash])),
+    );
+    check_typed::<Vec<UnspentEntryWire>>(
+        "blockchain.scripthash.listunspent",
+        &client.call("blockchain.scripthash.listunspent", json!([scripthash])),
+    );
+}
+
+#[test]
+fn test_shape_comparison() {
+    let result = json!({"height": 1, "hex": "00", "extra": null, "merkle": ["ab", "cd"]});
+    assert_eq!(
+        shape(&result),
+        json!({"height": "integer", "hex": "string", "extra": "null", "merkle": ["string"]})
+    );
+
+    let mut diffs = vec![];
+    compare("m", &shape(&result), &result, &mut diffs);
+    assert!(diffs.is_empty(), "{:?}", diffs);
+
+    let expected = json!({"height": "string", "missing": "integer", "merkle": ["integer"]});
+    compare("m", &expected, &result, &mut diffs);
+    assert_eq!(
+        diffs,
+        vec![
+            "m.height: expected string, got integer (1)",
+            "m.merkle[0]: expected integer, got string (\"ab\")",
+            "m.merkle[1]: expected integer, got string (\"cd\")",
+            "m.missing: missing field",
+        ]
+    );
+}
@@ -24,6 +24,17 @@ tail_log() {
 	tail -n +0 -F $1 || true
 }
 
+# call an electrs RPC method (with JSON params), printing its JSON result
+electrs_rpc() {
+	python3 -c '
+import json, socket, sys
+with socket.create_connection(("127.0.0.1", 60401)) as s:
+    request = {"jsonrpc": "2.0", "id": 0, "method": sys.argv[1], "params": json.loads(sys.argv[2])}
+    s.sendall(json.dumps(request).encode() + b"\n")
+    print(json.dumps(json.loads(s.makefile().readline())["result"]))
+' "$@"
+}
+
 echo "Starting $(bitcoind -version | head -n1)..."
 bitcoind -regtest -datadir=data/bitcoin -printtoconsole=0 &
 BITCOIND_PID=$!
@@ -77,6 +88,23 @@ test "`$EL getaddresshistory $NEW_ADDR | jq -c .`" == "[{\"fee\":100000,\"height
 echo " * getbalance"
 test "`$EL getbalance | jq -c .`" == '{"confirmed":"549.999","unmatured":"4950"}'
 
+# the mining address' scripthash (i.e. the reversed SHA256 of its script)
+SCRIPTHASH=`$BTC validateaddress $MINING_ADDR | jq -r .scriptPubKey | python3 -c \
+  "import hashlib, sys; print(hashlib.sha256(bytes.fromhex(input())).digest()[::-1].hex())"`
+
+echo " * listunspent (marking UTXOs spent in mempool)"
+SPENT=`$BTC getrawtransaction $TXID 1 | jq -c '[.vin[] | [.txid, .vout]] | sort'`
+UNSPENT=`electrs_rpc blockchain.scripthash.listunspent "[\"$SCRIPTHASH\", true]"`
+test "`echo "$UNSPENT" | jq -c '[.[] | select(.spent_in_mempool) | [.tx_hash, .tx_pos]] | sort'`" == "$SPENT"
+
+echo " * select_unspent (excluding UTXOs spent in mempool)"
+# selecting the whole unspent amount uses all the UTXOs, except the ones spent by $TXID
+AMOUNT=`echo "$UNSPENT" | jq '[.[] | select(.spent_in_mempool | not) | .value] | add'`
+SELECTED=`electrs_rpc blockchain.scripthash.select_unspent \
+  "[\"$SCRIPTHASH\", [$AMOUNT], 0, false, null, false, false, null, null, 1000]"`
+test "`echo "$SELECTED" | jq '[.[] | .value] | add'`" == "$AMOUNT"
+test "`echo "$SELECTED" | jq -c --argjson spent "$SPENT" '[.[] | [.tx_hash, .tx_pos] | select(IN($spent[]))]'`" == "[]"
+
 echo "Generating bitcoin block..."
 $BTC generatetoaddress 1 $MINING_ADDR > /dev/null
 $BTC getblockcount > /dev/null
@@ -94,6 +122,12 @@ test "`$EL getaddresshistory $NEW_ADDR | jq -c .`" == "[{\"height\":111,\"tx_has
 echo " * getbalance"
 test "`$EL getbalance | jq -c .`" == '{"confirmed":"599.999","unmatured":"4950.001"}'
 
+echo " * conformance"
+ELECTRS_CONFORMANCE_ADDR=127.0.0.1:60401 \
+ELECTRS_CONFORMANCE_SCRIPTHASH=$SCRIPTHASH \
+ELECTRS_CONFORMANCE_FIXTURES=$(dirname "$0")/conformance \
+  electrs-conformance --ignored
+
 echo "Electrum `$EL stop`"  # disconnect wallet
 wait $ELECTRUM_PID
 